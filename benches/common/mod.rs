@@ -0,0 +1,56 @@
+// common/mod.rs
+//
+// A small timing harness shared by the benchmarks (criterion isn't a dependency): each benchmark runs a few warm-up iterations, then times a fixed number of them and prints the median and range per iteration, the throughput, and (for benchmarks that send) how the server's own timing counters (see StatsSnapshot) moved meanwhile.
+
+use std::time::{Duration, Instant};
+use quicksocket::server::{LoopbackClient, Server, ServerConfig, Transport, logging};
+
+const WARMUP_ITERATIONS: usize = 10;
+
+/// A server on the loopback transport, so the benchmarks measure quicksocket rather than the kernel's TCP stack.
+pub fn loopback_server() -> Server {
+  // Log lines would drown out the results (and cost time of their own), so they go to a sink that's already closed.
+  let (log_tx, _) = tokio::sync::mpsc::channel(1);
+  logging::set_sink(Some((log_tx, logging::Level::Error)));
+  let server = Server::start(1, ServerConfig { transport: Transport::Loopback, ..Default::default() }).expect("the server starts");
+  assert!(server.wait_until_started(Some(Duration::from_secs(5))).expect("the server starts"), "the server didn't start in time");
+  server
+}
+
+/// Connects `count` loopback clients.
+pub fn connect(server: &Server, count: usize) -> Vec<LoopbackClient> {
+  (0..count).map(|_| {
+    let client = server.connect_loopback().expect("the client connects");
+    // (Connection events queue up until they're drained, and a full queue holds up new clients.)
+    server.drain_connection_events();
+    client
+  }).collect()
+}
+
+/// Times `iterations` runs of `run` (after a few untimed ones), each of which handles `items` of whatever the benchmark counts, and prints the results as `name`.
+pub fn bench(name: &str, server: &Server, iterations: usize, items: usize, mut run: impl FnMut()) {
+  for _ in 0..WARMUP_ITERATIONS { run(); }
+  let before = server.stats();
+  let mut times: Vec<Duration> = (0..iterations).map(|_| {
+    let started = Instant::now();
+    run();
+    started.elapsed()
+  }).collect();
+  let after = server.stats();
+  times.sort();
+
+  let median = times[times.len() / 2];
+  let total: Duration = times.iter().sum();
+  let per_item = |counter: Duration| (counter.as_nanos() as f64) / (iterations * items) as f64;
+  println!(
+    "{:<28} median {:>10.1?}  [{:.1?} .. {:.1?}]  {:>12.0} items/s",
+    name, median, times[0], times[times.len() - 1], (iterations * items) as f64 / total.as_secs_f64()
+  );
+  let (serialization, channel_wait, socket_write) = (after.serialization - before.serialization, after.channel_wait - before.channel_wait, after.socket_write - before.socket_write);
+  if serialization + channel_wait + socket_write > Duration::ZERO {
+    println!(
+      "{:<28} per item: {:.0} ns serializing, {:.0} ns waiting in channels, {:.0} ns writing to sockets",
+      "", per_item(serialization), per_item(channel_wait), per_item(socket_write)
+    );
+  }
+}
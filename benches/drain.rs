@@ -0,0 +1,35 @@
+// drain.rs
+//
+// Draining client messages: clients' messages sent in bursts, timed until the consumer has drained them all (the same path get_messages() converts to Python objects from).
+
+mod common;
+
+use std::time::Duration;
+use quicksocket::server::Message;
+
+const BURST_LEN: usize = 64;
+const ITERATIONS: usize = 200;
+
+fn main() {
+  for &(clients, payload_len) in &[(1, 64), (4, 64), (4, 4096)] {
+    let server = common::loopback_server();
+    let mut loopbacks = common::connect(&server, clients);
+    let payload = vec![7u8; payload_len];
+    common::bench(&format!("drain/{}x{}B", clients, payload_len), &server, ITERATIONS, clients * BURST_LEN, || {
+      // (Sent from threads of their own: the server reads only so far ahead of the drain, so a bigger burst waits for it.)
+      std::thread::scope(|scope| {
+        for client in loopbacks.iter_mut() {
+          let payload = &payload;
+          scope.spawn(move || client.send((0..BURST_LEN).map(|_| Message::Binary(payload.clone())).collect()).expect("the server is running"));
+        }
+        let mut drained = 0;
+        while drained < clients * BURST_LEN {
+          let messages = server.drain_messages(Some(Duration::from_secs(5)), usize::MAX).expect("the server is running");
+          assert!(!messages.is_empty(), "the clients' messages arrive");
+          drained += messages.len();
+        }
+      });
+    });
+    server.shutdown(true).expect("the server shuts down");
+  }
+}
@@ -0,0 +1,28 @@
+// fanout.rs
+//
+// Broadcast fan-out: batches of messages sent to every connected client, timed until each client has received the whole batch.
+
+mod common;
+
+use std::time::Duration;
+use quicksocket::server::Message;
+
+const BATCH_LEN: usize = 16;
+const ITERATIONS: usize = 200;
+
+fn main() {
+  for &(clients, payload_len) in &[(1, 64), (16, 64), (16, 4096), (64, 64)] {
+    let server = common::loopback_server();
+    let mut loopbacks = common::connect(&server, clients);
+    let payload = "x".repeat(payload_len);
+    common::bench(&format!("fanout/{}x{}B", clients, payload_len), &server, ITERATIONS, clients * BATCH_LEN, || {
+      server.send((0..BATCH_LEN).map(|_| Message::Text(payload.clone())).collect()).expect("the server is running");
+      for client in loopbacks.iter_mut() {
+        for _ in 0..BATCH_LEN {
+          client.recv(Some(Duration::from_secs(5))).expect("the server is running").expect("the broadcast arrives");
+        }
+      }
+    });
+    server.shutdown(true).expect("the server shuts down");
+  }
+}
@@ -0,0 +1,19 @@
+// handshake.rs
+//
+// Handshake throughput: clients connected (through the whole handshake, until the server has registered them) and closed again, one after another.
+
+mod common;
+
+const ITERATIONS: usize = 200;
+
+fn main() {
+  let server = common::loopback_server();
+  common::bench("handshake/connect+close", &server, ITERATIONS, 1, || {
+    let mut client = common::connect(&server, 1).pop().expect("the client connects");
+    client.close();
+    // (Like connection events, client messages, among them the close frames, queue up until they're drained, and a full queue holds up the close handshake.)
+    server.drain_connection_events();
+    server.drain_messages(None, usize::MAX).expect("the server is running");
+  });
+  server.shutdown(true).expect("the server shuts down");
+}
@@ -0,0 +1,129 @@
+/* quicksocket C API (the "capi" feature). See src/ffi.rs for the conventions. */
+
+#ifndef QUICKSOCKET_H
+#define QUICKSOCKET_H
+
+/* Generated with cbindgen from src/ffi.rs; don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The result of a fallible call.
+typedef enum QsStatus {
+  QS_OK = 0,
+  // The server isn't running (it stopped, or never started).
+  QS_NOT_RUNNING = 1,
+  // The server couldn't bind its port.
+  QS_BIND_ERROR = 2,
+  // Messages couldn't be handed to the server, or to the client.
+  QS_SEND_ERROR = 3,
+  // The client message receiver is in use elsewhere.
+  QS_RECEIVER_UNAVAILABLE = 4,
+  // A null pointer, invalid UTF-8, or an out-of-range argument.
+  QS_INVALID_ARGUMENT = 5,
+  // The wait timed out.
+  QS_TIMEOUT = 6,
+  QS_INTERNAL_ERROR = 7,
+} QsStatus;
+
+// Messages drained by qs_server_drain_messages(). Free with qs_batch_free().
+typedef struct QsMessageBatch QsMessageBatch;
+
+// A server, as returned by qs_server_start(). Free with qs_server_free() (which doesn't stop it; call qs_server_shutdown() first).
+typedef struct QsServer QsServer;
+
+// One message in a QsMessageBatch, as filled in by qs_batch_get(). Its pointers borrow from the batch, so they're valid until the batch is freed.
+typedef struct QsMessage {
+  // The client (peer address) that sent the message, NUL-terminated.
+  const char *client_id;
+  // The message: UTF-8 text (not NUL-terminated) if is_text, otherwise binary data.
+  const uint8_t *data;
+  uintptr_t len;
+  bool is_text;
+} QsMessage;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Returns a description of the last failure on the calling thread, or null if there hasn't been one. The string stays valid until the next failing call on this thread.
+const char *qs_last_error(void);
+
+// Starts a server on `port` (on its own thread), returning it, or null on failure. Returns before the port is bound: call qs_server_wait_until_started() to wait for that (and to find out whether binding failed).
+QsServer *qs_server_start(uint32_t port);
+
+// Blocks until the server has bound its port (QS_OK), binding failed (QS_BIND_ERROR), or `timeout_ms` elapsed (QS_TIMEOUT).
+//
+// # Safety
+// `server` must be a live pointer from qs_server_start().
+QsStatus qs_server_wait_until_started(const QsServer *server, int64_t timeout_ms);
+
+// Whether the server thread is alive: starting, running, or shutting down. False for a null server.
+//
+// # Safety
+// `server` must be null or a live pointer from qs_server_start().
+bool qs_server_is_running(const QsServer *server);
+
+// Sends one message to every connected client: `len` bytes of UTF-8 text if `is_text`, otherwise binary data.
+//
+// # Safety
+// `server` must be a live pointer from qs_server_start(), and `data` must point to `len` readable bytes (or be null if `len` is 0).
+QsStatus qs_server_send(const QsServer *server, const uint8_t *data, uintptr_t len, bool is_text);
+
+// Sends one message to the client `client_id` (NUL-terminated, as in QsMessage.client_id). With a negative `confirm_timeout_ms`, queues it without waiting; otherwise blocks until it's been written and flushed to the client's socket, for at most `confirm_timeout_ms`.
+//
+// # Safety
+// As for qs_server_send(), and `client_id` must be a NUL-terminated string.
+QsStatus qs_server_send_to_client(const QsServer *server,
+                                  const char *client_id,
+                                  const uint8_t *data,
+                                  uintptr_t len,
+                                  bool is_text,
+                                  int64_t confirm_timeout_ms);
+
+// Takes up to `max_messages` pending client messages, blocking for at most `timeout_ms` if none are pending, and stores them in `*out` (an empty batch on timeout). Free the batch with qs_batch_free().
+//
+// # Safety
+// `server` must be a live pointer from qs_server_start(), and `out` must be writable.
+QsStatus qs_server_drain_messages(const QsServer *server,
+                                  int64_t timeout_ms,
+                                  uintptr_t max_messages,
+                                  QsMessageBatch **out);
+
+// The number of messages in a batch (0 for a null batch).
+//
+// # Safety
+// `batch` must be null or a live pointer from qs_server_drain_messages().
+uintptr_t qs_batch_len(const QsMessageBatch *batch);
+
+// Fills in `*out` with the message at `index`, which borrows from the batch.
+//
+// # Safety
+// `batch` must be a live pointer from qs_server_drain_messages(), and `out` must be writable.
+QsStatus qs_batch_get(const QsMessageBatch *batch, uintptr_t index, QsMessage *out);
+
+// Frees a batch (and the messages borrowed from it). Null does nothing.
+//
+// # Safety
+// `batch` must be null or a pointer from qs_server_drain_messages() that hasn't been freed yet.
+void qs_batch_free(QsMessageBatch *batch);
+
+// Asks the server to shut down, sending clients close frames. If `wait`, blocks until the server thread has exited.
+//
+// # Safety
+// `server` must be a live pointer from qs_server_start().
+QsStatus qs_server_shutdown(const QsServer *server, bool wait);
+
+// Frees a server handle. Doesn't stop the server; shut it down first. Null does nothing.
+//
+// # Safety
+// `server` must be null or a pointer from qs_server_start() that hasn't been freed yet.
+void qs_server_free(QsServer *server);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif // QUICKSOCKET_H
@@ -0,0 +1,2 @@
+from .server import Server, Client, ClientStats, ClusterPeer, LoopbackClient, Relay, RelayStats, Replay, ReplayStats, RedisBridge, KafkaSink, ZmqBridge, ClientMessage, ClientCapabilities, ConnectionEvent, InputSnapshot, PingEvent, PlaybackEvent, PlaybackState, ErrorEvent, EventLogEntry, MessageData, MessageBuffer, RecordingStats, RegisteredMessage, ServerHandle, ServerState, ServerStats, LatencyHistogram, LatencyHistograms, Diagnostics, ShutdownProgress, get_server_state, get_recent_errors, set_recent_error_capacity, get_event_log, register_message, enable_python_logging, disable_python_logging, enable_signal_handling, get_shutdown_signal, connect_to, relay, replay, redis_bridge, kafka_sink, zmq_bridge
+from .quicksocket import QuicksocketError, ServerNotRunning, BindError, SendError, ConnectError, TlsError
@@ -0,0 +1,67 @@
+'''Async helpers for asyncio and trio (and anyio, running on either), so event-loop applications can await client messages without busy-polling or tying up a worker thread.
+
+They wait on the server's message descriptor (see Server.get_message_fd()) using whichever event loop is running, then drain without blocking:
+
+  async def handle_messages(server: quicksocket.Server):
+    async for msg in quicksocket.aio.messages(server):
+      handle(msg)
+
+The event loop is detected with sniffio if it's installed, and otherwise assumed to be asyncio. Sending doesn't block (the server queues messages), so the ordinary Server.send_messages() is fine to call from async code.'''
+import asyncio
+from typing import AsyncIterator, List, Optional, Union
+
+from .server import ClientMessage, MessageData, Server, ShutdownProgress
+
+def _current_async_library() -> str:
+  try:
+    import sniffio
+  except ImportError:
+    return 'asyncio'
+  library: str = sniffio.current_async_library()
+  return library
+
+async def _wait_readable(fd: int):
+  library = _current_async_library()
+  if library == 'trio':
+    import trio
+    await trio.lowlevel.wait_readable(fd)
+  elif library == 'asyncio':
+    loop = asyncio.get_running_loop()
+    readable = loop.create_future()
+    loop.add_reader(fd, lambda: readable.done() or readable.set_result(None))
+    try:
+      await readable
+    finally:
+      loop.remove_reader(fd)
+  else:
+    raise RuntimeError('quicksocket.aio supports asyncio and trio, not ' + library)
+
+async def wait_for_messages(server: Server):
+  '''Waits until client messages are pending or the server stops. Like the descriptor it waits on, it may also return with nothing pending.'''
+  await _wait_readable(server.get_message_fd())
+
+async def drain_client_messages(server: Server, max_messages: Optional[int] = None, structured: bool = False) -> Union[List[MessageData], List[ClientMessage]]:
+  '''Waits until at least one client message is pending, then drains them as Server.drain_client_messages() does. Returns an empty list once the server has stopped and no messages are left.'''
+  fd = server.get_message_fd()
+  while True:
+    client_msgs = server.drain_client_messages(max_messages = max_messages, structured = structured)
+    if client_msgs or not server.is_running():
+      return client_msgs
+    await _wait_readable(fd)
+
+async def messages(server: Server) -> AsyncIterator[MessageData]:
+  '''Yields client messages as they arrive, ending when the server stops: the async counterpart of Server.messages().'''
+  while True:
+    client_msgs = await drain_client_messages(server)
+    if not client_msgs:
+      return
+    for msg in client_msgs:
+      yield msg
+
+async def wait_for_shutdown(progress: ShutdownProgress):
+  '''Waits until a shutdown (from Server.stop(progress=True)) is done. The wait happens on a worker thread (the message descriptor is no use here, as it also wakes for messages).'''
+  if _current_async_library() == 'trio':
+    import trio
+    await trio.to_thread.run_sync(progress.wait)
+  else:
+    await asyncio.get_running_loop().run_in_executor(None, progress.wait)
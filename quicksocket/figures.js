@@ -0,0 +1,84 @@
+// figures.js
+//
+// Shows the figures sent by Server.send_figure() (see src/server/figures.rs for how they're sent), each in its own element, redrawn as new ones come in under its id:
+//
+//   const ws = new WebSocket("ws://localhost:8765");
+//   showFigures(ws, document.getElementById("figures"));
+//
+// Plotly figures are drawn with Plotly.react(), so plotly.js must be loaded (as window.Plotly, or passed as options.Plotly); images are shown as <img>s. Other messages are left to the socket's other listeners.
+
+const IMAGE_MAGIC = "QSFG";
+const IMAGE_HEADER_LEN = 8;
+const VERSION = 1;
+const FORMAT_PNG = 1;
+const PLOTLY_PREFIX = '{"topic":"figure",';
+
+// Returns {id, format: "plotly", figure (the figure's JSON, parsed)} or {id, format: "png", image (a Blob)}, or null if data (a message's data: a string, an ArrayBuffer, or a view of one) isn't a figure. Throws for an image of a newer version or format.
+export function decodeFigure(data) {
+  if (typeof data === "string") {
+    if (!data.startsWith(PLOTLY_PREFIX)) return null;
+    const message = JSON.parse(data);
+    return message.format === "plotly" ? {id: message.id, format: "plotly", figure: message.figure} : null;
+  }
+
+  const buffer = ArrayBuffer.isView(data) ? data.buffer : data;
+  const start = ArrayBuffer.isView(data) ? data.byteOffset : 0;
+  const length = data.byteLength;
+  if (!(buffer instanceof ArrayBuffer) || length < IMAGE_HEADER_LEN) return null;
+
+  const header = new DataView(buffer, start, IMAGE_HEADER_LEN);
+  const magic = String.fromCharCode(header.getUint8(0), header.getUint8(1), header.getUint8(2), header.getUint8(3));
+  if (magic !== IMAGE_MAGIC) return null;
+  if (header.getUint8(4) !== VERSION) throw new Error(`Unsupported figure version ${header.getUint8(4)}`);
+  if (header.getUint8(5) !== FORMAT_PNG) throw new Error(`Unsupported figure format ${header.getUint8(5)}`);
+  const idLength = header.getUint16(6, true);
+  if (length < IMAGE_HEADER_LEN + idLength) throw new Error(`Truncated figure: ${length} bytes`);
+
+  const idAt = start + IMAGE_HEADER_LEN;
+  const id = new TextDecoder().decode(new Uint8Array(buffer, idAt, idLength));
+  const image = new Blob([new Uint8Array(buffer, idAt + idLength, length - IMAGE_HEADER_LEN - idLength)], {type: "image/png"});
+  return {id, format: "png", image};
+}
+
+// Listens on ws (setting its binaryType to "arraybuffer") for figures, showing each in an element of container's, made for its id as it first comes in (a <div> with a data-figure-id attribute, which can be styled, or made beforehand to place it). Returns a function that stops listening.
+export function showFigures(ws, container, options = {}) {
+  ws.binaryType = "arraybuffer";
+  const urls = new Map();
+
+  const elementFor = (id, tag) => {
+    let element = Array.from(container.children).find((child) => child.dataset.figureId === id);
+    if (element === undefined) {
+      element = document.createElement("div");
+      element.dataset.figureId = id;
+      container.appendChild(element);
+    }
+    const showingImage = element.firstElementChild instanceof HTMLImageElement;
+    if (tag === "img" && !showingImage) element.replaceChildren(document.createElement("img"));
+    if (tag === "div" && showingImage) element.replaceChildren();
+    return element;
+  };
+
+  const onMessage = (event) => {
+    const figure = decodeFigure(event.data);
+    if (figure === null) return;
+    if (figure.format === "plotly") {
+      const Plotly = options.Plotly || window.Plotly;
+      if (Plotly === undefined) throw new Error("Showing Plotly figures needs plotly.js loaded");
+      const {data, layout, config} = figure.figure;
+      Plotly.react(elementFor(figure.id, "div"), data || [], layout || {}, config || {});
+    } else {
+      const img = elementFor(figure.id, "img").firstElementChild;
+      // (The last image's URL is let go, or every frame of an animated plot would be kept.)
+      if (urls.has(figure.id)) URL.revokeObjectURL(urls.get(figure.id));
+      const url = URL.createObjectURL(figure.image);
+      urls.set(figure.id, url);
+      img.src = url;
+    }
+  };
+
+  ws.addEventListener("message", onMessage);
+  return () => {
+    ws.removeEventListener("message", onMessage);
+    urls.forEach((url) => URL.revokeObjectURL(url));
+  };
+}
@@ -0,0 +1,65 @@
+// geometry.js
+//
+// Decodes the point clouds and meshes sent by Server.send_pointcloud() and Server.send_mesh() (see src/server/geometry.rs for the layout) into typed arrays, ready for WebGL or three.js:
+//
+//   ws.binaryType = "arraybuffer";
+//   ws.onmessage = (event) => {
+//     const geometry = decodeGeometry(event.data);
+//     if (geometry === null) return;  // some other message
+//     const buffer = new THREE.BufferGeometry();
+//     buffer.setAttribute("position", new THREE.BufferAttribute(geometry.positions, 3));
+//     if (geometry.colors) buffer.setAttribute("color", new THREE.BufferAttribute(geometry.colors, geometry.channels, true));
+//     if (geometry.faces) buffer.setIndex(new THREE.BufferAttribute(geometry.faces, 1));
+//   };
+//
+// The arrays are views of the message's buffer, not copies (bar on big-endian machines, or for a message in a view at an unaligned offset).
+
+const HEADER_LEN = 16;
+const VERSION = 1;
+const LITTLE_ENDIAN = new Uint8Array(new Uint32Array([1]).buffer)[0] === 1;
+
+// Returns {type: "pointcloud" or "mesh", count, positions (Float32Array, x, y, z per point), colors (Uint8Array, channels per point, or null), channels (0, 3 or 4), faces (Uint32Array, 3 vertex indices per triangle, or null)}, or null if data (an ArrayBuffer, or a view of one) isn't a point cloud or mesh. Throws for one that's truncated, or of a newer version.
+export function decodeGeometry(data) {
+  const buffer = ArrayBuffer.isView(data) ? data.buffer : data;
+  const start = ArrayBuffer.isView(data) ? data.byteOffset : 0;
+  const length = data.byteLength;
+  if (!(buffer instanceof ArrayBuffer) || length < HEADER_LEN) return null;
+
+  const header = new DataView(buffer, start, HEADER_LEN);
+  const magic = String.fromCharCode(header.getUint8(0), header.getUint8(1), header.getUint8(2), header.getUint8(3));
+  const type = {QSPC: "pointcloud", QSMS: "mesh"}[magic];
+  if (type === undefined) return null;
+  if (header.getUint8(4) !== VERSION) throw new Error(`Unsupported ${type} version ${header.getUint8(4)}`);
+  const channels = header.getUint8(5);
+  const count = header.getUint32(8, true);
+  const faceCount = header.getUint32(12, true);
+
+  const positionsAt = start + HEADER_LEN;
+  const restAt = positionsAt + count * 12;
+  const end = restAt + (type === "mesh" ? faceCount * 12 : count * channels);
+  if (length < end - start) throw new Error(`Truncated ${type}: ${length} bytes, expected ${end - start}`);
+
+  return {
+    type,
+    count,
+    positions: floats(buffer, positionsAt, count * 3),
+    colors: type === "pointcloud" && channels ? new Uint8Array(buffer, restAt, count * channels) : null,
+    channels: type === "pointcloud" ? channels : 0,
+    faces: type === "mesh" ? uint32s(buffer, restAt, faceCount * 3) : null,
+  };
+}
+
+// (Typed arrays need their offsets aligned, which they are unless the message is in a view at an odd offset; and they're in the machine's byte order, so big-endian machines read them one by one.)
+function floats(buffer, at, length) {
+  if (LITTLE_ENDIAN && at % 4 === 0) return new Float32Array(buffer, at, length);
+  const view = new DataView(buffer, at, length * 4), array = new Float32Array(length);
+  for (let i = 0; i < length; i++) array[i] = view.getFloat32(i * 4, true);
+  return array;
+}
+
+function uint32s(buffer, at, length) {
+  if (LITTLE_ENDIAN && at % 4 === 0) return new Uint32Array(buffer, at, length);
+  const view = new DataView(buffer, at, length * 4), array = new Uint32Array(length);
+  for (let i = 0; i < length; i++) array[i] = view.getUint32(i * 4, true);
+  return array;
+}
@@ -0,0 +1,88 @@
+'''Helpers for serving from Jupyter notebooks, where a server started by hand is easily left running: re-running its cell fails to bind the port the last run still holds, and restarting the kernel can leave it behind until the process goes.
+
+serve_widget() starts a server on a port the OS picks, shows it in the cell's output (an IFrame of its landing page, or a small JS client that shows the latest message it's sent), and looks after it: re-running the cell stops the server it started last time, under the same key, before starting the new one, and the kernel stops them all as it shuts down or restarts.
+
+  server = quicksocket.jupyter.serve_widget()
+  server.send_messages(['hello from the kernel'])
+
+IPython is only needed to show the widget; widget_html() gives its HTML for showing some other way.'''
+import atexit
+import html
+import json
+import threading
+from typing import Dict, Optional, Union
+
+from .server import Server
+
+_servers: Dict[str, Server] = {}
+_servers_lock = threading.Lock()
+
+def serve_widget(landing_page: Optional[str] = None, key: str = 'default', width: Union[int, str] = '100%', height: int = 300, host: Optional[str] = None, display: bool = True, **kwargs) -> Server:
+  '''Starts a Server on a port the OS picks (port = 0; pass port to choose one), waits for it to bind, and shows it in the cell's output, returning it. Keyword arguments are passed on to Server(), e.g. inspector=True.
+
+  With landing_page (HTML), it's served (see landing_page in Server.start()) and shown in an IFrame, width by height pixels, so the page's own script connects back to the server; without it, a small JS client is shown in its place, which connects and shows the latest message the server sends (a text message, or a binary one's size).
+
+  The browser connects to the server at host, or by default the host the notebook was loaded from (which, for a remote kernel, needs its port forwarded too).
+
+  A server started under the same key before (by an earlier run of the cell) is stopped first, so re-running a cell replaces its server rather than leaving it running; give cells that serve widgets side by side their own keys. Servers started here are stopped when the kernel shuts down or restarts, or by stop_widgets().
+
+  With display=False, nothing's shown (see widget_html()). Raises BindError if the server can't bind, and ImportError if display is True but IPython isn't installed.'''
+  stop_widgets(key)
+  kwargs.setdefault('port', 0)
+  server = Server(landing_page = landing_page, **kwargs)
+  server.start()
+  try:
+    server.wait_until_started()
+  except Exception:
+    server.stop(wait = True)
+    raise
+  with _servers_lock:
+    _servers[key] = server
+  if display:
+    from IPython.display import HTML, display as show
+    show(HTML(widget_html(server, iframe = landing_page is not None, width = width, height = height, host = host)))
+  return server
+
+def widget_html(server: Server, iframe: bool = False, width: Union[int, str] = '100%', height: int = 300, host: Optional[str] = None) -> str:
+  '''The HTML serve_widget() shows for a running server: an IFrame of its landing page if iframe is True, or otherwise the JS client (see serve_widget()).'''
+  port = server.get_bound_port()
+  if port is None:
+    raise ValueError("A widget needs a server that's bound its port (see Server.wait_until_started()).")
+  element_id = 'quicksocket-widget-{}'.format(port)
+  # (The host is filled in by the browser, which knows where the notebook came from.)
+  host_js = json.dumps(host) if host is not None else 'window.location.hostname'
+  if iframe:
+    width_attr = '{}px'.format(width) if isinstance(width, int) else width
+    return '''<iframe id="{id}" style="width: {width}; height: {height}px; border: 1px solid #ccc;"></iframe>
+<script>
+document.getElementById("{id}").src = "http://" + {host} + ":{port}/";
+</script>'''.format(id = element_id, width = html.escape(width_attr), height = height, host = host_js, port = port)
+  return '''<div id="{id}" style="font-family: monospace; white-space: pre-wrap; max-height: {height}px; overflow: auto;">Connecting to port {port}...</div>
+<script>
+(function() {{
+  const element = document.getElementById("{id}");
+  const ws = new WebSocket("ws://" + {host} + ":{port}/");
+  ws.onopen = () => {{ element.textContent = "Connected to port {port}."; }};
+  ws.onmessage = (event) => {{
+    element.textContent = typeof event.data === "string" ? event.data : "(" + event.data.size + " bytes of binary)";
+  }};
+  ws.onclose = () => {{ element.textContent += "\\n(Disconnected.)"; }};
+}})();
+</script>'''.format(id = element_id, height = height, host = host_js, port = port)
+
+def get_widget_server(key: str = 'default') -> Optional[Server]:
+  '''The server serve_widget() started under key, if it's still there.'''
+  with _servers_lock:
+    return _servers.get(key)
+
+def stop_widgets(key: Optional[str] = None):
+  '''Stops the server serve_widget() started under key, or with no key, all of them, waiting for their threads. Does nothing for a key with no server.'''
+  with _servers_lock:
+    keys = [key] if key is not None else list(_servers)
+    servers = [_servers.pop(key) for key in keys if key in _servers]
+  for server in servers:
+    if server.is_running():
+      server.stop(wait = True)
+
+# The kernel exits as it shuts down or restarts.
+atexit.register(stop_widgets)
@@ -0,0 +1,94 @@
+// metrics.js
+//
+// Decodes the metrics frames broadcast by Server.log_scalar() and Server.log_histogram() (see src/server/metrics.rs for the layout), and keeps each tag's points, for charting:
+//
+//   const ws = new WebSocket("ws://localhost:8765");
+//   const metrics = trackMetrics(ws, (tags) => tags.forEach((tag) => redraw(tag, metrics.scalars.get(tag))));
+//
+// Other messages are left to the socket's other listeners.
+
+const MAGIC = "QSMT";
+const HEADER_LEN = 12;
+const VERSION = 1;
+const SCALARS = 0;
+const HISTOGRAMS = 1;
+
+// Returns {history (true for the history a client's written as it connects), scalars: Map of tag to [{step, value}], histograms: Map of tag to [{step, min, max, counts (a Uint32Array)}]}, or null if data (a message's data: an ArrayBuffer, or a view of one) isn't a metrics frame. Throws for a frame of a newer version, or a truncated one.
+export function decodeMetrics(data) {
+  if (typeof data === "string") return null;
+  const buffer = ArrayBuffer.isView(data) ? data.buffer : data;
+  const start = ArrayBuffer.isView(data) ? data.byteOffset : 0;
+  const length = data.byteLength;
+  if (!(buffer instanceof ArrayBuffer) || length < HEADER_LEN) return null;
+
+  const view = new DataView(buffer, start, length);
+  const magic = String.fromCharCode(view.getUint8(0), view.getUint8(1), view.getUint8(2), view.getUint8(3));
+  if (magic !== MAGIC) return null;
+  if (view.getUint8(4) !== VERSION) throw new Error(`Unsupported metrics version ${view.getUint8(4)}`);
+
+  const frame = {history: view.getUint8(5) === 1, scalars: new Map(), histograms: new Map()};
+  const decoder = new TextDecoder();
+  let at = HEADER_LEN;
+  const need = (bytes) => {
+    if (at + bytes > length) throw new Error(`Truncated metrics frame: ${length} bytes`);
+  };
+  const f64 = () => { const value = view.getFloat64(at, true); at += 8; return value; };
+  const u32 = () => { const value = view.getUint32(at, true); at += 4; return value; };
+
+  const seriesCount = view.getUint32(8, true);
+  for (let series = 0; series < seriesCount; series++) {
+    need(2);
+    const tagLength = view.getUint16(at, true);
+    need(2 + tagLength + 5);
+    const tag = decoder.decode(new Uint8Array(buffer, start + at + 2, tagLength));
+    at += 2 + tagLength;
+    const type = view.getUint8(at);
+    at += 1;
+    const count = u32();
+    if (type === SCALARS) {
+      need(count * 16);
+      const points = [];
+      for (let i = 0; i < count; i++) points.push({step: f64(), value: f64()});
+      frame.scalars.set(tag, points);
+    } else if (type === HISTOGRAMS) {
+      const histograms = [];
+      for (let i = 0; i < count; i++) {
+        need(28);
+        const step = f64(), min = f64(), max = f64(), bins = u32();
+        need(bins * 4);
+        const counts = new Uint32Array(bins);
+        for (let bin = 0; bin < bins; bin++) counts[bin] = u32();
+        histograms.push({step, min, max, counts});
+      }
+      frame.histograms.set(tag, histograms);
+    } else {
+      throw new Error(`Unsupported metrics series type ${type}`);
+    }
+  }
+  return frame;
+}
+
+// Merges points into a tag's, in step order, a point replacing the one at its step (as one logged again at a step replaces it).
+function merge(store, tag, points) {
+  const merged = new Map((store.get(tag) || []).map((point) => [point.step, point]));
+  points.forEach((point) => merged.set(point.step, point));
+  store.set(tag, Array.from(merged.values()).sort((a, b) => a.step - b.step));
+}
+
+// Listens on ws (setting its binaryType to "arraybuffer") for metrics frames, keeping every tag's points in the returned object's scalars and histograms (Maps of tag to points, as decodeMetrics() gives them, in step order), and calling onUpdate, if given, with the tags each frame updated. The returned object's stop() stops listening.
+export function trackMetrics(ws, onUpdate = () => {}) {
+  ws.binaryType = "arraybuffer";
+  const metrics = {scalars: new Map(), histograms: new Map()};
+
+  const onMessage = (event) => {
+    const frame = decodeMetrics(event.data);
+    if (frame === null) return;
+    frame.scalars.forEach((points, tag) => merge(metrics.scalars, tag, points));
+    frame.histograms.forEach((histograms, tag) => merge(metrics.histograms, tag, histograms));
+    onUpdate([...frame.scalars.keys(), ...frame.histograms.keys()]);
+  };
+
+  ws.addEventListener("message", onMessage);
+  metrics.stop = () => ws.removeEventListener("message", onMessage);
+  return metrics;
+}
@@ -1,42 +1,1050 @@
-import traceback
-from typing import List, Union
+import enum
+import json
+import logging
+import time
+from typing import Any, Callable, Dict, Iterator, List, Optional, Union
 
-from .quicksocket import start_server as BACKEND_start_server
-from .quicksocket import is_server_running as BACKEND_is_server_running
-from .quicksocket import shutdown_server as BACKEND_shutdown_server
-from .quicksocket import drain_new_client_events as BACKEND_drain_new_client_events
-from .quicksocket import drain_client_messages as BACKEND_drain_client_messages
-from .quicksocket import try_send_messages as BACKEND_try_send_messages
+from .quicksocket import start_server_instance as BACKEND_start_server_instance
+from .quicksocket import drain_error_events as BACKEND_drain_error_events
+from .quicksocket import enable_python_logging as BACKEND_enable_python_logging
+from .quicksocket import disable_python_logging as BACKEND_disable_python_logging
+from .quicksocket import get_server_state as BACKEND_get_server_state
+from .quicksocket import enable_signal_handling as BACKEND_enable_signal_handling
+from .quicksocket import get_shutdown_signal as BACKEND_get_shutdown_signal
+from .quicksocket import get_recent_errors as BACKEND_get_recent_errors
+from .quicksocket import set_recent_error_capacity as BACKEND_set_recent_error_capacity
+from .quicksocket import get_event_log as BACKEND_get_event_log
+from .quicksocket import register_message as BACKEND_register_message
+from .quicksocket import connect_to as BACKEND_connect_to
+from .quicksocket import start_relay as BACKEND_start_relay
+from .quicksocket import start_replay as BACKEND_start_replay
+try:
+  from .quicksocket import start_redis_bridge as BACKEND_start_redis_bridge
+except ImportError:
+  # Built without the "redis" feature.
+  BACKEND_start_redis_bridge = None
+try:
+  from .quicksocket import start_kafka_sink as BACKEND_start_kafka_sink
+except ImportError:
+  # Built without the "kafka" feature.
+  BACKEND_start_kafka_sink = None
+try:
+  from .quicksocket import start_zmq_bridge as BACKEND_start_zmq_bridge
+except ImportError:
+  # Built without the "zmq" feature.
+  BACKEND_start_zmq_bridge = None
+from .quicksocket import ClientCapabilities, ClientHandle, ClientMessage, ClientStats, ClusterPeer, ConnectionEvent, Diagnostics, ErrorEvent, EventLogEntry, InputSnapshot, PingEvent, PlaybackEvent, PlaybackState, LatencyHistogram, LatencyHistograms, LoopbackClient as BACKEND_LoopbackClient, MessageBuffer, RecordingStats, RegisteredMessage, RelayHandle, RelayStats, ReplayHandle, ReplayStats, ServerHandle, ServerStats, ShutdownHandle, QuicksocketError, ServerNotRunning
+
+# A received client message's data: str (text), bytes (binary), or MessageBuffer (large binary, with zero-copy receive enabled).
+MessageData = Union[str, bytes, MessageBuffer]
+
+class ServerState(enum.Enum):
+  '''Where a server is in its lifecycle. See Server.get_state().'''
+  # Never started, or finished shutting down.
+  STOPPED = 'stopped'
+  # Binding its port (see Server.wait_until_started()).
+  STARTING = 'starting'
+  # Listening and serving clients.
+  RUNNING = 'running'
+  # No longer accepting connections: draining (see Server.begin_draining()), with the clients still connected served as before, or shutting down, with them being sent close frames and their connections wound down.
+  DRAINING = 'draining'
+  # Shutting down: the server's runtime is being torn down.
+  STOPPING = 'stopping'
+  # Couldn't bind its port, or its thread exited unexpectedly.
+  FAILED = 'failed'
+
+def get_server_state() -> ServerState:
+  '''The state of the server started with the module-level quicksocket.quicksocket.start_server().'''
+  return ServerState(BACKEND_get_server_state())
+
+def enable_python_logging(logger_name: str = 'quicksocket', level: int = logging.INFO):
+  '''Sends quicksocket's log output to logging.getLogger(logger_name) instead of stdout, so it shows up in the application's log handlers. Lines below level are discarded before they reach Python. Applies to all servers in the process.'''
+  BACKEND_enable_python_logging(logger_name = logger_name, level = level)
+
+def disable_python_logging():
+  '''Goes back to printing quicksocket's log output to stdout.'''
+  BACKEND_disable_python_logging()
+
+def enable_signal_handling():
+  '''Opts in to graceful shutdown on SIGINT/SIGTERM (Ctrl-C on Windows): all running servers are stopped, with close frames sent to their clients, and get_shutdown_signal() reports the signal. SIGINT still raises KeyboardInterrupt as usual, but SIGTERM no longer kills the process by itself, so headless scripts should exit once their servers stop:
+
+    quicksocket.enable_signal_handling()
+    server.start(9001)
+    for msg in server.messages(): # Ends when the server stops.
+      handle(msg)
+  '''
+  BACKEND_enable_signal_handling()
+
+def get_shutdown_signal() -> Optional[str]:
+  '''Returns "SIGINT" or "SIGTERM" if that signal has been received since enable_signal_handling(), or None.'''
+  signal_name: Optional[str] = BACKEND_get_shutdown_signal()
+  return signal_name
+
+def get_recent_errors(count: Optional[int] = None) -> List[ErrorEvent]:
+  '''Returns the most recent errors (by any server in the process), oldest first: the last count of them, or all that are kept (100 by default). Unlike Server.drain_error_events(), this doesn't consume them, so it's safe to call from e.g. a status page.'''
+  error_events: List[ErrorEvent] = BACKEND_get_recent_errors(count = count)
+  return error_events
+
+def set_recent_error_capacity(capacity: int):
+  '''Sets how many recent errors are kept for get_recent_errors(). Lowering it discards the oldest; 0 stops keeping them.'''
+  BACKEND_set_recent_error_capacity(capacity)
+
+def get_event_log(since_seq: Optional[int] = None) -> List[EventLogEntry]:
+  '''Returns the event log's entries (for every server in the process) after since_seq, or all that are kept (the last 1000), oldest first. Each has a seq, numbering the entries in order; a timestamp, as from time.time(); a level ("debug", "info", "warning" or "error"); a kind ("bind", "accept", "disconnect", "drain", "shutdown", or an error event's category); a message; and the client_id it concerns, or None. Nothing is consumed, so it can be read after the fact, e.g. for a post-mortem, or followed by passing the seq of the last entry seen.'''
+  entries: List[EventLogEntry] = BACKEND_get_event_log(since_seq = since_seq)
+  return entries
+
+def register_message(payload: Union[str, bytes, bytearray, memoryview]) -> RegisteredMessage:
+  '''Registers a payload to be sent over and over (a static asset, say, or the snapshot every new client is sent): str as a text message, bytes-like as a binary one. Put the returned RegisteredMessage in the lists given to the send methods (of any server) in the payload's place; its websocket frame was encoded once, here, so every send of it writes that same frame without copying or encoding it again. The payload is copied, so changing a buffer afterwards doesn't change the message.'''
+  registered: RegisteredMessage = BACKEND_register_message(payload)
+  return registered
+
+class ShutdownProgress:
+  '''Progress of a server shutdown, as returned by Server.stop(progress=True). Its properties are live, so it can be polled (e.g. to show "waiting for 3 clients...") or waited on.'''
+  def __init__(self, handle: ShutdownHandle):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  @property
+  def state(self) -> ServerState:
+    '''DRAINING and then STOPPING while the shutdown is underway (possibly RUNNING for a moment first, until the server thread sees the request), STOPPED once it's done.'''
+    return ServerState(self._handle.state)
+
+  @property
+  def done(self) -> bool:
+    '''Whether the server thread has finished shutting down, so it's safe to exit the process without cutting anything off.'''
+    done: bool = self._handle.done
+    return done
+
+  @property
+  def clients_at_shutdown(self) -> int:
+    '''Clients connected when the shutdown was requested.'''
+    clients: int = self._handle.clients_at_shutdown
+    return clients
+
+  @property
+  def clients_remaining(self) -> int:
+    '''Clients whose connections haven't closed yet.'''
+    clients: int = self._handle.clients_remaining
+    return clients
+
+  @property
+  def close_frames_sent(self) -> int:
+    '''Close frames written and flushed to clients so far.'''
+    frames: int = self._handle.close_frames_sent
+    return frames
+
+  @property
+  def close_acks_received(self) -> int:
+    '''Clients that have answered their close frame so far. (The others are disconnected once the close timeout is up.)'''
+    acks: int = self._handle.close_acks_received
+    return acks
+
+  @property
+  def aborted_tasks(self) -> List[str]:
+    '''What the shutdown aborted for still running at its deadline (see Server.stop()'s timeout_ms), e.g. "client 127.0.0.1:50312's sender task"; empty unless it had to.'''
+    tasks: List[str] = self._handle.aborted_tasks
+    return tasks
+
+  def wait(self, timeout_ms: Optional[int] = None) -> bool:
+    '''Blocks (releasing the GIL) until the shutdown is done and the server thread has been joined, returning True, or until timeout_ms elapses, returning False.'''
+    done: bool = self._handle.wait(timeout_ms = timeout_ms)
+    return done
+
+class LoopbackClient:
+  '''An in-process websocket client of a loopback server (see Server.connect_loopback()), for tests. Its messages go through the server's whole pipeline (connection events, routing, per-client sends) without a socket. Can be used as a context manager, which closes it on exit.'''
+  def __init__(self, client: BACKEND_LoopbackClient):
+    self._client = client
+
+  def __repr__(self) -> str:
+    return repr(self._client)
+
+  def __enter__(self) -> 'LoopbackClient':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.close()
+    return False
+
+  @property
+  def client_id(self) -> str:
+    '''The id the server knows this client by, as in its ConnectionEvents and ClientMessage.client_id, e.g. for Server.send_to_client().'''
+    client_id: str = self._client.client_id
+    return client_id
+
+  def send(self, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]]):
+    '''Sends messages to the server, in order: str as text, bytes-like as binary.'''
+    self._client.send(messages)
+
+  def recv(self, timeout_ms: Optional[int] = None) -> Optional[Union[str, bytes]]:
+    '''Blocks (releasing the GIL) until the next message from the server arrives, returning it (str for text, bytes for binary), or until timeout_ms elapses, returning None. Raises ServerNotRunning once the connection has closed, e.g. because the server stopped.'''
+    msg: Optional[Union[str, bytes]] = self._client.recv(timeout_ms = timeout_ms)
+    return msg
+
+  def close(self):
+    '''Closes the connection with a close handshake, so the server sees a clean disconnection. Does nothing if it's already closed.'''
+    self._client.close()
 
 class Server:
-  '''Wrapper around the quicksocket module that provides type annotations.'''
+  '''Wrapper around the quicksocket module that provides type annotations.
+
+  Each Server runs its own, independent server instance, so one process can serve on several ports at once (e.g. a public viewer port and a localhost control port).
+
+  Can also be used as a context manager, which starts the server (waiting for its port to be bound) on enter and shuts it down gracefully (close frames, drain, thread join) on exit, including on exceptions:
+
+    with quicksocket.Server(port=9001) as srv:
+      ...
+  '''
+
+  def __init__(self, port: Optional[int] = None, **options: Any):
+    '''Arguments given here are the defaults used by start() and by the context manager: the port, and any of start()'s other keyword arguments.'''
+    self.port = port
+    self.options: Dict[str, Any] = options
+    self._handle: Optional[ServerHandle] = None
+
+  def _started_handle(self, operation: str) -> ServerHandle:
+    if self._handle is None:
+      error = ServerNotRunning("Can't {}: the server hasn't been started.".format(operation))
+      error.operation = operation
+      raise error
+    return self._handle
+
+  def __enter__(self) -> 'Server':
+    if self.port is None:
+      raise ValueError('A port must be given to Server() to use it as a context manager.')
+    self.start()
+    self.wait_until_started()
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop(wait = True)
+    return False # Don't swallow exceptions.
+
+  def start(self, port: Optional[int] = None, **options: Any):
+    '''Starts the server on the given port. If inspector is True, a debug inspector page is served at http://localhost:<port>/inspector. Plain HTTP requests get landing_page (HTML) if provided, or a 426 Upgrade Required response otherwise.
+
+    If zero_copy_min_bytes is given, binary client messages of at least that size are received as MessageBuffer objects, which expose the received bytes through the buffer protocol (memoryview(msg), numpy.frombuffer(msg), ...) without copying them. Use msg.copy() to get bytes.
+
+    If loopback is True, the server doesn't bind its port (which is then only a label); clients connect in-process with connect_loopback() instead, so tests can run the whole pipeline without sockets or port clashes.
+
+    If io_uring is True, the server's sockets go through an io_uring (Linux 5.6 or later) instead of tokio's readiness polling, for servers with many connections; nothing else changes. Needs quicksocket built with the "uring" feature, and raises QuicksocketError otherwise. Where io_uring isn't available (e.g. a container that forbids it), the server fails to start, as if its port couldn't be bound.
+
+    proxy maps path prefixes to ws:// backend URLs, e.g. {'/legacy': 'ws://127.0.0.1:8000'}, so one port can front another websocket service too: connections to those paths (and below them) are relayed to the backend, with the rest of the path and the query appended to its URL. They never become this server's clients, so they don't appear in its events, messages or stats. A client whose backend can't be reached gets a 502 response. Raises ValueError for a route that can't work (a path not starting with '/', or a backend that isn't ws://).
+
+    cluster_peers makes the server a node of a cluster, for scaling out across processes or machines: it lists the other nodes' ws:// URLs, e.g. ['ws://10.0.0.2:9001', 'ws://10.0.0.3:9001'] (each node lists the others). Broadcasts (send_messages() and the like) then reach the clients of every node, not just this one's; messages sent to a single client stay on its node. node_id names this node within the cluster (one is made up if it isn't given), and nodes given a cluster_secret only link up with nodes given the same one. The links are kept up, and checked, in the background: see get_cluster_peers() for their health, and drain_error_events() for "cluster" errors when links are lost or refused. Raises ValueError for a peer that isn't a ws:// URL, and for a loopback cluster node.
+
+    If latency_histograms is True, the server keeps histograms of how long messages take at each stage of sending; see get_latency_histograms().
+
+    lag_policy says what happens when a client falls so far behind the broadcasts that 16 of them are queued for it. With 'drop' (the default), it misses the oldest: they're counted in get_stats() (messages_dropped, and per client in messages_missed_by_client) and recorded as "send" error events. With 'block', the send functions wait (with the GIL released) for the slowest client to catch up instead, for at most block_timeout_ms if it's given, after which the broadcast goes ahead as with 'drop'. Broadcasts from other cluster nodes never wait. Raises ValueError for any other policy.
+
+    Clients are written to as soon as there's something to send them, unless messages are arriving faster than that. Then, each client's writes are held back, for up to max_flush_delay_ms (1 ms by default) the longer the load lasts, so that more messages go out per write and flush (see socket_writes in get_stats()), rather than a burst costing a pair of syscalls per message per client. A client is back to being written to at once as soon as it's caught up. 0 never holds writes back; a negative delay raises ValueError.
+
+    With cork_ms (a few tenths of a millisecond, say), every write is held back at least that long, load or not, and takes whatever arrives meanwhile, like a corked TCP socket: for small updates that come too far apart to count as load, but close enough that a packet each is wasteful. Each message then waits up to cork_ms longer to go out. A negative cork raises ValueError.
+
+    If memory_budget_bytes is given, the server's queues hold at most that many payload bytes at once, so a burst of huge messages can't run the process out of memory: broadcasts and targeted sends count until they're written (a broadcast until every client has it), and received messages until they're drained. A send that would go over the budget raises SendError, and a client message that would is dropped, counted in get_stats() (messages_dropped and messages_over_budget) and recorded as a "receive" error event. get_stats() has queued_bytes (and peak_queued_bytes) whether or not there's a budget.
+
+    If max_outbound_bytes_per_sec is given, the server writes at most that many bytes a second to its clients, all of them together, so it can't saturate an uplink it shares with something more important. Writes beyond the rate wait their turn, and the messages behind them wait in the clients' queues, just as if the clients were slow: with lag_policy='drop', clients miss broadcasts when their queues fill up. After a quiet spell, up to outbound_burst_bytes go out at once (a tenth of a second's worth by default, and at least 64 KiB). get_stats() has the time writes have waited for the limit (rate_limit_wait_ns). Raises ValueError for a rate or burst of 0, and for a burst without a rate.
+
+    Each client can be kept to a rate of its own as well, so remote viewers on slow links get a throttled stream while the ones on the LAN get everything: client_bytes_per_sec limits every client, and client_bytes_per_sec_by_tag maps client tags to limits of their own, e.g. {'remote': 250000}. Clients start out untagged; set_client_tag() gives one a tag, and so its tag's limit (or client_bytes_per_sec, if the tag has none), from its next write on. Raises ValueError for a limit of 0.
+
+    The server runs on threads of its own: a worker per core, unless worker_threads says how many. To keep its latency steady while the rest of the process keeps the CPU busy (a training job, say), give it cores of its own with worker_cores, a list of core numbers counted from 0 (Linux only): its threads are pinned to them, with a worker per core unless worker_threads is given too. With isolate_cores=True, those cores are also taken away from the thread calling start(), and so from every thread it starts afterwards (they inherit its cores); start the server before the job's thread pools, and they leave its cores alone. Threads already running keep theirs. Raises ValueError for cores that don't exist, for isolation that would leave the calling thread no cores at all, and for worker_threads=0.
+
+    If ping_interval_ms is given, every client is pinged that often, so NAT gateways and firewalls don't drop idle connections, and clients that vanished without closing are noticed. A client that has left max_missed_pongs pings in a row (2 by default) unanswered when the next is due is disconnected: it's sent a close frame if it can still take one, counted in get_stats() (keepalive_timeouts), recorded as a "receive" error event, and reported disconnected like any other. The pongs also time each client's round trip (see get_client_stats()). With unhealthy_after_missed_pongs (fewer than max_missed_pongs), a client that has left that many pings in a row unanswered is marked unhealthy first, while it's still connected: it's reported with an "unhealthy" ConnectionEvent (see drain_connection_events()), so you can stop counting on it, and with a "healthy" one if it answers a ping before it's disconnected; get_client_stats() has its healthy flag meanwhile. Raises ValueError for an interval or max_missed_pongs of 0, an unhealthy_after_missed_pongs that isn't at least 1 and less than max_missed_pongs, and for either without an interval.
+
+    If idle_timeout_ms is given, a client that neither sends nor is sent a message (text or binary; keepalive pings don't count) for that long is sent a close frame and disconnected, so abandoned browser tabs don't keep their connections (and their share of every broadcast) forever. It's counted in get_stats() (idle_timeouts), and reported disconnected like any other. A timeout of 0 raises ValueError.
+
+    If ping_events is True, the pings and pongs clients send are reported as well, for applications that keep track of their clients' liveness themselves: see drain_ping_events() and send_ping(). Pongs answering the ping_interval_ms pings aren't.
+
+    If time_sync is True, clients can sync their clocks to the server's, as browsers can't see the server's clock otherwise: a client sends a text message 'time_sync:' followed by anything up to 256 bytes (its own clock reading, say), and is answered straight away, from the server's own threads, with '{"topic":"time_sync","echo":"...","server_time_us":...,"server_mono_us":...}', the server's clocks as the request was read (see send_stamped()). The requests aren't passed on to drain_client_messages(). With its clock's readings as it sent the request (t0) and got the answer (t3), a client reckons the server's clock is server_time_us - (t0 + t3) / 2 ahead of its own, give or take half the round trip.
+
+    If playback_control is True, clients can pause, resume, seek and change the speed of whatever the application plays back to them (a recording, a simulation), with the text messages 'playback:pause', 'playback:resume', 'playback:seek:<seconds>' (0 or more) and 'playback:speed:<rate>' (more than 0; 1 for real time). The server's own threads apply them to a playback state shared by every client, rather than passing them on to drain_client_messages(); the application reads where playback should be from get_playback_state(), and the controls as they come from drain_playback_events(). A malformed control changes nothing, and is recorded as a warning error event.
+
+    If chaos_seed is given, the server misbehaves on purpose, for checking that clients cope with a flaky network, e.g. that they reconnect and resync; never use it in production. Each write to a client drops the connection instead (without a close frame, as a network failure would) with a chance of chaos_drop_rate (0 to 1), is held back a random time up to chaos_max_delay_ms, and has its messages shuffled, none moving more than chaos_reorder_window places from where it was. Messages are never altered or lost but for a dropped connection's. It's random, but reproducible: the same seed makes the same decisions for each connection in turn (the first connection's, the second's, and so on), though which messages share a write still depends on timing. Raises ValueError for a drop rate outside 0 to 1, a negative delay, or the other chaos arguments without chaos_seed.
+
+    To try a frontend on a LAN as field users on distant, slow links see it, link_latency_ms delays every client's messages by that long, give or take up to link_jitter_ms (without ever reordering them), after sending them at link_bits_per_sec, if it's given: link_latency_ms=200, link_bits_per_sec=2_000_000 for a 200 ms, 2 Mbit/s link, say. Messages queue up behind a busy link as they would behind a slow client, so lag_policy applies. Pings and close frames aren't delayed. Raises ValueError for a negative latency or jitter, and for a bandwidth of 0.
+
+    If watchdog_stall_timeout_ms is given, a watchdog thread keeps an eye on the server's accept loop and on each client's sender task (which fans the broadcasts out to its client). One that's spent that long stuck in the middle of something, a deadlock say, or that panics, is recorded as a "critical" error event (see drain_error_events()), logged and counted among the errors in get_stats(). With watchdog_restart=True, stalled tasks are restarted as well: the accept loop starts over (a panicked one too, rather than taking the server down), and a client's sender task is cancelled, which disconnects the client so it can reconnect. A task stuck blocking its thread, rather than waiting, can only be reported. Slow clients hold up their sender tasks' writes, so set the timeout well above the longest write you expect. Raises ValueError for a timeout of 0, and for watchdog_restart without a timeout.
+
+    If heartbeat_interval_ms is given, the server broadcasts a heartbeat of its own that often, as JSON text: {"topic": "heartbeat", "seq": 42, "server_time_ms": 1700000000000, "clients": 3}, under heartbeat_topic instead of "heartbeat" if it's given. Heartbeats are sent from the server's own threads, whatever Python's doing, so a client that hears them but nothing else knows its connection is fine and it's your code that's gone quiet (busy, or stuck), while one that hears nothing at all knows its connection is dead. seq counts from 0 as the server starts, so clients can tell they missed some or that the server restarted; server_time_ms is the server's clock, in milliseconds since the Unix epoch; clients is how many clients are connected. They go to this server's clients only (not a cluster's other nodes), and are skipped rather than take the server over memory_budget_bytes. Raises ValueError for an interval of 0, and for heartbeat_topic without an interval.
+
+    max_topic_rates_hz caps how often samples sent with send_sample() go out on each of its topics, e.g. {'imu': 30} for a 1 kHz sensor that browsers only need to draw at 30 Hz. Samples that come too soon are held, and decimation says what goes out as the interval's up: 'drop' (the default), the latest; or 'average', their average, for samples that are JSON numbers, arrays of numbers or flat objects (whose numbers are averaged, and other values taken from the latest sample). Samples that can't be averaged go out as with 'drop'. Raises ValueError for a rate that isn't more than 0, or any other decimation.
+
+    default_client_topic_rates_hz caps how often each client is written the frames sent with send_frame() on each of its topics, e.g. {'camera': 5}: a frame that comes too soon after the last one the client was written waits (being replaced by newer ones, as for a slow client) until the interval's up. If topic_throttle_requests is True, clients can set their own rates too, so a phone can ask for 5 frames a second while desktops take every one: a client sends the text message 'throttle:<topic>:<rate>' (frames a second, more than 0), or 'throttle:<topic>:off' for every frame, which the server's own threads take rather than passing it on to drain_client_messages(). A malformed request is ignored, and recorded as a warning error event. Raises ValueError for a default rate that isn't more than 0.
+
+    sync_groups groups topics whose messages clients play back together, e.g. {'robot': ['camera', 'pose', 'imu']}: messages sent with send_synced() on a group's topics are tagged with a presentation timestamp, and go out in its order, so a browser can show a camera frame with the pose and IMU sample of the same moment. Each is held for sync_window_ms (50 by default) first, for those with earlier timestamps sent after it to catch up; one that comes after a message with a later timestamp has gone out is dropped, rather than going out of order. Raises ValueError for a topic in two groups, or a window less than 0.
+
+    capability_formats lets clients negotiate what they're sent, so the application can encode per audience rather than for the least capable client. As each client connects, it's sent the formats the application can encode, as JSON text ({"topic": "capabilities", "formats": ["webp", "jpeg"]}), and it can declare its capabilities, then or later, with a text message like 'capabilities:formats=webp,jpeg;max_resolution=1280x720;topics=camera,pose': the formats it decodes, in its order of preference; the largest resolution it wants; and the topics it wants (all of them if it doesn't say). Other keys are kept, for the application's own. The server's own threads take the declaration, rather than passing it on to drain_client_messages(): they answer with the format negotiated, the client's first that's offered ({"topic": "capabilities", "format": "webp"}, or null if there's none in common), and report a "capabilities" ConnectionEvent; get_client_capabilities() then has them. A malformed declaration is ignored, and recorded as a warning error event. Raises ValueError for an empty format, or one with a ',', ';', '=' or whitespace in it.
+
+    input_aggregation_ms aggregates clients' input, for interactive applications with many clients sending it at a high rate (cursor positions, slider values). A client sends the text message 'input:<name>:<value>' (e.g. 'input:cursor:0.25,0.75'), which the server's own threads take, rather than passing it on to drain_client_messages(), keeping each client's latest value of each input; every input_aggregation_ms, those sent since the last go to drain_input_snapshots() as one InputSnapshot. 100 clients moving their cursors at 60 Hz are then 1000 / input_aggregation_ms snapshots a second to handle, rather than 6000 messages. A malformed input (one without a name, or a client's 257th name) changes nothing, and is recorded as a warning error event. Raises ValueError for an interval that isn't more than 0.
+
+    If rosbridge is True, clients can speak the rosbridge v2 protocol, so ROS web tooling (roslibjs and what's built on it) can connect straight to the server. The server's own threads take the JSON ops a client subscribes to and unsubscribes from topics with ({"op": "subscribe", "topic": "/pose", "id": "subscribe:/pose:1"}), and its advertises and unadvertises, rather than passing them on to drain_client_messages(); publish_ros() publishes to the clients subscribed to a topic, and get_ros_subscriptions() says who they are. Clients' own publish ops ({"op": "publish", "topic": "/cmd_vel", "msg": {...}}) are passed on to drain_client_messages() as they are, for the application to route by their topic. Service calls and other ops the server doesn't do are answered with an error "status" op (and service calls with a failed "service_response"); a malformed op is answered the same way, and recorded as a warning error event. Subscriptions' throttle_rate, queue_length and compression are ignored.
+
+    Metrics logged with log_scalar() and log_histogram() are held for metrics_flush_ms (100 by default) for more to go out with them, then broadcast together as one compact binary frame on the reserved "metrics" topic, for quicksocket/metrics.js to chart. The server keeps each tag's last metrics_history points (1000 by default; 0 keeps none) and writes them to each client as it connects, so a dashboard opened mid-run charts the run so far. Raises ValueError for a flush interval less than 0.
+
+    With compression, clients that offer the permessage-deflate extension as they connect (as browsers do) are written the broadcasts' messages of compression_min_bytes or more (1024 by default) deflated, and can send theirs deflated too. Each broadcast message is deflated once, whatever the number of clients, by a pool of compression_threads threads of the server's own (2 by default), without the GIL; clients that didn't offer the extension are written the original. Messages sent to a single client and frames go out as they are. Raises ValueError for 0 threads, or for the other compression arguments without compression.
+
+    If audit_log_path is given, every websocket connection the server accepts or rejects, and every accepted one's closing, is appended to that file as a line of JSON, e.g. {"t": 1700000000.123456, "event": "accepted", "peer": "10.0.0.7:50000", "ip": "10.0.0.7", "path": "/feed", "principal": "alice", "reason": null}: event is "accepted", "rejected" or "closed", and reason says why a connection was rejected (a malformed upgrade, a failed handshake) or closed (the client's close frame, unanswered pings, an idle timeout, the server shutting down, or a lost connection). quicksocket doesn't authenticate anyone itself, so principal is whatever the request header named by audit_principal_header says (e.g. "X-Forwarded-User" from an authenticating proxy in front of the server), or None. The file is rotated when it would grow past audit_log_max_bytes (10 MiB by default): it's renamed audit_log_path + ".1", the previous .1 becomes .2, and so on, keeping audit_log_max_files of them (5 by default). Entries are written by a thread of the server's own, and are all in the file once the server has stopped; a failed write is recorded as an "audit" error event. Raises ValueError if the file can't be opened for appending, for a maximum size of 0, and for the other audit arguments without audit_log_path.
+
+    Arguments that aren't passed fall back to the ones given to Server(), and those to their defaults above. A stopped server can be started again, even straight after a stop() that didn't wait. Raises QuicksocketError if the server is already running, TypeError for an argument it doesn't take, or BindError if the port is invalid. The port is bound in the background; use wait_until_started() to wait for it, or to find out whether binding failed.'''
+    if self._handle is not None:
+      # Raises if the server is still running; otherwise waits for a stop() in progress to finish, so the port is free again.
+      self._handle._prepare_restart()
+    port = port if port is not None else self.port
+    if port is None:
+      raise ValueError('No port given to start() or Server().')
+    # (Arguments passed as None are taken as not passed, here and to Server().)
+    options = {name: value for given in (self.options, options) for name, value in given.items() if value is not None}
+    self._handle = BACKEND_start_server_instance(port, **options)
+
+  def connect_loopback(self) -> LoopbackClient:
+    '''Connects an in-process LoopbackClient to the server, which must have been started with loopback=True, and returns it once the websocket handshake is done:
 
-  def start(self, port: int):
-    BACKEND_start_server(port = port)
+      with quicksocket.Server(port=1, loopback=True) as srv, srv.connect_loopback() as client:
+        client.send(['ping'])
+        assert srv.drain_client_messages(timeout_ms = 1000) == ['ping']
+
+    Raises ServerNotRunning if the server isn't running, and QuicksocketError if it isn't a loopback server.'''
+    return LoopbackClient(self._started_handle('connect a loopback client').connect_loopback())
+
+  def wait_until_started(self, timeout_ms: Optional[int] = None) -> bool:
+    '''Blocks (releasing the GIL) until the server has bound its port, returning True, or until timeout_ms elapses, returning False. start() returns before the port is bound; this raises BindError (with .port, .address, .reason) if binding failed, e.g. because the port is already in use. Raises ServerNotRunning if the server was never started.'''
+    return self._started_handle('wait for the server to start').wait_until_started(timeout_ms = timeout_ms)
+
+  def wait_for_client(self, timeout_ms: Optional[int] = None) -> bool:
+    '''Blocks (releasing the GIL) until at least one client is connected, returning True, or until timeout_ms elapses or the server stops, returning False. Handy before sending a short burst of data:
+
+      server.start(9001)
+      if server.wait_for_client(timeout_ms = 5000):
+        server.send_messages(burst)
+
+    Waits for the port to be bound first, raising BindError if that failed. Raises ServerNotRunning if the server was never started.'''
+    return self._started_handle('wait for a client').wait_for_client(timeout_ms = timeout_ms)
+
+  def get_state(self) -> ServerState:
+    '''Returns where the server is in its lifecycle, so e.g. "still binding" (STARTING) can be told apart from "couldn't bind" (FAILED). is_running() is True for STARTING, RUNNING, DRAINING, and STOPPING.'''
+    if self._handle is None:
+      return ServerState.STOPPED
+    return ServerState(self._handle.get_state())
 
   def is_running(self) -> bool:
-    running = BACKEND_is_server_running()
+    if self._handle is None:
+      return False
+    running = self._handle.is_running()
     return running
 
-  def stop(self):
-    BACKEND_shutdown_server()
+  def get_bound_port(self) -> Optional[int]:
+    '''Returns the port the server is listening on once it's bound (see wait_until_started()): the one it was started with, or the one the OS picked if that was 0, e.g. for tests that mustn't clash over ports. None before that, and for loopback servers.'''
+    if self._handle is None:
+      return None
+    bound_port: Optional[int] = self._handle.bound_port
+    return bound_port
+
+  def is_client_connected(self, client_id: str) -> bool:
+    '''Returns whether a client with this id (from its ConnectionEvents, or ClientMessage.client_id) is connected, so send_to_client() can reach it. A client is connected by the time its "connected" event is drained.'''
+    if self._handle is None:
+      return False
+    connected: bool = self._handle.is_client_connected(client_id)
+    return connected
+
+  def set_client_tag(self, client_id: str, tag: Optional[str]) -> bool:
+    '''Tags a connected client, or untags it with None. A tag is any label you like (e.g. 'lan' or 'remote'); it gives the client its rate limit from client_bytes_per_sec_by_tag (or client_bytes_per_sec, if the tag has none) from its next write on. Returns False if no client with this id is connected.
+
+      for event in server.drain_connection_events():
+        if event.kind == 'connected' and not event.client_id.startswith('192.168.'):
+          server.set_client_tag(event.client_id, 'remote')'''
+    tagged: bool = self._started_handle('tag a client').set_client_tag(client_id, tag)
+    return tagged
+
+  def get_client_tag(self, client_id: str) -> Optional[str]:
+    '''Returns a connected client's tag (see set_client_tag()), or None if it has none or isn't connected.'''
+    if self._handle is None:
+      return None
+    tag: Optional[str] = self._handle.get_client_tag(client_id)
+    return tag
+
+  def get_client_stats(self, client_id: str) -> Optional[ClientStats]:
+    '''Returns a snapshot of a connected client's stats, or None if it isn't connected: its tag, the broadcast messages_missed by it, and its link's round trip as timed by the keepalive pings (so only if the server was started with ping_interval_ms, and once the client's answered one), in milliseconds: the latest one (rtt_ms), and one smoothed over the last few pings (smoothed_rtt_ms; a better guide to the link's quality, as it doesn't swing with every ping); and whether it's healthy (False while it's marked unhealthy for missing pongs; see unhealthy_after_missed_pongs).
+
+      stats = server.get_client_stats(client_id)
+      if stats is not None and stats.smoothed_rtt_ms is not None and stats.smoothed_rtt_ms > 200:
+        print('{} is lagging ({:.0f} ms)'.format(client_id, stats.smoothed_rtt_ms))'''
+    if self._handle is None:
+      return None
+    stats: Optional[ClientStats] = self._handle.get_client_stats(client_id)
+    return stats
+
+  def get_client_capabilities(self, client_id: str) -> Optional[ClientCapabilities]:
+    '''Returns the capabilities a connected client declared (see capability_formats in start()), or None if it hasn't declared any, or isn't connected: the formats it decodes, in its order of preference; the format negotiated (its first that's offered, or None if there's none in common); the largest resolution it wants (max_width and max_height, or None); the topics it wants (or None for all of them; wants(topic) checks); and any other keys it declared (extra, {key: value}).'''
+    if self._handle is None:
+      return None
+    capabilities: Optional[ClientCapabilities] = self._handle.get_client_capabilities(client_id)
+    return capabilities
+
+  def get_all_client_capabilities(self) -> Dict[str, ClientCapabilities]:
+    '''Returns the capabilities of every connected client that's declared them, {client_id: ClientCapabilities}, for encoding once per audience rather than for the least capable client:
+
+      by_format = {}
+      for client_id, capabilities in server.get_all_client_capabilities().items():
+        if capabilities.wants('camera'):
+          by_format.setdefault(capabilities.format, []).append(client_id)
+      for format, client_ids in by_format.items():
+        encoded = encode(image, format)
+        for client_id in client_ids:
+          server.send_to_client(client_id, [encoded])'''
+    if self._handle is None:
+      return {}
+    capabilities: Dict[str, ClientCapabilities] = self._handle.get_all_client_capabilities()
+    return capabilities
+
+  def get_ros_subscriptions(self) -> Dict[str, List[str]]:
+    '''Returns the topics connected clients are subscribed to over rosbridge (see rosbridge in start()), and the clients subscribed to each: {topic: [client_id, ...]}, e.g. to publish only the topics someone's watching.'''
+    if self._handle is None:
+      return {}
+    subscriptions: Dict[str, List[str]] = self._handle.get_ros_subscriptions()
+    return subscriptions
+
+  def begin_draining(self):
+    '''Stops accepting connections, while the clients already connected go on being served as before, for zero-downtime rollouts behind a load balancer: the port is closed, so the balancer's health checks take the server out of rotation (and the server replacing it can bind the port), and the state becomes DRAINING. Once the last client has disconnected, a "drained" ConnectionEvent (with an empty client_id) is reported, at once if there were none; or wait for it with wait_until_drained(). The server keeps running until it's stopped:
+
+      server.begin_draining()
+      server.wait_until_drained(timeout_ms = 60000)
+      server.stop(wait = True, close_code = 1012, close_reason = "Restarting")
+
+    Draining a server that's draining or stopped does nothing. Raises ServerNotRunning if the server was never started.'''
+    self._started_handle('drain the server').begin_draining()
+
+  def wait_until_drained(self, timeout_ms: Optional[int] = None) -> bool:
+    '''Blocks (releasing the GIL) until the server is draining (see begin_draining()) with no clients left, or has stopped, returning True, or until timeout_ms elapses, returning False. Raises ServerNotRunning if the server was never started.'''
+    drained: bool = self._started_handle('wait for the server to drain').wait_until_drained(timeout_ms = timeout_ms)
+    return drained
+
+  def stop(self, wait: bool = False, progress: bool = False, close_code: Optional[int] = None, close_reason: Optional[str] = None, close_timeout_ms: Optional[int] = None, timeout_ms: Optional[int] = None) -> Optional[ShutdownProgress]:
+    '''Requests server shutdown. Connected clients are sent close frames, and each one's connection is closed once it has answered its frame, or once close_timeout_ms (2000 by default) is up. If wait is True, blocks until the server thread has exited. Raises ServerNotRunning if the server was never started.
+
+    The close frames have close_code and close_reason, 1001 (going away) and "Server shutting down" by default; e.g. a server about to be restarted can tell its clients so, and to come back soon:
+
+      server.stop(wait = True, close_code = 1012, close_reason = "Restarting")
+
+    Raises ValueError for a code the server can't send (only 1000-1003, 1007-1014 and 3000-4999 are allowed) and a reason over 123 bytes of UTF-8.
+
+    If timeout_ms is given, whatever the server thread still has running that long into the shutdown (a connection wedged mid-write, say) is aborted, so the process can't hang on it; by default it's given close_timeout_ms and a second more. Aborted tasks are recorded as an "internal" error event, and listed in ShutdownProgress.aborted_tasks.
+
+    If the server was already asked to stop, the close frame and timeouts asked for then are the ones that count.
+
+    With progress=True, returns a ShutdownProgress for following the shutdown (clients remaining, close frames sent and answered, whether it's done) or waiting for it, instead of None.'''
+    handle: Optional[ShutdownHandle] = self._started_handle('shut down the server').shutdown(wait = wait, progress = progress, close_code = close_code, close_reason = close_reason, close_timeout_ms = close_timeout_ms, timeout_ms = timeout_ms)
+    return ShutdownProgress(handle) if handle is not None else None
+
+  def get_stats(self) -> ServerStats:
+    '''Returns a snapshot of server statistics: uptime_secs, total_connections, current_clients, messages/bytes sent and received, messages_dropped (by reason in messages_dropped_by_reason, {reason: count}, for "lagged", "over_budget", "disconnected" and "shutdown"; and messages_missed_by_client, {client_id: count} for the connected clients that fell behind the broadcasts), warning/error counts, and the cumulative nanoseconds spent encoding outbound frames, waiting in the send channels, writing to sockets, and waiting for slow clients with lag_policy='block' (serialization_ns, channel_wait_ns, socket_write_ns, broadcast_wait_ns), the number of writes to clients' sockets (socket_writes), and the payload bytes in the server's queues (queued_bytes, peak_queued_bytes, and messages_over_budget for those turned away by memory_budget_bytes), the nanoseconds writes have waited for max_outbound_bytes_per_sec (rate_limit_wait_ns), and the clients disconnected for not answering keepalive pings (keepalive_timeouts) or for being idle (idle_timeouts), the frames clients skipped for newer ones (frames_skipped; see send_frame()), the times clients were sent a keyframe again to catch up on a keyframe stream (keyframes_resent; see send_keyframe()), the samples on rate-limited topics that didn't go out on their own (samples_decimated; see send_sample()), and the messages on sync groups' topics dropped for coming too late to go out in order (synced_late; see send_synced()).'''
+    return self._started_handle('get server stats').get_stats()
+
+  def get_latency_histograms(self) -> Optional[LatencyHistograms]:
+    '''Returns a snapshot of how long sent messages have taken at each stage, or None if the server wasn't started with latency_histograms=True, to tell where latency spikes come from:
+
+      enqueue:      the send call handing them to the server (your code's side; for a cluster node's broadcasts, that includes queuing them for its peers)
+      fan_out:      waiting in the channel until each client's sender task picks them up (behind the client's earlier messages)
+      socket_write: encoding them and writing them to the client's socket (including waiting for room in its buffer: the network's side)
+
+    Each is a LatencyHistogram: count, total_ns and max_ns, buckets (a list of (upper_bound_ns, count), with bounds doubling from 1 µs, the last one None), and quantile_ns(fraction), e.g. quantile_ns(0.99) for the 99th percentile (to within a bucket). Each message of a batch counts once, and for fan_out and socket_write, once per client. The histograms only grow; compare two snapshots' buckets for the latencies in between.'''
+    histograms: Optional[LatencyHistograms] = self._started_handle('get latency histograms').get_latency_histograms()
+    return histograms
+
+  def get_diagnostics(self) -> Diagnostics:
+    '''Returns a snapshot of what the server holds right now, for telling whether a long-running server leaks: open_sockets (accepted connections not yet closed), tasks (its running tasks: two per client, one per connection being routed, one per link to a cluster peer), connected_clients and registered_clients (the same, but for a moment as a client comes or goes), broadcast_subscribers, queues ({name: (queued, capacity)} for "broadcasts", "targeted_sends", "client_messages", "connection_events" and "ping_events"), pending_frames (see send_frame()), and queued_bytes, pooled_buffer_bytes and heap_bytes (roughly the heap the queues hold). Once every client has gone, a server should be back where it started; compare snapshots taken at quiet moments for anything that keeps growing.'''
+    diagnostics: Diagnostics = self._started_handle('get diagnostics').get_diagnostics()
+    return diagnostics
+
+  def get_cluster_node_id(self) -> Optional[str]:
+    '''Returns this node's id within its cluster (the node_id given to start(), or the one made up for it), or None if the server isn't a cluster node.'''
+    if self._handle is None:
+      return None
+    node_id: Optional[str] = self._handle.cluster_node_id
+    return node_id
+
+  def get_cluster_peers(self) -> List[ClusterPeer]:
+    '''Returns a ClusterPeer for each of the other nodes in cluster_peers, in that order: its url and node_id, the link's state ("connecting", "up", or "down") and since when (as from time.time()), the broadcasts sent to, received from and dropped for it, the link's rtt_ms, and its last_error. Empty if the server isn't a cluster node.'''
+    peers: List[ClusterPeer] = self._started_handle('get cluster peers').get_cluster_peers()
+    return peers
+
+  def start_recording(self, path: str, format: str = 'jsonl'):
+    '''Starts recording the session to the file at path (created, or truncated), for auditing or analysing it offline: every text and binary message the clients send, and every one sent to them, with its time, direction and client. Broadcasts are recorded once, with no client, however many clients they reach. With format='jsonl', each message is a line of JSON:
+
+      {"t": 1700000000.123456, "dir": "in", "client": "127.0.0.1:50000", "type": "text", "data": "hello"}
+
+    t is seconds since the epoch (as from time.time()), dir is "in" (from a client) or "out" (to clients), client is None for broadcasts, and data is the text, or for "binary" messages, the bytes in base64. format='binary' writes length-prefixed records instead, which are smaller and quicker to write for binary-heavy sessions (see the README).
+
+    The server never waits for the file: messages the writer can't keep up with are left out, and counted in RecordingStats.dropped. Recording goes on until stop_recording(), or until the server stops. Raises ValueError for an unknown format, QuicksocketError if a recording is in progress already or the file can't be created, and ServerNotRunning if the server isn't running.'''
+    self._started_handle('start recording').start_recording(path, format = format)
+
+  def stop_recording(self) -> Optional[RecordingStats]:
+    '''Stops the recording in progress, and returns its RecordingStats (path, format, messages recorded and dropped, bytes_written, and the error writing the file failed with, if it did) once everything recorded is in the file. None if nothing was being recorded; a recording stops by itself when the server does.'''
+    if self._handle is None:
+      return None
+    stats: Optional[RecordingStats] = self._handle.stop_recording()
+    return stats
+
+  def get_recording_stats(self) -> Optional[RecordingStats]:
+    '''Returns the RecordingStats of the recording in progress, or None if nothing's being recorded.'''
+    if self._handle is None:
+      return None
+    stats: Optional[RecordingStats] = self._handle.get_recording_stats()
+    return stats
 
   def drain_new_client_events(self) -> List[str]:
-    new_client_events: List[str] = BACKEND_drain_new_client_events()
+    if self._handle is None:
+      return []
+    new_client_events: List[str] = self._handle.drain_new_client_events()
     # for new_client in new_client_events:
     #   print('Drained new client event: {}'.format(new_client))
     
     return new_client_events
   
-  def drain_client_messages(self) -> List[Union[str, bytes]]:
-    client_msgs: List[Union[str, bytes]] = BACKEND_drain_client_messages()
+  def drain_connection_events(self) -> List[ConnectionEvent]:
+    '''Returns a ConnectionEvent (client_id, timestamp, kind: "connected" or "disconnected", "unhealthy" and "healthy" with unhealthy_after_missed_pongs, or "capabilities" with capability_formats) for each client that connected, disconnected, changed health or declared its capabilities since the last call, oldest first; and, after the last disconnection of a draining server (see begin_draining()), one of kind "drained" with an empty client_id. Draws from the same queue as drain_new_client_events(), so use one or the other.
+
+    A disconnection's close_code and close_reason are those of the close frame the client sent, to tell a browser tab that was closed (1001, going away) or a page that closed its socket (1000, normal closure, or its own code) from a client that broke off over a protocol error (1002, and the like). They're None if the client sent none: its connection dropped, or the server closed it first.
+
+      for event in server.drain_connection_events():
+        if event.kind == 'disconnected' and event.close_code not in (1000, 1001):
+          print('{} disconnected abnormally: {} {}'.format(event.client_id, event.close_code, event.close_reason))'''
+    if self._handle is None:
+      return []
+    connection_events: List[ConnectionEvent] = self._handle.drain_connection_events()
+    return connection_events
+
+  def drain_ping_events(self) -> List[PingEvent]:
+    '''Returns a PingEvent (client_id, timestamp, kind: "ping" or "pong", and the frame's payload, as bytes) for each ping or pong clients have sent since the last call, oldest first, if the server was started with ping_events=True; otherwise, an empty list. Pings have been answered already. Up to 1024 are kept between calls; later ones are dropped until there's room.'''
+    if self._handle is None:
+      return []
+    ping_events: List[PingEvent] = self._handle.drain_ping_events()
+    return ping_events
+
+  def get_playback_state(self) -> Optional[PlaybackState]:
+    '''Returns where clients' playback is, as a PlaybackState, if the server was started with playback_control=True; otherwise (or if it isn't running), None. It has whether it's paused, its position (in seconds, as of this call: it moves on at its speed while playing), its speed (1.0 for real time), how many changes clients have made, and the client_id of the last to make one. Playback starts out playing, from 0 at speed 1.0, as the server starts.'''
+    if self._handle is None:
+      return None
+    state: Optional[PlaybackState] = self._handle.get_playback_state()
+    return state
+
+  def drain_playback_events(self) -> List[PlaybackEvent]:
+    '''Returns a PlaybackEvent (client_id, timestamp, control: "pause", "resume", "seek" or "speed", its value: the position or speed, or None, and the state it left playback in) for each playback control clients have sent since the last call, oldest first, if the server was started with playback_control=True; otherwise, an empty list. Up to 1024 are kept between calls; later ones are dropped until there's room (though they still change the state).'''
+    if self._handle is None:
+      return []
+    playback_events: List[PlaybackEvent] = self._handle.drain_playback_events()
+    return playback_events
+
+  def drain_input_snapshots(self) -> List[InputSnapshot]:
+    '''Returns an InputSnapshot (timestamp, inputs: each client's latest value of each input it sent over the interval, {client_id: {name: value}}, and updates: how many input messages went into it) for each input_aggregation_ms interval since the last call in which clients sent input, oldest first, if the server was started with input_aggregation_ms; otherwise, an empty list. Up to 1024 are kept between calls; after that, input goes on being merged until there's room. For many clients' cursors:
+
+      cursors = {}
+      for snapshot in server.drain_input_snapshots():
+        for client_id, inputs in snapshot.inputs.items():
+          if 'cursor' in inputs:
+            cursors[client_id] = inputs['cursor']'''
+    if self._handle is None:
+      return []
+    input_snapshots: List[InputSnapshot] = self._handle.drain_input_snapshots()
+    return input_snapshots
+
+  def drain_error_events(self) -> List[ErrorEvent]:
+    '''Returns an ErrorEvent for each error recorded (by any server in the process) since the last call, oldest first. Its timestamp is as from time.time(); severity is "warning", "error", or "critical" (one of the server's own tasks panicked, which its message explains, or stalled; see watchdog_stall_timeout_ms); category is one of "bind", "http", "handshake", "send", "receive", "callback", "proxy", "redis", "kafka", "zmq", "cluster", "otel", "recording", "audit", or "internal"; client_id is None for errors that don't concern a particular client; backtrace is a panic's backtrace, if the RUST_BACKTRACE environment variable is set, and None otherwise.'''
+    error_events: List[ErrorEvent] = BACKEND_drain_error_events()
+    return error_events
+
+  def drain_client_messages(self, timeout_ms: Optional[int] = None, max_messages: Optional[int] = None, structured: bool = False) -> Union[List[MessageData], List[ClientMessage]]:
+    '''Returns all pending client messages. If timeout_ms is given and none are pending, blocks (releasing the GIL) until at least one arrives or the timeout elapses. If max_messages is given, returns at most that many; the rest stay queued for the next call.
+
+    Messages are returned as their data (str or bytes), or with structured=True, as ClientMessage objects carrying the data along with the client_id that sent it and its arrival time (timestamp, as from time.time(), and monotonic_timestamp, as from time.monotonic(), for measuring latency).
+
+    Safe to call from several threads at once; each message is returned to exactly one of them. If another thread is already waiting for messages, this returns once timeout_ms elapses (or immediately, without a timeout) rather than waiting behind it.'''
+    if self._handle is None:
+      return []
+    client_msgs: Union[List[MessageData], List[ClientMessage]] = self._handle.drain_client_messages(timeout_ms = timeout_ms, max_messages = max_messages, structured = structured)
     return client_msgs
 
-  def send_messages(self, messages: List[Union[str, bytes]]):
-    '''If you have more than one message to send, best to send as many of them as you can to the library at once, so any synchronization overhead isn't eaten more than is necessary.'''
-    try:
-      BACKEND_try_send_messages(messages)
-      # print("Successfully sent messages!")
-    except BaseException as e:
-      print('Exception trying to send messages {}'.format(e))
-      print('Traceback: {}'.format(traceback.print_tb(e.__traceback__)))
+  def messages(self, timeout_ms: Optional[int] = None) -> Iterator[MessageData]:
+    '''Returns an iterator yielding client messages as they arrive (blocking, with the GIL released, between items). Iteration ends when the server stops, or when no message arrives for timeout_ms, if given:
+
+      for msg in server.messages():
+        handle(msg)
+    '''
+    if self._handle is None:
+      return iter(())
+    return self._handle.messages(timeout_ms = timeout_ms)
+
+  def get_message_fd(self) -> int:
+    '''Returns a file descriptor that becomes readable when client messages are pending, or when the server stops, so an event loop can wait for messages without polling: wait until it's readable, then drain_client_messages() (without a timeout), and repeat. See quicksocket.aio for async helpers built on it.
+
+    The descriptor belongs to the server: don't read from it or close it. It can become readable with nothing left to drain (e.g. when a message callback took the messages), so an empty drain just means waiting again. Unix only; raises QuicksocketError elsewhere.'''
+    message_fd: int = self._started_handle('get a message descriptor').get_message_fd()
+    return message_fd
+
+  def set_on_message(self, callback: Optional[Callable[[MessageData], None]]):
+    '''Registers a callback invoked (on a dedicated thread, holding the GIL only during the call) with each client message as it arrives, instead of polling drain_client_messages(). Pass None to go back to draining.'''
+    self._started_handle('set a message callback').set_on_message(callback)
+
+  def send_messages(self, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]]):
+    '''Messages may be str (text) or bytes, bytearray, memoryview, or any other C-contiguous buffer-protocol object (binary), or RegisteredMessages (see register_message()) for payloads sent over and over.
+
+    If you have more than one message to send, best to send as many of them as you can to the library at once, so any synchronization overhead isn't eaten more than is necessary.
+
+    Raises ServerNotRunning if the server isn't running and SendError if the messages couldn't be handed to the server. Sending with no clients connected isn't an error; the messages just go nowhere.'''
+    self._started_handle('send messages').try_send_messages(messages)
+
+  def send_stamped(self, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]]):
+    '''As send_messages(), stamping the messages with the server's clocks as they're sent, for clients that line telemetry up on the server's timeline: server_time_us (microseconds since the Unix epoch) and server_mono_us (microseconds on a monotonic clock, which never goes back, for measuring intervals).
+
+    A str that's a JSON object has the times spliced in as its first fields: '{"x": 1}' goes out as '{"server_time_us":1700000000000000,"server_mono_us":1234567,"x": 1}'. Any other str is wrapped in a JSON object with them, as a string under "data". Binary payloads are prefixed with 16 bytes: the two times, as big-endian unsigned 64-bit integers. See time_sync in start() for syncing clients' clocks to the server's.'''
+    self._started_handle('send messages').send_stamped(messages)
+
+  def send_sample(self, topic: str, message: Union[str, bytes, bytearray, memoryview, RegisteredMessage]):
+    '''Sends a sample under topic to all clients, at most at the topic's rate in max_topic_rates_hz (see start()), so a high-rate stream can be published sample by sample without overwhelming browsers:
+
+      for reading in sensor:  # 1 kHz
+        server.send_sample('imu', json.dumps(reading))  # with max_topic_rates_hz = {'imu': 30}
+
+    The first sample goes out straight away. One that comes sooner after the last one sent is held until the interval's up, and then goes out, unless a later one replaced it (with decimation='drop'), or it's averaged with the later ones (with 'average'). Samples that didn't go out on their own are counted in get_stats() (samples_decimated). They never wait for slow clients, whatever the lag_policy, and aren't relayed to other cluster nodes. On a topic without a rate, a sample's sent as by send().
+
+    Raises ServerNotRunning if the server isn't running.'''
+    self._started_handle('send a sample').send_sample(topic, message)
+
+  def send_synced(self, topic: str, message: Union[str, bytes, bytearray, memoryview, RegisteredMessage], pts_us: Optional[int] = None):
+    '''Sends a message under topic, one of a group's in sync_groups (see start()), to all clients, tagged with its presentation timestamp: pts_us, in microseconds on whatever clock the group's streams share (their capture time, say), or, if it's None, the server's monotonic clock as it's sent (see send_stamped()).
+
+      server.send_synced('camera', jpeg, pts_us = captured_us)
+      server.send_synced('pose', json.dumps(pose), pts_us = captured_us)
+
+    A str that's a JSON object has the tags spliced in as its first fields: '{"x": 1}' goes out as '{"sync_group":"robot","sync_topic":"pose","pts_us":1234567,"x": 1}'. Any other str is wrapped in a JSON object with them, as a string under "data". Binary payloads are prefixed with the timestamp, as a big-endian unsigned 64-bit integer, then the topic's length in bytes, as a big-endian unsigned 16-bit integer, then the topic.
+
+    A group's messages go out in timestamp order, each sync_window_ms after it's sent, so every client gets them in that order. One sent after a message with a later timestamp has gone out is dropped, and counted in get_stats() (synced_late). They never wait for slow clients, whatever the lag_policy, and aren't relayed to other cluster nodes.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if topic isn't in a sync group.'''
+    self._started_handle('send a synced message').send_synced(topic, message, pts_us = pts_us)
+
+  def publish_ros(self, topic: str, msg: Union[str, Dict[str, Any]]) -> int:
+    '''Publishes a ROS message on topic to the clients subscribed to it over rosbridge (see rosbridge in start()), as {"op": "publish", "topic": topic, "msg": msg}, and returns how many clients it was queued for. msg is the message, as a dict (which is encoded as JSON) or a str of a JSON object:
+
+      server.publish_ros('/pose', {'position': {'x': 1.0, 'y': 2.0, 'z': 0.0}, 'orientation': {'x': 0.0, 'y': 0.0, 'z': 0.0, 'w': 1.0}})
+
+    A subscriber whose queue is full misses the message, and it's counted as lagged in get_stats(). Never blocks.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if it wasn't started with rosbridge, or msg isn't a JSON object.'''
+    if not isinstance(msg, str):
+      msg = json.dumps(msg)
+    queued: int = self._started_handle('publish a ROS message').publish_ros(topic, msg)
+    return queued
+
+  def send_frame(self, topic: str, message: Union[str, bytes, bytearray, memoryview, RegisteredMessage]):
+    '''Sends a frame to all clients under topic, latest-frame-only: for camera frames, plots and other streams where only the newest payload matters. Each client has room for one frame per topic; if it hasn't been written the topic's previous frame yet (it's slow, or so is its link), that frame is skipped and this one written in its place, so a slow client skips ahead to the newest instead of working through a queue of stale frames.
+
+      for image in camera:
+        server.send_frame('camera', image.tobytes())
+
+    A client that's throttled the topic (see default_client_topic_rates_hz and topic_throttle_requests in start()) is written its frames at most at its rate, skipping those in between likewise.
+
+    Skipped frames are counted in get_stats() (frames_skipped), and aren't errors. Frames go out along with the clients' other messages, but aren't ordered with them, and never wait for slow clients, whatever the lag_policy. They aren't relayed to other cluster nodes.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if the frame would take the server over memory_budget_bytes.'''
+    self._started_handle('send a frame').send_frame(topic, message)
+
+  def send_keyframe(self, topic: str, message: Union[str, bytes, bytearray, memoryview, RegisteredMessage]):
+    '''Sends a keyframe on topic to all clients: the whole of some state, which the deltas sent on the topic from now on (see send_delta()) are changes to, like a video's keyframes, or a document and its edits.
+
+      server.send_keyframe('doc', json.dumps(document))
+      for edit in edits:
+        server.send_delta('doc', json.dumps(edit))
+
+    The server keeps the topic's latest keyframe and the deltas sent since, and keeps track of how far along them each client's been sent. A client that connects mid-stream is sent the keyframe and deltas so far straight away; one that falls behind the broadcasts and misses some is sent them again before the next delta; either way, it's never sent a delta without what comes before it. (Catch-ups are counted in get_stats(), as keyframes_resent.) Send keyframes often enough to keep the deltas between them few: they're held, and count against memory_budget_bytes, until the next keyframe. Keyframes and deltas never wait for slow clients, whatever the lag_policy, and aren't relayed to other cluster nodes.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if the keyframe would take the server over memory_budget_bytes.'''
+    self._started_handle('send a keyframe').send_keyframe(topic, message)
+
+  def send_delta(self, topic: str, message: Union[str, bytes, bytearray, memoryview, RegisteredMessage]):
+    '''Sends a delta on topic to all clients, following the topic's latest keyframe (see send_keyframe()) and the deltas sent since. Clients that haven't been sent those are sent them first.
+
+    Raises SendError if no keyframe has been sent on the topic, and as send_keyframe() otherwise.'''
+    self._started_handle('send a delta').send_delta(topic, message)
+
+  def send_pointcloud(self, xyz: Any, colors: Optional[Any] = None):
+    '''Sends a point cloud to all clients as a binary message, in a compact layout (see the README) that quicksocket/geometry.js decodes into typed arrays, for 3D visualizers:
+
+      server.send_pointcloud(points, colors)  # numpy arrays of shape (n, 3): float32 positions, uint8 RGB
+
+    xyz is a buffer-protocol object holding x, y, z for each point in turn, as float32s (float64s and integers are converted); colors, if given, holds 3 (RGB) or 4 (RGBA) uint8s for each point. It's a broadcast like any other, sent as by send().
+
+    Raises ServerNotRunning if the server isn't running, TypeError if xyz or colors isn't a buffer-protocol object, and ValueError if they're of the wrong type or don't add up to whole points.'''
+    self._started_handle('send a point cloud').send_pointcloud(xyz, colors = colors)
+
+  def send_mesh(self, vertices: Any, faces: Any):
+    '''Sends a triangle mesh to all clients as a binary message, in a compact layout (see the README) that quicksocket/geometry.js decodes into typed arrays:
+
+      server.send_mesh(vertices, faces)  # numpy arrays: (n, 3) float32 positions, (m, 3) integer vertex indices
+
+    vertices is a buffer-protocol object holding x, y, z for each vertex in turn, as float32s (float64s and integers are converted), and faces one holding three vertex indices for each triangle, as integers of any size (sent as uint32s). It's a broadcast like any other, sent as by send().
+
+    Raises ServerNotRunning if the server isn't running, TypeError if vertices or faces isn't a buffer-protocol object, and ValueError if they're of the wrong type, don't add up to whole vertices and triangles, or a face refers to a vertex that isn't there.'''
+    self._started_handle('send a mesh').send_mesh(vertices, faces)
+
+  def send_figure(self, figure: Any, figure_id: str = 'figure'):
+    '''Sends a plot to all clients on the reserved topic "figure", under figure_id, for quicksocket/figures.js to show (see the README), e.g. each time its data changes:
+
+      server.send_figure(plotly_figure, figure_id = 'loss')   # as its JSON, for Plotly.react()
+      server.send_figure(matplotlib_figure, figure_id = 'samples')  # as a PNG
+
+    A Matplotlib figure whose canvas draws to pixels (Agg's, as in notebooks and headless scripts) is drawn, and its pixels encoded as a PNG without holding the GIL; any other is saved with savefig(). It's a broadcast like any other, sent as by send().
+
+    Raises ServerNotRunning if the server isn't running, TypeError if figure isn't a Plotly or Matplotlib figure, and SendError if figure_id is longer than 65535 bytes.'''
+    self._started_handle('send a figure').send_figure(figure, figure_id = figure_id)
+
+  def log_scalar(self, tag: str, step: int, value: float):
+    '''Logs a scalar metric (a loss, an accuracy, a learning rate) at step, under tag, for quicksocket/metrics.js to chart (see the README), TensorBoard-style:
+
+      for step in range(steps):
+        loss = train_step()
+        server.log_scalar('loss', step, loss)
+
+    What's logged is held for the server's metrics_flush_ms (see start()) and broadcast in one frame with whatever's logged meanwhile, so it's cheap enough to call every step. The server also keeps the tag's last metrics_history points, for clients that connect later.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if tag is empty or longer than 65535 bytes.'''
+    self._started_handle('log a scalar').log_scalar(tag, step, value)
+
+  def log_histogram(self, tag: str, step: int, values: Any, bins: int = 30):
+    '''Logs a histogram of values (weights, gradients, rewards) at step, under tag, like log_scalar(). The values are counted into bins bins, splitting the range from the least to the greatest evenly, without holding the GIL, and only the counts are sent:
+
+      server.log_histogram('weights/fc1', step, model.fc1.weight.detach().numpy())
+
+    values is a buffer-protocol object of numbers (a numpy array of any shape, say) or a sequence of them; NaNs and infinities are left out.
+
+    Raises ServerNotRunning if the server isn't running, TypeError if values aren't numbers, and SendError if tag is empty or longer than 65535 bytes, or for bins of 0 or more than 1024.'''
+    self._started_handle('log a histogram').log_histogram(tag, step, values, bins = bins)
+
+  def send_python_objects(self, objects: List[Any], serializer: Optional[Callable[[Any], Union[str, bytes]]] = None, max_bytes: Optional[int] = None):
+    '''Sends Python objects to all clients, serialized with serializer (pickle.dumps by default; e.g. json.dumps works too). Raises SendError, sending nothing, if an object can't be serialized or serializes to more than max_bytes (16 MiB by default).
+
+    Only for trusted clients running the same codebase; see drain_python_objects().'''
+    self._started_handle('send messages').send_python_objects(objects, serializer = serializer, max_bytes = max_bytes)
+
+  def drain_python_objects(self, timeout_ms: Optional[int] = None, max_messages: Optional[int] = None, deserializer: Optional[Callable[[MessageData], Any]] = None, max_bytes: Optional[int] = None) -> List[Any]:
+    '''Drains client messages like drain_client_messages(), deserializing each with deserializer (pickle.loads by default). Messages over max_bytes (16 MiB by default), and ones that fail to deserialize, are skipped and reported through drain_error_events().
+
+    WARNING: unpickling runs arbitrary code chosen by the sender. Only use the default deserializer if every client that can connect is as trusted as your own code (e.g. another process of the same application); never for browsers or anything reachable from outside. Pass a safe deserializer such as json.loads otherwise.'''
+    if self._handle is None:
+      return []
+    objects: List[Any] = self._handle.drain_python_objects(timeout_ms = timeout_ms, max_messages = max_messages, deserializer = deserializer, max_bytes = max_bytes)
+    return objects
+
+  def send_to_client(self, client_id: str, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]]):
+    '''Sends messages to one client only, identified by the client_id from its connection events (or from a ClientMessage it sent). Doesn't wait for the messages to be written.
+
+    Raises ServerNotRunning if the server isn't running, and SendError if the client isn't connected or its send queue is full.'''
+    self._started_handle('send messages').try_send_to_client(client_id, messages)
+
+  def send_ping(self, client_id: str, payload: Union[bytes, bytearray, memoryview] = b''):
+    '''Pings one client, with up to 125 bytes of payload for its pong to echo back; with ping_events=True, the pong comes back from drain_ping_events(). Browsers answer pings by themselves. The ping is queued behind the client's other messages, like send_to_client()'s.
+
+      server.send_ping(client_id, struct.pack('!d', time.monotonic()))
+      ...
+      for event in server.drain_ping_events():
+        if event.kind == 'pong':
+          print('{} answered in {:.3f} s'.format(event.client_id, time.monotonic() - struct.unpack('!d', event.payload)[0]))
+
+    Raises ServerNotRunning if the server isn't running, SendError if the client isn't connected or its send queue is full, and ValueError for a payload over 125 bytes.'''
+    self._started_handle('send a ping').send_ping(client_id, payload)
+
+  def send_and_confirm(self, client_id: str, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]], timeout_ms: Optional[int] = None):
+    '''Sends messages to one client, blocking (releasing the GIL) until they've been written and flushed to its socket, for control commands that must not be silently dropped.
+
+    Raises SendError (with .reason) if the client isn't connected, disconnects first, the write fails, or timeout_ms elapses first.'''
+    self._started_handle('send messages').send_and_confirm(client_id, messages, timeout_ms = timeout_ms)
+
+class Client:
+  '''An outbound websocket connection, as returned by connect_to(): the client-side counterpart of a Server, with the same send and drain methods, so e.g. quicksocket.aio's helpers work with it too. Received messages come from the server at the other end; as ClientMessages, their client_id is the URL.
+
+  Can be used as a context manager, which closes the connection (waiting for the close handshake) on exit:
+
+    with quicksocket.connect_to('ws://feed.local:9000/') as feed, quicksocket.Server(port=9001) as srv:
+      while feed.is_connected():
+        msgs = feed.drain_client_messages(timeout_ms = 100)
+        if msgs:
+          srv.send_messages(msgs)
+  '''
+  def __init__(self, handle: ClientHandle):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'Client':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.close(wait = True)
+    return False # Don't swallow exceptions.
+
+  @property
+  def url(self) -> str:
+    url: str = self._handle.url
+    return url
+
+  def is_connected(self) -> bool:
+    '''Whether the connection is open. It closes when close() is called, when the server closes it, or when reading or writing fails (which is reported through drain_error_events()).'''
+    connected: bool = self._handle.is_connected()
+    return connected
+
+  def is_running(self) -> bool:
+    '''The same as is_connected(), for code written against Server (such as quicksocket.aio).'''
+    return self.is_connected()
+
+  def send_messages(self, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]]):
+    '''Queues messages for the server: str as text, bytes-like as binary. Doesn't wait for them to be written.
+
+    Raises QuicksocketError if the connection is closed, and SendError if its send queue is full.'''
+    self._handle.try_send_messages(messages)
+
+  def send_and_confirm(self, messages: List[Union[str, bytes, bytearray, memoryview, RegisteredMessage]], timeout_ms: Optional[int] = None):
+    '''Sends messages, blocking (releasing the GIL) until they've been written and flushed to the socket. Raises SendError (with .reason) if the connection closes first, the write fails, or timeout_ms elapses first.'''
+    self._handle.send_and_confirm(messages, timeout_ms = timeout_ms)
+
+  def drain_client_messages(self, timeout_ms: Optional[int] = None, max_messages: Optional[int] = None, structured: bool = False) -> Union[List[MessageData], List[ClientMessage]]:
+    '''Returns the messages received from the server since the last call, exactly as Server.drain_client_messages() does for client messages (including blocking for up to timeout_ms if none are pending). Messages received before the connection closed can still be drained after it has.'''
+    msgs: Union[List[MessageData], List[ClientMessage]] = self._handle.drain_client_messages(timeout_ms = timeout_ms, max_messages = max_messages, structured = structured)
+    return msgs
+
+  def get_message_fd(self) -> int:
+    '''Returns a file descriptor that becomes readable when received messages are pending (and when the connection closes), as Server.get_message_fd() does. Unix only.'''
+    message_fd: int = self._handle.get_message_fd()
+    return message_fd
+
+  def close(self, wait: bool = False):
+    '''Closes the connection with a close handshake. If wait is True, blocks (releasing the GIL) until it's closed, which takes at most a couple of seconds. Closing a closed connection does nothing.'''
+    self._handle.close(wait = wait)
+
+def connect_to(url: str, timeout_ms: Optional[int] = None, zero_copy_min_bytes: Optional[int] = None) -> Client:
+  '''Connects to the websocket server at url (ws:// only; there's no TLS support yet), blocking (releasing the GIL) until the handshake is done, or for at most timeout_ms. Received binary messages of at least zero_copy_min_bytes are MessageBuffers, as for Server.start().
+
+  Client connections run on a runtime thread shared by all of them, so consuming a feed doesn't cost a thread per connection. Raises ConnectError (with .url and .reason) if connecting fails or times out.'''
+  return Client(BACKEND_connect_to(url, timeout_ms = timeout_ms, zero_copy_min_bytes = zero_copy_min_bytes))
+
+class Relay:
+  '''A relay from an upstream Client to a Server's clients, as returned by relay(). It runs until stop() is called, or until the upstream connection closes or the server stops; it doesn't stop when this object is garbage collected.
+
+  Can be used as a context manager, which stops the relay on exit.'''
+  def __init__(self, handle: RelayHandle):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'Relay':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop()
+    return False # Don't swallow exceptions.
+
+  def is_running(self) -> bool:
+    running: bool = self._handle.is_running()
+    return running
+
+  def get_stats(self) -> RelayStats:
+    '''How many messages have been relayed each way (to_clients, to_upstream), and how many a filter dropped (filtered).'''
+    stats: RelayStats = self._handle.get_stats()
+    return stats
+
+  def stop(self):
+    '''Stops relaying, blocking (releasing the GIL) until the relay thread has finished. The upstream connection and the server are left open, and the server's client messages can be drained again. Stopping a stopped relay does nothing; it can't be called from a filter.'''
+    self._handle.stop()
+
+def relay(upstream: Client, server: Server, to_upstream: bool = False, filter: Optional[Callable[[MessageData], bool]] = None, upstream_filter: Optional[Callable[[MessageData], bool]] = None) -> Relay:
+  '''Broadcasts every message from upstream (a connection from connect_to()) to server's clients, so one connection to a data source can be fanned out to any number of clients:
+
+    feed = quicksocket.connect_to('ws://feed.local:9000/')
+    with quicksocket.Server(port=9001) as srv, quicksocket.relay(feed, srv, filter=lambda msg: msg != 'heartbeat') as r:
+      while r.is_running():
+        time.sleep(1)
+
+  With to_upstream, the clients' messages are also forwarded to upstream (and while the relay runs, they can't be drained from the server); otherwise they're drained as usual. filter, if given, is called with each upstream message and returns whether to broadcast it; upstream_filter does the same for the clients' messages. Filters run on the relay's own thread, holding the GIL only while they're called; one that raises drops the message (and the exception is printed).
+
+  While the relay runs, upstream's messages can't be drained. Raises ServerNotRunning if upstream is closed or server isn't running.'''
+  return Relay(BACKEND_start_relay(upstream._handle, server = server._started_handle('start a relay'), to_upstream = to_upstream, filter = filter, upstream_filter = upstream_filter))
+
+class Replay:
+  '''A replay of a session recording to a Server's clients, as returned by replay(). It runs until the recording ends (or, with repeat, until stop() is called), or the server stops; it doesn't stop when this object is garbage collected.
+
+  Can be used as a context manager, which stops the replay on exit.'''
+  def __init__(self, handle: ReplayHandle):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'Replay':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop()
+    return False # Don't swallow exceptions.
+
+  def is_running(self) -> bool:
+    running: bool = self._handle.is_running()
+    return running
+
+  def get_stats(self) -> ReplayStats:
+    '''How many recorded broadcasts have been sent (sent), how many other messages were skipped (skipped), how many times the recording has been replayed to its end (passes), and why the replay stopped early, if the recording couldn't be read (error).'''
+    stats: ReplayStats = self._handle.get_stats()
+    return stats
+
+  def wait(self, timeout_ms: Optional[int] = None) -> bool:
+    '''Blocks until the replay has finished, returning True, or until timeout_ms elapses, returning False.'''
+    deadline = None if timeout_ms is None else time.monotonic() + timeout_ms / 1000
+    while self.is_running():
+      if deadline is not None and time.monotonic() >= deadline:
+        return False
+      time.sleep(0.01)
+    return True
+
+  def stop(self):
+    '''Stops replaying, blocking (releasing the GIL) until the replay thread has finished. The server is left running. Stopping a stopped replay does nothing.'''
+    self._handle.stop()
+
+def replay(server: Server, path: str, speed: float = 1.0, repeat: bool = False) -> Replay:
+  '''Broadcasts what was broadcast in a session recording (see Server.start_recording(), in either format) to server's clients again, with its original timing, so a frontend can be developed against realistic data without running whatever produced it:
+
+    with quicksocket.Server(port=9001) as srv, quicksocket.replay(srv, 'session.jsonl', speed=2.0) as r:
+      r.wait()
+
+  The gaps between messages are divided by speed: 1.0 is real time, and float('inf') sends everything as fast as the clients take it. Messages from clients, and messages sent to single clients, are skipped. With repeat, the recording starts over each time it ends. A malformed recording stops the replay where it goes wrong, with a "recording" error event.
+
+  Raises ValueError for a speed that isn't positive, QuicksocketError if the file can't be opened, and ServerNotRunning if server isn't running.'''
+  return Replay(BACKEND_start_replay(path, speed = speed, repeat = repeat, server = server._started_handle('replay a recording')))
+
+class RedisBridge:
+  '''A bridge between Redis pub/sub and a Server's clients, as returned by redis_bridge(). It runs until stop() is called or the server stops (reconnecting to Redis by itself if the connection drops); it doesn't stop when this object is garbage collected.
+
+  Can be used as a context manager, which stops the bridge on exit.'''
+  def __init__(self, handle: Any):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'RedisBridge':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop()
+    return False # Don't swallow exceptions.
+
+  def is_running(self) -> bool:
+    running: bool = self._handle.is_running()
+    return running
+
+  def get_stats(self) -> Any:
+    '''A RedisBridgeStats: how many messages were broadcast from Redis (broadcast) and published to it (published), and how many times the Redis connection was reestablished (reconnects).'''
+    return self._handle.get_stats()
+
+  def stop(self):
+    '''Stops the bridge, blocking (releasing the GIL) until it has. The server keeps running, and its client messages can be drained again.'''
+    self._handle.stop()
+
+def redis_bridge(server: Server, url: str, channels: Optional[List[str]] = None, patterns: Optional[List[str]] = None, publish_channel: Optional[str] = None) -> RedisBridge:
+  '''Subscribes to Redis channels (or channel patterns, such as 'prices.*') and broadcasts every message published on them to server's clients, so several processes can feed the same clients. url is redis://[[username]:password@]host[:port]. Messages arrive as text if they're valid UTF-8, and as binary otherwise.
+
+  With publish_channel, every client message is also published to that channel, e.g. for other processes to handle (and while the bridge runs, client messages can't be drained from the server).
+
+    with quicksocket.Server(port=9001) as srv, quicksocket.redis_bridge(srv, 'redis://127.0.0.1:6379', channels=['updates']):
+      ...
+
+  Needs quicksocket built with the "redis" feature; raises QuicksocketError otherwise. Raises ValueError for a bad URL, ConnectError if Redis can't be reached, and ServerNotRunning if the server isn't running.'''
+  if BACKEND_start_redis_bridge is None:
+    raise QuicksocketError('quicksocket was built without the "redis" feature, so it has no Redis bridge.')
+  return RedisBridge(BACKEND_start_redis_bridge(url, channels = channels or [], patterns = patterns or [], publish_channel = publish_channel, server = server._started_handle('start a Redis bridge')))
+
+class KafkaSink:
+  '''A sink producing a Server's client messages to Kafka, as returned by kafka_sink(). It runs until stop() is called or the server stops; it doesn't stop when this object is garbage collected.
+
+  Can be used as a context manager, which stops the sink on exit.'''
+  def __init__(self, handle: Any):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'KafkaSink':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop()
+    return False # Don't swallow exceptions.
+
+  def is_running(self) -> bool:
+    running: bool = self._handle.is_running()
+    return running
+
+  def get_stats(self) -> Any:
+    '''A KafkaSinkStats: how many messages Kafka acknowledged (delivered) and how many were dropped after failing to be delivered, or missed by a sink too far behind (failed), and how many produce requests were sent (requests, including retries).'''
+    return self._handle.get_stats()
+
+  def stop(self):
+    '''Stops the sink, blocking (releasing the GIL) until the batch it's collecting has been delivered. The server keeps running.'''
+    self._handle.stop()
+
+def kafka_sink(server: Server, brokers: Union[str, List[str]], topic: str, max_batch: Optional[int] = None, linger_ms: Optional[int] = None) -> KafkaSink:
+  '''Produces every client message of server to a Kafka topic, from quicksocket's own threads as they're received, rather than when the Python side drains them, e.g. for an analytics pipeline. brokers are the bootstrap brokers, as a list of 'host[:port]' or a comma-separated string. Messages are batched: a batch is sent once it has max_batch messages (500 by default), or linger_ms after its first (5 by default). Each record is keyed by the client id, and all of a client's messages go to the same partition, in order.
+
+  The sink is sent copies of the client messages, so they can still be drained from the server as ever. Messages that can't be delivered after a few attempts are dropped, counted in get_stats().failed, and recorded as "kafka" error events, as are any the sink misses by falling too far behind.
+
+    with quicksocket.Server(port=9001) as srv, quicksocket.kafka_sink(srv, 'localhost:9092', 'client-messages'):
+      ...
+
+  Needs quicksocket built with the "kafka" feature; raises QuicksocketError otherwise. Raises ValueError for a bad broker address or topic name, ConnectError if no broker can be reached (or the topic isn't there), and ServerNotRunning if the server isn't running.'''
+  if BACKEND_start_kafka_sink is None:
+    raise QuicksocketError('quicksocket was built without the "kafka" feature, so it has no Kafka sink.')
+  if isinstance(brokers, str):
+    brokers = [broker.strip() for broker in brokers.split(',')]
+  return KafkaSink(BACKEND_start_kafka_sink(brokers, topic, max_batch = max_batch, linger_ms = linger_ms, server = server._started_handle('start a Kafka sink')))
+
+class ZmqBridge:
+  '''A bridge from ZeroMQ sockets to a Server's clients, as returned by zmq_bridge(). It runs until stop() is called or the server stops (reconnecting by itself if its connection drops); it doesn't stop when this object is garbage collected.
+
+  Can be used as a context manager, which stops the bridge on exit.'''
+  def __init__(self, handle: Any):
+    self._handle = handle
+
+  def __repr__(self) -> str:
+    return repr(self._handle)
+
+  def __enter__(self) -> 'ZmqBridge':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.stop()
+    return False # Don't swallow exceptions.
+
+  def is_running(self) -> bool:
+    running: bool = self._handle.is_running()
+    return running
+
+  def get_stats(self) -> Any:
+    '''A ZmqBridgeStats: how many messages were broadcast (broadcast), and how many peer connections were established (connections, including reconnections).'''
+    return self._handle.get_stats()
+
+  def stop(self):
+    '''Stops the bridge, blocking (releasing the GIL) until its connections have closed. The server keeps running.'''
+    self._handle.stop()
+
+def zmq_bridge(server: Server, endpoint: str, socket_type: str = 'SUB', topics: Optional[List[str]] = None, bind: bool = False) -> ZmqBridge:
+  '''Receives messages from ZeroMQ sockets (a simulator's PUB socket, say) and broadcasts each to server's clients, without going through the Python loop. socket_type is the bridge's end: 'SUB' to receive from PUB sockets, subscribed to topics (message prefixes; everything if None), or 'PULL' to receive from PUSH sockets. The bridge connects to endpoint (tcp://host:port), retrying every second while the peer isn't there, or with bind=True, listens on it (tcp://*:port for every interface) for any number of peers to connect.
+
+  Each message's last frame (the payload, for [topic, payload] messages) is broadcast, as text if it's valid UTF-8 and binary otherwise.
+
+    with quicksocket.Server(port=9001) as srv, quicksocket.zmq_bridge(srv, 'tcp://127.0.0.1:5556', topics=['frame']):
+      ...
+
+  Only tcp:// endpoints and ZeroMQ's default (NULL) security are supported. Needs quicksocket built with the "zmq" feature; raises QuicksocketError otherwise. Raises ValueError for a bad endpoint or socket type, BindError if the endpoint can't be bound, and ServerNotRunning if the server isn't running.'''
+  if BACKEND_start_zmq_bridge is None:
+    raise QuicksocketError('quicksocket was built without the "zmq" feature, so it has no ZeroMQ bridge.')
+  return ZmqBridge(BACKEND_start_zmq_bridge(endpoint, socket_type = socket_type, topics = topics or [], bind = bind, server = server._started_handle('start a ZeroMQ bridge')))
@@ -0,0 +1,291 @@
+'''Helpers for integration-testing applications built on quicksocket: a small blocking websocket client (TestClient), and pytest fixtures that start a server on an ephemeral port. To use the fixtures, add this to a conftest.py:
+
+  pytest_plugins = ['quicksocket.testing']
+
+and then take them as arguments:
+
+  def test_echo(quicksocket_server, quicksocket_client):
+    quicksocket_client.send(['ping'])
+    assert quicksocket_server.drain_client_messages(timeout_ms = 1000) == ['ping']
+    quicksocket_server.send_to_client(quicksocket_client.client_id, ['pong'])
+    quicksocket_client.expect('pong')
+
+TestClient speaks just enough of the websocket protocol for tests, using only the standard library, so it doesn't need a websocket package installed.'''
+
+import base64
+import contextlib
+import os
+import socket
+import struct
+import time
+import zlib
+from typing import Iterator, List, Optional, Union
+
+from .server import Server
+
+# A received message: str (text) or bytes (binary).
+Message = Union[str, bytes]
+
+OPCODE_CONTINUATION = 0x0
+OPCODE_TEXT = 0x1
+OPCODE_BINARY = 0x2
+OPCODE_CLOSE = 0x8
+OPCODE_PING = 0x9
+OPCODE_PONG = 0xA
+
+# The RSV1 bit, set on a deflated message's first frame (permessage-deflate).
+RSV1 = 0x40
+# The end of a sync-flushed deflate stream, left off deflated messages.
+DEFLATE_TAIL = b'\x00\x00\xff\xff'
+
+class TestClient:
+  '''A blocking websocket client for tests. Connects (and completes the handshake) on construction; can be used as a context manager, which closes it on exit.
+
+  Its client_id is the id the server knows it by (its local address), as in the server's ConnectionEvents and ClientMessage.client_id.
+
+  With compression, it offers the permessage-deflate extension; compression says whether the server agreed, and deflated_received counts the messages it's received deflated.'''
+  # Not a test class, even when imported into a test module.
+  __test__ = False
+
+  def __init__(self, port: int, path: str = '/', host: str = '127.0.0.1', timeout_ms: int = 5000, compression: bool = False):
+    self._sock = socket.create_connection((host, port), timeout = timeout_ms / 1000)
+    self._buffer = b''
+    self.closed = False
+    self.compression = False
+    self.deflated_received = 0
+    self._last_rsv1 = False
+    # (Kept from message to message: the client's context isn't taken over.)
+    self._deflater = zlib.compressobj(wbits = -15)
+    local_host, local_port = self._sock.getsockname()[:2]
+    self.client_id = '{}:{}'.format(local_host, local_port)
+
+    key = base64.b64encode(os.urandom(16)).decode()
+    extensions = 'Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits\r\n' if compression else ''
+    request = 'GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n'.format(path, host, port, key, extensions)
+    try:
+      self._sock.sendall(request.encode())
+      while b'\r\n\r\n' not in self._buffer:
+        self._fill()
+    except (OSError, EOFError) as e:
+      self._sock.close()
+      raise ConnectionError('The websocket handshake with {}:{} failed: {}'.format(host, port, e))
+    head, self._buffer = self._buffer.split(b'\r\n\r\n', 1)
+    status_line = head.split(b'\r\n', 1)[0].decode(errors = 'replace')
+    if status_line.split(' ')[1:2] != ['101']:
+      self._sock.close()
+      raise ConnectionError('The server refused the websocket handshake: {}'.format(status_line))
+    for line in head.split(b'\r\n')[1:]:
+      name, _, value = line.decode(errors = 'replace').partition(':')
+      if name.strip().lower() == 'sec-websocket-extensions' and value.strip().startswith('permessage-deflate'):
+        self.compression = True
+
+  def __repr__(self) -> str:
+    return '<quicksocket.testing.TestClient {}{}>'.format(self.client_id, ' (closed)' if self.closed else '')
+
+  def __enter__(self) -> 'TestClient':
+    return self
+
+  def __exit__(self, exc_type, exc_value, exc_traceback):
+    self.close()
+    return False
+
+  def send(self, messages: List[Union[str, bytes, bytearray, memoryview]], deflate: bool = False):
+    '''Sends messages to the server, in order: str as text, bytes-like as binary. With deflate, they're sent deflated (if the server agreed to compression).'''
+    for msg in messages:
+      opcode, payload = (OPCODE_TEXT, msg.encode()) if isinstance(msg, str) else (OPCODE_BINARY, bytes(msg))
+      if deflate and self.compression:
+        payload = self._deflater.compress(payload) + self._deflater.flush(zlib.Z_SYNC_FLUSH)
+        self._send_frame(opcode, payload[:-len(DEFLATE_TAIL)], rsv1 = True)
+      else:
+        self._send_frame(opcode, payload)
+
+  def recv(self, timeout_ms: Optional[int] = None) -> Optional[Message]:
+    '''Waits for the next text or binary message from the server, for at most timeout_ms if given, returning None on timeout. Pings are answered along the way. Raises ConnectionError once the server has closed the connection.'''
+    deadline = time.monotonic() + timeout_ms / 1000 if timeout_ms is not None else None
+    fragments: List[bytes] = []
+    fragmented_opcode = None
+    deflated = False
+    while True:
+      frame = self._recv_frame(deadline)
+      if frame is None:
+        return None
+      fin, opcode, payload = frame
+      if opcode == OPCODE_PING:
+        self._send_frame(OPCODE_PONG, payload)
+      elif opcode == OPCODE_CLOSE:
+        # Answer the close, as the protocol asks, then report it.
+        self._close_socket(reply = payload[:2])
+        raise ConnectionError('The server closed the connection{}.'.format(' (code {})'.format(struct.unpack('>H', payload[:2])[0]) if len(payload) >= 2 else ''))
+      elif opcode in (OPCODE_TEXT, OPCODE_BINARY, OPCODE_CONTINUATION):
+        if opcode != OPCODE_CONTINUATION:
+          fragmented_opcode = opcode
+          deflated = self._last_rsv1
+        fragments.append(payload)
+        if fin:
+          data = b''.join(fragments)
+          if deflated:
+            # (The server's context is never taken over, so each message inflates by itself.)
+            data = zlib.decompressobj(wbits = -15).decompress(data + DEFLATE_TAIL)
+            self.deflated_received += 1
+          return data.decode() if fragmented_opcode == OPCODE_TEXT else data
+
+  def expect(self, expected: Optional[Message] = None, timeout_ms: int = 1000) -> Message:
+    '''Receives the next message and returns it, failing the test (with an AssertionError) if none arrives within timeout_ms, or if expected is given and the message isn't equal to it.'''
+    msg = self.recv(timeout_ms = timeout_ms)
+    if msg is None:
+      raise AssertionError('Expected {} from the server, but nothing arrived within {} ms.'.format(repr(expected) if expected is not None else 'a message', timeout_ms))
+    if expected is not None and msg != expected:
+      raise AssertionError('Expected {!r} from the server, but got {!r}.'.format(expected, msg))
+    return msg
+
+  def close(self):
+    '''Closes the connection with a close handshake (waiting briefly for the server's reply), so the server sees a clean disconnection. Does nothing if it's already closed.'''
+    if self.closed:
+      return
+    try:
+      self._send_frame(OPCODE_CLOSE, struct.pack('>H', 1000))
+      deadline = time.monotonic() + 1.0
+      while True:
+        frame = self._recv_frame(deadline)
+        if frame is None or frame[1] == OPCODE_CLOSE:
+          break
+    except (OSError, EOFError):
+      pass
+    self._close_socket()
+
+  def _send_frame(self, opcode: int, payload: bytes, rsv1: bool = False):
+    if self.closed:
+      raise ConnectionError('The connection is closed.')
+    # Client frames are always masked.
+    mask = os.urandom(4)
+    length = len(payload)
+    header = bytes([0x80 | (RSV1 if rsv1 else 0) | opcode])
+    if length < 126:
+      header += bytes([0x80 | length])
+    elif length < 65536:
+      header += bytes([0x80 | 126]) + struct.pack('>H', length)
+    else:
+      header += bytes([0x80 | 127]) + struct.pack('>Q', length)
+    masked = bytes(byte ^ mask[i % 4] for i, byte in enumerate(payload))
+    self._sock.sendall(header + mask + masked)
+
+  def _recv_frame(self, deadline: Optional[float]):
+    '''The next frame as (fin, opcode, payload), or None if the deadline passes first (leaving any partial frame buffered).'''
+    if self.closed:
+      raise ConnectionError('The connection is closed.')
+    while True:
+      frame = self._parse_frame()
+      if frame is not None:
+        return frame
+      if deadline is not None:
+        remaining = deadline - time.monotonic()
+        if remaining <= 0:
+          return None
+        self._sock.settimeout(remaining)
+      else:
+        self._sock.settimeout(None)
+      try:
+        self._fill()
+      except socket.timeout:
+        return None
+      except EOFError:
+        self._close_socket()
+        raise ConnectionError('The server closed the connection without a close frame.')
+
+  def _parse_frame(self):
+    buffer = self._buffer
+    if len(buffer) < 2:
+      return None
+    fin = bool(buffer[0] & 0x80)
+    opcode = buffer[0] & 0x0F
+    length = buffer[1] & 0x7F
+    offset = 2
+    if length == 126:
+      if len(buffer) < 4:
+        return None
+      length = struct.unpack('>H', buffer[2:4])[0]
+      offset = 4
+    elif length == 127:
+      if len(buffer) < 10:
+        return None
+      length = struct.unpack('>Q', buffer[2:10])[0]
+      offset = 10
+    # (Server frames are never masked.)
+    if len(buffer) < offset + length:
+      return None
+    self._buffer = buffer[offset + length:]
+    # (Whether the frame's deflated, for recv().)
+    self._last_rsv1 = bool(buffer[0] & RSV1)
+    return fin, opcode, buffer[offset:offset + length]
+
+  def _fill(self):
+    chunk = self._sock.recv(65536)
+    if not chunk:
+      raise EOFError()
+    self._buffer += chunk
+
+  def _close_socket(self, reply: Optional[bytes] = None):
+    if reply is not None:
+      try:
+        self._send_frame(OPCODE_CLOSE, reply)
+      except OSError:
+        pass
+    self.closed = True
+    self._sock.close()
+
+def connect(server: Union[Server, int], path: str = '/', timeout_ms: int = 5000, compression: bool = False) -> TestClient:
+  '''Connects a TestClient to a running server (or to a port), offering compression if asked to. Given a Server, also waits until the server has registered the client, so e.g. send_to_client(client.client_id, ...) reaches it straight away.'''
+  if not isinstance(server, Server):
+    return TestClient(server, path = path, timeout_ms = timeout_ms, compression = compression)
+
+  port = server.get_bound_port()
+  if port is None:
+    raise ValueError('The server isn\'t listening on a port (is it started, and not a loopback server?).')
+  client = TestClient(port, path = path, timeout_ms = timeout_ms, compression = compression)
+  deadline = time.monotonic() + timeout_ms / 1000
+  while not server.is_client_connected(client.client_id):
+    if time.monotonic() > deadline:
+      client.close()
+      raise TimeoutError('The server didn\'t register the client within {} ms.'.format(timeout_ms))
+    time.sleep(0.001)
+  return client
+
+@contextlib.contextmanager
+def running_server(**kwargs) -> Iterator[Server]:
+  '''Runs a Server on a port the OS picks (so parallel tests never clash), for the duration of a with block, and stops it (waiting for its thread) afterwards. Keyword arguments are passed on to Server(), e.g. inspector=True.
+
+    with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+      ...
+  '''
+  with Server(port = 0, **kwargs) as server:
+    yield server
+
+def wait_until(condition, timeout_s: float = 5) -> bool:
+  '''Polls condition() until it's true, returning True, or until timeout_s has passed, returning False, for things that happen in the server's own threads:
+
+    assert(quicksocket.testing.wait_until(lambda: server.get_stats().current_clients == 1))
+  '''
+  deadline = time.monotonic() + timeout_s
+  while not condition():
+    if time.monotonic() > deadline:
+      return False
+    time.sleep(0.01)
+  return True
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+
+if pytest is not None:
+  @pytest.fixture
+  def quicksocket_server() -> Iterator[Server]:
+    '''A running quicksocket Server on an ephemeral port (see server.get_bound_port()), stopped after the test.'''
+    with running_server() as server:
+      yield server
+
+  @pytest.fixture
+  def quicksocket_client(quicksocket_server: Server) -> Iterator[TestClient]:
+    '''A TestClient connected to quicksocket_server, closed after the test.'''
+    with connect(quicksocket_server) as client:
+      yield client
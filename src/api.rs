@@ -1,185 +1,2916 @@
 // api.rs
 // ======
 //
-// Primary Python module and Rust-lib public API.
+// Primary Python module: pyo3 bindings over the Rust server API (server::Server), built with the "python" feature.
 
-use futures_util::FutureExt;
-use pyo3::{prelude::*, wrap_pyfunction};
+use std::{cell::RefCell, collections::VecDeque, sync::Arc, time::{Duration, Instant}};
+use pyo3::{prelude::*, wrap_pyfunction, PyIterProtocol};
 use tokio_tungstenite::tungstenite::Message as WsMessage;
 
-use crate::server::{self, consumer_state::{self}};
+use crate::buffer::{ByteBuffer, MessageBuffer};
+use crate::errors::{self, QuicksocketError};
+use crate::events::{ClientMessage, ConnectionEvent, ErrorEvent, EventLogEntry, InputSnapshot, PingEvent, PlaybackEvent, PlaybackState, ReceivedMessage};
+use crate::log_bridge;
+use crate::message_callback;
+use crate::objects;
+use crate::signals;
+use crate::server::{self, Delivery, Outbound, PreparedMessage, Server, ShutdownOptions, buffer_pool::OUTBOUND, consumer_state::{self, RunState, ServerState}, events::ConnectionChange, figures::Figure, geometry};
 use consumer_state as cs;
 
+/// The server the module-level functions operate on, if one has been started with start_server().
+fn default_server() -> Option<Server> {
+    cs::read(&cs::CS_DEFAULT_SERVER, |server| Server::from(server.clone()))
+}
+
+/// Starts a server instance with `config`; the shared body of start_server() and start_server_instance().
+fn start(port: u32, config: server::ServerConfig) -> PyResult<Server> {
+    let server = Server::start(port, config).map_err(|err| errors::from_server_error(err, "start the server"))?;
+
+    log_info!("Server started.");
+    Ok(server)
+}
+
+/// The keyword arguments passed to start_server() or start_server_instance(), taken one by one as server_config() parses them.
+struct Options<'py> {
+    function: &'static str,
+    kwargs: Option<&'py pyo3::types::PyDict>,
+    taken: Vec<&'static str>,
+}
+
+impl<'py> Options<'py> {
+    /// The argument `name`, or `default` if it wasn't passed. Raises TypeError if it's of the wrong type.
+    fn take<T: FromPyObject<'py>>(&mut self, name: &'static str, default: T) -> PyResult<T> {
+        self.taken.push(name);
+        match self.kwargs.and_then(|kwargs| kwargs.get_item(name)) {
+            Some(value) => value.extract().map_err(|err| pyo3::exceptions::PyTypeError::new_err(format!("{}() argument '{}': {}", self.function, name, err.pvalue(pyo3::PyNativeType::py(value))))),
+            None => Ok(default),
+        }
+    }
+
+    /// Raises TypeError if an argument was passed that hasn't been taken (one the function doesn't have).
+    fn finish(self) -> PyResult<()> {
+        for name in self.kwargs.into_iter().flat_map(|kwargs| kwargs.keys()) {
+            let name: &str = name.extract()?;
+            if !self.taken.contains(&name) {
+                return Err(pyo3::exceptions::PyTypeError::new_err(format!("{}() got an unexpected keyword argument '{}'", self.function, name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The server configuration for start_server()'s keyword arguments (`function`'s, which takes the same ones), with each left out taking its default. Raises TypeError for an argument it doesn't take, or of the wrong type, and ValueError for an invalid value.
+fn server_config(function: &'static str, kwargs: Option<&pyo3::types::PyDict>) -> PyResult<server::ServerConfig> {
+    let mut options = Options { function, kwargs, taken: Vec::new() };
+    let inspector = options.take("inspector", false)?;
+    let landing_page = options.take("landing_page", None)?;
+    let zero_copy_min_bytes = options.take("zero_copy_min_bytes", None)?;
+    let loopback = options.take("loopback", false)?;
+    let proxy: Option<std::collections::HashMap<String, String>> = options.take("proxy", None)?;
+    let cluster = cluster_config(options.take("cluster_peers", None)?, options.take("node_id", None)?, options.take("cluster_secret", None)?)?;
+    let io_uring = options.take("io_uring", false)?;
+    let latency_histograms = options.take("latency_histograms", false)?;
+    let lag_policy = self::lag_policy(&options.take::<String>("lag_policy", "drop".into())?, options.take("block_timeout_ms", None)?)?;
+    let batching = self::batching(options.take("max_flush_delay_ms", 1.0)?, options.take("cork_ms", 0.0)?)?;
+    let memory_budget = options.take("memory_budget_bytes", None)?;
+    let rate_limit = self::rate_limit(options.take("max_outbound_bytes_per_sec", None)?, options.take("outbound_burst_bytes", None)?)?;
+    let client_bytes_per_sec: Option<u64> = options.take("client_bytes_per_sec", None)?;
+    let client_bytes_per_sec_by_tag: Option<std::collections::HashMap<String, u64>> = options.take("client_bytes_per_sec_by_tag", None)?;
+    let client_rate_limits = server::ClientRateLimits {
+        default: client_bytes_per_sec.map(server::RateLimit::new),
+        by_tag: client_bytes_per_sec_by_tag.unwrap_or_default().into_iter().map(|(tag, bytes_per_sec)| (tag, server::RateLimit::new(bytes_per_sec))).collect(),
+    };
+    let worker_cores: Option<Vec<usize>> = options.take("worker_cores", None)?;
+    let threading = server::Threading { worker_threads: options.take("worker_threads", None)?, cores: worker_cores.unwrap_or_default(), isolate: options.take("isolate_cores", false)? };
+    let keepalive = self::keepalive(options.take("ping_interval_ms", None)?, options.take("max_missed_pongs", None)?, options.take("unhealthy_after_missed_pongs", None)?)?;
+    let idle_timeout = options.take::<Option<u64>>("idle_timeout_ms", None)?.map(Duration::from_millis);
+    let ping_events = options.take("ping_events", false)?;
+    let time_sync = options.take("time_sync", false)?;
+    let playback_control = options.take("playback_control", false)?;
+    let chaos = self::chaos(options.take("chaos_seed", None)?, options.take("chaos_drop_rate", 0.0)?, options.take("chaos_max_delay_ms", 0.0)?, options.take("chaos_reorder_window", 0)?)?;
+    let link = self::link(options.take("link_latency_ms", 0.0)?, options.take("link_jitter_ms", 0.0)?, options.take("link_bits_per_sec", None)?)?;
+    let watchdog = self::watchdog(options.take("watchdog_stall_timeout_ms", None)?, options.take("watchdog_restart", false)?)?;
+    let heartbeat = self::heartbeat(options.take("heartbeat_interval_ms", None)?, options.take("heartbeat_topic", None)?)?;
+    let topic_rates = self::topic_rates(options.take("max_topic_rates_hz", None)?, &options.take::<String>("decimation", "drop".into())?)?;
+    let client_topic_rates: Option<std::collections::HashMap<String, f64>> = options.take("default_client_topic_rates_hz", None)?;
+    let topic_throttle_requests = options.take("topic_throttle_requests", false)?;
+    let sync_groups: Option<std::collections::HashMap<String, Vec<String>>> = options.take("sync_groups", None)?;
+    let sync_window = self::sync_window(options.take("sync_window_ms", 50.0)?)?;
+    let capabilities = options.take::<Option<Vec<String>>>("capability_formats", None)?.map(server::CapabilityOffer::new);
+    let input_aggregation = self::input_aggregation(options.take("input_aggregation_ms", None)?)?;
+    let rosbridge = options.take("rosbridge", false)?;
+    let metrics = self::metrics(options.take("metrics_flush_ms", 100.0)?, options.take("metrics_history", 1000)?)?;
+    let compression = self::compression(options.take("compression", false)?, options.take("compression_min_bytes", None)?, options.take("compression_threads", None)?)?;
+    let audit_log = self::audit_log(options.take("audit_log_path", None)?, options.take("audit_log_max_bytes", None)?, options.take("audit_log_max_files", None)?, options.take("audit_principal_header", None)?)?;
+    options.finish()?;
+
+    let transport = match (loopback, io_uring) {
+        (true, true) => { return Err(pyo3::exceptions::PyValueError::new_err("A loopback server doesn't listen on its port, so it can't use io_uring.")); }
+        (true, false) => server::Transport::Loopback,
+        #[cfg(feature = "uring")]
+        (false, true) => server::Transport::Uring,
+        #[cfg(not(feature = "uring"))]
+        (false, true) => { return Err(QuicksocketError::new_err("quicksocket was built without the \"uring\" feature, so it can't use io_uring.")); }
+        (false, false) => server::Transport::Tcp,
+    };
+    let mut proxy_routes: Vec<server::ProxyRoute> = proxy.unwrap_or_default().iter().map(|(path, backend)| server::ProxyRoute::new(path, backend)).collect();
+    // (Routes are matched longest path first, whatever their order; sorting just keeps the config the same from run to run.)
+    proxy_routes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(server::ServerConfig { inspector, landing_page, zero_copy_min_bytes, transport, proxy_routes, cluster, latency_histograms, lag_policy, batching, memory_budget, rate_limit, client_rate_limits, threading, keepalive, idle_timeout, ping_events, time_sync, playback_control, chaos, link, watchdog, heartbeat, topic_rates, client_topic_rates: client_topic_rates.unwrap_or_default(), topic_throttle_requests, sync_groups: sync_groups.unwrap_or_default(), sync_window, capabilities, input_aggregation, rosbridge, metrics, compression, audit_log })
+}
+
+/// The cluster configuration for start_server()'s cluster arguments: cluster mode is on if `cluster_peers` is given (even empty, for a node only linked to by others).
+fn cluster_config(cluster_peers: Option<Vec<String>>, node_id: Option<String>, cluster_secret: Option<String>) -> PyResult<Option<server::ClusterConfig>> {
+    match cluster_peers {
+        Some(peers) => Ok(Some(server::ClusterConfig { node_id: node_id.unwrap_or_default(), peers, secret: cluster_secret })),
+        None if node_id.is_some() || cluster_secret.is_some() => Err(pyo3::exceptions::PyValueError::new_err("node_id and cluster_secret only apply to cluster nodes; pass cluster_peers too.")),
+        None => Ok(None),
+    }
+}
+
+/// The lag policy for start_server()'s `lag_policy` and `block_timeout_ms` arguments.
+fn lag_policy(lag_policy: &str, block_timeout_ms: Option<u64>) -> PyResult<server::LagPolicy> {
+    match lag_policy {
+        "drop" if block_timeout_ms.is_some() => Err(pyo3::exceptions::PyValueError::new_err("block_timeout_ms only applies to lag_policy=\"block\".")),
+        "drop" => Ok(server::LagPolicy::Drop),
+        "block" => Ok(server::LagPolicy::Block { timeout: block_timeout_ms.map(std::time::Duration::from_millis) }),
+        _ => Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown lag_policy {:?}; expected \"drop\" or \"block\".", lag_policy))),
+    }
+}
+
+/// The sync groups' window for start_server()'s `sync_window_ms` argument.
+fn sync_window(sync_window_ms: f64) -> PyResult<Duration> {
+    Duration::try_from_secs_f64(sync_window_ms / 1000.0)
+        .map_err(|_| pyo3::exceptions::PyValueError::new_err(format!("sync_window_ms must be a number of milliseconds, 0 or more, not {}.", sync_window_ms)))
+}
+
+/// The snapshot interval for start_server()'s `input_aggregation_ms` argument.
+fn input_aggregation(input_aggregation_ms: Option<f64>) -> PyResult<Option<Duration>> {
+    input_aggregation_ms.map(|ms| {
+        Duration::try_from_secs_f64(ms / 1000.0).ok().filter(|interval| !interval.is_zero())
+            .ok_or_else(|| pyo3::exceptions::PyValueError::new_err(format!("input_aggregation_ms must be a number of milliseconds, more than 0, not {}.", ms)))
+    }).transpose()
+}
+
+/// The metrics' batching and history for start_server()'s `metrics_flush_ms` and `metrics_history` arguments.
+fn metrics(metrics_flush_ms: f64, metrics_history: usize) -> PyResult<server::MetricsConfig> {
+    let flush = Duration::try_from_secs_f64(metrics_flush_ms / 1000.0)
+        .map_err(|_| pyo3::exceptions::PyValueError::new_err(format!("metrics_flush_ms must be a number of milliseconds, 0 or more, not {}.", metrics_flush_ms)))?;
+    Ok(server::MetricsConfig { flush, history: metrics_history })
+}
+
+/// The compression for start_server()'s `compression`, `compression_min_bytes` and `compression_threads` arguments: on if `compression` is true.
+fn compression(compression: bool, compression_min_bytes: Option<usize>, compression_threads: Option<usize>) -> PyResult<Option<server::Compression>> {
+    if !compression {
+        if compression_min_bytes.is_some() || compression_threads.is_some() {
+            return Err(pyo3::exceptions::PyValueError::new_err("compression_min_bytes and compression_threads only apply to compression; pass compression=True too."));
+        }
+        return Ok(None);
+    }
+    let defaults = server::Compression::default();
+    Ok(Some(server::Compression { min_bytes: compression_min_bytes.unwrap_or(defaults.min_bytes), threads: compression_threads.unwrap_or(defaults.threads), ..defaults }))
+}
+
+/// The batching for start_server()'s `max_flush_delay_ms` and `cork_ms` arguments.
+fn batching(max_flush_delay_ms: f64, cork_ms: f64) -> PyResult<server::Batching> {
+    let millis = |name: &str, ms: f64| Duration::try_from_secs_f64(ms / 1000.0)
+        .map_err(|_| pyo3::exceptions::PyValueError::new_err(format!("{} must be a number of milliseconds, 0 or more, not {}.", name, ms)));
+    Ok(server::Batching { max_flush_delay: millis("max_flush_delay_ms", max_flush_delay_ms)?, cork: millis("cork_ms", cork_ms)?, ..server::Batching::default() })
+}
+
+/// The outbound rate limit for start_server()'s `max_outbound_bytes_per_sec` and `outbound_burst_bytes` arguments.
+fn rate_limit(max_outbound_bytes_per_sec: Option<u64>, outbound_burst_bytes: Option<u64>) -> PyResult<Option<server::RateLimit>> {
+    match (max_outbound_bytes_per_sec, outbound_burst_bytes) {
+        (Some(bytes_per_sec), Some(burst)) => Ok(Some(server::RateLimit { bytes_per_sec, burst })),
+        (Some(bytes_per_sec), None) => Ok(Some(server::RateLimit::new(bytes_per_sec))),
+        (None, Some(_)) => Err(pyo3::exceptions::PyValueError::new_err("outbound_burst_bytes only applies to a rate limit; pass max_outbound_bytes_per_sec too.")),
+        (None, None) => Ok(None),
+    }
+}
+
+/// The keepalive for start_server()'s `ping_interval_ms`, `max_missed_pongs` and `unhealthy_after_missed_pongs` arguments.
+fn keepalive(ping_interval_ms: Option<u64>, max_missed_pongs: Option<u32>, unhealthy_after_missed_pongs: Option<u32>) -> PyResult<Option<server::Keepalive>> {
+    match (ping_interval_ms, max_missed_pongs, unhealthy_after_missed_pongs) {
+        (Some(interval_ms), max_missed_pongs, unhealthy_after) => Ok(Some(server::Keepalive {
+            interval: Duration::from_millis(interval_ms),
+            max_missed_pongs: max_missed_pongs.unwrap_or(server::keepalive::DEFAULT_MAX_MISSED_PONGS),
+            unhealthy_after,
+        })),
+        (None, None, None) => Ok(None),
+        (None, _, _) => Err(pyo3::exceptions::PyValueError::new_err("max_missed_pongs and unhealthy_after_missed_pongs only apply to keepalive pings; pass ping_interval_ms too.")),
+    }
+}
+
+/// The chaos testing for start_server()'s chaos arguments: on if `chaos_seed` is given.
+fn chaos(chaos_seed: Option<u64>, chaos_drop_rate: f64, chaos_max_delay_ms: f64, chaos_reorder_window: usize) -> PyResult<Option<server::Chaos>> {
+    let max_delay = Duration::try_from_secs_f64(chaos_max_delay_ms / 1000.0)
+        .map_err(|_| pyo3::exceptions::PyValueError::new_err(format!("chaos_max_delay_ms must be a number of milliseconds, 0 or more, not {}.", chaos_max_delay_ms)))?;
+    match chaos_seed {
+        Some(seed) => Ok(Some(server::Chaos { seed, drop_rate: chaos_drop_rate, max_delay, reorder_window: chaos_reorder_window })),
+        None if chaos_drop_rate != 0.0 || !max_delay.is_zero() || chaos_reorder_window != 0 => Err(pyo3::exceptions::PyValueError::new_err("The chaos arguments only apply to chaos testing; pass chaos_seed too.")),
+        None => Ok(None),
+    }
+}
+
+/// The link emulation for start_server()'s link arguments: on if any of them is given.
+fn link(link_latency_ms: f64, link_jitter_ms: f64, link_bits_per_sec: Option<u64>) -> PyResult<Option<server::LinkEmulation>> {
+    let millis = |name: &str, ms: f64| Duration::try_from_secs_f64(ms / 1000.0)
+        .map_err(|_| pyo3::exceptions::PyValueError::new_err(format!("{} must be a number of milliseconds, 0 or more, not {}.", name, ms)));
+    let link = server::LinkEmulation { latency: millis("link_latency_ms", link_latency_ms)?, jitter: millis("link_jitter_ms", link_jitter_ms)?, bits_per_sec: link_bits_per_sec };
+    Ok(Some(link).filter(|link| *link != server::LinkEmulation::default()))
+}
+
+/// The watchdog for start_server()'s `watchdog_stall_timeout_ms` and `watchdog_restart` arguments: on if a stall timeout is given.
+fn watchdog(watchdog_stall_timeout_ms: Option<u64>, watchdog_restart: bool) -> PyResult<Option<server::Watchdog>> {
+    match watchdog_stall_timeout_ms {
+        Some(ms) => Ok(Some(server::Watchdog { stall_timeout: Duration::from_millis(ms), restart: watchdog_restart })),
+        None if watchdog_restart => Err(pyo3::exceptions::PyValueError::new_err("watchdog_restart only applies to a watchdog; pass watchdog_stall_timeout_ms too.")),
+        None => Ok(None),
+    }
+}
+
+/// The heartbeat topic for start_server()'s `heartbeat_interval_ms` and `heartbeat_topic` arguments: on if an interval is given.
+fn heartbeat(heartbeat_interval_ms: Option<u64>, heartbeat_topic: Option<String>) -> PyResult<Option<server::HeartbeatTopic>> {
+    match heartbeat_interval_ms {
+        Some(ms) => {
+            let heartbeat = server::HeartbeatTopic::new(Duration::from_millis(ms));
+            Ok(Some(server::HeartbeatTopic { topic: heartbeat_topic.unwrap_or(heartbeat.topic), ..heartbeat }))
+        }
+        None if heartbeat_topic.is_some() => Err(pyo3::exceptions::PyValueError::new_err("heartbeat_topic only applies to heartbeats; pass heartbeat_interval_ms too.")),
+        None => Ok(None),
+    }
+}
+
+/// The decimated topics for start_server()'s `max_topic_rates_hz` and `decimation` arguments.
+fn topic_rates(max_topic_rates_hz: Option<std::collections::HashMap<String, f64>>, decimation: &str) -> PyResult<std::collections::HashMap<String, server::TopicRate>> {
+    let mode = match decimation {
+        "drop" => server::DecimationMode::Drop,
+        "average" => server::DecimationMode::Average,
+        _ => { return Err(pyo3::exceptions::PyValueError::new_err(format!("Unknown decimation {:?}; expected \"drop\" or \"average\".", decimation))); }
+    };
+    Ok(max_topic_rates_hz.unwrap_or_default().into_iter().map(|(topic, max_rate_hz)| (topic, server::TopicRate { max_rate_hz, mode })).collect())
+}
+
+/// The audit log for start_server()'s `audit_log_path`, `audit_log_max_bytes`, `audit_log_max_files` and `audit_principal_header` arguments: on if a path is given.
+fn audit_log(audit_log_path: Option<String>, audit_log_max_bytes: Option<u64>, audit_log_max_files: Option<u32>, audit_principal_header: Option<String>) -> PyResult<Option<server::AuditLog>> {
+    match audit_log_path {
+        Some(path) => {
+            let audit_log = server::AuditLog::new(path);
+            Ok(Some(server::AuditLog {
+                max_bytes: audit_log_max_bytes.unwrap_or(audit_log.max_bytes),
+                max_files: audit_log_max_files.unwrap_or(audit_log.max_files),
+                principal_header: audit_principal_header,
+                ..audit_log
+            }))
+        }
+        None if audit_log_max_bytes.is_some() || audit_log_max_files.is_some() || audit_principal_header.is_some() => Err(pyo3::exceptions::PyValueError::new_err("audit_log_max_bytes, audit_log_max_files and audit_principal_header only apply to an audit log; pass audit_log_path too.")),
+        None => Ok(None),
+    }
+}
+
 /// Starts the websocket server.
-#[pyfunction]
-pub fn start_server(port: u32) -> bool {
+///
+/// If `inspector` is true, the server also serves a debug inspector page at http://localhost:<port>/inspector, showing connected clients, recent messages, and throughput.
+///
+/// Plain HTTP requests to the port (e.g. from a browser) get a "426 Upgrade Required" response, or a "200 OK" with `landing_page` as its HTML body if one is provided.
+///
+/// If `zero_copy_min_bytes` is given, received binary messages of at least that many bytes are returned as MessageBuffer objects rather than bytes. A MessageBuffer exposes the received bytes through the buffer protocol (e.g. to memoryview() or numpy.frombuffer()) without copying them; call .copy() on it to get bytes.
+///
+/// If `loopback` is true, the server doesn't listen on `port` at all (it's only a label); clients connect in-process with connect_loopback() instead, for tests that want the whole send/receive pipeline without real sockets.
+///
+/// If `io_uring` is true, the server's sockets go through an io_uring (on Linux 5.6 or later) instead of tokio's readiness polling, which suits servers with many connections; everything else is the same. Needs quicksocket built with the "uring" feature (raises QuicksocketError otherwise); if io_uring isn't available, e.g. in a container that forbids it, the server fails to start as if its port couldn't be bound.
+///
+/// `proxy` maps path prefixes to ws:// backend URLs, e.g. {"/legacy": "ws://127.0.0.1:8000"}: websocket connections to those paths are relayed to the backend (the rest of the path and the query are appended to its URL) rather than becoming this server's clients, so one port can front a legacy service too. Proxied connections don't show up in connection events, messages, or stats; if the backend can't be reached, the client gets a 502 response. Raises ValueError for a route that can't work.
+///
+/// If `cluster_peers` is given, the server is a node of a cluster: a list of the other nodes' ws:// URLs, e.g. ["ws://10.0.0.2:9001"]. Broadcasts (try_send_messages() and the like) are relayed to the clients of every node, and the other nodes' broadcasts reach this node's clients; messages sent to a single client aren't relayed. `node_id` names this node (one is made up if it isn't given), and nodes given a `cluster_secret` only link up with nodes given the same one. The links are kept up in the background, and checked with pings; see get_cluster_peers() for their health. Raises ValueError for a peer that isn't a ws:// URL, or a loopback cluster node.
+///
+/// If `latency_histograms` is true, the server keeps histograms of how long messages take at each stage of sending (see get_latency_histograms()).
+///
+/// `lag_policy` says what happens when a client falls so far behind the broadcasts that 16 of them are queued for it: with "drop" (the default), it misses the oldest, which are counted in get_stats() (messages_dropped, and messages_missed_by_client) and recorded as "send" error events; with "block", the send functions wait (with the GIL released) for it to catch up instead, for at most `block_timeout_ms` if given, after which the broadcast goes ahead as with "drop". Broadcasts from other cluster nodes never wait. Raises ValueError for any other policy.
+///
+/// Clients are written to as soon as there's something to send them, unless it's arriving faster than that: then each client's writes are held back (for up to `max_flush_delay_ms`, 1 ms by default, the longer the load lasts) so that more messages go out in each write and flush, and bursts don't cost a pair of syscalls per message per client. A client goes back to being written to at once as soon as it's caught up. 0 never holds writes back. Raises ValueError for a negative delay.
+///
+/// With `cork_ms` (a few tenths of a millisecond, say), every write is held back at least that long, load or not, and takes whatever arrives meanwhile: for streams of small updates that come too far apart to count as load, but close enough together that a packet each is wasteful. Each message then waits up to `cork_ms` longer to go out. Raises ValueError for a negative cork.
+///
+/// If `memory_budget_bytes` is given, the server's queues hold at most that many payload bytes at once: broadcasts and targeted sends until they're written (a broadcast until its last client has written it), and received messages until they're drained. Sends that would take them over it raise SendError, and client messages that would are dropped, counted in get_stats() (messages_dropped, and messages_over_budget) and recorded as "receive" error events. get_stats() has the bytes queued (queued_bytes, and peak_queued_bytes) either way.
+///
+/// If `max_outbound_bytes_per_sec` is given, the server writes at most that many bytes a second to its clients' sockets, all clients together, so it can't saturate an uplink it shares: writes beyond it wait their turn, and the messages behind them wait in the clients' queues, as they would if the clients were slow (so `lag_policy` applies). Up to `outbound_burst_bytes` (by default, a tenth of a second's worth, and at least 64 KiB) go out at once after a quiet spell. get_stats() has the time writes have waited (rate_limit_wait_ns). Raises ValueError for a rate or burst of 0, or a burst without a rate.
+///
+/// Each client can be kept to a rate of its own too, e.g. to give remote viewers on slow links a throttled stream while the ones on the LAN get everything: `client_bytes_per_sec` is the limit for every client, and `client_bytes_per_sec_by_tag` maps client tags to limits of their own, e.g. {"remote": 250000}. Clients start out untagged; tag one with ServerHandle.set_client_tag() (once you know where it is, say from its connection event's client_id, which is its address) and it has its tag's limit (or `client_bytes_per_sec`, if the tag has none) from its next write on. Each limit allows bursts of a tenth of a second's worth (and at least 64 KiB). Raises ValueError for a limit of 0.
+///
+/// The server runs on a thread pool of its own, with a worker thread per core unless `worker_threads` says how many. If `worker_cores` is given, a list of core numbers (from 0, as the OS numbers them), the server's threads are pinned to those cores (on Linux only), so a busy process can't crowd them out, and it has a worker per listed core unless `worker_threads` is given too. With `isolate_cores`, the cores are also taken away from the thread calling this, and so from the threads it starts afterwards (which inherit its cores): start the server before a training job's thread pools, say, and they stay off its cores. Threads already running keep theirs. Raises ValueError for cores that don't exist, isolation that would leave the calling thread no cores, or 0 worker threads.
+///
+/// If `ping_interval_ms` is given, every client is pinged that often, which keeps NAT gateways and firewalls from dropping idle connections; a client that leaves `max_missed_pongs` pings in a row (2 by default) unanswered when the next is due is disconnected (with a close frame, if it can still take one), counted in get_stats() (keepalive_timeouts) and recorded as a "receive" error event, and its disconnection reported like any other. The pongs time each client's round trip, for get_client_stats(). With `unhealthy_after_missed_pongs`, a client that leaves that many pings in a row unanswered (fewer than `max_missed_pongs`) is marked unhealthy first: reported with an "unhealthy" connection event, and then a "healthy" one if it answers a ping before it's disconnected (get_client_stats() has its `healthy` meanwhile). Raises ValueError for an interval or max_missed_pongs of 0, an unhealthy_after_missed_pongs that isn't between 1 and max_missed_pongs - 1, or either without an interval.
+///
+/// If `idle_timeout_ms` is given, clients that neither send nor are sent a message (text or binary; keepalive pings don't count) for that long are sent a close frame and disconnected, e.g. to reclaim abandoned browser tabs; they're counted in get_stats() (idle_timeouts), and their disconnection is reported like any other. Raises ValueError for a timeout of 0.
+///
+/// If `ping_events` is true, clients' pings and pongs are reported too, as PingEvents from drain_ping_events(), for applications that keep track of their clients' liveness themselves (with send_ping(), say). Pongs answering the `ping_interval_ms` pings aren't.
+///
+/// If `time_sync` is true, clients can sync their clocks to the server's: a client sends a text message starting "time_sync:", followed by anything up to 256 bytes (its own clock reading, say), and is answered straight away, by the server's own threads, with JSON text echoing it along with the server's clocks as the request was read: {"topic": "time_sync", "echo": "123.4", "server_time_us": 1700000000000000, "server_mono_us": 1234567}. The requests aren't passed on to drain_client_messages(). With its clock's readings as it sent the request (t0) and got the answer (t3), the client reckons the server's clock is `server_time_us` - (t0 + t3) / 2 ahead of its own, give or take half the round trip. `server_time_us` is microseconds since the Unix epoch, and `server_mono_us` microseconds on a clock that never goes back (see send_stamped()).
+///
+/// If `playback_control` is true, clients can control playback, for applications that play something back to them (a recording, a simulation): a client sends the text messages "playback:pause", "playback:resume", "playback:seek:<seconds>" (0 or more) or "playback:speed:<rate>" (more than 0; 1 for real time), which the server's own threads apply to a playback state shared by every client, rather than passing them on to drain_client_messages(). The application reads where playback should be from get_playback_state(), and the controls as they come from drain_playback_events(). A malformed control is ignored, and recorded as a warning error event.
+///
+/// If `chaos_seed` is given, the server misbehaves on purpose, for testing that clients cope with a flaky network (reconnecting and resyncing, say); never in production. Each write to a client drops its connection instead (without a close frame) with a chance of `chaos_drop_rate` (0 to 1), is held back a random time up to `chaos_max_delay_ms`, and has its messages shuffled, none moving more than `chaos_reorder_window` places. Messages are never altered. The decisions are random, but the same seed makes the same ones for each connection in turn, so a failure can be reproduced. Raises ValueError for a drop rate outside 0 to 1, a negative delay, or the other chaos arguments without a seed.
+///
+/// To emulate distant clients on slow links (for testing on a LAN), `link_latency_ms` delays every client's messages by that long, give or take up to `link_jitter_ms` (never reordering them), after sending them at `link_bits_per_sec` if it's given: e.g. 200 and 2000000 for a 200 ms, 2 Mbit/s link. Messages wait behind a busy link as they would behind a slow client, so `lag_policy` applies. Pings and close frames aren't delayed. Raises ValueError for a negative latency or jitter, or a bandwidth of 0.
+///
+/// If `watchdog_stall_timeout_ms` is given, a watchdog thread watches the server's accept loop and its clients' sender tasks: one that's been stuck that long in the middle of something (a deadlock, say), or that panics, is recorded as a "critical" error event. With `watchdog_restart`, it's restarted too: the accept loop starts over, and a client's sender task is cancelled, disconnecting the client. Only a task stuck waiting can be restarted, not one blocking its thread. Set the timeout well above the longest a slow client can hold up a write. Raises ValueError for a timeout of 0, or `watchdog_restart` without a timeout.
+///
+/// If `heartbeat_interval_ms` is given, the server broadcasts a heartbeat that often by itself, as JSON text: {"topic": "heartbeat", "seq": 42, "server_time_ms": 1700000000000, "clients": 3}, with `heartbeat_topic` as its topic if given. They come from the server's own threads, whatever Python's doing, so a client that hears heartbeats but nothing else knows its connection's fine and it's the code sending the messages that's gone quiet. `seq` counts from 0 as the server starts, and `server_time_ms` is the server's clock. Heartbeats aren't relayed to other cluster nodes, and are skipped rather than going over `memory_budget_bytes`. Raises ValueError for an interval of 0, or `heartbeat_topic` without an interval.
+///
+/// `max_topic_rates_hz` caps how often samples sent with send_sample() go out on each of its topics, e.g. {"imu": 30} for a 1 kHz sensor that browsers only need to draw at 30 Hz. Samples that come too soon are held, and `decimation` says what goes out as the interval's up: "drop" (the default), the latest; or "average", their average, for samples that are JSON numbers, arrays of numbers or flat objects (whose numbers are averaged, and other values taken from the latest sample). Samples that can't be averaged go out as with "drop". Raises ValueError for a rate that isn't more than 0, or any other decimation.
+///
+/// `default_client_topic_rates_hz` caps how often each client is written the frames sent with send_frame() on each of its topics, e.g. {"camera": 5}: a frame that comes too soon after the last one the client was written waits (being replaced by newer ones, as for a slow client) until the interval's up. If `topic_throttle_requests` is true, clients can set their own rates too, for phones and other slow viewers: a client sends the text message "throttle:<topic>:<rate>" (frames a second, more than 0), or "throttle:<topic>:off" for every frame, which the server's own threads take rather than passing it on to drain_client_messages(). A malformed request is ignored, and recorded as a warning error event. Raises ValueError for a default rate that isn't more than 0.
+///
+/// `sync_groups` groups topics whose messages clients play back together, e.g. {"robot": ["camera", "pose", "imu"]}: messages sent with send_synced() on a group's topics are tagged with a presentation timestamp, and go out in its order. Each is held for `sync_window_ms` (50 by default) first, for those with earlier timestamps that are sent after it to catch up; one that comes after a message with a later timestamp has gone out is dropped, rather than going out of order. Raises ValueError for a topic in two groups, or a window less than 0.
+///
+/// If `capability_formats` is given, clients negotiate what they're sent: as each connects, it's sent the formats the application can encode, as JSON text ({"topic": "capabilities", "formats": ["webp", "jpeg"]}), and can declare its capabilities, then or later, with a text message like "capabilities:formats=webp,jpeg;max_resolution=1280x720;topics=camera,pose" (the formats it decodes, in its order of preference; the largest resolution it wants; and the topics it wants, all of them if it doesn't say; other keys are kept for the application). The server's own threads take the declaration, rather than passing it on to drain_client_messages(): they answer with the format negotiated, the client's first that's offered ({"topic": "capabilities", "format": "webp"}, or null if there's none in common), and report a "capabilities" connection event; get_client_capabilities() then has them. A malformed declaration is ignored, and recorded as a warning error event. Raises ValueError for an empty format, or one with a ',', ';', '=' or whitespace in it.
+///
+/// If `input_aggregation_ms` is given, clients' input is aggregated, for interactive applications with many clients sending it at a high rate (cursor positions, slider values): a client sends the text message "input:<name>:<value>" (e.g. "input:cursor:0.25,0.75"), which the server's own threads take, rather than passing it on to drain_client_messages(), keeping each client's latest value of each input. Every `input_aggregation_ms`, those sent since the last are taken as one InputSnapshot for drain_input_snapshots(), so 100 clients sending input 60 times a second make 1000 / `input_aggregation_ms` snapshots a second rather than 6000 messages. A malformed input (one without a name, or a client's 257th name) is ignored, and recorded as a warning error event. Raises ValueError for an interval that isn't more than 0.
+///
+/// If `rosbridge` is true, clients can speak the rosbridge v2 protocol, so ROS web tooling (roslibjs and what's built on it) can connect straight to the server: the server's own threads take the JSON ops a client subscribes to and unsubscribes from topics with ({"op": "subscribe", "topic": "/pose", "id": "subscribe:/pose:1"}), and its advertises and unadvertises, rather than passing them on to drain_client_messages(). publish_ros() publishes a message to the clients subscribed to its topic, as {"op": "publish", "topic": "/pose", "msg": {...}}. Clients' own publish ops are passed on to drain_client_messages() as they are, for the application to route by their "topic". Service calls and the other ops the server doesn't do are answered with an error "status" op (and service calls with a failed "service_response"); a malformed op (without its topic, say) is answered the same way, and recorded as a warning error event. Subscriptions' throttle_rate, queue_length and compression are ignored.
+///
+/// Metrics logged with log_scalar() and log_histogram() are held for `metrics_flush_ms` (100 by default) for more to go out with them, then broadcast together, as one compact binary frame, on the reserved "metrics" topic, for quicksocket/metrics.js to chart. The server keeps each tag's last `metrics_history` points (1000 by default; 0 keeps none), and writes them to each client as it connects, so a dashboard opened mid-run charts the run so far. Raises ValueError for a flush interval less than 0.
+///
+/// With `compression`, clients that offer the permessage-deflate extension as they connect (as browsers do) are written the broadcasts' messages of `compression_min_bytes` or more (1024 by default) deflated, and can send theirs deflated too. Each broadcast message is deflated once, however many clients it goes to, by a pool of `compression_threads` threads of the server's own (2 by default); clients that didn't offer the extension are written the original. Messages sent to a single client, and frames, go out as they are. Raises ValueError for 0 threads, or for the other compression arguments without `compression`.
+///
+/// If `audit_log_path` is given, the server appends a line of JSON to that file for every websocket connection it accepts or rejects, and for every accepted one when it closes: e.g. {"t": 1700000000.123456, "event": "accepted", "peer": "10.0.0.7:50000", "ip": "10.0.0.7", "path": "/feed", "principal": "alice", "reason": null}, with `event` one of "accepted", "rejected" and "closed", and `reason` saying why a connection was rejected or closed. quicksocket doesn't authenticate clients, so `principal` is the value of the request header named by `audit_principal_header` (e.g. "X-Forwarded-User", set by an authenticating proxy in front of the server), or null. The file is rotated once it would pass `audit_log_max_bytes` (10 MiB by default): it becomes `audit_log_path`.1, the one before that .2, and so on, keeping `audit_log_max_files` of them (5 by default). Entries are written from a thread of their own, and are all in the file once the server's stopped; failed writes are recorded as "audit" error events. Raises ValueError if the file can't be opened, for a maximum size of 0, or for the other audit arguments without a path.
+///
+/// The server can be started again after shutdown_server(), even one that didn't wait: the old server thread is joined first, so the port is free. Raises QuicksocketError if the server is still running (and wasn't asked to shut down), and BindError if `port` isn't a valid port number. The port is bound in the background; use wait_until_started() to wait for it (and to find out if binding failed).
+///
+/// The module-level functions all operate on the server started here. To run more than one server in a process, use start_server_instance() instead.
+///
+/// Everything but `port` is passed by keyword; raises TypeError for an argument it doesn't take.
+#[pyfunction(kwargs = "**")]
+pub fn start_server(py: Python, port: u32, kwargs: Option<&pyo3::types::PyDict>) -> PyResult<bool> {
     // For now, start_server can only be called if the server is not already running.
-    if is_server_running() {
-      consumer_state::weakly_record_error("Server is already running, can't invoke start_server().".to_string());
-      return false;
+    if let Some(previous) = default_server() {
+        prepare_restart(py, &previous)?;
+    }
+
+    let server = start(port, server_config("start_server", kwargs)?)?;
+    if cs::set_value(&cs::CS_DEFAULT_SERVER, server.state().clone()).is_err() {
+        return Err(QuicksocketError::new_err("Started the server, but failed to store it as the default server."));
     }
+    Ok(true)
+}
 
-    let server_started = server::start(port).is_ok();
-    if !server_started { return false; }
+/// Makes sure a previously started server is completely gone before starting its replacement: if it's been asked to shut down, waits (with the GIL released) for its thread and callback thread to exit, so its port is free again. Raises QuicksocketError if it's still running and wasn't asked to shut down.
+fn prepare_restart(py: Python, previous: &Server) -> PyResult<()> {
+    if previous.is_alive() && !previous.shutdown_requested() {
+        return Err(QuicksocketError::new_err("Server is already running, can't start it again."));
+    }
+    shutdown(py, previous, true, ShutdownOptions::default())
+}
 
-    println!("Server started.");
-    return true;
+/// Starts an independent server instance and returns a ServerHandle for it, whose methods mirror the module-level functions. Any number of instances can run at once on different ports, e.g. a public viewer port and a localhost control port. Takes the same arguments as start_server().
+///
+/// Instances started this way aren't affected by (and don't affect) the module-level functions.
+#[pyfunction(kwargs = "**")]
+pub fn start_server_instance(port: u32, kwargs: Option<&pyo3::types::PyDict>) -> PyResult<ServerHandle> {
+    let server = start(port, server_config("start_server_instance", kwargs)?)?;
+    Ok(ServerHandle { server })
 }
 
-/// Gets whether the server is running.
+/// Gets whether the server is running: starting, serving, or still shutting down. See get_server_state() to tell those apart.
 #[pyfunction]
 pub fn is_server_running() -> bool {
-    cs::read(&cs::CS_SER_ALIVE_RX, |rx| {
-        println!("Returning server alive: {}", *rx.borrow());
-        *rx.borrow()
-    }).unwrap_or_else(|| {
-        println!("Failed to get server alive!");
-        false
-    })
+    let server_alive = default_server().map(|server| server.is_alive());
+    match server_alive {
+        Some(server_alive) => { log_debug!("Returning server alive: {}", server_alive); server_alive }
+        None => { log_debug!("Failed to get server alive!"); false }
+    }
+}
+
+/// Returns the server's lifecycle state, as one of:
+///
+/// - "starting": the server thread is binding its port (see wait_until_started()).
+/// - "running": listening and serving clients.
+/// - "draining": no longer accepting connections: begin_draining() was called, and the clients still connected are served as before, or the server is shutting down, and they're being sent close frames and their connections wound down.
+/// - "stopping": shutting down; the server's runtime is being torn down.
+/// - "stopped": the server was never started, or has finished shutting down.
+/// - "failed": the server couldn't bind its port, or its thread exited unexpectedly.
+///
+/// is_server_running() is true for the first four.
+#[pyfunction]
+pub fn get_server_state() -> &'static str {
+    default_server().map(|server| server.run_state().as_str()).unwrap_or_else(|| RunState::Stopped.as_str())
+}
+
+/// Blocks (with the GIL released) until the server has bound its listener, returning True, or until `timeout_ms` elapses, returning False. Without a timeout, waits for as long as binding takes.
+///
+/// start_server() returns as soon as the server thread is launched, before it has bound the port; call this to find out whether it actually did. Raises BindError (with `.port`, `.address`, `.reason`) if binding failed, e.g. because the port is already in use, and ServerNotRunning if the server was never started.
+#[pyfunction(timeout_ms = "None")]
+pub fn wait_until_started(py: Python, timeout_ms: Option<u64>) -> PyResult<bool> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("wait for the server to start"))?;
+    wait_until_started_for(py, &server, timeout_ms)
+}
+
+fn wait_until_started_for(py: Python, server: &Server, timeout_ms: Option<u64>) -> PyResult<bool> {
+    py.allow_threads(|| server.wait_until_started(timeout_ms.map(Duration::from_millis)))
+        .map_err(|err| errors::from_server_error(err, "wait for the server to start"))
+}
+
+/// Blocks (with the GIL released) until at least one client is connected, returning True, or until `timeout_ms` elapses or the server stops, returning False. Returns True immediately if a client is already connected. Without a timeout, waits for as long as it takes.
+///
+/// Waits for the server to bind its listener first, so it can be called straight after start_server(); like wait_until_started(), raises BindError if binding failed, and ServerNotRunning if the server was never started.
+#[pyfunction(timeout_ms = "None")]
+pub fn wait_for_client(py: Python, timeout_ms: Option<u64>) -> PyResult<bool> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("wait for a client"))?;
+    wait_for_client_for(py, &server, timeout_ms)
+}
+
+fn wait_for_client_for(py: Python, server: &Server, timeout_ms: Option<u64>) -> PyResult<bool> {
+    py.allow_threads(|| server.wait_for_client(timeout_ms.map(Duration::from_millis)))
+        .map_err(|err| errors::from_server_error(err, "wait for a client"))
+}
+
+/// Gets whether a client with this id (from its connection events, or ClientMessage.client_id) is connected, so targeted sends to it will be routed. False if the server isn't running.
+#[pyfunction]
+pub fn is_client_connected(client_id: &str) -> bool {
+    default_server().is_some_and(|server| server.is_client_connected(client_id))
 }
 
-/// Requests that the websocket server shut down. The server will not shut down immediately but will stop serving as soon as e.g. it processes the shutdown request and any existing network requests are resolved.
+/// Stops the server accepting connections, while the clients already connected go on being served as before, for a zero-downtime rollout: the port is closed, so a load balancer's health checks see the server's gone (and the server replacing it can bind the port), and its state becomes "draining". Once the last client has disconnected, a connection event of kind "drained" (with an empty client_id) is reported, at once if there are no clients; wait_until_drained() waits for that. The server keeps running until it's shut down.
+///
+/// Draining a server that's draining or stopped does nothing. Raises ServerNotRunning if the server was never started.
 #[pyfunction]
-pub fn shutdown_server() {
-    let res = cs::mutate(&cs::CS_SER_REQ_SHUTDOWN_TX, |tx| tx.send(true));
-    if res.is_none() {
-        println!("[api.rs] Warning! Failed to send shutdown request.");
+pub fn begin_draining() -> PyResult<()> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("drain the server"))?;
+    server.begin_draining();
+    Ok(())
+}
+
+/// Blocks (with the GIL released) until the server is draining (see begin_draining()) with no clients left, or has stopped, returning True, or until `timeout_ms` elapses, returning False. Without a timeout, waits for as long as the clients take to disconnect. Raises ServerNotRunning if the server was never started.
+#[pyfunction(timeout_ms = "None")]
+pub fn wait_until_drained(py: Python, timeout_ms: Option<u64>) -> PyResult<bool> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("wait for the server to drain"))?;
+    Ok(wait_until_drained_for(py, &server, timeout_ms))
+}
+
+fn wait_until_drained_for(py: Python, server: &Server, timeout_ms: Option<u64>) -> bool {
+    py.allow_threads(|| server.wait_until_drained(timeout_ms.map(Duration::from_millis)))
+}
+
+/// Requests that the websocket server shut down. The server will not shut down immediately but will stop serving as soon as e.g. it processes the shutdown request and any existing network requests are resolved. Connected clients are sent a close frame as part of the shutdown (1001 Going Away, with the reason "Server shutting down", unless `close_code` and `close_reason` say otherwise; e.g. 1012 Service Restart tells clients to come back soon), and the server waits for each of them to answer it before closing its connection, for at most `close_timeout_ms` (2000 by default). Raises ValueError for a code the server can't send (only 1000-1003, 1007-1014 and 3000-4999 are allowed) and a reason over 123 bytes of UTF-8.
+///
+/// If `timeout_ms` is given, the server thread's tasks still running that long into the shutdown are aborted, so a connection wedged mid-write can't keep the server (and a `wait`) from finishing; by default they're given the close timeout and a second more. Aborted tasks are recorded as an "internal" error event and listed in ShutdownHandle.aborted_tasks.
+///
+/// If the server was already asked to shut down, the close frame and timeouts asked for then are the ones that count.
+///
+/// If `wait` is true, blocks (with the GIL released) until the server thread has finished shutting down and has been joined.
+///
+/// If `progress` is true, returns a ShutdownHandle for following the shutdown: how many clients are left, how many close frames have been flushed, and whether it's done (e.g. to know when it's safe to exit the process), or for blocking until it is with ShutdownHandle.wait(). Otherwise returns None.
+///
+/// Shutting down a server that has already stopped does nothing. Raises ServerNotRunning if the server was never started.
+#[pyfunction(wait = "false", progress = "false", close_code = "None", close_reason = "None", close_timeout_ms = "None", timeout_ms = "None")]
+pub fn shutdown_server(py: Python, wait: bool, progress: bool, close_code: Option<u16>, close_reason: Option<String>, close_timeout_ms: Option<u64>, timeout_ms: Option<u64>) -> PyResult<Option<ShutdownHandle>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("shut down the server"))?;
+    shutdown_with_progress(py, &server, wait, progress, shutdown_options(close_code, close_reason, close_timeout_ms, timeout_ms)?)
+}
+
+/// The ShutdownOptions for shutdown_server()'s arguments: the default's, for those not given.
+fn shutdown_options(close_code: Option<u16>, close_reason: Option<String>, close_timeout_ms: Option<u64>, timeout_ms: Option<u64>) -> PyResult<ShutdownOptions> {
+    let default = ShutdownOptions::default();
+    let options = ShutdownOptions {
+        close_code: close_code.unwrap_or(default.close_code),
+        close_reason: close_reason.unwrap_or(default.close_reason),
+        close_timeout: close_timeout_ms.map_or(default.close_timeout, Duration::from_millis),
+        timeout: timeout_ms.map(Duration::from_millis),
+    };
+    options.validate().map_err(|err| pyo3::exceptions::PyValueError::new_err(format!("Invalid shutdown close frame: {}.", err)))?;
+    Ok(options)
+}
+
+fn shutdown_with_progress(py: Python, server: &Server, wait: bool, progress: bool, options: ShutdownOptions) -> PyResult<Option<ShutdownHandle>> {
+    let clients_at_shutdown = server.stats.current_clients();
+    shutdown(py, server, wait, options)?;
+    Ok(if progress { Some(ShutdownHandle { server: server.clone(), clients_at_shutdown }) } else { None })
+}
+
+fn shutdown(py: Python, server: &Server, wait: bool, options: ShutdownOptions) -> PyResult<()> {
+    server.shutdown_with(options, false).map_err(|err| errors::from_server_error(err, "shut down the server"))?;
+    if !wait { return Ok(()); }
+    join_server(py, server);
+    Ok(())
+}
+
+/// Joins the server thread (if nobody has yet) and then its callback thread. Only call once the server has stopped, or been asked to.
+fn join_server(py: Python, server: &Server) {
+    py.allow_threads(|| server.join());
+    // The callback thread (if any) exits by itself once the server is gone; join it too, so nothing of the old server lingers. This fails harmlessly when called from the callback itself.
+    py.allow_threads(|| { let _ = message_callback::stop_for(server); });
+}
+
+/// Progress of a requested shutdown, as returned by shutdown_server(progress=True). Its fields are live: each read reflects the shutdown as it is now.
+#[pyclass]
+pub struct ShutdownHandle {
+    server: Server,
+    clients_at_shutdown: u64,
+}
+
+#[pymethods]
+impl ShutdownHandle {
+    /// The server's state (see get_server_state()): "draining" and then "stopping" while the shutdown is underway (possibly "running" for a moment first, until the server thread sees the request), "stopped" once it's done.
+    #[getter]
+    fn state(&self) -> &'static str {
+        self.server.run_state().as_str()
+    }
+
+    /// Whether the server thread has finished shutting down, so nothing more will be sent to clients.
+    #[getter]
+    fn done(&self) -> bool {
+        !self.server.is_alive()
+    }
+
+    /// Clients connected when the shutdown was requested.
+    #[getter]
+    fn clients_at_shutdown(&self) -> u64 {
+        self.clients_at_shutdown
+    }
+
+    /// Clients whose connections haven't closed yet.
+    #[getter]
+    fn clients_remaining(&self) -> u64 {
+        self.server.stats.current_clients()
+    }
+
+    /// Close frames written and flushed to clients so far.
+    #[getter]
+    fn close_frames_sent(&self) -> u64 {
+        self.server.stats.close_frames_sent()
+    }
+
+    /// Clients that have answered their close frame so far. (The others are disconnected once the close timeout is up.)
+    #[getter]
+    fn close_acks_received(&self) -> u64 {
+        self.server.stats.close_acks_received()
     }
+
+    /// What the shutdown aborted for still running at its deadline (see shutdown_server()'s timeout_ms), e.g. "client 127.0.0.1:50312's sender task"; empty unless it had to.
+    #[getter]
+    fn aborted_tasks(&self) -> Vec<String> {
+        self.server.tasks.aborted()
+    }
+
+    /// Blocks (with the GIL released) until the shutdown is done, and the server thread has been joined, returning True; or until `timeout_ms` elapses, returning False.
+    #[args(timeout_ms = "None")]
+    fn wait(&self, py: Python, timeout_ms: Option<u64>) -> bool {
+        let done = py.allow_threads(|| self.server.wait_for_shutdown(timeout_ms.map(Duration::from_millis)));
+        if done { join_server(py, &self.server); }
+        done
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ShutdownHandle {
+    fn __repr__(&self) -> String {
+        format!(
+            "<quicksocket.ShutdownHandle for port {}: {}, {} of {} client(s) remaining, {} close frame(s) sent>",
+            self.server.port, self.server.run_state().as_str(), self.server.stats.current_clients(), self.clients_at_shutdown, self.server.stats.close_frames_sent()
+        )
+    }
+}
+
+/// Shuts down every running server (sending clients close frames) and waits for their threads to exit, then stops relays, replays, message callback threads and Python log forwarding. Registered with atexit when the module is imported, so scripts that exit without calling shutdown_server() don't hang or leave sockets behind; it's safe to call more than once.
+#[pyfunction]
+pub fn shutdown_all_servers() {
+    // Takes no `py` argument so pyo3 generates a plain no-arguments wrapper, which atexit can call directly.
+    Python::with_gil(|py| {
+        let servers = cs::read(&cs::CS_SERVERS, |servers| servers.clone()).unwrap_or_default();
+        for server in servers {
+            // Servers that already stopped (or never bound) have nothing left to do but possibly be joined.
+            let _ = shutdown(py, &Server::from(server), true, ShutdownOptions::default());
+        }
+        py.allow_threads(|| {
+            let relays = PY_RELAYS.lock().map(|mut relays| std::mem::take(&mut *relays)).unwrap_or_default();
+            for relay in relays { relay.stop(); }
+            let replays = PY_REPLAYS.lock().map(|mut replays| std::mem::take(&mut *replays)).unwrap_or_default();
+            for replay in replays { replay.stop(); }
+            message_callback::stop_all();
+            log_bridge::disable();
+        });
+    })
+}
+
+/// Opts in to handling SIGINT and SIGTERM (Ctrl-C on Windows): when either arrives, every running server is shut down gracefully, and get_shutdown_signal() starts returning the signal's name. Meant for headless deployments, so that e.g. `systemctl stop` or `docker stop` stops the servers cleanly. Enabling it again does nothing.
+///
+/// Python's own SIGINT handling still happens (raising KeyboardInterrupt in the main thread), but SIGTERM no longer terminates the process by itself; check get_shutdown_signal(), or wait for the servers to stop, and exit.
+#[pyfunction]
+pub fn enable_signal_handling() -> PyResult<()> {
+    signals::enable().map_err(QuicksocketError::new_err)
+}
+
+/// Returns the name of the last shutdown signal received ("SIGINT" or "SIGTERM") since enable_signal_handling() was called, or None if there hasn't been one.
+#[pyfunction]
+pub fn get_shutdown_signal() -> Option<&'static str> {
+    signals::received_signal()
 }
 
 /// Returns a string describing the nature of the last error the server encountered. No error has been detected if this function returns None.
+///
+/// Errors that happen in quick succession overwrite each other here; use drain_error_events() to see all of them.
 #[pyfunction]
 pub fn get_last_error_string() -> Option<String> {
     consumer_state::try_get_last_error()
 }
 
-/// Retrieves a List (Rust: Vec<String>) of all new client connection events that have occurred since this function was last called.
+/// Returns the most recent errors recorded by any server or API call, oldest first, as ErrorEvents (see drain_error_events()): the last `count` of them if given, otherwise all that are kept (the last 100, by default; see set_recent_error_capacity()).
+///
+/// Unlike drain_error_events(), this doesn't consume anything, so it can be called from anywhere (e.g. a status page) without taking events away from the code that handles them.
+#[pyfunction(count = "None")]
+pub fn get_recent_errors(count: Option<usize>) -> Vec<ErrorEvent> {
+    server::error_events::recent(count).into_iter().map(ErrorEvent::from).collect()
+}
+
+/// Sets how many recent errors get_recent_errors() can return (100 by default). Lowering it discards the oldest ones; zero stops keeping them at all.
+#[pyfunction]
+pub fn set_recent_error_capacity(capacity: usize) {
+    server::error_events::set_recent_capacity(capacity)
+}
+
+/// Returns the event log's entries after `since_seq` (all that are kept, the last 1000, if not given), oldest first, as EventLogEntries: servers binding their ports, clients connecting and disconnecting, servers draining and shutting down, and every error event, each with a `seq`, a `timestamp`, a `level`, a `kind`, a `message`, and the `client_id` it concerns, if any.
+///
+/// It's for looking back at what happened, e.g. after a crash, without any logging set up. Nothing is consumed, so to follow it, pass the `seq` of the last entry seen; a jump in the sequence numbers means entries were dropped in between, being older than the last 1000.
+#[pyfunction(since_seq = "None")]
+pub fn get_event_log(since_seq: Option<u64>) -> Vec<EventLogEntry> {
+    server::event_log::since(since_seq.unwrap_or(0)).into_iter().map(EventLogEntry::from).collect()
+}
+
+/// Retrieves a List of ErrorEvents for all errors recorded since this function was last called, oldest first. Each has a `timestamp` (seconds since the Unix epoch, as from time.time()), a `severity`, a `category`, a `message`, and the `client_id` it concerns, if any:
+///
+/// - `severity` is "warning" (a single connection or request had a problem), "error" (the server or an API call did), or "critical" (one of the server's own tasks panicked, or the watchdog saw one stall). A panic's message says which task, and where; its `backtrace` is the panic's backtrace if RUST_BACKTRACE is set (None otherwise, and for other errors).
+/// - `category` is one of "bind", "http", "handshake", "send", "receive", "callback", "proxy", "redis", "kafka", "zmq", "cluster", "otel", "recording", "audit", or "internal".
+///
+/// Unlike get_last_error_string(), errors don't overwrite each other between calls (up to a limit of 1024 undrained events, past which the oldest are dropped).
+#[pyfunction]
+pub fn drain_error_events() -> Vec<ErrorEvent> {
+    server::error_events::drain().into_iter().map(ErrorEvent::from).collect()
+}
+
+/// Retrieves a List (Rust: Vec<String>) of the ids of all clients that have connected since this function was last called.
+///
+/// This is the id-only form of drain_connection_events(). Both drain the same queue, so use one or the other; disconnection events drained by this function are discarded.
 #[pyfunction]
 pub fn drain_new_client_events(py: Python) -> Vec<String> {
+    match default_server() {
+        Some(server) => drain_new_client_events_for(py, &server),
+        None => vec![],
+    }
+}
+
+fn drain_new_client_events_for(py: Python, server: &Server) -> Vec<String> {
+    drain_connection_events_for(py, server).into_iter()
+        .filter(|event| event.change == ConnectionChange::Connected)
+        .map(|event| event.client_id)
+        .collect()
+}
+
+/// Retrieves a List of ConnectionEvents for all clients that have connected or disconnected since this function was last called, oldest first. Each has the `client_id`, a `timestamp` (as from time.time()), and a `kind`: "connected" or "disconnected". A disconnection's `close_code` and `close_reason` are those of the close frame the client sent (e.g. 1001 from a browser tab that was closed), or None if it sent none.
+#[pyfunction]
+pub fn drain_connection_events(py: Python) -> Vec<ConnectionEvent> {
+    match default_server() {
+        Some(server) => drain_connection_events_for(py, &server).into_iter().map(ConnectionEvent::from).collect(),
+        None => vec![],
+    }
+}
+
+fn drain_connection_events_for(py: Python, server: &Server) -> Vec<server::events::ConnectionEvent> {
+    py.allow_threads(|| server.drain_connection_events())
+}
+
+/// Retrieves a List of PingEvents for the pings and pongs clients have sent since this function was last called, oldest first, if the server was started with `ping_events`; an empty list otherwise. Each has the `client_id`, a `timestamp` (as from time.time()), a `kind` ("ping" or "pong"), and the frame's `payload` (bytes). Pings have been answered by the time they're drained. At most 1024 are kept between drains; later ones are dropped until there's room.
+#[pyfunction]
+pub fn drain_ping_events(py: Python) -> Vec<PingEvent> {
+    match default_server() {
+        Some(server) => drain_ping_events_for(py, &server),
+        None => vec![],
+    }
+}
+
+fn drain_ping_events_for(py: Python, server: &Server) -> Vec<PingEvent> {
+    py.allow_threads(|| server.drain_ping_events()).into_iter().map(|event| PingEvent::new(py, event)).collect()
+}
+
+/// Retrieves where clients' playback is, as a PlaybackState, if the server was started with `playback_control`; None otherwise (or if no server is running). It has whether it's `paused`, its `position` (in seconds, as of this call: it moves on at `speed` while playing), its `speed` (1.0 for real time), how many `changes` clients have made, and the `client_id` of the last to make one. Playback starts out playing, from 0 at speed 1.0, as the server starts.
+#[pyfunction]
+pub fn get_playback_state(py: Python) -> Option<PlaybackState> {
+    default_server().and_then(|server| get_playback_state_for(py, &server))
+}
+
+fn get_playback_state_for(py: Python, server: &Server) -> Option<PlaybackState> {
+    py.allow_threads(|| server.playback_state()).map(PlaybackState::from)
+}
+
+/// Retrieves a List of PlaybackEvents for the playback controls clients have sent since this function was last called, oldest first, if the server was started with `playback_control`; an empty list otherwise. Each has the `client_id`, a `timestamp` (as from time.time()), the `control` ("pause", "resume", "seek" or "speed"), its `value` (the position or speed, or None), and the `state` it left playback in. At most 1024 are kept between drains; later ones are dropped until there's room (though they still change the state).
+#[pyfunction]
+pub fn drain_playback_events(py: Python) -> Vec<PlaybackEvent> {
+    match default_server() {
+        Some(server) => drain_playback_events_for(py, &server),
+        None => vec![],
+    }
+}
+
+fn drain_playback_events_for(py: Python, server: &Server) -> Vec<PlaybackEvent> {
+    py.allow_threads(|| server.drain_playback_events()).into_iter().map(PlaybackEvent::from).collect()
+}
+
+/// Retrieves a List of InputSnapshots of the input clients have sent since this function was last called, oldest first, if the server was started with `input_aggregation_ms`; an empty list otherwise. Each has a `timestamp` (as from time.time()), the `inputs`, each client's latest value of each input it sent over the interval ({client_id: {name: value}}, the values as str), and how many input messages, `updates`, went into it. Intervals without input have no snapshot. At most 1024 are kept between drains; after that, input goes on being merged until there's room, so only the values in between are lost.
+#[pyfunction]
+pub fn drain_input_snapshots(py: Python) -> Vec<InputSnapshot> {
+    match default_server() {
+        Some(server) => drain_input_snapshots_for(py, &server),
+        None => vec![],
+    }
+}
+
+fn drain_input_snapshots_for(py: Python, server: &Server) -> Vec<InputSnapshot> {
+    py.allow_threads(|| server.drain_input_snapshots()).into_iter().map(InputSnapshot::from).collect()
+}
+
+/// Pings a single client, with up to 125 bytes of `payload` for its pong to echo back (see drain_ping_events()). Browsers answer pings by themselves. The ping is queued behind the client's other messages, like try_send_to_client()'s.
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if no client with that id is connected or its send queue is full, and ValueError for a payload over 125 bytes.
+#[pyfunction(payload = "None")]
+pub fn send_ping(py: Python, client_id: &str, payload: Option<MessagePayload>) -> PyResult<()> {
+    send_ping_for(py, default_server().as_ref(), client_id, payload)
+}
+
+fn send_ping_for(py: Python, server: Option<&Server>, client_id: &str, payload: Option<MessagePayload>) -> PyResult<()> {
+    let payload = match payload {
+        Some(MessagePayload::Binary(bytes)) => bytes,
+        Some(_) => { return Err(pyo3::exceptions::PyTypeError::new_err("A ping's payload must be bytes-like, not str.")); }
+        None => vec![],
+    };
+    if payload.len() > server::events::MAX_PING_PAYLOAD {
+        return Err(pyo3::exceptions::PyValueError::new_err(format!("A ping's payload can be at most {} bytes, not {}.", server::events::MAX_PING_PAYLOAD, payload.len())));
+    }
     py.allow_threads(|| {
-        let drained_new_cli_evts = cs::mutate(&cs::CS_CLI_CONN_RX, |rx| {
-            let mut new_cli_evts = vec![];
-            while let Some(Some(new_cli)) = rx.recv().now_or_never() {
-                new_cli_evts.push(new_cli);
-            }
-            new_cli_evts
-        });
-        if drained_new_cli_evts.is_none() {
-            return vec![]
-        }
-        let drained_new_cli_evts = drained_new_cli_evts.unwrap();
-        
-        drained_new_cli_evts
+        let server = server.ok_or_else(|| errors::server_not_running("send a ping"))?;
+        server.send_ping(client_id, payload).map_err(|err| errors::from_server_error(err, "send a ping"))
     })
 }
 
-/// Valid message payloads in the list of messages to provide to try_send_message consist of strings (text messages) and bytes (binary messages).
+/// Valid message payloads in the list of messages to provide to try_send_message consist of strings (text messages) and bytes, bytearrays, memoryviews, or any other object supporting the buffer protocol (binary messages).
 ///
 /// Passing any other type within the list of objects will raise an exception.
-#[derive(FromPyObject)]
 pub enum MessagePayload {
-    #[pyo3(transparent, annotation = "str")]
     Text(String),
-    #[pyo3(transparent, annotation = "bytes")]
-    Binary(Vec<u8>)
+    Binary(Vec<u8>),
+    /// A received binary message large enough to be handed to Python without copying (see start_server's zero_copy_min_bytes). Never produced by extraction from Python.
+    ZeroCopyBinary(Vec<u8>),
+}
+impl<'source> FromPyObject<'source> for MessagePayload {
+    fn extract(obj: &'source PyAny) -> PyResult<Self> {
+        if let Ok(text) = obj.downcast::<pyo3::types::PyString>() {
+            return Ok(MessagePayload::Text(text.to_str()?.to_string()));
+        }
+        if let Ok(bytes) = obj.downcast::<pyo3::types::PyBytes>() {
+            return Ok(MessagePayload::Binary(bytes.as_bytes().to_vec()));
+        }
+        if let Some(buffer) = ByteBuffer::get(obj) {
+            return Ok(MessagePayload::Binary(buffer?.as_slice().to_vec()));
+        }
+        Err(pyo3::exceptions::PyTypeError::new_err(format!(
+            "Message payloads must be str, bytes, RegisteredMessage, or support the buffer protocol (e.g. bytearray, memoryview), not {}.", obj.get_type().name().unwrap_or("<unknown>")
+        )))
+    }
+}
+impl MessagePayload {
+    /// Converts a client message into the python-convertible MessagePayload type. For now, ping/pong and Close websocket messages are ignored (None). Binary messages of at least `zero_copy_min_bytes` bytes (if given) become ZeroCopyBinary payloads.
+    pub(crate) fn from_ws_message(msg: WsMessage, zero_copy_min_bytes: Option<usize>) -> Option<MessagePayload> {
+        match msg {
+            WsMessage::Text(text)    => { Some(MessagePayload::Text(text)) }
+            WsMessage::Binary(bytes) if zero_copy_min_bytes.is_some_and(|min| bytes.len() >= min) => {
+                Some(MessagePayload::ZeroCopyBinary(bytes))
+            }
+            WsMessage::Binary(bytes) => { Some(MessagePayload::Binary(bytes)) }
+            WsMessage::Ping(_)       => { None }
+            WsMessage::Pong(_)       => { None }
+            WsMessage::Close(_)      => { None }
+        }
+    }
+
+    /// Converts a drained message (always text or binary: drains skip control messages) straight to its Python object.
+    pub(crate) fn data_into_py(py: Python, msg: WsMessage, zero_copy_min_bytes: Option<usize>) -> PyObject {
+        MessagePayload::from_ws_message(msg, zero_copy_min_bytes).map_or_else(|| py.None(), |payload| payload.into_py(py))
+    }
 }
 impl IntoPy<PyObject> for MessagePayload {
     fn into_py(self, py: Python) -> PyObject {
         match self {
             MessagePayload::Text(text) => {
-                pyo3::types::PyUnicode::new(py, text.as_str()).into()
+                py_str(py, &text)
             }
             MessagePayload::Binary(bytes) => {
                 pyo3::types::PyBytes::new(py, &bytes).into()
             }
+            MessagePayload::ZeroCopyBinary(bytes) => {
+                // Moves the Vec into the Python object; the bytes themselves aren't copied.
+                Py::new(py, MessageBuffer::new(bytes))
+                    .map(|buffer| buffer.into_py(py))
+                    .unwrap_or_else(|err| err.into_py(py))
+            }
         }
     }
 }
 
-/// Send messages to all connected clients. The socket stream is flushed after buffering each message in the argument List, so it's better to call this once per 'update,' rather than calling this method multiple times if multiple messages are all available to be sent.
+/// A str holding `text`. Python checks the UTF-8 it's handed as it decodes it, but received text has been checked already (by tungstenite), so ASCII text (most JSON) is copied straight into a new str instead, which is many times quicker. Other text still goes through Python's decoder, which is as quick as decoding it unchecked would be.
+fn py_str(py: Python, text: &str) -> PyObject {
+    if text.is_ascii() {
+        unsafe {
+            let obj = pyo3::ffi::PyUnicode_New(text.len() as pyo3::ffi::Py_ssize_t, 127);
+            if !obj.is_null() {
+                // A new ASCII str's data is one byte per character, with room for all of them.
+                std::ptr::copy_nonoverlapping(text.as_ptr(), pyo3::ffi::PyUnicode_DATA(obj) as *mut u8, text.len());
+                return PyObject::from_owned_ptr(py, obj);
+            }
+            // (Out of memory: the MemoryError is set. Clear it and let the usual path fail too.)
+            pyo3::ffi::PyErr_Clear();
+        }
+    }
+    pyo3::types::PyUnicode::new(py, text).into()
+}
+
+/// bytes payloads at least this big are sent from the bytes object itself, rather than copied (see SharedPyBytes). Smaller ones are copied anyway, into the headers' buffer, when they're written (see writer.rs), so sharing them would only add a reference to drop later.
+const SHARED_BYTES_MIN: usize = 4096;
+
+/// A bytes object's contents, shared with the server for as long as it's sending them. bytes are immutable and their contents never move, and the reference held here keeps the object alive, so the contents can be read from any thread, with or without the GIL.
 ///
-/// The List may contain strings or bytes.
+/// The last reference to go is usually dropped on a tokio thread, without the GIL; pyo3 then releases the object the next time Python calls into quicksocket.
+struct SharedPyBytes {
+    _bytes: Py<pyo3::types::PyBytes>,
+    ptr: *const u8,
+    len: usize,
+}
+unsafe impl Send for SharedPyBytes {}
+unsafe impl Sync for SharedPyBytes {}
+
+impl AsRef<[u8]> for SharedPyBytes {
+    fn as_ref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+/// The contents of a message payload, borrowed from the Python object so they can be copied out with the GIL released.
+///
+/// This is sound because str and bytes are immutable, buffer exports pin their memory (see ByteBuffer), and the borrowed objects are kept alive (by the caller's argument references) for as long as the BorrowedPayloads are in use. Big bytes payloads are shared instead, and so kept alive for as long as the server needs them.
+enum BorrowedPayload {
+    Text { ptr: *const u8, len: usize },
+    Bytes { ptr: *const u8, len: usize },
+    Buffer(ByteBuffer),
+    /// (Created with the GIL held, since that's when the bytes object's reference count can be changed.)
+    Shared(Arc<SharedPyBytes>),
+    /// A RegisteredMessage's frame.
+    Registered(PreparedMessage),
+}
+// The pointed-to bytes outlive the BorrowedPayload and aren't freed or moved while it exists (see above), so reading them from another thread is fine.
+unsafe impl Send for BorrowedPayload {}
+unsafe impl Sync for BorrowedPayload {}
+
+impl BorrowedPayload {
+    fn borrow(obj: &PyAny) -> PyResult<BorrowedPayload> {
+        if let Ok(registered) = obj.extract::<PyRef<RegisteredMessage>>() {
+            return Ok(BorrowedPayload::Registered(registered.prepared.clone()));
+        }
+        if let Ok(text) = obj.downcast::<pyo3::types::PyString>() {
+            // The UTF-8 representation is cached on the str object, so it lives as long as the object does.
+            let text = text.to_str()?;
+            return Ok(BorrowedPayload::Text { ptr: text.as_ptr(), len: text.len() });
+        }
+        if let Ok(bytes) = obj.downcast::<pyo3::types::PyBytes>() {
+            let data = bytes.as_bytes();
+            if data.len() >= SHARED_BYTES_MIN {
+                return Ok(BorrowedPayload::Shared(Arc::new(SharedPyBytes { ptr: data.as_ptr(), len: data.len(), _bytes: bytes.into() })));
+            }
+            return Ok(BorrowedPayload::Bytes { ptr: data.as_ptr(), len: data.len() });
+        }
+        // bytearray, memoryview, and anything else exposing a buffer.
+        if let Some(buffer) = ByteBuffer::get(obj) {
+            return Ok(BorrowedPayload::Buffer(buffer?));
+        }
+        Err(pyo3::exceptions::PyTypeError::new_err(format!(
+            "Message payloads must be str, bytes, RegisteredMessage, or support the buffer protocol (e.g. bytearray, memoryview), not {}.", obj.get_type().name().unwrap_or("<unknown>")
+        )))
+    }
+
+    /// Copies the payload into a websocket message, in a buffer from the outbound pool.
+    fn to_ws_message(&self) -> WsMessage {
+        match self {
+            BorrowedPayload::Text { ptr, len } => {
+                let bytes = unsafe { std::slice::from_raw_parts(*ptr, *len) };
+                // The bytes came from a &str, so they're valid UTF-8.
+                OUTBOUND.text(unsafe { std::str::from_utf8_unchecked(bytes) })
+            }
+            BorrowedPayload::Bytes { ptr, len } => {
+                OUTBOUND.binary(unsafe { std::slice::from_raw_parts(*ptr, *len) })
+            }
+            BorrowedPayload::Buffer(buffer) => {
+                OUTBOUND.binary(buffer.as_slice())
+            }
+            BorrowedPayload::Shared(bytes) => {
+                OUTBOUND.binary((**bytes).as_ref())
+            }
+            BorrowedPayload::Registered(prepared) => {
+                Outbound::from(prepared.clone()).into_message()
+            }
+        }
+    }
+
+    /// The payload as a message for the server: shared if it can be, copied (as for to_ws_message()) if not.
+    fn to_outbound(&self) -> Outbound {
+        match self {
+            BorrowedPayload::Shared(bytes) => Outbound::SharedBinary(bytes.clone()),
+            BorrowedPayload::Registered(prepared) => Outbound::Prepared(prepared.clone()),
+            payload => payload.to_ws_message().into(),
+        }
+    }
+}
+
+/// A message payload registered with register_message(), to be sent any number of times: its websocket frame is encoded once, when it's registered, and every send of it (broadcast or to one client, by any server) writes that same frame, with neither a copy nor an encoding. For payloads sent over and over, like static assets or the snapshot every new client is sent.
+#[pyclass]
+pub struct RegisteredMessage {
+    prepared: PreparedMessage,
+    /// The payload's length, in bytes.
+    #[pyo3(get)] len: usize,
+    /// Whether it's sent as a text message (it was registered as a str) rather than a binary one.
+    #[pyo3(get)] is_text: bool,
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for RegisteredMessage {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.RegisteredMessage {}, {} bytes>", if self.is_text { "text" } else { "binary" }, self.len)
+    }
+}
+
+/// Registers a payload to be sent any number of times, returning a RegisteredMessage to put in the lists given to the send functions in its place. A str is registered as a text message; bytes, or any other buffer-protocol object, as a binary one. The payload is copied, so changing a buffer afterwards doesn't change the message.
+///
+/// Raises TypeError for unsupported payload types.
+#[pyfunction]
+pub fn register_message(payload: &PyAny) -> PyResult<RegisteredMessage> {
+    let prepared = match BorrowedPayload::borrow(payload)? {
+        BorrowedPayload::Text { ptr, len } => {
+            // (From a &str, as for to_ws_message().)
+            PreparedMessage::text(unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(ptr, len)) })
+        }
+        BorrowedPayload::Bytes { ptr, len } => PreparedMessage::binary(unsafe { std::slice::from_raw_parts(ptr, len) }),
+        BorrowedPayload::Buffer(buffer) => PreparedMessage::binary(buffer.as_slice()),
+        BorrowedPayload::Shared(bytes) => PreparedMessage::binary((*bytes).as_ref()),
+        BorrowedPayload::Registered(prepared) => prepared,
+    };
+    Ok(RegisteredMessage { len: prepared.payload().len(), is_text: prepared.is_text(), prepared })
+}
+
+/// Send messages to all connected clients. The socket stream is flushed after buffering each message in the argument List, so it's better to call this once per 'update,' rather than calling this method multiple times if multiple messages are all available to be sent.
+///
+/// The List may contain strings (text messages) or bytes, bytearrays, memoryviews, or other buffer-protocol objects (binary messages). Buffers must be C-contiguous; their raw bytes are sent regardless of element type.
 ///
 /// Will return false if there are not currently any active subscribers (websocket clients), indicating no data was sent. False may also be returned if there was an error trying to access the broadcast channel in the first place (i.e. thread contention to access it).
 ///
-/// A return value of true does not guarantee all websocket clients received the message, as the tokio tasks for forwarding the messages to the clients must be able to receive the broadcast messages to forward them, which is subject to thread/task contention.
+/// A return value of true does not guarantee all websocket clients received the message, as the tokio tasks for forwarding the messages to the clients must be able to receive the broadcast messages to forward them, which is subject to thread/task contention.
+///
+/// Only borrowing the payloads' contents happens with the GIL held; copying them into websocket messages and handing them to the server happen with the GIL released, so large batched sends don't stall other Python threads. bytes payloads of 4 KiB or more aren't copied at all: the server holds on to the bytes objects, and writes them to the clients' sockets from where they are.
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if the messages couldn't be handed to the server, and TypeError for unsupported payload types.
+#[pyfunction]
+pub fn try_send_messages(py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+    send_messages(py, default_server().as_ref(), messages)
+}
+
+fn send_messages(py: Python, server: Option<&Server>, messages: Vec<&PyAny>) -> PyResult<()> {
+    let borrowed = messages.iter().map(|msg| BorrowedPayload::borrow(msg)).collect::<PyResult<Vec<_>>>()?;
+
+    // (Borrowed rather than moved into the closure, so any buffer views are released back here with the GIL held.)
+    py.allow_threads(|| {
+        let messages: Vec<Outbound> = borrowed.iter().map(BorrowedPayload::to_outbound).collect();
+        let server = server.ok_or_else(|| errors::server_not_running("send messages"))?;
+        server.send(messages).map_err(|err| errors::from_server_error(err, "send messages"))
+    })
+}
+
+/// Sends messages to a single client, rather than broadcasting them: `client_id` is the id from its connection events (or ClientMessage.client_id). Like try_send_messages(), this doesn't wait for the messages to be written; see send_and_confirm() for that.
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if no client with that id is connected or its send queue is full, and TypeError for unsupported payload types.
+#[pyfunction]
+pub fn try_send_to_client(py: Python, client_id: &str, messages: Vec<&PyAny>) -> PyResult<()> {
+    send_to_client(py, default_server().as_ref(), client_id, messages, Delivery::Queue)
+}
+
+/// Sends messages to a single client like try_send_to_client(), then blocks (with the GIL released) until they've been written and flushed to the client's socket, for control commands that must not be silently dropped. If `timeout_ms` is given, waits at most that long (including for room in the client's send queue).
+///
+/// Raises SendError (with `.reason`) if the client isn't connected, disconnects before the messages are flushed, writing them fails, or the timeout elapses first; in the last case the messages may still be sent later. Note that a flush means the bytes were handed to the OS, not that the client has processed them.
+#[pyfunction(timeout_ms = "None")]
+pub fn send_and_confirm(py: Python, client_id: &str, messages: Vec<&PyAny>, timeout_ms: Option<u64>) -> PyResult<()> {
+    send_to_client(py, default_server().as_ref(), client_id, messages, Delivery::Confirm { timeout: timeout_ms.map(Duration::from_millis) })
+}
+
+fn send_to_client(py: Python, server: Option<&Server>, client_id: &str, messages: Vec<&PyAny>, delivery: Delivery) -> PyResult<()> {
+    let borrowed = messages.iter().map(|msg| BorrowedPayload::borrow(msg)).collect::<PyResult<Vec<_>>>()?;
+
+    py.allow_threads(|| {
+        let messages: Vec<Outbound> = borrowed.iter().map(BorrowedPayload::to_outbound).collect();
+        let server = server.ok_or_else(|| errors::server_not_running("send messages"))?;
+        server.send_to_client_with(client_id, messages, delivery).map_err(|err| errors::from_server_error(err, "send messages"))
+    })
+}
+
+/// As try_send_messages(), stamping the messages with the server's clocks as they're sent: its wall clock (`server_time_us`, microseconds since the Unix epoch) and a monotonic one (`server_mono_us`, microseconds on a clock that never goes back, for measuring intervals). A str that's a JSON object has them spliced in as its first fields ('{"x": 1}' goes out as '{"server_time_us":1700000000000000,"server_mono_us":1234567,"x": 1}'); any other str is wrapped in a JSON object with them, as a string under "data"; and binary payloads are prefixed with 16 bytes, the two times as big-endian unsigned 64-bit integers. See `time_sync` in start_server() for syncing a client's clock to the server's.
+#[pyfunction]
+pub fn send_stamped(py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+    send_stamped_for(py, default_server().as_ref(), messages)
+}
+
+fn send_stamped_for(py: Python, server: Option<&Server>, messages: Vec<&PyAny>) -> PyResult<()> {
+    let borrowed = messages.iter().map(|msg| BorrowedPayload::borrow(msg)).collect::<PyResult<Vec<_>>>()?;
+
+    py.allow_threads(|| {
+        let messages: Vec<Outbound> = borrowed.iter().map(BorrowedPayload::to_outbound).collect();
+        let server = server.ok_or_else(|| errors::server_not_running("send messages"))?;
+        server.send_stamped(messages).map_err(|err| errors::from_server_error(err, "send messages"))
+    })
+}
+
+/// Sends a sample under `topic` to all connected clients, at most at the topic's rate in `max_topic_rates_hz` (see start_server()), so a high-rate stream can be published sample by sample without overwhelming browsers. The first sample goes out straight away; one that comes sooner after the last one sent is held until the interval's up, and then goes out, unless a later sample replaced it (with `decimation="drop"`) or it's averaged with the later ones (with "average"). Samples that didn't go out on their own are counted in get_stats() (samples_decimated). They never wait for slow clients, whatever the `lag_policy`, and aren't relayed to other cluster nodes. On a topic without a rate, a sample's sent as by try_send_messages(). `message` is a str or a buffer-protocol object, as for try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, and TypeError for an unsupported payload type.
+#[pyfunction]
+pub fn send_sample(py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+    send_sample_for(py, default_server().as_ref(), topic, message)
+}
+
+fn send_sample_for(py: Python, server: Option<&Server>, topic: &str, message: &PyAny) -> PyResult<()> {
+    let borrowed = BorrowedPayload::borrow(message)?;
+
+    py.allow_threads(|| {
+        let message = borrowed.to_outbound();
+        let server = server.ok_or_else(|| errors::server_not_running("send a sample"))?;
+        server.send_sample(topic, message).map_err(|err| errors::from_server_error(err, "send a sample"))
+    })
+}
+
+/// Sends a message under `topic`, one of a group's in `sync_groups` (see start_server()), to all connected clients, tagged with its presentation timestamp `pts_us` (microseconds on whatever clock the group's streams share, such as their capture time), or, if it's None, the server's monotonic clock as it's sent (see send_stamped()). A str that's a JSON object has the tags spliced in as its first fields ('{"x": 1}' goes out as '{"sync_group":"robot","sync_topic":"pose","pts_us":1234567,"x": 1}'); any other str is wrapped in a JSON object with them, as a string under "data"; and binary payloads are prefixed with the timestamp, as a big-endian unsigned 64-bit integer, then the topic's length in bytes, as a big-endian unsigned 16-bit integer, then the topic. A group's messages go out in timestamp order, each `sync_window_ms` after it's sent, so every client gets them in that order; one sent after a message with a later timestamp has gone out is dropped, and counted in get_stats() (synced_late). They never wait for slow clients, whatever the `lag_policy`, and aren't relayed to other cluster nodes. `message` is a str or a buffer-protocol object, as for try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if `topic` isn't in a sync group, and TypeError for an unsupported payload type.
+#[pyfunction(pts_us = "None")]
+pub fn send_synced(py: Python, topic: &str, message: &PyAny, pts_us: Option<u64>) -> PyResult<()> {
+    send_synced_for(py, default_server().as_ref(), topic, message, pts_us)
+}
+
+fn send_synced_for(py: Python, server: Option<&Server>, topic: &str, message: &PyAny, pts_us: Option<u64>) -> PyResult<()> {
+    let borrowed = BorrowedPayload::borrow(message)?;
+
+    py.allow_threads(|| {
+        let message = borrowed.to_outbound();
+        let server = server.ok_or_else(|| errors::server_not_running("send a synced message"))?;
+        server.send_synced(topic, message, pts_us).map_err(|err| errors::from_server_error(err, "send a synced message"))
+    })
+}
+
+/// Publishes a ROS message on `topic` to the clients subscribed to it over rosbridge (see `rosbridge` in start_server()), as a publish op: {"op": "publish", "topic": topic, "msg": msg}. `msg` is the message, as a str of a JSON object. Returns how many clients it was queued for; a subscriber whose queue is full misses it, and it's counted as lagged in get_stats(). Never blocks.
+///
+/// Raises ServerNotRunning if the server isn't running, and SendError if it wasn't started with `rosbridge`, or `msg` isn't a JSON object.
+#[pyfunction]
+pub fn publish_ros(py: Python, topic: &str, msg: &str) -> PyResult<usize> {
+    publish_ros_for(py, default_server().as_ref(), topic, msg)
+}
+
+fn publish_ros_for(py: Python, server: Option<&Server>, topic: &str, msg: &str) -> PyResult<usize> {
+    py.allow_threads(|| {
+        let server = server.ok_or_else(|| errors::server_not_running("publish a ROS message"))?;
+        server.publish_ros(topic, msg).map_err(|err| errors::from_server_error(err, "publish a ROS message"))
+    })
+}
+
+/// Sends a frame to all connected clients under `topic`, latest-frame-only: each client is only ever written the newest frame of a topic. If it hasn't been written the topic's previous frame yet (it's slow, or its link is, or it's throttled the topic; see `default_client_topic_rates_hz` in start_server()), it skips that one and is written this one in its place, rather than working through a queue of stale frames; get_stats() counts the frames skipped (frames_skipped), which isn't an error. For camera frames, plots and the like, where only the latest matters. Frames go out with the clients' other messages, but aren't ordered with them, and never wait, whatever the `lag_policy`. `message` is a str or a buffer-protocol object, as for try_send_messages(). Frames aren't relayed to other cluster nodes, or shown by the inspector.
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if the frame would take the server over `memory_budget_bytes`, and TypeError for an unsupported payload type.
+#[pyfunction]
+pub fn send_frame(py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+    send_frame_for(py, default_server().as_ref(), topic, message)
+}
+
+fn send_frame_for(py: Python, server: Option<&Server>, topic: &str, message: &PyAny) -> PyResult<()> {
+    let borrowed = BorrowedPayload::borrow(message)?;
+
+    py.allow_threads(|| {
+        let message = borrowed.to_outbound();
+        let server = server.ok_or_else(|| errors::server_not_running("send a frame"))?;
+        server.send_frame(topic, message).map_err(|err| errors::from_server_error(err, "send a frame"))
+    })
+}
+
+/// Sends a keyframe on `topic` to all connected clients: the whole of the state the deltas sent on it from now on (see send_delta()) are changes to. The server keeps the topic's latest keyframe, and the deltas sent since, so that a client that connects later, or falls behind and misses some of them, is sent the keyframe and the deltas it needs before any more deltas; keyframes should be sent often enough to keep that short, as it counts against `memory_budget_bytes` until the next one. Keyframes and deltas never wait, whatever the `lag_policy`, and aren't relayed to other cluster nodes. `message` is a str or a buffer-protocol object, as for try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, SendError if the keyframe would take the server over `memory_budget_bytes`, and TypeError for an unsupported payload type.
+#[pyfunction]
+pub fn send_keyframe(py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+    send_keyframe_for(py, default_server().as_ref(), topic, message, false)
+}
+
+/// Sends a delta on `topic` to all connected clients, following the topic's latest keyframe (see send_keyframe()) and the deltas sent since. A client that hasn't been sent those (it's just connected, or it fell behind the broadcasts) is sent them first, and counted in get_stats() (keyframes_resent).
+///
+/// Raises SendError if no keyframe has been sent on `topic`, and as send_keyframe() otherwise.
+#[pyfunction]
+pub fn send_delta(py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+    send_keyframe_for(py, default_server().as_ref(), topic, message, true)
+}
+
+fn send_keyframe_for(py: Python, server: Option<&Server>, topic: &str, message: &PyAny, delta: bool) -> PyResult<()> {
+    let borrowed = BorrowedPayload::borrow(message)?;
+    let operation = if delta { "send a delta" } else { "send a keyframe" };
+
+    py.allow_threads(|| {
+        let message = borrowed.to_outbound();
+        let server = server.ok_or_else(|| errors::server_not_running(operation))?;
+        let sent = if delta { server.send_delta(topic, message) } else { server.send_keyframe(topic, message) };
+        sent.map_err(|err| errors::from_server_error(err, operation))
+    })
+}
+
+/// Sends a point cloud to all connected clients as a binary message, in the compact layout quicksocket/geometry.js decodes (see the README): for 3D visualizers, without packing the bytes by hand. `xyz` is a buffer-protocol object (a numpy array of shape (n, 3), say) holding x, y, z for each point in turn, as float32s (or float64s or integers, which are converted); `colors`, if given, holds 3 (RGB) or 4 (RGBA) uint8s for each point. The message is a broadcast like any other, sent as by try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, TypeError if `xyz` or `colors` isn't a buffer-protocol object, and ValueError if they're of the wrong type or don't add up to whole points.
+#[pyfunction(colors = "None")]
+pub fn send_pointcloud(py: Python, xyz: &PyAny, colors: Option<PyObject>) -> PyResult<()> {
+    send_pointcloud_for(py, default_server().as_ref(), xyz, colors.as_ref().map(|colors| colors.as_ref(py)))
+}
+
+fn send_pointcloud_for(py: Python, server: Option<&Server>, xyz: &PyAny, colors: Option<&PyAny>) -> PyResult<()> {
+    let xyz = ByteBuffer::get_typed(xyz)?;
+    let colors = colors.map(ByteBuffer::get_typed).transpose()?;
+
+    py.allow_threads(|| {
+        let positions = xyz.to_f32s().map_err(|reason| geometry_error("xyz", &reason))?;
+        let colors = match &colors {
+            Some(colors) if colors.format().trim_start_matches(&['@', '=', '<', '>', '!'][..]) != "B" => {
+                return Err(geometry_error("colors", &format!("expected uint8s, not elements of format {:?}", colors.format())));
+            }
+            // (4 channels if there are 4 bytes a point; otherwise 3, and point_cloud() checks they add up.)
+            Some(colors) => Some((colors.as_slice(), if !positions.is_empty() && colors.len() == positions.len() / 3 * 4 { 4 } else { 3 })),
+            None => None,
+        };
+        let message = geometry::point_cloud(&positions, colors).map_err(|reason| geometry_error("point cloud", &reason))?;
+        let server = server.ok_or_else(|| errors::server_not_running("send a point cloud"))?;
+        server.send(vec![message]).map_err(|err| errors::from_server_error(err, "send a point cloud"))
+    })
+}
+
+/// Sends a triangle mesh to all connected clients as a binary message, in the compact layout quicksocket/geometry.js decodes (see the README). `vertices` is a buffer-protocol object (a numpy array of shape (n, 3), say) holding x, y, z for each vertex in turn, as float32s (or float64s or integers, which are converted), and `faces` one holding three vertex indices for each triangle, as integers of any size (sent as uint32s). The message is a broadcast like any other, sent as by try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, TypeError if `vertices` or `faces` isn't a buffer-protocol object, and ValueError if they're of the wrong type, don't add up to whole vertices and triangles, or a face refers to a vertex that isn't there.
+#[pyfunction]
+pub fn send_mesh(py: Python, vertices: &PyAny, faces: &PyAny) -> PyResult<()> {
+    send_mesh_for(py, default_server().as_ref(), vertices, faces)
+}
+
+fn send_mesh_for(py: Python, server: Option<&Server>, vertices: &PyAny, faces: &PyAny) -> PyResult<()> {
+    let vertices = ByteBuffer::get_typed(vertices)?;
+    let faces = ByteBuffer::get_typed(faces)?;
+
+    py.allow_threads(|| {
+        let vertices = vertices.to_f32s().map_err(|reason| geometry_error("vertices", &reason))?;
+        let faces = faces.to_u32s().map_err(|reason| geometry_error("faces", &reason))?;
+        let message = geometry::mesh(&vertices, &faces).map_err(|reason| geometry_error("mesh", &reason))?;
+        let server = server.ok_or_else(|| errors::server_not_running("send a mesh"))?;
+        server.send(vec![message]).map_err(|err| errors::from_server_error(err, "send a mesh"))
+    })
+}
+
+fn geometry_error(what: &str, reason: &str) -> PyErr {
+    pyo3::exceptions::PyValueError::new_err(format!("Invalid {}: {}.", what, reason))
+}
+
+/// Sends a figure to all connected clients on the reserved topic "figure", under `figure_id`, for quicksocket/figures.js to show (see the README): a Plotly figure as its JSON (figure.to_json()), or a Matplotlib figure as a PNG. A Matplotlib figure whose canvas draws to pixels (Agg's, as in notebooks and headless scripts) is drawn, and its pixels encoded without holding the GIL; any other is saved with savefig(). The message is a broadcast like any other, sent as by try_send_messages().
+///
+/// Raises ServerNotRunning if the server isn't running, TypeError if `figure` isn't a Plotly or Matplotlib figure, and SendError if `figure_id` is longer than 65535 bytes.
+#[pyfunction(figure_id = "\"figure\"")]
+pub fn send_figure(py: Python, figure: &PyAny, figure_id: &str) -> PyResult<()> {
+    send_figure_for(py, default_server().as_ref(), figure, figure_id)
+}
+
+fn send_figure_for(py: Python, server: Option<&Server>, figure: &PyAny, figure_id: &str) -> PyResult<()> {
+    let figure = FigureData::get(py, figure)?;
+
+    py.allow_threads(|| {
+        let server = server.ok_or_else(|| errors::server_not_running("send a figure"))?;
+        server.send_figure(figure_id, figure.as_figure()).map_err(|err| errors::from_server_error(err, "send a figure"))
+    })
+}
+
+/// A figure taken from Python, to be sent once the GIL's released.
+enum FigureData {
+    Plotly(String),
+    Png(Vec<u8>),
+    Rgba { width: u32, height: u32, pixels: ByteBuffer },
+}
+
+impl FigureData {
+    fn get(py: Python, figure: &PyAny) -> PyResult<FigureData> {
+        // (By the package its class, or a class it derives from, is defined in.)
+        let is_from = |package: &str| -> PyResult<bool> {
+            for class in figure.get_type().getattr("__mro__")?.iter()? {
+                let module = class?.getattr("__module__")?.extract::<String>().unwrap_or_default();
+                if module == package || module.starts_with(&format!("{}.", package)) { return Ok(true); }
+            }
+            Ok(false)
+        };
+        if is_from("plotly")? {
+            return Ok(FigureData::Plotly(figure.call_method0("to_json")?.extract()?));
+        }
+        if !is_from("matplotlib")? {
+            return Err(pyo3::exceptions::PyTypeError::new_err(format!("Expected a Plotly or Matplotlib figure, not {}.", figure.get_type().name()?)));
+        }
+
+        let canvas = figure.getattr("canvas")?;
+        if canvas.hasattr("buffer_rgba")? {
+            canvas.call_method0("draw")?;
+            let pixels = canvas.call_method0("buffer_rgba")?;
+            if let Ok((height, width, 4)) = pixels.getattr("shape").and_then(|shape| shape.extract::<(u32, u32, usize)>()) {
+                return Ok(FigureData::Rgba { width, height, pixels: ByteBuffer::get_typed(pixels)? });
+            }
+        }
+        let png = py.import("io")?.call_method0("BytesIO")?;
+        figure.call_method("savefig", (png,), Some(pyo3::types::IntoPyDict::into_py_dict(vec![("format", "png")], py)))?;
+        Ok(FigureData::Png(png.call_method0("getvalue")?.extract::<&[u8]>()?.to_vec()))
+    }
+
+    fn as_figure(&self) -> Figure<'_> {
+        match self {
+            FigureData::Plotly(json) => Figure::Plotly(json),
+            FigureData::Png(png) => Figure::Png(png),
+            FigureData::Rgba { width, height, pixels } => Figure::Rgba { width: *width, height: *height, pixels: pixels.as_slice() },
+        }
+    }
+}
+
+/// Logs a scalar metric (a loss, an accuracy, a learning rate) at `step`, under `tag`, for quicksocket/metrics.js to chart (see the README). What's logged is held for the server's `metrics_flush_ms` and broadcast in one frame with what's logged meanwhile, on the reserved topic "metrics", so it can be called every step of a training loop; the server also keeps the tag's last `metrics_history` points, for clients that connect later.
+///
+/// Raises ServerNotRunning if the server isn't running, and SendError if `tag` is empty or longer than 65535 bytes.
+#[pyfunction]
+pub fn log_scalar(py: Python, tag: &str, step: i64, value: f64) -> PyResult<()> {
+    log_scalar_for(py, default_server().as_ref(), tag, step, value)
+}
+
+fn log_scalar_for(py: Python, server: Option<&Server>, tag: &str, step: i64, value: f64) -> PyResult<()> {
+    py.allow_threads(|| {
+        let server = server.ok_or_else(|| errors::server_not_running("log a scalar"))?;
+        server.log_scalar(tag, step, value).map_err(|err| errors::from_server_error(err, "log a scalar"))
+    })
+}
+
+/// Logs a histogram of `values` (weights, gradients, rewards) at `step`, under `tag`, like log_scalar(): `bins` bins, splitting the range from the least value to the greatest evenly, are counted here, without holding the GIL, and only the counts are sent. `values` is a buffer of numbers (a numpy array, say, of any shape) or a sequence of them; NaNs and infinities are left out.
+///
+/// Raises ServerNotRunning if the server isn't running, TypeError if `values` aren't numbers, and SendError if `tag` is empty or longer than 65535 bytes, or for `bins` of 0 or more than 1024.
+#[pyfunction(bins = "30")]
+pub fn log_histogram(py: Python, tag: &str, step: i64, values: &PyAny, bins: usize) -> PyResult<()> {
+    log_histogram_for(py, default_server().as_ref(), tag, step, values, bins)
+}
+
+fn log_histogram_for(py: Python, server: Option<&Server>, tag: &str, step: i64, values: &PyAny, bins: usize) -> PyResult<()> {
+    let values = match ByteBuffer::get(values) {
+        // (Taken again with its format, to read its elements as numbers.)
+        Some(_) => {
+            let buffer = ByteBuffer::get_typed(values)?;
+            py.allow_threads(|| buffer.to_f64s()).map_err(|reason| pyo3::exceptions::PyTypeError::new_err(format!("Invalid histogram values: {}.", reason)))?
+        }
+        None => values.extract::<Vec<f64>>()?,
+    };
+
+    py.allow_threads(|| {
+        let server = server.ok_or_else(|| errors::server_not_running("log a histogram"))?;
+        server.log_histogram(tag, step, &values, bins).map_err(|err| errors::from_server_error(err, "log a histogram"))
+    })
+}
+
+/// Sends Python objects to all clients, serialized with `serializer` (pickle.dumps by default; any callable returning bytes or str works, e.g. json.dumps). Raises SendError if an object can't be serialized or serializes to more than `max_bytes` (16 MiB by default), in which case none of them are sent.
+///
+/// Only for trusted clients running the same codebase: see drain_python_objects() for why.
+#[pyfunction(serializer = "None", max_bytes = "None")]
+pub fn send_python_objects(py: Python, objects: Vec<&PyAny>, serializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<()> {
+    send_python_objects_for(py, default_server().as_ref(), objects, serializer, max_bytes)
+}
+
+fn send_python_objects_for(py: Python, server: Option<&Server>, objects: Vec<&PyAny>, serializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<()> {
+    let object_count = objects.len();
+    let serialized = objects::serialize(py, objects, serializer, max_bytes.unwrap_or(objects::DEFAULT_MAX_OBJECT_BYTES))
+        .map_err(|reason| errors::send_error(&reason, object_count))?;
+    send_messages(py, server, serialized.iter().map(|data| data.as_ref(py)).collect())
+}
+
+/// Drains client messages as drain_client_messages() does (with the same `timeout_ms` and `max_messages`), deserializing each with `deserializer` (pickle.loads by default). Messages over `max_bytes` (16 MiB by default), and messages that fail to deserialize, are skipped and recorded as error events (see drain_error_events()).
+///
+/// WARNING: unpickling runs arbitrary code chosen by whoever sent the data. Only use the default deserializer when every client that can connect is as trusted as your own code, e.g. another process of the same application on a private network; never for browsers or anything reachable from outside.
+#[pyfunction(timeout_ms = "None", max_messages = "None", deserializer = "None", max_bytes = "None")]
+pub fn drain_python_objects(py: Python, timeout_ms: Option<u64>, max_messages: Option<usize>, deserializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<Vec<PyObject>> {
+    match default_server() {
+        Some(server) => drain_python_objects_for(py, &server, timeout_ms, max_messages, deserializer, max_bytes),
+        None => Ok(vec![]),
+    }
+}
+
+fn drain_python_objects_for(py: Python, server: &Server, timeout_ms: Option<u64>, max_messages: Option<usize>, deserializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<Vec<PyObject>> {
+    let messages = py.allow_threads(|| {
+        drain_client_messages_blocking(server, timeout_ms, max_messages.unwrap_or(usize::MAX)).unwrap_or_default()
+    });
+    objects::deserialize(py, server, messages, deserializer, max_bytes.unwrap_or(objects::DEFAULT_MAX_OBJECT_BYTES))
+}
+
+/// Drains all messages pending from all clients and returns them as a list of str (text messages) and bytes (binary messages). Clients are not distinguished; pass `structured=True` to get ClientMessage objects instead, which carry the message's `data` along with the `client_id` that sent it and its arrival `timestamp`.
+///
+/// If `timeout_ms` is given and no messages are pending, blocks (with the GIL released) until at least one message arrives or the timeout elapses, whichever is first. An empty list is returned on timeout.
+///
+/// If `max_messages` is given, at most that many messages are returned; any remaining messages stay queued for the next call. This bounds how long a single drain can take during a burst of inbound messages.
+#[pyfunction(timeout_ms = "None", max_messages = "None", structured = "false")]
+pub fn drain_client_messages(py: Python, timeout_ms: Option<u64>, max_messages: Option<usize>, structured: bool) -> PyObject {
+    match default_server() {
+        Some(server) => drain_client_messages_for(py, &server, timeout_ms, max_messages, structured),
+        None => pyo3::types::PyList::empty(py).into(),
+    }
+}
+
+fn drain_client_messages_for(py: Python, server: &Server, timeout_ms: Option<u64>, max_messages: Option<usize>, structured: bool) -> PyObject {
+    drain_into_py(py, server.config.zero_copy_min_bytes, structured, |slots| {
+        let _ = server.drain_messages_into(timeout_ms.map(Duration::from_millis), max_messages.unwrap_or(usize::MAX), slots);
+    })
+}
+
+/// Most message slots a thread keeps between drains; after a bigger burst, its buffer is let go of rather than held onto for good.
+const MAX_KEPT_DRAIN_SLOTS: usize = 32 * 1024;
+
+thread_local! {
+    /// Each thread's buffer of message slots for drain_client_messages(), kept from drain to drain, so a drain loop taking thousands of small messages at a time fills the same buffer each time instead of growing a new one.
+    static DRAIN_SLOTS: RefCell<Vec<server::events::ClientMessage>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Drains (with `drain`, with the GIL released) into the thread's message slots, then converts the messages for Python in one pass, into a list allocated at its final size.
+fn drain_into_py(py: Python, zero_copy_min_bytes: Option<usize>, structured: bool, drain: impl FnOnce(&mut Vec<server::events::ClientMessage>) + Send) -> PyObject {
+    // (Taken out of the cell for the drain, so nothing borrows it while Python runs.)
+    let mut slots = DRAIN_SLOTS.with(|slots| std::mem::take(&mut *slots.borrow_mut()));
+    py.allow_threads(|| drain(&mut slots));
+    let list = if structured {
+        ClientMessage::batch_into_py(py, slots.drain(..), zero_copy_min_bytes)
+    } else {
+        pyo3::types::PyList::new(py, slots.drain(..).map(|msg| MessagePayload::data_into_py(py, msg.message, zero_copy_min_bytes))).into()
+    };
+    if slots.capacity() <= MAX_KEPT_DRAIN_SLOTS {
+        DRAIN_SLOTS.with(|kept| *kept.borrow_mut() = slots);
+    }
+    list
+}
+
+/// The GIL-free body of drain_client_messages(): drains as Server::drain_messages() does, converting the messages for Python. Returns None if the client message receiver isn't available (e.g. a message callback holds it).
+fn drain_client_messages_blocking(server: &Server, timeout_ms: Option<u64>, max_messages: usize) -> Option<Vec<ReceivedMessage>> {
+    let zero_copy_min_bytes = server.config.zero_copy_min_bytes;
+    let messages = server.drain_messages(timeout_ms.map(Duration::from_millis), max_messages).ok()?;
+    Some(messages.into_iter().filter_map(|msg| ReceivedMessage::from_client_message(msg, zero_copy_min_bytes)).collect())
+}
+
+/// How long a MessageIterator blocks (with the GIL released) per wait before re-checking for signals (e.g. Ctrl+C) and server shutdown.
+const MESSAGE_ITER_POLL_MS: u64 = 100;
+
+/// Iterator over incoming client messages, returned by messages(). Each step blocks, with the GIL released, until a message arrives. Iteration stops once the server is no longer running (after any remaining messages are yielded), or when no message arrives within the iterator's timeout, if it has one.
+#[pyclass]
+pub struct MessageIterator {
+    /// None if there's no server to iterate over (iteration ends immediately).
+    server: Option<Server>,
+    pending: VecDeque<MessagePayload>,
+    timeout_ms: Option<u64>,
+}
+
+#[pyproto]
+impl PyIterProtocol for MessageIterator {
+    fn __iter__(slf: PyRef<Self>) -> PyRef<Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<MessagePayload>> {
+        if let Some(msg) = slf.pending.pop_front() { return Ok(Some(msg)); }
+
+        let server = match slf.server.clone() {
+            Some(server) => server,
+            None => { return Ok(None); }
+        };
+        let py = slf.py();
+        let deadline = slf.timeout_ms.map(|timeout_ms| Instant::now() + Duration::from_millis(timeout_ms));
+        loop {
+            let wait_ms = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now()).as_millis() as u64;
+                    if remaining == 0 { return Ok(None); }
+                    remaining.min(MESSAGE_ITER_POLL_MS)
+                }
+                None => MESSAGE_ITER_POLL_MS,
+            };
+            // Read server liveness *before* draining, so messages that arrived before a shutdown are still yielded.
+            let server_running = server.is_alive();
+            let drained = py.allow_threads(|| drain_client_messages_blocking(&server, Some(wait_ms), usize::MAX));
+            match drained {
+                Some(drained) if !drained.is_empty() => {
+                    slf.pending.extend(drained.into_iter().map(|msg| msg.payload));
+                    return Ok(slf.pending.pop_front());
+                }
+                Some(_) => {}
+                None if server_running => {
+                    return Err(QuicksocketError::new_err("The client message receiver is unavailable; is a message callback registered?"));
+                }
+                None => {}
+            }
+            if !server_running { return Ok(None); }
+            py.check_signals()?;
+        }
+    }
+}
+
+/// Returns an iterator that yields client messages (str or bytes) as they arrive, blocking with the GIL released between items, so message handling can be written as a plain for-loop. Iteration ends when the server stops, or if `timeout_ms` is given, when no message arrives for that long.
+#[pyfunction(timeout_ms = "None")]
+pub fn messages(timeout_ms: Option<u64>) -> MessageIterator {
+    MessageIterator { server: default_server(), pending: VecDeque::new(), timeout_ms }
+}
+
+/// Returns a file descriptor that becomes readable when client messages are pending (and when the server stops), for waiting on messages from an event loop without polling or a helper thread: e.g. asyncio's loop.add_reader(), or trio.lowlevel.wait_readable(). Draining messages makes it unreadable again, so wait on it, then drain with drain_client_messages() (without a timeout), and repeat. The quicksocket.aio module does this for asyncio and trio.
+///
+/// The descriptor belongs to the server; don't read from it or close it. It may also become readable when nothing is left to drain (e.g. a message callback took the messages), so treat an empty drain as a reason to wait again. Raises ServerNotRunning if the server was never started, and QuicksocketError on platforms without Unix sockets.
+#[pyfunction]
+pub fn get_message_fd() -> PyResult<i32> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get a message descriptor"))?;
+    server.notifier.fd().map_err(QuicksocketError::new_err)
+}
+
+/// Registers a callable to be invoked with each client message (str or bytes) as soon as it arrives, as an alternative to polling drain_client_messages(). Pass None to unregister it and go back to draining.
+///
+/// The callable runs on a dedicated callback thread, which holds the GIL only while calling it. While a callback is registered, drain_client_messages() returns nothing. Exceptions raised by the callable are printed and otherwise ignored. The server must be running to register a callback (ServerNotRunning is raised otherwise).
+#[pyfunction]
+pub fn set_on_message(py: Python, callback: Option<PyObject>) -> PyResult<()> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("set a message callback"))?;
+    set_on_message_for(py, &server, callback)
+}
+
+fn set_on_message_for(py: Python, server: &Server, callback: Option<PyObject>) -> PyResult<()> {
+    if let Some(callback) = &callback {
+        if !callback.as_ref(py).is_callable() {
+            return Err(pyo3::exceptions::PyTypeError::new_err("set_on_message() expects a callable or None."));
+        }
+    }
+    let registering = callback.is_some();
+    py.allow_threads(|| message_callback::set(server.state(), callback))
+        .map_err(|err| {
+            if registering && !server.is_alive() { errors::server_not_running("set a message callback") }
+            else { QuicksocketError::new_err(err) }
+        })
+}
+
+/// Aggregate server statistics, as returned by get_server_stats(). A snapshot: the fields don't update after it's taken.
+#[pyclass]
+#[derive(Clone)]
+pub struct ServerStats {
+    /// Seconds since the server started (until it stopped, if it has).
+    #[pyo3(get)] uptime_secs: f64,
+    /// Websocket clients accepted since the server started.
+    #[pyo3(get)] total_connections: u64,
+    #[pyo3(get)] current_clients: u64,
+    /// Messages (and payload bytes) written to clients; a broadcast to N clients counts N times.
+    #[pyo3(get)] messages_sent: u64,
+    #[pyo3(get)] bytes_sent: u64,
+    /// Text and binary messages (and payload bytes) received from clients.
+    #[pyo3(get)] messages_received: u64,
+    #[pyo3(get)] bytes_received: u64,
+    /// Messages lost on the way, to clients or from them, and the same by why they were lost: {reason: count}, where the reasons are "lagged" (broadcasts skipped by clients that fell behind), "over_budget" (messages dropped rather than take the queues over memory_budget_bytes), "disconnected" (messages sent to a client alone that it left before they were written) and "shutdown" (messages still queued as the server stopped, or sent after). Each loss is recorded as a warning error event too.
+    #[pyo3(get)] messages_dropped: u64,
+    #[pyo3(get)] messages_dropped_by_reason: std::collections::HashMap<String, u64>,
+    /// The connected clients that have fallen behind and missed server messages, and how many each has missed: {client_id: count}.
+    #[pyo3(get)] messages_missed_by_client: std::collections::HashMap<String, u64>,
+    /// Error events (see drain_error_events()) recorded since the server started, by severity (critical ones counting as errors).
+    #[pyo3(get)] warning_count: u64,
+    #[pyo3(get)] error_count: u64,
+    /// Cumulative nanoseconds the server has spent encoding outbound frames, ...
+    #[pyo3(get)] serialization_ns: u64,
+    /// ... that outbound batches have waited to be picked up by clients' sender tasks (summed over clients, like messages_sent), ...
+    #[pyo3(get)] channel_wait_ns: u64,
+    /// ... and writing to clients' sockets. Compare two snapshots to see where sending time goes.
+    #[pyo3(get)] socket_write_ns: u64,
+    /// Writes (each with its flush) of messages to clients' sockets; messages_sent / socket_writes is the messages per write, which grows as writes are batched under load.
+    #[pyo3(get)] socket_writes: u64,
+    /// Cumulative nanoseconds the send functions have waited for the slowest client to catch up, with lag_policy="block".
+    #[pyo3(get)] broadcast_wait_ns: u64,
+    /// Payload bytes in the server's queues right now, and the most there have been at once.
+    #[pyo3(get)] queued_bytes: u64,
+    #[pyo3(get)] peak_queued_bytes: u64,
+    /// Messages turned away because they'd have taken the queues over memory_budget_bytes: sends refused, and received messages dropped.
+    #[pyo3(get)] messages_over_budget: u64,
+    /// Cumulative nanoseconds clients' writes have waited for max_outbound_bytes_per_sec (summed over clients).
+    #[pyo3(get)] rate_limit_wait_ns: u64,
+    /// Clients disconnected for leaving too many keepalive pings unanswered (see ping_interval_ms).
+    #[pyo3(get)] keepalive_timeouts: u64,
+    /// Clients disconnected for being idle for idle_timeout_ms.
+    #[pyo3(get)] idle_timeouts: u64,
+    /// Frames clients skipped because a newer frame of the same topic was sent before they'd been written them (see send_frame()). Not counted in messages_dropped.
+    #[pyo3(get)] frames_skipped: u64,
+    /// Times a client was sent a keyframe again, and the deltas since, to catch up on a keyframe stream it had missed part of (see send_keyframe()).
+    #[pyo3(get)] keyframes_resent: u64,
+    /// Samples on topics with a maximum rate that didn't go out on their own, dropped for a later one or averaged with it (see send_sample()). Not counted in messages_dropped.
+    #[pyo3(get)] samples_decimated: u64,
+    /// Messages on sync groups' topics dropped for being sent after one with a later pts had gone out, having missed `sync_window_ms` (see send_synced()). Not counted in messages_dropped.
+    #[pyo3(get)] synced_late: u64,
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ServerStats {
+    fn __repr__(&self) -> String {
+        format!(
+            "ServerStats(uptime_secs={:.1}, total_connections={}, current_clients={}, messages_sent={}, bytes_sent={}, messages_received={}, bytes_received={}, messages_dropped={}, messages_dropped_by_reason={:?}, messages_missed_by_client={:?}, warning_count={}, error_count={}, serialization_ns={}, channel_wait_ns={}, socket_write_ns={}, socket_writes={}, broadcast_wait_ns={}, queued_bytes={}, peak_queued_bytes={}, messages_over_budget={}, rate_limit_wait_ns={}, keepalive_timeouts={}, idle_timeouts={}, frames_skipped={}, keyframes_resent={}, samples_decimated={}, synced_late={})",
+            self.uptime_secs, self.total_connections, self.current_clients, self.messages_sent, self.bytes_sent,
+            self.messages_received, self.bytes_received, self.messages_dropped, self.messages_dropped_by_reason, self.messages_missed_by_client, self.warning_count, self.error_count,
+            self.serialization_ns, self.channel_wait_ns, self.socket_write_ns, self.socket_writes, self.broadcast_wait_ns,
+            self.queued_bytes, self.peak_queued_bytes, self.messages_over_budget, self.rate_limit_wait_ns, self.keepalive_timeouts, self.idle_timeouts, self.frames_skipped, self.keyframes_resent, self.samples_decimated, self.synced_late
+        )
+    }
+}
+
+/// Returns a ServerStats snapshot for the running server (or the most recently stopped one), so apps can display server health without a metrics stack. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_server_stats() -> PyResult<ServerStats> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get server stats"))?;
+    Ok(server_stats(&server))
+}
+
+fn server_stats(server: &ServerState) -> ServerStats {
+    let snapshot = server.stats.snapshot();
+    ServerStats {
+        uptime_secs: snapshot.uptime.as_secs_f64(),
+        total_connections: snapshot.total_connections,
+        current_clients: snapshot.current_clients,
+        messages_sent: snapshot.messages_sent,
+        bytes_sent: snapshot.bytes_sent,
+        messages_received: snapshot.messages_received,
+        bytes_received: snapshot.bytes_received,
+        messages_dropped: snapshot.messages_dropped,
+        messages_dropped_by_reason: snapshot.dropped.by_reason().iter().map(|&(reason, count)| (reason.to_string(), count)).collect(),
+        messages_missed_by_client: server.clients.missed_messages(),
+        warning_count: snapshot.warnings,
+        error_count: snapshot.errors,
+        serialization_ns: snapshot.serialization.as_nanos() as u64,
+        channel_wait_ns: snapshot.channel_wait.as_nanos() as u64,
+        socket_write_ns: snapshot.socket_write.as_nanos() as u64,
+        socket_writes: snapshot.socket_writes,
+        broadcast_wait_ns: snapshot.broadcast_wait.as_nanos() as u64,
+        queued_bytes: snapshot.queued_bytes,
+        peak_queued_bytes: snapshot.peak_queued_bytes,
+        messages_over_budget: snapshot.messages_over_budget,
+        rate_limit_wait_ns: snapshot.rate_limit_wait.as_nanos() as u64,
+        keepalive_timeouts: snapshot.keepalive_timeouts,
+        idle_timeouts: snapshot.idle_timeouts,
+        frames_skipped: snapshot.frames_skipped,
+        keyframes_resent: snapshot.keyframes_resent,
+        samples_decimated: snapshot.samples_decimated,
+        synced_late: snapshot.synced_late,
+    }
+}
+
+/// What a server holds right now, as returned by get_diagnostics(), for telling whether a long-running server leaks: once its clients have all gone, it should be back where it started. A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct Diagnostics {
+    /// Accepted connections not yet closed: clients', and those still being served otherwise (plain HTTP requests, inspector feeds, proxied connections, other cluster nodes' links).
+    #[pyo3(get)] open_sockets: u64,
+    /// The server's running tasks: one per connection being routed, two per client, and one per link to a cluster peer.
+    #[pyo3(get)] tasks: usize,
+    /// Clients counted as connected, and those registered for sends to them alone: the same, but for a moment as a client connects or disconnects.
+    #[pyo3(get)] connected_clients: u64,
+    #[pyo3(get)] registered_clients: usize,
+    /// Subscriptions to the broadcasts: one per client, plus the inspector's and a session recording's.
+    #[pyo3(get)] broadcast_subscribers: usize,
+    /// How full each of the server's queues is, {name: (queued, capacity)}: "broadcasts" (batches queued for the slowest client), "targeted_sends" (for all clients together), "client_messages", "connection_events" and "ping_events" (waiting to be drained).
+    #[pyo3(get)] queues: std::collections::HashMap<String, (usize, usize)>,
+    /// Frames pending for all clients together (see send_frame()): at most one per topic per client.
+    #[pyo3(get)] pending_frames: usize,
+    /// Payload bytes held by the queues, and by the process-wide pool of buffers kept for reuse (which is capped); heap_bytes is the two together, roughly the heap the queues hold.
+    #[pyo3(get)] queued_bytes: u64,
+    #[pyo3(get)] pooled_buffer_bytes: u64,
+    #[pyo3(get)] heap_bytes: u64,
+}
+
+impl From<server::Diagnostics> for Diagnostics {
+    fn from(diagnostics: server::Diagnostics) -> Diagnostics {
+        let queues = [
+            ("broadcasts", diagnostics.broadcasts),
+            ("targeted_sends", diagnostics.targeted_sends),
+            ("client_messages", diagnostics.client_messages),
+            ("connection_events", diagnostics.connection_events),
+            ("ping_events", diagnostics.ping_events),
+        ];
+        Diagnostics {
+            open_sockets: diagnostics.open_sockets,
+            tasks: diagnostics.tasks,
+            connected_clients: diagnostics.connected_clients,
+            registered_clients: diagnostics.registered_clients,
+            broadcast_subscribers: diagnostics.broadcast_subscribers,
+            queues: queues.iter().map(|(name, queue)| (name.to_string(), (queue.queued, queue.capacity))).collect(),
+            pending_frames: diagnostics.pending_frames,
+            queued_bytes: diagnostics.queued_bytes,
+            pooled_buffer_bytes: diagnostics.pooled_buffer_bytes,
+            heap_bytes: diagnostics.heap_bytes(),
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for Diagnostics {
+    fn __repr__(&self) -> String {
+        format!(
+            "Diagnostics(open_sockets={}, tasks={}, connected_clients={}, registered_clients={}, broadcast_subscribers={}, queues={:?}, pending_frames={}, queued_bytes={}, pooled_buffer_bytes={}, heap_bytes={})",
+            self.open_sockets, self.tasks, self.connected_clients, self.registered_clients, self.broadcast_subscribers, self.queues, self.pending_frames, self.queued_bytes, self.pooled_buffer_bytes, self.heap_bytes
+        )
+    }
+}
+
+/// Returns a Diagnostics snapshot of what the running server (or the most recently stopped one) holds: open sockets, running tasks, registered clients, queue occupancy and the heap its queues hold. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_diagnostics() -> PyResult<Diagnostics> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get diagnostics"))?;
+    Ok(server::Diagnostics::take(&server).into())
+}
+
+/// One stage's latencies, as in LatencyHistograms: how many messages took how long. A snapshot; it only ever grows, so compare two (subtracting their buckets) for the latencies in between.
+#[pyclass]
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    snapshot: server::latency::HistogramSnapshot,
+}
+
+#[pymethods]
+impl LatencyHistogram {
+    /// Messages timed (each message of a batch counts once, and for fan-out and socket writes, once per client).
+    #[getter]
+    fn count(&self) -> u64 {
+        self.snapshot.count
+    }
+
+    /// Their latencies, summed, in nanoseconds.
+    #[getter]
+    fn total_ns(&self) -> u64 {
+        self.snapshot.total.as_nanos() as u64
+    }
+
+    /// The longest latency, in nanoseconds.
+    #[getter]
+    fn max_ns(&self) -> u64 {
+        self.snapshot.max.as_nanos() as u64
+    }
+
+    /// (upper_bound_ns, count) per bucket: `count` messages took less than `upper_bound_ns`, and at least the previous bucket's bound. The bounds are powers of two of microseconds, from 1 µs; the last bucket's is None.
+    #[getter]
+    fn buckets(&self) -> Vec<(Option<u64>, u64)> {
+        self.snapshot.buckets.iter().enumerate()
+            .map(|(bucket, count)| (server::latency::HistogramSnapshot::upper_bound(bucket).map(|bound| bound.as_nanos() as u64), *count))
+            .collect()
+    }
+
+    /// A latency, in nanoseconds, that `fraction` of the messages took no longer than (e.g. 0.99 for the 99th percentile): the upper bound of the bucket the quantile falls in, or max_ns if that's lower. None if no messages were timed.
+    fn quantile_ns(&self, fraction: f64) -> Option<u64> {
+        self.snapshot.quantile(fraction).map(|quantile| quantile.as_nanos() as u64)
+    }
+}
+
+impl LatencyHistogram {
+    fn repr(&self) -> String {
+        let quantile = |fraction| self.quantile_ns(fraction).map_or("None".to_string(), |ns| ns.to_string());
+        format!("LatencyHistogram(count={}, p50_ns={}, p99_ns={}, max_ns={})", self.count(), quantile(0.5), quantile(0.99), self.max_ns())
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for LatencyHistogram {
+    fn __repr__(&self) -> String {
+        self.repr()
+    }
+}
+
+/// The outbound pipeline's latencies, as returned by get_latency_histograms(), to tell where slow messages were held up: `enqueue` is the send call handing them to the server (for a cluster node's broadcasts, to its peers too), `fan_out` is waiting to be picked up by each client's sender task (behind the client's earlier messages), and `socket_write` is encoding and writing them to the client's socket (including waiting for room in its buffer, i.e. for the network).
+#[pyclass]
+#[derive(Clone)]
+pub struct LatencyHistograms {
+    #[pyo3(get)] enqueue: LatencyHistogram,
+    #[pyo3(get)] fan_out: LatencyHistogram,
+    #[pyo3(get)] socket_write: LatencyHistogram,
+}
+
+impl From<server::latency::LatencySnapshot> for LatencyHistograms {
+    fn from(snapshot: server::latency::LatencySnapshot) -> LatencyHistograms {
+        LatencyHistograms {
+            enqueue: LatencyHistogram { snapshot: snapshot.enqueue },
+            fan_out: LatencyHistogram { snapshot: snapshot.fan_out },
+            socket_write: LatencyHistogram { snapshot: snapshot.socket_write },
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for LatencyHistograms {
+    fn __repr__(&self) -> String {
+        format!("LatencyHistograms(enqueue={}, fan_out={}, socket_write={})", self.enqueue.repr(), self.fan_out.repr(), self.socket_write.repr())
+    }
+}
+
+/// Returns the running server's (or the most recently stopped one's) latency histograms, or None if it wasn't started with `latency_histograms`. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_latency_histograms() -> PyResult<Option<LatencyHistograms>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get latency histograms"))?;
+    Ok(server.stats.latency().map(LatencyHistograms::from))
+}
+
+/// The health of a cluster node's link to one of its peers, as returned by get_cluster_peers(). A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct ClusterPeer {
+    /// The peer's URL, as given in cluster_peers.
+    #[pyo3(get)] url: String,
+    /// The id the peer gave when the link was last made, or None if it never was.
+    #[pyo3(get)] node_id: Option<String>,
+    /// "connecting" (not linked up yet), "up" (broadcasts are being relayed to it), or "down" (the link was lost, or couldn't be made, and is being retried).
+    #[pyo3(get)] state: &'static str,
+    /// When the link entered its state, as from time.time().
+    #[pyo3(get)] since: f64,
+    /// Broadcasts (each batch of messages sent together counts once) relayed to the peer, received from it, and missed by it (because it was down, or fell behind).
+    #[pyo3(get)] sent: u64,
+    #[pyo3(get)] received: u64,
+    #[pyo3(get)] dropped: u64,
+    /// The latest ping round trip over the link, in milliseconds.
+    #[pyo3(get)] rtt_ms: Option<f64>,
+    /// Why the link was last lost, or couldn't be made.
+    #[pyo3(get)] last_error: Option<String>,
+}
+
+impl From<server::PeerStatus> for ClusterPeer {
+    fn from(peer: server::PeerStatus) -> ClusterPeer {
+        ClusterPeer {
+            url: peer.url,
+            node_id: peer.node_id,
+            state: peer.state.as_str(),
+            since: crate::events::unix_timestamp(peer.since),
+            sent: peer.sent,
+            received: peer.received,
+            dropped: peer.dropped,
+            rtt_ms: peer.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            last_error: peer.last_error,
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClusterPeer {
+    fn __repr__(&self) -> String {
+        match &self.node_id {
+            Some(node_id) => format!("<quicksocket.ClusterPeer {} (node {}): {}>", self.url, node_id, self.state),
+            None          => format!("<quicksocket.ClusterPeer {}: {}>", self.url, self.state),
+        }
+    }
+}
+
+/// Returns a ClusterPeer for each of the cluster's other nodes, in the order they were given to start_server(): whether each is linked up, and what's been relayed to and from it. Empty if the server isn't a cluster node. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_cluster_peers() -> PyResult<Vec<ClusterPeer>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get cluster peers"))?;
+    Ok(server.cluster_peers().into_iter().map(ClusterPeer::from).collect())
+}
+
+/// A connected client's stats, as returned by get_client_stats(). A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct ClientStats {
+    #[pyo3(get)] client_id: String,
+    /// The client's tag (see ServerHandle.set_client_tag()), or None if it has none.
+    #[pyo3(get)] tag: Option<String>,
+    /// Broadcast messages the client has missed by falling behind.
+    #[pyo3(get)] messages_missed: u64,
+    /// The latest ping round trip to the client, and the smoothed one (a moving average, less jumpy from one ping to the next), in milliseconds. None until the client's answered a ping, and if the server wasn't started with `ping_interval_ms`.
+    #[pyo3(get)] rtt_ms: Option<f64>,
+    #[pyo3(get)] smoothed_rtt_ms: Option<f64>,
+    /// False while the client's marked unhealthy for leaving `unhealthy_after_missed_pongs` pings unanswered; True otherwise.
+    #[pyo3(get)] healthy: bool,
+}
+
+impl ClientStats {
+    fn new(client_id: &str, stats: server::ClientStats) -> ClientStats {
+        ClientStats {
+            client_id: client_id.to_string(),
+            tag: stats.tag,
+            messages_missed: stats.messages_missed,
+            rtt_ms: stats.rtt.map(|rtt| rtt.latest.as_secs_f64() * 1000.0),
+            smoothed_rtt_ms: stats.rtt.map(|rtt| rtt.smoothed.as_secs_f64() * 1000.0),
+            healthy: stats.healthy,
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientStats {
+    fn __repr__(&self) -> String {
+        let unhealthy = if self.healthy { "" } else { ", unhealthy" };
+        match self.smoothed_rtt_ms {
+            Some(rtt_ms) => format!("<quicksocket.ClientStats {}: rtt {:.1} ms, {} missed{}>", self.client_id, rtt_ms, self.messages_missed, unhealthy),
+            None         => format!("<quicksocket.ClientStats {}: {} missed{}>", self.client_id, self.messages_missed, unhealthy),
+        }
+    }
+}
+
+/// Returns a connected client's ClientStats: its tag, the broadcast messages it's missed, how long its pings take to come back (if the server was started with `ping_interval_ms`), and whether it's healthy. None if no client with this id is connected. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_client_stats(client_id: &str) -> PyResult<Option<ClientStats>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get client stats"))?;
+    Ok(server.client_stats(client_id).map(|stats| ClientStats::new(client_id, stats)))
+}
+
+/// The capabilities a client declared (see `capability_formats` in start_server()), as returned by get_client_capabilities().
+#[pyclass]
+#[derive(Clone)]
+pub struct ClientCapabilities {
+    #[pyo3(get)] client_id: String,
+    /// The formats the client decodes, in its order of preference.
+    #[pyo3(get)] formats: Vec<String>,
+    /// The format negotiated: the client's first that the server offers, or None if there's none in common.
+    #[pyo3(get)] format: Option<String>,
+    /// The largest resolution the client wants, or None if it didn't say.
+    #[pyo3(get)] max_width: Option<u32>,
+    #[pyo3(get)] max_height: Option<u32>,
+    /// The topics the client wants, or None for all of them.
+    #[pyo3(get)] topics: Option<Vec<String>>,
+    /// The other keys it declared, and their values, as str.
+    #[pyo3(get)] extra: std::collections::HashMap<String, String>,
+}
+
+impl ClientCapabilities {
+    fn new(client_id: &str, capabilities: server::ClientCapabilities) -> ClientCapabilities {
+        ClientCapabilities {
+            client_id: client_id.to_string(),
+            formats: capabilities.formats,
+            format: capabilities.format,
+            max_width: capabilities.max_resolution.map(|(width, _)| width),
+            max_height: capabilities.max_resolution.map(|(_, height)| height),
+            topics: capabilities.topics,
+            extra: capabilities.extra,
+        }
+    }
+}
+
+#[pymethods]
+impl ClientCapabilities {
+    /// Whether the client wants `topic`: it's in its topics, or it didn't say which it wants.
+    fn wants(&self, topic: &str) -> bool {
+        self.topics.as_ref().is_none_or(|topics| topics.iter().any(|wanted| wanted == topic))
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientCapabilities {
+    fn __repr__(&self) -> String {
+        let resolution = match (self.max_width, self.max_height) {
+            (Some(width), Some(height)) => format!(", up to {}x{}", width, height),
+            _ => String::new(),
+        };
+        format!("<quicksocket.ClientCapabilities {}: format {:?}{}>", self.client_id, self.format, resolution)
+    }
+}
+
+/// Returns the capabilities a connected client declared, with the format negotiated for it (see `capability_formats` in start_server()), or None if it hasn't declared any, or no client with this id is connected. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_client_capabilities(client_id: &str) -> PyResult<Option<ClientCapabilities>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get client capabilities"))?;
+    Ok(server.client_capabilities(client_id).map(|capabilities| ClientCapabilities::new(client_id, capabilities)))
+}
+
+/// Returns the capabilities of every connected client that's declared them, {client_id: ClientCapabilities}, e.g. for encoding each negotiated format once and sending it to the clients that chose it with try_send_to_client(). Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_all_client_capabilities() -> PyResult<std::collections::HashMap<String, ClientCapabilities>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get client capabilities"))?;
+    Ok(all_client_capabilities(&server))
+}
+
+fn all_client_capabilities(server: &Server) -> std::collections::HashMap<String, ClientCapabilities> {
+    server.all_client_capabilities().into_iter().map(|(client_id, capabilities)| (client_id.clone(), ClientCapabilities::new(&client_id, capabilities))).collect()
+}
+
+/// Returns the topics connected clients are subscribed to over rosbridge (see `rosbridge` in start_server()), and the clients subscribed to each: {topic: [client_id, ...]}. Raises ServerNotRunning if no server has been started.
+#[pyfunction]
+pub fn get_ros_subscriptions() -> PyResult<std::collections::HashMap<String, Vec<String>>> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("get ROS subscriptions"))?;
+    Ok(server.ros_subscriptions())
+}
+
+/// How a session recording went (or is going), as returned by stop_recording() and get_recording_stats(). A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct RecordingStats {
+    /// The file recorded to, and its format: "jsonl" or "binary".
+    #[pyo3(get)] path: String,
+    #[pyo3(get)] format: &'static str,
+    /// Messages written to the file, and those left out because writing couldn't keep up (or failed).
+    #[pyo3(get)] recorded: u64,
+    #[pyo3(get)] dropped: u64,
+    #[pyo3(get)] bytes_written: u64,
+    /// Why writing the file failed, if it did (it's also recorded as a "recording" error event); nothing more was written after that.
+    #[pyo3(get)] error: Option<String>,
+}
+
+impl From<server::RecordingStats> for RecordingStats {
+    fn from(stats: server::RecordingStats) -> RecordingStats {
+        RecordingStats {
+            path: stats.path.display().to_string(),
+            format: stats.format.as_str(),
+            recorded: stats.recorded,
+            dropped: stats.dropped,
+            bytes_written: stats.bytes_written,
+            error: stats.error,
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for RecordingStats {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.RecordingStats {} ({}): {} recorded, {} dropped>", self.path, self.format, self.recorded, self.dropped)
+    }
+}
+
+fn recording_format(format: &str) -> PyResult<server::RecordingFormat> {
+    server::RecordingFormat::parse(format)
+        .ok_or_else(|| pyo3::exceptions::PyValueError::new_err(format!("Unknown recording format {:?}; expected \"jsonl\" or \"binary\".", format)))
+}
+
+/// Starts recording the session to the file at `path` (created, or truncated): every text and binary message the clients send, and every one sent to them, each with its time, direction, and client, for auditing or analysing a session offline. Broadcasts are recorded once, without a client, however many clients they reach. `format` is "jsonl", one JSON object per line ({"t": seconds since the epoch, "dir": "in" or "out", "client": client_id or null, "type": "text" or "binary", "data": the text, or the bytes in base64}), or "binary", length-prefixed records (see the README). The server never waits for the file: messages the writer can't keep up with are left out, and counted in RecordingStats.dropped.
+///
+/// Recording goes on until stop_recording(), or until the server stops. Raises ValueError for an unknown format, QuicksocketError if a recording is in progress already or the file can't be created, and ServerNotRunning if the server isn't running.
+#[pyfunction(format = "\"jsonl\"")]
+pub fn start_recording(path: &str, format: &str) -> PyResult<()> {
+    let server = default_server().ok_or_else(|| errors::server_not_running("start recording"))?;
+    start_recording_for(&server, path, format)
+}
+
+fn start_recording_for(server: &Server, path: &str, format: &str) -> PyResult<()> {
+    server.start_recording(path, recording_format(format)?).map_err(|err| errors::from_server_error(err, "start recording"))
+}
+
+/// Stops the recording in progress, returning its RecordingStats once everything recorded is in the file, or None if nothing was being recorded (a recording stops by itself when the server does). Blocks (with the GIL released) while the file is written.
+#[pyfunction]
+pub fn stop_recording(py: Python) -> Option<RecordingStats> {
+    let server = default_server()?;
+    py.allow_threads(|| server.stop_recording()).map(RecordingStats::from)
+}
+
+/// Returns the RecordingStats of the recording in progress, or None if nothing's being recorded.
+#[pyfunction]
+pub fn get_recording_stats() -> Option<RecordingStats> {
+    default_server()?.recording_stats().map(RecordingStats::from)
+}
+
+/// Handle to a server instance started with start_server_instance(). Its methods behave like the module-level functions of the same names, for this instance.
+#[pyclass]
+pub struct ServerHandle {
+    server: Server,
+}
+
+#[pymethods]
+impl ServerHandle {
+    /// The port the server was started on.
+    #[getter]
+    fn port(&self) -> u32 {
+        self.server.port
+    }
+
+    /// The port the server is listening on once it's bound: `port`, or the one the OS picked if that was 0. None before then, and for loopback servers.
+    #[getter]
+    fn bound_port(&self) -> Option<u32> {
+        self.server.bound_port()
+    }
+
+    fn is_running(&self) -> bool {
+        self.server.is_alive()
+    }
+
+    fn get_state(&self) -> &'static str {
+        self.server.run_state().as_str()
+    }
+
+    fn is_client_connected(&self, client_id: &str) -> bool {
+        self.server.is_client_connected(client_id)
+    }
+
+    /// Tags a connected client (or untags it, with None), giving it its tag's rate limit from client_bytes_per_sec_by_tag (or client_bytes_per_sec, if the tag has none) from its next write on. Returns False if no client with this id is connected.
+    #[args(tag = "None")]
+    fn set_client_tag(&self, client_id: &str, tag: Option<String>) -> bool {
+        self.server.set_client_tag(client_id, tag.as_deref())
+    }
+
+    /// A connected client's tag, or None if it has none (or isn't connected).
+    fn get_client_tag(&self, client_id: &str) -> Option<String> {
+        self.server.client_tag(client_id)
+    }
+
+    #[args(timeout_ms = "None")]
+    fn wait_until_started(&self, py: Python, timeout_ms: Option<u64>) -> PyResult<bool> {
+        wait_until_started_for(py, &self.server, timeout_ms)
+    }
+
+    #[args(timeout_ms = "None")]
+    fn wait_for_client(&self, py: Python, timeout_ms: Option<u64>) -> PyResult<bool> {
+        wait_for_client_for(py, &self.server, timeout_ms)
+    }
+
+    /// Used by Server.start() to restart a stopped (or stopping) instance's port; see prepare_restart().
+    #[pyo3(name = "_prepare_restart")]
+    fn prepare_restart(&self, py: Python) -> PyResult<()> {
+        prepare_restart(py, &self.server)
+    }
+
+    fn begin_draining(&self) {
+        self.server.begin_draining()
+    }
+
+    #[args(timeout_ms = "None")]
+    fn wait_until_drained(&self, py: Python, timeout_ms: Option<u64>) -> bool {
+        wait_until_drained_for(py, &self.server, timeout_ms)
+    }
+
+    #[args(wait = "false", progress = "false", close_code = "None", close_reason = "None", close_timeout_ms = "None", timeout_ms = "None")]
+    #[allow(clippy::too_many_arguments)]
+    fn shutdown(&self, py: Python, wait: bool, progress: bool, close_code: Option<u16>, close_reason: Option<String>, close_timeout_ms: Option<u64>, timeout_ms: Option<u64>) -> PyResult<Option<ShutdownHandle>> {
+        shutdown_with_progress(py, &self.server, wait, progress, shutdown_options(close_code, close_reason, close_timeout_ms, timeout_ms)?)
+    }
+
+    fn drain_new_client_events(&self, py: Python) -> Vec<String> {
+        drain_new_client_events_for(py, &self.server)
+    }
+
+    fn drain_connection_events(&self, py: Python) -> Vec<ConnectionEvent> {
+        drain_connection_events_for(py, &self.server).into_iter().map(ConnectionEvent::from).collect()
+    }
+
+    fn drain_ping_events(&self, py: Python) -> Vec<PingEvent> {
+        drain_ping_events_for(py, &self.server)
+    }
+
+    fn get_playback_state(&self, py: Python) -> Option<PlaybackState> {
+        get_playback_state_for(py, &self.server)
+    }
+
+    fn drain_playback_events(&self, py: Python) -> Vec<PlaybackEvent> {
+        drain_playback_events_for(py, &self.server)
+    }
+
+    fn drain_input_snapshots(&self, py: Python) -> Vec<InputSnapshot> {
+        drain_input_snapshots_for(py, &self.server)
+    }
+
+    #[args(payload = "None")]
+    fn send_ping(&self, py: Python, client_id: &str, payload: Option<MessagePayload>) -> PyResult<()> {
+        send_ping_for(py, Some(&self.server), client_id, payload)
+    }
+
+    fn try_send_messages(&self, py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+        send_messages(py, Some(&self.server), messages)
+    }
+
+    fn try_send_to_client(&self, py: Python, client_id: &str, messages: Vec<&PyAny>) -> PyResult<()> {
+        send_to_client(py, Some(&self.server), client_id, messages, Delivery::Queue)
+    }
+
+    #[args(timeout_ms = "None")]
+    fn send_and_confirm(&self, py: Python, client_id: &str, messages: Vec<&PyAny>, timeout_ms: Option<u64>) -> PyResult<()> {
+        send_to_client(py, Some(&self.server), client_id, messages, Delivery::Confirm { timeout: timeout_ms.map(Duration::from_millis) })
+    }
+
+    #[args(timeout_ms = "None", max_messages = "None", structured = "false")]
+    fn drain_client_messages(&self, py: Python, timeout_ms: Option<u64>, max_messages: Option<usize>, structured: bool) -> PyObject {
+        drain_client_messages_for(py, &self.server, timeout_ms, max_messages, structured)
+    }
+
+    fn send_stamped(&self, py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+        send_stamped_for(py, Some(&self.server), messages)
+    }
+
+    fn send_sample(&self, py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+        send_sample_for(py, Some(&self.server), topic, message)
+    }
+
+    #[args(pts_us = "None")]
+    fn send_synced(&self, py: Python, topic: &str, message: &PyAny, pts_us: Option<u64>) -> PyResult<()> {
+        send_synced_for(py, Some(&self.server), topic, message, pts_us)
+    }
+
+    fn publish_ros(&self, py: Python, topic: &str, msg: &str) -> PyResult<usize> {
+        publish_ros_for(py, Some(&self.server), topic, msg)
+    }
+
+    fn send_frame(&self, py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+        send_frame_for(py, Some(&self.server), topic, message)
+    }
+
+    fn send_keyframe(&self, py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+        send_keyframe_for(py, Some(&self.server), topic, message, false)
+    }
+
+    fn send_delta(&self, py: Python, topic: &str, message: &PyAny) -> PyResult<()> {
+        send_keyframe_for(py, Some(&self.server), topic, message, true)
+    }
+
+    #[args(colors = "None")]
+    fn send_pointcloud(&self, py: Python, xyz: &PyAny, colors: Option<PyObject>) -> PyResult<()> {
+        send_pointcloud_for(py, Some(&self.server), xyz, colors.as_ref().map(|colors| colors.as_ref(py)))
+    }
+
+    fn send_mesh(&self, py: Python, vertices: &PyAny, faces: &PyAny) -> PyResult<()> {
+        send_mesh_for(py, Some(&self.server), vertices, faces)
+    }
+
+    #[args(figure_id = "\"figure\"")]
+    fn send_figure(&self, py: Python, figure: &PyAny, figure_id: &str) -> PyResult<()> {
+        send_figure_for(py, Some(&self.server), figure, figure_id)
+    }
+
+    fn log_scalar(&self, py: Python, tag: &str, step: i64, value: f64) -> PyResult<()> {
+        log_scalar_for(py, Some(&self.server), tag, step, value)
+    }
+
+    #[args(bins = "30")]
+    fn log_histogram(&self, py: Python, tag: &str, step: i64, values: &PyAny, bins: usize) -> PyResult<()> {
+        log_histogram_for(py, Some(&self.server), tag, step, values, bins)
+    }
+
+    #[args(serializer = "None", max_bytes = "None")]
+    fn send_python_objects(&self, py: Python, objects: Vec<&PyAny>, serializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<()> {
+        send_python_objects_for(py, Some(&self.server), objects, serializer, max_bytes)
+    }
+
+    #[args(timeout_ms = "None", max_messages = "None", deserializer = "None", max_bytes = "None")]
+    fn drain_python_objects(&self, py: Python, timeout_ms: Option<u64>, max_messages: Option<usize>, deserializer: Option<PyObject>, max_bytes: Option<usize>) -> PyResult<Vec<PyObject>> {
+        drain_python_objects_for(py, &self.server, timeout_ms, max_messages, deserializer, max_bytes)
+    }
+
+    #[args(timeout_ms = "None")]
+    fn messages(&self, timeout_ms: Option<u64>) -> MessageIterator {
+        MessageIterator { server: Some(self.server.clone()), pending: VecDeque::new(), timeout_ms }
+    }
+
+    fn get_message_fd(&self) -> PyResult<i32> {
+        self.server.notifier.fd().map_err(QuicksocketError::new_err)
+    }
+
+    fn set_on_message(&self, py: Python, callback: Option<PyObject>) -> PyResult<()> {
+        set_on_message_for(py, &self.server, callback)
+    }
+
+    fn get_stats(&self) -> ServerStats {
+        server_stats(&self.server)
+    }
+
+    fn get_latency_histograms(&self) -> Option<LatencyHistograms> {
+        self.server.stats.latency().map(LatencyHistograms::from)
+    }
+
+    fn get_diagnostics(&self) -> Diagnostics {
+        self.server.diagnostics().into()
+    }
+
+    /// This node's id, if the server is a cluster node.
+    #[getter]
+    fn cluster_node_id(&self) -> Option<String> {
+        self.server.cluster_node_id().map(str::to_string)
+    }
+
+    fn get_client_stats(&self, client_id: &str) -> Option<ClientStats> {
+        self.server.client_stats(client_id).map(|stats| ClientStats::new(client_id, stats))
+    }
+
+    fn get_client_capabilities(&self, client_id: &str) -> Option<ClientCapabilities> {
+        self.server.client_capabilities(client_id).map(|capabilities| ClientCapabilities::new(client_id, capabilities))
+    }
+
+    fn get_all_client_capabilities(&self) -> std::collections::HashMap<String, ClientCapabilities> {
+        all_client_capabilities(&self.server)
+    }
+
+    fn get_ros_subscriptions(&self) -> std::collections::HashMap<String, Vec<String>> {
+        self.server.ros_subscriptions()
+    }
+
+    fn get_cluster_peers(&self) -> Vec<ClusterPeer> {
+        self.server.cluster_peers().into_iter().map(ClusterPeer::from).collect()
+    }
+
+    fn connect_loopback(&self, py: Python) -> PyResult<LoopbackClient> {
+        connect_loopback_to(py, Some(&self.server))
+    }
+
+    #[args(format = "\"jsonl\"")]
+    fn start_recording(&self, path: &str, format: &str) -> PyResult<()> {
+        start_recording_for(&self.server, path, format)
+    }
+
+    fn stop_recording(&self, py: Python) -> Option<RecordingStats> {
+        py.allow_threads(|| self.server.stop_recording()).map(RecordingStats::from)
+    }
+
+    fn get_recording_stats(&self) -> Option<RecordingStats> {
+        self.server.recording_stats().map(RecordingStats::from)
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ServerHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.ServerHandle on port {} ({})>", self.server.port, self.server.run_state().as_str())
+    }
+}
+
+/// Connects an in-process websocket client to the server, which must have been started with loopback=True, and returns it once the handshake is done. The connection goes through the same connection events, routing and client tasks as one over TCP, so tests can exercise the server deterministically without opening sockets.
+///
+/// Raises ServerNotRunning if the server isn't running, and QuicksocketError if it isn't a loopback server.
 #[pyfunction]
-pub fn try_send_messages(py: Python, messages: Vec<MessagePayload>) -> PyResult<()> {
-    py.allow_threads(|| {
-        // Create a Vec<WsMessage> out of the Vec<MessagePayload> so the backend is just working with the tungstenite WebSocket lib types.
-        let messages: Vec<WsMessage> = messages.into_iter().map(|msg| { match msg {
-            MessagePayload::Text(text)   => { WsMessage::Text(text) }
-            MessagePayload::Binary(bytes) => { WsMessage::Binary(bytes) }
-        }}).collect();
-    
-        let send_res = cs::read(&cs::CS_SER_MSG_TX, |tx| {
-            // Send!
-            tx.send(messages)
-        });
-        // Check whether, and precisely how, we failed to send.
-        if send_res.is_none() || send_res.as_ref().unwrap().is_err() {
-            let details = "Error reading server state for transmitter".to_string();
-            // if send_res.is_some() {
-            //     details = format!("{:?}", send_res.unwrap().err());return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
-            // }
-            // For now, only return an error if the send fails unrelated to the number of receivers, because we simply expect the message to go nowhere if there are no connected clients.
-
-            if send_res.is_none() {
-                return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
-            }
-    
-            // weakly_record_error(format!("Failed to send message. Details: {}", details));
-            // return Err(pyo3::exceptions::PyBaseException::new_err(format!("Failed to send message. Details: {}", details)));
-        }
-    
-        Ok(())
-    })
+pub fn connect_loopback(py: Python) -> PyResult<LoopbackClient> {
+    connect_loopback_to(py, default_server().as_ref())
 }
 
-/// Drains all messages pending from all clients and returns them as a list[bytes]. Note that clients are not distinguished, so clients will have to self-identify in their messages, or the library will need to change to return messages per-client or bundled with client connection info.
-#[pyfunction]
-pub fn drain_client_messages(py: Python) -> Vec<MessagePayload> {
-    py.allow_threads(|| {
-        let drained_messages = cs::mutate(&cs::CS_CLI_MSG_RX, |rx| {
-            let mut messages = vec![];
-    
-            // Apparently there's an issue with try_recv() where messages may not be immediately available once submitted to the channel (they may be subject to a slight delay).
-            // Details: https://github.com/tokio-rs/tokio/issues/3350
-            // TODO: May look into using flume, with some tokio-based sync primitive on the tokio task side.
-            while let Some(Some(cli_msg)) = rx.recv().now_or_never() {
-                // Convert the message into the python-convertible MessagePayload type.
-                // For now, we ignore the ping/pong and Close websocket messages.
-                let converted_msg = match cli_msg {
-                    WsMessage::Text(text)    => { Some(MessagePayload::Text(text)) }
-                    WsMessage::Binary(bytes) => { Some(MessagePayload::Binary(bytes)) }
-                    WsMessage::Ping(_)       => { None }
-                    WsMessage::Pong(_)       => { None }
-                    WsMessage::Close(_)      => { None }
-                };
-                if converted_msg.is_some() { messages.push(converted_msg.unwrap()); }
-            }
-    
-            messages
-        });
-        if drained_messages.is_none() {
-            return vec![];
+fn connect_loopback_to(py: Python, server: Option<&Server>) -> PyResult<LoopbackClient> {
+    let server = server.ok_or_else(|| errors::server_not_running("connect a loopback client"))?;
+    let client = py.allow_threads(|| server.connect_loopback()).map_err(|err| errors::from_server_error(err, "connect a loopback client"))?;
+    Ok(LoopbackClient { client })
+}
+
+/// A websocket client connected in-process to a loopback server, as returned by connect_loopback(). Its methods block with the GIL released.
+#[pyclass]
+pub struct LoopbackClient {
+    client: server::LoopbackClient,
+}
+
+#[pymethods]
+impl LoopbackClient {
+    /// The id the server knows this client by, as in its connection events and ClientMessage.client_id.
+    #[getter]
+    fn client_id(&self) -> &str {
+        self.client.client_id()
+    }
+
+    /// Sends messages (str for text, bytes-like for binary) to the server, in order.
+    fn send(&mut self, py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+        let borrowed = messages.iter().map(|msg| BorrowedPayload::borrow(msg)).collect::<PyResult<Vec<_>>>()?;
+        let client = &mut self.client;
+        py.allow_threads(|| {
+            let messages: Vec<WsMessage> = borrowed.iter().map(BorrowedPayload::to_ws_message).collect();
+            client.send(messages).map_err(|err| errors::from_server_error(err, "send messages"))
+        })
+    }
+
+    /// Waits for the next message from the server, for at most `timeout_ms` if given: a str for text, bytes for binary, or None on timeout. Raises ServerNotRunning once the connection has closed.
+    #[args(timeout_ms = "None")]
+    fn recv(&mut self, py: Python, timeout_ms: Option<u64>) -> PyResult<Option<MessagePayload>> {
+        let client = &mut self.client;
+        let msg = py.allow_threads(|| client.recv(timeout_ms.map(Duration::from_millis)))
+            .map_err(|err| errors::from_server_error(err, "receive messages"))?;
+        Ok(msg.and_then(|msg| MessagePayload::from_ws_message(msg, None)))
+    }
+
+    /// Closes the connection with a close handshake, so the server reports the disconnection. Closing a closed client does nothing.
+    fn close(&mut self) {
+        let client = &mut self.client;
+        Python::with_gil(|py| py.allow_threads(|| client.close()));
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for LoopbackClient {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.LoopbackClient {}>", self.client.client_id())
+    }
+}
+
+/// Connects to the websocket server at `url` (ws:// only, for now) and returns a ClientHandle for the connection, whose send and drain methods work like a server's, e.g. to consume an upstream feed in the same process that serves browsers. Blocks (with the GIL released) until the handshake is done, or for at most `timeout_ms` if given. As for start_server(), received binary messages of at least `zero_copy_min_bytes` bytes are drained as MessageBuffer objects.
+///
+/// All client connections share one runtime thread, separate from the servers'. Raises ConnectError (with `.url`, `.reason`) if the connection or handshake fails or times out.
+#[pyfunction(timeout_ms = "None", zero_copy_min_bytes = "None")]
+pub fn connect_to(py: Python, url: &str, timeout_ms: Option<u64>, zero_copy_min_bytes: Option<usize>) -> PyResult<ClientHandle> {
+    let client = py.allow_threads(|| server::Client::connect(url, timeout_ms.map(Duration::from_millis)))
+        .map_err(|err| errors::from_server_error(err, "connect"))?;
+    Ok(ClientHandle { client, zero_copy_min_bytes })
+}
+
+/// Handle to an outbound connection opened with connect_to(). Its methods behave like ServerHandle's methods of the same names, for messages to and from the server at the other end; received ClientMessages have the URL as their client_id. The connection closes when the handle is garbage collected, if close() wasn't called first.
+#[pyclass]
+pub struct ClientHandle {
+    client: server::Client,
+    zero_copy_min_bytes: Option<usize>,
+}
+
+#[pymethods]
+impl ClientHandle {
+    #[getter]
+    fn url(&self) -> &str {
+        self.client.url()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.client.is_connected()
+    }
+
+    fn try_send_messages(&self, py: Python, messages: Vec<&PyAny>) -> PyResult<()> {
+        self.send_messages_with(py, messages, Delivery::Queue)
+    }
+
+    #[args(timeout_ms = "None")]
+    fn send_and_confirm(&self, py: Python, messages: Vec<&PyAny>, timeout_ms: Option<u64>) -> PyResult<()> {
+        self.send_messages_with(py, messages, Delivery::Confirm { timeout: timeout_ms.map(Duration::from_millis) })
+    }
+
+    #[args(timeout_ms = "None", max_messages = "None", structured = "false")]
+    fn drain_client_messages(&self, py: Python, timeout_ms: Option<u64>, max_messages: Option<usize>, structured: bool) -> PyObject {
+        drain_into_py(py, self.zero_copy_min_bytes, structured, |slots| {
+            self.client.drain_messages_into(timeout_ms.map(Duration::from_millis), max_messages.unwrap_or(usize::MAX), slots);
+        })
+    }
+
+    fn get_message_fd(&self) -> PyResult<i32> {
+        self.client.message_fd().map_err(QuicksocketError::new_err)
+    }
+
+    /// Closes the connection with a close handshake; with `wait`, blocks (with the GIL released) until it's closed.
+    #[args(wait = "false")]
+    fn close(&self, py: Python, wait: bool) {
+        py.allow_threads(|| self.client.close(wait))
+    }
+}
+
+impl ClientHandle {
+    fn send_messages_with(&self, py: Python, messages: Vec<&PyAny>, delivery: Delivery) -> PyResult<()> {
+        let borrowed = messages.iter().map(|msg| BorrowedPayload::borrow(msg)).collect::<PyResult<Vec<_>>>()?;
+        py.allow_threads(|| {
+            let messages: Vec<WsMessage> = borrowed.iter().map(BorrowedPayload::to_ws_message).collect();
+            self.client.send_with(messages, delivery).map_err(|err| match err {
+                server::Error::NotRunning => QuicksocketError::new_err(format!("Can't send messages: the connection to {} is closed.", self.client.url())),
+                err => errors::from_server_error(err, "send messages"),
+            })
+        })
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.ClientHandle to {} ({})>", self.client.url(), if self.client.is_connected() { "connected" } else { "closed" })
+    }
+}
+
+lazy_static! {
+    /// Relays started from Python, stopped by shutdown_all_servers() so no relay thread is left calling a filter while the interpreter exits.
+    static ref PY_RELAYS: std::sync::Mutex<Vec<std::sync::Arc<server::Relay>>> = std::sync::Mutex::new(Vec::new());
+    /// Replays started from Python, stopped by shutdown_all_servers() along with the relays.
+    static ref PY_REPLAYS: std::sync::Mutex<Vec<std::sync::Arc<server::Replay>>> = std::sync::Mutex::new(Vec::new());
+}
+
+/// Starts relaying from `upstream` (a connection opened with connect_to()) to the clients of `server` (a ServerHandle; the module-level server if None): every message the upstream sends is broadcast to the server's clients, so a single-connection data source can be fanned out to any number of browsers. With `to_upstream`, the clients' messages are forwarded to the upstream too (and can't be drained from the server while the relay runs); otherwise they're left for draining as usual.
+///
+/// `filter`, if given, is called with each upstream message (str or bytes) and decides whether it's broadcast (a truthy result) or dropped; `upstream_filter` does the same for the clients' messages with `to_upstream`. Filters run on the relay thread, which holds the GIL only while calling them; a filter that raises drops the message, and the exception is printed.
+///
+/// The relay runs until stopped, or until the upstream connection closes or the server stops. While it runs, the upstream's messages can't be drained. Raises ServerNotRunning if either end has already closed.
+#[pyfunction(server = "None", to_upstream = "false", filter = "None", upstream_filter = "None")]
+pub fn start_relay(
+    py: Python,
+    upstream: &ClientHandle,
+    server: Option<&ServerHandle>,
+    to_upstream: bool,
+    filter: Option<PyObject>,
+    upstream_filter: Option<PyObject>,
+) -> PyResult<RelayHandle> {
+    let server = match server {
+        Some(handle) => handle.server.clone(),
+        None => default_server().ok_or_else(|| errors::server_not_running("start a relay"))?,
+    };
+    for callable in filter.iter().chain(upstream_filter.iter()) {
+        if !callable.as_ref(py).is_callable() {
+            return Err(pyo3::exceptions::PyTypeError::new_err("start_relay() expects callables (or None) as filters."));
         }
-        let drained_messages = drained_messages.unwrap();
-    
-        drained_messages
+    }
+    let config = server::RelayConfig {
+        to_upstream,
+        filter: filter.map(|filter| python_relay_filter(filter, upstream.zero_copy_min_bytes)),
+        upstream_filter: upstream_filter.map(|filter| python_relay_filter(filter, server.config.zero_copy_min_bytes)),
+    };
+    let relay = py.allow_threads(|| server::Relay::start(&upstream.client, &server, config))
+        .map_err(|err| match err {
+            server::Error::NotRunning => errors::server_not_running("start a relay"),
+            err => errors::from_server_error(err, "start a relay"),
+        })?;
+
+    let relay = std::sync::Arc::new(relay);
+    if let Ok(mut relays) = PY_RELAYS.lock() {
+        relays.retain(|relay| relay.is_running());
+        relays.push(relay.clone());
+    }
+    Ok(RelayHandle { relay, upstream_url: upstream.client.url().to_string() })
+}
+
+/// Wraps a Python callable as a relay filter: the message is relayed if the callable returns something truthy.
+fn python_relay_filter(filter: PyObject, zero_copy_min_bytes: Option<usize>) -> server::relay::RelayFilter {
+    Box::new(move |msg| {
+        let payload = match MessagePayload::from_ws_message(msg.message.clone(), zero_copy_min_bytes) {
+            Some(payload) => payload,
+            None => { return false; }
+        };
+        Python::with_gil(|py| match filter.call1(py, (payload,)).and_then(|relay| relay.is_true(py)) {
+            Ok(relay) => relay,
+            Err(err) => {
+                // Like the message callback, keep relaying; report it like an unraisable exception.
+                server::error_events::record(server::error_events::Severity::Warning, server::error_events::Category::Callback, format!("Relay filter raised an exception: {}", err), Some(msg.client_id.clone()));
+                err.print(py);
+                false
+            }
+        })
     })
 }
 
+/// Counts of the messages a relay has forwarded, as returned by RelayHandle.get_stats(). A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct RelayStats {
+    /// Upstream messages broadcast to the server's clients.
+    #[pyo3(get)] to_clients: u64,
+    /// Client messages forwarded to the upstream.
+    #[pyo3(get)] to_upstream: u64,
+    /// Messages (in either direction) dropped by a filter.
+    #[pyo3(get)] filtered: u64,
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for RelayStats {
+    fn __repr__(&self) -> String {
+        format!("RelayStats(to_clients={}, to_upstream={}, filtered={})", self.to_clients, self.to_upstream, self.filtered)
+    }
+}
+
+/// Handle to a relay started with start_relay(). The relay keeps running if the handle is garbage collected.
+#[pyclass]
+pub struct RelayHandle {
+    relay: std::sync::Arc<server::Relay>,
+    upstream_url: String,
+}
+
+#[pymethods]
+impl RelayHandle {
+    fn is_running(&self) -> bool {
+        self.relay.is_running()
+    }
+
+    fn get_stats(&self) -> RelayStats {
+        let stats = self.relay.stats();
+        RelayStats { to_clients: stats.to_clients, to_upstream: stats.to_upstream, filtered: stats.filtered }
+    }
+
+    /// Stops relaying, waiting (with the GIL released) for the relay thread to finish. Can't be called from a filter.
+    fn stop(&self) {
+        let relay = &self.relay;
+        Python::with_gil(|py| py.allow_threads(|| relay.stop()));
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for RelayHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.RelayHandle from {} ({})>", self.upstream_url, if self.relay.is_running() { "running" } else { "stopped" })
+    }
+}
+
+/// Starts replaying the session recording at `path` (made with start_recording(), in either format) to the clients of `server` (a ServerHandle; the module-level server if None): what was broadcast while recording is broadcast again, with the gaps between messages divided by `speed` (1.0 is real time, 10.0 ten times as fast, and float("inf") as fast as the clients take them), so a frontend can be developed against a realistic stream without running what produced it. Messages from clients, and sent to single clients, are skipped. With `repeat`, the recording starts over each time it ends.
+///
+/// The replay runs on its own thread until the recording ends (with `repeat`, until stopped), or the server stops; a malformed recording stops it where it goes wrong, with a "recording" error event (see ReplayStats.error). Raises ValueError for a speed that isn't positive, QuicksocketError if the file can't be opened, and ServerNotRunning if the server isn't running.
+#[pyfunction(speed = "1.0", repeat = "false", server = "None")]
+pub fn start_replay(py: Python, path: &str, speed: f64, repeat: bool, server: Option<&ServerHandle>) -> PyResult<ReplayHandle> {
+    let server = match server {
+        Some(handle) => handle.server.clone(),
+        None => default_server().ok_or_else(|| errors::server_not_running("replay a recording"))?,
+    };
+    let config = server::ReplayConfig { speed, repeat };
+    let replay = py.allow_threads(|| server::Replay::start(&server, path, config))
+        .map_err(|err| match err {
+            server::Error::InvalidConfig(reason) => pyo3::exceptions::PyValueError::new_err(format!("Can't replay a recording: {}.", reason)),
+            err => errors::from_server_error(err, "replay a recording"),
+        })?;
+    let replay = std::sync::Arc::new(replay);
+    if let Ok(mut replays) = PY_REPLAYS.lock() {
+        replays.retain(|replay| replay.is_running());
+        replays.push(replay.clone());
+    }
+    Ok(ReplayHandle { replay })
+}
+
+/// How far a replay has got, as returned by ReplayHandle.get_stats(). A snapshot, like ServerStats.
+#[pyclass]
+#[derive(Clone)]
+pub struct ReplayStats {
+    /// Recorded broadcasts sent to the server's clients.
+    #[pyo3(get)] sent: u64,
+    /// Recorded messages that weren't broadcasts, and so weren't replayed.
+    #[pyo3(get)] skipped: u64,
+    /// Times the recording was replayed to its end.
+    #[pyo3(get)] passes: u64,
+    /// Why the replay stopped early, if the recording couldn't be read.
+    #[pyo3(get)] error: Option<String>,
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ReplayStats {
+    fn __repr__(&self) -> PyResult<String> {
+        // (The error as Python shows it: None, or a quoted str.)
+        let error = Python::with_gil(|py| self.error.to_object(py).as_ref(py).repr().map(|repr| repr.to_string()))?;
+        Ok(format!("ReplayStats(sent={}, skipped={}, passes={}, error={})", self.sent, self.skipped, self.passes, error))
+    }
+}
+
+/// Handle to a replay started with start_replay(). The replay keeps running if the handle is garbage collected.
+#[pyclass]
+pub struct ReplayHandle {
+    replay: std::sync::Arc<server::Replay>,
+}
+
+#[pymethods]
+impl ReplayHandle {
+    fn is_running(&self) -> bool {
+        self.replay.is_running()
+    }
+
+    fn get_stats(&self) -> ReplayStats {
+        let stats = self.replay.stats();
+        ReplayStats { sent: stats.sent, skipped: stats.skipped, passes: stats.passes, error: stats.error }
+    }
+
+    /// Stops replaying, waiting (with the GIL released) for the replay thread to finish.
+    fn stop(&self) {
+        let replay = &self.replay;
+        Python::with_gil(|py| py.allow_threads(|| replay.stop()));
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ReplayHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.ReplayHandle of {} ({})>", self.replay.path().display(), if self.replay.is_running() { "running" } else { "stopped" })
+    }
+}
+
+/// Connects to Redis at `url` (redis://[[username]:password@]host[:port]) and starts a bridge that broadcasts every message published on `channels` (or on channels matching `patterns`, as for PSUBSCRIBE) to the clients of `server` (a ServerHandle; the module-level server if None), as text if it's valid UTF-8 and binary otherwise. With `publish_channel`, every client message is also published to that channel (and while the bridge runs, client messages can't be drained from the server). Blocks (with the GIL released) until subscribed.
+///
+/// A lost Redis connection is retried every second, and recorded as a "redis" error event, until the bridge is stopped or the server stops. Raises ValueError for a bad URL, ConnectError if Redis can't be reached (or refuses the login), and ServerNotRunning if the server isn't running. Only available when quicksocket is built with the "redis" feature.
+#[cfg(feature = "redis")]
+#[pyfunction(channels = "Vec::new()", patterns = "Vec::new()", publish_channel = "None", server = "None")]
+pub fn start_redis_bridge(py: Python, url: String, channels: Vec<String>, patterns: Vec<String>, publish_channel: Option<String>, server: Option<&ServerHandle>) -> PyResult<RedisBridgeHandle> {
+    let server = match server {
+        Some(handle) => handle.server.clone(),
+        None => default_server().ok_or_else(|| errors::server_not_running("start a Redis bridge"))?,
+    };
+    let config = server::RedisBridgeConfig { url, channels, patterns, publish_channel };
+    let bridge = py.allow_threads(|| server::RedisBridge::start(&server, config))
+        .map_err(|err| match err {
+            server::Error::NotRunning => errors::server_not_running("start a Redis bridge"),
+            err => errors::from_server_error(err, "start a Redis bridge"),
+        })?;
+    Ok(RedisBridgeHandle { bridge })
+}
+
+/// Counts of the messages a Redis bridge has passed on, as returned by RedisBridgeHandle.get_stats(). A snapshot, like ServerStats.
+#[cfg(feature = "redis")]
+#[pyclass]
+#[derive(Clone)]
+pub struct RedisBridgeStats {
+    /// Messages received from Redis and broadcast to the server's clients.
+    #[pyo3(get)] broadcast: u64,
+    /// Client messages published to Redis.
+    #[pyo3(get)] published: u64,
+    /// Times a lost Redis connection was reconnected.
+    #[pyo3(get)] reconnects: u64,
+}
+
+#[cfg(feature = "redis")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for RedisBridgeStats {
+    fn __repr__(&self) -> String {
+        format!("RedisBridgeStats(broadcast={}, published={}, reconnects={})", self.broadcast, self.published, self.reconnects)
+    }
+}
+
+/// Handle to a bridge started with start_redis_bridge(). The bridge keeps running if the handle is garbage collected.
+#[cfg(feature = "redis")]
+#[pyclass]
+pub struct RedisBridgeHandle {
+    bridge: server::RedisBridge,
+}
+
+#[cfg(feature = "redis")]
+#[pymethods]
+impl RedisBridgeHandle {
+    fn is_running(&self) -> bool {
+        self.bridge.is_running()
+    }
+
+    fn get_stats(&self) -> RedisBridgeStats {
+        let stats = self.bridge.stats();
+        RedisBridgeStats { broadcast: stats.broadcast, published: stats.published, reconnects: stats.reconnects }
+    }
+
+    /// Stops the bridge, waiting (with the GIL released) for its tasks to finish.
+    fn stop(&self) {
+        let bridge = &self.bridge;
+        Python::with_gil(|py| py.allow_threads(|| bridge.stop()));
+    }
+}
+
+#[cfg(feature = "redis")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for RedisBridgeHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.RedisBridgeHandle to {} ({})>", self.bridge.url(), if self.bridge.is_running() { "running" } else { "stopped" })
+    }
+}
+
+/// Starts a sink that produces every client message of `server` (a ServerHandle; the module-level server if None) to the Kafka `topic`, from quicksocket's own threads as they're received, rather than when the Python side drains them. `brokers` are bootstrap brokers, as "host[:port]". Messages are batched: a batch is sent once it has `max_batch` messages, or `linger_ms` after its first. Each record is keyed by the client id, and all of a client's messages go to the same partition. The sink is sent copies of the messages as they're received, so they can still be drained from the server as ever. Blocks (with the GIL released) until the topic has been found.
+///
+/// Messages that can't be delivered after a few attempts are dropped, counted in get_stats(), and recorded as "kafka" error events, as are those the sink misses by falling too far behind. Raises ValueError for a bad broker address or topic name, ConnectError if no broker can be reached (or the topic isn't there), and ServerNotRunning if the server isn't running. Only available when quicksocket is built with the "kafka" feature.
+#[cfg(feature = "kafka")]
+#[pyfunction(max_batch = "None", linger_ms = "None", server = "None")]
+pub fn start_kafka_sink(py: Python, brokers: Vec<String>, topic: String, max_batch: Option<usize>, linger_ms: Option<u64>, server: Option<&ServerHandle>) -> PyResult<KafkaSinkHandle> {
+    let server = match server {
+        Some(handle) => handle.server.clone(),
+        None => default_server().ok_or_else(|| errors::server_not_running("start a Kafka sink"))?,
+    };
+    let config = server::KafkaSinkConfig { brokers, topic, max_batch, linger: linger_ms.map(Duration::from_millis) };
+    let sink = py.allow_threads(|| server::KafkaSink::start(&server, config))
+        .map_err(|err| match err {
+            server::Error::NotRunning => errors::server_not_running("start a Kafka sink"),
+            err => errors::from_server_error(err, "start a Kafka sink"),
+        })?;
+    Ok(KafkaSinkHandle { sink })
+}
+
+/// Counts of what a Kafka sink has produced, as returned by KafkaSinkHandle.get_stats(). A snapshot, like ServerStats.
+#[cfg(feature = "kafka")]
+#[pyclass]
+#[derive(Clone)]
+pub struct KafkaSinkStats {
+    /// Messages acknowledged by Kafka.
+    #[pyo3(get)] delivered: u64,
+    /// Messages dropped after failing to be delivered.
+    #[pyo3(get)] failed: u64,
+    /// Produce requests sent, including retries.
+    #[pyo3(get)] requests: u64,
+}
+
+#[cfg(feature = "kafka")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for KafkaSinkStats {
+    fn __repr__(&self) -> String {
+        format!("KafkaSinkStats(delivered={}, failed={}, requests={})", self.delivered, self.failed, self.requests)
+    }
+}
+
+/// Handle to a sink started with start_kafka_sink(). The sink keeps running if the handle is garbage collected.
+#[cfg(feature = "kafka")]
+#[pyclass]
+pub struct KafkaSinkHandle {
+    sink: server::KafkaSink,
+}
+
+#[cfg(feature = "kafka")]
+#[pymethods]
+impl KafkaSinkHandle {
+    fn is_running(&self) -> bool {
+        self.sink.is_running()
+    }
+
+    fn get_stats(&self) -> KafkaSinkStats {
+        let stats = self.sink.stats();
+        KafkaSinkStats { delivered: stats.delivered, failed: stats.failed, requests: stats.requests }
+    }
+
+    /// Stops the sink, waiting (with the GIL released) for the batch in hand to be delivered.
+    fn stop(&self) {
+        let sink = &self.sink;
+        Python::with_gil(|py| py.allow_threads(|| sink.stop()));
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for KafkaSinkHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.KafkaSinkHandle to {} ({})>", self.sink.topic(), if self.sink.is_running() { "running" } else { "stopped" })
+    }
+}
+
+/// Starts a bridge that receives messages from ZeroMQ sockets and broadcasts each to the clients of `server` (a ServerHandle; the module-level server if None), without going through Python. `socket_type` is the bridge's end: "SUB" (the default) to receive from PUB sockets, subscribed to `topics` (message prefixes; everything if empty), or "PULL" to receive from PUSH sockets. The bridge connects to `endpoint` (tcp://host:port), retrying every second while it can't, or with `bind`, listens on it (tcp://*:port for every interface) for any number of peers.
+///
+/// Each message's last frame is broadcast, as text if it's valid UTF-8 and binary otherwise. Lost connections are recorded as "zmq" error events. Raises ValueError for a bad endpoint or socket type, BindError if the endpoint can't be bound, and ServerNotRunning if the server isn't running. Only available when quicksocket is built with the "zmq" feature.
+#[cfg(feature = "zmq")]
+#[pyfunction(socket_type = "\"SUB\"", topics = "Vec::new()", bind = "false", server = "None")]
+pub fn start_zmq_bridge(py: Python, endpoint: String, socket_type: &str, topics: Vec<String>, bind: bool, server: Option<&ServerHandle>) -> PyResult<ZmqBridgeHandle> {
+    let socket_type = match socket_type.to_ascii_uppercase().as_str() {
+        "SUB" => server::ZmqSocketType::Sub,
+        "PULL" => server::ZmqSocketType::Pull,
+        _ => { return Err(pyo3::exceptions::PyValueError::new_err(format!("socket_type must be \"SUB\" or \"PULL\", not {:?}.", socket_type))); }
+    };
+    let server = match server {
+        Some(handle) => handle.server.clone(),
+        None => default_server().ok_or_else(|| errors::server_not_running("start a ZeroMQ bridge"))?,
+    };
+    let config = server::ZmqBridgeConfig { endpoint, socket_type, topics, bind };
+    let bridge = py.allow_threads(|| server::ZmqBridge::start(&server, config))
+        .map_err(|err| match err {
+            server::Error::NotRunning => errors::server_not_running("start a ZeroMQ bridge"),
+            err => errors::from_server_error(err, "start a ZeroMQ bridge"),
+        })?;
+    Ok(ZmqBridgeHandle { bridge })
+}
+
+/// Counts of what a ZeroMQ bridge has passed on, as returned by ZmqBridgeHandle.get_stats(). A snapshot, like ServerStats.
+#[cfg(feature = "zmq")]
+#[pyclass]
+#[derive(Clone)]
+pub struct ZmqBridgeStats {
+    /// Messages received and broadcast to the server's clients.
+    #[pyo3(get)] broadcast: u64,
+    /// Peer connections established (with a completed ZMTP handshake), including reconnections.
+    #[pyo3(get)] connections: u64,
+}
+
+#[cfg(feature = "zmq")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for ZmqBridgeStats {
+    fn __repr__(&self) -> String {
+        format!("ZmqBridgeStats(broadcast={}, connections={})", self.broadcast, self.connections)
+    }
+}
+
+/// Handle to a bridge started with start_zmq_bridge(). The bridge keeps running if the handle is garbage collected.
+#[cfg(feature = "zmq")]
+#[pyclass]
+pub struct ZmqBridgeHandle {
+    bridge: server::ZmqBridge,
+}
+
+#[cfg(feature = "zmq")]
+#[pymethods]
+impl ZmqBridgeHandle {
+    fn is_running(&self) -> bool {
+        self.bridge.is_running()
+    }
+
+    fn get_stats(&self) -> ZmqBridgeStats {
+        let stats = self.bridge.stats();
+        ZmqBridgeStats { broadcast: stats.broadcast, connections: stats.connections }
+    }
+
+    /// Stops the bridge, waiting (with the GIL released) for its connections to close.
+    fn stop(&self) {
+        let bridge = &self.bridge;
+        Python::with_gil(|py| py.allow_threads(|| bridge.stop()));
+    }
+}
+
+#[cfg(feature = "zmq")]
+#[pyproto]
+impl pyo3::PyObjectProtocol for ZmqBridgeHandle {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.ZmqBridgeHandle on {} ({})>", self.bridge.endpoint(), if self.bridge.is_running() { "running" } else { "stopped" })
+    }
+}
+
+/// Routes the server's log output to Python's logging module, through logging.getLogger(`logger_name`), instead of printing it to stdout. Log lines below `level` (a logging level, e.g. logging.INFO) are discarded before they reach Python.
+///
+/// Records are handed to the logger from a dedicated log thread, so the server never waits on the GIL to log.
+#[pyfunction(logger_name = "String::from(\"quicksocket\")", level = "20")]
+pub fn enable_python_logging(py: Python, logger_name: String, level: u32) -> PyResult<()> {
+    let logger: PyObject = py.import("logging")?.call_method1("getLogger", (logger_name,))?.into();
+    py.allow_threads(|| log_bridge::enable(logger, log_bridge::level_from_python(level)))
+        .map_err(QuicksocketError::new_err)
+}
+
+/// Stops routing log output to Python's logging module (see enable_python_logging()); the server prints it to stdout again.
+#[pyfunction]
+pub fn disable_python_logging(py: Python) {
+    py.allow_threads(log_bridge::disable)
+}
+
 /// Defines the actual python module for pyo3 to generate.
 #[pymodule]
-fn quicksocket(_py: Python, m: &PyModule) -> PyResult<()> {
+fn quicksocket(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(start_server,               m)?)?;
+    m.add_function(wrap_pyfunction!(start_server_instance,      m)?)?;
     m.add_function(wrap_pyfunction!(is_server_running,          m)?)?;
+    m.add_function(wrap_pyfunction!(get_server_state,           m)?)?;
+    m.add_function(wrap_pyfunction!(wait_until_started,         m)?)?;
+    m.add_function(wrap_pyfunction!(wait_for_client,            m)?)?;
+    m.add_function(wrap_pyfunction!(is_client_connected,        m)?)?;
+    m.add_function(wrap_pyfunction!(begin_draining,             m)?)?;
+    m.add_function(wrap_pyfunction!(wait_until_drained,         m)?)?;
     m.add_function(wrap_pyfunction!(shutdown_server,            m)?)?;
+    m.add_function(wrap_pyfunction!(shutdown_all_servers,       m)?)?;
+    m.add_function(wrap_pyfunction!(enable_signal_handling,     m)?)?;
+    m.add_function(wrap_pyfunction!(get_shutdown_signal,        m)?)?;
     m.add_function(wrap_pyfunction!(get_last_error_string,      m)?)?;
     m.add_function(wrap_pyfunction!(drain_new_client_events,    m)?)?;
+    m.add_function(wrap_pyfunction!(drain_connection_events,    m)?)?;
+    m.add_function(wrap_pyfunction!(drain_ping_events,          m)?)?;
+    m.add_function(wrap_pyfunction!(get_playback_state,         m)?)?;
+    m.add_function(wrap_pyfunction!(drain_playback_events,      m)?)?;
+    m.add_function(wrap_pyfunction!(drain_input_snapshots,      m)?)?;
+    m.add_function(wrap_pyfunction!(drain_error_events,         m)?)?;
+    m.add_function(wrap_pyfunction!(get_recent_errors,          m)?)?;
+    m.add_function(wrap_pyfunction!(set_recent_error_capacity,  m)?)?;
+    m.add_function(wrap_pyfunction!(get_event_log,              m)?)?;
     m.add_function(wrap_pyfunction!(try_send_messages,          m)?)?;
+    m.add_function(wrap_pyfunction!(try_send_to_client,         m)?)?;
+    m.add_function(wrap_pyfunction!(send_ping,                  m)?)?;
+    m.add_function(wrap_pyfunction!(register_message,           m)?)?;
+    m.add_function(wrap_pyfunction!(send_and_confirm,           m)?)?;
+    m.add_function(wrap_pyfunction!(send_stamped,               m)?)?;
+    m.add_function(wrap_pyfunction!(send_sample,                m)?)?;
+    m.add_function(wrap_pyfunction!(send_synced,                m)?)?;
+    m.add_function(wrap_pyfunction!(publish_ros,                m)?)?;
+    m.add_function(wrap_pyfunction!(send_frame,                 m)?)?;
+    m.add_function(wrap_pyfunction!(send_keyframe,              m)?)?;
+    m.add_function(wrap_pyfunction!(send_delta,                 m)?)?;
+    m.add_function(wrap_pyfunction!(send_pointcloud,            m)?)?;
+    m.add_function(wrap_pyfunction!(send_mesh,                  m)?)?;
+    m.add_function(wrap_pyfunction!(send_figure,                m)?)?;
+    m.add_function(wrap_pyfunction!(log_scalar,                 m)?)?;
+    m.add_function(wrap_pyfunction!(log_histogram,              m)?)?;
     m.add_function(wrap_pyfunction!(drain_client_messages,      m)?)?;
+    m.add_function(wrap_pyfunction!(messages,                   m)?)?;
+    m.add_function(wrap_pyfunction!(send_python_objects,        m)?)?;
+    m.add_function(wrap_pyfunction!(drain_python_objects,       m)?)?;
+    m.add_function(wrap_pyfunction!(get_message_fd,             m)?)?;
+    m.add_function(wrap_pyfunction!(set_on_message,             m)?)?;
+    m.add_function(wrap_pyfunction!(get_server_stats,           m)?)?;
+    m.add_function(wrap_pyfunction!(get_latency_histograms,     m)?)?;
+    m.add_function(wrap_pyfunction!(get_diagnostics,            m)?)?;
+    m.add_function(wrap_pyfunction!(get_cluster_peers,          m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_stats,           m)?)?;
+    m.add_function(wrap_pyfunction!(get_client_capabilities,    m)?)?;
+    m.add_function(wrap_pyfunction!(get_all_client_capabilities, m)?)?;
+    m.add_function(wrap_pyfunction!(get_ros_subscriptions,      m)?)?;
+    m.add_function(wrap_pyfunction!(start_recording,            m)?)?;
+    m.add_function(wrap_pyfunction!(stop_recording,             m)?)?;
+    m.add_function(wrap_pyfunction!(get_recording_stats,        m)?)?;
+    m.add_function(wrap_pyfunction!(connect_loopback,           m)?)?;
+    m.add_function(wrap_pyfunction!(connect_to,                 m)?)?;
+    m.add_function(wrap_pyfunction!(start_relay,                m)?)?;
+    m.add_function(wrap_pyfunction!(start_replay,               m)?)?;
+    #[cfg(feature = "redis")]
+    m.add_function(wrap_pyfunction!(start_redis_bridge,         m)?)?;
+    #[cfg(feature = "kafka")]
+    m.add_function(wrap_pyfunction!(start_kafka_sink,           m)?)?;
+    #[cfg(feature = "zmq")]
+    m.add_function(wrap_pyfunction!(start_zmq_bridge,           m)?)?;
+    m.add_function(wrap_pyfunction!(enable_python_logging,      m)?)?;
+    m.add_function(wrap_pyfunction!(disable_python_logging,     m)?)?;
+    m.add_class::<MessageIterator>()?;
+    m.add_class::<MessageBuffer>()?;
+    m.add_class::<RegisteredMessage>()?;
+    m.add_class::<ClientMessage>()?;
+    m.add_class::<ConnectionEvent>()?;
+    m.add_class::<PingEvent>()?;
+    m.add_class::<PlaybackState>()?;
+    m.add_class::<PlaybackEvent>()?;
+    m.add_class::<InputSnapshot>()?;
+    m.add_class::<ErrorEvent>()?;
+    m.add_class::<EventLogEntry>()?;
+    m.add_class::<ServerStats>()?;
+    m.add_class::<LatencyHistogram>()?;
+    m.add_class::<LatencyHistograms>()?;
+    m.add_class::<Diagnostics>()?;
+    m.add_class::<ClusterPeer>()?;
+    m.add_class::<ClientStats>()?;
+    m.add_class::<ClientCapabilities>()?;
+    m.add_class::<RecordingStats>()?;
+    m.add_class::<ServerHandle>()?;
+    m.add_class::<ShutdownHandle>()?;
+    m.add_class::<LoopbackClient>()?;
+    m.add_class::<ClientHandle>()?;
+    m.add_class::<RelayHandle>()?;
+    m.add_class::<RelayStats>()?;
+    m.add_class::<ReplayHandle>()?;
+    m.add_class::<ReplayStats>()?;
+    #[cfg(feature = "redis")]
+    m.add_class::<RedisBridgeHandle>()?;
+    #[cfg(feature = "redis")]
+    m.add_class::<RedisBridgeStats>()?;
+    #[cfg(feature = "kafka")]
+    m.add_class::<KafkaSinkHandle>()?;
+    #[cfg(feature = "kafka")]
+    m.add_class::<KafkaSinkStats>()?;
+    #[cfg(feature = "zmq")]
+    m.add_class::<ZmqBridgeHandle>()?;
+    #[cfg(feature = "zmq")]
+    m.add_class::<ZmqBridgeStats>()?;
+    errors::register(py, m)?;
+
+    // Shut down gracefully at interpreter exit, while threads can still take the GIL.
+    py.import("atexit")?.call_method1("register", (m.getattr("shutdown_all_servers")?,))?;
 
     Ok(())
 }
@@ -0,0 +1,9 @@
+// quicksocket.rs
+// ==============
+//
+// The standalone quicksocket binary, built with the "cli" feature. Everything's in cli.rs; see cli::USAGE.
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    std::process::exit(quicksocket::cli::main(&args));
+}
@@ -0,0 +1,228 @@
+// buffer.rs
+// =========
+//
+// Read-only access to Python objects supporting the buffer protocol (bytearray, memoryview, numpy arrays, ...), so binary payloads can be taken from them directly rather than requiring a bytes() copy first.
+
+use pyo3::{ffi, prelude::*, AsPyPointer, PyNativeType};
+
+/// A C-contiguous view of an object's buffer, viewed as raw bytes regardless of the buffer's element format. The buffer is released on drop.
+///
+/// While the view is held, exporters like bytearray refuse to resize, so the memory stays valid even with the GIL released. (Its contents can still be mutated by other Python threads, in which case readers may see a torn copy -- the same as any unsynchronized access in Python.)
+pub struct ByteBuffer(Box<ffi::Py_buffer>);
+
+// The Py_buffer is only read after creation, and its memory is pinned by the export (see above).
+unsafe impl Send for ByteBuffer {}
+unsafe impl Sync for ByteBuffer {}
+
+impl ByteBuffer {
+    /// Returns None if the object doesn't support the buffer protocol at all, or an Err if it does but can't provide a C-contiguous view.
+    pub fn get(obj: &PyAny) -> Option<PyResult<ByteBuffer>> {
+        unsafe {
+            if ffi::PyObject_CheckBuffer(obj.as_ptr()) == 0 { return None; }
+
+            let mut view: Box<ffi::Py_buffer> = Box::new(std::mem::zeroed());
+            if ffi::PyObject_GetBuffer(obj.as_ptr(), &mut *view, ffi::PyBUF_C_CONTIGUOUS) == -1 {
+                return Some(Err(PyErr::fetch(obj.py())));
+            }
+            Some(Ok(ByteBuffer(view)))
+        }
+    }
+
+    /// As get(), also asking for the buffer's element format, for reading its elements as numbers (see to_f32s(), to_f64s() and to_u32s()). Raises TypeError if the object doesn't support the buffer protocol.
+    pub fn get_typed(obj: &PyAny) -> PyResult<ByteBuffer> {
+        unsafe {
+            if ffi::PyObject_CheckBuffer(obj.as_ptr()) == 0 {
+                return Err(pyo3::exceptions::PyTypeError::new_err(format!("Expected a buffer-protocol object (a numpy array, array.array, ...), not {}.", obj.get_type().name()?)));
+            }
+
+            let mut view: Box<ffi::Py_buffer> = Box::new(std::mem::zeroed());
+            if ffi::PyObject_GetBuffer(obj.as_ptr(), &mut *view, ffi::PyBUF_C_CONTIGUOUS | ffi::PyBUF_FORMAT) == -1 {
+                return Err(PyErr::fetch(obj.py()));
+            }
+            Ok(ByteBuffer(view))
+        }
+    }
+
+    /// The struct-module format of the buffer's elements ("B" if it didn't say), e.g. "f" for float32 or "<q" for little-endian int64.
+    pub fn format(&self) -> &str {
+        if self.0.format.is_null() { return "B"; }
+        unsafe { std::ffi::CStr::from_ptr(self.0.format) }.to_str().unwrap_or("")
+    }
+
+    pub fn itemsize(&self) -> usize {
+        self.0.itemsize as usize
+    }
+
+    /// The elements, if they're floats or integers, as f32s (rounded to the nearest, for float64s and large integers).
+    pub fn to_f32s(&self) -> Result<Vec<f32>, String> {
+        self.elements().map(|elements| elements.map(|element| match element {
+            Element::Float(value) => value as f32,
+            Element::Int(value) => value as f32,
+        }).collect())
+    }
+
+    /// The elements, if they're floats or integers, as f64s (rounded to the nearest, for large integers).
+    pub fn to_f64s(&self) -> Result<Vec<f64>, String> {
+        self.elements().map(|elements| elements.map(|element| match element {
+            Element::Float(value) => value,
+            Element::Int(value) => value as f64,
+        }).collect())
+    }
+
+    /// The elements, if they're integers from 0 to u32::MAX, as u32s.
+    pub fn to_u32s(&self) -> Result<Vec<u32>, String> {
+        self.elements()?.map(|element| match element {
+            Element::Int(value) => <u32 as std::convert::TryFrom<i128>>::try_from(value).map_err(|_| format!("{} is out of range for a 32-bit unsigned integer", value)),
+            Element::Float(_) => Err(format!("expected integers, not elements of format {:?}", self.format())),
+        }).collect()
+    }
+
+    /// Reads the elements by their format: native or little-endian floats or integers of any size.
+    fn elements(&self) -> Result<impl Iterator<Item = Element> + '_, String> {
+        let format = self.format();
+        let (little_endian, code) = match format.as_bytes() {
+            [b'@' | b'=', code] | [code] => (cfg!(target_endian = "little"), *code),
+            [b'<', code] => (true, *code),
+            _ => return Err(format!("unsupported buffer format {:?} (expected native or little-endian numbers)", format)),
+        };
+        let size = self.itemsize();
+        let kind = match (code, size) {
+            (b'f', 4) | (b'd', 8) => Kind::Float,
+            (b'b' | b'h' | b'i' | b'l' | b'q' | b'n', 1 | 2 | 4 | 8) => Kind::Signed,
+            (b'B' | b'H' | b'I' | b'L' | b'Q' | b'N', 1 | 2 | 4 | 8) => Kind::Unsigned,
+            _ => return Err(format!("unsupported buffer format {:?} (expected floats or integers)", format)),
+        };
+        Ok(self.as_slice().chunks_exact(size).map(move |chunk| {
+            let mut bytes = [0u8; 8];
+            match little_endian {
+                true => bytes[..size].copy_from_slice(chunk),
+                false => bytes[8 - size..].copy_from_slice(chunk),
+            }
+            let raw = if little_endian { u64::from_le_bytes(bytes) } else { u64::from_be_bytes(bytes) };
+            // (Sign-extended from its size.)
+            let shift = 64 - 8 * size as u32;
+            match (kind, size) {
+                (Kind::Float, 4) => Element::Float(f32::from_bits(raw as u32) as f64),
+                (Kind::Float, _) => Element::Float(f64::from_bits(raw)),
+                (Kind::Signed, _) => Element::Int((((raw << shift) as i64) >> shift) as i128),
+                (Kind::Unsigned, _) => Element::Int(raw as i128),
+            }
+        }))
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.0.buf as *const u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        if self.len() == 0 { return &[]; }
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Float,
+    Signed,
+    Unsigned,
+}
+
+enum Element {
+    Float(f64),
+    Int(i128),
+}
+
+impl Drop for ByteBuffer {
+    fn drop(&mut self) {
+        Python::with_gil(|_| unsafe { ffi::PyBuffer_Release(&mut *self.0) });
+    }
+}
+
+/// An inbound binary message whose bytes stay owned by Rust, exposed to Python through the (read-only) buffer protocol. Returned in place of bytes for large messages when zero-copy receive is enabled, so e.g. `numpy.frombuffer(msg)` or `memoryview(msg)` don't copy multi-megabyte payloads.
+///
+/// Use .copy() (or bytes(msg)) to get an ordinary bytes object.
+#[pyclass]
+pub struct MessageBuffer {
+    data: Vec<u8>,
+}
+
+impl MessageBuffer {
+    pub fn new(data: Vec<u8>) -> MessageBuffer {
+        MessageBuffer { data }
+    }
+}
+
+#[pymethods]
+impl MessageBuffer {
+    /// Returns a copy of the message as bytes.
+    fn copy(&self) -> PyObject {
+        Python::with_gil(|py| pyo3::types::PyBytes::new(py, &self.data).into())
+    }
+
+    fn __bytes__(&self) -> PyObject {
+        self.copy()
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for MessageBuffer {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.MessageBuffer of {} bytes>", self.data.len())
+    }
+}
+
+#[pyproto]
+impl pyo3::PySequenceProtocol for MessageBuffer {
+    fn __len__(&self) -> usize {
+        self.data.len()
+    }
+}
+
+#[pyproto]
+impl pyo3::PyBufferProtocol for MessageBuffer {
+    fn bf_getbuffer(slf: PyRefMut<Self>, view: *mut ffi::Py_buffer, flags: std::os::raw::c_int) -> PyResult<()> {
+        if view.is_null() {
+            return Err(pyo3::exceptions::PyBufferError::new_err("View is null"));
+        }
+        if (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE {
+            return Err(pyo3::exceptions::PyBufferError::new_err("MessageBuffer is read-only"));
+        }
+
+        // The data Vec is never modified after construction, so its pointer stays valid for as long as the view holds a reference to the object.
+        unsafe {
+            (*view).obj = slf.as_ptr();
+            ffi::Py_INCREF((*view).obj);
+
+            (*view).buf = slf.data.as_ptr() as *mut std::os::raw::c_void;
+            (*view).len = slf.data.len() as isize;
+            (*view).readonly = 1;
+            (*view).itemsize = 1;
+
+            (*view).format = std::ptr::null_mut();
+            if (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT {
+                (*view).format = b"B\0".as_ptr() as *mut _;
+            }
+
+            (*view).ndim = 1;
+            (*view).shape = std::ptr::null_mut();
+            if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND {
+                (*view).shape = &((*view).len) as *const _ as *mut _;
+            }
+
+            (*view).strides = std::ptr::null_mut();
+            if (flags & ffi::PyBUF_STRIDES) == ffi::PyBUF_STRIDES {
+                (*view).strides = &((*view).itemsize) as *const _ as *mut _;
+            }
+
+            (*view).suboffsets = std::ptr::null_mut();
+            (*view).internal = std::ptr::null_mut();
+        }
+        Ok(())
+    }
+
+    fn bf_releasebuffer(_slf: PyRefMut<Self>, _view: *mut ffi::Py_buffer) {}
+}
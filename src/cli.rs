@@ -0,0 +1,385 @@
+// cli.rs
+// ======
+//
+// The standalone quicksocket binary (src/bin/quicksocket.rs), built with the "cli" feature: runs a server configured from a file and command-line flags, with lines of text as its interface, so tools without bindings (shell scripts, or programs in any language) can produce and consume through a pipe. Each line read from the input (stdin, or a named pipe) is broadcast to the clients as a text message, and each message from a client is written to the output (stdout, or a named pipe) as a line.
+//
+// Config files have one `key = value` setting per line, with `#` comments; every key can also be given as a flag (`--key value`, or `--key=value`), which overrides the file. See USAGE for the keys.
+
+use std::{fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Read, Write}, path::{Path, PathBuf}, sync::mpsc, thread, time::Duration};
+
+use crate::server::{self, ClusterConfig, Message, ProxyRoute, Server, ServerConfig, events::ClientMessage, logging::{self, Level}};
+use crate::signals;
+
+/// How long each drain of client messages waits for one, before checking whether the server's still running.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long the binary waits, once the server has stopped, for the last client messages to be written out.
+const OUTPUT_FINISH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many log lines can be waiting to be written to stderr before new ones are dropped.
+const LOG_QUEUE_LEN: usize = 1024;
+
+pub const USAGE: &str = "\
+Usage: quicksocket [--config FILE] [--KEY VALUE]...
+
+Runs a quicksocket websocket server. Each line of input is broadcast to the
+clients as a text message, and each client message is written out as a line.
+Settings come from FILE (one `key = value` per line, `#` for comments), and
+flags override them:
+
+  port = 9001                     The port to listen on (required; 0 picks one).
+  input = -                       Where lines to broadcast come from: - (stdin,
+                                  the default), a file, a named pipe, or none.
+                                  The server stops when stdin or a file ends; a
+                                  named pipe is reopened for the next writer.
+  output = -                      Where client messages go: - (stdout, the
+                                  default), a file (appended to), a named pipe,
+                                  or none. Line breaks in messages become
+                                  spaces; binary messages are skipped.
+  client_ids = false              Start each output line with the sending
+                                  client's id and a tab.
+  inspector = false               Serve the debug inspector at /inspector.
+  landing_page = FILE             HTML for plain HTTP requests.
+  proxy = /PATH ws://HOST:PORT    Proxy a path to another backend (repeatable).
+  cluster_peer = ws://HOST:PORT   Relay broadcasts to another node, and its
+                                  broadcasts to these clients (repeatable).
+  node_id = NAME                  This node's id within its cluster.
+  cluster_secret = SECRET         Only link with nodes that have it too.
+  log_level = warning             Logging to stderr: debug, info, warning,
+                                  error, or off.
+
+Flags: --config FILE, --help, and --KEY VALUE (or --KEY=VALUE) for any key
+above; --inspector and --client_ids alone mean true. Dashes can stand in for
+underscores in keys. Stops on SIGINT or SIGTERM.
+";
+
+/// Where the binary reads lines from, or writes them to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    /// Stdin for input, stdout for output.
+    Std,
+    /// A file or named pipe.
+    Path(PathBuf),
+    /// Nothing: no lines are read, or client messages are discarded.
+    Disabled,
+}
+
+/// Everything the binary runs with.
+#[derive(Clone, Debug)]
+pub struct CliConfig {
+    pub port: Option<u32>,
+    pub server: ServerConfig,
+    pub input: Endpoint,
+    pub output: Endpoint,
+    /// Whether output lines start with the client's id and a tab.
+    pub client_ids: bool,
+    /// The lowest level logged to stderr, or None for no logging.
+    pub log_level: Option<Level>,
+}
+
+impl Default for CliConfig {
+    fn default() -> CliConfig {
+        CliConfig { port: None, server: ServerConfig::default(), input: Endpoint::Std, output: Endpoint::Std, client_ids: false, log_level: Some(Level::Warning) }
+    }
+}
+
+impl CliConfig {
+    /// Parses command-line arguments (without the program name), reading the config file they name, if any. Returns None if they ask for the usage text.
+    pub fn from_args(args: &[String]) -> Result<Option<CliConfig>, String> {
+        let mut flags = vec![];
+        let mut config_file = None;
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            if arg == "-h" || arg == "--help" { return Ok(None); }
+            let flag = arg.strip_prefix("--").ok_or_else(|| format!("unexpected argument {:?}", arg))?;
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.replace('-', "_"), value.to_string()),
+                None => {
+                    let key = flag.replace('-', "_");
+                    let is_bare = args.peek().map(|next| next.starts_with("--")).unwrap_or(true);
+                    match (is_bare, key.as_str()) {
+                        (true, "inspector") | (true, "client_ids") => (key, "true".to_string()),
+                        (true, _) => { return Err(format!("--{} needs a value", flag)); }
+                        (false, _) => (key, args.next().unwrap().clone()),
+                    }
+                }
+            };
+            match key.as_str() {
+                "config" => { config_file = Some(PathBuf::from(value)); }
+                _ => { flags.push((key, value)); }
+            }
+        }
+
+        let mut config = CliConfig::default();
+        if let Some(path) = config_file {
+            let text = fs::read_to_string(&path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+            // (Paths in the file are relative to it.)
+            let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            for (number, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() { continue; }
+                let (key, value) = line.split_once('=').ok_or_else(|| format!("{}:{}: expected `key = value`", path.display(), number + 1))?;
+                config.set(key.trim(), value.trim(), &base_dir).map_err(|err| format!("{}:{}: {}", path.display(), number + 1, err))?;
+            }
+        }
+        for (key, value) in flags {
+            config.set(&key, &value, Path::new("")).map_err(|err| format!("--{}: {}", key, err))?;
+        }
+        Ok(Some(config))
+    }
+
+    /// Applies one setting. Later settings replace earlier ones, except for the repeatable proxy and cluster_peer, which add to them.
+    fn set(&mut self, key: &str, value: &str, base_dir: &Path) -> Result<(), String> {
+        match key {
+            "port" => { self.port = Some(value.parse().map_err(|_| format!("{:?} isn't a port number", value))?); }
+            "input" => { self.input = endpoint(value, base_dir); }
+            "output" => { self.output = endpoint(value, base_dir); }
+            "client_ids" => { self.client_ids = boolean(value)?; }
+            "inspector" => { self.server.inspector = boolean(value)?; }
+            "landing_page" => {
+                let path = base_dir.join(value);
+                self.server.landing_page = Some(fs::read_to_string(&path).map_err(|err| format!("failed to read {}: {}", path.display(), err))?);
+            }
+            "proxy" => {
+                let (path, backend) = value.split_once(char::is_whitespace).ok_or_else(|| "expected a path and a backend URL, e.g. `/legacy ws://127.0.0.1:8000`".to_string())?;
+                self.server.proxy_routes.push(ProxyRoute::new(path, backend.trim()));
+            }
+            "cluster_peer" => { self.cluster().peers.push(value.to_string()); }
+            "node_id" => { self.cluster().node_id = value.to_string(); }
+            "cluster_secret" => { self.cluster().secret = Some(value.to_string()); }
+            "log_level" => {
+                self.log_level = match value {
+                    "debug" => Some(Level::Debug),
+                    "info" => Some(Level::Info),
+                    "warning" => Some(Level::Warning),
+                    "error" => Some(Level::Error),
+                    "off" => None,
+                    _ => { return Err(format!("{:?} isn't one of debug, info, warning, error, or off", value)); }
+                };
+            }
+            _ => { return Err(format!("unknown setting {:?}", key)); }
+        }
+        Ok(())
+    }
+
+    /// The cluster configuration, which any of the cluster settings turns on.
+    fn cluster(&mut self) -> &mut ClusterConfig {
+        self.server.cluster.get_or_insert_with(ClusterConfig::default)
+    }
+}
+
+fn endpoint(value: &str, base_dir: &Path) -> Endpoint {
+    match value {
+        "-" => Endpoint::Std,
+        "none" => Endpoint::Disabled,
+        path => Endpoint::Path(base_dir.join(path)),
+    }
+}
+
+fn boolean(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("{:?} isn't true or false", value)),
+    }
+}
+
+/// The binary's entry point: parses `args` (without the program name) and runs the server until it stops, returning the process's exit code (0 once the server has stopped, 1 if it couldn't run, 2 for bad arguments).
+pub fn main(args: &[String]) -> i32 {
+    let config = match CliConfig::from_args(args) {
+        Ok(Some(config)) => config,
+        Ok(None) => { print!("{}", USAGE); return 0; }
+        Err(err) => { eprintln!("quicksocket: {}\nRun quicksocket --help for usage.", err); return 2; }
+    };
+    log_to_stderr(config.log_level);
+    match run(config) {
+        Ok(()) => 0,
+        Err(err) => { eprintln!("quicksocket: {}", err); 1 }
+    }
+}
+
+/// Routes the server's log output to stderr (stdout being the output, by default), from a thread of its own.
+fn log_to_stderr(min_level: Option<Level>) {
+    let (log_tx, mut log_rx) = tokio::sync::mpsc::channel::<logging::LogRecord>(LOG_QUEUE_LEN);
+    // With logging off, the receiver's just dropped: records sent to a closed sink are discarded.
+    logging::set_sink(Some((log_tx, min_level.unwrap_or(Level::Error))));
+    if min_level.is_none() { return; }
+    let spawned = thread::Builder::new().name("quicksocket-log".to_string()).spawn(move || {
+        while let Some(record) = log_rx.blocking_recv() { eprintln!("{}", record.message); }
+    });
+    if let Err(err) = spawned { eprintln!("quicksocket: failed to spawn the log thread: {:?}", err); }
+}
+
+/// Runs the server `config` describes until it's stopped: by a signal, or by the end of the input (if that's stdin or a file).
+pub fn run(config: CliConfig) -> Result<(), String> {
+    let port = config.port.ok_or_else(|| "no port given (set `port` in the config file, or pass --port)".to_string())?;
+    signals::enable()?;
+    let server = Server::start(port, config.server).map_err(|err| err.to_string())?;
+    server.wait_until_started(None).map_err(|err| err.to_string())?;
+    if config.log_level.is_some() {
+        eprintln!("quicksocket: listening on {}", server::bind_address(server.bound_port().unwrap_or(port)));
+    }
+
+    if config.input != Endpoint::Disabled {
+        let input_server = server.clone();
+        let input = config.input.clone();
+        // (Never joined: it may be blocked reading, or waiting for a writer to open its pipe, when the server stops.)
+        thread::Builder::new().name("quicksocket-input".to_string())
+            .spawn(move || read_input(&input_server, &input))
+            .map_err(|err| format!("failed to spawn the input thread: {:?}", err))?;
+    }
+    let (output_done_tx, output_done_rx) = mpsc::channel();
+    let output_server = server.clone();
+    let output = config.output.clone();
+    let client_ids = config.client_ids;
+    thread::Builder::new().name("quicksocket-output".to_string())
+        .spawn(move || { write_output(&output_server, &output, client_ids); let _ = output_done_tx.send(()); })
+        .map_err(|err| format!("failed to spawn the output thread: {:?}", err))?;
+
+    server.wait_for_shutdown(None);
+    // An output pipe nobody's reading from may never take the last messages.
+    let _ = output_done_rx.recv_timeout(OUTPUT_FINISH_TIMEOUT);
+    server.join();
+    Ok(())
+}
+
+/// Whether `path` is a named pipe, which is reopened for the next writer (or reader) rather than ending the input (or output).
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).map(|metadata| metadata.file_type().is_fifo()).unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// Input thread body: broadcasts each line read from `input`, until it ends (stopping the server) or the server stops.
+fn read_input(server: &Server, input: &Endpoint) {
+    loop {
+        let reader: io::Result<Box<dyn Read>> = match input {
+            Endpoint::Std => Ok(Box::new(io::stdin())),
+            // (Opening a named pipe waits for a writer.)
+            Endpoint::Path(path) => File::open(path).map(|file| Box::new(file) as Box<dyn Read>),
+            Endpoint::Disabled => { return; }
+        };
+        let reader = match reader {
+            Ok(reader) => reader,
+            Err(err) => {
+                log_error!("[cli] Failed to open the input: {}", err);
+                break;
+            }
+        };
+        if broadcast_lines(server, BufReader::new(reader)).is_err() { return; }
+        match input {
+            Endpoint::Path(path) if is_fifo(path) && server.is_running() => { log_debug!("[cli] The input pipe's writer closed it; waiting for the next."); }
+            _ => { break; }
+        }
+    }
+    log_info!("[cli] The input ended; shutting down.");
+    let _ = server.shutdown(false);
+}
+
+/// Broadcasts lines from `reader` until it ends, returning Err once the server's stopped. Lines that have already been read in together are sent together, as one batch.
+fn broadcast_lines(server: &Server, mut reader: BufReader<Box<dyn Read>>) -> Result<(), ()> {
+    let mut line = vec![];
+    loop {
+        let mut batch = vec![];
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => { break; }
+                Ok(_) => {}
+                Err(err) => {
+                    log_error!("[cli] Failed to read the input: {}", err);
+                    break;
+                }
+            }
+            while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') { line.pop(); }
+            match String::from_utf8(line.clone()) {
+                Ok(text) if text.is_empty() => {}
+                Ok(text) => { batch.push(Message::Text(text)); }
+                Err(_) => { log_warn!("[cli] Skipped an input line that isn't UTF-8."); }
+            }
+            if !reader.buffer().contains(&b'\n') { break; }
+        }
+        if batch.is_empty() && line.is_empty() {
+            // (Only an empty read, i.e. the end of the input, leaves both empty.)
+            return Ok(());
+        }
+        if !batch.is_empty() && server.send(batch).is_err() { return Err(()); }
+    }
+}
+
+/// Output thread body: writes each client message to `output` as a line, until the server has stopped and every message has been written.
+fn write_output(server: &Server, output: &Endpoint, client_ids: bool) {
+    let mut writer: Option<Box<dyn Write>> = None;
+    loop {
+        let messages = server.drain_messages(Some(DRAIN_TIMEOUT), usize::MAX);
+        let messages = match messages {
+            Ok(messages) => messages,
+            Err(err) => {
+                log_error!("[cli] Can't take the client messages: {}", err);
+                return;
+            }
+        };
+        if messages.is_empty() {
+            if !server.is_running() { return; }
+            continue;
+        }
+        if *output == Endpoint::Disabled { continue; }
+
+        let mut lines = Vec::with_capacity(messages.len() * 64);
+        for msg in &messages { push_line(&mut lines, msg, client_ids); }
+        if lines.is_empty() { continue; }
+        if writer.is_none() {
+            match open_output(output) {
+                Ok(opened) => { writer = Some(opened); }
+                Err(err) => {
+                    log_error!("[cli] Failed to open the output: {}", err);
+                    let _ = server.shutdown(false);
+                    return;
+                }
+            }
+        }
+        let written = writer.as_mut().unwrap().write_all(&lines).and_then(|_| writer.as_mut().unwrap().flush());
+        if let Err(err) = written {
+            writer = None;
+            match output {
+                Endpoint::Path(path) if is_fifo(path) => {
+                    log_warn!("[cli] The output pipe's reader went away ({}); {} message(s) lost. Waiting for the next.", err, messages.len());
+                }
+                _ => {
+                    log_error!("[cli] Failed to write the output ({}); shutting down.", err);
+                    let _ = server.shutdown(false);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn open_output(output: &Endpoint) -> io::Result<Box<dyn Write>> {
+    match output {
+        Endpoint::Std => Ok(Box::new(io::stdout())),
+        // (Opening a named pipe waits for a reader.)
+        Endpoint::Path(path) => Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        Endpoint::Disabled => Ok(Box::new(io::sink())),
+    }
+}
+
+/// Appends a client message's output line to `lines`. Line breaks become spaces (which keeps JSON, say, meaning the same); binary messages are skipped.
+fn push_line(lines: &mut Vec<u8>, msg: &ClientMessage, client_ids: bool) {
+    let text = match &msg.message {
+        Message::Text(text) => text,
+        _ => {
+            log_warn!("[cli] Skipped a binary message from {}: the output is lines of text.", msg.client_id);
+            return;
+        }
+    };
+    if client_ids {
+        lines.extend_from_slice(msg.client_id.as_bytes());
+        lines.push(b'\t');
+    }
+    lines.extend(text.bytes().map(|byte| if byte == b'\n' || byte == b'\r' { b' ' } else { byte }));
+    lines.push(b'\n');
+}
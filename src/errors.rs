@@ -0,0 +1,88 @@
+// errors.rs
+// =========
+//
+// Python exception hierarchy raised by the pyfunctions in api.rs. Every exception derives from quicksocket.QuicksocketError, and carries its context as attributes on the exception instance (e.g. BindError.port), so callers don't have to parse messages.
+
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+use crate::server;
+
+create_exception!(quicksocket, QuicksocketError, PyException);
+create_exception!(quicksocket, ServerNotRunning, QuicksocketError);
+create_exception!(quicksocket, BindError, QuicksocketError);
+create_exception!(quicksocket, SendError, QuicksocketError);
+create_exception!(quicksocket, TlsError, QuicksocketError);
+create_exception!(quicksocket, ConnectError, QuicksocketError);
+
+/// Creates `err` and sets the given attributes on its instance.
+fn with_context(err: PyErr, context: &[(&str, PyObject)]) -> PyErr {
+    Python::with_gil(|py| {
+        let instance = err.instance(py);
+        for (name, value) in context {
+            // Setting attributes on an exception instance can't reasonably fail; if it does, the message still says what happened.
+            let _ = instance.setattr(*name, value);
+        }
+        err
+    })
+}
+
+/// The server isn't running, so `operation` (the name of the function called) can't be performed. Attributes: `operation`.
+pub fn server_not_running(operation: &str) -> PyErr {
+    let err = ServerNotRunning::new_err(format!("Can't {}: the server isn't running.", operation));
+    with_context(err, &[("operation", Python::with_gil(|py| operation.into_py(py)))])
+}
+
+/// The server couldn't listen on `address`. Attributes: `port`, `address`, `reason`.
+pub fn bind_error(port: u32, address: &str, reason: &str) -> PyErr {
+    let err = BindError::new_err(format!("Failed to bind {}: {}", address, reason));
+    with_context(err, &Python::with_gil(|py| [
+        ("port", port.into_py(py)),
+        ("address", address.into_py(py)),
+        ("reason", reason.into_py(py)),
+    ]))
+}
+
+/// A batch of `message_count` messages couldn't be handed to the server. Attributes: `reason`, `message_count`.
+pub fn send_error(reason: &str, message_count: usize) -> PyErr {
+    let err = SendError::new_err(format!("Failed to send {} message(s): {}", message_count, reason));
+    with_context(err, &Python::with_gil(|py| [
+        ("reason", reason.into_py(py)),
+        ("message_count", message_count.into_py(py)),
+    ]))
+}
+
+/// An outbound connection to `url` couldn't be established. Attributes: `url`, `reason`.
+pub fn connect_error(url: &str, reason: &str) -> PyErr {
+    let err = ConnectError::new_err(format!("Failed to connect to {}: {}", url, reason));
+    with_context(err, &Python::with_gil(|py| [
+        ("url", url.into_py(py)),
+        ("reason", reason.into_py(py)),
+    ]))
+}
+
+/// Maps an error from the Rust server API to the exception for it; `operation` is what was being attempted, for ServerNotRunning.
+pub fn from_server_error(err: server::Error, operation: &str) -> PyErr {
+    match err {
+        server::Error::NotRunning                       => server_not_running(operation),
+        server::Error::Bind { port, address, reason }   => bind_error(port, &address, &reason),
+        server::Error::Send { reason, message_count }   => send_error(&reason, message_count),
+        server::Error::ReceiverUnavailable              => QuicksocketError::new_err("The client message receiver is unavailable; is a message callback registered?"),
+        server::Error::Connect { url, reason }          => connect_error(&url, &reason),
+        server::Error::InvalidConfig(reason)            => pyo3::exceptions::PyValueError::new_err(format!("Invalid server configuration: {}.", reason)),
+        server::Error::Recording(reason)                => QuicksocketError::new_err(format!("Can't start recording: {}.", reason)),
+        server::Error::Replay(reason)                   => QuicksocketError::new_err(format!("Can't replay recording: {}.", reason)),
+        server::Error::Internal(reason)                 => QuicksocketError::new_err(reason),
+    }
+}
+
+/// Registers the exception classes on the Python module.
+pub fn register(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add("QuicksocketError", py.get_type::<QuicksocketError>())?;
+    m.add("ServerNotRunning", py.get_type::<ServerNotRunning>())?;
+    m.add("BindError",        py.get_type::<BindError>())?;
+    m.add("SendError",        py.get_type::<SendError>())?;
+    m.add("ConnectError",     py.get_type::<ConnectError>())?;
+    // Reserved for TLS support; the server doesn't terminate TLS yet, so nothing raises it.
+    m.add("TlsError",         py.get_type::<TlsError>())?;
+    Ok(())
+}
@@ -0,0 +1,309 @@
+// events.rs
+// =========
+//
+// Python classes for the events the drain APIs return: client messages (with the client they came from), client connections and disconnections, clients' pings and pongs, playback controls, and error events; and for the event log's entries. Each has typed, read-only fields and a timestamp in seconds since the Unix epoch (as from time.time()).
+
+use std::{collections::HashMap, time::{Instant, SystemTime, UNIX_EPOCH}};
+use pyo3::{prelude::*, types::{PyBytes, PyList, PyString}};
+
+use crate::api::MessagePayload;
+use crate::server::{error_events, event_log, events as server_events, inputs, playback};
+
+/// Seconds since the Unix epoch, as from time.time().
+pub(crate) fn unix_timestamp(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// Converts Instants to seconds on Python's time.monotonic() clock, using one reading of both clocks.
+struct MonotonicClock {
+    now: Instant,
+    python_now: f64,
+}
+
+impl MonotonicClock {
+    fn read(py: Python) -> MonotonicClock {
+        let python_now = py.import("time").and_then(|time| time.call_method0("monotonic")).and_then(|now| now.extract()).unwrap_or(0.0);
+        MonotonicClock { now: Instant::now(), python_now }
+    }
+
+    fn timestamp(&self, instant: Instant) -> f64 {
+        self.python_now - self.now.saturating_duration_since(instant).as_secs_f64()
+    }
+}
+
+/// A client message converted for Python, along with its client. Control messages (ping, pong, close) don't convert.
+pub struct ReceivedMessage {
+    pub client_id: String,
+    pub payload: MessagePayload,
+}
+
+impl ReceivedMessage {
+    pub fn from_client_message(msg: server_events::ClientMessage, zero_copy_min_bytes: Option<usize>) -> Option<ReceivedMessage> {
+        let payload = MessagePayload::from_ws_message(msg.message, zero_copy_min_bytes)?;
+        Some(ReceivedMessage { client_id: msg.client_id, payload })
+    }
+}
+
+/// A message received from a client, as returned by drain_client_messages(structured=True).
+#[pyclass]
+pub struct ClientMessage {
+    /// The client (peer address, as in connection events) that sent the message.
+    #[pyo3(get)] client_id: Py<PyString>,
+    /// When the server received the message.
+    #[pyo3(get)] timestamp: f64,
+    /// When the server received the message, in seconds on the time.monotonic() clock: `time.monotonic() - msg.monotonic_timestamp` is how long ago that was, regardless of wall clock adjustments (or the client's clock).
+    #[pyo3(get)] monotonic_timestamp: f64,
+    /// The message: str for text messages; bytes (or MessageBuffer, with zero-copy receive) for binary ones.
+    #[pyo3(get)] data: PyObject,
+    #[pyo3(get)] is_text: bool,
+}
+
+impl ClientMessage {
+    /// Converts a batch of drained messages into a list of ClientMessages in one go, straight from the drain's buffer. Messages from the same client share one client_id string, so a burst from a few clients doesn't create a new string per message.
+    pub fn batch_into_py(py: Python, messages: impl ExactSizeIterator<Item = server_events::ClientMessage>, zero_copy_min_bytes: Option<usize>) -> PyObject {
+        let clock = MonotonicClock::read(py);
+        let mut client_ids: HashMap<String, Py<PyString>> = HashMap::new();
+        PyList::new(py, messages.map(|msg| {
+            let is_text = msg.message.is_text();
+            let client_id = client_ids.entry(msg.client_id).or_insert_with_key(|client_id| PyString::new(py, client_id).into()).clone_ref(py);
+            let converted = ClientMessage {
+                client_id,
+                timestamp: unix_timestamp(msg.timestamp),
+                monotonic_timestamp: clock.timestamp(msg.received_at),
+                data: MessagePayload::data_into_py(py, msg.message, zero_copy_min_bytes),
+                is_text,
+            };
+            Py::new(py, converted).map(|converted| converted.into_py(py)).unwrap_or_else(|err| err.into_py(py))
+        })).into()
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ClientMessage {
+    fn __repr__(&self) -> String {
+        let kind = if self.is_text { "text" } else { "binary" };
+        Python::with_gil(|py| {
+            let len = self.data.as_ref(py).len().unwrap_or(0);
+            format!("<quicksocket.ClientMessage from {}: {} message of length {}>", self.client_id.as_ref(py), kind, len)
+        })
+    }
+}
+
+/// A client connecting, disconnecting, or changing health (or a draining server's last client having disconnected), as returned by drain_connection_events().
+#[pyclass]
+pub struct ConnectionEvent {
+    #[pyo3(get)] client_id: String,
+    #[pyo3(get)] timestamp: f64,
+    /// "connected" (the client completed the websocket handshake) or "disconnected"; "unhealthy" (the client left `unhealthy_after_missed_pongs` keepalive pings unanswered) or "healthy" (it answered one again); "capabilities" (the client declared its capabilities; see get_client_capabilities()); or "drained", with an empty client_id, once the last client of a draining server (see begin_draining()) has disconnected.
+    #[pyo3(get)] kind: &'static str,
+    /// For a disconnection, the code and reason of the close frame the client sent, e.g. 1001 (going away) from a browser tab that was closed, or 1002 (protocol error) from a client that couldn't make sense of what it was sent. None if it sent none: its connection dropped, or the server closed it first.
+    #[pyo3(get)] close_code: Option<u16>,
+    #[pyo3(get)] close_reason: Option<String>,
+}
+
+impl From<server_events::ConnectionEvent> for ConnectionEvent {
+    fn from(event: server_events::ConnectionEvent) -> ConnectionEvent {
+        let (close_code, close_reason) = match event.close {
+            Some(close) => (Some(close.code), Some(close.reason)),
+            None => (None, None),
+        };
+        ConnectionEvent { client_id: event.client_id, timestamp: unix_timestamp(event.timestamp), kind: event.change.as_str(), close_code, close_reason }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ConnectionEvent {
+    fn __repr__(&self) -> String {
+        // (A "drained" event isn't about any one client.)
+        if self.client_id.is_empty() { return format!("<quicksocket.ConnectionEvent: {}>", self.kind); }
+        match self.close_code {
+            Some(close_code) => format!("<quicksocket.ConnectionEvent: {} {} ({})>", self.client_id, self.kind, close_code),
+            None             => format!("<quicksocket.ConnectionEvent: {} {}>", self.client_id, self.kind),
+        }
+    }
+}
+
+/// A ping or pong from a client, as returned by drain_ping_events().
+#[pyclass]
+pub struct PingEvent {
+    #[pyo3(get)] client_id: String,
+    #[pyo3(get)] timestamp: f64,
+    /// "ping" (which the server has answered already) or "pong".
+    #[pyo3(get)] kind: &'static str,
+    #[pyo3(get)] payload: Py<PyBytes>,
+}
+
+impl PingEvent {
+    pub fn new(py: Python, event: server_events::PingEvent) -> PingEvent {
+        PingEvent { client_id: event.client_id, timestamp: unix_timestamp(event.timestamp), kind: event.kind.as_str(), payload: PyBytes::new(py, &event.payload).into() }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for PingEvent {
+    fn __repr__(&self) -> String {
+        Python::with_gil(|py| format!("<quicksocket.PingEvent: {} from {} ({} bytes)>", self.kind, self.client_id, self.payload.as_ref(py).as_bytes().len()))
+    }
+}
+
+/// Where clients' playback is, as returned by get_playback_state() (as of when it was called), and as a PlaybackEvent left it.
+#[pyclass]
+#[derive(Clone)]
+pub struct PlaybackState {
+    #[pyo3(get)] paused: bool,
+    /// In seconds.
+    #[pyo3(get)] position: f64,
+    /// 1.0 for real time.
+    #[pyo3(get)] speed: f64,
+    /// How many controls clients have sent.
+    #[pyo3(get)] changes: u64,
+    /// The client that sent the last control, or None if none has.
+    #[pyo3(get)] client_id: Option<String>,
+}
+
+impl From<playback::PlaybackState> for PlaybackState {
+    fn from(state: playback::PlaybackState) -> PlaybackState {
+        PlaybackState { paused: state.paused, position: state.position, speed: state.speed, changes: state.changes, client_id: state.client_id }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for PlaybackState {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.PlaybackState: {} at {:.3}s, speed {}>", if self.paused { "paused" } else { "playing" }, self.position, self.speed)
+    }
+}
+
+/// A playback control a client sent, as returned by drain_playback_events().
+#[pyclass]
+pub struct PlaybackEvent {
+    #[pyo3(get)] client_id: String,
+    #[pyo3(get)] timestamp: f64,
+    /// "pause", "resume", "seek" or "speed".
+    #[pyo3(get)] control: &'static str,
+    /// A seek's position (in seconds) or a speed; None for pause and resume.
+    #[pyo3(get)] value: Option<f64>,
+    /// The state the control left playback in.
+    #[pyo3(get)] state: PlaybackState,
+}
+
+impl From<playback::PlaybackEvent> for PlaybackEvent {
+    fn from(event: playback::PlaybackEvent) -> PlaybackEvent {
+        PlaybackEvent {
+            client_id: event.client_id,
+            timestamp: unix_timestamp(event.timestamp),
+            control: event.control.as_str(),
+            value: event.control.value(),
+            state: event.state.into(),
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for PlaybackEvent {
+    fn __repr__(&self) -> String {
+        match self.value {
+            Some(value) => format!("<quicksocket.PlaybackEvent: {} {} from {}>", self.control, value, self.client_id),
+            None        => format!("<quicksocket.PlaybackEvent: {} from {}>", self.control, self.client_id),
+        }
+    }
+}
+
+/// Clients' aggregated input over an interval, as returned by drain_input_snapshots().
+#[pyclass]
+pub struct InputSnapshot {
+    #[pyo3(get)] timestamp: f64,
+    /// Each client's latest value of each input it sent over the interval: {client_id: {name: value}}.
+    #[pyo3(get)] inputs: HashMap<String, HashMap<String, String>>,
+    /// How many input messages went into it.
+    #[pyo3(get)] updates: u64,
+}
+
+impl From<inputs::InputSnapshot> for InputSnapshot {
+    fn from(snapshot: inputs::InputSnapshot) -> InputSnapshot {
+        InputSnapshot { timestamp: unix_timestamp(snapshot.timestamp), inputs: snapshot.inputs, updates: snapshot.updates }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for InputSnapshot {
+    fn __repr__(&self) -> String {
+        format!("<quicksocket.InputSnapshot: {} update(s) from {} client(s)>", self.updates, self.inputs.len())
+    }
+}
+
+/// An error recorded by the server or the API, as returned by drain_error_events().
+#[pyclass]
+pub struct ErrorEvent {
+    #[pyo3(get)] timestamp: f64,
+    /// "warning" (a single connection or request had a problem), "error" (the server or an API call did), or "critical" (one of the server's own tasks panicked, or stalled; see the watchdog arguments of start_server()).
+    #[pyo3(get)] severity: &'static str,
+    /// One of "bind", "http", "handshake", "send", "receive", "callback", "proxy", "redis", "kafka", "zmq", "cluster", "otel", "recording", "audit", or "internal".
+    #[pyo3(get)] category: &'static str,
+    #[pyo3(get)] message: String,
+    /// The client the error concerns, or None.
+    #[pyo3(get)] client_id: Option<String>,
+    /// For a panic in one of the server's tasks, where it happened, if the RUST_BACKTRACE environment variable asked for backtraces; otherwise None.
+    #[pyo3(get)] backtrace: Option<String>,
+}
+
+impl From<error_events::ErrorEvent> for ErrorEvent {
+    fn from(event: error_events::ErrorEvent) -> ErrorEvent {
+        ErrorEvent {
+            timestamp: unix_timestamp(event.timestamp),
+            severity: event.severity.as_str(),
+            category: event.category.as_str(),
+            message: event.message,
+            client_id: event.client_id,
+            backtrace: event.backtrace,
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for ErrorEvent {
+    fn __repr__(&self) -> String {
+        match &self.client_id {
+            Some(client_id) => format!("<quicksocket.ErrorEvent: {} ({}, client {}): {}>", self.severity, self.category, client_id, self.message),
+            None            => format!("<quicksocket.ErrorEvent: {} ({}): {}>", self.severity, self.category, self.message),
+        }
+    }
+}
+
+/// An entry in the event log, as returned by get_event_log().
+#[pyclass]
+pub struct EventLogEntry {
+    /// Numbers the entries from 1, in the order they were logged; pass the last one seen to get_event_log() for those after it.
+    #[pyo3(get)] seq: u64,
+    #[pyo3(get)] timestamp: f64,
+    /// "debug", "info", "warning" or "error".
+    #[pyo3(get)] level: &'static str,
+    /// "bind", "accept", "disconnect", "drain" or "shutdown"; or, for an error event, its category (see ErrorEvent).
+    #[pyo3(get)] kind: &'static str,
+    #[pyo3(get)] message: String,
+    /// The client the entry concerns, or None.
+    #[pyo3(get)] client_id: Option<String>,
+}
+
+impl From<event_log::Entry> for EventLogEntry {
+    fn from(entry: event_log::Entry) -> EventLogEntry {
+        EventLogEntry {
+            seq: entry.seq,
+            timestamp: unix_timestamp(entry.timestamp),
+            level: entry.level.as_str(),
+            kind: entry.kind.as_str(),
+            message: entry.message,
+            client_id: entry.client_id,
+        }
+    }
+}
+
+#[pyproto]
+impl pyo3::PyObjectProtocol for EventLogEntry {
+    fn __repr__(&self) -> String {
+        match &self.client_id {
+            Some(client_id) => format!("<quicksocket.EventLogEntry {}: {} ({}, client {}): {}>", self.seq, self.level, self.kind, client_id, self.message),
+            None            => format!("<quicksocket.EventLogEntry {}: {} ({}): {}>", self.seq, self.level, self.kind, self.message),
+        }
+    }
+}
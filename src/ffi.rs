@@ -0,0 +1,280 @@
+// ffi.rs
+// ======
+//
+// C ABI over the Rust server API (server::Server), built with the "capi" feature, so C, C++, C#, Julia, etc. hosts can embed the server the way Python does. The declarations are in include/quicksocket.h, generated with `cbindgen --config cbindgen.toml --output include/quicksocket.h`.
+//
+// Conventions: functions that can fail return a QsStatus, with a description of the failure available from qs_last_error() on the same thread. Timeouts are in milliseconds, negative meaning "no timeout". Pointers returned by quicksocket are owned by it and freed with the matching qs_*_free() function; pointers passed in are only borrowed for the duration of the call. Panics never cross the boundary: they're reported as QS_INTERNAL_ERROR.
+
+use std::{cell::RefCell, ffi::{CStr, CString}, os::raw::c_char, panic::{self, AssertUnwindSafe}, ptr, time::Duration};
+
+use crate::server::{self, Message, Server, ServerConfig, buffer_pool::OUTBOUND, events::ClientMessage};
+
+/// The result of a fallible call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QsStatus {
+    QsOk = 0,
+    /// The server isn't running (it stopped, or never started).
+    QsNotRunning = 1,
+    /// The server couldn't bind its port.
+    QsBindError = 2,
+    /// Messages couldn't be handed to the server, or to the client.
+    QsSendError = 3,
+    /// The client message receiver is in use elsewhere.
+    QsReceiverUnavailable = 4,
+    /// A null pointer, invalid UTF-8, or an out-of-range argument.
+    QsInvalidArgument = 5,
+    /// The wait timed out.
+    QsTimeout = 6,
+    QsInternalError = 7,
+}
+
+/// A server, as returned by qs_server_start(). Free with qs_server_free() (which doesn't stop it; call qs_server_shutdown() first).
+pub struct QsServer {
+    server: Server,
+}
+
+/// Messages drained by qs_server_drain_messages(). Free with qs_batch_free().
+pub struct QsMessageBatch {
+    messages: Vec<BatchMessage>,
+}
+
+struct BatchMessage {
+    client_id: CString,
+    data: Vec<u8>,
+    is_text: bool,
+}
+
+/// One message in a QsMessageBatch, as filled in by qs_batch_get(). Its pointers borrow from the batch, so they're valid until the batch is freed.
+#[repr(C)]
+pub struct QsMessage {
+    /// The client (peer address) that sent the message, NUL-terminated.
+    pub client_id: *const c_char,
+    /// The message: UTF-8 text (not NUL-terminated) if is_text, otherwise binary data.
+    pub data: *const u8,
+    pub len: usize,
+    pub is_text: bool,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    // Interior NULs can't be represented; they'd only come from client ids or OS messages, which don't have them.
+    let msg = CString::new(msg.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(msg));
+}
+
+fn fail(status: QsStatus, msg: String) -> QsStatus {
+    set_last_error(msg);
+    status
+}
+
+fn from_server_error(err: server::Error) -> QsStatus {
+    let status = match &err {
+        server::Error::NotRunning             => QsStatus::QsNotRunning,
+        server::Error::Bind { .. }            => QsStatus::QsBindError,
+        server::Error::Send { .. }            => QsStatus::QsSendError,
+        server::Error::ReceiverUnavailable    => QsStatus::QsReceiverUnavailable,
+        // (None of the C API's calls connect out.)
+        server::Error::Connect { .. }         => QsStatus::QsInternalError,
+        server::Error::InvalidConfig(_)       => QsStatus::QsInvalidArgument,
+        // (Nor record.)
+        server::Error::Recording(_)           => QsStatus::QsInternalError,
+        server::Error::Replay(_)              => QsStatus::QsInternalError,
+        server::Error::Internal(_)            => QsStatus::QsInternalError,
+    };
+    fail(status, err.to_string())
+}
+
+/// Runs `f`, turning a panic into QS_INTERNAL_ERROR (or `on_panic`).
+fn guard<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(res) => res,
+        Err(_) => {
+            set_last_error("quicksocket panicked; see its log output for details".to_string());
+            on_panic
+        }
+    }
+}
+
+fn timeout(timeout_ms: i64) -> Option<Duration> {
+    if timeout_ms < 0 { None } else { Some(Duration::from_millis(timeout_ms as u64)) }
+}
+
+/// Borrows `len` bytes at `data`, which may be null if `len` is 0.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 { return Some(&[]); }
+    if data.is_null() { return None; }
+    Some(std::slice::from_raw_parts(data, len))
+}
+
+unsafe fn message(data: *const u8, len: usize, is_text: bool) -> Result<Message, QsStatus> {
+    let data = bytes(data, len).ok_or_else(|| fail(QsStatus::QsInvalidArgument, "message data is null".to_string()))?;
+    if !is_text { return Ok(OUTBOUND.binary(data)); }
+    match std::str::from_utf8(data) {
+        Ok(text) => Ok(OUTBOUND.text(text)),
+        Err(err) => Err(fail(QsStatus::QsInvalidArgument, format!("text messages must be UTF-8: {}", err))),
+    }
+}
+
+/// Returns a description of the last failure on the calling thread, or null if there hasn't been one. The string stays valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn qs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| last_error.borrow().as_ref().map(|msg| msg.as_ptr()).unwrap_or(ptr::null()))
+}
+
+/// Starts a server on `port` (on its own thread), returning it, or null on failure. Returns before the port is bound: call qs_server_wait_until_started() to wait for that (and to find out whether binding failed).
+#[no_mangle]
+pub extern "C" fn qs_server_start(port: u32) -> *mut QsServer {
+    guard(ptr::null_mut(), || {
+        match Server::start(port, ServerConfig::default()) {
+            Ok(server) => Box::into_raw(Box::new(QsServer { server })),
+            Err(err) => { from_server_error(err); ptr::null_mut() }
+        }
+    })
+}
+
+/// Blocks until the server has bound its port (QS_OK), binding failed (QS_BIND_ERROR), or `timeout_ms` elapsed (QS_TIMEOUT).
+///
+/// # Safety
+/// `server` must be a live pointer from qs_server_start().
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_wait_until_started(server: *const QsServer, timeout_ms: i64) -> QsStatus {
+    let server = match server.as_ref() { Some(server) => server, None => { return fail(QsStatus::QsInvalidArgument, "server is null".to_string()); } };
+    guard(QsStatus::QsInternalError, || match server.server.wait_until_started(timeout(timeout_ms)) {
+        Ok(true) => QsStatus::QsOk,
+        Ok(false) => fail(QsStatus::QsTimeout, format!("the server didn't start within {} ms", timeout_ms)),
+        Err(err) => from_server_error(err),
+    })
+}
+
+/// Whether the server thread is alive: starting, running, or shutting down. False for a null server.
+///
+/// # Safety
+/// `server` must be null or a live pointer from qs_server_start().
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_is_running(server: *const QsServer) -> bool {
+    server.as_ref().map(|server| server.server.is_running()).unwrap_or(false)
+}
+
+/// Sends one message to every connected client: `len` bytes of UTF-8 text if `is_text`, otherwise binary data.
+///
+/// # Safety
+/// `server` must be a live pointer from qs_server_start(), and `data` must point to `len` readable bytes (or be null if `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_send(server: *const QsServer, data: *const u8, len: usize, is_text: bool) -> QsStatus {
+    let server = match server.as_ref() { Some(server) => server, None => { return fail(QsStatus::QsInvalidArgument, "server is null".to_string()); } };
+    let msg = match message(data, len, is_text) { Ok(msg) => msg, Err(status) => { return status; } };
+    guard(QsStatus::QsInternalError, || server.server.send(vec![msg]).map_or_else(from_server_error, |_| QsStatus::QsOk))
+}
+
+/// Sends one message to the client `client_id` (NUL-terminated, as in QsMessage.client_id). With a negative `confirm_timeout_ms`, queues it without waiting; otherwise blocks until it's been written and flushed to the client's socket, for at most `confirm_timeout_ms`.
+///
+/// # Safety
+/// As for qs_server_send(), and `client_id` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_send_to_client(server: *const QsServer, client_id: *const c_char, data: *const u8, len: usize, is_text: bool, confirm_timeout_ms: i64) -> QsStatus {
+    let server = match server.as_ref() { Some(server) => server, None => { return fail(QsStatus::QsInvalidArgument, "server is null".to_string()); } };
+    if client_id.is_null() {
+        return fail(QsStatus::QsInvalidArgument, "client_id is null".to_string());
+    }
+    let client_id = match CStr::from_ptr(client_id).to_str() { Ok(client_id) => client_id, Err(_) => { return fail(QsStatus::QsInvalidArgument, "client_id isn't UTF-8".to_string()); } };
+    let msg = match message(data, len, is_text) { Ok(msg) => msg, Err(status) => { return status; } };
+    guard(QsStatus::QsInternalError, || {
+        let res = if confirm_timeout_ms < 0 {
+            server.server.send_to_client(client_id, vec![msg])
+        } else {
+            server.server.send_and_confirm(client_id, vec![msg], timeout(confirm_timeout_ms))
+        };
+        res.map_or_else(from_server_error, |_| QsStatus::QsOk)
+    })
+}
+
+/// Takes up to `max_messages` pending client messages, blocking for at most `timeout_ms` if none are pending, and stores them in `*out` (an empty batch on timeout). Free the batch with qs_batch_free().
+///
+/// # Safety
+/// `server` must be a live pointer from qs_server_start(), and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_drain_messages(server: *const QsServer, timeout_ms: i64, max_messages: usize, out: *mut *mut QsMessageBatch) -> QsStatus {
+    let server = match server.as_ref() { Some(server) => server, None => { return fail(QsStatus::QsInvalidArgument, "server is null".to_string()); } };
+    if out.is_null() {
+        return fail(QsStatus::QsInvalidArgument, "out is null".to_string());
+    }
+    guard(QsStatus::QsInternalError, || {
+        let drained = server.server.drain_messages(timeout(timeout_ms), max_messages);
+        if let Err(err) = drained {
+            return from_server_error(err);
+        }
+        let messages = drained.unwrap().into_iter().filter_map(BatchMessage::from_client_message).collect();
+        *out = Box::into_raw(Box::new(QsMessageBatch { messages }));
+        QsStatus::QsOk
+    })
+}
+
+impl BatchMessage {
+    fn from_client_message(msg: ClientMessage) -> Option<BatchMessage> {
+        let (data, is_text) = match msg.message {
+            Message::Text(text) => (text.into_bytes(), true),
+            Message::Binary(bytes) => (bytes, false),
+            _ => { return None; }
+        };
+        Some(BatchMessage { client_id: CString::new(msg.client_id).unwrap_or_default(), data, is_text })
+    }
+}
+
+/// The number of messages in a batch (0 for a null batch).
+///
+/// # Safety
+/// `batch` must be null or a live pointer from qs_server_drain_messages().
+#[no_mangle]
+pub unsafe extern "C" fn qs_batch_len(batch: *const QsMessageBatch) -> usize {
+    batch.as_ref().map(|batch| batch.messages.len()).unwrap_or(0)
+}
+
+/// Fills in `*out` with the message at `index`, which borrows from the batch.
+///
+/// # Safety
+/// `batch` must be a live pointer from qs_server_drain_messages(), and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn qs_batch_get(batch: *const QsMessageBatch, index: usize, out: *mut QsMessage) -> QsStatus {
+    let batch = match batch.as_ref() { Some(batch) => batch, None => { return fail(QsStatus::QsInvalidArgument, "batch is null".to_string()); } };
+    if out.is_null() {
+        return fail(QsStatus::QsInvalidArgument, "out is null".to_string());
+    }
+    let msg = match batch.messages.get(index) {
+        Some(msg) => msg,
+        None => { return fail(QsStatus::QsInvalidArgument, format!("index {} is out of range for a batch of {}", index, batch.messages.len())); }
+    };
+    *out = QsMessage { client_id: msg.client_id.as_ptr(), data: msg.data.as_ptr(), len: msg.data.len(), is_text: msg.is_text };
+    QsStatus::QsOk
+}
+
+/// Frees a batch (and the messages borrowed from it). Null does nothing.
+///
+/// # Safety
+/// `batch` must be null or a pointer from qs_server_drain_messages() that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn qs_batch_free(batch: *mut QsMessageBatch) {
+    if !batch.is_null() { drop(Box::from_raw(batch)); }
+}
+
+/// Asks the server to shut down, sending clients close frames. If `wait`, blocks until the server thread has exited.
+///
+/// # Safety
+/// `server` must be a live pointer from qs_server_start().
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_shutdown(server: *const QsServer, wait: bool) -> QsStatus {
+    let server = match server.as_ref() { Some(server) => server, None => { return fail(QsStatus::QsInvalidArgument, "server is null".to_string()); } };
+    guard(QsStatus::QsInternalError, || server.server.shutdown(wait).map_or_else(from_server_error, |_| QsStatus::QsOk))
+}
+
+/// Frees a server handle. Doesn't stop the server; shut it down first. Null does nothing.
+///
+/// # Safety
+/// `server` must be null or a pointer from qs_server_start() that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn qs_server_free(server: *mut QsServer) {
+    if !server.is_null() { drop(Box::from_raw(server)); }
+}
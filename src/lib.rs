@@ -1,12 +1,35 @@
 // quicksocket
 // =============
 //
-// A simple WebSocket server that is compiled via pyo3 to a native Python module. This module targets Python consumption primarily, but Rust consumption is theoretically also valid, and used for testing purposes.
+// A simple WebSocket server that is compiled via pyo3 to a native Python module. This module targets Python consumption primarily, but the server engine (in `server`) is plain Rust, with its own API (server::Server); the Python bindings are a thin layer over it, behind the default "python" feature. Build with --no-default-features to embed the server in a Rust program without Python, with the "capi" feature for a C ABI (ffi.rs), and with the "cli" feature for a standalone binary (cli.rs).
 
 #[macro_use]
 extern crate lazy_static;
 
-mod server;
+#[macro_use]
+pub mod server;
+pub mod signals;
+
+#[cfg(feature = "capi")]
+pub mod ffi;
+
+#[cfg(feature = "cli")]
+pub mod cli;
+
+#[cfg(feature = "python")]
 mod api;
+#[cfg(feature = "python")]
+mod buffer;
+#[cfg(feature = "python")]
+mod errors;
+#[cfg(feature = "python")]
+mod events;
+#[cfg(feature = "python")]
+mod log_bridge;
+#[cfg(feature = "python")]
+mod message_callback;
+#[cfg(feature = "python")]
+mod objects;
 
+#[cfg(feature = "python")]
 pub use api::*;
@@ -0,0 +1,87 @@
+// log_bridge.rs
+// =============
+//
+// Forwards the server's log output to Python's logging module. While enabled, a dedicated log thread receives log records from the server (see server::logging) and passes them to a logging.Logger, acquiring the GIL once per batch, so the server's own threads never wait on the GIL to log.
+
+use std::{sync::RwLock, thread::{self, JoinHandle}};
+use pyo3::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::server::{consumer_state as cs, logging::{self, Level, LogRecord}};
+
+/// How many log records can be waiting for the log thread before new ones are dropped.
+const LOG_QUEUE_LEN: usize = 1024;
+
+lazy_static! {
+    static ref LOG_THREAD: RwLock<Option<JoinHandle<()>>> = RwLock::new(None);
+}
+
+/// The Python logging level for a server log level.
+fn python_level(level: Level) -> u32 {
+    match level {
+        Level::Debug   => 10,
+        Level::Info    => 20,
+        Level::Warning => 30,
+        Level::Error   => 40,
+    }
+}
+
+/// The lowest server log level that passes a Python logging level.
+pub fn level_from_python(level: u32) -> Level {
+    match level {
+        0..=10  => Level::Debug,
+        11..=20 => Level::Info,
+        21..=30 => Level::Warning,
+        _       => Level::Error,
+    }
+}
+
+/// Starts forwarding log records at or above `min_level` to `logger` (a logging.Logger), replacing any previous forwarding.
+///
+/// Must be called with the GIL released (stopping a previous log thread may need to wait for it to finish a batch).
+pub fn enable(logger: PyObject, min_level: Level) -> Result<(), String> {
+    disable();
+
+    let (tx, rx) = mpsc::channel::<LogRecord>(LOG_QUEUE_LEN);
+    let thread = thread::Builder::new()
+        .name("quicksocket-log".to_string())
+        .spawn(move || run(logger, rx));
+    if let Err(err) = thread {
+        return Err(format!("Failed to spawn the log thread: {:?}", err));
+    }
+    let thread = thread.unwrap();
+
+    logging::set_sink(Some((tx, min_level)));
+    cs::set_value(&LOG_THREAD, thread)
+        .map_err(|_| "Failed to store the log thread.".to_string())
+}
+
+/// Stops forwarding, sending log output back to stdout. Records already queued are still delivered to the logger before the log thread is joined.
+///
+/// Must be called with the GIL released.
+pub fn disable() {
+    // Dropping the sink's sender ends the log thread once it has emptied the queue.
+    logging::set_sink(None);
+    if let Some(thread) = cs::take_value(&LOG_THREAD) {
+        if thread.join().is_err() {
+            cs::weakly_record_error("Log thread panicked.".to_string());
+        }
+    }
+}
+
+/// Log thread loop. Waits (without the GIL) for log records, then acquires the GIL once per batch of immediately-available records to log them.
+fn run(logger: PyObject, mut rx: mpsc::Receiver<LogRecord>) {
+    while let Some(first_record) = rx.blocking_recv() {
+        let mut batch = vec![first_record];
+        while let Ok(record) = rx.try_recv() { batch.push(record); }
+
+        Python::with_gil(|py| {
+            for record in batch {
+                if let Err(err) = logger.call_method1(py, "log", (python_level(record.level), record.message)) {
+                    // Most likely a misbehaving handler; there's nowhere better to report it.
+                    err.print(py);
+                }
+            }
+        });
+    }
+}
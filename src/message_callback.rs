@@ -0,0 +1,161 @@
+// message_callback.rs
+// ===================
+//
+// Push-based delivery of client messages to a Python callable, as an alternative to draining. While a callback is registered with a server, a dedicated callback thread owns that server's client message receiver (so drain_client_messages() returns nothing) and calls the callback for each message as soon as it arrives, holding the GIL only for the duration of the calls.
+
+use std::{collections::HashMap, sync::{Arc, RwLock}, thread::{self, JoinHandle}};
+use pyo3::prelude::*;
+use tokio::sync::watch;
+
+use crate::api::MessagePayload;
+use crate::server::{consumer_state::{self as cs, ServerState, SharedReceiver}, events::ClientMessage, error_events::{self, Category, Severity}};
+
+/// The running callback thread, and how to stop it.
+struct CallbackThread {
+    stop_tx: watch::Sender<bool>,
+    thread: JoinHandle<()>,
+}
+
+lazy_static! {
+    /// The callback thread of each server that has a callback registered, by server id.
+    static ref CALLBACK_THREADS: RwLock<HashMap<u64, CallbackThread>> = RwLock::new(HashMap::new());
+}
+
+/// Replaces the message callback registered with `server` (None unregisters it). Any previous callback thread is stopped and joined first, which hands the client message receiver back to the server's consumer state.
+///
+/// Must be called with the GIL released (the callback thread may need the GIL to finish its current call before it can stop).
+pub fn set(server: &Arc<ServerState>, callback: Option<PyObject>) -> Result<(), String> {
+    stop_for(server)?;
+
+    let callback = match callback {
+        Some(callback) => callback,
+        None => { return Ok(()); }
+    };
+
+    let cli_msg_rx = server.cli_msg_rx.claim();
+    if cli_msg_rx.is_none() {
+        return Err("Can't set a message callback: the server isn't running (or another consumer holds the message receiver).".to_string());
+    }
+    let cli_msg_rx = cli_msg_rx.unwrap();
+
+    let (stop_tx, stop_rx) = watch::channel::<bool>(false);
+    let thread_server = server.clone();
+    let thread = thread::Builder::new()
+        .name("quicksocket-callback".to_string())
+        .spawn(move || run(thread_server, callback, cli_msg_rx, stop_rx));
+    if let Err(err) = thread {
+        // The server's messages can be drained again.
+        server.cli_msg_rx.release();
+        return Err(format!("Failed to spawn the message callback thread: {:?}", err));
+    }
+    let thread = thread.unwrap();
+
+    let new_thread = CallbackThread { stop_tx, thread };
+    match CALLBACK_THREADS.write() {
+        Ok(mut callback_threads) => { callback_threads.insert(server.id, new_thread); }
+        Err(_) => {
+            stop(new_thread);
+            return Err("Failed to store the message callback thread.".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Stops and joins the callback thread of `server`, if it has one. Like set(), must be called with the GIL released, and fails if called from that callback thread.
+pub fn stop_for(server: &ServerState) -> Result<(), String> {
+    // (The lock isn't held while joining, so a callback registering callbacks with *other* servers can't deadlock against us.)
+    let previous = match CALLBACK_THREADS.write() {
+        Ok(mut callback_threads) => callback_threads.remove(&server.id),
+        Err(_) => { return Err("Failed to get access to the message callback threads.".to_string()); }
+    };
+    if let Some(previous) = previous {
+        if previous.thread.thread().id() == thread::current().id() {
+            // Put it back; we can't join ourselves.
+            if let Ok(mut callback_threads) = CALLBACK_THREADS.write() { callback_threads.insert(server.id, previous); }
+            return Err("set_on_message() can't be called from inside the message callback.".to_string());
+        }
+        stop(previous);
+    }
+    Ok(())
+}
+
+/// Stops and joins every server's callback thread, e.g. at interpreter exit. Like set(), must be called with the GIL released.
+pub fn stop_all() {
+    let callback_threads: Vec<CallbackThread> = match CALLBACK_THREADS.write() {
+        Ok(mut callback_threads) => callback_threads.drain().map(|(_, callback_thread)| callback_thread).collect(),
+        Err(_) => { return; }
+    };
+    for callback_thread in callback_threads {
+        if callback_thread.thread.thread().id() == thread::current().id() { continue; }
+        stop(callback_thread);
+    }
+}
+
+/// Stops a callback thread and waits for it to exit.
+fn stop(callback_thread: CallbackThread) {
+    let _ = callback_thread.stop_tx.send(true);
+    if callback_thread.thread.join().is_err() {
+        cs::weakly_record_error_in(Category::Callback, "Message callback thread panicked.".to_string());
+    }
+}
+
+/// Callback thread loop. Waits (without the GIL) for client messages, then acquires the GIL once per batch of immediately-available messages to invoke the callback for each of them.
+fn run(server: Arc<ServerState>, callback: PyObject, shared_rx: SharedReceiver<ClientMessage>, mut stop_rx: watch::Receiver<bool>) {
+    // The channels are runtime-agnostic, so a bare current-thread runtime (no IO/time drivers) is enough to wait on them.
+    let waiter = tokio::runtime::Builder::new_current_thread().build();
+    if let Err(err) = waiter {
+        cs::weakly_record_error_in(Category::Callback, format!("Message callback thread failed to create its runtime: {:?}", err));
+        return;
+    }
+    let waiter = waiter.unwrap();
+
+    // A drain that got hold of the receiver just before the callback took it finishes within its own timeout; after that, the callback is its only user.
+    let cli_msg_rx = waiter.block_on(async {
+        tokio::select! {
+            rx = shared_rx.lock() => { Some(rx) }
+            _ = stop_rx.changed() => { None }
+        }
+    });
+    let mut cli_msg_rx = match cli_msg_rx {
+        Some(cli_msg_rx) => cli_msg_rx,
+        None => {
+            server.cli_msg_rx.release();
+            return;
+        }
+    };
+
+    let stopped = loop {
+        let first_msg = waiter.block_on(async {
+            tokio::select! {
+                msg = cli_msg_rx.recv() => { msg }
+                _ = stop_rx.changed() => { None }
+            }
+        });
+        let first_msg = match first_msg {
+            Some(first_msg) => first_msg,
+            None => { break *stop_rx.borrow(); }
+        };
+
+        let mut batch = vec![first_msg];
+        while let Ok(msg) = cli_msg_rx.try_recv() { batch.push(msg); }
+        let zero_copy_min_bytes = server.config.zero_copy_min_bytes;
+        let payloads: Vec<MessagePayload> = batch.into_iter().filter_map(|msg| MessagePayload::from_ws_message(msg.message, zero_copy_min_bytes)).collect();
+        if payloads.is_empty() { continue; }
+
+        Python::with_gil(|py| {
+            for payload in payloads {
+                if let Err(err) = callback.call1(py, (payload,)) {
+                    // Don't let an exception in the callback kill message delivery; report it like an unraisable exception.
+                    error_events::record(Severity::Warning, Category::Callback, format!("Message callback raised an exception: {}", err), None);
+                    err.print(py);
+                }
+            }
+        });
+    };
+
+    // If we were stopped (rather than the server going away), hand the receiver back so draining works again.
+    drop(cli_msg_rx);
+    if stopped {
+        server.cli_msg_rx.release();
+    }
+}
@@ -0,0 +1,70 @@
+// objects.rs
+// ==========
+//
+// Opt-in sending and receiving of arbitrary Python objects (see send_python_objects() and drain_python_objects()), serialized with pickle by default or with any other serializer callable (e.g. json.dumps/json.loads).
+//
+// Unpickling data runs whatever code the data says to, so this is only for clients that are as trusted as the server's own code (e.g. another process of the same application). Never unpickle messages from browsers or anything else that can reach the port. The size limit bounds memory use, not what unpickling can do.
+
+use pyo3::prelude::*;
+
+use crate::api::MessagePayload;
+use crate::events::ReceivedMessage;
+use crate::server::{consumer_state::ServerState, error_events::{Category, Severity}};
+
+/// The default limit on a serialized object's size, in either direction: 16 MiB.
+pub const DEFAULT_MAX_OBJECT_BYTES: usize = 16 * 1024 * 1024;
+
+/// Serializes each object with `serializer` (pickle.dumps by default), which must return bytes (or another buffer) for a binary message, or str for a text one. Fails on the first object that can't be serialized, or that serializes to more than `max_bytes`.
+pub fn serialize(py: Python, objects: Vec<&PyAny>, serializer: Option<PyObject>, max_bytes: usize) -> Result<Vec<PyObject>, String> {
+    let serializer = match &serializer {
+        Some(serializer) => serializer.as_ref(py),
+        None => default_codec(py, "dumps")?,
+    };
+
+    let mut serialized = vec![];
+    for (i, object) in objects.into_iter().enumerate() {
+        let data = serializer.call1((object,));
+        if let Err(err) = data {
+            return Err(format!("object {} couldn't be serialized: {}", i, err));
+        }
+        let data = data.unwrap();
+        let len = data.len().unwrap_or(0);
+        if len > max_bytes {
+            return Err(format!("object {} serialized to {} bytes, over the limit of {} bytes", i, len, max_bytes));
+        }
+        serialized.push(data.into());
+    }
+    Ok(serialized)
+}
+
+/// Deserializes each message with `deserializer` (pickle.loads by default). Messages over `max_bytes` are skipped without being deserialized, and messages that fail to deserialize are skipped too; both are recorded as error events, so one bad message doesn't cost the rest of the batch.
+pub fn deserialize(py: Python, server: &ServerState, messages: Vec<ReceivedMessage>, deserializer: Option<PyObject>, max_bytes: usize) -> PyResult<Vec<PyObject>> {
+    let deserializer = match &deserializer {
+        Some(deserializer) => deserializer.as_ref(py),
+        None => default_codec(py, "loads").map_err(crate::errors::QuicksocketError::new_err)?,
+    };
+
+    let mut objects = vec![];
+    for msg in messages {
+        let len = match &msg.payload {
+            MessagePayload::Text(text) => text.len(),
+            MessagePayload::Binary(bytes) | MessagePayload::ZeroCopyBinary(bytes) => bytes.len(),
+        };
+        if len > max_bytes {
+            server.stats.record_error(Severity::Warning, Category::Receive, format!("Skipped a {} byte message while draining Python objects: it's over the limit of {} bytes.", len, max_bytes), Some(msg.client_id));
+            continue;
+        }
+        match deserializer.call1((msg.payload.into_py(py),)) {
+            Ok(object) => { objects.push(object.into()); }
+            Err(err) => {
+                server.stats.record_error(Severity::Warning, Category::Receive, format!("Skipped a message that couldn't be deserialized as a Python object: {}", err), Some(msg.client_id));
+            }
+        }
+    }
+    Ok(objects)
+}
+
+/// pickle.dumps or pickle.loads.
+fn default_codec<'py>(py: Python<'py>, name: &str) -> Result<&'py PyAny, String> {
+    py.import("pickle").and_then(|pickle| pickle.getattr(name)).map_err(|err| format!("couldn't load pickle.{}: {}", name, err))
+}
@@ -0,0 +1,219 @@
+// audit_log.rs
+//
+// The connection audit log (ServerConfig::audit_log): a line of JSON for every websocket connection the server accepts or rejects, and another for each accepted one when it closes, appended to a file the server rotates by size, for deployments that have to keep a record of who connected when. E.g.
+//
+//   {"t":1700000000.123456,"event":"accepted","peer":"10.0.0.7:50000","ip":"10.0.0.7","path":"/feed","principal":"alice","reason":null}
+//   {"t":1700000042.500000,"event":"closed","peer":"10.0.0.7:50000","ip":"10.0.0.7","path":"/feed","principal":"alice","reason":"the client closed the connection (1000 \"bye\")"}
+//   {"t":1700000050.250000,"event":"rejected","peer":"10.0.0.9:41000","ip":"10.0.0.9","path":"/feed","principal":null,"reason":"malformed websocket upgrade (400 Bad Request)"}
+//
+// t is seconds since the Unix epoch (to the microsecond), as in session recordings; ip is null for connections that didn't come over the network (loopback and service transports). quicksocket doesn't authenticate clients itself, so the principal is whatever the request's AuditLog::principal_header says (e.g. X-Forwarded-User, as set by an authenticating proxy in front of the server), or null. A closed entry repeats its accepted entry's path and principal, and says why it closed: the client's close frame, the server giving up on it (unanswered pings, an idle timeout), the server shutting down, or the connection failing.
+//
+// Plain HTTP requests, inspector feeds, proxied connections and other cluster nodes' links aren't clients, and aren't logged, unless they're rejected (e.g. for a malformed request head).
+//
+// The tasks never wait on the file: they hand their entries to a writer thread, which flushes whenever it's caught up. Unlike a session recording's records, entries are never dropped, however far behind the disk falls (there are only a couple per connection). When an entry would take the file past max_bytes, it's rotated first: path becomes path.1, path.1 becomes path.2, and so on, keeping max_files of them, the oldest deleted. A failed write is recorded as an "audit" error event, and the writer carries on with the next entry.
+
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, BufWriter, Write}, net::SocketAddr, path::PathBuf, sync::{Mutex, PoisonError, mpsc}, thread::{self, JoinHandle}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::{error_events::{self, Category, Severity}, http::RequestHead, inspector::json_string};
+
+/// Where connections are audited, and how the file's rotated (ServerConfig::audit_log).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditLog {
+  pub path: PathBuf,
+  /// How big the file may grow, in bytes, before it's rotated.
+  pub max_bytes: u64,
+  /// How many rotated files (path.1, path.2, ...) are kept besides the current one.
+  pub max_files: u32,
+  /// The request header naming the client's authenticated principal, e.g. X-Forwarded-User. Only trustworthy if everything reaching the server comes through a proxy that sets it.
+  pub principal_header: Option<String>,
+}
+
+impl AuditLog {
+  /// An audit log at `path`, rotated at 10 MiB, keeping 5 rotated files, without principals.
+  pub fn new(path: impl Into<PathBuf>) -> AuditLog {
+    AuditLog { path: path.into(), max_bytes: 10 * 1024 * 1024, max_files: 5, principal_header: None }
+  }
+
+  /// Checks the sizes, and that the file can be opened for appending (creating it if it isn't there).
+  pub fn validate(&self) -> Result<(), String> {
+    if self.max_bytes == 0 {
+      return Err("the audit log's maximum size must be more than 0".to_string());
+    }
+    if self.principal_header.as_deref() == Some("") {
+      return Err("the audit log's principal header can't be empty".to_string());
+    }
+    self.open().map(|_| ()).map_err(|err| format!("can't open the audit log {}: {}", self.path.display(), err))
+  }
+
+  fn open(&self) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(&self.path)
+  }
+
+  /// The nth rotated file's path.
+  fn rotated(&self, n: u32) -> PathBuf {
+    let mut path = self.path.clone().into_os_string();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+  }
+
+  /// Moves the file aside, and the rotated files along, deleting the oldest.
+  fn rotate(&self) -> io::Result<()> {
+    let removed = match self.max_files {
+      0 => fs::remove_file(&self.path),
+      max_files => {
+        ignore_missing(fs::remove_file(self.rotated(max_files)))?;
+        for n in (1..max_files).rev() {
+          ignore_missing(fs::rename(self.rotated(n), self.rotated(n + 1)))?;
+        }
+        fs::rename(&self.path, self.rotated(1))
+      }
+    };
+    ignore_missing(removed)
+  }
+}
+
+fn ignore_missing(res: io::Result<()>) -> io::Result<()> {
+  match res {
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+    res => res,
+  }
+}
+
+/// One line of the log.
+struct Entry {
+  timestamp: SystemTime,
+  event: &'static str,
+  peer: String,
+  path: Option<String>,
+  principal: Option<String>,
+  reason: Option<String>,
+}
+
+impl Entry {
+  fn to_json(&self) -> String {
+    let t = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let ip = self.peer.parse::<SocketAddr>().ok().map(|addr| addr.ip().to_string());
+    let or_null = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+    format!(
+      "{{\"t\":{}.{:06},\"event\":\"{}\",\"peer\":{},\"ip\":{},\"path\":{},\"principal\":{},\"reason\":{}}}\n",
+      t.as_secs(), t.subsec_micros(), self.event, json_string(&self.peer), or_null(ip.as_deref()), or_null(self.path.as_deref()), or_null(self.principal.as_deref()), or_null(self.reason.as_deref())
+    )
+  }
+}
+
+/// A connected client's path and principal, for its closed entry.
+type ClientOrigin = (Option<String>, Option<String>);
+
+/// A running server's audit log: what its tasks hand their entries to.
+pub(crate) struct Auditor {
+  tx: Mutex<Option<mpsc::Sender<Entry>>>,
+  writer: Mutex<Option<JoinHandle<()>>>,
+  principal_header: Option<String>,
+  /// The connected clients' paths and principals, by client id, for their closed entries.
+  clients: Mutex<HashMap<String, ClientOrigin>>,
+}
+
+impl Auditor {
+  /// Opens the log and launches its writer thread.
+  pub fn start(config: &AuditLog) -> Result<Auditor, String> {
+    let file = config.open().map_err(|err| format!("can't open the audit log {}: {}", config.path.display(), err))?;
+    let (tx, rx) = mpsc::channel::<Entry>();
+    let writer_config = config.clone();
+    let writer = thread::Builder::new()
+      .name("quicksocket-audit".to_string())
+      .spawn(move || write_entries(writer_config, file, rx))
+      .map_err(|err| format!("failed to spawn the audit log's writer thread: {}", err))?;
+    Ok(Auditor { tx: Mutex::new(Some(tx)), writer: Mutex::new(Some(writer)), principal_header: config.principal_header.clone(), clients: Mutex::default() })
+  }
+
+  /// The principal a request names, if the log has a principal header and the request has it.
+  pub fn principal(&self, head: &RequestHead) -> Option<String> {
+    self.principal_header.as_deref().and_then(|name| head.header(name)).map(str::to_string)
+  }
+
+  /// Logs a client's connection being accepted, remembering its path and principal for when it closes.
+  pub fn accepted(&self, client_id: &str, path: Option<&str>, principal: Option<String>) {
+    self.log("accepted", client_id, path.map(str::to_string), principal.clone(), None);
+    self.clients.lock().unwrap_or_else(PoisonError::into_inner).insert(client_id.to_string(), (path.map(str::to_string), principal));
+  }
+
+  /// Logs a connection being turned away, and why.
+  pub fn rejected(&self, client_id: &str, path: Option<&str>, principal: Option<String>, reason: String) {
+    self.log("rejected", client_id, path.map(str::to_string), principal, Some(reason));
+  }
+
+  /// Logs an accepted client's connection closing, and why.
+  pub fn closed(&self, client_id: &str, reason: String) {
+    let (path, principal) = self.clients.lock().unwrap_or_else(PoisonError::into_inner).remove(client_id).unwrap_or_default();
+    self.log("closed", client_id, path, principal, Some(reason));
+  }
+
+  fn log(&self, event: &'static str, client_id: &str, path: Option<String>, principal: Option<String>, reason: Option<String>) {
+    let entry = Entry { timestamp: SystemTime::now(), event, peer: client_id.to_string(), path, principal, reason };
+    if let Some(tx) = &*self.tx.lock().unwrap_or_else(PoisonError::into_inner) {
+      let _ = tx.send(entry);
+    }
+  }
+
+  /// Waits for the writer thread to write and flush everything logged so far, and stops it. Nothing's logged after this.
+  pub fn close(&self) {
+    self.tx.lock().unwrap_or_else(PoisonError::into_inner).take();
+    if let Some(writer) = self.writer.lock().unwrap_or_else(PoisonError::into_inner).take() {
+      let _ = writer.join();
+    }
+  }
+}
+
+impl Drop for Auditor {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
+/// The writer thread: appends entries until every sender's gone, rotating the file as it fills, and flushing whenever it's caught up.
+fn write_entries(config: AuditLog, file: File, rx: mpsc::Receiver<Entry>) {
+  let mut size = file.metadata().map_or(0, |metadata| metadata.len());
+  let mut out = Some(BufWriter::new(file));
+  // (Reported once per run of failures, not once per entry.)
+  let mut failing = false;
+  while let Ok(entry) = rx.recv() {
+    for entry in std::iter::once(entry).chain(rx.try_iter()) {
+      let line = entry.to_json();
+      match write_entry(&config, &mut out, &mut size, &line) {
+        Ok(()) => { failing = false; }
+        Err(err) => {
+          // (Given up on, to be reopened for the next entry.)
+          out = None;
+          if !failing {
+            log_error!("[audit_log] Failed to write to {}: {}", config.path.display(), err);
+            error_events::record(Severity::Error, Category::Audit, format!("Failed to write to the audit log {}: {}", config.path.display(), err), None);
+          }
+          failing = true;
+        }
+      }
+    }
+    if let Some(out) = &mut out {
+      let _ = out.flush();
+    }
+  }
+}
+
+/// Appends a line, rotating the file first if it'd go past its maximum size (and reopening it, if it had to be given up on after a failure).
+fn write_entry(config: &AuditLog, out: &mut Option<BufWriter<File>>, size: &mut u64, line: &str) -> io::Result<()> {
+  if *size > 0 && *size + line.len() as u64 > config.max_bytes {
+    if let Some(mut full) = out.take() { full.flush()?; }
+    config.rotate()?;
+    *size = 0;
+  }
+  let file = match out {
+    Some(file) => file,
+    None => {
+      let file = config.open()?;
+      *size = file.metadata()?.len();
+      out.insert(BufWriter::new(file))
+    }
+  };
+  file.write_all(line.as_bytes())?;
+  *size += line.len() as u64;
+  Ok(())
+}
@@ -0,0 +1,65 @@
+// batching.rs
+//
+// How a client's sender task batches its writes as the load on it changes. Every batch (broadcast or targeted send) already queued when the task picks one up goes out with it, in one vectored write and flush; but a client being sent a steady stream of small broadcasts, each written before the next arrives, still costs a write and a flush per broadcast, and under a burst those syscalls are what the server runs out of. So once a client's queue is seen to hold more than the batch being picked up, its sender task holds the write back for a moment, taking in whatever arrives meanwhile (which also empties the queue, so nothing is dropped for the wait). The longer the load lasts, the longer it holds back, up to Batching::max_flush_delay, and the more batches it lets a write take, up to Batching::max_batches; as soon as it finds its queue empty, it goes back to writing each batch the moment it arrives.
+//
+// That leaves streams that are steady but not quite fast enough: small updates a few dozen microseconds apart, say, which each find the queue empty and go out in a packet of their own. With Batching::cork, every write is held back at least that long, like a corked TCP socket, so whatever arrives within it shares the write; each message waits at most the cork (or, under load, the delay it has grown to) for the others.
+
+use std::time::Duration;
+
+/// Batches written at once when a client isn't under load. (Only what was already queued; there's no waiting at this size.)
+const BASE_BATCHES: usize = 64;
+/// The first delay once a client is under load; it doubles with each loaded write from there.
+const FIRST_DELAY: Duration = Duration::from_micros(50);
+
+/// How far a client's sender task may go batching its writes under load (ServerConfig::batching).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Batching {
+  /// Longest a write is held back for more batches to join it. Zero never holds writes back: each goes out with whatever was already queued.
+  pub max_flush_delay: Duration,
+  /// Most batches one write takes (at least 64, what a client not under load is written at most).
+  pub max_batches: usize,
+  /// Shortest a write is held back, load or not. Zero (the default) doesn't hold back writes a client isn't under load for.
+  pub cork: Duration,
+}
+
+impl Default for Batching {
+  fn default() -> Batching {
+    Batching { max_flush_delay: Duration::from_millis(1), max_batches: 1024, cork: Duration::ZERO }
+  }
+}
+
+/// One client's batching, adapted to its load from one write to the next.
+pub(crate) struct Batcher {
+  config: Batching,
+  limit: usize,
+  delay: Duration,
+}
+
+impl Batcher {
+  pub fn new(config: Batching) -> Batcher {
+    Batcher { config, limit: BASE_BATCHES, delay: Duration::ZERO }
+  }
+
+  /// Most batches the next write takes.
+  pub fn limit(&self) -> usize {
+    self.limit
+  }
+
+  /// Called once a write's already-queued batches have been taken, `taken` of them (the one picked up included): how long to wait for more before writing. Just the cork (zero, by default) if nothing was queued behind the first, which also ends any load.
+  pub fn delay(&mut self, taken: usize) -> Duration {
+    if taken <= 1 {
+      self.limit = BASE_BATCHES;
+      self.delay = Duration::ZERO;
+    } else {
+      self.delay = (self.delay * 2).max(FIRST_DELAY).min(self.config.max_flush_delay);
+    }
+    self.delay.max(self.config.cork)
+  }
+
+  /// Called with how many batches the write took in the end: if that was all it could take, the next may take more.
+  pub fn wrote(&mut self, taken: usize) {
+    if taken >= self.limit {
+      self.limit = (self.limit * 2).min(self.config.max_batches.max(BASE_BATCHES));
+    }
+  }
+}
@@ -0,0 +1,96 @@
+// budget.rs
+//
+// The server's memory budget: the payload bytes resident in its queues, between the send functions and the clients' sockets (broadcasts, until their last client has written or missed them; targeted sends, until they're written) and between the clients' sockets and the consumer (received messages, until they're drained). Everything queued holds a Charge for its bytes, which gives them back when it's dropped, so the count is always what's actually waiting; with ServerConfig::memory_budget, whatever would take it over the cap is turned away instead of queued, so a burst of huge frames can't run the process out of memory:
+//
+// - Sends (Server::send() and the like) fail, for the caller to retry later or give up on.
+// - Received client messages and broadcasts relayed from other cluster nodes are dropped, counted (messages_dropped), and reported as error events.
+//
+// Control frames (pings, close frames, ...) are never charged, so a full budget doesn't stop clients from disconnecting.
+
+use std::{fmt, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}};
+
+/// The bytes resident in a server's queues, and the cap on them (if any).
+pub struct MemoryBudget {
+  limit: Option<usize>,
+  resident: AtomicUsize,
+  peak: AtomicUsize,
+  /// Messages turned away for lack of room (sent or received).
+  refused: AtomicU64,
+}
+
+/// Bytes that didn't fit in the budget.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OverBudget {
+  pub bytes: usize,
+  pub resident: usize,
+  pub limit: usize,
+}
+
+impl fmt::Display for OverBudget {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "the server's memory budget is full ({} of {} bytes queued, and {} more wouldn't fit)", self.resident, self.limit, self.bytes)
+  }
+}
+
+impl MemoryBudget {
+  /// A budget of at most `limit` bytes (unlimited if None: then it only counts).
+  pub fn new(limit: Option<usize>) -> Arc<MemoryBudget> {
+    Arc::new(MemoryBudget { limit, resident: AtomicUsize::new(0), peak: AtomicUsize::new(0), refused: AtomicU64::new(0) })
+  }
+
+  /// Takes `bytes` out of the budget until the Charge is dropped; or, if they don't fit, counts the `messages` they belong to as refused.
+  pub fn charge(self: &Arc<Self>, bytes: usize, messages: usize) -> Result<Charge, OverBudget> {
+    let mut resident = self.resident.load(Ordering::Relaxed);
+    loop {
+      if let Some(limit) = self.limit {
+        if resident.saturating_add(bytes) > limit {
+          self.refused.fetch_add(messages as u64, Ordering::Relaxed);
+          return Err(OverBudget { bytes, resident, limit });
+        }
+      }
+      match self.resident.compare_exchange_weak(resident, resident + bytes, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => { break; }
+        Err(actual) => { resident = actual; }
+      }
+    }
+    self.peak.fetch_max(resident + bytes, Ordering::Relaxed);
+    Ok(Charge { budget: self.clone(), bytes })
+  }
+
+  pub fn limit(&self) -> Option<usize> {
+    self.limit
+  }
+
+  /// Bytes queued right now.
+  pub fn resident(&self) -> usize {
+    self.resident.load(Ordering::Relaxed)
+  }
+
+  /// The most bytes that have been queued at once.
+  pub fn peak(&self) -> usize {
+    self.peak.load(Ordering::Relaxed)
+  }
+
+  /// Messages turned away so far for lack of room.
+  pub fn refused(&self) -> u64 {
+    self.refused.load(Ordering::Relaxed)
+  }
+}
+
+/// Bytes taken out of a budget, given back when this is dropped.
+pub struct Charge {
+  budget: Arc<MemoryBudget>,
+  bytes: usize,
+}
+
+impl fmt::Debug for Charge {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Charge({} bytes)", self.bytes)
+  }
+}
+
+impl Drop for Charge {
+  fn drop(&mut self) {
+    self.budget.resident.fetch_sub(self.bytes, Ordering::Relaxed);
+  }
+}
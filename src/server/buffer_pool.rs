@@ -0,0 +1,87 @@
+// buffer_pool.rs
+//
+// Reusable byte buffers for the outbound path. Every message sent used to cost a few allocations along the way: its payload, copied out of Python (or C, or a cluster envelope), then a copy of the whole batch for each client, then tungstenite's frame. Now a batch is shared by the clients it's broadcast to, each client's writer encodes its frames' headers (and small payloads) into a buffer taken from here (see writer.rs), and once every client has written a batch, its payloads come back here too, to hold the next messages' payloads. At a steady rate, sending allocates nothing.
+//
+// The pool is process-wide, like the consumer runtime: buffers are plain bytes and don't care which server they carry messages for.
+
+use std::sync::{Arc, Mutex};
+use lazy_static::lazy_static;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{clients::Broadcast, outbound::Outbound};
+
+/// Most buffers the pool keeps for reuse. Beyond that, returned buffers are freed.
+const MAX_POOLED_BUFFERS: usize = 1024;
+/// Largest buffer the pool keeps, in bytes. The rare huge message's buffer is freed rather than pinning its memory for the lifetime of the process.
+const MAX_POOLED_CAPACITY: usize = 256 * 1024;
+
+lazy_static! {
+  /// The pool for everything the servers send.
+  pub static ref OUTBOUND: BufferPool = BufferPool::new(MAX_POOLED_BUFFERS, MAX_POOLED_CAPACITY);
+}
+
+/// A stack of empty buffers, most recently returned first (so the buffers in use stay warm in cache).
+pub struct BufferPool {
+  buffers: Mutex<Vec<Vec<u8>>>,
+  max_buffers: usize,
+  max_capacity: usize,
+}
+
+impl BufferPool {
+  pub fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+    BufferPool { buffers: Mutex::new(Vec::new()), max_buffers, max_capacity }
+  }
+
+  /// An empty buffer with room for at least `capacity` bytes: a pooled one if there is one, otherwise a new one.
+  pub fn take(&self, capacity: usize) -> Vec<u8> {
+    let pooled = self.buffers.lock().ok().and_then(|mut buffers| buffers.pop());
+    match pooled {
+      Some(mut buf) => {
+        buf.reserve(capacity);
+        buf
+      }
+      None => Vec::with_capacity(capacity),
+    }
+  }
+
+  /// Returns a buffer for reuse, whatever's in it.
+  pub fn recycle(&self, mut buf: Vec<u8>) {
+    if buf.capacity() == 0 || buf.capacity() > self.max_capacity { return; }
+    buf.clear();
+    if let Ok(mut buffers) = self.buffers.lock() {
+      if buffers.len() < self.max_buffers { buffers.push(buf); }
+    }
+  }
+
+  /// The bytes held by the pooled buffers, waiting to be reused.
+  pub fn pooled_bytes(&self) -> usize {
+    self.buffers.lock().map_or(0, |buffers| buffers.iter().map(Vec::capacity).sum())
+  }
+
+  /// Returns the payloads of messages that are done with. (Shared payloads go back to their owners instead.)
+  pub fn recycle_messages(&self, messages: Vec<Outbound>) {
+    for msg in messages {
+      if let Outbound::Message(msg @ (Message::Text(_) | Message::Binary(_))) = msg { self.recycle(msg.into_data()); }
+    }
+  }
+
+  /// Returns the payloads of a broadcast, if this was the last reference to it (i.e. every client has written it).
+  pub fn recycle_shared(&self, broadcast: Arc<Broadcast>) {
+    if let Ok(broadcast) = Arc::try_unwrap(broadcast) { self.recycle_messages(broadcast.messages); }
+  }
+
+  /// A text message holding a copy of `text`, in a pooled buffer.
+  pub fn text(&self, text: &str) -> Message {
+    let mut buf = self.take(text.len());
+    buf.extend_from_slice(text.as_bytes());
+    // (A copy of a str is valid UTF-8.)
+    Message::Text(unsafe { String::from_utf8_unchecked(buf) })
+  }
+
+  /// A binary message holding a copy of `data`, in a pooled buffer.
+  pub fn binary(&self, data: &[u8]) -> Message {
+    let mut buf = self.take(data.len());
+    buf.extend_from_slice(data);
+    Message::Binary(buf)
+  }
+}
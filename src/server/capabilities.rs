@@ -0,0 +1,108 @@
+// capabilities.rs
+//
+// The capability exchange (ServerConfig::capabilities), for producers that encode what they send per audience rather than for the least capable client: a desktop browser that decodes WebP at 1080p, say, and a phone that only wants JPEG at 480p, and only some of the topics. As a client connects, its receiver task writes it the server's offer, the formats the producer can encode, as JSON text:
+//
+//   {"topic":"capabilities","formats":["webp","jpeg"]}
+//
+// and the client answers (then, or any time later, to change them) with its capabilities, as a text message of `key=value` pairs separated by semicolons:
+//
+//   capabilities:formats=webp,jpeg;max_resolution=1280x720;topics=camera,pose
+//
+// `formats` lists the formats the client can decode, in its order of preference; `max_resolution` is the largest it wants, as WIDTHxHEIGHT; and `topics` lists the topics it wants (without it, it wants them all). Other keys are kept as they are, for the application's own. The receiver task takes the message, rather than passing it on to the consumer: it negotiates the format (the client's first that the server offers, if any), answers with it:
+//
+//   {"topic":"capabilities","format":"webp"}   (or "format":null, if there's none in common)
+//
+// stores the capabilities with the client (Server::client_capabilities()), and reports them to the consumer with a ConnectionChange::Capabilities event. A malformed declaration changes nothing; it's recorded as a "receive" warning event.
+
+use std::collections::HashMap;
+
+use super::inspector::json_string;
+
+/// What a capability declaration starts with.
+pub const CAPABILITIES_PREFIX: &str = "capabilities:";
+/// The longest a capability declaration can be; longer ones are malformed.
+pub const MAX_CAPABILITIES_LEN: usize = 4096;
+
+/// What the server offers clients (ServerConfig::capabilities).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CapabilityOffer {
+  /// The formats the producer can encode, e.g. ["webp", "jpeg"].
+  pub formats: Vec<String>,
+}
+
+impl CapabilityOffer {
+  pub fn new(formats: Vec<String>) -> CapabilityOffer {
+    CapabilityOffer { formats }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if let Some(format) = self.formats.iter().find(|format| !is_name(format)) {
+      return Err(format!("a capability format can't be empty, or contain ',', ';', '=' or whitespace: {:?}", format));
+    }
+    Ok(())
+  }
+
+  /// The offer written to each client as it connects.
+  pub fn message(&self) -> String {
+    format!("{{\"topic\":\"capabilities\",\"formats\":[{}]}}", self.formats.iter().map(|format| json_string(format)).collect::<Vec<_>>().join(","))
+  }
+}
+
+/// A client's capabilities, as it declared them, and the format negotiated for it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientCapabilities {
+  /// The formats the client can decode, in its order of preference.
+  pub formats: Vec<String>,
+  /// The client's first format that the server offers, if any.
+  pub format: Option<String>,
+  /// The largest resolution it wants: width and height.
+  pub max_resolution: Option<(u32, u32)>,
+  /// The topics it wants, or None for all of them.
+  pub topics: Option<Vec<String>>,
+  /// Any other keys it declared, and their values.
+  pub extra: HashMap<String, String>,
+}
+
+impl ClientCapabilities {
+  /// The capabilities a message declares, negotiating their format with `offer`, if it's a declaration: Some(Err) for a malformed one.
+  pub fn parse(text: &str, offer: &CapabilityOffer) -> Option<Result<ClientCapabilities, String>> {
+    let declaration = text.strip_prefix(CAPABILITIES_PREFIX)?;
+    if text.len() > MAX_CAPABILITIES_LEN {
+      return Some(Err(format!("the declaration is longer than {} bytes", MAX_CAPABILITIES_LEN)));
+    }
+    let mut capabilities = ClientCapabilities::default();
+    for pair in declaration.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+      let Some((key, value)) = pair.split_once('=') else {
+        return Some(Err(format!("expected key=value, not {:?}", pair)));
+      };
+      let (key, value) = (key.trim(), value.trim());
+      let list = || value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect::<Vec<_>>();
+      match key {
+        "formats" => { capabilities.formats = list(); }
+        "topics" => { capabilities.topics = Some(list()); }
+        "max_resolution" => match value.split_once(['x', 'X']).and_then(|(width, height)| Some((width.trim().parse().ok()?, height.trim().parse().ok()?))) {
+          Some(resolution) => { capabilities.max_resolution = Some(resolution); }
+          None => { return Some(Err(format!("max_resolution must be WIDTHxHEIGHT, not {:?}", value))); }
+        }
+        "" => { return Some(Err(format!("a key can't be empty: {:?}", pair))); }
+        _ => { capabilities.extra.insert(key.to_string(), value.to_string()); }
+      }
+    }
+    capabilities.format = capabilities.formats.iter().find(|format| offer.formats.contains(format)).cloned();
+    Some(Ok(capabilities))
+  }
+
+  /// The answer to the client's declaration.
+  pub fn reply(&self) -> String {
+    format!("{{\"topic\":\"capabilities\",\"format\":{}}}", self.format.as_deref().map_or_else(|| "null".to_string(), json_string))
+  }
+
+  /// Whether the client wants `topic`.
+  pub fn wants(&self, topic: &str) -> bool {
+    self.topics.as_ref().is_none_or(|topics| topics.iter().any(|wanted| wanted == topic))
+  }
+}
+
+fn is_name(name: &str) -> bool {
+  !name.is_empty() && !name.contains(|c: char| c == ',' || c == ';' || c == '=' || c.is_whitespace())
+}
@@ -0,0 +1,89 @@
+// chaos.rs
+//
+// Chaos testing (ServerConfig::chaos): a server that misbehaves on purpose, within bounds, for checking that clients cope with the network going wrong, e.g. that they reconnect and resync. Each client's sender task drops the connection now and then (without a close frame, as a network failure would), holds back its writes, and shuffles the messages in each write a bounded distance; what's sent is never altered, only delayed, reordered, or cut off.
+//
+// It's all driven by a seeded generator, one per connection (seeded from the chaos seed and the connection's number, counting from the server's start), so a run that goes wrong can be repeated with the same seed: the nth connection makes the same decisions, write for write. (Which messages share a write still depends on timing, as it does without chaos.)
+
+use std::time::Duration;
+
+/// How a chaos testing server misbehaves (ServerConfig::chaos). Not for production use.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Chaos {
+  /// Seeds the connections' generators: the same seed makes the same decisions.
+  pub seed: u64,
+  /// Chance (0 to 1) that each write to a client drops its connection instead.
+  pub drop_rate: f64,
+  /// Longest each write to a client is held back; each is held back a random time up to it. Messages arriving meanwhile join the write.
+  pub max_delay: Duration,
+  /// Furthest (in messages) a message may be moved within its write: 0 keeps everything in order.
+  pub reorder_window: usize,
+}
+
+impl Chaos {
+  pub fn validate(&self) -> Result<(), String> {
+    if !(0.0..=1.0).contains(&self.drop_rate) {
+      return Err(format!("the chaos drop rate must be between 0 and 1 (it's {})", self.drop_rate));
+    }
+    Ok(())
+  }
+}
+
+/// One connection's chaos: its own generator, so its decisions don't depend on any other client's.
+pub(crate) struct ClientChaos {
+  config: Chaos,
+  rng: SplitMix64,
+}
+
+impl ClientChaos {
+  /// The chaos for the server's `connection`th connection (counting from 0).
+  pub fn new(config: Chaos, connection: u64) -> ClientChaos {
+    // (Mixed once, so neighbouring seeds and connections don't start out alike.)
+    let mut rng = SplitMix64(config.seed ^ connection.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    rng.next_u64();
+    ClientChaos { config, rng }
+  }
+
+  /// Whether to drop the connection rather than make the next write.
+  pub fn drops_connection(&mut self) -> bool {
+    self.config.drop_rate > 0.0 && self.rng.next_f64() < self.config.drop_rate
+  }
+
+  /// How long to hold back the next write.
+  pub fn delay(&mut self) -> Duration {
+    if self.config.max_delay.is_zero() { return Duration::ZERO; }
+    self.config.max_delay.mul_f64(self.rng.next_f64())
+  }
+
+  /// Shuffles `items`, moving none of them more than the reorder window from where it was.
+  pub fn reorder<T>(&mut self, items: &mut Vec<T>) {
+    let window = self.config.reorder_window;
+    if window == 0 || items.len() < 2 { return; }
+    // Each item is sorted by its position plus a random 0 to window: an item can only be overtaken by, or overtake, items within the window of it.
+    let mut keyed: Vec<(usize, T)> = items.drain(..).enumerate().map(|(i, item)| (i + self.rng.below(window as u64 + 1) as usize, item)).collect();
+    keyed.sort_by_key(|(key, _)| *key);
+    items.extend(keyed.into_iter().map(|(_, item)| item));
+  }
+}
+
+/// A small, fast, seedable generator (SplitMix64); plenty for chaos (and link.rs's jitter), and no dependency.
+pub(crate) struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// Uniform in [0, 1).
+  pub fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  /// Uniform in [0, n), for n > 0 (near enough: n is small).
+  fn below(&mut self, n: u64) -> u64 {
+    self.next_u64() % n
+  }
+}
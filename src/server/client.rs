@@ -0,0 +1,225 @@
+// client.rs
+//
+// Client mode: outbound websocket connections (e.g. to an upstream data feed), with the same send and drain semantics as a server, so one process can serve browsers and consume a feed side by side. Every connection's task runs on a runtime shared by all clients, rather than on a thread of its own, and independently of any server.
+
+use std::{sync::Arc, time::Duration};
+use futures_util::{SinkExt, StreamExt, stream::SplitSink};
+use tokio::{net::TcpStream, sync::{mpsc, watch}};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, tungstenite::{self, Message}};
+
+use super::{Delivery, Error, clients::TargetedSend, consumer_state::{self as cs, SharedReceiver}, error_events::{self, Category, Severity}, events::ClientMessage, handle, notify::MessageNotifier, outbound::{self, Outbound}, queue};
+
+/// How many sends can be queued for a connection before further sends wait (or, for Delivery::Queue, fail); the same as for a server's clients.
+const SEND_QUEUE_LEN: usize = 16;
+/// How many received messages are buffered for draining before the connection stops reading from the server.
+const RECV_QUEUE_LEN: usize = 16;
+/// How long closing waits for the server to answer the close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+lazy_static! {
+  /// Runs every client connection's task (and those of other outbound connections, such as the Redis bridge's). Created the first time it's needed; a single worker is plenty, since the tasks only shuttle messages between channels and sockets.
+  pub(crate) static ref CLIENT_RT: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+    .worker_threads(1)
+    .thread_name("quicksocket-client")
+    .enable_all()
+    .build()
+    .expect("Failed to create the client runtime.");
+}
+
+/// An outbound websocket connection, as returned by Client::connect(). Cheap to clone; every clone refers to the same connection, which stays open until close() is called, the server closes it, or every clone has been dropped.
+///
+/// Messages received from the server are drained like a server's client messages, as ClientMessages whose client_id is the URL.
+#[derive(Clone)]
+pub struct Client {
+  state: Arc<ClientState>,
+}
+
+struct ClientState {
+  url: String,
+  send_tx: mpsc::Sender<TargetedSend>,
+  msg_rx: SharedReceiver<ClientMessage>,
+  notifier: Arc<MessageNotifier>,
+  close_tx: watch::Sender<bool>,
+  /// Whether the connection's open, as reported by its task.
+  connected_rx: watch::Receiver<bool>,
+}
+
+impl Client {
+  /// Connects to the websocket server at `url`, waiting at most `timeout` (if given) for the connection and handshake. Only ws:// URLs are supported; quicksocket has no TLS support yet.
+  pub fn connect(url: &str, timeout: Option<Duration>) -> Result<Client, Error> {
+    let connect_error = |reason: String| Error::Connect { url: url.to_string(), reason };
+    if url.starts_with("wss://") {
+      return Err(connect_error("wss:// URLs need TLS, which isn't supported yet".to_string()));
+    }
+
+    let connected = CLIENT_RT.block_on(async {
+      let connecting = async { tokio_tungstenite::connect_async(url).await.map_err(|err| err.to_string()) };
+      match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connecting).await
+          .unwrap_or_else(|_| Err(format!("timed out after {} ms", timeout.as_millis()))),
+        None => connecting.await,
+      }
+    });
+    let (ws, _) = connected.map_err(connect_error)?;
+    log_info!("[client] Connected to {}.", url);
+
+    let (send_tx, send_rx) = mpsc::channel::<TargetedSend>(SEND_QUEUE_LEN);
+    let (msg_tx, msg_rx) = queue::channel::<ClientMessage>(RECV_QUEUE_LEN);
+    let (close_tx, close_rx) = watch::channel(false);
+    let (connected_tx, connected_rx) = watch::channel(true);
+    let notifier = Arc::new(MessageNotifier::new());
+    CLIENT_RT.spawn(run(url.to_string(), ws, send_rx, msg_tx, notifier.clone(), close_rx, connected_tx));
+
+    Ok(Client { state: Arc::new(ClientState {
+      url: url.to_string(),
+      send_tx,
+      msg_rx: Arc::new(tokio::sync::Mutex::new(msg_rx)),
+      notifier,
+      close_tx,
+      connected_rx,
+    })})
+  }
+
+  pub fn url(&self) -> &str {
+    &self.state.url
+  }
+
+  /// Whether the connection is still open.
+  pub fn is_connected(&self) -> bool {
+    *self.state.connected_rx.borrow()
+  }
+
+  /// A descriptor that becomes readable when received messages are pending (and once more when the connection closes), for event loops, as for a server (see notify.rs). Unix only.
+  pub fn message_fd(&self) -> Result<i32, String> {
+    self.state.notifier.fd()
+  }
+
+  // Sending
+  // -------
+
+  /// Queues messages for the server, without waiting for them to be written. (Shared payloads are copied on the way out: tungstenite frames a client's messages.)
+  pub fn send<M: Into<Outbound>>(&self, messages: Vec<M>) -> Result<(), Error> {
+    self.send_with(messages, Delivery::Queue)
+  }
+
+  /// Sends messages, then blocks until they've been written and flushed to the socket (or `timeout` elapses).
+  pub fn send_and_confirm<M: Into<Outbound>>(&self, messages: Vec<M>, timeout: Option<Duration>) -> Result<(), Error> {
+    self.send_with(messages, Delivery::Confirm { timeout })
+  }
+
+  /// Fails with Error::NotRunning once the connection has closed.
+  pub fn send_with<M: Into<Outbound>>(&self, messages: Vec<M>, delivery: Delivery) -> Result<(), Error> {
+    if !self.is_connected() {
+      return Err(Error::NotRunning);
+    }
+    let message_count = messages.len();
+    handle::deliver(&self.state.send_tx, outbound::collect(messages), None, delivery, "the server").map_err(|reason| Error::Send { reason, message_count })
+  }
+
+  /// Queues messages for the server, waiting for room in the send queue rather than failing when it's full; for relays (see relay.rs), which forward at the pace the server takes messages.
+  pub(crate) async fn send_queued(&self, messages: Vec<Message>) -> Result<(), Error> {
+    self.state.send_tx.send(TargetedSend::new(outbound::collect(messages), None)).await.map_err(|_| Error::NotRunning)
+  }
+
+  // Draining
+  // --------
+
+  /// Takes up to `max_messages` pending text and binary messages from the server, as Server::drain_messages() does. Messages received before the connection closed can still be drained after.
+  pub fn drain_messages(&self, timeout: Option<Duration>, max_messages: usize) -> Vec<ClientMessage> {
+    handle::drain_shared(&self.state.msg_rx, &self.state.notifier, timeout, max_messages)
+  }
+
+  /// Drains as drain_messages() does, appending to a buffer the caller reuses (see Server::drain_messages_into()); returns how many messages were appended.
+  pub fn drain_messages_into(&self, timeout: Option<Duration>, max_messages: usize, messages: &mut Vec<ClientMessage>) -> usize {
+    handle::drain_shared_into(&self.state.msg_rx, &self.state.notifier, timeout, max_messages, messages)
+  }
+
+  pub(crate) fn message_receiver(&self) -> &SharedReceiver<ClientMessage> {
+    &self.state.msg_rx
+  }
+
+  // Closing
+  // -------
+
+  /// Closes the connection with a close handshake. If `wait` is true, blocks until it's closed (which takes at most a couple of seconds, if the server doesn't answer). Closing a closed connection does nothing.
+  pub fn close(&self, wait: bool) {
+    self.state.close_tx.send_replace(true);
+    if wait {
+      let mut connected_rx = self.state.connected_rx.clone();
+      let _ = cs::block_on(async move { connected_rx.wait_for(|connected| !connected).await.map(|_| ()) });
+    }
+  }
+}
+
+/// Connection task. Writes queued sends and forwards received messages for draining until either side closes the connection (or every Client handle is dropped), then completes the close handshake.
+async fn run(
+  url: String,
+  ws: ClientStream,
+  mut send_rx: mpsc::Receiver<TargetedSend>,
+  msg_tx: queue::Sender<ClientMessage>,
+  notifier: Arc<MessageNotifier>,
+  mut close_rx: watch::Receiver<bool>,
+  connected_tx: watch::Sender<bool>,
+) {
+  let (mut ws_write, mut ws_read) = ws.split();
+  loop { tokio::select! {
+    send = send_rx.recv() => {
+      match send {
+        Some(TargetedSend { messages, confirm, .. }) => {
+          let res = write_messages(&mut ws_write, messages).await;
+          if let Err(err) = &res {
+            log_warn!("[client] {} ({})", err, url);
+            error_events::record(Severity::Warning, Category::Send, err.clone(), Some(url.clone()));
+          }
+          let failed = res.is_err();
+          if let Some(confirm) = confirm { let _ = confirm.send(res); }
+          if failed { break; }
+        }
+        // Every Client handle is gone, so nobody can use the connection any more.
+        None => { break; }
+      }
+    }
+
+    // (Read once the message queue has room, so what's been read can go straight in; see queue.rs.)
+    msg = async { msg_tx.room().await; ws_read.next().await } => {
+      match msg {
+        Some(Ok(msg)) => {
+          // (Pings are answered, and a close frame is acknowledged, by the stream itself; a close then ends the stream.)
+          if msg.is_text() || msg.is_binary() {
+            if msg_tx.push(ClientMessage::new(url.clone(), msg)).is_err() { break; }
+            notifier.notify();
+          }
+        }
+        Some(Err(tungstenite::Error::ConnectionClosed)) | None => { break; }
+        Some(Err(err)) => {
+          log_warn!("[client] Error reading from {}: {}", url, err);
+          error_events::record(Severity::Warning, Category::Receive, format!("Failed to read from the server: {}", err), Some(url.clone()));
+          break;
+        }
+      }
+    }
+
+    _ = close_rx.changed() => {
+      if *close_rx.borrow() { break; }
+    }
+  }}
+
+  // Send our close frame (which fails harmlessly if the server closed first), and give the server a moment to answer it.
+  let _ = ws_write.close().await;
+  let _ = tokio::time::timeout(CLOSE_TIMEOUT, async { while let Some(Ok(_)) = ws_read.next().await {} }).await;
+
+  connected_tx.send_replace(false);
+  // Once more, so event loops waiting for messages see that the connection ended.
+  notifier.notify();
+  log_info!("[client] Connection to {} closed.", url);
+}
+
+/// Writes and flushes messages to the server.
+async fn write_messages(ws_write: &mut SplitSink<ClientStream, Message>, messages: Vec<Outbound>) -> Result<(), String> {
+  for msg in messages {
+    ws_write.feed(msg.into_message()).await.map_err(|err| format!("Failed to write to the server: {}", err))?;
+  }
+  ws_write.flush().await.map_err(|err| format!("Failed to flush to the server: {}", err))
+}
@@ -0,0 +1,399 @@
+// clients.rs
+//
+// Registry of connected websocket clients, for sending to one client rather than broadcasting. Each client's sender task registers a channel here when the client connects (and removes it when the task exits); the consumer looks the client up by id to queue messages for it alone. Also the batches the sender tasks forward: TargetedSends, and the Broadcasts for every client.
+//
+// Broadcasts go through a BroadcastQueue: a tokio broadcast channel, whose receivers skip the oldest batches when they fall too far behind, plus the accounting the channel doesn't do. Every message broadcast is numbered, so a client that skipped batches knows exactly how many messages it missed (which is counted against it, and recorded as an error event); and with LagPolicy::Block, senders wait for the slowest client to make room instead.
+
+use std::{collections::HashMap, sync::{Arc, Condvar, Mutex, PoisonError, RwLock, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use super::{budget::Charge, capabilities::ClientCapabilities, compression::Deflater, frames::{self, Frame, FrameSlots}, keepalive::{Liveness, RoundTrip}, keyframes::{KeyframeStore, StreamPart}, outbound::Outbound, rate_limit::{ClientThrottle, RateLimit}, rosbridge::Subscriptions};
+
+/// How many targeted sends can be queued for one client before further sends wait (or, for try_send, fail).
+const CLIENT_QUEUE_LEN: usize = 16;
+/// How many broadcasts can be queued for the slowest client before it starts missing them (or, with LagPolicy::Block, before broadcasts wait for it).
+pub const BROADCAST_QUEUE_LEN: usize = 16;
+/// How often a broadcast waiting for room checks again, whether or not it's been told a client made some.
+const ROOM_RECHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Messages for a single client, optionally with a way to report back once they've been written and flushed to the client's socket (or why they couldn't be).
+pub struct TargetedSend {
+  pub messages: Vec<Outbound>,
+  pub confirm: Option<oneshot::Sender<Result<(), String>>>,
+  /// When it was queued, for the channel wait counter (see stats.rs).
+  pub queued_at: Instant,
+  /// Its bytes, taken out of the server's memory budget (see budget.rs) while it's queued.
+  _charge: Option<Charge>,
+}
+
+impl TargetedSend {
+  pub fn new(messages: Vec<Outbound>, confirm: Option<oneshot::Sender<Result<(), String>>>) -> TargetedSend {
+    TargetedSend { messages, confirm, queued_at: Instant::now(), _charge: None }
+  }
+
+  pub fn with_charge(self, charge: Charge) -> TargetedSend {
+    TargetedSend { _charge: Some(charge), ..self }
+  }
+}
+
+/// Messages for every client, shared by their sender tasks.
+pub struct Broadcast {
+  pub messages: Vec<Outbound>,
+  pub queued_at: Instant,
+  /// The number of its first message, counting every message broadcast before it.
+  seq: u64,
+  /// Where it is in a keyframe stream (see keyframes.rs), if it's a keyframe or delta.
+  pub stream: Option<StreamPart>,
+  /// Its bytes, taken out of the server's memory budget (see budget.rs) until the last client has written (or missed) it.
+  _charge: Charge,
+}
+
+/// What happens to broadcasts when a client falls so far behind that the queue is full of batches it hasn't written yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+  /// The broadcast goes ahead, and the client misses the oldest batches.
+  #[default]
+  Drop,
+  /// Server::send() waits (blocking) until the slowest client makes room, for at most `timeout` if given, and then goes ahead as with Drop. Broadcasts relayed from other cluster nodes never wait: they arrive on the server's own thread, which the clients need.
+  Block { timeout: Option<Duration> },
+}
+
+/// The broadcast channel to every client's sender task (and the inspector's), with explicit accounting of what lagging clients miss.
+pub struct BroadcastQueue {
+  tx: broadcast::Sender<Arc<Broadcast>>,
+  /// The number of the next message to be broadcast. Also held while sending, so the numbers go up in the channel's order.
+  next_seq: Mutex<u64>,
+  policy: LagPolicy,
+  /// Notified whenever a receiver picks up broadcasts (with LagPolicy::Block only), for broadcasts waiting for room.
+  room: Condvar,
+  room_lock: Mutex<()>,
+  /// The keyframe streams' latest keyframes and chains, added to as they're queued.
+  keyframes: KeyframeStore,
+  /// If the server compresses its messages, what deflates the broadcasts as they're queued (see compression.rs).
+  deflater: Option<Arc<Deflater>>,
+}
+
+impl BroadcastQueue {
+  pub fn new(policy: LagPolicy, deflater: Option<Arc<Deflater>>) -> BroadcastQueue {
+    BroadcastQueue { tx: broadcast::channel(BROADCAST_QUEUE_LEN).0, next_seq: Mutex::new(0), policy, room: Condvar::new(), room_lock: Mutex::new(()), keyframes: KeyframeStore::default(), deflater }
+  }
+
+  /// A receiver for every broadcast from now on.
+  pub fn subscribe(self: &Arc<Self>) -> BroadcastReceiver {
+    let next_seq = self.next_seq.lock().unwrap_or_else(PoisonError::into_inner);
+    BroadcastReceiver { rx: self.tx.subscribe(), next_seq: *next_seq, queue: self.clone() }
+  }
+
+  /// Queues messages for every receiver, with their charge against the memory budget. Fails, handing the broadcast back, if there are no receivers (no clients are connected, or the server has stopped).
+  pub fn send(&self, messages: Vec<Outbound>, charge: Charge) -> Result<(), Arc<Broadcast>> {
+    let messages = self.deflate(messages);
+    let mut next_seq = self.next_seq.lock().unwrap_or_else(PoisonError::into_inner);
+    let count = messages.len() as u64;
+    let broadcast = Arc::new(Broadcast { messages, queued_at: Instant::now(), seq: *next_seq, stream: None, _charge: charge });
+    self.tx.send(broadcast).map_err(|unsent| unsent.0)?;
+    *next_seq += count;
+    Ok(())
+  }
+
+  /// Queues a keyframe on `topic` for every receiver, starting the topic's chain over (see keyframes.rs). It's kept for clients that connect later, so it's not a failure if there are no receivers.
+  pub fn send_keyframe(&self, topic: &str, message: Outbound, charge: Charge) {
+    let _ = self.send_stream(|keyframes| Some(keyframes.next_keyframe(topic)), message, charge);
+  }
+
+  /// Queues a delta on `topic` for every receiver, adding it to the topic's chain. Fails, handing the message back, if no keyframe has been sent on the topic; not if there are no receivers.
+  pub fn send_delta(&self, topic: &str, message: Outbound, charge: Charge) -> Result<(), Outbound> {
+    self.send_stream(|keyframes| keyframes.next_delta(topic), message, charge)
+  }
+
+  fn send_stream(&self, part: impl FnOnce(&KeyframeStore) -> Option<StreamPart>, message: Outbound, charge: Charge) -> Result<(), Outbound> {
+    let message = self.deflate(vec![message]).remove(0);
+    // (Held while it's added to the chain as well, so the chain's in the channel's order.)
+    let mut next_seq = self.next_seq.lock().unwrap_or_else(PoisonError::into_inner);
+    let part = match part(&self.keyframes) {
+      Some(part) => part,
+      None => { return Err(message); }
+    };
+    let broadcast = Arc::new(Broadcast { messages: vec![message], queued_at: Instant::now(), seq: *next_seq, stream: Some(part), _charge: charge });
+    self.keyframes.add(&broadcast);
+    if self.tx.send(broadcast).is_ok() { *next_seq += 1; }
+    Ok(())
+  }
+
+  /// The keyframe streams' latest keyframes and chains.
+  pub fn keyframes(&self) -> &KeyframeStore {
+    &self.keyframes
+  }
+
+  /// What deflates the broadcasts, if the server compresses its messages.
+  pub fn deflater(&self) -> Option<&Arc<Deflater>> {
+    self.deflater.as_ref()
+  }
+
+  /// A broadcast's messages, wrapped to be deflated if the server compresses its messages (see compression.rs).
+  fn deflate(&self, messages: Vec<Outbound>) -> Vec<Outbound> {
+    match &self.deflater {
+      Some(deflater) => deflater.wrap(messages),
+      None => messages,
+    }
+  }
+
+  /// With LagPolicy::Block, waits until every receiver has room for another broadcast (or the policy's timeout elapses, or `keep_waiting` says to stop), returning how long that took. Returns straight away otherwise.
+  pub fn wait_for_room(&self, keep_waiting: impl Fn() -> bool) -> Duration {
+    let timeout = match self.policy {
+      LagPolicy::Drop => { return Duration::ZERO; }
+      LagPolicy::Block { timeout } => timeout,
+    };
+    let started = Instant::now();
+    let mut guard = self.room_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    // (The channel counts a batch as queued until every receiver has picked it up, so the slowest receiver has room while it's not full.)
+    while self.tx.len() >= BROADCAST_QUEUE_LEN && keep_waiting() {
+      let mut wait = ROOM_RECHECK_INTERVAL;
+      if let Some(timeout) = timeout {
+        let left = timeout.saturating_sub(started.elapsed());
+        if left.is_zero() { break; }
+        wait = wait.min(left);
+      }
+      guard = self.room.wait_timeout(guard, wait).unwrap_or_else(PoisonError::into_inner).0;
+    }
+    started.elapsed()
+  }
+
+  /// Batches queued for the slowest receiver (each is queued until every receiver has picked it up), out of the BROADCAST_QUEUE_LEN it can fall behind by.
+  pub fn queued(&self) -> usize {
+    self.tx.len()
+  }
+
+  /// How many receivers there are: one per client, plus the inspector's and a session recording's.
+  pub fn subscribers(&self) -> usize {
+    self.tx.receiver_count()
+  }
+
+  fn picked_up(&self) {
+    if self.policy == LagPolicy::Drop { return; }
+    let _guard = self.room_lock.lock().unwrap_or_else(PoisonError::into_inner);
+    self.room.notify_all();
+  }
+}
+
+/// One subscriber's end of a BroadcastQueue.
+pub struct BroadcastReceiver {
+  rx: broadcast::Receiver<Arc<Broadcast>>,
+  /// The number of the next message this receiver expects.
+  next_seq: u64,
+  queue: Arc<BroadcastQueue>,
+}
+
+impl BroadcastReceiver {
+  /// The next broadcast, and how many messages were missed before it (those of the batches this receiver fell too far behind to get), or None once the queue is gone.
+  pub async fn recv(&mut self) -> Option<(Arc<Broadcast>, u64)> {
+    loop {
+      match self.rx.recv().await {
+        Ok(broadcast) => { return Some(self.received(broadcast)); }
+        // (The next broadcast says how many messages were skipped.)
+        Err(broadcast::error::RecvError::Lagged(_)) => {}
+        Err(broadcast::error::RecvError::Closed) => { return None; }
+      }
+    }
+  }
+
+  /// As recv(), for a broadcast that's already queued, if there is one.
+  pub fn try_recv(&mut self) -> Option<(Arc<Broadcast>, u64)> {
+    loop {
+      match self.rx.try_recv() {
+        Ok(broadcast) => { return Some(self.received(broadcast)); }
+        Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+        Err(_) => { return None; }
+      }
+    }
+  }
+
+  /// The keyframe streams' latest keyframes and chains (see keyframes.rs).
+  pub fn keyframes(&self) -> &KeyframeStore {
+    self.queue.keyframes()
+  }
+
+  fn received(&mut self, broadcast: Arc<Broadcast>) -> (Arc<Broadcast>, u64) {
+    self.queue.picked_up();
+    let missed = broadcast.seq.saturating_sub(self.next_seq);
+    self.next_seq = broadcast.seq + broadcast.messages.len() as u64;
+    (broadcast, missed)
+  }
+}
+
+#[derive(Default)]
+/// Shared between every client's sender task and any number of consumer threads; the lock is only held to look up, add or remove a sender, never while sending.
+pub struct ClientRegistry {
+  senders: RwLock<HashMap<String, RegisteredClient>>,
+}
+
+struct RegisteredClient {
+  sender: mpsc::Sender<TargetedSend>,
+  /// Broadcast messages the client has missed by falling behind.
+  missed: AtomicU64,
+  /// Set by the consumer (see Server::set_client_tag()), along with the client's rate limit, which its sender task has too.
+  tag: Mutex<Option<String>>,
+  throttle: Arc<ClientThrottle>,
+  /// Shared with its sender and receiver tasks, which time its pings.
+  liveness: Arc<Liveness>,
+  /// Shared with its sender task, which writes them.
+  frames: Arc<FrameSlots>,
+  /// Set by its receiver task, as the client declares them (see capabilities.rs).
+  capabilities: Mutex<Option<ClientCapabilities>>,
+  /// Set by its receiver task, as the client subscribes to topics and unsubscribes (see rosbridge.rs).
+  ros_subscriptions: Mutex<Subscriptions>,
+}
+
+/// A connected client's tag, broadcast messages missed, ping round trips and health, as returned by Server::client_stats().
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientStats {
+  pub tag: Option<String>,
+  pub messages_missed: u64,
+  /// None until the client's answered a ping, and for clients that aren't pinged (see ServerConfig::keepalive).
+  pub rtt: Option<RoundTrip>,
+  /// False while it's marked unhealthy for leaving its pings unanswered (see Keepalive::unhealthy_after).
+  pub healthy: bool,
+}
+
+impl ClientRegistry {
+  pub fn new() -> ClientRegistry {
+    ClientRegistry::default()
+  }
+
+  /// Registers a client, with the rate limit its sender task keeps to, the liveness its tasks keep track of and the slots its frames are put in, returning the receiver its sender task should forward targeted sends from. Replaces any stale registration under the same id.
+  pub(crate) fn register(&self, client_id: &str, throttle: Arc<ClientThrottle>, liveness: Arc<Liveness>, frames: Arc<FrameSlots>) -> mpsc::Receiver<TargetedSend> {
+    let (tx, rx) = mpsc::channel::<TargetedSend>(CLIENT_QUEUE_LEN);
+    let client = RegisteredClient { sender: tx, missed: AtomicU64::new(0), tag: Mutex::new(None), throttle, liveness, frames, capabilities: Mutex::new(None), ros_subscriptions: Mutex::default() };
+    self.senders.write().unwrap_or_else(PoisonError::into_inner).insert(client_id.to_string(), client);
+    rx
+  }
+
+  pub fn unregister(&self, client_id: &str) {
+    self.senders.write().unwrap_or_else(PoisonError::into_inner).remove(client_id);
+  }
+
+  /// The channel to a connected client, or None if no client with that id is connected.
+  pub fn sender(&self, client_id: &str) -> Option<mpsc::Sender<TargetedSend>> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).get(client_id).map(|client| client.sender.clone())
+  }
+
+  /// Tags a client, and gives it `limit`. False if no client with that id is connected.
+  pub fn set_tag(&self, client_id: &str, tag: Option<&str>, limit: Option<RateLimit>) -> bool {
+    let senders = self.senders.read().unwrap_or_else(PoisonError::into_inner);
+    let client = match senders.get(client_id) {
+      Some(client) => client,
+      None => { return false; }
+    };
+    *client.tag.lock().unwrap_or_else(PoisonError::into_inner) = tag.map(str::to_string);
+    client.throttle.set_limit(limit);
+    true
+  }
+
+  pub fn tag(&self, client_id: &str) -> Option<String> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).get(client_id)
+      .and_then(|client| client.tag.lock().unwrap_or_else(PoisonError::into_inner).clone())
+  }
+
+  /// Stores the capabilities a client declared. False if no client with that id is connected.
+  pub(crate) fn set_capabilities(&self, client_id: &str, capabilities: ClientCapabilities) -> bool {
+    let senders = self.senders.read().unwrap_or_else(PoisonError::into_inner);
+    let Some(client) = senders.get(client_id) else { return false; };
+    *client.capabilities.lock().unwrap_or_else(PoisonError::into_inner) = Some(capabilities);
+    true
+  }
+
+  /// The capabilities a connected client declared, or None if it hasn't declared any, or no client with that id is connected.
+  pub fn capabilities(&self, client_id: &str) -> Option<ClientCapabilities> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).get(client_id)
+      .and_then(|client| client.capabilities.lock().unwrap_or_else(PoisonError::into_inner).clone())
+  }
+
+  /// The connected clients that have declared capabilities, and theirs.
+  pub fn all_capabilities(&self) -> HashMap<String, ClientCapabilities> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).iter()
+      .filter_map(|(client_id, client)| Some((client_id.clone(), client.capabilities.lock().unwrap_or_else(PoisonError::into_inner).clone()?)))
+      .collect()
+  }
+
+  /// Subscribes a client to a topic's rosbridge publishes, or unsubscribes it.
+  pub(crate) fn ros_subscription(&self, client_id: &str, topic: &str, id: Option<&str>, subscribe: bool) {
+    let senders = self.senders.read().unwrap_or_else(PoisonError::into_inner);
+    let Some(client) = senders.get(client_id) else { return; };
+    let mut subscriptions = client.ros_subscriptions.lock().unwrap_or_else(PoisonError::into_inner);
+    match subscribe {
+      true => subscriptions.subscribe(topic, id),
+      false => subscriptions.unsubscribe(topic, id),
+    }
+  }
+
+  /// The channels to the clients subscribed to a topic's rosbridge publishes.
+  pub fn ros_subscribers(&self, topic: &str) -> Vec<mpsc::Sender<TargetedSend>> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).values()
+      .filter(|client| client.ros_subscriptions.lock().unwrap_or_else(PoisonError::into_inner).contains(topic))
+      .map(|client| client.sender.clone())
+      .collect()
+  }
+
+  /// The topics connected clients are subscribed to, and the clients subscribed to each.
+  pub fn ros_subscriptions(&self) -> HashMap<String, Vec<String>> {
+    let mut topics: HashMap<String, Vec<String>> = HashMap::new();
+    for (client_id, client) in self.senders.read().unwrap_or_else(PoisonError::into_inner).iter() {
+      for topic in client.ros_subscriptions.lock().unwrap_or_else(PoisonError::into_inner).topics() {
+        topics.entry(topic.to_string()).or_default().push(client_id.clone());
+      }
+    }
+    topics
+  }
+
+  /// A connected client's stats, or None if no client with that id is connected.
+  pub fn stats(&self, client_id: &str) -> Option<ClientStats> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).get(client_id).map(|client| ClientStats {
+      tag: client.tag.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+      messages_missed: client.missed.load(Ordering::Relaxed),
+      rtt: client.liveness.rtt(),
+      healthy: client.liveness.is_healthy(),
+    })
+  }
+
+  /// How many clients are registered.
+  pub fn registered(&self) -> usize {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).len()
+  }
+
+  /// Targeted sends queued for the registered clients, all together, and how many they could queue before sends wait.
+  pub fn queued_sends(&self) -> (usize, usize) {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).values()
+      .map(|client| (client.sender.max_capacity() - client.sender.capacity(), client.sender.max_capacity()))
+      .fold((0, 0), |(queued, capacity), (more, room)| (queued + more, capacity + room))
+  }
+
+  /// Puts a frame in every registered client's slot for its topic (see frames.rs), returning how many clients skipped the frame it replaced.
+  pub(crate) fn put_frame(&self, frame: &Arc<Frame>) -> usize {
+    let mut skipped = 0;
+    for client in self.senders.read().unwrap_or_else(PoisonError::into_inner).values() {
+      if let Some(replaced) = client.frames.put(frame.clone()) {
+        skipped += 1;
+        frames::recycle(replaced);
+      }
+    }
+    skipped
+  }
+
+  /// Frames pending for the registered clients, all together.
+  pub fn pending_frames(&self) -> usize {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).values().map(|client| client.frames.pending()).sum()
+  }
+
+  /// Counts broadcast messages missed by a client, returning how many it's missed in all.
+  pub fn count_missed(&self, client_id: &str, missed: u64) -> u64 {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).get(client_id)
+      .map_or(missed, |client| client.missed.fetch_add(missed, Ordering::Relaxed) + missed)
+  }
+
+  /// The connected clients that have missed broadcast messages, and how many each has missed.
+  pub fn missed_messages(&self) -> HashMap<String, u64> {
+    self.senders.read().unwrap_or_else(PoisonError::into_inner).iter()
+      .map(|(client_id, client)| (client_id.clone(), client.missed.load(Ordering::Relaxed)))
+      .filter(|(_, missed)| *missed > 0)
+      .collect()
+  }
+}
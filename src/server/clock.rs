@@ -0,0 +1,81 @@
+// clock.rs
+//
+// Server-side timestamps, for browser clients that line their telemetry up with the server's timeline. Two clocks: the wall clock (microseconds since the Unix epoch, which the host's clock sync may step) and a monotonic one (microseconds since the process first read it, which never goes back, for measuring intervals).
+//
+// Server::send_stamped() stamps messages with both as they're sent. A text message that's a JSON object has them spliced in as its first fields, e.g. {"server_time_us":1700000000000000,"server_mono_us":1234567,"x":1}; any other text is wrapped, as a JSON string under "data". A binary message is prefixed with 16 bytes: the wall time then the monotonic time, each as a big-endian u64.
+//
+// With ServerConfig::time_sync, clients can ask the server's time, NTP-style: a client sends a text message starting "time_sync:" (followed by its own clock reading, say; anything up to MAX_TIME_SYNC_ECHO bytes), and its receiver task answers straight away, without the consumer seeing the request, with {"topic":"time_sync","echo":"...","server_time_us":...,"server_mono_us":...}, echoing whatever followed the colon, and stamped as the request was read. The client takes its clock's readings as it sent the request (t0) and got the answer (t3): the server's clock is about server_time_us - (t0 + t3) / 2 ahead of its own, to within half the round trip, t3 - t0. (Repeating the exchange a few times and keeping the one with the shortest round trip gets it closest.)
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, inspector::json_string, outbound::Outbound};
+
+/// What a time sync request starts with.
+pub const TIME_SYNC_PREFIX: &str = "time_sync:";
+/// The most of a time sync request, after its prefix, that's echoed: longer requests are passed on to the consumer like any other message.
+pub const MAX_TIME_SYNC_ECHO: usize = 256;
+/// How long a binary message's stamp is.
+pub const BINARY_STAMP_LEN: usize = 16;
+
+lazy_static! {
+  /// Where the monotonic clock starts.
+  static ref MONOTONIC_EPOCH: Instant = Instant::now();
+}
+
+/// A reading of the server's clocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+  /// Microseconds since the Unix epoch.
+  pub wall_us: u64,
+  /// Microseconds since the monotonic clock started.
+  pub mono_us: u64,
+}
+
+impl Timestamp {
+  pub fn now() -> Timestamp {
+    let mono_us = MONOTONIC_EPOCH.elapsed().as_micros() as u64;
+    let wall_us = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+    Timestamp { wall_us, mono_us }
+  }
+
+  fn fields(&self) -> String {
+    format!("\"server_time_us\":{},\"server_mono_us\":{}", self.wall_us, self.mono_us)
+  }
+}
+
+/// Stamps a text or binary message with `at` (see above), returning the original's buffer to the pool. Control messages are left as they are.
+pub fn stamp(message: Outbound, at: Timestamp) -> Outbound {
+  let stamped = if let Some(text) = message.text_data() {
+    Message::Text(stamp_text(text, at))
+  } else if let Some(data) = message.binary_data() {
+    let mut buf = OUTBOUND.take(BINARY_STAMP_LEN + data.len());
+    buf.extend_from_slice(&at.wall_us.to_be_bytes());
+    buf.extend_from_slice(&at.mono_us.to_be_bytes());
+    buf.extend_from_slice(data);
+    Message::Binary(buf)
+  } else {
+    return message;
+  };
+  OUTBOUND.recycle_messages(vec![message]);
+  Outbound::from(stamped)
+}
+
+fn stamp_text(text: &str, at: Timestamp) -> String {
+  let trimmed = text.trim();
+  match trimmed.strip_prefix('{').filter(|_| trimmed.ends_with('}')) {
+    Some(rest) if rest[..rest.len() - 1].trim().is_empty() => format!("{{{}}}", at.fields()),
+    Some(rest) => format!("{{{},{}", at.fields(), rest),
+    None => format!("{{{},\"data\":{}}}", at.fields(), json_string(text)),
+  }
+}
+
+/// What follows a time sync request's prefix, if `text` is one.
+pub fn time_sync_request(text: &str) -> Option<&str> {
+  text.strip_prefix(TIME_SYNC_PREFIX).filter(|echo| echo.len() <= MAX_TIME_SYNC_ECHO)
+}
+
+/// The answer to a time sync request, read `at`.
+pub fn time_sync_reply(echo: &str, at: Timestamp) -> String {
+  format!("{{\"topic\":\"time_sync\",\"echo\":{},{}}}", json_string(echo), at.fields())
+}
@@ -0,0 +1,546 @@
+// cluster.rs
+//
+// Cluster mode: several quicksocket processes (nodes), each listing the others' URLs (see ServerConfig::cluster), relay their broadcasts to one another so a message sent on any node reaches the clients of every node. Each node keeps an outbound link to every peer, over a websocket to the peer's reserved PATH, and forwards its own broadcasts (Server::send()) down them; broadcasts arriving from peers go to the node's own clients only.
+//
+// Loops are prevented three ways: relayed broadcasts are never forwarded again (the mesh is full, so they don't need to be), a node refuses links from peers claiming its own id, and each broadcast carries its origin's id, incarnation (which changes whenever the node restarts) and sequence number, so a copy arriving twice (e.g. over two links to the same node) is dropped.
+//
+// Links are health-checked with pings; a peer that stops answering is marked down and reconnected to, with its failures recorded as "cluster" errors (once per outage). Link traffic is invisible to the server's consumer: peers aren't clients, so they don't appear in its events, messages or stats.
+
+use std::{collections::{HashMap, HashSet}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use futures_util::{SinkExt, Stream, StreamExt};
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::tungstenite::{self, Message, protocol::{CloseFrame, frame::coding::CloseCode}};
+
+use super::{buffer_pool::OUTBOUND, clients::BroadcastQueue, outbound::{self, Outbound}, error_events::{Category, Severity}, stats::{DropReason, ServerStats}, tasks::Task, transport::Connection};
+
+/// The path peers' links connect to. Only servers in cluster mode serve it, ahead of any proxy route covering it.
+pub const PATH: &str = "/quicksocket/cluster";
+
+/// Version of the envelope format below, exchanged in the hello; peers must speak the same one.
+const PROTOCOL_VERSION: u8 = 1;
+/// How long connecting to a peer and exchanging hellos may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often links ping their peer.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// A link whose peer hasn't answered a ping (or, on the peer's side, sent anything) for this long is considered lost.
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a link waits before reconnecting to a peer it lost (or couldn't reach).
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Broadcasts queued for links before a slow one starts missing them.
+const ENVELOPE_QUEUE: usize = 256;
+
+const KIND_HELLO: u8 = 0;
+const KIND_BROADCAST: u8 = 1;
+const MESSAGE_TEXT: u8 = 0;
+const MESSAGE_BINARY: u8 = 1;
+
+/// Which other nodes a server relays its broadcasts to, and how it identifies itself to them.
+#[derive(Clone, Debug, Default)]
+pub struct ClusterConfig {
+  /// This node's id, unique within the cluster. If empty, one is made up ("node-" and some hex digits).
+  pub node_id: String,
+  /// The ws:// URLs of the other nodes' servers, e.g. "ws://10.0.0.2:9001". Listing this node too is harmless: it refuses the link.
+  pub peers: Vec<String>,
+  /// If given, nodes only link up with peers configured with the same secret. It crosses the network in the clear, so it keeps out misconfigured nodes rather than attackers.
+  pub secret: Option<String>,
+}
+
+impl ClusterConfig {
+  /// Checks the configuration can work, returning what's wrong with it otherwise.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.node_id.len() > u8::MAX as usize {
+      return Err(format!("cluster node ids can be at most {} bytes long", u8::MAX));
+    }
+    if self.secret.as_ref().map(String::len).unwrap_or(0) > u16::MAX as usize {
+      return Err(format!("the cluster secret can be at most {} bytes long", u16::MAX));
+    }
+    for peer in &self.peers {
+      if !peer.starts_with("ws://") {
+        return Err(format!("cluster peer {:?} must be a ws:// URL (wss:// needs TLS, which isn't supported yet)", peer));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Where a link to a peer stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+  /// Not linked up yet since the server started.
+  Connecting,
+  /// Linked; broadcasts are being relayed to the peer.
+  Up,
+  /// The link was lost, or couldn't be made; it's being retried.
+  Down,
+}
+
+impl PeerState {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      PeerState::Connecting => "connecting",
+      PeerState::Up         => "up",
+      PeerState::Down       => "down",
+    }
+  }
+}
+
+/// The health of one peer's link, from Server::cluster_peers().
+#[derive(Clone, Debug)]
+pub struct PeerStatus {
+  /// The peer's URL, as configured.
+  pub url: String,
+  /// The id the peer gave when the link was last made, if it ever was.
+  pub node_id: Option<String>,
+  pub state: PeerState,
+  /// When the link entered its current state.
+  pub since: SystemTime,
+  /// Broadcasts (batches of messages sent together) relayed to the peer.
+  pub sent: u64,
+  /// Broadcasts received from the peer (over its link to this node) and delivered to this node's clients.
+  pub received: u64,
+  /// Broadcasts the peer missed, because it was down or its link fell behind.
+  pub dropped: u64,
+  /// The link's latest ping round trip.
+  pub rtt: Option<Duration>,
+  /// Why the link was last lost, or couldn't be made.
+  pub last_error: Option<String>,
+}
+
+/// A server's cluster membership, shared by its consumer (which publishes broadcasts) and its tokio tasks (which run the links).
+pub struct Cluster {
+  node_id: String,
+  secret: String,
+  /// Tells this run of the node apart from earlier ones, whose sequence numbers started over.
+  incarnation: u64,
+  next_seq: AtomicU64,
+  envelope_tx: broadcast::Sender<Arc<Vec<u8>>>,
+  peers: Vec<Mutex<PeerStatus>>,
+  /// The latest (incarnation, sequence number) delivered from each origin.
+  delivered: Mutex<HashMap<String, (u64, u64)>>,
+  /// The (host, reason) pairs links have been refused for, so a peer retrying a bad link isn't recorded as an error every time.
+  refused: Mutex<HashSet<String>>,
+}
+
+/// A decoded link message.
+enum Envelope {
+  Hello { version: u8, node_id: String, secret: String },
+  Broadcast { origin: String, incarnation: u64, seq: u64, messages: Vec<Message> },
+}
+
+impl Cluster {
+  pub fn new(config: &ClusterConfig) -> Cluster {
+    let incarnation = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_nanos() as u64).unwrap_or(0) ^ std::process::id() as u64;
+    let node_id = match config.node_id.is_empty() {
+      true => format!("node-{:08x}", incarnation as u32),
+      false => config.node_id.clone(),
+    };
+    let now = SystemTime::now();
+    let peers = config.peers.iter().map(|url| Mutex::new(PeerStatus {
+      url: url.clone(), node_id: None, state: PeerState::Connecting, since: now, sent: 0, received: 0, dropped: 0, rtt: None, last_error: None,
+    })).collect();
+    Cluster {
+      node_id,
+      secret: config.secret.clone().unwrap_or_default(),
+      incarnation,
+      next_seq: AtomicU64::new(0),
+      envelope_tx: broadcast::channel(ENVELOPE_QUEUE).0,
+      peers,
+      delivered: Mutex::new(HashMap::new()),
+      refused: Mutex::new(HashSet::new()),
+    }
+  }
+
+  pub fn node_id(&self) -> &str {
+    &self.node_id
+  }
+
+  pub fn peer_count(&self) -> usize {
+    self.peers.len()
+  }
+
+  /// The URL of the peer at `index`.
+  pub fn peer_url(&self, index: usize) -> String {
+    self.peers[index].lock().map(|peer| peer.url.clone()).unwrap_or_default()
+  }
+
+  pub fn peers(&self) -> Vec<PeerStatus> {
+    self.peers.iter().filter_map(|peer| peer.lock().ok().map(|peer| peer.clone())).collect()
+  }
+
+  /// Relays a batch of messages this node is broadcasting to every peer that's up (its text and binary messages, that is; if there are none, nothing is relayed).
+  pub fn publish(&self, messages: &[Outbound]) {
+    let relayed: Vec<&Outbound> = messages.iter().filter(|msg| msg.is_text() || msg.is_binary()).collect();
+    if relayed.is_empty() || self.peers.is_empty() { return; }
+    let seq = self.next_seq.fetch_add(1, Ordering::Relaxed) + 1;
+    let envelope = encode_broadcast(&self.node_id, self.incarnation, seq, &relayed);
+    for index in 0..self.peers.len() {
+      self.update_peer(index, |peer| if peer.state != PeerState::Up { peer.dropped += 1; });
+    }
+    // (Fails only when no link is up, which was just counted.)
+    let _ = self.envelope_tx.send(Arc::new(envelope));
+  }
+
+  fn update_peer(&self, index: usize, update: impl FnOnce(&mut PeerStatus)) {
+    if let Ok(mut peer) = self.peers[index].lock() { update(&mut peer); }
+  }
+
+  fn set_peer_state(&self, index: usize, state: PeerState) {
+    self.update_peer(index, |peer| if peer.state != state {
+      peer.state = state;
+      peer.since = SystemTime::now();
+    });
+  }
+
+  /// Counts a broadcast received from the node `node_id` against the peers it's configured as.
+  fn count_received(&self, node_id: &str) {
+    for index in 0..self.peers.len() {
+      self.update_peer(index, |peer| if peer.node_id.as_deref() == Some(node_id) { peer.received += 1; });
+    }
+  }
+
+  /// Whether a broadcast from `origin` hasn't been delivered before, noting it as delivered if so.
+  fn is_new(&self, origin: &str, incarnation: u64, seq: u64) -> bool {
+    let delivered = self.delivered.lock();
+    if delivered.is_err() { return true; }
+    let mut delivered = delivered.unwrap();
+    match delivered.get_mut(origin) {
+      Some(last) if last.0 == incarnation && seq <= last.1 => false,
+      Some(last) => { *last = (incarnation, seq); true }
+      None => { delivered.insert(origin.to_string(), (incarnation, seq)); true }
+    }
+  }
+
+  fn hello(&self) -> Vec<u8> {
+    let mut hello = vec![KIND_HELLO, PROTOCOL_VERSION];
+    put_short_string(&mut hello, &self.node_id);
+    hello.extend_from_slice(&(self.secret.len() as u16).to_be_bytes());
+    hello.extend_from_slice(self.secret.as_bytes());
+    hello
+  }
+
+  /// Reads the other end's hello from a link, returning its node id, or why it can't be linked with.
+  async fn read_hello<S>(&self, read: &mut S) -> Result<String, String>
+  where S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin {
+    let data = loop {
+      match read.next().await {
+        Some(Ok(Message::Binary(data))) => { break data; }
+        Some(Ok(Message::Close(frame))) => {
+          return Err(format!("refused the link: {}", frame.map(|frame| frame.reason.to_string()).unwrap_or_else(|| "no reason given".to_string())));
+        }
+        Some(Ok(msg)) if msg.is_ping() || msg.is_pong() => {}
+        Some(Ok(_)) => { return Err("sent something other than a hello".to_string()); }
+        Some(Err(err)) => { return Err(err.to_string()); }
+        None => { return Err("closed the connection during the handshake".to_string()); }
+      }
+    };
+    match decode(&data)? {
+      Envelope::Hello { version, .. } if version != PROTOCOL_VERSION => {
+        Err(format!("speaks cluster protocol version {} (this node speaks {})", version, PROTOCOL_VERSION))
+      }
+      Envelope::Hello { secret, .. } if !secrets_match(&secret, &self.secret) => Err("the cluster secret doesn't match".to_string()),
+      Envelope::Hello { node_id, .. } if node_id == self.node_id => Err(format!("it has this node's id ({:?})", node_id)),
+      Envelope::Hello { node_id, .. } => Ok(node_id),
+      Envelope::Broadcast { .. } => Err("sent a broadcast before its hello".to_string()),
+    }
+  }
+}
+
+/// Keeps the link to the peer at `index` up until the server shuts down: connects, relays this node's broadcasts to it, and reconnects whenever the link's lost.
+pub async fn run_link(cluster: Arc<Cluster>, index: usize, stats: Arc<ServerStats>, mut ser_req_shutdown_rx: watch::Receiver<bool>, _task: Task) {
+  let url = cluster.peer_url(index);
+  let link_url = format!("{}{}", url.trim_end_matches('/'), PATH);
+  // Whether the current outage has been recorded as an error yet.
+  let mut reported = false;
+  while !*ser_req_shutdown_rx.borrow() {
+    let linked = tokio::select! {
+      linked = connect(&cluster, &link_url) => linked,
+      _ = ser_req_shutdown_rx.changed() => { continue; }
+    };
+    let reason = match linked {
+      Ok((link, node_id)) => {
+        // Subscribed before the peer's reported up, so no broadcast counted as sent to it is missed.
+        let envelope_rx = cluster.envelope_tx.subscribe();
+        log_info!("[cluster] Linked to node {} at {}.", node_id, url);
+        cluster.update_peer(index, |peer| { peer.node_id = Some(node_id); peer.last_error = None; });
+        cluster.set_peer_state(index, PeerState::Up);
+        reported = false;
+        match relay(&cluster, index, link, envelope_rx, &stats, &mut ser_req_shutdown_rx).await {
+          Some(reason) => format!("lost the link: {}", reason),
+          // Shutting down.
+          None => { break; }
+        }
+      }
+      Err(reason) => reason,
+    };
+    cluster.update_peer(index, |peer| peer.last_error = Some(reason.clone()));
+    cluster.set_peer_state(index, PeerState::Down);
+    if !reported {
+      log_warn!("[cluster] Peer {}: {}", url, reason);
+      stats.record_error(Severity::Warning, Category::Cluster, format!("Cluster peer {}: {}", url, reason), None);
+      reported = true;
+    }
+    tokio::select! {
+      _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+      _ = ser_req_shutdown_rx.changed() => {}
+    }
+  }
+  log_debug!("[cluster] Link to {} finished.", url);
+}
+
+type Link = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Connects to a peer and exchanges hellos with it, returning the link and the peer's node id.
+async fn connect(cluster: &Cluster, url: &str) -> Result<(Link, String), String> {
+  let handshake = async {
+    let (mut link, _) = tokio_tungstenite::connect_async(url).await.map_err(|err| format!("failed to connect: {}", err))?;
+    link.send(Message::Binary(cluster.hello())).await.map_err(|err| format!("failed to send the hello: {}", err))?;
+    let node_id = cluster.read_hello(&mut link).await?;
+    Ok((link, node_id))
+  };
+  tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await
+    .unwrap_or_else(|_| Err(format!("timed out after {} ms linking up", HANDSHAKE_TIMEOUT.as_millis())))
+}
+
+/// Relays broadcasts down a link, and pings the peer, until the link's lost (returning why) or the server shuts down (returning None, once the peer's been sent a close frame).
+async fn relay(cluster: &Cluster, index: usize, link: Link, mut envelope_rx: broadcast::Receiver<Arc<Vec<u8>>>, stats: &ServerStats, ser_req_shutdown_rx: &mut watch::Receiver<bool>) -> Option<String> {
+  let (mut write, mut read) = link.split();
+  let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+  let mut last_pong = Instant::now();
+  let mut ping_sent: Option<Instant> = None;
+  loop { tokio::select! {
+    envelope = envelope_rx.recv() => { match envelope {
+      Ok(envelope) => {
+        if let Err(err) = write.send(Message::Binary(envelope.to_vec())).await { return Some(err.to_string()); }
+        cluster.update_peer(index, |peer| peer.sent += 1);
+      }
+      Err(broadcast::error::RecvError::Lagged(skipped)) => {
+        cluster.update_peer(index, |peer| peer.dropped += skipped);
+      }
+      // (The cluster outlives its links.)
+      Err(broadcast::error::RecvError::Closed) => { return Some("the cluster went away".to_string()); }
+    }}
+
+    msg = read.next() => { match msg {
+      Some(Ok(Message::Pong(_))) => {
+        last_pong = Instant::now();
+        if let Some(sent) = ping_sent.take() { cluster.update_peer(index, |peer| peer.rtt = Some(sent.elapsed())); }
+      }
+      Some(Ok(Message::Close(frame))) => {
+        return Some(format!("the peer closed it ({})", frame.map(|frame| frame.reason.to_string()).unwrap_or_else(|| "no reason given".to_string())));
+      }
+      Some(Ok(_)) => {}
+      Some(Err(err)) => { return Some(err.to_string()); }
+      None => { return Some("the connection closed".to_string()); }
+    }}
+
+    _ = heartbeat.tick() => {
+      if last_pong.elapsed() > PEER_TIMEOUT {
+        return Some(format!("no answer to pings for {} ms", PEER_TIMEOUT.as_millis()));
+      }
+      // One ping at a time, so each pong's round trip is measured from the right one.
+      if ping_sent.is_none() {
+        if let Err(err) = write.send(Message::Ping(vec![])).await { return Some(err.to_string()); }
+        ping_sent = Some(Instant::now());
+      }
+    }
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() {
+        let close_frame = CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() };
+        if write.send(Message::Close(Some(close_frame))).await.is_ok() { stats.close_frame_sent(); }
+        return None;
+      }
+    }
+  }}
+}
+
+/// Serves a peer's link to this node for its lifetime: exchanges hellos (refusing the link with a 1008 close frame if the peer can't be linked with), then delivers the peer's broadcasts to this node's clients until the link closes, the peer goes quiet, or the server shuts down.
+pub async fn serve_peer(
+  addr: String,
+  stream: Connection,
+  cluster: Arc<Cluster>,
+  stats: &ServerStats,
+  ser_msg_tx: Arc<BroadcastQueue>,
+  mut ser_req_shutdown_rx: watch::Receiver<bool>
+) {
+  let link = tokio_tungstenite::accept_async(stream).await;
+  if let Err(err) = link {
+    log_warn!("[cluster] Error during the websocket handshake with {}: {:?}", addr, err);
+    stats.record_error(Severity::Warning, Category::Handshake, format!("Websocket handshake failed: {}", err), Some(addr));
+    return;
+  }
+  let (mut write, mut read) = link.unwrap().split();
+
+  let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, cluster.read_hello(&mut read)).await
+    .unwrap_or_else(|_| Err(format!("sent no hello within {} ms", HANDSHAKE_TIMEOUT.as_millis())));
+  let node_id = match hello {
+    Ok(node_id) => node_id,
+    Err(reason) => {
+      let host = addr.rsplitn(2, ':').last().unwrap_or(&addr);
+      let first_refusal = cluster.refused.lock().map(|mut refused| refused.insert(format!("{} {}", host, reason))).unwrap_or(true);
+      if first_refusal {
+        log_warn!("[cluster] Refused a link from {}: {}", addr, reason);
+        stats.record_error(Severity::Warning, Category::Cluster, format!("Refused a cluster link: {}", reason), Some(addr));
+      }
+      let close_frame = CloseFrame { code: CloseCode::Policy, reason: reason.into() };
+      let _ = write.send(Message::Close(Some(close_frame))).await;
+      return;
+    }
+  };
+  if let Err(err) = write.send(Message::Binary(cluster.hello())).await {
+    log_warn!("[cluster] Failed to answer the hello from node {} ({}): {}", node_id, addr, err);
+    return;
+  }
+  log_info!("[cluster] Node {} linked up from {}.", node_id, addr);
+
+  loop { tokio::select! {
+    msg = tokio::time::timeout(PEER_TIMEOUT, read.next()) => { match msg {
+      Ok(Some(Ok(Message::Binary(data)))) => { match decode(&data) {
+        Ok(Envelope::Broadcast { origin, incarnation, seq, messages }) => {
+          if origin != cluster.node_id && cluster.is_new(&origin, incarnation, seq) {
+            cluster.count_received(&node_id);
+            // Only to this node's clients: relayed broadcasts are never relayed again. (Sending fails when no clients are connected, which is fine. It never waits for room, whatever the lag policy: this is the server's own thread.)
+            let messages = outbound::collect(messages);
+            let bytes = messages.iter().map(Outbound::len).sum();
+            match stats.memory().charge(bytes, messages.len()) {
+              Ok(charge) => { if let Err(unsent) = ser_msg_tx.send(messages, charge) { OUTBOUND.recycle_shared(unsent); } }
+              Err(err) => {
+                log_warn!("[cluster] Dropped a broadcast from node {}: {}", origin, err);
+                stats.messages_dropped(DropReason::OverBudget, messages.len() as u64);
+                stats.record_error(Severity::Warning, Category::Cluster, format!("Dropped a broadcast from node {}: {}", origin, err), Some(addr.clone()));
+                OUTBOUND.recycle_messages(messages);
+              }
+            }
+          }
+        }
+        Ok(Envelope::Hello { .. }) => {}
+        Err(reason) => {
+          log_warn!("[cluster] Malformed broadcast from node {} ({}): {}", node_id, addr, reason);
+          stats.record_error(Severity::Warning, Category::Cluster, format!("Malformed broadcast from node {}: {}", node_id, reason), Some(addr.clone()));
+        }
+      }}
+      // Pings (answered by the stream itself) and the like.
+      Ok(Some(Ok(_))) => {}
+      Ok(Some(Err(err))) => {
+        log_warn!("[cluster] Link from node {} ({}) failed: {}", node_id, addr, err);
+        break;
+      }
+      Ok(None) => { break; }
+      Err(_) => {
+        log_warn!("[cluster] Node {} ({}) went quiet for {} ms; dropping its link.", node_id, addr, PEER_TIMEOUT.as_millis());
+        break;
+      }
+    }}
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() {
+        let close_frame = CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() };
+        if write.send(Message::Close(Some(close_frame))).await.is_ok() { stats.close_frame_sent(); }
+        break;
+      }
+    }
+  }}
+  log_info!("[cluster] Link from node {} ({}) closed.", node_id, addr);
+}
+
+/// Compares secrets without giving away, through timing, how much of one matched.
+fn secrets_match(a: &str, b: &str) -> bool {
+  a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Envelopes
+// ---------
+//
+// Each link message is one binary websocket message, big-endian throughout:
+//
+//   hello:     0, version: u8, node id (u8 length), secret (u16 length)
+//   broadcast: 1, origin node id (u8 length), incarnation: u64, seq: u64, message count: u32, then per message: kind (0 text, 1 binary), u32 length, bytes
+
+fn put_short_string(buf: &mut Vec<u8>, value: &str) {
+  buf.push(value.len() as u8);
+  buf.extend_from_slice(value.as_bytes());
+}
+
+fn encode_broadcast(origin: &str, incarnation: u64, seq: u64, messages: &[&Outbound]) -> Vec<u8> {
+  let payload_len: usize = messages.iter().map(|msg| 5 + msg.len()).sum();
+  let mut buf = Vec::with_capacity(2 + origin.len() + 20 + payload_len);
+  buf.push(KIND_BROADCAST);
+  put_short_string(&mut buf, origin);
+  buf.extend_from_slice(&incarnation.to_be_bytes());
+  buf.extend_from_slice(&seq.to_be_bytes());
+  buf.extend_from_slice(&(messages.len() as u32).to_be_bytes());
+  for msg in messages {
+    let (kind, data): (u8, &[u8]) = match (msg.text_data(), msg.binary_data()) {
+      (Some(text), _) => (MESSAGE_TEXT, text.as_bytes()),
+      (_, Some(data)) => (MESSAGE_BINARY, data),
+      _ => unreachable!("only text and binary messages are relayed"),
+    };
+    buf.push(kind);
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+  }
+  buf
+}
+
+/// Reads an envelope's fields in order, failing on truncation.
+struct Decoder<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    if self.data.len() < len { return Err("truncated envelope".to_string()); }
+    let (taken, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(taken)
+  }
+
+  fn u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn uint(&mut self, len: usize) -> Result<u64, String> {
+    Ok(self.take(len)?.iter().fold(0, |value, byte| value << 8 | *byte as u64))
+  }
+
+  fn string(&mut self, len: usize) -> Result<String, String> {
+    String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "a string in the envelope isn't UTF-8".to_string())
+  }
+}
+
+/// Decodes an envelope. Its text messages are checked to be UTF-8, whatever the peer: a link's bytes are only as trustworthy as whatever's at its other end.
+fn decode(data: &[u8]) -> Result<Envelope, String> {
+  let mut decoder = Decoder { data };
+  let envelope = match decoder.u8()? {
+    KIND_HELLO => {
+      let version = decoder.u8()?;
+      let node_id_len = decoder.u8()? as usize;
+      let node_id = decoder.string(node_id_len)?;
+      let secret_len = decoder.uint(2)? as usize;
+      Envelope::Hello { version, node_id, secret: decoder.string(secret_len)? }
+    }
+    KIND_BROADCAST => {
+      let origin_len = decoder.u8()? as usize;
+      let origin = decoder.string(origin_len)?;
+      let incarnation = decoder.uint(8)?;
+      let seq = decoder.uint(8)?;
+      let count = decoder.uint(4)? as usize;
+      // (Each message takes at least 5 bytes, so a bogus count can't make this allocate much.)
+      let mut messages = Vec::with_capacity(count.min(decoder.data.len() / 5));
+      for _ in 0..count {
+        let kind = decoder.u8()?;
+        let len = decoder.uint(4)? as usize;
+        messages.push(match kind {
+          // (Copied into pooled buffers, like the consumer's own broadcasts.)
+          MESSAGE_TEXT => OUTBOUND.text(std::str::from_utf8(decoder.take(len)?).map_err(|_| "a message in the envelope isn't UTF-8".to_string())?),
+          MESSAGE_BINARY => OUTBOUND.binary(decoder.take(len)?),
+          kind => { return Err(format!("unknown message kind {}", kind)); }
+        });
+      }
+      Envelope::Broadcast { origin, incarnation, seq, messages }
+    }
+    kind => { return Err(format!("unknown envelope kind {}", kind)); }
+  };
+  if !decoder.data.is_empty() { return Err("trailing bytes after the envelope".to_string()); }
+  Ok(envelope)
+}
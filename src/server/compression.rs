@@ -0,0 +1,353 @@
+// compression.rs
+//
+// The permessage-deflate extension (RFC 7692, ServerConfig::compression): clients that offer it in their handshake, as browsers do, are written the server's bigger messages deflated, and can send theirs deflated too.
+//
+// The server agrees to server_no_context_takeover, so each message it deflates starts from scratch, and comes out as the same frame for every client. A broadcast's messages are deflated once, then, rather than in each client's sender task: as it's queued, each of its messages worth deflating (MIN_BYTES or more of text or binary) is wrapped in a DeflatedMessage, which keeps its deflated frame alongside it, and handed to the server's pool of deflating threads. Clients' writers wait for a message's frame before writing it to a client that agreed to the extension, and write the original to the rest (the pool's threads aren't the runtime's, so the clients' tasks carry on meanwhile). Nothing's deflated while no client has agreed to it. Messages sent to a client alone, and frames (see frames.rs), go out as they are.
+//
+// tungstenite can't read deflated frames (it rejects frames with the RSV1 bit), so a client's reader inflates its deflated messages first, into plain frames for tungstenite to read (see Inflater). Clients may keep their context from message to message, so each client's inflater keeps its own.
+//
+// Servers behind the tower Service (see service.rs) don't negotiate the extension, as the service does their handshakes.
+
+use std::{io, sync::{Arc, Mutex, OnceLock, PoisonError, atomic::{AtomicBool, AtomicUsize, Ordering}, mpsc}, thread};
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::{io::ReadBuf, sync::Notify};
+
+use super::{outbound::Outbound, writer::{MAX_HEADER_LEN, push_header}};
+
+/// Messages shorter than this go out as they are, unless another minimum is given: deflating them saves too little to be worth the time.
+pub const DEFAULT_MIN_BYTES: usize = 1024;
+/// The deflating threads, unless another number is given.
+pub const DEFAULT_THREADS: usize = 2;
+/// The extension the server agrees to, in its handshake's Sec-WebSocket-Extensions.
+pub const RESPONSE: &str = "permessage-deflate; server_no_context_takeover";
+/// The longest message a client's inflated into (tungstenite's own limit on a message), so a small deflated message can't take the server's memory.
+const MAX_INFLATED_LEN: usize = 64 << 20;
+/// The RSV1 bit of a frame's first byte, set on a deflated message's first frame.
+const RSV1: u8 = 0x40;
+/// Every deflated message ends with an empty stored block, which is left off the frame (and added back to inflate it).
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+/// Name of the deflating threads (as in top -H).
+const THREAD_NAME: &str = "quicksocket-deflate";
+
+/// How the server compresses its messages (ServerConfig::compression).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+  /// Messages shorter than this (in bytes) go out as they are.
+  pub min_bytes: usize,
+  /// The deflate level: 0 (stored) to 9 (smallest, slowest).
+  pub level: u32,
+  /// How many threads deflate the broadcasts.
+  pub threads: usize,
+}
+
+impl Default for Compression {
+  fn default() -> Compression {
+    Compression { min_bytes: DEFAULT_MIN_BYTES, level: 6, threads: DEFAULT_THREADS }
+  }
+}
+
+impl Compression {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.level > 9 {
+      return Err(format!("the compression level must be from 0 to 9, not {}", self.level));
+    }
+    if self.threads == 0 {
+      return Err("compression needs at least one thread".to_string());
+    }
+    Ok(())
+  }
+}
+
+/// Whether a client's Sec-WebSocket-Extensions offers permessage-deflate with parameters the server can agree to: any, bar a server window under 32 KiB (the deflater's only window).
+pub fn offered(extensions: &str) -> bool {
+  extensions.split(',').any(|offer| {
+    let mut params = offer.split(';').map(str::trim).filter(|param| !param.is_empty());
+    params.next() == Some("permessage-deflate") && params.all(|param| {
+      let (name, value) = match param.split_once('=') {
+        Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+        None => (param, None),
+      };
+      match name {
+        "server_no_context_takeover" | "client_no_context_takeover" => value.is_none(),
+        "server_max_window_bits" => value == Some("15"),
+        "client_max_window_bits" => value.is_none_or(|bits| bits.parse::<u8>().is_ok_and(|bits| (8..=15).contains(&bits))),
+        _ => false,
+      }
+    })
+  })
+}
+
+/// A server's compression: its pool of deflating threads, and the count of its clients that agreed to the extension.
+pub struct Deflater {
+  config: Compression,
+  clients: AtomicUsize,
+  /// The pool's queue, from start() on: None in it tells a thread to exit. (Set once rather than locked, so queueing a broadcast's messages takes no lock.)
+  jobs: OnceLock<mpsc::Sender<Option<Job>>>,
+  /// Whether the pool's running, from start() to stop().
+  running: AtomicBool,
+}
+
+impl Deflater {
+  pub fn new(config: Compression) -> Deflater {
+    Deflater { config, clients: AtomicUsize::new(0), jobs: OnceLock::new(), running: AtomicBool::new(false) }
+  }
+
+  /// Starts the pool's threads. (Called from the server's thread, so they run on its cores, if it's pinned to some; see threading.rs.)
+  pub fn start(&self) {
+    let (jobs_tx, jobs_rx) = mpsc::channel();
+    let jobs_rx = Arc::new(Mutex::new(jobs_rx));
+    for _ in 0..self.config.threads {
+      let jobs_rx = jobs_rx.clone();
+      let level = flate2::Compression::new(self.config.level);
+      if let Err(err) = thread::Builder::new().name(THREAD_NAME.to_string()).spawn(move || deflate_jobs(&jobs_rx, level)) {
+        log_warn!("[compression] Failed to start a deflating thread: {}", err);
+      }
+    }
+    if self.jobs.set(jobs_tx).is_ok() { self.running.store(true, Ordering::Release); }
+  }
+
+  /// Stops the pool: its threads exit once they've deflated what's queued, and broadcasts go out as they are from now on.
+  pub fn stop(&self) {
+    if !self.running.swap(false, Ordering::AcqRel) { return; }
+    if let Some(jobs) = self.jobs.get() {
+      for _ in 0..self.config.threads { let _ = jobs.send(None); }
+    }
+  }
+
+  /// Counts a client that offered the extension in `extensions` (its Sec-WebSocket-Extensions), while the returned DeflatingClient's held; or None, if it didn't offer it.
+  pub fn accept(self: &Arc<Self>, extensions: Option<&str>) -> Option<DeflatingClient> {
+    if !extensions.is_some_and(offered) { return None; }
+    self.clients.fetch_add(1, Ordering::Relaxed);
+    Some(DeflatingClient(self.clone()))
+  }
+
+  /// A broadcast's messages, with those worth deflating wrapped and queued to be; or as they are, if no client's agreed to the extension (or the pool isn't running).
+  pub fn wrap(&self, messages: Vec<Outbound>) -> Vec<Outbound> {
+    if self.clients.load(Ordering::Relaxed) == 0 || !messages.iter().any(|msg| self.worth_deflating(msg)) { return messages; }
+    let jobs = match self.jobs.get() {
+      Some(jobs) if self.running.load(Ordering::Acquire) => jobs,
+      _ => { return messages; }
+    };
+    messages.into_iter().map(|msg| {
+      if !self.worth_deflating(&msg) { return msg; }
+      let deflated = DeflatedMessage(Arc::new(DeflatedFrame { original: msg, frame: OnceLock::new(), done: Notify::new() }));
+      // (If the pool's gone, the job's dropped, and the message goes out as it is.)
+      let _ = jobs.send(Some(Job(deflated.clone())));
+      Outbound::Deflated(deflated)
+    }).collect()
+  }
+
+  fn worth_deflating(&self, msg: &Outbound) -> bool {
+    (msg.is_text() || msg.is_binary()) && msg.len() >= self.config.min_bytes && !matches!(msg, Outbound::Deflated(_))
+  }
+}
+
+/// A client that agreed to the extension, counted by its server's Deflater until it's dropped (with the client's writer).
+pub struct DeflatingClient(Arc<Deflater>);
+
+impl Drop for DeflatingClient {
+  fn drop(&mut self) {
+    self.0.clients.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// A message along with its deflated frame, once the pool's deflated it. Cloning one shares both.
+#[derive(Clone)]
+pub struct DeflatedMessage(Arc<DeflatedFrame>);
+
+struct DeflatedFrame {
+  original: Outbound,
+  /// The deflated frame, header and all; None if deflating didn't make it any shorter (or the pool stopped first).
+  frame: OnceLock<Option<Vec<u8>>>,
+  done: Notify,
+}
+
+impl DeflatedMessage {
+  /// The message, as it was sent.
+  pub fn original(&self) -> &Outbound {
+    &self.0.original
+  }
+
+  /// Resolves once the pool's done with the message.
+  pub async fn deflated(&self) {
+    let done = self.0.done.notified();
+    tokio::pin!(done);
+    done.as_mut().enable();
+    if self.0.frame.get().is_some() { return; }
+    done.await
+  }
+
+  /// The deflated frame, if the pool's done with the message and it came out shorter.
+  pub fn frame(&self) -> Option<&[u8]> {
+    self.0.frame.get().and_then(Option::as_deref)
+  }
+
+  fn finish(&self, frame: Option<Vec<u8>>) {
+    if self.0.frame.set(frame).is_ok() { self.0.done.notify_waiters(); }
+  }
+}
+
+/// A message for the pool to deflate. One that's dropped undone (the pool having stopped) is done with as it is, so its writers don't wait on it.
+struct Job(DeflatedMessage);
+
+impl Drop for Job {
+  fn drop(&mut self) {
+    self.0.finish(None);
+  }
+}
+
+/// A deflating thread: deflates the queued messages until the pool stops.
+fn deflate_jobs(jobs_rx: &Mutex<mpsc::Receiver<Option<Job>>>, level: flate2::Compression) {
+  let mut compress = Compress::new(level, false);
+  loop {
+    let job = match jobs_rx.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+      Ok(Some(job)) => job,
+      Ok(None) | Err(_) => { return; }
+    };
+    let msg = job.0.original();
+    let (opcode, payload) = match (msg.text_data(), msg.binary_data()) {
+      (Some(text), _) => (0x1, text.as_bytes()),
+      (None, Some(data)) => (0x2, data),
+      (None, None) => { continue; }
+    };
+    job.0.finish(deflate_frame(&mut compress, opcode, payload));
+  }
+}
+
+/// A message deflated as a frame of its own (with no context from the messages before it), or None if it doesn't come out shorter.
+fn deflate_frame(compress: &mut Compress, opcode: u8, payload: &[u8]) -> Option<Vec<u8>> {
+  compress.reset();
+  let mut deflated = Vec::with_capacity(payload.len() / 2 + 64);
+  let mut consumed = 0;
+  loop {
+    if deflated.len() == deflated.capacity() { deflated.reserve(deflated.capacity()); }
+    let before = compress.total_in();
+    compress.compress_vec(&payload[consumed..], &mut deflated, FlushCompress::Sync).ok()?;
+    consumed += (compress.total_in() - before) as usize;
+    // (A sync flush is done once it's left room to spare.)
+    if consumed == payload.len() && deflated.len() < deflated.capacity() { break; }
+    if deflated.len() > payload.len() { return None; }
+  }
+  if deflated.ends_with(&TAIL) { deflated.truncate(deflated.len() - TAIL.len()); }
+  if deflated.len() >= payload.len() { return None; }
+  let mut frame = Vec::with_capacity(MAX_HEADER_LEN + deflated.len());
+  push_header(opcode, deflated.len(), &mut frame);
+  frame[0] |= RSV1;
+  frame.extend_from_slice(&deflated);
+  Some(frame)
+}
+
+/// A client's frames, as read from its socket, with its deflated messages inflated: each one becomes a single plain frame (masked, with a zero key, as tungstenite expects a client's frames to be). Other frames are passed on as they are, control frames in the middle of a deflated message included.
+pub struct Inflater {
+  decompress: Decompress,
+  /// Read from the socket, but not yet a whole frame.
+  raw: Vec<u8>,
+  /// Ready for tungstenite, from `plain_at` on.
+  plain: Vec<u8>,
+  plain_at: usize,
+  /// The deflated message whose frames are being read, if one is: its opcode, and its payload so far.
+  message: Option<(u8, Vec<u8>)>,
+}
+
+impl Default for Inflater {
+  fn default() -> Inflater {
+    Inflater { decompress: Decompress::new(false), raw: vec![], plain: vec![], plain_at: 0, message: None }
+  }
+}
+
+impl Inflater {
+  /// Whether there's anything ready for tungstenite.
+  pub fn has_plain(&self) -> bool {
+    self.plain_at < self.plain.len()
+  }
+
+  /// Copies what's ready for tungstenite into `buf`, as far as it goes.
+  pub fn read_plain(&mut self, buf: &mut ReadBuf<'_>) {
+    let len = buf.remaining().min(self.plain.len() - self.plain_at);
+    buf.put_slice(&self.plain[self.plain_at..self.plain_at + len]);
+    self.plain_at += len;
+    if self.plain_at == self.plain.len() {
+      self.plain.clear();
+      self.plain_at = 0;
+    }
+  }
+
+  /// Takes bytes read from the socket, passing on or inflating the frames they complete. Fails for a deflated message that's malformed, or too long.
+  pub fn push(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.raw.extend_from_slice(bytes);
+    let mut at = 0;
+    while let Some((header_len, payload_len)) = frame_len(&self.raw[at..])? {
+      let len = header_len + payload_len;
+      if self.raw.len() - at < len { break; }
+      let frame = &self.raw[at..at + len];
+      at += len;
+      let (fin, deflated, opcode, masked) = (frame[0] & 0x80 != 0, frame[0] & RSV1 != 0, frame[0] & 0x0F, frame[1] & 0x80 != 0);
+      let data = opcode == 0x1 || opcode == 0x2;
+      if data && self.message.is_some() { return Err(invalid("a new message before the deflated one before it finished")); }
+      let starts = data && deflated && masked;
+      let continues = opcode == 0x0 && masked && self.message.is_some();
+      if !starts && !continues {
+        self.plain.extend_from_slice(frame);
+        continue;
+      }
+      let payload = &mut self.message.get_or_insert_with(|| (opcode, vec![])).1;
+      if payload.len() + payload_len > MAX_INFLATED_LEN { return Err(invalid("a deflated message too long to read")); }
+      let mask = &frame[header_len - 4..header_len];
+      payload.extend(frame[header_len..].iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+      if fin {
+        let (opcode, mut payload) = self.message.take().unwrap_or_default();
+        let inflated = self.inflate(&mut payload)?;
+        let start = self.plain.len();
+        push_header(opcode, inflated.len(), &mut self.plain);
+        self.plain[start + 1] |= 0x80;
+        self.plain.extend_from_slice(&[0; 4]);
+        self.plain.extend_from_slice(&inflated);
+      }
+    }
+    self.raw.drain(..at);
+    Ok(())
+  }
+
+  fn inflate(&mut self, payload: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    payload.extend_from_slice(&TAIL);
+    let mut inflated = Vec::with_capacity(payload.len() * 4);
+    let mut consumed = 0;
+    loop {
+      if inflated.len() == inflated.capacity() {
+        if inflated.len() >= MAX_INFLATED_LEN { return Err(invalid("a deflated message that inflates too long to read")); }
+        inflated.reserve(inflated.capacity());
+      }
+      let (before_in, before_out) = (self.decompress.total_in(), self.decompress.total_out());
+      let status = self.decompress.decompress_vec(&payload[consumed..], &mut inflated, FlushDecompress::Sync).map_err(|err| invalid(&format!("a malformed deflated message ({})", err)))?;
+      consumed += (self.decompress.total_in() - before_in) as usize;
+      // (A client can end a message with a final block, after which its next message starts a new stream.)
+      if status == Status::StreamEnd {
+        self.decompress.reset(false);
+        break;
+      }
+      if consumed == payload.len() && inflated.len() < inflated.capacity() { break; }
+      if self.decompress.total_in() == before_in && self.decompress.total_out() == before_out && inflated.len() < inflated.capacity() {
+        return Err(invalid("a truncated deflated message"));
+      }
+    }
+    Ok(inflated)
+  }
+}
+
+/// The lengths of the header and payload of the frame `bytes` start with, if there's enough of it to tell. Fails for a frame too long to read.
+fn frame_len(bytes: &[u8]) -> io::Result<Option<(usize, usize)>> {
+  if bytes.len() < 2 { return Ok(None); }
+  let mask_len = if bytes[1] & 0x80 != 0 { 4 } else { 0 };
+  let (len_len, payload_len) = match bytes[1] & 0x7F {
+    126 if bytes.len() >= 4 => (2, u16::from_be_bytes([bytes[2], bytes[3]]) as u64),
+    127 if bytes.len() >= 10 => (8, u64::from_be_bytes([bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9]])),
+    126 | 127 => { return Ok(None); }
+    len => (0, len as u64),
+  };
+  if payload_len > MAX_INFLATED_LEN as u64 { return Err(invalid("a frame too long to read")); }
+  Ok(Some((2 + len_len + mask_len, payload_len as usize)))
+}
+
+fn invalid(what: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, format!("The client sent {}.", what))
+}
@@ -0,0 +1,78 @@
+// config.rs
+//
+// Server configuration, passed to server::start() and shared (read-only) with the tokio tasks.
+
+use std::{collections::HashMap, time::Duration};
+
+use super::{audit_log::AuditLog, batching::Batching, capabilities::CapabilityOffer, chaos::Chaos, clients::LagPolicy, cluster::ClusterConfig, compression::Compression, decimation::TopicRate, heartbeat::HeartbeatTopic, keepalive::Keepalive, link::LinkEmulation, metrics::MetricsConfig, proxy::ProxyRoute, rate_limit::{ClientRateLimits, RateLimit}, threading::Threading, transport::Transport, watchdog::Watchdog};
+
+/// Options controlling server behavior beyond the port to listen on.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+  /// Whether to serve the debug inspector page at /inspector (and its feed at /inspector/ws).
+  pub inspector: bool,
+  /// HTML body served ("200 OK") to plain HTTP requests that don't ask for a websocket upgrade. If None, such requests get a "426 Upgrade Required" response instead.
+  pub landing_page: Option<String>,
+  /// Inbound binary messages of at least this many bytes are handed to Python as MessageBuffer objects (read-only buffers over the received bytes) instead of being copied into bytes. If None, binary messages are always bytes.
+  pub zero_copy_min_bytes: Option<usize>,
+  /// Where connections come from: the port (TCP, or with the "uring" feature, TCP through io_uring); Server::connect_loopback() alone, for tests; or, with the "tower" feature, the Server::service() mounted in another HTTP server.
+  pub transport: Transport,
+  /// Websocket connections to these paths are relayed to other backends (see proxy.rs) instead of becoming the server's clients; paths the inspector serves take precedence.
+  pub proxy_routes: Vec<ProxyRoute>,
+  /// If given, the server is a node of a cluster (see cluster.rs): its broadcasts are relayed to the other nodes' clients, and theirs to its own.
+  pub cluster: Option<ClusterConfig>,
+  /// Whether to keep histograms of the outbound pipeline's latencies (see latency.rs and Server::latency()). They cost a few atomic adds per message and stage.
+  pub latency_histograms: bool,
+  /// What happens when a client falls so far behind the broadcasts that its queue is full: by default it misses the oldest (and they're counted against it); or broadcasts wait for it (see clients.rs).
+  pub lag_policy: LagPolicy,
+  /// How far clients' sender tasks may go batching their writes under load, to write less often (see batching.rs). Clients that aren't under load are written to as soon as there's something to send.
+  pub batching: Batching,
+  /// Most payload bytes the server's queues may hold at once, in broadcasts, targeted sends and received messages (see budget.rs): sends that would go over it fail, and client messages that would are dropped. If None, there's no cap (the bytes are still counted, in the stats).
+  pub memory_budget: Option<usize>,
+  /// Most bytes written to clients' sockets per second, all clients together (see rate_limit.rs): writes wait their turn beyond it, and the messages behind them wait in the clients' queues. If None, there's no cap.
+  pub rate_limit: Option<RateLimit>,
+  /// Most bytes written to each client's socket per second, by its tag (see Server::set_client_tag() and rate_limit.rs), on top of `rate_limit`.
+  pub client_rate_limits: ClientRateLimits,
+  /// How many worker threads the server's runtime has, and the cores they're pinned to, if any (see threading.rs).
+  pub threading: Threading,
+  /// If given, clients are pinged at its interval, and disconnected once they've missed too many pongs (see keepalive.rs). If None, idle connections are left to themselves.
+  pub keepalive: Option<Keepalive>,
+  /// If given, clients that neither send nor are sent a message for this long are disconnected (see keepalive.rs).
+  pub idle_timeout: Option<Duration>,
+  /// Whether clients' pings and pongs are reported to the consumer, as PingEvents (see Server::drain_ping_events()), for consumers that keep track of their clients' liveness themselves. Pongs answering the server's own keepalive pings aren't.
+  pub ping_events: bool,
+  /// Whether clients can ask the server's time, sending "time_sync:" messages that their receiver tasks answer with the server's clocks, rather than passing them on to the consumer, for syncing their clocks to the server's (see clock.rs).
+  pub time_sync: bool,
+  /// Whether clients can control playback, sending "playback:" messages (pause, resume, seek, speed) that their receiver tasks apply to the server's playback state, rather than passing them on to the consumer, for visualizers playing something back (see playback.rs).
+  pub playback_control: bool,
+  /// If given, clients' "input:" messages (cursor positions, slider values) are taken by their receiver tasks, rather than passed on to the consumer, and merged, each client's latest value of each input, into a snapshot taken at this interval (see inputs.rs), so many clients sending input at a high rate make the consumer no more work than one.
+  pub input_aggregation: Option<Duration>,
+  /// If given, the server misbehaves on purpose, for testing clients against a flaky network: it drops connections, holds back writes and reorders messages, at random but reproducibly (see chaos.rs). Not for production use.
+  pub chaos: Option<Chaos>,
+  /// If given, every client is written to as if over a link with this latency, jitter and bandwidth, to emulate distant, slow clients on a LAN (see link.rs). For testing only.
+  pub link: Option<LinkEmulation>,
+  /// If given, a watchdog thread reports the accept loop and clients' sender tasks when they stall or panic, as critical error events, and restarts them if asked to (see watchdog.rs).
+  pub watchdog: Option<Watchdog>,
+  /// If given, the server broadcasts a heartbeat (a small JSON message with a sequence number, the server's time and its client count) at its interval, from its own runtime, so clients can tell a consumer that's gone quiet from a dead connection (see heartbeat.rs).
+  pub heartbeat: Option<HeartbeatTopic>,
+  /// Topics whose samples (Server::send_sample()) go out at most at a given rate, the rest being dropped or averaged, so high-rate streams don't overwhelm clients (see decimation.rs).
+  pub topic_rates: HashMap<String, TopicRate>,
+  /// Topics whose frames (Server::send_frame()) each client is written at most at a given rate (in frames a second), to start with, e.g. for clients on slow devices (see frames.rs).
+  pub client_topic_rates: HashMap<String, f64>,
+  /// Whether clients can set their own topics' rates, sending "throttle:" messages that their receiver tasks take, rather than passing them on to the consumer (see frames.rs).
+  pub topic_throttle_requests: bool,
+  /// Groups of topics whose messages (Server::send_synced()) are tagged with a presentation timestamp and broadcast in its order, for clients to play them back together, keyed by the groups' names (see sync_groups.rs).
+  pub sync_groups: HashMap<String, Vec<String>>,
+  /// How long a sync group's messages are held, for those with earlier timestamps sent after them to catch up, before they go out; 0 sends them straight away, dropping any that come out of order.
+  pub sync_window: Duration,
+  /// If given, clients are offered these formats as they connect, and can declare their capabilities (the formats they decode, the resolution and topics they want), which their receiver tasks take, negotiating a format, rather than passing them on to the consumer (see capabilities.rs).
+  pub capabilities: Option<CapabilityOffer>,
+  /// Whether clients can speak rosbridge: subscribing to topics, which Server::publish_ros() publishes to, with JSON ops that their receiver tasks take rather than passing them on to the consumer (bar publishes), for ROS web tooling (see rosbridge.rs).
+  pub rosbridge: bool,
+  /// How the metrics logged with Server::log_scalar() and Server::log_histogram() are batched into frames, and how many of each tag's last points are kept for clients that connect later (see metrics.rs).
+  pub metrics: MetricsConfig,
+  /// If given, clients that offer the permessage-deflate extension are written the bigger messages deflated, each broadcast's deflated once, on threads of the server's own (see compression.rs). If None, every message goes out as it is.
+  pub compression: Option<Compression>,
+  /// If given, every websocket connection the server accepts or rejects, and every accepted one's closing, is appended to a rotating audit log file (see audit_log.rs).
+  pub audit_log: Option<AuditLog>,
+}
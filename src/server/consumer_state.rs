@@ -1,45 +1,295 @@
 // consumer_state.rs
 //
-// Internal handlers for managing static-scope (singleton) server state in a thread-safe manner.
+// Internal handlers for managing consumer-side server state in a thread-safe manner. Each running server has its own ServerState (so one process can run several servers on different ports); the module-level Python API works on a default instance.
 //
-// Static server state is guarded for thread-safe access using a blocking RwLock. This is definitely not optimal, and it'd probably be better to use tokio async locks and keep everything async, but I'm not sure what the best design for that is yet for a library receiving calls from the Python consumer thread. -Nick 2021-02-24
+// Server state used to be guarded by blocking RwLocks, one per channel end, which every call took (sends included) and which server startup and the relays, bridges and callbacks that borrow the receivers wrote to. Now each concern has its own mechanism, and none of the calls the consumer makes while a server runs takes a lock shared with anything else:
+// - The channel ends the consumer only ever uses by reference (the state watch, the broadcast and shutdown senders, the loopback and service connectors) are created before the ServerState and are plain fields: tokio's channel ends are Sync, so any number of threads can send on them at once.
+// - The two receivers (client messages and connection events) are ClaimableReceivers: drains share them through their own async mutex, and something that wants one to itself (a relay, a bridge, a callback, an event stream) claims it with an atomic flag rather than taking it out of the state and putting it back.
+// - Thread handles, which are set once and taken once to join the thread, are Slots: only ever locked to do that.
+//
+// The process-wide statics (the default server, the list of servers, the last error) are still CS items, RwLock<Option<T>>s; they're only written when a server starts (or an error is recorded), never by a server thread. A poisoned lock (a panic while it was held) is recovered rather than treated as an access failure: what's guarded can't be left half-modified by a panic.
 
-use std::{sync::{RwLock}};
-use tokio::sync::{broadcast, mpsc, watch};
+use std::{sync::{Arc, Mutex, PoisonError, RwLock, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}}, thread::JoinHandle};
+use tokio::sync::watch;
 
-type CS<T> = RwLock<Option<T>>;
-type WsMessage = tokio_tungstenite::tungstenite::Message;
+use super::{ServerConfig, ShutdownOptions, clients::{BroadcastQueue, ClientRegistry}, cluster::Cluster, decimation::Decimator, sync_groups::Sequencer, events::{ClientMessage, ConnectionEvent, PingEvent}, error_events::{self, Category, Severity}, inputs::InputAggregator, metrics::MetricsLog, notify::MessageNotifier, playback::Playback, queue, recording::Recorder, stats::ServerStats, taps::MessageTaps, tasks::TaskTracker, transport::LoopbackConnector};
 
-// Lazy Static
-// -----------
-//
-// Various RwLock<Option<T>>s (here short-handed to CS, for Consumer State) guard against thread-unsafe access to consumer state -- consisting mostly of channels used to communicate with the tokio runtime thread. Channels as a whole are thread-safe, but the channel ends -- Senders, Receivers -- are expected to be used from just one thread at a time! Hence the RwLock, so the consumer can't get cheeky with the channels when invoking library functions from different threads.
+pub type CS<T> = RwLock<Option<T>>;
+/// A receiver that several consumer threads may want to wait on. The async mutex lets a waiting thread hold it for as long as it waits, while others give up (or wait in turn, within their own timeouts).
+pub type SharedReceiver<T> = Arc<tokio::sync::Mutex<queue::Receiver<T>>>;
+
+/// One of a server's receivers: shared by the consumer's drains, unless something has claimed it for itself.
+pub struct ClaimableReceiver<T> {
+  rx: SharedReceiver<T>,
+  claimed: AtomicBool,
+  occupancy: queue::Occupancy<T>,
+}
+
+impl<T> ClaimableReceiver<T> {
+  pub fn new(rx: queue::Receiver<T>) -> ClaimableReceiver<T> {
+    let occupancy = rx.occupancy();
+    ClaimableReceiver { rx: Arc::new(tokio::sync::Mutex::new(rx)), claimed: AtomicBool::new(false), occupancy }
+  }
+
+  /// How many items are queued, and the queue's capacity, whoever has the receiver.
+  pub fn occupancy(&self) -> (usize, usize) {
+    self.occupancy.get()
+  }
+
+  /// The receiver, for draining, or None while it's claimed.
+  pub fn shared(&self) -> Option<SharedReceiver<T>> {
+    if self.claimed.load(Ordering::Acquire) { return None; }
+    Some(self.rx.clone())
+  }
+
+  /// Claims the receiver until release(), so drains see nothing; None if it's claimed already. Drains already under way finish first: the claimer should lock the receiver before receiving from it.
+  pub fn claim(&self) -> Option<SharedReceiver<T>> {
+    self.claimed.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok()?;
+    Some(self.rx.clone())
+  }
+
+  /// Gives a claimed receiver back to the drains.
+  pub fn release(&self) {
+    self.claimed.store(false, Ordering::Release);
+  }
+}
+
+/// A value that's put once and taken once, like a thread's JoinHandle (taken to join the thread). Its lock is only taken to do that, never on the send and drain paths.
+pub struct Slot<T>(Mutex<Option<T>>);
+
+impl<T> Slot<T> {
+  pub fn empty() -> Slot<T> {
+    Slot(Mutex::new(None))
+  }
+
+  pub fn put(&self, value: T) {
+    *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(value);
+  }
+
+  pub fn take(&self) -> Option<T> {
+    self.0.lock().unwrap_or_else(PoisonError::into_inner).take()
+  }
+}
+
+// Server State
+// ------------
 //
-// However, it's totally OK for the consumer to access *different* channel ends from different threads; e.g., to check for client messages on one thread while sending server messages from another.
+// (See the top of the file for how each part of it is shared between threads.)
 
-lazy_static! {
-  /// Consumer thread(s) receiver for whether the Tokio server thread is alive.
-  pub static ref CS_SER_ALIVE_RX: CS<watch::Receiver<bool>> =
-    RwLock::new(None);
+/// The consumer's ends of a server's channels, created along with the tokio thread's ends by server::start().
+pub struct ConsumerEnds {
+  pub ser_state_rx: watch::Receiver<RunState>,
+  pub cli_conn_rx: queue::Receiver<ConnectionEvent>,
+  pub cli_ping_rx: queue::Receiver<PingEvent>,
+  pub ser_msg_tx: Arc<BroadcastQueue>,
+  pub cli_msg_rx: queue::Receiver<ClientMessage>,
+  pub ser_req_shutdown_tx: watch::Sender<bool>,
+  pub ser_req_drain_tx: watch::Sender<bool>,
+  pub recorder: Arc<Recorder>,
+  pub loopback: Option<LoopbackConnector>,
+  #[cfg(feature = "tower")]
+  pub service: Option<super::service::ServiceConnector>,
+}
 
-  /// Consumer thread(s) receiver for events indicating newly-connected clients. The server-side consumer should drain this receiver regularly.
-  pub static ref CS_CLI_CONN_RX: CS<mpsc::Receiver<String>> =
-    RwLock::new(None);
+/// The consumer's side of one server: its channel ends, configuration, and thread. Created by server::start() and shared via an Arc.
+pub struct ServerState {
+  /// Unique (per process) id of this server instance.
+  pub id: u64,
+  /// The port the server was started with.
+  pub port: u32,
+  /// The port it's actually listening on: 0 until the tokio thread has bound it (which it sets before reporting Running), and for loopback servers. Differs from `port` when that was 0, i.e. the OS picked one.
+  pub bound_port: Arc<AtomicU32>,
+  /// The configuration the server was started with, for consumer-side behavior (e.g. how received messages are converted for Python).
+  pub config: ServerConfig,
+  /// Statistics for the server, shared with its tokio tasks.
+  pub stats: Arc<ServerStats>,
+  /// Connected clients, for targeted sends. Shared with the tokio tasks, which keep it up to date.
+  pub clients: Arc<ClientRegistry>,
+  /// Readiness descriptor for client messages, for event loops (see get_message_fd()). Shared with the tokio tasks, which notify it.
+  pub notifier: Arc<MessageNotifier>,
+  /// The server's cluster membership, if it's a cluster node (see cluster.rs): broadcasts are published to it, and its tokio tasks run the links to the other nodes.
+  pub cluster: Option<Arc<Cluster>>,
+  /// The clients' playback state, if the server takes playback controls (ServerConfig::playback_control; see playback.rs). Shared with the receiver tasks, which apply them.
+  pub playback: Option<Arc<Playback>>,
+  /// The clients' aggregated input, if the server aggregates it (ServerConfig::input_aggregation; see inputs.rs). Shared with the receiver tasks, which merge it in, and the tokio task that takes its snapshots.
+  pub inputs: Option<Arc<InputAggregator>>,
+  /// The decimated topics' held samples, if any topics have a maximum rate (ServerConfig::topic_rates; see decimation.rs). Shared with the tokio task that sends them.
+  pub decimator: Option<Arc<Decimator>>,
+  /// The sync groups' held messages, if there are any groups (ServerConfig::sync_groups; see sync_groups.rs). Shared with the tokio task that sends them.
+  pub sequencer: Option<Arc<Sequencer>>,
+  /// The logged metrics (see metrics.rs). Shared with the tokio task that broadcasts them, and the receiver tasks, which write new clients their history.
+  pub metrics: Arc<MetricsLog>,
+  /// The taps on the client messages (see taps.rs). Shared with the receiver tasks, which copy the messages to them.
+  pub taps: Arc<MessageTaps>,
+
+  /// Consumer thread(s) receiver for the server's lifecycle state, as reported by the Tokio server thread. Receivers are cloned out of here to wait on state changes (see wait_until_started()), so any number of threads can wait at once.
+  pub ser_state_rx: watch::Receiver<RunState>,
+
+  /// Consumer thread(s) receiver for events indicating clients connecting and disconnecting. The server-side consumer should drain this receiver regularly.
+  pub cli_conn_rx: ClaimableReceiver<ConnectionEvent>,
 
-  /// Consumer thread(s) clone of the server message transmitter, used to subscribe new receivers for any new connections.
+  /// Consumer thread(s) receiver for clients' pings and pongs, if the server was configured to report them (ServerConfig::ping_events).
+  pub cli_ping_rx: ClaimableReceiver<PingEvent>,
+
+  /// Consumer thread(s) end of the server message broadcast queue, used to send to every client (the tokio thread subscribes each new connection to it).
   ///
-  /// This is a clone of the Sender owned by the TokioState struct.
-  pub static ref CS_SER_MSG_TX: CS<broadcast::Sender<Vec<WsMessage>>> =
-    RwLock::new(None);
+  /// Shared with the tokio server.
+  pub ser_msg_tx: Arc<BroadcastQueue>,
 
   /// Consumer thread(s) receiver for messages from any connected clients. The server-side consumer should drain this receiver regularly.
-  pub static ref CS_CLI_MSG_RX: CS<mpsc::Receiver<WsMessage>> =
-    RwLock::new(None);
+  pub cli_msg_rx: ClaimableReceiver<ClientMessage>,
 
   /// Consumer thread(s) transmitter for requesting tokio to shut down.
-  pub static ref CS_SER_REQ_SHUTDOWN_TX: CS<watch::Sender<bool>> =
+  pub ser_req_shutdown_tx: watch::Sender<bool>,
+
+  /// Consumer thread(s) transmitter for requesting tokio to stop accepting connections, while it goes on serving the clients it has (see Server::begin_draining()).
+  pub ser_req_drain_tx: watch::Sender<bool>,
+
+  /// How the server shuts down (see Server::shutdown_with()): set before the shutdown is requested, and read by the tokio tasks once they see the request.
+  pub shutdown_options: Arc<Mutex<ShutdownOptions>>,
+
+  /// The session recording in progress, if any (see Server::start_recording()). Shared with the tokio tasks, which record to it.
+  pub recorder: Arc<Recorder>,
+
+  /// The tokio tasks the shutdown waits for, and those it aborted (see tasks.rs). Shared with the tokio tasks, which register themselves.
+  pub tasks: Arc<TaskTracker>,
+
+  /// Handle to the server thread, taken by the consumer to join the thread after requesting shutdown.
+  pub ser_thread: Slot<JoinHandle<Result<String, String>>>,
+
+  /// Makes loopback connections, if the server was started with the loopback transport (see transport.rs).
+  pub loopback: Option<LoopbackConnector>,
+
+  /// Mints WebSocketServices, if the server was started with the service transport (see service.rs).
+  #[cfg(feature = "tower")]
+  pub service: Option<super::service::ServiceConnector>,
+
+  /// Handle to the handler dispatch thread, if the server was started with a ServerHandler (see handler.rs); joined after the server thread.
+  pub handler_thread: Slot<JoinHandle<()>>,
+}
+
+/// Where a server is in its lifecycle, as reported by its tokio thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RunState {
+  /// The server thread has been launched and is binding its listener.
+  Starting,
+  /// Listening, and serving clients.
+  Running,
+  /// Not accepting connections any more. Either draining was requested (see Server::begin_draining()), and the clients still connected are served as before, or shutdown was: clients are being sent close frames, and their connections wound down.
+  Draining,
+  /// Connections are done (or timed out), and the server's runtime is being torn down.
+  Stopping,
+  /// The server thread has finished.
+  Stopped,
+  /// The server couldn't start (e.g. its port is already in use), or its thread exited unexpectedly. Holds the reason.
+  Failed(String),
+}
+
+impl RunState {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      RunState::Starting  => "starting",
+      RunState::Running   => "running",
+      RunState::Draining  => "draining",
+      RunState::Stopping  => "stopping",
+      RunState::Stopped   => "stopped",
+      RunState::Failed(_) => "failed",
+    }
+  }
+
+  /// Whether the server thread is (still) doing anything.
+  pub fn is_alive(&self) -> bool {
+    matches!(self, RunState::Starting | RunState::Running | RunState::Draining | RunState::Stopping)
+  }
+
+  /// The state reported on `rx`, or Failed if the server thread went away without finishing (i.e. it panicked).
+  pub fn observe(rx: &watch::Receiver<RunState>) -> RunState {
+    let state = rx.borrow().clone();
+    if state.is_alive() && rx.has_changed().is_err() {
+      return RunState::Failed("The server thread exited unexpectedly.".to_string());
+    }
+    state
+  }
+}
+
+static NEXT_SERVER_ID: AtomicU64 = AtomicU64::new(1);
+
+impl ServerState {
+  pub fn new(port: u32, config: ServerConfig, stats: Arc<ServerStats>, ends: ConsumerEnds) -> ServerState {
+    let cluster = config.cluster.as_ref().map(|cluster| Arc::new(Cluster::new(cluster)));
+    let playback = config.playback_control.then(|| Arc::new(Playback::new()));
+    let inputs = config.input_aggregation.map(|interval| Arc::new(InputAggregator::new(interval)));
+    let decimator = (!config.topic_rates.is_empty()).then(|| Arc::new(Decimator::new(&config.topic_rates, stats.clone(), ends.ser_msg_tx.clone())));
+    let sequencer = (!config.sync_groups.is_empty()).then(|| Arc::new(Sequencer::new(&config.sync_groups, config.sync_window, stats.clone(), ends.ser_msg_tx.clone())));
+    let metrics = Arc::new(MetricsLog::new(config.metrics, stats.clone(), ends.ser_msg_tx.clone()));
+    let tasks = Arc::new(TaskTracker::new(stats.clone()));
+    ServerState {
+      id: NEXT_SERVER_ID.fetch_add(1, Ordering::Relaxed),
+      port,
+      bound_port: Arc::new(AtomicU32::new(0)),
+      config,
+      stats,
+      clients: Arc::new(ClientRegistry::new()),
+      notifier: Arc::new(MessageNotifier::new()),
+      cluster,
+      playback,
+      inputs,
+      decimator,
+      sequencer,
+      metrics,
+      taps: Arc::default(),
+      ser_state_rx: ends.ser_state_rx,
+      cli_conn_rx: ClaimableReceiver::new(ends.cli_conn_rx),
+      cli_ping_rx: ClaimableReceiver::new(ends.cli_ping_rx),
+      ser_msg_tx: ends.ser_msg_tx,
+      cli_msg_rx: ClaimableReceiver::new(ends.cli_msg_rx),
+      ser_req_shutdown_tx: ends.ser_req_shutdown_tx,
+      ser_req_drain_tx: ends.ser_req_drain_tx,
+      shutdown_options: Arc::default(),
+      recorder: ends.recorder,
+      tasks,
+      ser_thread: Slot::empty(),
+      loopback: ends.loopback,
+      #[cfg(feature = "tower")]
+      service: ends.service,
+      handler_thread: Slot::empty(),
+    }
+  }
+
+  pub fn run_state(&self) -> RunState {
+    RunState::observe(&self.ser_state_rx)
+  }
+
+  /// Whether the server thread is alive, i.e. starting, running, or shutting down.
+  pub fn is_alive(&self) -> bool {
+    self.run_state().is_alive()
+  }
+
+  /// Whether the consumer has asked the server to shut down.
+  pub fn shutdown_requested(&self) -> bool {
+    *self.ser_req_shutdown_tx.borrow()
+  }
+}
+
+// Lazy Static
+// -----------
+//
+
+lazy_static! {
+  /// The server the module-level API functions (start_server(), try_send_messages(), ...) operate on.
+  pub static ref CS_DEFAULT_SERVER: CS<Arc<ServerState>> =
     RwLock::new(None);
 
+  /// Every server started in this process that hadn't yet stopped when the next one was started (so at least every running server), for shutting them all down at interpreter exit.
+  pub static ref CS_SERVERS: CS<Vec<Arc<ServerState>>> =
+    RwLock::new(Some(Vec::new()));
+
+  /// Small runtime (driving only timers) used to block the consumer thread on async channel operations with timeouts. It's independent of the servers' runtimes so blocking calls stay valid while a server is starting up or shutting down.
+  static ref CONSUMER_RT: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+    .enable_time()
+    .build()
+    .expect("Failed to create consumer-side runtime.");
+
   /// Very coarse way of providing some quick error reporting to the consumer without panicking.
   static ref LAST_ERROR: CS<String> = RwLock::new(None);
 }
@@ -50,49 +300,48 @@ lazy_static! {
 // This is probably not a very good error API. -Nick 2021-04-21
 
 /// Attempts to record the passed &str to the thread-safe LAST_ERROR storage. The method may fail silently if it can't get write access to LAST_ERROR, hence, "weakly". This is very likely to clobber errors if more than one propagates in short succession across more than one thread.
+///
+/// The error is also queued as an (internal) error event, which doesn't get clobbered; see error_events.
 pub fn weakly_record_error(msg: String) {
-  let last_err = LAST_ERROR.try_write();
-  if last_err.is_err() { return; /* silently fail. */ }
-  let mut last_err = last_err.unwrap();
+  weakly_record_error_in(Category::Internal, msg);
+}
+
+/// Like weakly_record_error(), with the category to queue the error event under.
+pub fn weakly_record_error_in(category: Category, msg: String) {
+  error_events::record(Severity::Error, category, msg.clone(), None);
+  weakly_record_last_error(msg);
+}
+
+/// Records the error to LAST_ERROR only, without queueing an error event. Used for state that isn't available because the server isn't running, which is routine (e.g. draining before start) and is reported to Python by the API itself where it matters.
+fn weakly_record_last_error(msg: String) {
+  let mut last_err = LAST_ERROR.write().unwrap_or_else(PoisonError::into_inner);
 
   *last_err = Some(msg);
 }
 
 /// Returns the last error if possible. Returns None only if there is no last error.
 pub fn try_get_last_error() -> Option<String> {
-  // Attempt to lock and unwrap.
-  let err_store = LAST_ERROR.read();
-  if err_store.is_err() { return Some("Couldn't get last error.".to_string()); }
-
-  // Convert (possibly poisoned) lock into a Result.
-  let err_store = err_store.as_deref();
-  if err_store.is_err() { return Some("Couldn't get last error.".to_string()); }
+  let err_store = LAST_ERROR.read().unwrap_or_else(PoisonError::into_inner);
 
   // If the error is None, great! Otherwise return a copy of the string content.
-  let err_store = err_store.unwrap().as_ref();
-  err_store.map_or(None, |s| Some(String::from(s)))
+  err_store.as_ref().map(String::from)
 }
 
 // State API
 // ---------
 //
+// (Poisoned locks are recovered; see the thread-safety rules at the top of the file.)
 
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and an operating function to do something with read access to that static (e.g. receive a message from a consumer channel).
-pub fn read<T, U, R, F>(lazy_static_item: &R, f: F) -> Option<U>
+/// Pass one of the CS (consumer state) items -- a "CS_" static -- and an operating function to do something with read access to it (e.g. receive a message from a consumer channel).
+pub fn read<T, U, F>(lazy_static_item: &CS<T>, f: F) -> Option<U>
 where
   F: FnOnce(&T) -> U,
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
 {
-  let read_guard = lazy_static_item.read();
-  if read_guard.is_err() {
-    weakly_record_error(format!("Failed to get read access to {}.", std::any::type_name::<R>()));
-    return None;
-  }
-  let read_guard = read_guard.unwrap();
+  let read_guard = lazy_static_item.read().unwrap_or_else(PoisonError::into_inner);
 
   let item = read_guard.as_ref();
   if item.is_none() {
-    weakly_record_error(format!("Failed to get read access to {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<R>()));
+    weakly_record_last_error(format!("Failed to get read access to {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<T>()));
     return None;
   }
   let item = item.unwrap();
@@ -100,22 +349,16 @@ where
   Some(f(item))
 }
 
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and an operating function to do something with mutable access to that static (e.g. send a message using a Sender).
-pub fn mutate<T, U, R, F>(lazy_static_item: &R, f: F) -> Option<U>
+/// Pass one of the CS (consumer state) items and an operating function to do something with mutable access to it (e.g. send a message using a Sender).
+pub fn mutate<T, U, F>(lazy_static_item: &CS<T>, f: F) -> Option<U>
 where
   F: FnOnce(&mut T) -> U,
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
 {
-  let write_guard = lazy_static_item.write();
-  if write_guard.is_err() {
-    weakly_record_error(format!("Failed to get write access to {}.", std::any::type_name::<R>()));
-    return None;
-  }
-  let mut write_guard = write_guard.unwrap();
+  let mut write_guard = lazy_static_item.write().unwrap_or_else(PoisonError::into_inner);
 
   let state = write_guard.as_mut();
   if state.is_none() {
-    weakly_record_error(format!("Failed to mutable ref for {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<R>()));
+    weakly_record_last_error(format!("Failed to mutable ref for {}, as it hasn't been created yet. Is the server running?", std::any::type_name::<T>()));
     return None;
   }
   let state = state.unwrap();
@@ -124,18 +367,29 @@ where
 }
 
 
-/// Pass one of the "CS_" (consumer state) statics available in the consumer_state module and a value of the inner type to set the RwLock<Option<T>> with Some<T>. This is used internally by the server::start() function to initialize consumer-side channels.
-pub fn set_value<T, R>(lazy_static_item: &R, new_val: T) -> Result<(), ()>
-where
-  R: std::ops::Deref<Target = RwLock<Option<T>>>
-{
-  let write_guard = lazy_static_item.write();
-  if write_guard.is_err() {
-    weakly_record_error(format!("Failed to get write access to {} to set its value.", std::any::type_name::<R>()));
-    return Err(());
-  }
-  let mut write_guard = write_guard.unwrap();
+/// Pass one of the CS (consumer state) items and a value of the inner type to set the RwLock<Option<T>> with Some<T>. (Only the Python bindings' statics are set after they're created.)
+#[cfg(feature = "python")]
+pub(crate) fn set_value<T>(lazy_static_item: &CS<T>, new_val: T) -> Result<(), ()> {
+  let mut write_guard = lazy_static_item.write().unwrap_or_else(PoisonError::into_inner);
 
   (*write_guard) = Some(new_val);
   Ok(())
 }
+
+/// Pass one of the CS (consumer state) items to take its value, leaving None in its place. This is used for state that can only be used once, like the log bridge's thread.
+pub fn take_value<T>(lazy_static_item: &CS<T>) -> Option<T> {
+  let mut write_guard = lazy_static_item.write().unwrap_or_else(PoisonError::into_inner);
+
+  write_guard.take()
+}
+
+// Blocking API
+// ------------
+//
+
+/// Blocks the calling (consumer) thread on the passed future, e.g. receiving from a consumer channel with a tokio::time timeout. Callers should release the GIL first.
+///
+/// Timers must be created inside the future (e.g. in an async block), since that's where the runtime context is available. Any number of consumer threads may block at once; they share the runtime's timer.
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+  CONSUMER_RT.block_on(future)
+}
@@ -0,0 +1,362 @@
+// decimation.rs
+//
+// Server-side decimation (ServerConfig::topic_rates), for high-rate streams the consumer publishes every sample of: a 1 kHz sensor, say, which browsers can't draw (or even parse) at that rate. Samples sent under a topic with a maximum rate (Server::send_sample()) go out at most that often: the first straight away, and those that come sooner after the last one sent are held, and go out as the interval's up. With DecimationMode::Drop, that's the latest sample held, the rest being dropped; with DecimationMode::Average, it's their average, field by field: a text sample that's a JSON number, array of numbers, or flat object (whose numbers are averaged, and other values taken from the latest sample). Samples that can't be averaged (binary ones, other JSON, or ones not shaped like those before them) go out as with Drop. Either way, the samples that didn't go out on their own are counted in the stats (samples_decimated).
+//
+// What goes out is broadcast like a heartbeat (see heartbeat.rs): queued behind the broadcasts before it, but never waiting for room, whatever the lag policy, and only to this server's clients, not a cluster's other nodes. A sample that would take the server over its memory budget is dropped. Each topic holds at most one sample (or average) at a time, so however fast the consumer publishes, what's held doesn't grow. Samples on topics without a rate are broadcast as they're sent, like Server::send()'s.
+
+use std::{collections::HashMap, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use tokio::sync::{Notify, watch};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::BroadcastQueue, outbound::Outbound, stats::{DropReason, ServerStats}};
+
+/// The longest interval between a topic's samples: a year, so it can always be added to an Instant.
+pub const MAX_INTERVAL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// What a decimated topic sends of the samples held between intervals.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecimationMode {
+  /// The latest.
+  Drop,
+  /// Their average, if they can be averaged; the latest otherwise.
+  Average,
+}
+
+/// A topic's maximum rate (ServerConfig::topic_rates).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TopicRate {
+  /// Samples per second, at most.
+  pub max_rate_hz: f64,
+  pub mode: DecimationMode,
+}
+
+impl TopicRate {
+  /// At most `max_rate_hz` samples a second, the latest of those held.
+  pub fn new(max_rate_hz: f64) -> TopicRate {
+    TopicRate { max_rate_hz, mode: DecimationMode::Drop }
+  }
+
+  /// At most `max_rate_hz` samples a second, averaging those held.
+  pub fn averaged(max_rate_hz: f64) -> TopicRate {
+    TopicRate { max_rate_hz, mode: DecimationMode::Average }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    let interval = Duration::try_from_secs_f64(1.0 / self.max_rate_hz).ok().filter(|interval| *interval <= MAX_INTERVAL);
+    if !(self.max_rate_hz.is_finite() && self.max_rate_hz > 0.0) || interval.is_none() {
+      return Err(format!("a topic's maximum rate must be more than 0 Hz, and at least one sample a year (not {})", self.max_rate_hz));
+    }
+    Ok(())
+  }
+
+  /// The least time between samples (at most MAX_INTERVAL, for a rate that wouldn't pass validate()).
+  pub fn interval(&self) -> Duration {
+    Duration::try_from_secs_f64(1.0 / self.max_rate_hz).map_or(MAX_INTERVAL, |interval| interval.min(MAX_INTERVAL))
+  }
+}
+
+/// A topic's samples held until its interval's up.
+struct Held {
+  latest: Outbound,
+  samples: u64,
+  /// The sum of the samples since the last that couldn't be averaged with those before it, and how many that is; None if they can't be averaged.
+  sum: Option<(Sample, u64)>,
+}
+
+struct Topic {
+  rate: TopicRate,
+  interval: Duration,
+  last_sent: Option<Instant>,
+  held: Option<Held>,
+}
+
+impl Topic {
+  fn due(&self) -> Option<Instant> {
+    match (&self.held, self.last_sent) {
+      (None, _) => None,
+      (Some(_), None) => Some(Instant::now()),
+      (Some(_), Some(last_sent)) => Some(last_sent + self.interval),
+    }
+  }
+}
+
+/// The decimated topics' state, shared by the consumer, which offers samples, and the server's task that sends those held as their intervals are up.
+pub struct Decimator {
+  topics: Mutex<HashMap<String, Topic>>,
+  /// Wakes the task when a topic starts holding a sample, which may be due before the one it's waiting for.
+  held: Notify,
+  stats: Arc<ServerStats>,
+  ser_msg_tx: Arc<BroadcastQueue>,
+}
+
+impl Decimator {
+  pub fn new(rates: &HashMap<String, TopicRate>, stats: Arc<ServerStats>, ser_msg_tx: Arc<BroadcastQueue>) -> Decimator {
+    let topics = rates.iter().map(|(topic, rate)| (topic.clone(), Topic { rate: *rate, interval: rate.interval(), last_sent: None, held: None })).collect();
+    Decimator { topics: Mutex::new(topics), held: Notify::new(), stats, ser_msg_tx }
+  }
+
+  /// Whether `topic` has a maximum rate.
+  pub fn decimates(&self, topic: &str) -> bool {
+    self.topics.lock().unwrap_or_else(PoisonError::into_inner).contains_key(topic)
+  }
+
+  /// Sends a sample on a decimated topic straight away, if the topic's interval's up (and nothing's held); holds it otherwise.
+  pub fn offer(&self, topic: &str, message: Outbound) {
+    let mut topics = self.topics.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(state) = topics.get_mut(topic) else { return; };
+    let now = Instant::now();
+    let due = state.last_sent.is_none_or(|last_sent| now >= last_sent + state.interval);
+    if due && state.held.is_none() {
+      state.last_sent = Some(now);
+      self.broadcast(message);
+      return;
+    }
+    let sample = match state.rate.mode {
+      DecimationMode::Average => message.text_data().and_then(Sample::parse),
+      DecimationMode::Drop => None,
+    };
+    match &mut state.held {
+      Some(held) => {
+        held.sum = match (held.sum.take(), sample) {
+          (Some((mut sum, count)), Some(sample)) => if sum.add(&sample) { Some((sum, count + 1)) } else { Some((sample, 1)) },
+          (_, sample) => sample.map(|sample| (sample, 1)),
+        };
+        held.samples += 1;
+        OUTBOUND.recycle_messages(vec![std::mem::replace(&mut held.latest, message)]);
+      }
+      None => {
+        state.held = Some(Held { latest: message, samples: 1, sum: sample.map(|sample| (sample, 1)) });
+        self.held.notify_one();
+      }
+    }
+  }
+
+  /// When the next held sample's due, if any is held.
+  fn next_due(&self) -> Option<Instant> {
+    self.topics.lock().unwrap_or_else(PoisonError::into_inner).values().filter_map(Topic::due).min()
+  }
+
+  /// Sends the held samples that are due.
+  fn send_due(&self) {
+    let now = Instant::now();
+    let mut topics = self.topics.lock().unwrap_or_else(PoisonError::into_inner);
+    for state in topics.values_mut() {
+      if state.due().is_none_or(|due| due > now) { continue; }
+      let Some(held) = state.held.take() else { continue; };
+      state.last_sent = Some(now);
+      let message = match held.sum {
+        Some((sum, count)) if count > 1 => match sum.average(count) {
+          Some(average) => {
+            OUTBOUND.recycle_messages(vec![held.latest]);
+            Outbound::from(Message::Text(average))
+          }
+          None => held.latest,
+        }
+        _ => held.latest,
+      };
+      // (The held samples go out as one: all but one of them didn't go out on their own.)
+      if held.samples > 1 { self.stats.samples_decimated(held.samples - 1); }
+      self.broadcast(message);
+    }
+  }
+
+  /// Forgets the held samples, returning them to the pool.
+  fn clear(&self) {
+    let mut topics = self.topics.lock().unwrap_or_else(PoisonError::into_inner);
+    let held = topics.values_mut().filter_map(|state| state.held.take()).map(|held| held.latest).collect();
+    OUTBOUND.recycle_messages(held);
+  }
+
+  fn broadcast(&self, message: Outbound) {
+    let messages = vec![message];
+    // (Sending fails when no clients are connected, which is fine: nobody missed it.)
+    match self.stats.memory().charge(messages[0].len(), 1) {
+      Ok(charge) => { if let Err(unsent) = self.ser_msg_tx.send(messages, charge) { OUTBOUND.recycle_shared(unsent); } }
+      Err(err) => {
+        log_debug!("[decimation] Dropped a sample: {}", err);
+        self.stats.messages_dropped(DropReason::OverBudget, 1);
+        OUTBOUND.recycle_messages(messages);
+      }
+    }
+  }
+}
+
+/// Sends the decimated topics' held samples as they're due, until the server shuts down.
+pub async fn send_held_samples(decimator: Arc<Decimator>, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  loop {
+    let due = decimator.next_due();
+    let wait = async {
+      match due {
+        Some(due) => tokio::time::sleep_until(tokio::time::Instant::from_std(due)).await,
+        None => std::future::pending().await,
+      }
+    };
+    tokio::select! {
+      _ = wait => decimator.send_due(),
+      _ = decimator.held.notified() => {}
+      _ = ser_req_shutdown_rx.changed() => {
+        if *ser_req_shutdown_rx.borrow() { break; }
+      }
+    }
+  }
+  decimator.clear();
+}
+
+/// A text sample that can be averaged.
+#[derive(Clone, Debug, PartialEq)]
+enum Sample {
+  Number(f64),
+  Array(Vec<f64>),
+  /// Its keys (as they were written) and values, in order.
+  Object(Vec<(String, Field)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Field {
+  Number(f64),
+  /// Any other value (a string, true, false or null), as it was written.
+  Other(String),
+}
+
+impl Sample {
+  /// The sample a message is, if it's a JSON number, array of numbers, or flat object.
+  fn parse(text: &str) -> Option<Sample> {
+    let mut json = Json { text, at: 0 };
+    let sample = match json.peek()? {
+      b'[' => {
+        json.at += 1;
+        let mut numbers = vec![];
+        if !json.eat(b']') {
+          loop {
+            numbers.push(json.number()?);
+            if json.eat(b']') { break; }
+            json.expect(b',')?;
+          }
+        }
+        Sample::Array(numbers)
+      }
+      b'{' => {
+        json.at += 1;
+        let mut fields = vec![];
+        if !json.eat(b'}') {
+          loop {
+            let key = json.string()?.to_string();
+            json.expect(b':')?;
+            let value = match json.peek()? {
+              b'"' => Field::Other(json.string()?.to_string()),
+              b't' | b'f' | b'n' => Field::Other(json.literal()?.to_string()),
+              _ => Field::Number(json.number()?),
+            };
+            fields.push((key, value));
+            if json.eat(b'}') { break; }
+            json.expect(b',')?;
+          }
+        }
+        Sample::Object(fields)
+      }
+      _ => Sample::Number(json.number()?),
+    };
+    json.peek().is_none().then_some(sample)
+  }
+
+  /// Adds a sample shaped like this one to it (taking its other values), returning false if it isn't.
+  fn add(&mut self, sample: &Sample) -> bool {
+    match (self, sample) {
+      (Sample::Number(sum), Sample::Number(number)) => { *sum += number; true }
+      (Sample::Array(sums), Sample::Array(numbers)) if sums.len() == numbers.len() => {
+        for (sum, number) in sums.iter_mut().zip(numbers) { *sum += number; }
+        true
+      }
+      (Sample::Object(sums), Sample::Object(fields)) if sums.len() == fields.len() => {
+        let same_shape = sums.iter().zip(fields).all(|((key, sum), (other_key, field))| key == other_key && matches!((sum, field), (Field::Number(_), Field::Number(_)) | (Field::Other(_), Field::Other(_))));
+        if !same_shape { return false; }
+        for ((_, sum), (_, field)) in sums.iter_mut().zip(fields) {
+          match (sum, field) {
+            (Field::Number(sum), Field::Number(number)) => { *sum += number; }
+            (sum, field) => { *sum = field.clone(); }
+          }
+        }
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// The average of `count` samples this is the sum of, as JSON; None if it overflowed.
+  fn average(&self, count: u64) -> Option<String> {
+    let average = |sum: f64| Some(sum / count as f64).filter(|average| average.is_finite());
+    Some(match self {
+      Sample::Number(sum) => average(*sum)?.to_string(),
+      Sample::Array(sums) => {
+        let averages = sums.iter().map(|sum| average(*sum).map(|average| average.to_string())).collect::<Option<Vec<_>>>()?;
+        format!("[{}]", averages.join(","))
+      }
+      Sample::Object(fields) => {
+        let fields = fields.iter().map(|(key, field)| match field {
+          Field::Number(sum) => average(*sum).map(|average| format!("{}:{}", key, average)),
+          Field::Other(value) => Some(format!("{}:{}", key, value)),
+        }).collect::<Option<Vec<_>>>()?;
+        format!("{{{}}}", fields.join(","))
+      }
+    })
+  }
+}
+
+/// Just enough of a JSON reader for samples.
+struct Json<'a> {
+  text: &'a str,
+  at: usize,
+}
+
+impl<'a> Json<'a> {
+  /// The next byte that isn't whitespace, skipping to it.
+  fn peek(&mut self) -> Option<u8> {
+    let rest = &self.text.as_bytes()[self.at..];
+    self.at += rest.iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+    self.text.as_bytes().get(self.at).copied()
+  }
+
+  fn eat(&mut self, byte: u8) -> bool {
+    let next = self.peek() == Some(byte);
+    if next { self.at += 1; }
+    next
+  }
+
+  fn expect(&mut self, byte: u8) -> Option<()> {
+    self.eat(byte).then_some(())
+  }
+
+  fn number(&mut self) -> Option<f64> {
+    self.peek()?;
+    let rest = &self.text[self.at..];
+    let len = rest.bytes().take_while(|byte| matches!(byte, b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E')).count();
+    let number = rest[..len].parse::<f64>().ok().filter(|number| number.is_finite())?;
+    self.at += len;
+    Some(number)
+  }
+
+  /// A string, quotes and escapes and all.
+  fn string(&mut self) -> Option<&'a str> {
+    if self.peek()? != b'"' { return None; }
+    let start = self.at;
+    let mut escaped = false;
+    for (offset, byte) in self.text.as_bytes()[start + 1..].iter().enumerate() {
+      match (escaped, byte) {
+        (false, b'"') => {
+          self.at = start + offset + 2;
+          return Some(&self.text[start..self.at]);
+        }
+        (false, b'\\') => { escaped = true; }
+        _ => { escaped = false; }
+      }
+    }
+    None
+  }
+
+  fn literal(&mut self) -> Option<&'a str> {
+    self.peek()?;
+    let literal = *["true", "false", "null"].iter().find(|literal| self.text[self.at..].starts_with(*literal))?;
+    self.at += literal.len();
+    Some(literal)
+  }
+}
@@ -0,0 +1,74 @@
+// diagnostics.rs
+//
+// Resource diagnostics (Server::diagnostics()), for telling whether a long-running server leaks: what it holds right now (open sockets, running tasks, registered clients, what's in its queues and the memory that takes), where the counts in stats.rs only ever go up. A server whose clients have all gone should be back where it started: no sockets or registered clients left, no tasks (aside from the links to cluster peers), and empty queues. Anything that goes on growing across snapshots taken at quiet moments is being held on to.
+//
+// Reading them takes no lock the send and drain paths wait on for long: the client registry's read lock, and each queue's own, just long enough to count.
+
+use super::{buffer_pool::OUTBOUND, clients::BROADCAST_QUEUE_LEN, consumer_state::ServerState};
+
+/// How full one of the server's queues is.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueOccupancy {
+  pub queued: usize,
+  /// How many it holds before whatever fills it waits (or, for broadcasts, before the slowest client misses them).
+  pub capacity: usize,
+}
+
+impl From<(usize, usize)> for QueueOccupancy {
+  fn from((queued, capacity): (usize, usize)) -> QueueOccupancy {
+    QueueOccupancy { queued, capacity }
+  }
+}
+
+/// What a server holds, as returned by Server::diagnostics(). A snapshot, like a StatsSnapshot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostics {
+  /// Accepted connections not yet closed: clients', and those still being routed or served otherwise (plain HTTP requests, inspector feeds, proxied connections, other cluster nodes' links).
+  pub open_sockets: u64,
+  /// The server's running tasks (see tasks.rs): one per connection being routed, two per client (its sender and receiver tasks), and one per link to a cluster peer. Those that only live as long as the server (the inspector's feed, the heartbeat topic) aren't counted.
+  pub tasks: usize,
+  /// Clients counted as connected, and those registered for targeted sends: the same, but for a moment as a client connects or disconnects.
+  pub connected_clients: u64,
+  pub registered_clients: usize,
+  /// Subscriptions to the broadcasts: one per client (and connection about to become one), plus the inspector's and a session recording's.
+  pub broadcast_subscribers: usize,
+  /// Broadcast batches queued for the slowest subscriber.
+  pub broadcasts: QueueOccupancy,
+  /// Targeted sends queued, for every client together.
+  pub targeted_sends: QueueOccupancy,
+  /// Client messages, connection events and ping events waiting for the consumer.
+  pub client_messages: QueueOccupancy,
+  pub connection_events: QueueOccupancy,
+  pub ping_events: QueueOccupancy,
+  /// Frames pending for every client together (see frames.rs): at most one per topic per client, so this levels off however slow the clients are.
+  pub pending_frames: usize,
+  /// Payload bytes held by the queues (as in StatsSnapshot::queued_bytes).
+  pub queued_bytes: u64,
+  /// Bytes held by the pool of buffers kept for reuse (see buffer_pool.rs). The pool is shared by every server in the process, and capped, so this levels off rather than growing.
+  pub pooled_buffer_bytes: u64,
+}
+
+impl Diagnostics {
+  pub(crate) fn take(state: &ServerState) -> Diagnostics {
+    Diagnostics {
+      open_sockets: state.stats.open_sockets(),
+      tasks: state.tasks.running(),
+      connected_clients: state.stats.current_clients(),
+      registered_clients: state.clients.registered(),
+      broadcast_subscribers: state.ser_msg_tx.subscribers(),
+      broadcasts: QueueOccupancy { queued: state.ser_msg_tx.queued(), capacity: BROADCAST_QUEUE_LEN },
+      targeted_sends: state.clients.queued_sends().into(),
+      client_messages: state.cli_msg_rx.occupancy().into(),
+      connection_events: state.cli_conn_rx.occupancy().into(),
+      ping_events: state.cli_ping_rx.occupancy().into(),
+      pending_frames: state.clients.pending_frames(),
+      queued_bytes: state.stats.memory().resident() as u64,
+      pooled_buffer_bytes: OUTBOUND.pooled_bytes() as u64,
+    }
+  }
+
+  /// Roughly the heap the server's queues hold: their payload bytes, and the pooled buffers. (Not what the messages' bookkeeping takes, which is small beside payloads of any size.)
+  pub fn heap_bytes(&self) -> u64 {
+    self.queued_bytes + self.pooled_buffer_bytes
+  }
+}
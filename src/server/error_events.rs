@@ -0,0 +1,172 @@
+// error_events.rs
+//
+// Queue of structured error events, recorded by both the tokio server tasks and the consumer-side API, and drained by the consumer. Unlike the single last-error string, errors that happen between polls aren't clobbered (up to MAX_QUEUED_EVENTS of them).
+//
+// Every event is also kept in a ring of the most recent ones, which isn't drained: it's for looking back at what happened (e.g. after noticing an error storm in the stats), independently of whoever consumes the queue. And it's logged in the event log (see event_log.rs), among the servers' other events.
+
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use super::event_log;
+
+/// Maximum number of undrained events kept. When full, the oldest events are dropped to make room.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+/// How many recent events are kept by default (see set_recent_capacity()).
+pub const DEFAULT_RECENT_CAPACITY: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+  /// Something went wrong with a single connection or request; the server carries on.
+  Warning,
+  /// Something went wrong with the server itself, or with an API call.
+  Error,
+  /// One of the server's own tasks panicked (see tasks.rs) or stalled (see watchdog.rs): the server may have stopped working.
+  Critical,
+}
+
+impl Severity {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Severity::Warning  => "warning",
+      Severity::Error    => "error",
+      Severity::Critical => "critical",
+    }
+  }
+}
+
+/// Roughly where an error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+  /// Binding the listener.
+  Bind,
+  /// Reading a request head or writing a plain HTTP response.
+  Http,
+  /// The websocket handshake.
+  Handshake,
+  /// Forwarding server messages to a client.
+  Send,
+  /// Receiving messages from a client.
+  Receive,
+  /// The message callback thread.
+  Callback,
+  /// Proxying a connection to its backend (see proxy.rs).
+  Proxy,
+  /// The Redis bridge's connections (see redis_bridge.rs).
+  Redis,
+  /// The Kafka sink's deliveries (see kafka_sink.rs).
+  Kafka,
+  /// The ZeroMQ bridge's connections (see zmq_bridge.rs).
+  Zmq,
+  /// Links between cluster nodes (see cluster.rs).
+  Cluster,
+  /// Exporting to OpenTelemetry (see otel.rs).
+  Otel,
+  /// Writing a session recording (see recording.rs).
+  Recording,
+  /// Writing the connection audit log (see audit_log.rs).
+  Audit,
+  /// Consumer state access and other internal failures.
+  Internal,
+}
+
+impl Category {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Category::Bind      => "bind",
+      Category::Http      => "http",
+      Category::Handshake => "handshake",
+      Category::Send      => "send",
+      Category::Receive   => "receive",
+      Category::Callback  => "callback",
+      Category::Proxy     => "proxy",
+      Category::Redis     => "redis",
+      Category::Kafka     => "kafka",
+      Category::Zmq       => "zmq",
+      Category::Cluster   => "cluster",
+      Category::Otel      => "otel",
+      Category::Recording => "recording",
+      Category::Audit     => "audit",
+      Category::Internal  => "internal",
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct ErrorEvent {
+  pub timestamp: SystemTime,
+  pub severity: Severity,
+  pub category: Category,
+  pub message: String,
+  /// The client (peer address) the error concerns, if any.
+  pub client_id: Option<String>,
+  /// Where a panic came from (see tasks.rs), if the error's a panic and RUST_BACKTRACE asked for backtraces.
+  pub backtrace: Option<String>,
+}
+
+/// The most recent events, oldest first, up to `capacity` of them.
+struct RecentEvents {
+  capacity: usize,
+  events: VecDeque<ErrorEvent>,
+}
+
+lazy_static! {
+  static ref ERROR_EVENTS: Mutex<VecDeque<ErrorEvent>> = Mutex::new(VecDeque::new());
+  static ref RECENT_EVENTS: Mutex<RecentEvents> = Mutex::new(RecentEvents { capacity: DEFAULT_RECENT_CAPACITY, events: VecDeque::new() });
+}
+
+impl ErrorEvent {
+  pub fn new(severity: Severity, category: Category, message: String, client_id: Option<String>) -> ErrorEvent {
+    ErrorEvent { timestamp: SystemTime::now(), severity, category, message, client_id, backtrace: None }
+  }
+
+  pub fn with_backtrace(self, backtrace: Option<String>) -> ErrorEvent {
+    ErrorEvent { backtrace, ..self }
+  }
+}
+
+/// Records an error event. Never blocks for long, and silently drops the event if the queue's lock is poisoned.
+pub fn record(severity: Severity, category: Category, message: String, client_id: Option<String>) {
+  record_event(ErrorEvent::new(severity, category, message, client_id))
+}
+
+pub fn record_event(event: ErrorEvent) {
+  event_log::record_error(&event);
+
+  let events = ERROR_EVENTS.lock();
+  if events.is_err() { return; /* silently fail. */ }
+  let mut events = events.unwrap();
+
+  if let Ok(mut recent) = RECENT_EVENTS.lock() {
+    if recent.capacity > 0 {
+      if recent.events.len() >= recent.capacity { recent.events.pop_front(); }
+      recent.events.push_back(event.clone());
+    }
+  }
+
+  if events.len() >= MAX_QUEUED_EVENTS { events.pop_front(); }
+  events.push_back(event);
+}
+
+/// Removes and returns all queued error events, oldest first.
+pub fn drain() -> Vec<ErrorEvent> {
+  let events = ERROR_EVENTS.lock();
+  if events.is_err() { return vec![]; }
+  events.unwrap().drain(..).collect()
+}
+
+/// Returns copies of the most recent events (the last `count` of them, if given), oldest first. Unlike drain(), this doesn't remove anything.
+pub fn recent(count: Option<usize>) -> Vec<ErrorEvent> {
+  let recent = RECENT_EVENTS.lock();
+  if recent.is_err() { return vec![]; }
+  let recent = recent.unwrap();
+  let skip = count.map(|count| recent.events.len().saturating_sub(count)).unwrap_or(0);
+  recent.events.iter().skip(skip).cloned().collect()
+}
+
+/// Sets how many recent events are kept, dropping the oldest if there are more than that already. Zero stops keeping them.
+pub fn set_recent_capacity(capacity: usize) {
+  if let Ok(mut recent) = RECENT_EVENTS.lock() {
+    recent.capacity = capacity;
+    while recent.events.len() > capacity { recent.events.pop_front(); }
+  }
+}
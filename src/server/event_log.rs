@@ -0,0 +1,97 @@
+// event_log.rs
+//
+// A bounded, in-memory log of the significant things that happen to the process's servers: binding their ports, clients connecting and disconnecting, draining and shutting down, and every error event (see error_events.rs), which covers failed binds and handshakes and dropped messages. Each entry has a level, a timestamp and a sequence number, so it's a timeline to look back over after something went wrong, without any logging set up. Unlike the error event queue, reading it doesn't consume anything: since() returns the entries after a given sequence number, so a poller can carry on from the last one it saw.
+
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use super::{error_events::{Category, ErrorEvent, Severity}, logging::Level};
+
+/// How many entries are kept. When full, the oldest are dropped to make room (which a poller notices as a gap in the sequence numbers).
+pub const MAX_ENTRIES: usize = 1000;
+
+/// What an entry is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+  /// A server started listening (or accepting loopback or service connections).
+  Bind,
+  /// A client completed the websocket handshake.
+  Accept,
+  /// A client's connection ended, with or without a close frame.
+  Disconnect,
+  /// A server began draining, or was drained.
+  Drain,
+  /// A server began shutting down, or finished.
+  Shutdown,
+  /// An error event, by its category.
+  Error(Category),
+}
+
+impl Kind {
+  /// "bind", "accept", "disconnect", "drain", "shutdown", or an error event's category (see Category::as_str()).
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Kind::Bind            => "bind",
+      Kind::Accept          => "accept",
+      Kind::Disconnect      => "disconnect",
+      Kind::Drain           => "drain",
+      Kind::Shutdown        => "shutdown",
+      Kind::Error(category) => category.as_str(),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+  /// Numbers the process's entries from 1, in the order they were logged.
+  pub seq: u64,
+  pub timestamp: SystemTime,
+  pub level: Level,
+  pub kind: Kind,
+  pub message: String,
+  /// The client (peer address) the entry concerns, if any.
+  pub client_id: Option<String>,
+}
+
+struct EventLog {
+  next_seq: u64,
+  entries: VecDeque<Entry>,
+}
+
+lazy_static! {
+  static ref EVENT_LOG: Mutex<EventLog> = Mutex::new(EventLog { next_seq: 1, entries: VecDeque::new() });
+}
+
+/// Logs an event. Silently drops it if the log's lock is poisoned.
+pub fn record(level: Level, kind: Kind, message: String, client_id: Option<String>) {
+  record_at(SystemTime::now(), level, kind, message, client_id)
+}
+
+/// Logs an error event (see error_events::record_event()), as a warning or an error by its severity.
+pub fn record_error(event: &ErrorEvent) {
+  let level = match event.severity {
+    Severity::Warning => Level::Warning,
+    Severity::Error | Severity::Critical => Level::Error,
+  };
+  record_at(event.timestamp, level, Kind::Error(event.category), event.message.clone(), event.client_id.clone())
+}
+
+fn record_at(timestamp: SystemTime, level: Level, kind: Kind, message: String, client_id: Option<String>) {
+  let log = EVENT_LOG.lock();
+  if log.is_err() { return; /* silently fail. */ }
+  let mut log = log.unwrap();
+
+  let seq = log.next_seq;
+  log.next_seq += 1;
+  if log.entries.len() >= MAX_ENTRIES { log.entries.pop_front(); }
+  log.entries.push_back(Entry { seq, timestamp, level, kind, message, client_id });
+}
+
+/// Returns copies of the entries logged after `seq` (all those kept, for 0), oldest first.
+pub fn since(seq: u64) -> Vec<Entry> {
+  let log = EVENT_LOG.lock();
+  if log.is_err() { return vec![]; }
+  let log = log.unwrap();
+  // (Sequence numbers are consecutive, so the first entry's tells where the ones after `seq` start.)
+  let skip = log.entries.front().map(|first| seq.saturating_sub(first.seq - 1)).unwrap_or(0);
+  log.entries.iter().skip(skip as usize).cloned().collect()
+}
@@ -0,0 +1,131 @@
+// event_stream.rs
+//
+// A server's events (client messages, connection changes, and errors) merged into one sequence, in the order they happened, for Rust consumers: as a Stream from Server::events(), to select! over in their own runtime, and as the source the ServerHandler dispatch thread (handler.rs) reads.
+
+use std::{collections::VecDeque, pin::Pin};
+use futures_util::Stream;
+use tokio::sync::{OwnedMutexGuard, broadcast};
+
+use super::{Error, Server, consumer_state::SharedReceiver, error_events::ErrorEvent, events::{ClientMessage, ConnectionChange, ConnectionEvent}, queue};
+
+/// Something that happened on a server.
+#[derive(Debug)]
+pub enum ServerEvent {
+  /// A client connected or disconnected.
+  Connection(ConnectionEvent),
+  /// A client sent a text or binary message.
+  Message(ClientMessage),
+  /// The server recorded an error.
+  Error(ErrorEvent),
+}
+
+/// The stream returned by Server::events(). Unpin, so it can be used with select! or StreamExt::next() as it is.
+pub type EventStream = Pin<Box<dyn Stream<Item = ServerEvent> + Send>>;
+
+/// Reads a server's events from its receivers. Claims the connection event receiver, and holds both receivers' locks once it's been polled, until dropped; then they're the consumer's again (for drains).
+pub(crate) struct EventSource {
+  server: Server,
+  shared_msg_rx: SharedReceiver<ClientMessage>,
+  msg_rx: Option<OwnedMutexGuard<queue::Receiver<ClientMessage>>>,
+  shared_conn_rx: SharedReceiver<ConnectionEvent>,
+  conn_rx: Option<OwnedMutexGuard<queue::Receiver<ConnectionEvent>>>,
+  error_rx: broadcast::Receiver<ErrorEvent>,
+  /// Events read ahead of the one being returned (a disconnected client's last messages, and its disconnection).
+  pending: VecDeque<ServerEvent>,
+  messages_open: bool,
+  connections_open: bool,
+}
+
+impl EventSource {
+  /// Takes the server's receivers. `error_rx` decides which errors are seen: those recorded after it subscribed.
+  pub(crate) fn new(server: &Server, error_rx: broadcast::Receiver<ErrorEvent>) -> Result<EventSource, Error> {
+    let shared_msg_rx = server.cli_msg_rx.shared().ok_or(Error::ReceiverUnavailable)?;
+    let shared_conn_rx = server.cli_conn_rx.claim().ok_or(Error::ReceiverUnavailable)?;
+    Ok(EventSource {
+      server: server.clone(),
+      shared_msg_rx,
+      msg_rx: None,
+      shared_conn_rx,
+      conn_rx: None,
+      error_rx,
+      pending: VecDeque::new(),
+      messages_open: true,
+      connections_open: true,
+    })
+  }
+
+  /// The next event, or None once the server has stopped and every event has been returned.
+  pub(crate) async fn next(&mut self) -> Option<ServerEvent> {
+    if self.msg_rx.is_none() {
+      // Waits for a drain in progress (which ends within its own timeout).
+      self.msg_rx = Some(self.shared_msg_rx.clone().lock_owned().await);
+    }
+    if self.conn_rx.is_none() {
+      // (Drains of connection events only ever hold it briefly.)
+      self.conn_rx = Some(self.shared_conn_rx.clone().lock_owned().await);
+    }
+    let msg_rx = self.msg_rx.as_mut().unwrap();
+    let conn_rx = self.conn_rx.as_mut().unwrap();
+
+    loop {
+      if let Some(event) = self.pending.pop_front() { return Some(event); }
+      // Both channels close once the server thread has exited, after which only errors recorded while it shut down are left.
+      if !self.messages_open && !self.connections_open {
+        return loop {
+          match self.error_rx.try_recv() {
+            Ok(error) => break Some(ServerEvent::Error(error)),
+            Err(broadcast::error::TryRecvError::Lagged(_)) => {}
+            Err(_) => break None,
+          }
+        };
+      }
+
+      tokio::select! {
+        // Connection events first, so a client's connection comes before its first message.
+        biased;
+        event = conn_rx.recv(), if self.connections_open => {
+          match event {
+            Some(event) => {
+              if event.change == ConnectionChange::Disconnected {
+                // The client's messages were all queued before its disconnection was reported; they come first.
+                while let Ok(msg) = msg_rx.try_recv() {
+                  if msg.is_data() { self.pending.push_back(ServerEvent::Message(msg)); }
+                }
+              }
+              self.pending.push_back(ServerEvent::Connection(event));
+            }
+            None => { self.connections_open = false; }
+          }
+        }
+        msg = msg_rx.recv(), if self.messages_open => {
+          match msg {
+            Some(msg) => { if msg.is_data() { return Some(ServerEvent::Message(msg)); } }
+            None => { self.messages_open = false; }
+          }
+        }
+        error = self.error_rx.recv() => {
+          match error {
+            Ok(error) => { return Some(ServerEvent::Error(error)); }
+            // Skipped errors are still in the process-wide error queue.
+            Err(broadcast::error::RecvError::Lagged(_)) => {}
+            // The server state (and its stats) outlive this source, so this can't happen; stop if it does.
+            Err(broadcast::error::RecvError::Closed) => { self.messages_open = false; self.connections_open = false; }
+          }
+        }
+      }
+    }
+  }
+
+  pub(crate) fn into_stream(self) -> EventStream {
+    Box::pin(futures_util::stream::unfold(self, |mut source| async move {
+      source.next().await.map(|event| (event, source))
+    }))
+  }
+}
+
+impl Drop for EventSource {
+  fn drop(&mut self) {
+    // (The receivers' locks are released along with their guards.)
+    self.server.cli_conn_rx.release();
+  }
+}
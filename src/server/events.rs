@@ -0,0 +1,146 @@
+// events.rs
+//
+// What the tokio tasks report to the consumer about clients: received messages, connection changes (with the close frames clients disconnect with), and (if asked for) pings and pongs, each stamped with the client it came from and when it happened.
+
+use std::time::{Instant, SystemTime};
+use tokio_tungstenite::tungstenite::{Message as WsMessage, protocol::CloseFrame};
+
+use super::budget::Charge;
+
+/// A message received from a client.
+#[derive(Debug)]
+pub struct ClientMessage {
+  /// The client (peer address) that sent the message.
+  pub client_id: String,
+  /// When the server received the message, by the wall clock and by the monotonic clock (for measuring latency, which the wall clock can't be trusted for). Both are read by the reader task as soon as the message has been read off the socket.
+  pub timestamp: SystemTime,
+  pub received_at: Instant,
+  pub message: WsMessage,
+  /// Its bytes, taken out of the server's memory budget (see budget.rs) until it's been drained and dropped.
+  _charge: Option<Charge>,
+}
+
+impl ClientMessage {
+  pub fn new(client_id: String, message: WsMessage) -> ClientMessage {
+    ClientMessage { client_id, timestamp: SystemTime::now(), received_at: Instant::now(), message, _charge: None }
+  }
+
+  pub fn with_charge(self, charge: Charge) -> ClientMessage {
+    ClientMessage { _charge: Some(charge), ..self }
+  }
+
+  /// A copy of the message, for a tap (see taps.rs). Copies aren't charged to the memory budget; the taps' queues are bounded instead.
+  pub fn copy(&self) -> ClientMessage {
+    ClientMessage { client_id: self.client_id.clone(), timestamp: self.timestamp, received_at: self.received_at, message: self.message.clone(), _charge: None }
+  }
+
+  /// Text and binary messages; pings, pongs and close frames aren't handed to the consumer.
+  pub fn is_data(&self) -> bool {
+    self.message.is_text() || self.message.is_binary()
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionChange {
+  /// The client completed the websocket handshake.
+  Connected,
+  /// The client's connection closed (or the server shut down).
+  Disconnected,
+  /// The server was draining (see Server::begin_draining()), and its last client has disconnected. Not about any one client: the event's client_id is empty.
+  Drained,
+  /// The client has left enough keepalive pings unanswered to be marked unhealthy (see Keepalive::unhealthy_after). It's still connected, for now.
+  Unhealthy,
+  /// An unhealthy client answered a ping, and is healthy again.
+  Healthy,
+  /// The client declared its capabilities (see capabilities.rs; Server::client_capabilities() has them), for the first time or again.
+  Capabilities,
+}
+
+impl ConnectionChange {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ConnectionChange::Connected    => "connected",
+      ConnectionChange::Disconnected => "disconnected",
+      ConnectionChange::Drained      => "drained",
+      ConnectionChange::Unhealthy    => "unhealthy",
+      ConnectionChange::Healthy      => "healthy",
+      ConnectionChange::Capabilities => "capabilities",
+    }
+  }
+}
+
+/// A client connecting or disconnecting.
+#[derive(Clone, Debug)]
+pub struct ConnectionEvent {
+  pub client_id: String,
+  pub timestamp: SystemTime,
+  pub change: ConnectionChange,
+  /// For a disconnection, the close frame the client sent, if it sent one. None if its connection just dropped, or the server closed it first (shutting down, or for a keepalive or idle timeout).
+  pub close: Option<ClientClose>,
+}
+
+impl ConnectionEvent {
+  pub fn new(client_id: String, change: ConnectionChange) -> ConnectionEvent {
+    ConnectionEvent { client_id, timestamp: SystemTime::now(), change, close: None }
+  }
+
+  pub fn with_close(self, close: Option<ClientClose>) -> ConnectionEvent {
+    ConnectionEvent { close, ..self }
+  }
+}
+
+/// The close frame a client sent: why it's closing its connection, e.g. 1000 (normal closure) from an application closing it, 1001 (going away) from a browser leaving the page, or 1002 (protocol error) and the like from a client that took a frame for a broken one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientClose {
+  /// The close code; 1005 (no status received) if the frame didn't have one.
+  pub code: u16,
+  /// The reason the client gave, if any (it's often empty).
+  pub reason: String,
+}
+
+impl ClientClose {
+  /// Status code for a close frame without one (which browsers report for those, too).
+  pub const NO_STATUS: u16 = 1005;
+
+  pub fn from_frame(frame: Option<&CloseFrame>) -> ClientClose {
+    match frame {
+      Some(frame) => ClientClose { code: frame.code.into(), reason: frame.reason.to_string() },
+      None => ClientClose { code: ClientClose::NO_STATUS, reason: String::new() },
+    }
+  }
+}
+
+/// Longest payload a ping can carry (the limit for every websocket control frame).
+pub const MAX_PING_PAYLOAD: usize = 125;
+/// How many ping events are kept until they're drained; more are dropped (see ServerConfig::ping_events).
+pub const PING_EVENT_QUEUE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingKind {
+  Ping,
+  Pong,
+}
+
+impl PingKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      PingKind::Ping => "ping",
+      PingKind::Pong => "pong",
+    }
+  }
+}
+
+/// A ping or pong from a client (see ServerConfig::ping_events). Its pings have been answered already, with pongs echoing their payloads.
+#[derive(Clone, Debug)]
+pub struct PingEvent {
+  pub client_id: String,
+  pub timestamp: SystemTime,
+  pub kind: PingKind,
+  pub payload: Vec<u8>,
+}
+
+impl PingEvent {
+  pub fn new(client_id: String, kind: PingKind, payload: Vec<u8>) -> PingEvent {
+    PingEvent { client_id, timestamp: SystemTime::now(), kind, payload }
+  }
+}
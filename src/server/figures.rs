@@ -0,0 +1,251 @@
+// figures.rs
+//
+// Plots pushed to the browser (Server::send_figure()), for dashboards and notebooks that redraw a figure as its data changes, without a transport of their own. Figures are broadcast on the reserved topic "figure", each under an id, so a page can show several, and quicksocket/figures.js shows them as they come in:
+//
+// - A Plotly figure goes out as text, its JSON wrapped with its id, for Plotly.react() to draw:
+//
+//     {"topic":"figure","id":"loss","format":"plotly","figure":{"data":[...],"layout":{...}}}
+//
+// - An image (a Matplotlib figure, say) goes out as a PNG, in a binary message with an 8-byte header: "QSFG"; a version byte, 1; a format byte, 1 for PNG; and the id's length in bytes, a little-endian u16; followed by the id, as UTF-8, then the PNG.
+//
+// An image given as RGBA pixels (a Matplotlib figure, as its Agg canvas draws it) is encoded as a PNG here, off the GIL. The encoder is just enough of one for plots: a single deflate block of fixed Huffman codes, matching repeats against the last occurrence of their first three bytes, which takes plots' flat backgrounds and runs of lines down to a fraction of their pixels, if not as far as libpng would.
+
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, inspector::json_string, outbound::Outbound};
+
+/// The topic figures go out on.
+pub const FIGURE_TOPIC: &str = "figure";
+pub const IMAGE_MAGIC: &[u8; 4] = b"QSFG";
+pub const VERSION: u8 = 1;
+pub const FORMAT_PNG: u8 = 1;
+pub const IMAGE_HEADER_LEN: usize = 8;
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// A figure, in one of the forms it can be sent in.
+#[derive(Clone, Copy, Debug)]
+pub enum Figure<'a> {
+  /// A Plotly figure's JSON: an object with its "data" and "layout".
+  Plotly(&'a str),
+  /// An image, encoded as a PNG.
+  Png(&'a [u8]),
+  /// An image's pixels, 4 bytes (RGBA) each, row by row from the top, to be encoded as a PNG.
+  Rgba { width: u32, height: u32, pixels: &'a [u8] },
+}
+
+/// The message a figure goes out as, under `id` (see above).
+pub fn message(id: &str, figure: Figure) -> Result<Outbound, String> {
+  if id.len() > u16::MAX as usize {
+    return Err(format!("a figure's id can't be longer than {} bytes", u16::MAX));
+  }
+  let message = match figure {
+    Figure::Plotly(json) => {
+      let json = json.trim();
+      if !(json.starts_with('{') && json.ends_with('}')) {
+        return Err("a Plotly figure must be a JSON object".to_string());
+      }
+      Message::Text(format!("{{\"topic\":{},\"id\":{},\"format\":\"plotly\",\"figure\":{}}}", json_string(FIGURE_TOPIC), json_string(id), json))
+    }
+    Figure::Png(png) => {
+      if !png.starts_with(PNG_SIGNATURE) {
+        return Err("the image isn't a PNG".to_string());
+      }
+      image(id, png)
+    }
+    Figure::Rgba { width, height, pixels } => image(id, &png(width, height, pixels)?),
+  };
+  Ok(Outbound::from(message))
+}
+
+fn image(id: &str, png: &[u8]) -> Message {
+  let mut buf = OUTBOUND.take(IMAGE_HEADER_LEN + id.len() + png.len());
+  buf.extend_from_slice(IMAGE_MAGIC);
+  buf.extend_from_slice(&[VERSION, FORMAT_PNG]);
+  buf.extend_from_slice(&(id.len() as u16).to_le_bytes());
+  buf.extend_from_slice(id.as_bytes());
+  buf.extend_from_slice(png);
+  Message::Binary(buf)
+}
+
+/// Encodes an image's RGBA pixels (see Figure::Rgba) as a PNG.
+pub fn png(width: u32, height: u32, pixels: &[u8]) -> Result<Vec<u8>, String> {
+  if width == 0 || height == 0 {
+    return Err(format!("an image can't be empty ({}x{})", width, height));
+  }
+  let stride = width as usize * 4;
+  if Some(pixels.len()) != stride.checked_mul(height as usize) {
+    return Err(format!("a {}x{} image is {} bytes of RGBA pixels, not {}", width, height, stride as u128 * height as u128, pixels.len()));
+  }
+  // (Each row starts with its filter type, 0: its bytes as they are.)
+  let mut rows = Vec::with_capacity(pixels.len() + height as usize);
+  for row in pixels.chunks_exact(stride) {
+    rows.push(0);
+    rows.extend_from_slice(row);
+  }
+  let mut header = Vec::with_capacity(13);
+  header.extend_from_slice(&width.to_be_bytes());
+  header.extend_from_slice(&height.to_be_bytes());
+  // 8 bits a channel, RGBA, deflated, filtered by row, not interlaced.
+  header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+  let mut png = PNG_SIGNATURE.to_vec();
+  chunk(&mut png, b"IHDR", &header);
+  chunk(&mut png, b"IDAT", &zlib(&rows));
+  chunk(&mut png, b"IEND", &[]);
+  Ok(png)
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+  png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+  let start = png.len();
+  png.extend_from_slice(kind);
+  png.extend_from_slice(data);
+  let crc = crc32(&png[start..]);
+  png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32, which PNG chunks are checksummed with.
+fn crc32(data: &[u8]) -> u32 {
+  lazy_static! {
+    static ref TABLE: [u32; 256] = {
+      let mut table = [0u32; 256];
+      for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 { crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 }; }
+        *entry = crc;
+      }
+      table
+    };
+  }
+  !data.iter().fold(!0u32, |crc, byte| TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Adler-32, which zlib streams end with.
+fn adler32(data: &[u8]) -> u32 {
+  let (mut a, mut b) = (1u32, 0u32);
+  // (5552 bytes is as many as can be summed before b could overflow.)
+  for block in data.chunks(5552) {
+    for byte in block {
+      a += *byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  (b << 16) | a
+}
+
+/// A zlib stream of `data`, deflated.
+fn zlib(data: &[u8]) -> Vec<u8> {
+  let mut bits = BitWriter::default();
+  // Deflate with a 32 KiB window, and the header's check bits.
+  bits.out.extend_from_slice(&[0x78, 0x01]);
+  deflate(data, &mut bits);
+  let mut out = bits.finish();
+  out.extend_from_slice(&adler32(data).to_be_bytes());
+  out
+}
+
+const WINDOW: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASES: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA_BITS: [u32; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASES: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA_BITS: [u32; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+/// Deflates `data` as one final block of fixed Huffman codes.
+fn deflate(data: &[u8], bits: &mut BitWriter) {
+  bits.write(1, 1); // BFINAL
+  bits.write(1, 2); // BTYPE: fixed Huffman codes
+  let hash = |at: usize| {
+    let word = data[at] as u32 | (data[at + 1] as u32) << 8 | (data[at + 2] as u32) << 16;
+    (word.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+  };
+  // Where each hash of three bytes was last seen.
+  let mut last_seen = vec![usize::MAX; 1 << HASH_BITS];
+  // (Only positions with three bytes from them are hashed.)
+  let hashed_end = data.len().saturating_sub(MIN_MATCH - 1);
+  let mut at = 0;
+  while at < data.len() {
+    let mut matched = 0;
+    let mut distance = 0;
+    if at < hashed_end {
+      let candidate = std::mem::replace(&mut last_seen[hash(at)], at);
+      if candidate != usize::MAX && at - candidate <= WINDOW {
+        let longest = (data.len() - at).min(MAX_MATCH);
+        // (A match can overlap where it's copied to, e.g. a run of one byte at a distance of 1.)
+        matched = data[candidate..].iter().zip(&data[at..at + longest]).take_while(|(earlier, byte)| earlier == byte).count();
+        distance = at - candidate;
+      }
+    }
+    if matched >= MIN_MATCH {
+      bits.length(matched);
+      bits.distance(distance);
+      for skipped in at + 1..(at + matched).min(hashed_end) {
+        last_seen[hash(skipped)] = skipped;
+      }
+      at += matched;
+    } else {
+      bits.symbol(data[at] as u16);
+      at += 1;
+    }
+  }
+  bits.symbol(256); // The end of the block.
+}
+
+/// Writes deflate's bits, least significant first.
+#[derive(Default)]
+struct BitWriter {
+  out: Vec<u8>,
+  pending: u64,
+  pending_bits: u32,
+}
+
+impl BitWriter {
+  fn write(&mut self, value: u32, bits: u32) {
+    self.pending |= (value as u64) << self.pending_bits;
+    self.pending_bits += bits;
+    while self.pending_bits >= 8 {
+      self.out.push(self.pending as u8);
+      self.pending >>= 8;
+      self.pending_bits -= 8;
+    }
+  }
+
+  /// Writes a Huffman code, which goes most significant bit first.
+  fn code(&mut self, code: u32, bits: u32) {
+    self.write(code.reverse_bits() >> (32 - bits), bits);
+  }
+
+  /// Writes a literal/length symbol in its fixed Huffman code.
+  fn symbol(&mut self, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+      0..=143 => self.code(0x30 + symbol, 8),
+      144..=255 => self.code(0x190 + symbol - 144, 9),
+      256..=279 => self.code(symbol - 256, 7),
+      _ => self.code(0xc0 + symbol - 280, 8),
+    }
+  }
+
+  fn length(&mut self, length: usize) {
+    let index = LENGTH_BASES.iter().rposition(|base| *base as usize <= length).unwrap_or(0);
+    self.symbol(257 + index as u16);
+    self.write((length - LENGTH_BASES[index] as usize) as u32, LENGTH_EXTRA_BITS[index]);
+  }
+
+  fn distance(&mut self, distance: usize) {
+    let index = DISTANCE_BASES.iter().rposition(|base| *base as usize <= distance).unwrap_or(0);
+    self.code(index as u32, 5);
+    self.write((distance - DISTANCE_BASES[index] as usize) as u32, DISTANCE_EXTRA_BITS[index]);
+  }
+
+  fn finish(mut self) -> Vec<u8> {
+    if self.pending_bits > 0 { self.out.push(self.pending as u8); }
+    self.out
+  }
+}
@@ -0,0 +1,157 @@
+// frames.rs
+//
+// Latest-frame-only streaming (Server::send_frame()), for streams where only the newest payload matters: a camera's frames, say, or a plot redrawn as its data arrives. Broadcasts are queued for every client, so a slow one works through a backlog of stale frames before it sees the current one (or, with LagPolicy::Drop, misses a run of them and is reported for it). Frames are sent under a topic instead, and each client has a slot per topic holding the newest frame it hasn't been written yet: a frame sent while the topic's previous one is still waiting for a client replaces it there, and the skipped one's counted in the stats (frames_skipped). That's the point of frames rather than a failure, so it isn't an error event. However slow a client is, it has at most one frame per topic pending, and whenever it's written, it's written the newest.
+//
+// Frames go out in the same writes as a client's broadcasts and targeted sends, after them; they aren't ordered with other messages, and each topic's pending frame goes out with whatever else is pending. A frame's bytes count against the memory budget (see budget.rs) until the last client it's pending for has been written it or skipped it. They go to this server's clients only, not a cluster's other nodes, and aren't seen by the inspector; a session recording records what each client is written. Through an emulated link (see link.rs), frames already on the link aren't skipped, but none are put on it while it's full.
+//
+// A client can also be written a topic's frames less often than they're sent: a phone viewer, say, that only needs 5 a second of a stream desktops take in full. Each topic can have a maximum rate per client, from ServerConfig::client_topic_rates (every client's to start with) or, with ServerConfig::topic_throttle_requests, asked for by the client itself with a text message
+//
+//   throttle:<topic>:<rate>    (in frames a second, more than 0; or "off", for every frame)
+//
+// which its receiver task takes rather than passing it on to the consumer (as for time sync requests; a malformed one is recorded as a "receive" warning event). A throttled topic's frame waits in its slot until the interval since the client was last written one is up, being replaced (and skipped) meanwhile like any other, so the client's written the newest frame there is at most that often.
+
+use std::{collections::HashMap, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use tokio::sync::Notify;
+
+use super::{budget::Charge, buffer_pool::OUTBOUND, outbound::Outbound};
+
+/// A frame sent under a topic, shared by the slots of every client it's pending for.
+pub struct Frame {
+  pub topic: String,
+  pub message: Outbound,
+  pub queued_at: Instant,
+  /// Its bytes, taken out of the server's memory budget until no client has it pending.
+  _charge: Charge,
+}
+
+impl Frame {
+  pub fn new(topic: &str, message: Outbound, charge: Charge) -> Frame {
+    Frame { topic: topic.to_string(), message, queued_at: Instant::now(), _charge: charge }
+  }
+}
+
+/// Returns a frame's payload to the pool, if this was the last reference to it.
+pub fn recycle(frame: Arc<Frame>) {
+  if let Ok(frame) = Arc::try_unwrap(frame) { OUTBOUND.recycle_messages(vec![frame.message]); }
+}
+
+/// What comes before a throttle request.
+pub const THROTTLE_PREFIX: &str = "throttle:";
+
+/// The topic and rate (None for "off") a throttle request asks for, if `text` is one: Some(Err) for a malformed one.
+pub fn throttle_request(text: &str) -> Option<Result<(&str, Option<f64>), String>> {
+  let request = text.strip_prefix(THROTTLE_PREFIX)?;
+  let Some((topic, rate)) = request.rsplit_once(':').filter(|(topic, _)| !topic.is_empty()) else {
+    return Some(Err(format!("expected \"throttle:<topic>:<rate>\", not {:?}", text)));
+  };
+  if rate == "off" { return Some(Ok((topic, None))); }
+  Some(match rate.trim().parse::<f64>() {
+    Ok(rate) if rate.is_finite() && rate > 0.0 => Ok((topic, Some(rate))),
+    _ => Err(format!("the rate must be a number more than 0, or \"off\": {:?}", text)),
+  })
+}
+
+/// The least time between frames at `rate` a second.
+pub fn interval(rate: f64) -> Duration {
+  Duration::from_secs_f64(1.0 / rate)
+}
+
+#[derive(Default)]
+struct Slots {
+  frames: Vec<Arc<Frame>>,
+  /// The throttled topics' intervals, and when the client was last written a frame of each.
+  intervals: HashMap<String, Duration>,
+  written: HashMap<String, Instant>,
+}
+
+impl Slots {
+  /// When a frame of `topic` can next be written.
+  fn due(&self, topic: &str) -> Option<Instant> {
+    Some(*self.written.get(topic)? + *self.intervals.get(topic)?)
+  }
+}
+
+/// A client's pending frames: the newest of each topic it hasn't been written yet, in the order their topics were first sent, and its topics' maximum rates. Shared by the consumer, which puts frames in, the client's sender task, which takes them out, and its receiver task, which throttles topics as the client asks.
+#[derive(Default)]
+pub(crate) struct FrameSlots {
+  pending: Mutex<Slots>,
+  ready: Notify,
+}
+
+impl FrameSlots {
+  /// Slots for a client, with its topics' maximum rates to start with (ServerConfig::client_topic_rates).
+  pub fn new(rates: &HashMap<String, f64>) -> FrameSlots {
+    let intervals = rates.iter().map(|(topic, rate)| (topic.clone(), interval(*rate))).collect();
+    FrameSlots { pending: Mutex::new(Slots { intervals, ..Slots::default() }), ready: Notify::new() }
+  }
+
+  /// Puts a frame in its topic's slot, and wakes the sender task. Returns the frame it replaced, which the client skips, if there was one.
+  pub fn put(&self, frame: Arc<Frame>) -> Option<Arc<Frame>> {
+    let skipped = {
+      let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+      match pending.frames.iter_mut().find(|pending| pending.topic == frame.topic) {
+        Some(slot) => Some(std::mem::replace(slot, frame)),
+        None => { pending.frames.push(frame); None }
+      }
+    };
+    self.ready.notify_one();
+    skipped
+  }
+
+  /// Writes the client `topic`'s frames at most `rate` a second from now on, or every one (None).
+  pub fn throttle(&self, topic: &str, rate: Option<f64>) {
+    {
+      let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+      match rate {
+        Some(rate) => { pending.intervals.insert(topic.to_string(), interval(rate)); }
+        None => { pending.intervals.remove(topic); }
+      }
+    }
+    // (Its pending frame may be due sooner, or straight away.)
+    self.ready.notify_one();
+  }
+
+  /// Takes the pending frames that are due: every one but those of throttled topics the client's been written a frame of too recently, which stay in their slots.
+  pub fn take(&self) -> Vec<Arc<Frame>> {
+    let now = Instant::now();
+    let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+    let slots = &mut *pending;
+    let (due, waiting): (Vec<Arc<Frame>>, Vec<Arc<Frame>>) = std::mem::take(&mut slots.frames).into_iter().partition(|frame| slots.due(&frame.topic).is_none_or(|due| due <= now));
+    slots.frames = waiting;
+    for frame in &due {
+      if slots.intervals.contains_key(&frame.topic) { slots.written.insert(frame.topic.clone(), now); }
+    }
+    due
+  }
+
+  /// Takes every pending frame, due or not, emptying the slots.
+  pub fn take_all(&self) -> Vec<Arc<Frame>> {
+    std::mem::take(&mut self.pending.lock().unwrap_or_else(PoisonError::into_inner).frames)
+  }
+
+  /// Resolves once a frame's been put in (or a topic's rate changed) since the last time it resolved, or once a throttled topic's pending frame comes due. (There may be none due by then: the sender task can take frames without waiting for this.)
+  pub async fn ready(&self) {
+    let next_due = {
+      let pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+      pending.frames.iter().filter_map(|frame| pending.due(&frame.topic)).min()
+    };
+    match next_due {
+      Some(due) => tokio::select! {
+        _ = self.ready.notified() => {}
+        _ = tokio::time::sleep_until(tokio::time::Instant::from_std(due)) => {}
+      },
+      None => self.ready.notified().await,
+    }
+  }
+
+  /// How many pending frames are due (see take()).
+  pub fn due(&self) -> usize {
+    let now = Instant::now();
+    let pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+    pending.frames.iter().filter(|frame| pending.due(&frame.topic).is_none_or(|due| due <= now)).count()
+  }
+
+  pub fn pending(&self) -> usize {
+    self.pending.lock().unwrap_or_else(PoisonError::into_inner).frames.len()
+  }
+}
@@ -0,0 +1,73 @@
+// geometry.rs
+//
+// Point clouds and meshes packed into a compact binary layout (Server::send_pointcloud() and Server::send_mesh()), for 3D visualizers, so neither side writes its own packing code. quicksocket/geometry.js decodes them in the browser, straight into typed arrays for WebGL or three.js. Every number is little-endian, and each message starts with a 16-byte header:
+//
+//   offset  size  field
+//   0       4     magic: "QSPC" for a point cloud, "QSMS" for a mesh
+//   4       1     version: 1
+//   5       1     color channels per point: 0 (no colors), 3 (RGB) or 4 (RGBA)
+//   6       2     reserved (0)
+//   8       4     point (or vertex) count, n: u32
+//   12      4     face count, m: u32 (0 for a point cloud)
+//
+// followed by the positions, n * 3 f32s (x, y, z for each point in turn) from offset 16; then, for a point cloud, its colors, n * channels u8s from offset 16 + 12n; or, for a mesh, its faces, m * 3 u32s (each a triangle's vertex indices) from offset 16 + 12n. Positions and faces are 4-byte aligned, so they can be viewed in place as a Float32Array and Uint32Array.
+
+use std::convert::TryFrom;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, outbound::Outbound};
+
+pub const POINT_CLOUD_MAGIC: &[u8; 4] = b"QSPC";
+pub const MESH_MAGIC: &[u8; 4] = b"QSMS";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+
+/// Packs a point cloud: `positions` holds x, y, z for each point in turn, and `colors` (if there are any) `channels` bytes (3 or 4) for each.
+pub fn point_cloud(positions: &[f32], colors: Option<(&[u8], u8)>) -> Result<Outbound, String> {
+  let points = point_count(positions)?;
+  let (colors, channels) = colors.unwrap_or((&[], 0));
+  if channels != 0 && channels != 3 && channels != 4 {
+    return Err(format!("colors must have 3 (RGB) or 4 (RGBA) channels, not {}", channels));
+  }
+  if colors.len() != points * channels as usize {
+    return Err(format!("there are {} points, but {} color bytes ({} would be {} channel(s) each)", points, colors.len(), points * channels as usize, channels));
+  }
+  let mut buf = OUTBOUND.take(HEADER_LEN + positions.len() * 4 + colors.len());
+  header(&mut buf, POINT_CLOUD_MAGIC, channels, points, 0);
+  for position in positions { buf.extend_from_slice(&position.to_le_bytes()); }
+  buf.extend_from_slice(colors);
+  Ok(Outbound::from(Message::Binary(buf)))
+}
+
+/// Packs a triangle mesh: `vertices` holds x, y, z for each vertex in turn, and `faces` three vertex indices for each triangle.
+pub fn mesh(vertices: &[f32], faces: &[u32]) -> Result<Outbound, String> {
+  let vertex_count = point_count(vertices)?;
+  if !faces.len().is_multiple_of(3) {
+    return Err(format!("faces must have 3 vertex indices each, but there are {} indices", faces.len()));
+  }
+  if let Some(index) = faces.iter().find(|index| **index as usize >= vertex_count) {
+    return Err(format!("a face refers to vertex {}, but there are only {} vertices", index, vertex_count));
+  }
+  let face_count = u32::try_from(faces.len() / 3).map_err(|_| "too many faces".to_string())?;
+  let mut buf = OUTBOUND.take(HEADER_LEN + vertices.len() * 4 + faces.len() * 4);
+  header(&mut buf, MESH_MAGIC, 0, vertex_count, face_count);
+  for coordinate in vertices { buf.extend_from_slice(&coordinate.to_le_bytes()); }
+  for index in faces { buf.extend_from_slice(&index.to_le_bytes()); }
+  Ok(Outbound::from(Message::Binary(buf)))
+}
+
+fn point_count(positions: &[f32]) -> Result<usize, String> {
+  if !positions.len().is_multiple_of(3) {
+    return Err(format!("positions must have 3 coordinates (x, y, z) each, but there are {} numbers", positions.len()));
+  }
+  let points = positions.len() / 3;
+  u32::try_from(points).map_err(|_| "too many points".to_string())?;
+  Ok(points)
+}
+
+fn header(buf: &mut Vec<u8>, magic: &[u8; 4], channels: u8, points: usize, faces: u32) {
+  buf.extend_from_slice(magic);
+  buf.extend_from_slice(&[VERSION, channels, 0, 0]);
+  buf.extend_from_slice(&(points as u32).to_le_bytes());
+  buf.extend_from_slice(&faces.to_le_bytes());
+}
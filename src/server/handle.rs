@@ -0,0 +1,833 @@
+// handle.rs
+//
+// The Rust API for running a server: start one, wait for it and its clients, send to and drain from those clients, and shut it down. The Python bindings (api.rs) are a thin layer over this, and Rust programs can use it directly, with the crate's "python" feature turned off so Python isn't needed at all:
+//
+//   let server = Server::start(9001, ServerConfig::default())?;
+//   server.wait_until_started(None)?;
+//   loop {
+//     for msg in server.drain_messages(Some(Duration::from_millis(100)), usize::MAX)? { ... }
+//     server.send(vec![Message::text("tick")])?;
+//   }
+//
+// Every method is safe to call from any number of threads at once (see consumer_state.rs), and the blocking ones block only the calling thread.
+
+use std::{collections::HashMap, fmt, ops::Deref, path::Path, sync::{Arc, PoisonError, atomic::Ordering}, time::{Duration, Instant}};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+use super::{ClientCapabilities, Diagnostics, Message, PeerStatus, ServerConfig, ServerHandler, budget::Charge, buffer_pool, clock::{self, Timestamp}, clients::{ClientStats, TargetedSend}, error_events::{Category, Severity}, event_stream::{EventSource, EventStream}, consumer_state::{self as cs, RunState, ServerState, SharedReceiver}, events::{ClientMessage, ConnectionEvent, MAX_PING_PAYLOAD, PingEvent}, figures::{self, Figure}, frames::{self, Frame}, geometry, inputs::{self, InputSnapshot}, latency::LatencySnapshot, metrics::Histogram, notify::MessageNotifier, outbound::{self, Outbound, PreparedMessage}, playback::{PlaybackEvent, PlaybackState}, queue, recording::{RecordingFormat, RecordingStats}, rosbridge, stats::{DropReason, StatsSnapshot}, sync_groups, transport::LoopbackClient};
+
+/// Why a server operation failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+  /// The server isn't running (it stopped, or never started).
+  NotRunning,
+  /// The server couldn't listen on `address`.
+  Bind { port: u32, address: String, reason: String },
+  /// A batch of `message_count` messages couldn't be handed to the server (or, for confirmed sends, to the client).
+  Send { reason: String, message_count: usize },
+  /// The client message receiver is in use elsewhere (e.g. by the Python message callback thread).
+  ReceiverUnavailable,
+  /// A client connection to `url` (see Client::connect()) couldn't be established.
+  Connect { url: String, reason: String },
+  /// The ServerConfig can't work, e.g. a proxy route's backend isn't a ws:// URL (or the ShutdownOptions, e.g. the close reason is too long).
+  InvalidConfig(String),
+  /// A session recording (see Server::start_recording()) couldn't be started: one's in progress already, or its file couldn't be created.
+  Recording(String),
+  /// A session recording couldn't be replayed (see Replay::start()), as its file couldn't be opened.
+  Replay(String),
+  /// Anything else, e.g. the server thread couldn't be spawned.
+  Internal(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::NotRunning                       => write!(f, "the server isn't running"),
+      Error::Bind { address, reason, .. }     => write!(f, "failed to bind {}: {}", address, reason),
+      Error::Send { reason, message_count }   => write!(f, "failed to send {} message(s): {}", message_count, reason),
+      Error::ReceiverUnavailable              => write!(f, "the client message receiver is unavailable"),
+      Error::Connect { url, reason }          => write!(f, "failed to connect to {}: {}", url, reason),
+      Error::InvalidConfig(reason)            => write!(f, "invalid server configuration: {}", reason),
+      Error::Recording(reason)                => write!(f, "can't start recording: {}", reason),
+      Error::Replay(reason)                   => write!(f, "can't replay recording: {}", reason),
+      Error::Internal(reason)                 => write!(f, "{}", reason),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+/// How send_to_client_with() hands messages to the client.
+pub enum Delivery {
+  /// Queue the messages for the client, failing if its queue is full.
+  Queue,
+  /// Wait for the messages to be written and flushed to the client's socket, for at most `timeout` (if given).
+  Confirm { timeout: Option<Duration> },
+}
+
+/// How long clients are given by default to answer the close frame they're sent when the server shuts down.
+pub const DEFAULT_SHUTDOWN_CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Longest reason a close frame can carry: a control frame's payload, less the code's 2 bytes.
+const MAX_CLOSE_REASON: usize = MAX_PING_PAYLOAD - 2;
+
+/// How shutdown_with() shuts the server down: the close frame it sends every client, how long it waits for their answers, and how long it waits for everything at all.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShutdownOptions {
+  /// The close code: by default 1001 (going away); e.g. 1012 (service restart) tells clients to come back soon.
+  pub close_code: u16,
+  pub close_reason: String,
+  /// Clients that haven't answered their close frame (or taken it, if their sockets are full) this long after the shutdown request are disconnected without an answer.
+  pub close_timeout: Duration,
+  /// The shutdown's deadline: tasks still running this long after the request (a connection wedged mid-write, say) are aborted along with the runtime, and reported (see tasks.rs). If None, they're given the close timeout and a second more.
+  pub timeout: Option<Duration>,
+}
+
+impl Default for ShutdownOptions {
+  fn default() -> ShutdownOptions {
+    ShutdownOptions { close_code: CloseCode::Away.into(), close_reason: "Server shutting down".to_string(), close_timeout: DEFAULT_SHUTDOWN_CLOSE_TIMEOUT, timeout: None }
+  }
+}
+
+impl ShutdownOptions {
+  pub fn validate(&self) -> Result<(), String> {
+    if !CloseCode::from(self.close_code).is_allowed() {
+      return Err(format!("{} isn't a close code the server can send (use 1000-1003, 1007-1014 or 3000-4999)", self.close_code));
+    }
+    if self.close_reason.len() > MAX_CLOSE_REASON {
+      return Err(format!("a close reason must be at most {} bytes of UTF-8, not {}", MAX_CLOSE_REASON, self.close_reason.len()));
+    }
+    Ok(())
+  }
+}
+
+/// A running (or stopped) server. Cheap to clone; every clone refers to the same server. Dropping the handles doesn't stop the server; call shutdown().
+#[derive(Clone)]
+pub struct Server {
+  state: Arc<ServerState>,
+}
+
+impl From<Arc<ServerState>> for Server {
+  fn from(state: Arc<ServerState>) -> Server {
+    Server { state }
+  }
+}
+
+/// The consumer state is available for crate internals (e.g. the Python bindings' callback thread) that work with it directly.
+impl Deref for Server {
+  type Target = ServerState;
+
+  fn deref(&self) -> &ServerState {
+    &self.state
+  }
+}
+
+impl Server {
+  /// Starts a server on `port`, on its own thread. Returns as soon as the thread is launched; the port is bound in the background (see wait_until_started()).
+  pub fn start(port: u32, config: ServerConfig) -> Result<Server, Error> {
+    Server::start_inner(port, config, None)
+  }
+
+  /// Starts a server like start(), with its events going to `handler` as they happen (see ServerHandler) instead of being drained: drain_messages() and drain_connection_events() return nothing for it.
+  pub fn start_with_handler<H: ServerHandler>(port: u32, config: ServerConfig, handler: H) -> Result<Server, Error> {
+    Server::start_inner(port, config, Some(Box::new(handler)))
+  }
+
+  fn start_inner(port: u32, config: ServerConfig, handler: Option<Box<dyn ServerHandler>>) -> Result<Server, Error> {
+    if port > u16::MAX as u32 {
+      return Err(Error::Bind { port, address: super::bind_address(port), reason: "port numbers must be between 0 and 65535".to_string() });
+    }
+    for route in &config.proxy_routes {
+      route.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(cluster) = &config.cluster {
+      if !config.transport.binds_port() {
+        return Err(Error::InvalidConfig("cluster nodes need a transport that listens on their port, so the other nodes can link to them".to_string()));
+      }
+      cluster.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(rate_limit) = &config.rate_limit {
+      rate_limit.validate().map_err(Error::InvalidConfig)?;
+    }
+    config.client_rate_limits.validate().map_err(Error::InvalidConfig)?;
+    if let Some(keepalive) = &config.keepalive {
+      keepalive.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(chaos) = &config.chaos {
+      chaos.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(link) = &config.link {
+      link.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(watchdog) = &config.watchdog {
+      watchdog.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(heartbeat) = &config.heartbeat {
+      heartbeat.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(compression) = &config.compression {
+      compression.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some(audit_log) = &config.audit_log {
+      audit_log.validate().map_err(Error::InvalidConfig)?;
+    }
+    for rate in config.topic_rates.values() {
+      rate.validate().map_err(Error::InvalidConfig)?;
+    }
+    sync_groups::validate(&config.sync_groups).map_err(Error::InvalidConfig)?;
+    if let Some(interval) = config.input_aggregation {
+      inputs::validate(interval).map_err(Error::InvalidConfig)?;
+    }
+    if let Some(capabilities) = &config.capabilities {
+      capabilities.validate().map_err(Error::InvalidConfig)?;
+    }
+    if let Some((topic, rate)) = config.client_topic_rates.iter().find(|(_, rate)| !(rate.is_finite() && **rate > 0.0)) {
+      return Err(Error::InvalidConfig(format!("topic {:?}'s rate for clients must be more than 0 (not {})", topic, rate)));
+    }
+    if config.idle_timeout == Some(Duration::ZERO) {
+      return Err(Error::InvalidConfig("the idle timeout must be more than 0".to_string()));
+    }
+    config.threading.validate().map_err(Error::InvalidConfig)?;
+    config.threading.isolate_caller().map_err(Error::InvalidConfig)?;
+    let state = super::start(port, config, handler);
+    if state.is_err() {
+      return Err(Error::Internal(format!("Failed to start the server. Details: {}", cs::try_get_last_error().unwrap_or_default())));
+    }
+    Ok(Server::from(state.unwrap()))
+  }
+
+  pub fn state(&self) -> &Arc<ServerState> {
+    &self.state
+  }
+
+  pub fn run_state(&self) -> RunState {
+    self.state.run_state()
+  }
+
+  /// The port the server is listening on, once it's bound (see wait_until_started()): the one it was started with, or the one the OS picked if that was 0. None before then, and for loopback servers.
+  pub fn bound_port(&self) -> Option<u32> {
+    match self.state.bound_port.load(Ordering::Relaxed) {
+      0 => None,
+      port => Some(port),
+    }
+  }
+
+  /// Whether a client with this id (from its connection event) is connected, i.e. can be sent to with send_to_client().
+  pub fn is_client_connected(&self, client_id: &str) -> bool {
+    self.state.clients.sender(client_id).is_some()
+  }
+
+  /// Tags a connected client (or, with None, untags it), which gives it the outbound rate limit for its tag in ServerConfig::client_rate_limits from its next write on: the default one if the tag has none. Tags are only labels otherwise, so any string will do, e.g. "lan" or "remote". False if no client with this id is connected.
+  pub fn set_client_tag(&self, client_id: &str, tag: Option<&str>) -> bool {
+    self.state.clients.set_tag(client_id, tag, self.state.config.client_rate_limits.for_tag(tag))
+  }
+
+  /// A connected client's tag (see set_client_tag()): None if it hasn't got one, or no client with this id is connected.
+  pub fn client_tag(&self, client_id: &str) -> Option<String> {
+    self.state.clients.tag(client_id)
+  }
+
+  /// A connected client's stats: its tag, the broadcast messages it's missed, and (if clients are pinged, see ServerConfig::keepalive) its ping round trips. None if no client with this id is connected.
+  pub fn client_stats(&self, client_id: &str) -> Option<ClientStats> {
+    self.state.clients.stats(client_id)
+  }
+
+  /// The capabilities a connected client declared, with the format negotiated for it (see ServerConfig::capabilities and capabilities.rs): None if it hasn't declared any, or no client with this id is connected.
+  pub fn client_capabilities(&self, client_id: &str) -> Option<ClientCapabilities> {
+    self.state.clients.capabilities(client_id)
+  }
+
+  /// The topics connected clients are subscribed to over rosbridge (see ServerConfig::rosbridge and rosbridge.rs), and the clients subscribed to each.
+  pub fn ros_subscriptions(&self) -> HashMap<String, Vec<String>> {
+    self.state.clients.ros_subscriptions()
+  }
+
+  /// The connected clients that have declared capabilities, and theirs, e.g. for encoding each format once and sending it to the clients that negotiated it.
+  pub fn all_client_capabilities(&self) -> HashMap<String, ClientCapabilities> {
+    self.state.clients.all_capabilities()
+  }
+
+  /// Whether the server thread is alive: starting, running, or shutting down.
+  pub fn is_running(&self) -> bool {
+    self.state.is_alive()
+  }
+
+  pub fn stats(&self) -> StatsSnapshot {
+    self.state.stats.snapshot()
+  }
+
+  /// The outbound pipeline's latency histograms, if the server was configured to keep them (ServerConfig::latency_histograms).
+  pub fn latency(&self) -> Option<LatencySnapshot> {
+    self.state.stats.latency()
+  }
+
+  /// What the server holds right now: open sockets, running tasks, registered clients, and what's in its queues (see diagnostics.rs). For telling whether a long-running server leaks.
+  pub fn diagnostics(&self) -> Diagnostics {
+    Diagnostics::take(&self.state)
+  }
+
+  /// The connected clients that have fallen behind the broadcasts and missed messages (see LagPolicy), and how many each has missed.
+  pub fn messages_missed_by_client(&self) -> HashMap<String, u64> {
+    self.state.clients.missed_messages()
+  }
+
+  /// This node's id within its cluster (the configured one, or the one made up for it), if it's a cluster node.
+  pub fn cluster_node_id(&self) -> Option<&str> {
+    self.state.cluster.as_ref().map(|cluster| cluster.node_id())
+  }
+
+  /// The health of the links to each of the cluster's other nodes, in the order they were configured. Empty if the server isn't a cluster node.
+  pub fn cluster_peers(&self) -> Vec<PeerStatus> {
+    self.state.cluster.as_ref().map(|cluster| cluster.peers()).unwrap_or_default()
+  }
+
+  pub(crate) fn state_rx(&self) -> watch::Receiver<RunState> {
+    self.state.ser_state_rx.clone()
+  }
+
+  // Waiting
+  // -------
+
+  /// Blocks until the server has bound its listener, returning true, or until `timeout` elapses, returning false. Fails with Error::Bind if binding failed.
+  pub fn wait_until_started(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+    let mut state_rx = self.state_rx();
+    let startup = async move {
+      loop {
+        let state = RunState::observe(&state_rx);
+        if state != RunState::Starting { return state; }
+        // If the server thread went away, observe() reports it as failed.
+        if state_rx.changed().await.is_err() { return RunState::observe(&state_rx); }
+      }
+    };
+    let state = match timeout {
+      Some(timeout) => cs::block_on(async { tokio::time::timeout(timeout, startup).await.ok() }),
+      None => Some(cs::block_on(startup)),
+    };
+    match state {
+      None => Ok(false),
+      Some(RunState::Failed(reason)) => Err(Error::Bind { port: self.state.port, address: super::bind_address(self.state.port), reason }),
+      // Anything past Starting means the listener was bound (even if the server has stopped again since).
+      Some(_) => Ok(true),
+    }
+  }
+
+  /// Blocks until at least one client is connected, returning true, or until `timeout` elapses or the server stops, returning false. Waits for the server to start first, failing like wait_until_started() if it can't.
+  pub fn wait_for_client(&self, timeout: Option<Duration>) -> Result<bool, Error> {
+    let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+    if !self.wait_until_started(timeout)? {
+      return Ok(false);
+    }
+
+    let mut state_rx = self.state_rx();
+    let mut clients_rx = self.state.stats.subscribe_current_clients();
+    let connected = async move {
+      tokio::select! {
+        connected = clients_rx.wait_for(|clients| *clients > 0) => connected.is_ok(),
+        // Also resolves (with an error) if the server thread is gone.
+        _ = state_rx.wait_for(|state| !state.is_alive()) => false,
+      }
+    };
+    Ok(match deadline {
+      Some(deadline) => {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        cs::block_on(async { tokio::time::timeout(remaining, connected).await.unwrap_or(false) })
+      }
+      None => cs::block_on(connected),
+    })
+  }
+
+  // Sending
+  // -------
+
+  /// Sends messages to all connected clients, and, for a cluster node, to the clients of the other nodes (its text and binary messages, that is). Sending with no clients connected isn't an error; the messages just go nowhere.
+  ///
+  /// Doesn't block, unless the server's lag policy is LagPolicy::Block: then, if the slowest client's queue is full, this waits for it to make room (up to the policy's timeout) rather than have that client miss the oldest broadcasts.
+  ///
+  /// Takes Messages, or Outbounds for binary payloads shared rather than copied (see outbound.rs).
+  pub fn send<M: Into<Outbound>>(&self, messages: Vec<M>) -> Result<(), Error> {
+    let started = Instant::now();
+    let messages = outbound::collect(messages);
+    let message_count = messages.len();
+    let charge = self.charge(&messages)?;
+    if let Some(cluster) = &self.state.cluster {
+      if self.is_running() { cluster.publish(&messages); }
+    }
+    let waited = self.state.ser_msg_tx.wait_for_room(|| self.is_running());
+    if !waited.is_zero() { self.state.stats.waited_for_room(waited); }
+    // Sends fail both when there are no connected clients and when the server has stopped; only the latter is an error.
+    if let Err(unsent) = self.state.ser_msg_tx.send(messages, charge) {
+      buffer_pool::OUTBOUND.recycle_shared(unsent);
+      if !self.is_running() { return Err(self.sent_after_shutdown(message_count)); }
+      return Ok(());
+    }
+    self.state.stats.enqueued(started.elapsed(), message_count);
+    Ok(())
+  }
+
+  /// As send(), stamping the messages with the server's wall and monotonic clocks as they're sent (see clock.rs): a JSON object has the times spliced in as its first fields, other text is wrapped in a JSON object with them, and binary payloads are prefixed with them.
+  pub fn send_stamped<M: Into<Outbound>>(&self, messages: Vec<M>) -> Result<(), Error> {
+    let now = Timestamp::now();
+    self.send(messages.into_iter().map(|message| clock::stamp(message.into(), now)).collect())
+  }
+
+  /// Sends a sample under `topic` to all connected clients, at most at the topic's maximum rate, if it has one (ServerConfig::topic_rates; see decimation.rs): a sample that comes sooner after the last one sent is held, and goes out as the interval's up, unless a later one replaces it (or, averaging, is averaged with it). Never blocks, on a decimated topic, whatever the lag policy. On any other topic, it's sent as by send().
+  pub fn send_sample<M: Into<Outbound>>(&self, topic: &str, message: M) -> Result<(), Error> {
+    let started = Instant::now();
+    let message = message.into();
+    let Some(decimator) = self.state.decimator.as_ref().filter(|decimator| decimator.decimates(topic)) else {
+      return self.send(vec![message]);
+    };
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    decimator.offer(topic, message);
+    self.state.stats.enqueued(started.elapsed(), 1);
+    Ok(())
+  }
+
+  /// Sends a point cloud to all connected clients as a binary message, packed as geometry.rs lays out: `positions` holds x, y, z for each point in turn, and `colors` (if given) 3 or 4 bytes (RGB or RGBA) for each, with how many. A malformed cloud (a position missing a coordinate, or colors that don't match the points) isn't sent, and returns Error::Send.
+  pub fn send_pointcloud(&self, positions: &[f32], colors: Option<(&[u8], u8)>) -> Result<(), Error> {
+    let message = geometry::point_cloud(positions, colors).map_err(|reason| Error::Send { reason, message_count: 1 })?;
+    self.send(vec![message])
+  }
+
+  /// Sends a triangle mesh to all connected clients as a binary message, packed as geometry.rs lays out: `vertices` holds x, y, z for each vertex in turn, and `faces` three vertex indices for each triangle. A malformed mesh (a vertex missing a coordinate, a face missing an index, or an index past the last vertex) isn't sent, and returns Error::Send.
+  pub fn send_mesh(&self, vertices: &[f32], faces: &[u32]) -> Result<(), Error> {
+    let message = geometry::mesh(vertices, faces).map_err(|reason| Error::Send { reason, message_count: 1 })?;
+    self.send(vec![message])
+  }
+
+  /// Sends a message under `topic`, which must be in a sync group (ServerConfig::sync_groups; see sync_groups.rs), to all connected clients, tagged with its group, topic and presentation timestamp: `pts_us`, in microseconds on whatever clock the group's streams share, or, if None, the server's monotonic clock now (as in send_stamped()). The group's messages go out in pts order, each after the group's window; one sent after a message with a later pts has gone out is dropped, and counted in the stats (synced_late). Never blocks, whatever the lag policy. Returns Error::Send if the topic isn't in a group.
+  pub fn send_synced<M: Into<Outbound>>(&self, topic: &str, message: M, pts_us: Option<u64>) -> Result<(), Error> {
+    let started = Instant::now();
+    let message = message.into();
+    let Some(sequencer) = self.state.sequencer.as_ref().filter(|sequencer| sequencer.group(topic).is_some()) else {
+      buffer_pool::OUTBOUND.recycle_messages(vec![message]);
+      return Err(Error::Send { reason: format!("topic {:?} isn't in a sync group", topic), message_count: 1 });
+    };
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    sequencer.offer(topic, message, pts_us.unwrap_or_else(|| Timestamp::now().mono_us));
+    self.state.stats.enqueued(started.elapsed(), 1);
+    Ok(())
+  }
+
+  /// Sends a figure to all connected clients on the figure topic, under `id` (see figures.rs): a Plotly figure's JSON as text, or an image as a PNG, encoding one given as RGBA pixels first. A malformed figure (JSON that isn't an object, bytes that aren't a PNG, or pixels that don't add up to the image) isn't sent, and returns Error::Send.
+  pub fn send_figure(&self, id: &str, figure: Figure) -> Result<(), Error> {
+    let message = figures::message(id, figure).map_err(|reason| Error::Send { reason, message_count: 1 })?;
+    self.send(vec![message])
+  }
+
+  /// Logs a scalar metric's value at `step`, under `tag`, for the server to broadcast with the others logged over its flush interval, and keep for clients that connect later (see metrics.rs). A tag that's empty or too long returns Error::Send.
+  pub fn log_scalar(&self, tag: &str, step: i64, value: f64) -> Result<(), Error> {
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    self.state.metrics.log_scalar(tag, step, value).map_err(|reason| Error::Send { reason, message_count: 1 })
+  }
+
+  /// Logs a histogram of `values` in `bins` bins at `step`, under `tag`, like log_scalar(). A tag that's empty or too long, or a bin count of 0 or more than metrics::MAX_BINS, returns Error::Send.
+  pub fn log_histogram(&self, tag: &str, step: i64, values: &[f64], bins: usize) -> Result<(), Error> {
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    let histogram = Histogram::new(step, values, bins).map_err(|reason| Error::Send { reason, message_count: 1 })?;
+    self.state.metrics.log_histogram(tag, histogram).map_err(|reason| Error::Send { reason, message_count: 1 })
+  }
+
+  /// Sends a frame under `topic` to all connected clients, latest-frame-only (see frames.rs): a client that hasn't been written the topic's previous frame yet skips it, and is written this one in its place, so a slow client never has more than one frame of a topic pending. (As does a client that's been written one too recently, if the topic's throttled for it.) Never blocks, whatever the lag policy.
+  pub fn send_frame<M: Into<Outbound>>(&self, topic: &str, message: M) -> Result<(), Error> {
+    let started = Instant::now();
+    let message = message.into();
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    let charge = self.charge(std::slice::from_ref(&message))?;
+    let frame = Arc::new(Frame::new(topic, message, charge));
+    let skipped = self.state.clients.put_frame(&frame);
+    if skipped > 0 { self.state.stats.frames_skipped(skipped as u64); }
+    // (With no clients connected, it goes nowhere, like a broadcast.)
+    frames::recycle(frame);
+    self.state.stats.enqueued(started.elapsed(), 1);
+    Ok(())
+  }
+
+  /// Sends a keyframe on `topic` to all connected clients, starting the topic's keyframe stream over (see keyframes.rs): the deltas sent on the topic from now on follow this keyframe. It's kept, along with the deltas, for the clients that connect (or fall behind) later, who are sent it before the deltas. Never blocks, whatever the lag policy.
+  pub fn send_keyframe<M: Into<Outbound>>(&self, topic: &str, message: M) -> Result<(), Error> {
+    let started = Instant::now();
+    let message = message.into();
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    let charge = self.charge(std::slice::from_ref(&message))?;
+    self.state.ser_msg_tx.send_keyframe(topic, message, charge);
+    self.state.stats.enqueued(started.elapsed(), 1);
+    Ok(())
+  }
+
+  /// Sends a delta on `topic` to all connected clients, following the topic's latest keyframe and the deltas sent since. A client that's missed any of those (it's just connected, or it fell behind the broadcasts) is sent the keyframe and deltas it needs first. Fails with Error::Send if no keyframe has been sent on the topic. Never blocks, whatever the lag policy.
+  pub fn send_delta<M: Into<Outbound>>(&self, topic: &str, message: M) -> Result<(), Error> {
+    let started = Instant::now();
+    let message = message.into();
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    let charge = self.charge(std::slice::from_ref(&message))?;
+    if let Err(unsent) = self.state.ser_msg_tx.send_delta(topic, message, charge) {
+      buffer_pool::OUTBOUND.recycle_messages(vec![unsent]);
+      return Err(Error::Send { reason: format!("no keyframe has been sent on topic {:?}", topic), message_count: 1 });
+    }
+    self.state.stats.enqueued(started.elapsed(), 1);
+    Ok(())
+  }
+
+  /// Queues messages for a single client (by the id from its connection events), without waiting for them to be written.
+  pub fn send_to_client<M: Into<Outbound>>(&self, client_id: &str, messages: Vec<M>) -> Result<(), Error> {
+    self.send_to_client_with(client_id, messages, Delivery::Queue)
+  }
+
+  /// Pings a single client, with a payload of up to 125 bytes, which its pong should echo (see ServerConfig::ping_events). The ping is queued like any message sent to the client alone, and pongs to it don't time the client's round trips (see Server::client_stats()).
+  pub fn send_ping(&self, client_id: &str, payload: Vec<u8>) -> Result<(), Error> {
+    if payload.len() > MAX_PING_PAYLOAD {
+      return Err(Error::Send { reason: format!("a ping's payload can be at most {} bytes, not {}", MAX_PING_PAYLOAD, payload.len()), message_count: 1 });
+    }
+    self.send_to_client(client_id, vec![Message::Ping(payload)])
+  }
+
+  /// Sends messages to a single client, then blocks until they've been written and flushed to its socket (or `timeout` elapses).
+  pub fn send_and_confirm<M: Into<Outbound>>(&self, client_id: &str, messages: Vec<M>, timeout: Option<Duration>) -> Result<(), Error> {
+    self.send_to_client_with(client_id, messages, Delivery::Confirm { timeout })
+  }
+
+  pub fn send_to_client_with<M: Into<Outbound>>(&self, client_id: &str, messages: Vec<M>, delivery: Delivery) -> Result<(), Error> {
+    let started = Instant::now();
+    let messages = outbound::collect(messages);
+    let message_count = messages.len();
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(message_count));
+    }
+    let client = self.state.clients.sender(client_id);
+    if client.is_none() {
+      return Err(Error::Send { reason: format!("no client {} is connected", client_id), message_count });
+    }
+    let charge = self.charge(&messages)?;
+    // (The messages are queued from here on: waiting for room in the client's queue counts as fan-out.)
+    self.state.stats.enqueued(started.elapsed(), message_count);
+    deliver(&client.unwrap(), messages, Some(charge), delivery, "the client").map_err(|reason| Error::Send { reason, message_count })
+  }
+
+  /// Publishes a ROS message on `topic` to the clients subscribed to it over rosbridge (see rosbridge.rs), as a publish op: `msg` is the message, as a JSON object. Returns how many clients it was queued for; a subscriber whose queue is full misses it, and it's counted as lagged. Fails with Error::Send if the server doesn't speak rosbridge (ServerConfig::rosbridge), or `msg` isn't an object. Never blocks.
+  pub fn publish_ros(&self, topic: &str, msg: &str) -> Result<usize, Error> {
+    let started = Instant::now();
+    if !self.is_running() {
+      return Err(self.sent_after_shutdown(1));
+    }
+    if !self.state.config.rosbridge {
+      return Err(Error::Send { reason: "the server wasn't started with rosbridge".to_string(), message_count: 1 });
+    }
+    let trimmed = msg.trim();
+    if !(trimmed.starts_with('{') && trimmed.ends_with('}')) {
+      return Err(Error::Send { reason: "a ROS message must be a JSON object".to_string(), message_count: 1 });
+    }
+    let message = Outbound::from(PreparedMessage::text(&rosbridge::publish(topic, trimmed)));
+    let mut queued = 0;
+    for subscriber in self.state.clients.ros_subscribers(topic) {
+      let charge = self.charge(std::slice::from_ref(&message))?;
+      match deliver(&subscriber, vec![message.clone()], Some(charge), Delivery::Queue, "the client") {
+        Ok(()) => { queued += 1; }
+        Err(_) => { self.state.stats.messages_dropped(DropReason::Lagged, 1); }
+      }
+    }
+    self.state.stats.enqueued(started.elapsed(), queued);
+    Ok(queued)
+  }
+
+  /// Counts messages sent once the server's stopped as dropped, recording an event for them, and returns the error for the sender.
+  fn sent_after_shutdown(&self, message_count: usize) -> Error {
+    self.state.stats.messages_dropped(DropReason::Shutdown, message_count as u64);
+    self.state.stats.record_error(Severity::Warning, Category::Send, format!("{} message(s) were sent after the server shut down, and dropped.", message_count), None);
+    Error::NotRunning
+  }
+
+  /// Takes messages' bytes out of the memory budget while they're queued (see budget.rs), failing if they'd take it over.
+  fn charge(&self, messages: &[Outbound]) -> Result<Charge, Error> {
+    let bytes = messages.iter().map(Outbound::len).sum();
+    self.state.stats.memory().charge(bytes, messages.len()).map_err(|err| Error::Send { reason: err.to_string(), message_count: messages.len() })
+  }
+
+  // Draining
+  // --------
+
+  /// Takes up to `max_messages` pending text and binary messages from clients (control messages are skipped). If `timeout` is given and none are pending, blocks until at least one arrives or the timeout elapses.
+  ///
+  /// Each message goes to exactly one caller. Only one caller can take messages at a time, so a caller that would have to wait for another's drain waits no longer than its own timeout; without a timeout it returns an empty list right away (the other drain is taking the pending messages anyway).
+  pub fn drain_messages(&self, timeout: Option<Duration>, max_messages: usize) -> Result<Vec<ClientMessage>, Error> {
+    let mut messages = vec![];
+    self.drain_messages_into(timeout, max_messages, &mut messages)?;
+    Ok(messages)
+  }
+
+  /// Drains as drain_messages() does, appending the messages to `messages` and returning how many there were. For callers that drain in a loop: a buffer kept from drain to drain has room for the next batch already, so a burst of small messages doesn't spend the drain growing a new one.
+  pub fn drain_messages_into(&self, timeout: Option<Duration>, max_messages: usize, messages: &mut Vec<ClientMessage>) -> Result<usize, Error> {
+    let shared_rx = self.state.cli_msg_rx.shared().ok_or(Error::ReceiverUnavailable)?;
+    Ok(drain_shared_into(&shared_rx, &self.state.notifier, timeout, max_messages, messages))
+  }
+
+  /// The server's events (client messages, connections and disconnections, and errors recorded from now on) as a Stream, in the order they happened, for select!-ing over in the caller's own runtime (any runtime will do). The stream ends once the server has stopped and every event has been yielded.
+  ///
+  /// While the stream exists, it has the server's events to itself: drains return nothing. Fails with Error::ReceiverUnavailable if another stream (or a ServerHandler) already has them.
+  ///
+  /// The methods that block (the waits, drains with a timeout, send_and_confirm(), shutdown(true)) mustn't be called from async tasks; use spawn_blocking() for them. send(), send_to_client() and shutdown(false) don't block.
+  pub fn events(&self) -> Result<EventStream, Error> {
+    Ok(EventSource::new(self, self.state.stats.subscribe_errors())?.into_stream())
+  }
+
+  /// Takes all pending connection events (clients connecting and disconnecting), oldest first. Like a drain of messages without a timeout, returns nothing while another drain (or an event stream) is taking them.
+  pub fn drain_connection_events(&self) -> Vec<ConnectionEvent> {
+    let shared_rx = self.state.cli_conn_rx.shared();
+    let mut events = vec![];
+    if let Some(mut rx) = shared_rx.as_ref().and_then(|shared_rx| shared_rx.try_lock().ok()) {
+      while let Ok(event) = rx.try_recv() { events.push(event); }
+    }
+    events
+  }
+
+  /// Takes all pending ping events (clients' pings and pongs), oldest first, if the server was configured to report them (ServerConfig::ping_events); nothing otherwise. Up to PING_EVENT_QUEUE_LEN are kept for the next drain; after that, they're dropped until there's room.
+  pub fn drain_ping_events(&self) -> Vec<PingEvent> {
+    let shared_rx = self.state.cli_ping_rx.shared();
+    let mut events = vec![];
+    if let Some(mut rx) = shared_rx.as_ref().and_then(|shared_rx| shared_rx.try_lock().ok()) {
+      while let Ok(event) = rx.try_recv() { events.push(event); }
+    }
+    events
+  }
+
+  /// Where the clients' playback is (see playback.rs), if the server takes playback controls (ServerConfig::playback_control); None otherwise.
+  pub fn playback_state(&self) -> Option<PlaybackState> {
+    self.state.playback.as_ref().map(|playback| playback.state())
+  }
+
+  /// Takes all pending playback events (the controls clients sent, each with the state it left), oldest first, if the server takes playback controls; nothing otherwise. Up to PLAYBACK_EVENT_QUEUE_LEN are kept for the next drain; after that, they're dropped until there's room.
+  pub fn drain_playback_events(&self) -> Vec<PlaybackEvent> {
+    self.state.playback.as_ref().map(|playback| playback.drain()).unwrap_or_default()
+  }
+
+  /// Takes all pending input snapshots (each client's latest value of each input it sent over an interval; see inputs.rs), oldest first, if the server aggregates input (ServerConfig::input_aggregation); nothing otherwise. Up to INPUT_SNAPSHOT_QUEUE_LEN are kept for the next drain; after that, input goes on being merged until there's room.
+  pub fn drain_input_snapshots(&self) -> Vec<InputSnapshot> {
+    self.state.inputs.as_ref().map(|inputs| inputs.drain()).unwrap_or_default()
+  }
+
+  /// Connects a LoopbackClient to this server, which must have been started with the loopback transport (ServerConfig::transport) and be running. The connection goes through the same handshake, events and client tasks as a TCP one, without a socket; by the time this returns, sends to the client are routed.
+  pub fn connect_loopback(&self) -> Result<LoopbackClient, Error> {
+    let connector = self.state.loopback.as_ref().ok_or_else(|| Error::Internal("The server wasn't started with the loopback transport.".to_string()))?;
+    let (pipe, client_id, registered) = connector.connect()?;
+    LoopbackClient::handshake(pipe, client_id, registered)
+  }
+
+  /// A tower Service that accepts websocket upgrade requests as this server's clients, for mounting in another HTTP server (e.g. an axum application's router). The server must have been started with the service transport (ServerConfig::transport); its connections then go through the same client tasks and events as TCP ones.
+  #[cfg(feature = "tower")]
+  pub fn service(&self) -> Result<super::WebSocketService, Error> {
+    self.state.service.as_ref().map(|connector| connector.service(self.state.stats.clone(), self.state.metrics.clone()))
+      .ok_or_else(|| Error::Internal("The server wasn't started with the service transport.".to_string()))
+  }
+
+  // Recording
+  // ---------
+
+  /// Starts recording the session to `path` (created, or truncated) in `format` (see recording.rs for both formats): every text and binary message clients send the server, and every one it sends them, with when it passed through the server, which way it went, and the client (none for broadcasts, which are recorded once however many clients they reach). Recording goes on until stop_recording(), or until the server stops. The server never waits for the file: messages the writer can't keep up with are left out, and counted in RecordingStats::dropped. Fails with Error::Recording if a recording's in progress already, or the file can't be created.
+  pub fn start_recording(&self, path: impl AsRef<Path>, format: RecordingFormat) -> Result<(), Error> {
+    if !self.state.is_alive() { return Err(Error::NotRunning); }
+    self.state.recorder.start(path.as_ref(), format, &self.state.ser_msg_tx).map_err(Error::Recording)
+  }
+
+  /// Stops the recording in progress, returning how it went once everything recorded is in the file; None if nothing's being recorded (the recording stops by itself when the server does).
+  pub fn stop_recording(&self) -> Option<RecordingStats> {
+    self.state.recorder.stop()
+  }
+
+  /// How the recording in progress is going, if there is one.
+  pub fn recording_stats(&self) -> Option<RecordingStats> {
+    self.state.recorder.stats()
+  }
+
+  // Draining and shutting down
+  // --------------------------
+
+  /// Stops accepting connections, while the clients already connected go on being served as before, for a rollout: the listener is closed (so a load balancer's health checks see the server's gone, and the server replacing it can bind the port), and the state becomes RunState::Draining. Once the last client has disconnected, a ConnectionChange::Drained event is reported (at once, if there are none). The server keeps running until it's shut down. Draining a server that's draining or stopped does nothing.
+  pub fn begin_draining(&self) {
+    self.state.ser_req_drain_tx.send_replace(true);
+  }
+
+  /// Blocks until the server is draining with no clients left (see begin_draining()), or has stopped, returning true; or until `timeout` elapses, returning false.
+  pub fn wait_until_drained(&self, timeout: Option<Duration>) -> bool {
+    let mut state_rx = self.state_rx();
+    let mut clients_rx = self.state.stats.subscribe_current_clients();
+    let drained = async move {
+      loop {
+        let state = RunState::observe(&state_rx);
+        if !state.is_alive() || (state == RunState::Draining && *clients_rx.borrow_and_update() == 0) { return; }
+        // (If the server thread is gone, observe() reports it as failed.)
+        tokio::select! {
+          _ = state_rx.changed() => {}
+          _ = clients_rx.changed() => {}
+        }
+      }
+    };
+    match timeout {
+      Some(timeout) => cs::block_on(async { tokio::time::timeout(timeout, drained).await.is_ok() }),
+      None => { cs::block_on(drained); true }
+    }
+  }
+
+  /// Requests that the server shut down: connected clients are sent close frames (1001 Going Away), which they're given a couple of seconds to answer, and the server thread winds down. If `wait` is true, blocks until the thread has exited and been joined. Shutting down a stopped server does nothing.
+  pub fn shutdown(&self, wait: bool) -> Result<(), Error> {
+    self.shutdown_with(ShutdownOptions::default(), wait)
+  }
+
+  /// shutdown(), as `options` say: sending clients their close frame, and aborting whatever's still running at their deadline. The server thread only winds down once every client has answered its close frame (or the close timeout is up). If a shutdown was already requested, its options are the ones that count.
+  pub fn shutdown_with(&self, options: ShutdownOptions, wait: bool) -> Result<(), Error> {
+    options.validate().map_err(Error::InvalidConfig)?;
+    {
+      let mut shutdown_options = self.state.shutdown_options.lock().unwrap_or_else(PoisonError::into_inner);
+      if !self.state.shutdown_requested() { *shutdown_options = options; }
+      // (Updates the value even once the server thread is gone, so shutdown_requested() still reports the request.)
+      self.state.ser_req_shutdown_tx.send_replace(true);
+    }
+    if wait { self.join(); }
+    Ok(())
+  }
+
+  /// Blocks until the server thread has finished (or gone away), returning false if `timeout` elapses first. Doesn't request a shutdown itself.
+  pub fn wait_for_shutdown(&self, timeout: Option<Duration>) -> bool {
+    let mut state_rx = self.state_rx();
+    // Resolves with an error if the server thread is gone, which counts as done too.
+    let stopped = async move { let _ = state_rx.wait_for(|state| !state.is_alive()).await; };
+    match timeout {
+      Some(timeout) => cs::block_on(async { tokio::time::timeout(timeout, stopped).await.is_ok() }),
+      None => { cs::block_on(stopped); true }
+    }
+  }
+
+  /// Joins the server thread, and then the handler thread (so every event has been handled), if nobody has yet. Only call once the server has stopped, or been asked to.
+  pub fn join(&self) {
+    if let Some(thread_handle) = self.state.ser_thread.take() {
+      if thread_handle.join().is_err() {
+        cs::weakly_record_error("Server thread panicked before shutting down.".to_string());
+      }
+    }
+    if let Some(thread_handle) = self.state.handler_thread.take() {
+      if thread_handle.thread().id() == std::thread::current().id() {
+        // Called from the handler itself; it finishes once it returns.
+        self.state.handler_thread.put(thread_handle);
+        return;
+      }
+      if thread_handle.join().is_err() {
+        cs::weakly_record_error("Server handler panicked.".to_string());
+      }
+    }
+  }
+}
+
+/// Hands `messages` (and their charge against the memory budget, if they have one) to a connection's sender task as `delivery` says; `peer` names the other end in failure reasons (e.g. "the client disconnected").
+pub(crate) fn deliver(sender: &mpsc::Sender<TargetedSend>, messages: Vec<Outbound>, charge: Option<Charge>, delivery: Delivery, peer: &str) -> Result<(), String> {
+  let targeted = |confirm| {
+    let targeted = TargetedSend::new(messages, confirm);
+    match charge {
+      Some(charge) => targeted.with_charge(charge),
+      None => targeted,
+    }
+  };
+  match delivery {
+    Delivery::Queue => {
+      sender.try_send(targeted(None)).map_err(|err| match err {
+        mpsc::error::TrySendError::Full(_)   => format!("{}'s send queue is full", peer),
+        mpsc::error::TrySendError::Closed(_) => format!("{} disconnected", peer),
+      })
+    }
+    Delivery::Confirm { timeout } => {
+      let (confirm_tx, confirm_rx) = oneshot::channel();
+      let confirmed = async move {
+        if sender.send(targeted(Some(confirm_tx))).await.is_err() {
+          return Err(format!("{} disconnected", peer));
+        }
+        // The sender task drops the confirmation sender, unanswered, if the connection closes first.
+        confirm_rx.await.unwrap_or_else(|_| Err(format!("{} disconnected before the messages were written", peer)))
+      };
+      match timeout {
+        Some(timeout) => cs::block_on(async {
+          tokio::time::timeout(timeout, confirmed).await
+            .unwrap_or_else(|_| Err(format!("timed out after {} ms waiting for the messages to be written", timeout.as_millis())))
+        }),
+        None => cs::block_on(confirmed),
+      }
+    }
+  }
+}
+
+/// The body of drain_messages(), for any shared message receiver and the notifier its messages are announced on (a server's, or a client connection's).
+pub(crate) fn drain_shared(shared_rx: &SharedReceiver<ClientMessage>, notifier: &MessageNotifier, timeout: Option<Duration>, max_messages: usize) -> Vec<ClientMessage> {
+  let mut messages = vec![];
+  drain_shared_into(shared_rx, notifier, timeout, max_messages, &mut messages);
+  messages
+}
+
+/// drain_shared(), appending to `messages` (which may already hold some, from the caller's earlier drains); returns how many it appended.
+pub(crate) fn drain_shared_into(shared_rx: &SharedReceiver<ClientMessage>, notifier: &MessageNotifier, timeout: Option<Duration>, max_messages: usize, messages: &mut Vec<ClientMessage>) -> usize {
+  if max_messages == 0 { return 0; }
+  let start = messages.len();
+  let limit = start.saturating_add(max_messages);
+
+  // Fast path: if the receiver's free and messages are pending, take them right away, without entering the runtime or setting up a timer. Without a timeout, that's all a drain does; with one, the wait below only happens when there's nothing to take yet.
+  if let Ok(mut rx) = shared_rx.try_lock() {
+    notifier.clear();
+    drain_pending(&mut rx, messages, limit);
+    if messages.len() > start || timeout.is_none() {
+      rearm_notifier(notifier, messages.len(), limit);
+      return messages.len() - start;
+    }
+  }
+  let timeout = match timeout {
+    Some(timeout) => timeout,
+    None => { return 0; }
+  };
+
+  // Wait for the receiver, then for the first message if nothing is pending. Messages are collected outside the future and recv() is cancel-safe, so none are lost when the timeout hits.
+  let deadline = tokio::time::Instant::now() + timeout;
+  let _ = cs::block_on(async {
+    tokio::time::timeout_at(deadline, async {
+      let mut rx = shared_rx.lock().await;
+      notifier.clear();
+      drain_pending(&mut rx, messages, limit);
+      while messages.len() == start {
+        match rx.recv().await {
+          Some(msg) => { if msg.is_data() { messages.push(msg); } }
+          // The server (or connection) went away.
+          None => { break; }
+        }
+      }
+      drain_pending(&mut rx, messages, limit);
+    }).await
+  });
+  rearm_notifier(notifier, messages.len(), limit);
+
+  messages.len() - start
+}
+
+/// Drains clear the message notifier before taking messages; one that stopped at its limit may have left some behind, so it makes the notifier readable again for them.
+fn rearm_notifier(notifier: &MessageNotifier, drained_to: usize, limit: usize) {
+  if drained_to >= limit { notifier.notify(); }
+}
+
+/// Moves immediately-available client messages from the receiver into `messages` until it holds `limit` of them or nothing is pending.
+fn drain_pending(rx: &mut queue::Receiver<ClientMessage>, messages: &mut Vec<ClientMessage>, limit: usize) {
+  // Size the batch up front rather than growing it message by message (a reused buffer usually has the room already).
+  messages.reserve(rx.len().min(limit - messages.len()));
+  while messages.len() < limit {
+    match rx.try_recv() {
+      Ok(msg) => { if msg.is_data() { messages.push(msg); } }
+      // Empty, or the server went away.
+      Err(_) => { break; }
+    }
+  }
+}
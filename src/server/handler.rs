@@ -0,0 +1,85 @@
+// handler.rs
+//
+// Push-based delivery of a server's events to a Rust ServerHandler, as an alternative to draining (see Server::start_with_handler()). The handler runs on a dedicated dispatch thread, which owns the server's events (see event_stream.rs; drains return nothing) and calls the handler for each event as soon as it arrives, one call at a time.
+
+use std::{sync::Arc, thread};
+use tokio::sync::broadcast;
+
+use super::{Server, consumer_state::{self as cs, ServerState}, error_events::{Category, ErrorEvent}, event_stream::{EventSource, ServerEvent}, events::{ClientClose, ClientMessage, ConnectionChange}};
+
+/// Handles a server's events as they happen. Every method has a default that does nothing, so implement only the ones you need.
+///
+/// The methods are called from the server's dispatch thread, never concurrently, in the order the events happened: a client's on_connect() comes before its messages, and its on_disconnect() after them. The thread exits once the server has stopped and every event has been handled (shutdown(true) waits for that). A handler that blocks holds up the events after it, but never the server, which keeps queueing them.
+///
+/// `server` is the server the event came from, e.g. for replying with server.send_to_client().
+pub trait ServerHandler: Send + 'static {
+  /// A client completed the websocket handshake.
+  fn on_connect(&mut self, _server: &Server, _client_id: &str) {}
+
+  /// A client sent a text or binary message.
+  fn on_message(&mut self, _server: &Server, _message: ClientMessage) {}
+
+  /// A client sent a close frame, saying why it's disconnecting. Called just before its on_disconnect(); clients whose connections just drop, or that the server closes first, don't get one.
+  fn on_close(&mut self, _server: &Server, _client_id: &str, _close: &ClientClose) {}
+
+  /// A client's connection closed (or the server shut down).
+  fn on_disconnect(&mut self, _server: &Server, _client_id: &str) {}
+
+  /// The server was draining (see Server::begin_draining()), and its last client has disconnected.
+  fn on_drained(&mut self, _server: &Server) {}
+
+  /// A client was marked unhealthy for not answering keepalive pings (`healthy` false; see Keepalive::unhealthy_after), or answered one again (`healthy` true).
+  fn on_health_change(&mut self, _server: &Server, _client_id: &str, _healthy: bool) {}
+
+  /// A client declared its capabilities (see capabilities.rs), for the first time or again; server.client_capabilities() has them.
+  fn on_capabilities(&mut self, _server: &Server, _client_id: &str) {}
+
+  /// The server recorded an error (see error_events.rs). If the handler falls far enough behind, some errors may be skipped; they're still in the process-wide error queue.
+  fn on_error(&mut self, _server: &Server, _error: &ErrorEvent) {}
+}
+
+/// Spawns the dispatch thread for `server`, taking its events. `error_rx` is subscribed before the server thread is launched, so even bind errors reach the handler.
+pub(crate) fn spawn(server: &Arc<ServerState>, handler: Box<dyn ServerHandler>, error_rx: broadcast::Receiver<ErrorEvent>) -> Result<(), String> {
+  let server = Server::from(server.clone());
+  let events = EventSource::new(&server, error_rx).map_err(|err| format!("Failed to take the server's events for its handler: {}", err))?;
+
+  let thread_server = server.clone();
+  let thread = thread::Builder::new()
+    .name("quicksocket-handler".to_string())
+    .spawn(move || run(thread_server, handler, events));
+  if let Err(err) = thread {
+    return Err(format!("Failed to spawn the handler thread: {:?}", err));
+  }
+  server.handler_thread.put(thread.unwrap());
+  Ok(())
+}
+
+/// Dispatch thread loop. Waits for the next event of any kind, then hands it to the handler.
+fn run(server: Server, mut handler: Box<dyn ServerHandler>, mut events: EventSource) {
+  // The channels are runtime-agnostic, so a bare current-thread runtime (no IO/time drivers) is enough to wait on them.
+  let waiter = tokio::runtime::Builder::new_current_thread().build();
+  if let Err(err) = waiter {
+    cs::weakly_record_error_in(Category::Internal, format!("Handler thread failed to create its runtime: {:?}", err));
+    return;
+  }
+  let waiter = waiter.unwrap();
+
+  while let Some(event) = waiter.block_on(events.next()) {
+    match event {
+      ServerEvent::Connection(event) => match event.change {
+        ConnectionChange::Connected    => handler.on_connect(&server, &event.client_id),
+        ConnectionChange::Disconnected => {
+          if let Some(close) = &event.close { handler.on_close(&server, &event.client_id, close); }
+          handler.on_disconnect(&server, &event.client_id)
+        }
+        ConnectionChange::Drained      => handler.on_drained(&server),
+        ConnectionChange::Unhealthy    => handler.on_health_change(&server, &event.client_id, false),
+        ConnectionChange::Healthy      => handler.on_health_change(&server, &event.client_id, true),
+        ConnectionChange::Capabilities => handler.on_capabilities(&server, &event.client_id),
+      },
+      ServerEvent::Message(msg) => handler.on_message(&server, msg),
+      ServerEvent::Error(error) => handler.on_error(&server, &error),
+    }
+  }
+  log_debug!("[handler] Handler thread exiting.");
+}
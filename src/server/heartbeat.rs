@@ -0,0 +1,72 @@
+// heartbeat.rs
+//
+// The heartbeat topic (ServerConfig::heartbeat): a small message the server broadcasts by itself, from its own runtime, at an interval, e.g. {"topic":"heartbeat","seq":42,"server_time_ms":1700000000000,"clients":3}. It doesn't depend on the consumer at all, so a client hearing heartbeats but nothing else knows its connection's fine and it's whatever produces the broadcasts (the Python consumer, stuck in a long computation, say) that's gone quiet; a client hearing nothing at all knows it's the connection (or the whole server). `seq` counts the heartbeats from 0 as the server started (a client can tell it missed some, or that the server restarted); `server_time_ms` is the server's clock when it was sent, in milliseconds since the Unix epoch; and `clients` is how many clients were connected.
+//
+// Heartbeats are broadcast like any other message, queued behind the broadcasts before them (and seen by the inspector and recordings), but only to this server's clients, not a cluster's other nodes. They never wait for room, whatever the lag policy, as the runtime's threads are the clients' too; one that would take the server over its memory budget is skipped.
+
+use std::{sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::{sync::watch, time::{self, Instant, MissedTickBehavior}};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::BroadcastQueue, inspector::json_string, outbound::Outbound, stats::ServerStats};
+
+/// The topic heartbeats are sent under, unless another is given.
+pub const DEFAULT_TOPIC: &str = "heartbeat";
+
+/// How often the server broadcasts heartbeats, and under what topic (ServerConfig::heartbeat).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeartbeatTopic {
+  pub interval: Duration,
+  /// The heartbeats' "topic" field, for clients to tell them from the consumer's messages.
+  pub topic: String,
+}
+
+impl HeartbeatTopic {
+  /// Heartbeats every `interval`, under the default topic.
+  pub fn new(interval: Duration) -> HeartbeatTopic {
+    HeartbeatTopic { interval, topic: DEFAULT_TOPIC.to_string() }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.interval.is_zero() {
+      return Err("the heartbeat interval must be more than 0".to_string());
+    }
+    Ok(())
+  }
+}
+
+/// Broadcasts heartbeats at the configured interval (the first an interval after the server starts) until the server shuts down.
+pub async fn send_heartbeats(
+  config: HeartbeatTopic,
+  stats: Arc<ServerStats>,
+  ser_msg_tx: Arc<BroadcastQueue>,
+  mut ser_req_shutdown_rx: watch::Receiver<bool>
+) {
+  let mut interval = time::interval_at(Instant::now() + config.interval, config.interval);
+  interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  let mut seq: u64 = 0;
+  loop { tokio::select! {
+    _ = interval.tick() => {
+      let messages = vec![Outbound::from(Message::Text(heartbeat(&config.topic, seq, stats.current_clients())))];
+      seq += 1;
+      // (Sending fails when no clients are connected, which is fine: nobody missed it.)
+      match stats.memory().charge(messages[0].len(), 1) {
+        Ok(charge) => { if let Err(unsent) = ser_msg_tx.send(messages, charge) { OUTBOUND.recycle_shared(unsent); } }
+        Err(err) => {
+          log_debug!("[heartbeat] Skipped a heartbeat: {}", err);
+          OUTBOUND.recycle_messages(messages);
+        }
+      }
+    }
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() { break; }
+    }
+  }}
+}
+
+/// The `seq`th heartbeat's JSON.
+fn heartbeat(topic: &str, seq: u64, clients: u64) -> String {
+  let server_time_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+  format!("{{\"topic\":{},\"seq\":{},\"server_time_ms\":{},\"clients\":{}}}", json_string(topic), seq, server_time_ms, clients)
+}
@@ -0,0 +1,243 @@
+// http.rs
+//
+// Minimal HTTP handling for connections that arrive at the server port. The request head is peeked (not consumed) so that websocket upgrade requests can still be handed on untouched (to tungstenite, or a proxy backend), while plain HTTP requests (e.g. a browser pointed at the port, or the inspector page) get a real HTTP response instead of a raw handshake failure. Clients' own upgrades are answered here too (see accept_upgrade()), since their connections are split up for writer.rs rather than handed to tungstenite whole.
+
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+use super::transport::Connection;
+
+/// Maximum size of a request head we're willing to peek at.
+const MAX_HEAD_LEN: usize = 8192;
+/// Maximum number of headers parsed from a request head.
+const MAX_HEADERS: usize = 64;
+/// How long to wait for a complete request head before giving up on the connection.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Body of the 426 response sent to plain HTTP requests when no landing page is configured.
+const DEFAULT_UPGRADE_REQUIRED_BODY: &str = "This is a quicksocket WebSocket server. Connect to it with a WebSocket client.\n";
+
+/// The parts of an HTTP request head the server cares about for routing and upgrade validation.
+pub struct RequestHead {
+  pub method: String,
+  pub path: String,
+  /// HTTP minor version (0 for HTTP/1.0, 1 for HTTP/1.1).
+  pub version: u8,
+  /// Whether the request asks to upgrade to the websocket protocol.
+  pub is_upgrade: bool,
+  /// The (non-empty) Sec-WebSocket-Key.
+  pub ws_key: Option<String>,
+  pub ws_version: Option<String>,
+  /// The extensions the client offers in its Sec-WebSocket-Extensions, if any (see compression.rs).
+  pub ws_extensions: Option<String>,
+  /// Every header (name, and value trimmed), in order, for those the server doesn't look at itself (e.g. the audit log's principal header).
+  headers: Vec<(String, String)>,
+  /// Length in bytes of the request head, as still buffered in the (peeked) stream.
+  head_len: usize,
+}
+
+/// Ways peeking a request head can fail.
+#[derive(Debug)]
+pub enum PeekError {
+  /// The peer sent something that isn't a valid HTTP request head. It should get a 400; `buffered` bytes are waiting in the stream.
+  Malformed { reason: String, buffered: usize },
+  /// The connection failed, closed, or timed out before a complete head arrived. There's nobody sensible to respond to.
+  Failed(String),
+}
+
+impl std::fmt::Display for PeekError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      PeekError::Malformed { reason, .. } => write!(f, "{}", reason),
+      PeekError::Failed(reason)           => write!(f, "{}", reason),
+    }
+  }
+}
+
+/// Peeks at the stream until a complete HTTP request head is available, and parses it. The stream is left unread, so a websocket handshake can still be performed on it afterwards.
+pub async fn peek_request_head(stream: &mut Connection) -> Result<RequestHead, PeekError> {
+  let mut buf = vec![0u8; MAX_HEAD_LEN];
+  let deadline = tokio::time::Instant::now() + HEAD_TIMEOUT;
+  let mut last_len = 0;
+  loop {
+    let len = stream.peek(&mut buf).await.map_err(|err| PeekError::Failed(format!("Failed to peek request head: {:?}", err)))?;
+    if len == 0 { return Err(PeekError::Failed("Connection closed before a request head was received.".to_string())); }
+
+    if len != last_len {
+      let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+      let mut req = httparse::Request::new(&mut headers);
+      match req.parse(&buf[..len]) {
+        Ok(httparse::Status::Complete(head_len)) => { return Ok(RequestHead::from_parsed(&req, head_len)); }
+        Ok(httparse::Status::Partial) => {}
+        Err(err) => { return Err(PeekError::Malformed { reason: format!("Malformed request head: {}", err), buffered: len }); }
+      }
+    }
+    if len >= MAX_HEAD_LEN { return Err(PeekError::Malformed { reason: "Request head too large.".to_string(), buffered: len }); }
+    last_len = len;
+
+    // peek() doesn't consume anything, so it returns immediately while any data is buffered; wait a little for the rest of the head to arrive.
+    if tokio::time::Instant::now() >= deadline { return Err(PeekError::Failed("Timed out waiting for a request head.".to_string())); }
+    tokio::time::sleep(Duration::from_millis(5)).await;
+  }
+}
+
+impl RequestHead {
+  fn from_parsed(req: &httparse::Request, head_len: usize) -> RequestHead {
+    let header_value = |name: &str| {
+      req.headers.iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+        .map(|v| v.trim().to_string())
+    };
+    let header_contains = |name: &str, token: &str| {
+      req.headers.iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .any(|v| v.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    };
+    RequestHead {
+      method: req.method.unwrap_or("").to_string(),
+      path: req.path.unwrap_or("/").to_string(),
+      version: req.version.unwrap_or(0),
+      is_upgrade: header_contains("Connection", "upgrade") && header_contains("Upgrade", "websocket"),
+      ws_key: header_value("Sec-WebSocket-Key").filter(|key| !key.is_empty()),
+      ws_version: header_value("Sec-WebSocket-Version"),
+      ws_extensions: header_value("Sec-WebSocket-Extensions"),
+      headers: req.headers.iter()
+        .filter_map(|h| std::str::from_utf8(h.value).ok().map(|v| (h.name.to_string(), v.trim().to_string())))
+        .collect(),
+      head_len,
+    }
+  }
+
+  /// The head of a request another HTTP server has already parsed (see service.rs).
+  #[cfg(feature = "tower")]
+  pub fn from_request<B>(req: &hyper::Request<B>) -> RequestHead {
+    let header_value = |name: hyper::header::HeaderName| {
+      req.headers().get(name).and_then(|v| v.to_str().ok()).map(|v| v.trim().to_string())
+    };
+    let header_contains = |name: hyper::header::HeaderName, token: &str| {
+      req.headers().get_all(name).iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+    };
+    RequestHead {
+      method: req.method().to_string(),
+      path: req.uri().path_and_query().map(|path| path.to_string()).unwrap_or_else(|| "/".to_string()),
+      version: if req.version() == hyper::Version::HTTP_10 || req.version() == hyper::Version::HTTP_09 { 0 } else { 1 },
+      is_upgrade: header_contains(hyper::header::CONNECTION, "upgrade") && header_contains(hyper::header::UPGRADE, "websocket"),
+      ws_key: header_value(hyper::header::SEC_WEBSOCKET_KEY).filter(|key| !key.is_empty()),
+      ws_version: header_value(hyper::header::SEC_WEBSOCKET_VERSION),
+      ws_extensions: header_value(hyper::header::SEC_WEBSOCKET_EXTENSIONS),
+      headers: req.headers().iter()
+        .filter_map(|(name, v)| v.to_str().ok().map(|v| (name.to_string(), v.trim().to_string())))
+        .collect(),
+      head_len: 0,
+    }
+  }
+
+  /// The first value of a header (whatever its name's case), if the request has it.
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+  }
+
+  /// The request path without any query string.
+  pub fn route(&self) -> &str {
+    self.path.split('?').next().unwrap_or("/")
+  }
+
+  /// Checks that a websocket upgrade request is one tungstenite will accept, returning the error response to send otherwise.
+  pub fn validate_upgrade(&self) -> Result<(), Response> {
+    if self.method != "GET" {
+      return Err(Response::text(400, "Bad Request", "WebSocket upgrade requests must use GET.\n"));
+    }
+    if self.version < 1 {
+      return Err(Response::text(400, "Bad Request", "WebSocket upgrade requests must use HTTP/1.1 or later.\n"));
+    }
+    if self.ws_key.is_none() {
+      return Err(Response::text(400, "Bad Request", "Missing Sec-WebSocket-Key header.\n"));
+    }
+    if self.ws_version.as_deref() != Some("13") {
+      // RFC 6455 section 4.4: advertise the version we do support.
+      return Err(Response::text(426, "Upgrade Required", "Unsupported Sec-WebSocket-Version; only version 13 is supported.\n")
+        .with_header("Sec-WebSocket-Version", "13"));
+    }
+    Ok(())
+  }
+}
+
+/// A complete HTTP response to write to a connection that won't be upgraded.
+pub struct Response {
+  pub status: u16,
+  pub reason: &'static str,
+  pub content_type: &'static str,
+  pub headers: Vec<(&'static str, String)>,
+  pub body: Vec<u8>,
+}
+
+impl Response {
+  pub fn new(status: u16, reason: &'static str, content_type: &'static str, body: Vec<u8>) -> Response {
+    Response { status, reason, content_type, headers: vec![], body }
+  }
+
+  pub fn text(status: u16, reason: &'static str, body: &str) -> Response {
+    Response::new(status, reason, "text/plain; charset=utf-8", body.as_bytes().to_vec())
+  }
+
+  pub fn html(body: &str) -> Response {
+    Response::new(200, "OK", "text/html; charset=utf-8", body.as_bytes().to_vec())
+  }
+
+  pub fn with_header(mut self, name: &'static str, value: &str) -> Response {
+    self.headers.push((name, value.to_string()));
+    self
+  }
+
+  /// The response for plain (non-upgrade) HTTP requests: the configured landing page if there is one, otherwise a friendly 426 Upgrade Required.
+  pub fn landing(landing_page: Option<&str>) -> Response {
+    match landing_page {
+      Some(body) => Response::html(body),
+      None => Response::text(426, "Upgrade Required", DEFAULT_UPGRADE_REQUIRED_BODY).with_header("Upgrade", "websocket"),
+    }
+  }
+}
+
+/// Consumes `consume_len` bytes of the request (the peeked head), then writes a complete HTTP/1.1 response and shuts down the write side of the stream.
+///
+/// (Consuming the head first matters: closing a socket with unread data in its receive buffer resets the connection, which can eat the response.)
+pub async fn write_response(stream: &mut Connection, consume_len: usize, response: &Response) -> std::io::Result<()> {
+  let mut consumed = vec![0u8; consume_len];
+  stream.read_exact(&mut consumed).await?;
+
+  let mut response_head = format!(
+    "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\nCache-Control: no-store\r\n",
+    response.status, response.reason, response.content_type, response.body.len()
+  );
+  for (name, value) in response.headers.iter() {
+    response_head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  response_head.push_str("\r\n");
+
+  stream.write_all(response_head.as_bytes()).await?;
+  stream.write_all(&response.body).await?;
+  stream.flush().await?;
+  stream.shutdown().await
+}
+
+/// Responds to a request whose head was successfully peeked.
+pub async fn respond(stream: &mut Connection, head: &RequestHead, response: &Response) -> std::io::Result<()> {
+  write_response(stream, head.head_len, response).await
+}
+
+/// Completes the handshake of a websocket upgrade request that passed validate_upgrade(): consumes the request head, and writes the 101 response, agreeing to `extensions` if given.
+pub async fn accept_upgrade(stream: &mut Connection, head: &RequestHead, extensions: Option<&str>) -> std::io::Result<()> {
+  let mut consumed = vec![0u8; head.head_len];
+  stream.read_exact(&mut consumed).await?;
+
+  let accept_key = derive_accept_key(head.ws_key.as_deref().unwrap_or("").as_bytes());
+  let extensions = extensions.map_or(String::new(), |extensions| format!("Sec-WebSocket-Extensions: {}\r\n", extensions));
+  let response_head = format!("HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n{}\r\n", accept_key, extensions);
+  stream.write_all(response_head.as_bytes()).await?;
+  stream.flush().await
+}
@@ -0,0 +1,122 @@
+// inputs.rs
+//
+// Input aggregation (ServerConfig::input_aggregation), for interactive demos with many viewers, whose clients send input at a high rate (cursor positions, slider values): 100 clients moving their cursors at 60 Hz would otherwise be 6000 messages a second for the consumer to drain and handle, most of them outdated by the time it does. Clients send their input as text messages
+//
+//   input:<name>:<value>    e.g. input:cursor:0.25,0.75 or input:zoom:2
+//
+// which their receiver tasks take, rather than passing them on to the consumer, keeping only each client's latest value of each input. The server's own task takes those it's been sent since the last, every interval, as one InputSnapshot for the consumer to drain (Server::drain_input_snapshots()): {client: {input: latest value}}, with how many messages went into it. No input, no snapshot.
+//
+// A malformed input (without a name, or a name past a client's MAX_INPUTS_PER_CLIENT'th) changes nothing; it's recorded as a "receive" warning event. At most INPUT_SNAPSHOT_QUEUE_LEN snapshots are kept until they're drained; while the queue's full, input goes on being merged, and goes in the next snapshot there's room for, so only the values in between are lost.
+
+use std::{collections::{HashMap, HashSet, VecDeque}, sync::{Arc, Mutex, PoisonError}, time::{Duration, SystemTime}};
+use tokio::sync::watch;
+
+/// What an input message starts with.
+pub const INPUT_PREFIX: &str = "input:";
+/// How many inputs a client can send (by name); more are malformed.
+pub const MAX_INPUTS_PER_CLIENT: usize = 256;
+/// How many snapshots are kept until they're drained; more wait, merged, for room.
+pub const INPUT_SNAPSHOT_QUEUE_LEN: usize = 1024;
+
+/// The input a message is, if it's one: its name and value, or Err for a malformed one.
+pub fn parse(text: &str) -> Option<Result<(&str, &str), String>> {
+  let input = text.strip_prefix(INPUT_PREFIX)?;
+  Some(match input.split_once(':') {
+    Some((name, value)) if !name.is_empty() => Ok((name, value)),
+    _ => Err(format!("expected input:<name>:<value>, not {:?}", text)),
+  })
+}
+
+/// Checks that the interval's more than 0.
+pub fn validate(interval: Duration) -> Result<(), String> {
+  if interval.is_zero() {
+    return Err("the input aggregation interval must be more than 0".to_string());
+  }
+  Ok(())
+}
+
+/// The clients' latest input over an interval, as drained by Server::drain_input_snapshots().
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputSnapshot {
+  pub timestamp: SystemTime,
+  /// Each client's latest value of each input it sent over the interval, by client id and name.
+  pub inputs: HashMap<String, HashMap<String, String>>,
+  /// How many input messages went into it.
+  pub updates: u64,
+}
+
+#[derive(Default)]
+struct Pending {
+  inputs: HashMap<String, HashMap<String, String>>,
+  updates: u64,
+  /// The names each client's sent, to bound them.
+  names: HashMap<String, HashSet<String>>,
+}
+
+/// A server's aggregated input, shared by its clients' receiver tasks, which merge theirs in, the task that takes snapshots of it, and the consumer.
+pub struct InputAggregator {
+  interval: Duration,
+  pending: Mutex<Pending>,
+  snapshots: Mutex<VecDeque<InputSnapshot>>,
+}
+
+impl InputAggregator {
+  pub fn new(interval: Duration) -> InputAggregator {
+    InputAggregator { interval, pending: Mutex::default(), snapshots: Mutex::default() }
+  }
+
+  /// Merges a client's input in, replacing its last value of it.
+  pub fn merge(&self, client_id: &str, name: &str, value: &str) -> Result<(), String> {
+    let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+    let Pending { inputs, updates, names } = &mut *pending;
+    let sent = names.entry(client_id.to_string()).or_default();
+    if !sent.contains(name) {
+      if sent.len() >= MAX_INPUTS_PER_CLIENT {
+        return Err(format!("a client can't send more than {} inputs: {:?}", MAX_INPUTS_PER_CLIENT, name));
+      }
+      sent.insert(name.to_string());
+    }
+    let client = inputs.entry(client_id.to_string()).or_default();
+    match client.get_mut(name) {
+      Some(latest) => { latest.clear(); latest.push_str(value); }
+      None => { client.insert(name.to_string(), value.to_string()); }
+    }
+    *updates += 1;
+    Ok(())
+  }
+
+  /// Forgets a client that's disconnected, for the names it sent (the input it sent is still in the next snapshot).
+  pub fn forget(&self, client_id: &str) {
+    self.pending.lock().unwrap_or_else(PoisonError::into_inner).names.remove(client_id);
+  }
+
+  /// Takes the input merged since the last snapshot as a snapshot, if there's any, and room for it.
+  fn snapshot(&self) {
+    let mut snapshots = self.snapshots.lock().unwrap_or_else(PoisonError::into_inner);
+    if snapshots.len() >= INPUT_SNAPSHOT_QUEUE_LEN { return; }
+    let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+    if pending.updates == 0 { return; }
+    let inputs = std::mem::take(&mut pending.inputs);
+    let updates = std::mem::take(&mut pending.updates);
+    snapshots.push_back(InputSnapshot { timestamp: SystemTime::now(), inputs, updates });
+  }
+
+  /// Takes the pending snapshots, oldest first.
+  pub fn drain(&self) -> Vec<InputSnapshot> {
+    self.snapshots.lock().unwrap_or_else(PoisonError::into_inner).drain(..).collect()
+  }
+}
+
+/// Takes a snapshot of the aggregated input every interval, until the server shuts down.
+pub async fn take_snapshots(aggregator: Arc<InputAggregator>, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  let mut interval = tokio::time::interval(aggregator.interval);
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  loop {
+    tokio::select! {
+      _ = interval.tick() => aggregator.snapshot(),
+      _ = ser_req_shutdown_rx.changed() => {
+        if *ser_req_shutdown_rx.borrow() { break; }
+      }
+    }
+  }
+}
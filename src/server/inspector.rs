@@ -0,0 +1,250 @@
+// inspector.rs
+//
+// Optional built-in debug inspector. When enabled, GET /inspector serves a self-contained HTML page, which connects back to the server at /inspector/ws to receive periodic JSON snapshots of connected clients, recent messages, and throughput counters.
+//
+// Inspector connections are not websocket clients in the usual sense: they don't produce new client events, don't receive broadcasts, and don't show up in their own client list.
+
+use std::{collections::{BTreeMap, VecDeque}, sync::{Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::BroadcastReceiver, outbound::Outbound, transport::Connection};
+
+/// Path of the inspector HTML page.
+pub const PAGE_PATH: &str = "/inspector";
+/// Path the inspector page connects its websocket feed to.
+pub const FEED_PATH: &str = "/inspector/ws";
+
+/// The inspector page, served as-is.
+pub const PAGE_HTML: &str = include_str!("inspector.html");
+
+/// How many recent messages are kept for display.
+const RECENT_CAPACITY: usize = 200;
+/// How many bytes of a message are previewed.
+const PREVIEW_LEN: usize = 48;
+/// How often the inspector feed pushes a snapshot.
+const FEED_INTERVAL: Duration = Duration::from_millis(500);
+
+struct ClientInfo {
+  connected_at: Instant,
+  msgs_in: u64,
+  bytes_in: u64,
+}
+
+struct RecentMessage {
+  seq: u64,
+  at: Instant,
+  /// "in" for client -> server, "out" for server -> clients.
+  dir: &'static str,
+  /// The originating client for inbound messages; "*" for outbound broadcasts.
+  client: String,
+  kind: &'static str,
+  len: usize,
+  preview: String,
+}
+
+/// Server-wide inspector bookkeeping, shared across the tokio tasks via an Arc.
+pub struct Inspector {
+  started_at: Instant,
+  clients: Mutex<BTreeMap<String, ClientInfo>>,
+  recent: Mutex<VecDeque<RecentMessage>>,
+  next_seq: AtomicU64,
+  msgs_in: AtomicU64,
+  bytes_in: AtomicU64,
+  msgs_out: AtomicU64,
+  bytes_out: AtomicU64,
+}
+
+impl Inspector {
+  pub fn new() -> Inspector {
+    Inspector {
+      started_at: Instant::now(),
+      clients: Mutex::new(BTreeMap::new()),
+      recent: Mutex::new(VecDeque::with_capacity(RECENT_CAPACITY)),
+      next_seq: AtomicU64::new(0),
+      msgs_in: AtomicU64::new(0),
+      bytes_in: AtomicU64::new(0),
+      msgs_out: AtomicU64::new(0),
+      bytes_out: AtomicU64::new(0),
+    }
+  }
+
+  pub fn client_connected(&self, client: &str) {
+    if let Ok(mut clients) = self.clients.lock() {
+      clients.insert(client.to_string(), ClientInfo { connected_at: Instant::now(), msgs_in: 0, bytes_in: 0 });
+    }
+  }
+
+  pub fn client_disconnected(&self, client: &str) {
+    if let Ok(mut clients) = self.clients.lock() {
+      clients.remove(client);
+    }
+  }
+
+  /// Records a message received from a websocket client.
+  pub fn record_inbound(&self, client: &str, msg: &Message) {
+    let len = msg.len();
+    self.msgs_in.fetch_add(1, Ordering::Relaxed);
+    self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+    if let Ok(mut clients) = self.clients.lock() {
+      if let Some(info) = clients.get_mut(client) {
+        info.msgs_in += 1;
+        info.bytes_in += len as u64;
+      }
+    }
+    self.push_recent("in", client, msg);
+  }
+
+  /// Records a message broadcast by the server-side consumer.
+  pub fn record_outbound(&self, msg: &Outbound) {
+    self.msgs_out.fetch_add(1, Ordering::Relaxed);
+    self.bytes_out.fetch_add(msg.len() as u64, Ordering::Relaxed);
+    let msg = match msg {
+      Outbound::Deflated(deflated) => deflated.original(),
+      msg => msg,
+    };
+    match msg {
+      Outbound::Message(msg) => { self.push_recent("out", "*", msg); }
+      Outbound::SharedBinary(data) => { self.push_entry("out", "*", "binary", (**data).as_ref().len(), hex_preview((**data).as_ref())); }
+      Outbound::Prepared(prepared) => match msg.text_data() {
+        Some(text) => { self.push_entry("out", "*", "text", text.len(), text.chars().take(PREVIEW_LEN).collect()); }
+        None => { self.push_entry("out", "*", "binary", prepared.payload().len(), hex_preview(prepared.payload())); }
+      },
+      // (Unwrapped above.)
+      Outbound::Deflated(_) => {}
+    }
+  }
+
+  fn push_recent(&self, dir: &'static str, client: &str, msg: &Message) {
+    let (kind, preview) = match msg {
+      Message::Text(text)    => { ("text", text.chars().take(PREVIEW_LEN).collect()) }
+      Message::Binary(bytes) => { ("binary", hex_preview(bytes)) }
+      Message::Ping(bytes)   => { ("ping", hex_preview(bytes)) }
+      Message::Pong(bytes)   => { ("pong", hex_preview(bytes)) }
+      Message::Close(_)      => { ("close", String::new()) }
+    };
+    self.push_entry(dir, client, kind, msg.len(), preview);
+  }
+
+  fn push_entry(&self, dir: &'static str, client: &str, kind: &'static str, len: usize, preview: String) {
+    let entry = RecentMessage {
+      seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+      at: Instant::now(),
+      dir,
+      client: client.to_string(),
+      kind,
+      len,
+      preview,
+    };
+    if let Ok(mut recent) = self.recent.lock() {
+      if recent.len() >= RECENT_CAPACITY { recent.pop_front(); }
+      recent.push_back(entry);
+    }
+  }
+
+  /// Serializes the current state to the JSON snapshot consumed by the inspector page.
+  fn snapshot_json(&self) -> String {
+    let ms_since_start = |at: Instant| at.saturating_duration_since(self.started_at).as_millis();
+    let now = Instant::now();
+
+    let clients = self.clients.lock().map(|clients| {
+      clients.iter().map(|(id, info)| format!(
+        "{{\"id\":{},\"connected_ms\":{},\"msgs_in\":{},\"bytes_in\":{}}}",
+        json_string(id), now.saturating_duration_since(info.connected_at).as_millis(), info.msgs_in, info.bytes_in
+      )).collect::<Vec<_>>().join(",")
+    }).unwrap_or_default();
+
+    let recent = self.recent.lock().map(|recent| {
+      recent.iter().map(|m| format!(
+        "{{\"seq\":{},\"t_ms\":{},\"dir\":\"{}\",\"client\":{},\"kind\":\"{}\",\"len\":{},\"preview\":{}}}",
+        m.seq, ms_since_start(m.at), m.dir, json_string(&m.client), m.kind, m.len, json_string(&m.preview)
+      )).collect::<Vec<_>>().join(",")
+    }).unwrap_or_default();
+
+    format!(
+      "{{\"uptime_ms\":{},\"totals\":{{\"msgs_in\":{},\"bytes_in\":{},\"msgs_out\":{},\"bytes_out\":{}}},\"clients\":[{}],\"recent\":[{}]}}",
+      ms_since_start(now),
+      self.msgs_in.load(Ordering::Relaxed), self.bytes_in.load(Ordering::Relaxed),
+      self.msgs_out.load(Ordering::Relaxed), self.bytes_out.load(Ordering::Relaxed),
+      clients, recent
+    )
+  }
+}
+
+/// Records every message broadcast by the consumer. This task is just another subscriber to the server message broadcast channel, so it sees each broadcast exactly once regardless of how many clients are connected.
+pub async fn record_broadcasts(
+  inspector: std::sync::Arc<Inspector>,
+  mut ser_msg_rx: BroadcastReceiver,
+  mut ser_req_shutdown_rx: watch::Receiver<bool>
+) {
+  loop { tokio::select! {
+    recv_res = ser_msg_rx.recv() => { match recv_res {
+      Some((msgs, _)) => {
+        for msg in msgs.messages.iter() { inspector.record_outbound(msg); }
+        OUTBOUND.recycle_shared(msgs);
+      }
+      None => { break; }
+    }}
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() { break; }
+    }
+  }}
+}
+
+/// Serves the inspector feed to a connected inspector page until it disconnects or the server shuts down.
+pub async fn serve_feed(
+  inspector: std::sync::Arc<Inspector>,
+  stream: Connection,
+  mut ser_req_shutdown_rx: watch::Receiver<bool>
+) {
+  let ws_stream = match tokio_tungstenite::accept_async(stream).await {
+    Ok(ws_stream) => ws_stream,
+    Err(err) => { log_warn!("[inspector] Error during the inspector websocket handshake: {:?}", err); return; }
+  };
+  let (mut ws_write, mut ws_read) = ws_stream.split();
+  let mut interval = tokio::time::interval(FEED_INTERVAL);
+
+  loop { tokio::select! {
+    _ = interval.tick() => {
+      if ws_write.send(Message::Text(inspector.snapshot_json())).await.is_err() { break; }
+    }
+
+    // The inspector page doesn't send anything meaningful; just watch for it going away.
+    read_res = ws_read.next() => { match read_res {
+      Some(Ok(Message::Close(_))) | Some(Err(_)) | None => { break; }
+      Some(Ok(_)) => {}
+    }}
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() { break; }
+    }
+  }}
+  let _ = ws_write.close().await;
+}
+
+fn hex_preview(bytes: &[u8]) -> String {
+  let mut preview = bytes.iter().take(PREVIEW_LEN / 2).map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+  if bytes.len() > PREVIEW_LEN / 2 { preview.push_str(" ..."); }
+  preview
+}
+
+/// Quotes and escapes a string for embedding in JSON.
+pub(super) fn json_string(s: &str) -> String {
+  let mut out = String::with_capacity(s.len() + 2);
+  out.push('"');
+  for c in s.chars() {
+    match c {
+      '"'  => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
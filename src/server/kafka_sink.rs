@@ -0,0 +1,662 @@
+// kafka_sink.rs
+//
+// Kafka producer sink (the "kafka" feature): copies of a server's client messages are produced to a Kafka topic straight from a task on the client-mode runtime (see client.rs), batched, as they're received rather than as the consumer drains them (which it still does: the sink taps them, see taps.rs, rather than taking them). Speaks just enough of the Kafka protocol for producing (Metadata v1, and Produce v3 with v2 record batches), over plain TCP, so it needs no Kafka client library. Messages that can't be delivered after a few attempts are counted and recorded as "kafka" error events.
+
+use std::{collections::{HashMap, hash_map::Entry}, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, sync::watch, task::JoinHandle, time::Instant};
+
+use super::{Error, Message, Server, client::CLIENT_RT, consumer_state as cs, error_events::{self, Category, Severity}, events::ClientMessage, taps::Tap};
+
+/// How long connecting to a broker, or any one request, may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before retrying a failed produce request (after refreshing the topic's metadata).
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+/// How many times a batch is sent before its messages are given up on.
+const MAX_ATTEMPTS: usize = 3;
+/// Batches stop growing at this many bytes of message payloads, below Kafka's default 1 MB limit on a batch.
+const MAX_BATCH_BYTES: usize = 900 << 10;
+/// The largest response accepted from a broker.
+const MAX_RESPONSE_LEN: usize = 64 << 20;
+/// The default for KafkaSinkConfig::max_batch.
+pub const DEFAULT_MAX_BATCH: usize = 500;
+/// The default for KafkaSinkConfig::linger.
+pub const DEFAULT_LINGER: Duration = Duration::from_millis(5);
+
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+
+/// Where a Kafka sink produces the client messages, and how it batches them.
+#[derive(Clone, Debug, Default)]
+pub struct KafkaSinkConfig {
+  /// Bootstrap brokers, as host[:port] (the port defaults to 9092). Any one of them is enough to find the topic's partition leaders.
+  pub brokers: Vec<String>,
+  /// The topic to produce to. It must exist, unless the brokers create topics automatically.
+  pub topic: String,
+  /// The most messages in one batch (DEFAULT_MAX_BATCH if None).
+  pub max_batch: Option<usize>,
+  /// How long a batch waits for more messages after its first, before it's sent (DEFAULT_LINGER if None).
+  pub linger: Option<Duration>,
+}
+
+/// What a Kafka sink has produced, from KafkaSink::stats().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KafkaSinkStats {
+  /// Messages the partition leaders acknowledged.
+  pub delivered: u64,
+  /// Messages given up on, or missed by a sink too far behind (each failure is also recorded as a "kafka" error event).
+  pub failed: u64,
+  /// Produce requests sent (every attempt, one per partition leader per batch).
+  pub requests: u64,
+}
+
+#[derive(Default)]
+struct SinkCounters {
+  delivered: AtomicU64,
+  failed: AtomicU64,
+  requests: AtomicU64,
+}
+
+/// A sink started with KafkaSink::start(). It runs until stop() is called or the server stops; dropping the KafkaSink doesn't stop it. While it runs, a copy of each of the server's client messages goes to Kafka, and the messages themselves still go to its consumer.
+///
+/// Each record's key is the id of the client that sent it, and its timestamp when the server received it. All of a client's messages go to the same partition (chosen by a hash of the key, though not the Java client's), so they stay in order. Records are acknowledged by the partition leader alone (acks=1).
+pub struct KafkaSink {
+  topic: String,
+  stop_tx: watch::Sender<bool>,
+  task: Mutex<Option<JoinHandle<()>>>,
+  counters: Arc<SinkCounters>,
+}
+
+impl KafkaSink {
+  /// Looks up the topic's partitions, then starts producing `server`'s client messages. Fails with Error::InvalidConfig for a bad broker address or topic name, Error::Connect if no broker can be reached or the topic isn't available, and Error::NotRunning if the server isn't running.
+  pub fn start(server: &Server, config: KafkaSinkConfig) -> Result<KafkaSink, Error> {
+    validate(&config).map_err(Error::InvalidConfig)?;
+    let bootstrap: Vec<String> = config.brokers.iter().map(|broker| broker_address(broker)).collect();
+    if !server.is_running() {
+      return Err(Error::NotRunning);
+    }
+
+    // Look the topic up before tapping the server, so a failure leaves it as it was.
+    let producer = CLIENT_RT.block_on(Producer::connect(bootstrap, config.topic.clone()))
+      .map_err(|reason| Error::Connect { url: format!("kafka://{}/{}", config.brokers.join(","), config.topic), reason })?;
+    let tap = server.taps.tap();
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let counters = Arc::new(SinkCounters::default());
+    let batching = Batching {
+      max_batch: config.max_batch.unwrap_or(DEFAULT_MAX_BATCH).max(1),
+      linger: config.linger.unwrap_or(DEFAULT_LINGER),
+    };
+    log_info!("[kafka_sink] Producing the client messages of port {} to {} ({} partitions).", server.port, config.topic, producer.leaders.len());
+    let task = CLIENT_RT.spawn(run_sink(producer, batching, tap, counters.clone(), stop_rx));
+    Ok(KafkaSink { topic: config.topic, stop_tx, task: Mutex::new(Some(task)), counters })
+  }
+
+  pub fn topic(&self) -> &str {
+    &self.topic
+  }
+
+  pub fn is_running(&self) -> bool {
+    self.task.lock().is_ok_and(|task| task.as_ref().is_some_and(|task| !task.is_finished()))
+  }
+
+  pub fn stats(&self) -> KafkaSinkStats {
+    KafkaSinkStats {
+      delivered: self.counters.delivered.load(Ordering::Relaxed),
+      failed: self.counters.failed.load(Ordering::Relaxed),
+      requests: self.counters.requests.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Stops the sink, waiting for the batch being collected to be delivered. Stopping a stopped sink does nothing. Mustn't be called from an async task.
+  pub fn stop(&self) {
+    self.stop_tx.send_replace(true);
+    let task = self.task.lock().ok().and_then(|mut task| task.take());
+    if let Some(task) = task {
+      let _ = cs::block_on(task);
+    }
+  }
+}
+
+fn validate(config: &KafkaSinkConfig) -> Result<(), String> {
+  if config.brokers.is_empty() {
+    return Err("a Kafka sink needs at least one broker".to_string());
+  }
+  for broker in &config.brokers {
+    let port = broker.rsplit_once(':').map(|(_, port)| port);
+    if broker.is_empty() || broker.starts_with(':') || port.is_some_and(|port| port.parse::<u16>().is_err()) {
+      return Err(format!("{:?} isn't a Kafka broker address (host[:port])", broker));
+    }
+  }
+  let topic_chars_ok = config.topic.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-');
+  if config.topic.is_empty() || config.topic.len() > 249 || !topic_chars_ok || config.topic == "." || config.topic == ".." {
+    return Err(format!("{:?} isn't a valid Kafka topic name", config.topic));
+  }
+  Ok(())
+}
+
+/// A broker's host:port, with the default port if it has none.
+fn broker_address(broker: &str) -> String {
+  match broker.contains(':') {
+    true => broker.to_string(),
+    false => format!("{}:9092", broker),
+  }
+}
+
+// Sink task
+// ---------
+
+struct Batching {
+  max_batch: usize,
+  linger: Duration,
+}
+
+/// Collects the copies of the client messages into batches and delivers each, until stopped or the server stops.
+async fn run_sink(mut producer: Producer, batching: Batching, mut tap: Tap, counters: Arc<SinkCounters>, mut stop_rx: watch::Receiver<bool>) {
+  loop {
+    let (batch, finished) = collect_batch(&mut tap, &batching, &mut stop_rx).await;
+    if !batch.is_empty() {
+      producer.deliver(batch, &counters).await;
+    }
+    let missed = tap.take_missed();
+    if missed > 0 {
+      counters.failed.fetch_add(missed, Ordering::Relaxed);
+      record_error(format!("Missed {} client message(s), being too far behind to take them.", missed));
+    }
+    if finished { break; }
+  }
+  log_debug!("[kafka_sink] Sink task exiting.");
+}
+
+/// Waits for a message, then collects more until the batch is full or has lingered long enough. Also returns whether the sink should finish (once this batch is delivered): it's been stopped, or the server has.
+async fn collect_batch(tap: &mut Tap, batching: &Batching, stop_rx: &mut watch::Receiver<bool>) -> (Vec<Record>, bool) {
+  let mut batch = vec![];
+  let mut batch_bytes = 0;
+  let mut deadline = None;
+  while batch.len() < batching.max_batch && batch_bytes < MAX_BATCH_BYTES {
+    let linger = async {
+      match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+      }
+    };
+    let msg = tokio::select! {
+      msg = tap.recv() => msg,
+      _ = stop_rx.wait_for(|stop| *stop) => { return (batch, true); }
+      _ = linger => { break; }
+    };
+    let msg = match msg {
+      Some(msg) => msg,
+      // The server has stopped.
+      None => { return (batch, true); }
+    };
+    if let Some(record) = Record::from_message(msg) {
+      batch_bytes += record.value.len();
+      batch.push(record);
+      deadline.get_or_insert_with(|| Instant::now() + batching.linger);
+    }
+  }
+  (batch, false)
+}
+
+fn record_error(err: String) {
+  log_warn!("[kafka_sink] {}", err);
+  error_events::record(Severity::Warning, Category::Kafka, err, None);
+}
+
+// Producing
+// ---------
+
+/// A client message, as a record to produce.
+struct Record {
+  key: Vec<u8>,
+  value: Vec<u8>,
+  timestamp_ms: i64,
+}
+
+impl Record {
+  /// Text and binary messages become records; control frames are skipped.
+  fn from_message(msg: ClientMessage) -> Option<Record> {
+    let value = match msg.message {
+      Message::Text(text) => text.into_bytes(),
+      Message::Binary(bytes) => bytes,
+      _ => { return None; }
+    };
+    let timestamp_ms = msg.timestamp.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as i64).unwrap_or(0);
+    Some(Record { key: msg.client_id.into_bytes(), value, timestamp_ms })
+  }
+}
+
+/// The topic's partition leaders, and connections to the brokers, kept across batches.
+struct Producer {
+  topic: String,
+  bootstrap: Vec<String>,
+  /// Broker addresses by node id, as of the last metadata refresh.
+  brokers: HashMap<i32, String>,
+  /// Each partition's leader (by node id), by partition index; None while a partition has no leader.
+  leaders: Vec<Option<i32>>,
+  connections: HashMap<i32, KafkaConnection>,
+}
+
+impl Producer {
+  /// Fetches the topic's metadata, for start().
+  async fn connect(bootstrap: Vec<String>, topic: String) -> Result<Producer, String> {
+    let mut producer = Producer { topic, bootstrap, brokers: HashMap::new(), leaders: vec![], connections: HashMap::new() };
+    // A topic the brokers have just created has no leaders for a moment.
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    loop {
+      match producer.refresh_metadata().await {
+        Ok(()) => { return Ok(producer); }
+        Err(err) if err.leaderless && Instant::now() < deadline => { tokio::time::sleep(RETRY_DELAY).await; }
+        Err(err) => { return Err(err.reason); }
+      }
+    }
+  }
+
+  /// Asks each known broker (then each bootstrap broker) in turn for the topic's metadata, until one answers.
+  async fn refresh_metadata(&mut self) -> Result<(), Failure> {
+    let mut addresses: Vec<String> = self.brokers.values().cloned().collect();
+    addresses.extend(self.bootstrap.iter().cloned());
+    let mut last_failure = Failure::retriable("no brokers".to_string());
+    for address in addresses {
+      let metadata = async {
+        let mut connection = KafkaConnection::open(&address).await?;
+        let response = connection.request(API_METADATA, 1, &encode_metadata_request(&self.topic)).await?;
+        parse_metadata_response(&response, &self.topic)
+      };
+      match metadata.await {
+        Ok(metadata) => {
+          if let Some(missing) = metadata.leaders.iter().position(|leader| leader.is_none()) {
+            return Err(Failure { leaderless: true, ..Failure::retriable(format!("topic {} partition {} has no leader", self.topic, missing)) });
+          }
+          self.connections.retain(|node_id, _| metadata.brokers.contains_key(node_id));
+          self.brokers = metadata.brokers;
+          self.leaders = metadata.leaders;
+          return Ok(());
+        }
+        Err(failure) if !failure.retriable => { return Err(failure); }
+        Err(failure) => { last_failure = Failure { reason: format!("{}: {}", address, failure.reason), ..failure }; }
+      }
+    }
+    Err(last_failure)
+  }
+
+  /// Produces the batch, retrying what fails (after refreshing the metadata, since leaders move) until it's delivered or MAX_ATTEMPTS have failed.
+  async fn deliver(&mut self, batch: Vec<Record>, counters: &SinkCounters) {
+    let mut by_partition: HashMap<i32, Vec<Record>> = HashMap::new();
+    for record in batch {
+      let partition = partition_for(&record.key, self.leaders.len());
+      by_partition.entry(partition).or_default().push(record);
+    }
+
+    for attempt in 1..=MAX_ATTEMPTS {
+      let mut by_leader: HashMap<i32, Vec<(i32, Vec<Record>)>> = HashMap::new();
+      for (partition, records) in by_partition.drain() {
+        let leader = self.leaders.get(partition as usize).copied().flatten().unwrap_or(-1);
+        by_leader.entry(leader).or_default().push((partition, records));
+      }
+
+      let mut failures = vec![];
+      for (leader, partitions) in by_leader {
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        let mut results = self.produce(leader, &partitions).await;
+        for (partition, records) in partitions {
+          match results.remove(&partition).unwrap_or_else(|| Err(Failure::retriable("no response for the partition".to_string()))) {
+            Ok(()) => { counters.delivered.fetch_add(records.len() as u64, Ordering::Relaxed); }
+            Err(failure) if failure.retriable && attempt < MAX_ATTEMPTS => { by_partition.insert(partition, records); }
+            Err(failure) => { failures.push((partition, records.len(), failure.reason)); }
+          }
+        }
+      }
+      for (partition, count, reason) in failures {
+        counters.failed.fetch_add(count as u64, Ordering::Relaxed);
+        record_error(format!("Failed to deliver {} message(s) to {} partition {}: {}", count, self.topic, partition, reason));
+      }
+      if by_partition.is_empty() { return; }
+
+      log_debug!("[kafka_sink] Retrying {} partition(s) of {} (attempt {} failed).", by_partition.len(), self.topic, attempt);
+      tokio::time::sleep(RETRY_DELAY).await;
+      if let Err(failure) = self.refresh_metadata().await {
+        log_debug!("[kafka_sink] Refreshing the metadata of {} failed: {}", self.topic, failure.reason);
+      }
+    }
+  }
+
+  /// Sends one produce request for `partitions` to their leader, returning each partition's result. A connection failure fails them all, and closes the connection.
+  async fn produce(&mut self, leader: i32, partitions: &[(i32, Vec<Record>)]) -> HashMap<i32, Result<(), Failure>> {
+    let all_failed = |reason: String| -> HashMap<i32, Result<(), Failure>> { partitions.iter().map(|(partition, _)| (*partition, Err(Failure::retriable(reason.clone())))).collect() };
+    let address = match self.brokers.get(&leader) {
+      Some(address) => address.clone(),
+      None => { return all_failed("the partition has no known leader".to_string()); }
+    };
+    let connection = match self.connections.entry(leader) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => match KafkaConnection::open(&address).await {
+        Ok(connection) => entry.insert(connection),
+        Err(failure) => { return all_failed(format!("{}: {}", address, failure.reason)); }
+      },
+    };
+    let response = connection.request(API_PRODUCE, 3, &encode_produce_request(&self.topic, partitions)).await;
+    match response.and_then(|response| parse_produce_response(&response).map_err(Failure::retriable)) {
+      Ok(results) => results,
+      Err(failure) => {
+        self.connections.remove(&leader);
+        all_failed(format!("{}: {}", address, failure.reason))
+      }
+    }
+  }
+}
+
+/// Which partition a key's records go to: FNV-1a, so a client's messages always land on the same partition.
+fn partition_for(key: &[u8], partitions: usize) -> i32 {
+  let hash = key.iter().fold(0x811c9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193));
+  (hash % partitions.max(1) as u32) as i32
+}
+
+/// Why a request (or a partition's part of one) failed, and whether trying again might help.
+struct Failure {
+  reason: String,
+  retriable: bool,
+  /// The topic (or a partition of it) has no leader yet, as when it's just been created.
+  leaderless: bool,
+}
+
+impl Failure {
+  fn retriable(reason: String) -> Failure {
+    Failure { reason, retriable: true, leaderless: false }
+  }
+
+  fn from_error_code(code: i16) -> Failure {
+    // UNKNOWN_TOPIC_OR_PARTITION, LEADER_NOT_AVAILABLE, NOT_LEADER_OR_FOLLOWER, REQUEST_TIMED_OUT, NETWORK_EXCEPTION, NOT_ENOUGH_REPLICAS(_AFTER_APPEND), all transient.
+    let retriable = matches!(code, 3 | 5 | 6 | 7 | 13 | 19 | 20);
+    Failure { reason: format!("the broker answered with error {}", error_name(code)), retriable, leaderless: code == 5 }
+  }
+}
+
+/// The names of the error codes a producer's likely to see.
+fn error_name(code: i16) -> String {
+  let name = match code {
+    2 => "CORRUPT_MESSAGE",
+    3 => "UNKNOWN_TOPIC_OR_PARTITION",
+    5 => "LEADER_NOT_AVAILABLE",
+    6 => "NOT_LEADER_OR_FOLLOWER",
+    7 => "REQUEST_TIMED_OUT",
+    10 => "MESSAGE_TOO_LARGE",
+    13 => "NETWORK_EXCEPTION",
+    17 => "INVALID_TOPIC_EXCEPTION",
+    18 => "RECORD_LIST_TOO_LARGE",
+    19 => "NOT_ENOUGH_REPLICAS",
+    20 => "NOT_ENOUGH_REPLICAS_AFTER_APPEND",
+    29 => "TOPIC_AUTHORIZATION_FAILED",
+    87 => "INVALID_RECORD",
+    _ => { return code.to_string(); }
+  };
+  format!("{} ({})", code, name)
+}
+
+// Kafka protocol
+// --------------
+
+struct KafkaConnection {
+  stream: TcpStream,
+  next_correlation_id: i32,
+}
+
+impl KafkaConnection {
+  async fn open(address: &str) -> Result<KafkaConnection, Failure> {
+    let stream = tokio::time::timeout(REQUEST_TIMEOUT, TcpStream::connect(address)).await
+      .unwrap_or_else(|_| Err(std::io::Error::other("timed out")))
+      .map_err(|err| Failure::retriable(err.to_string()))?;
+    let _ = stream.set_nodelay(true);
+    Ok(KafkaConnection { stream, next_correlation_id: 0 })
+  }
+
+  /// Sends a request (with a v1 header) and returns the body of its response.
+  async fn request(&mut self, api_key: i16, api_version: i16, body: &[u8]) -> Result<Vec<u8>, Failure> {
+    let correlation_id = self.next_correlation_id;
+    self.next_correlation_id = self.next_correlation_id.wrapping_add(1);
+    let mut request = Encoder::default();
+    request.i16(api_key).i16(api_version).i32(correlation_id).string("quicksocket");
+    request.buf.extend_from_slice(body);
+
+    let exchange = async {
+      self.stream.write_all(&(request.buf.len() as i32).to_be_bytes()).await?;
+      self.stream.write_all(&request.buf).await?;
+      let len = self.stream.read_i32().await?;
+      if len < 4 || len as usize > MAX_RESPONSE_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid response length {}", len)));
+      }
+      let mut response = vec![0u8; len as usize];
+      self.stream.read_exact(&mut response).await?;
+      Ok(response)
+    };
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange).await
+      .map_err(|_| Failure::retriable("timed out waiting for the broker".to_string()))?
+      .map_err(|err| Failure::retriable(err.to_string()))?;
+    if response[..4] != correlation_id.to_be_bytes() {
+      return Err(Failure::retriable("the broker's response was for another request".to_string()));
+    }
+    Ok(response[4..].to_vec())
+  }
+}
+
+fn encode_metadata_request(topic: &str) -> Vec<u8> {
+  let mut body = Encoder::default();
+  body.i32(1).string(topic);
+  body.buf
+}
+
+/// What a metadata response says about the produced topic.
+struct TopicMetadata {
+  /// Broker addresses by node id.
+  brokers: HashMap<i32, String>,
+  /// Each partition's leader, by partition index; None if it has none.
+  leaders: Vec<Option<i32>>,
+}
+
+/// A topic, as a metadata response describes it.
+struct TopicDescription {
+  name: String,
+  error_code: i16,
+  /// (partition index, leader node id) pairs; the leader is -1 if there's none.
+  partitions: Vec<(i32, i32)>,
+}
+
+/// The brokers, and the leaders of `topic`'s partitions, from a v1 metadata response.
+fn parse_metadata_response(response: &[u8], topic: &str) -> Result<TopicMetadata, Failure> {
+  let (brokers, topics) = decode_metadata_response(response).map_err(Failure::retriable)?;
+  let mut description = topics.into_iter()
+    .find(|description| description.name == topic)
+    .ok_or_else(|| Failure::retriable(format!("the broker didn't describe topic {}", topic)))?;
+  if description.error_code != 0 {
+    let failure = Failure::from_error_code(description.error_code);
+    return Err(Failure { reason: format!("topic {}: {}", topic, failure.reason), ..failure });
+  }
+  if description.partitions.is_empty() {
+    return Err(Failure::retriable(format!("topic {} has no partitions", topic)));
+  }
+  description.partitions.sort();
+  let leaders = description.partitions.iter().map(|(_, leader)| (*leader >= 0).then_some(*leader)).collect();
+  Ok(TopicMetadata { brokers, leaders })
+}
+
+fn decode_metadata_response(response: &[u8]) -> Result<(HashMap<i32, String>, Vec<TopicDescription>), String> {
+  let mut decoder = Decoder { buf: response };
+  let mut brokers = HashMap::new();
+  for _ in 0..decoder.array_len()? {
+    let node_id = decoder.i32()?;
+    let host = decoder.string()?.unwrap_or_default();
+    let port = decoder.i32()?;
+    decoder.string()?; // rack
+    brokers.insert(node_id, format!("{}:{}", host, port));
+  }
+  decoder.i32()?; // controller_id
+  let mut topics = vec![];
+  for _ in 0..decoder.array_len()? {
+    let error_code = decoder.i16()?;
+    let name = decoder.string()?.unwrap_or_default();
+    decoder.i8()?; // is_internal
+    let mut partitions = vec![];
+    for _ in 0..decoder.array_len()? {
+      decoder.i16()?; // the partition's error_code (a partition without a leader has a leader_id of -1 too)
+      let index = decoder.i32()?;
+      let leader = decoder.i32()?;
+      for _ in 0..decoder.array_len()? { decoder.i32()?; } // replica_nodes
+      for _ in 0..decoder.array_len()? { decoder.i32()?; } // isr_nodes
+      partitions.push((index, leader));
+    }
+    topics.push(TopicDescription { name, error_code, partitions });
+  }
+  Ok((brokers, topics))
+}
+
+/// A v3 produce request, acks=1, with each partition's records as one v2 record batch.
+fn encode_produce_request(topic: &str, partitions: &[(i32, Vec<Record>)]) -> Vec<u8> {
+  let mut body = Encoder::default();
+  body.i16(-1); // transactional_id (null)
+  body.i16(1).i32(REQUEST_TIMEOUT.as_millis() as i32);
+  body.i32(1).string(topic);
+  body.i32(partitions.len() as i32);
+  for (partition, records) in partitions {
+    let batch = encode_record_batch(records);
+    body.i32(*partition).i32(batch.len() as i32);
+    body.buf.extend_from_slice(&batch);
+  }
+  body.buf
+}
+
+/// Each partition's result from a v3 produce response.
+fn parse_produce_response(response: &[u8]) -> Result<HashMap<i32, Result<(), Failure>>, String> {
+  let mut decoder = Decoder { buf: response };
+  let mut results = HashMap::new();
+  for _ in 0..decoder.array_len()? {
+    decoder.string()?; // name
+    for _ in 0..decoder.array_len()? {
+      let index = decoder.i32()?;
+      let error_code = decoder.i16()?;
+      decoder.i64()?; // base_offset
+      decoder.i64()?; // log_append_time_ms
+      results.insert(index, if error_code == 0 { Ok(()) } else { Err(Failure::from_error_code(error_code)) });
+    }
+  }
+  Ok(results)
+}
+
+/// A v2 record batch ("magic" 2), uncompressed and non-transactional.
+fn encode_record_batch(records: &[Record]) -> Vec<u8> {
+  let base_timestamp = records.iter().map(|record| record.timestamp_ms).min().unwrap_or_else(now_ms);
+  let max_timestamp = records.iter().map(|record| record.timestamp_ms).max().unwrap_or(base_timestamp);
+
+  // From the attributes on, which is what the CRC covers.
+  let mut tail = Encoder::default();
+  tail.i16(0).i32(records.len() as i32 - 1).i64(base_timestamp).i64(max_timestamp);
+  tail.i64(-1).i16(-1).i32(-1); // producer_id, producer_epoch, base_sequence: no idempotence
+  tail.i32(records.len() as i32);
+  for (offset_delta, record) in records.iter().enumerate() {
+    let mut body = Encoder::default();
+    body.i8(0).varint(record.timestamp_ms - base_timestamp).varint(offset_delta as i64);
+    body.varint(record.key.len() as i64);
+    body.buf.extend_from_slice(&record.key);
+    body.varint(record.value.len() as i64);
+    body.buf.extend_from_slice(&record.value);
+    body.varint(0); // headers
+    tail.varint(body.buf.len() as i64);
+    tail.buf.extend_from_slice(&body.buf);
+  }
+
+  let mut batch = Encoder::default();
+  batch.i64(0); // base_offset, assigned by the broker
+  batch.i32((4 + 1 + 4 + tail.buf.len()) as i32); // batch_length, from partition_leader_epoch on
+  batch.i32(-1).i8(2);
+  batch.buf.extend_from_slice(&crc32c(&tail.buf).to_be_bytes());
+  batch.buf.extend_from_slice(&tail.buf);
+  batch.buf
+}
+
+fn now_ms() -> i64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_millis() as i64).unwrap_or(0)
+}
+
+/// CRC-32C (Castagnoli), which v2 record batches are checksummed with.
+fn crc32c(data: &[u8]) -> u32 {
+  lazy_static! {
+    static ref TABLE: [u32; 256] = {
+      let mut table = [0u32; 256];
+      for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 { crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f63b78 } else { crc >> 1 }; }
+        *entry = crc;
+      }
+      table
+    };
+  }
+  !data.iter().fold(!0u32, |crc, byte| TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Big-endian writer for request bodies.
+#[derive(Default)]
+struct Encoder {
+  buf: Vec<u8>,
+}
+
+impl Encoder {
+  fn i8(&mut self, value: i8) -> &mut Encoder { self.buf.push(value as u8); self }
+  fn i16(&mut self, value: i16) -> &mut Encoder { self.buf.extend_from_slice(&value.to_be_bytes()); self }
+  fn i32(&mut self, value: i32) -> &mut Encoder { self.buf.extend_from_slice(&value.to_be_bytes()); self }
+  fn i64(&mut self, value: i64) -> &mut Encoder { self.buf.extend_from_slice(&value.to_be_bytes()); self }
+
+  fn string(&mut self, value: &str) -> &mut Encoder {
+    self.i16(value.len() as i16);
+    self.buf.extend_from_slice(value.as_bytes());
+    self
+  }
+
+  /// A zigzag varint, as records' fields are encoded.
+  fn varint(&mut self, value: i64) -> &mut Encoder {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag >= 0x80 {
+      self.buf.push((zigzag as u8 & 0x7f) | 0x80);
+      zigzag >>= 7;
+    }
+    self.buf.push(zigzag as u8);
+    self
+  }
+}
+
+/// Big-endian reader for response bodies; running out of bytes is an error.
+struct Decoder<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+  fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+    if self.buf.len() < N {
+      return Err("truncated response from the broker".to_string());
+    }
+    let (taken, rest) = self.buf.split_at(N);
+    self.buf = rest;
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(taken);
+    Ok(bytes)
+  }
+
+  fn i8(&mut self) -> Result<i8, String> { self.take::<1>().map(i8::from_be_bytes) }
+  fn i16(&mut self) -> Result<i16, String> { self.take::<2>().map(i16::from_be_bytes) }
+  fn i32(&mut self) -> Result<i32, String> { self.take::<4>().map(i32::from_be_bytes) }
+  fn i64(&mut self) -> Result<i64, String> { self.take::<8>().map(i64::from_be_bytes) }
+
+  /// An array's length; a null array (-1) counts as empty.
+  fn array_len(&mut self) -> Result<usize, String> {
+    Ok(self.i32()?.max(0) as usize)
+  }
+
+  fn string(&mut self) -> Result<Option<String>, String> {
+    let len = self.i16()?;
+    if len < 0 { return Ok(None); }
+    if self.buf.len() < len as usize {
+      return Err("truncated response from the broker".to_string());
+    }
+    let (string, rest) = self.buf.split_at(len as usize);
+    self.buf = rest;
+    Ok(Some(String::from_utf8_lossy(string).into_owned()))
+  }
+}
@@ -0,0 +1,216 @@
+// keepalive.rs
+//
+// Pings for idle connections (ServerConfig::keepalive). NAT gateways and stateful firewalls forget connections that have been quiet for a few minutes, so a dashboard left open with nothing to show loses its connection without either end hearing about it; and a client that vanishes without closing (a laptop lid shut, a phone off the Wi-Fi) holds its connection, and its queue, until TCP gives up on it, which can take hours. So each client's sender task pings it every Keepalive::interval, and its receiver task notes the pongs. A client that has left Keepalive::max_missed_pongs pings in a row unanswered when the next one is due is taken for dead: it's sent a close frame (if its socket will take one), counted in the stats (keepalive_timeouts) and reported as a "receive" error event, and disconnected like any other.
+//
+// It can be marked unhealthy first (Keepalive::unhealthy_after): a client that has left fewer pings unanswered, but still some, is reported to the consumer with a ConnectionChange::Unhealthy event (once, however many more it misses), so it can be shown as lagging, say, or sent less, while it's given the chance to come back; if it answers any ping before it's disconnected, it's reported with a ConnectionChange::Healthy event. Server::client_stats() says whether it's healthy meanwhile.
+//
+// The pings also time the client's link. Each carries the moment it was sent (microseconds since the connection was made, 8 bytes big-endian), which the client's pong echoes back: so the receiver task matches each pong to the ping it answers, however many are in flight, and ignores pongs that don't answer one (unsolicited ones, and those answering the consumer's own pings; see Server::send_ping()). Server::client_stats() has the latest round trip and a smoothed one (an exponentially weighted moving average, weighting each new round trip by 1/8 as TCP does).
+//
+// Clients can also be disconnected for being idle (ServerConfig::idle_timeout): an abandoned browser tab, say, that nobody will look at again, but which keeps its connection (and its share of every broadcast) until it's closed. A client that has neither sent nor been sent a message (text or binary; pings, pongs and the rest don't count, so keepalive pings don't keep it from idling) for that long is sent a close frame (1001 Going Away), counted in the stats (idle_timeouts), and disconnected.
+
+use std::{collections::VecDeque, convert::TryInto, sync::{Mutex, PoisonError, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}}, time::{Duration, Instant}};
+use tokio::{sync::Notify, time::{self, Interval, MissedTickBehavior}};
+
+/// Pongs a client may miss in a row by default before it's taken for dead.
+pub const DEFAULT_MAX_MISSED_PONGS: u32 = 2;
+/// How much each new round trip counts for in the smoothed one.
+const RTT_SMOOTHING: f64 = 1.0 / 8.0;
+
+/// How often clients are pinged, and how many pings they may leave unanswered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+  pub interval: Duration,
+  /// A client that hasn't answered this many pings in a row when the next is due is disconnected. Each ping has at least `interval` to be answered.
+  pub max_missed_pongs: u32,
+  /// If given, a client that hasn't answered this many pings in a row when the next is due is marked unhealthy (and reported, with a ConnectionChange::Unhealthy event) until it answers one. Fewer than `max_missed_pongs`, so it's marked before it's disconnected.
+  pub unhealthy_after: Option<u32>,
+}
+
+impl Keepalive {
+  /// Pings every `interval`, allowing the default number of missed pongs.
+  pub fn new(interval: Duration) -> Keepalive {
+    Keepalive { interval, max_missed_pongs: DEFAULT_MAX_MISSED_PONGS, unhealthy_after: None }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.interval.is_zero() {
+      return Err("the keepalive ping interval must be more than 0".to_string());
+    }
+    if self.max_missed_pongs == 0 {
+      return Err("clients must be allowed to miss at least 1 pong".to_string());
+    }
+    if let Some(unhealthy_after) = self.unhealthy_after {
+      if unhealthy_after == 0 || unhealthy_after >= self.max_missed_pongs {
+        return Err(format!("clients must be marked unhealthy after at least 1 missed pong, and fewer than the {} they're disconnected after (not {})", self.max_missed_pongs, unhealthy_after));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A client's ping round trips: the latest, and the smoothed one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoundTrip {
+  pub latest: Duration,
+  pub smoothed: Duration,
+}
+
+/// One connection's unanswered pings, counted by its sender task (which pings) and reset by its receiver task (which reads the pongs), and whether that's made it unhealthy; when it was last active, noted by both; and the signal from the one to the other that the client's been given up on.
+pub(crate) struct Liveness {
+  keepalive: Option<Keepalive>,
+  unanswered: AtomicU32,
+  unhealthy: AtomicBool,
+  idle_timeout: Option<Duration>,
+  created_at: Instant,
+  /// When the client last sent or was sent a message, in milliseconds since `created_at`.
+  active_at_ms: AtomicU64,
+  rtt: Mutex<RttTimer>,
+  hung_up: Notify,
+  /// Why the server closed the connection, if it did (for the audit log).
+  server_close: Mutex<Option<String>>,
+}
+
+/// The pings still waiting for their pongs (when each was sent, in microseconds since the connection was made, oldest first), and the round trips of those answered.
+#[derive(Default)]
+struct RttTimer {
+  in_flight: VecDeque<u64>,
+  /// None until the client's answered a ping.
+  rtt: Option<RoundTrip>,
+}
+
+impl Liveness {
+  pub fn new(keepalive: Option<Keepalive>, idle_timeout: Option<Duration>) -> Liveness {
+    Liveness { keepalive, unanswered: AtomicU32::new(0), unhealthy: AtomicBool::new(false), idle_timeout, created_at: Instant::now(), active_at_ms: AtomicU64::new(0), rtt: Mutex::default(), hung_up: Notify::new(), server_close: Mutex::default() }
+  }
+
+  /// Resolves whenever a ping is due (after an interval, and then every interval); never, if the client isn't kept alive.
+  pub fn pings(&self) -> PingTimer {
+    PingTimer(self.keepalive.map(|keepalive| {
+      let mut interval = time::interval_at(time::Instant::now() + keepalive.interval, keepalive.interval);
+      // (A sender task held up writing doesn't owe the client a ping per interval it missed.)
+      interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      interval
+    }))
+  }
+
+  /// Called when a ping is due: counts it as sent and returns true, or returns false if the client has already missed as many pongs as it may.
+  pub fn ping_due(&self) -> bool {
+    let max_missed_pongs = self.keepalive.map_or(u32::MAX, |keepalive| keepalive.max_missed_pongs);
+    if self.unanswered.load(Ordering::Relaxed) >= max_missed_pongs { return false; }
+    self.unanswered.fetch_add(1, Ordering::Relaxed);
+    true
+  }
+
+  /// Called when a ping is due, before ping_due(): returns true if the client has just left enough pings unanswered to be marked unhealthy (see Keepalive::unhealthy_after), so it's to be reported.
+  pub fn turned_unhealthy(&self) -> bool {
+    let unhealthy_after = match self.keepalive.and_then(|keepalive| keepalive.unhealthy_after) {
+      Some(unhealthy_after) => unhealthy_after,
+      None => { return false; }
+    };
+    self.unanswered.load(Ordering::Relaxed) >= unhealthy_after && !self.unhealthy.swap(true, Ordering::Relaxed)
+  }
+
+  /// Called after each pong (see ponged()): returns true if the client was unhealthy, and so has just recovered.
+  pub fn recovered(&self) -> bool {
+    self.unhealthy.swap(false, Ordering::Relaxed)
+  }
+
+  pub fn is_healthy(&self) -> bool {
+    !self.unhealthy.load(Ordering::Relaxed)
+  }
+
+  /// The payload for a keepalive ping sent now: the time it was sent, for its pong to bring back.
+  pub fn ping_payload(&self) -> Vec<u8> {
+    let sent_at = self.created_at.elapsed().as_micros() as u64;
+    let mut timer = self.rtt.lock().unwrap_or_else(PoisonError::into_inner);
+    // (A client with more pings than this unanswered is disconnected before the next.)
+    let max_in_flight = self.keepalive.map_or(1, |keepalive| keepalive.max_missed_pongs as usize + 1);
+    while timer.in_flight.len() >= max_in_flight { timer.in_flight.pop_front(); }
+    timer.in_flight.push_back(sent_at);
+    sent_at.to_be_bytes().to_vec()
+  }
+
+  /// Called with each pong's payload: any pong means the client's answering. Returns whether it answered one of the keepalive pings, whose round trip (and those of the pings before it, which won't be answered now) is then over.
+  pub fn ponged(&self, payload: &[u8]) -> bool {
+    self.unanswered.store(0, Ordering::Relaxed);
+    let sent_at = match payload.try_into() {
+      Ok(bytes) => u64::from_be_bytes(bytes),
+      Err(_) => { return false; }
+    };
+    let mut timer = self.rtt.lock().unwrap_or_else(PoisonError::into_inner);
+    let answered = match timer.in_flight.iter().position(|&in_flight| in_flight == sent_at) {
+      Some(answered) => answered,
+      None => { return false; }
+    };
+    timer.in_flight.drain(..=answered);
+    let latest = self.created_at.elapsed().saturating_sub(Duration::from_micros(sent_at));
+    let smoothed = timer.rtt.map_or(latest, |rtt| rtt.smoothed.mul_f64(1.0 - RTT_SMOOTHING) + latest.mul_f64(RTT_SMOOTHING));
+    timer.rtt = Some(RoundTrip { latest, smoothed });
+    true
+  }
+
+  /// The client's ping round trips, or None if it hasn't answered a ping (or isn't pinged).
+  pub fn rtt(&self) -> Option<RoundTrip> {
+    self.rtt.lock().unwrap_or_else(PoisonError::into_inner).rtt
+  }
+
+  /// The client sent or was sent a message.
+  pub fn active(&self) {
+    if self.idle_timeout.is_some() {
+      self.active_at_ms.fetch_max(self.created_at.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+  }
+
+  /// Resolves once the client's been idle for the idle timeout; never, if it has none.
+  pub async fn idled_out(&self) {
+    let idle_timeout = match self.idle_timeout {
+      Some(idle_timeout) => idle_timeout,
+      None => std::future::pending().await,
+    };
+    loop {
+      let deadline = self.created_at + Duration::from_millis(self.active_at_ms.load(Ordering::Relaxed)) + idle_timeout;
+      if Instant::now() >= deadline { return; }
+      time::sleep_until(deadline.into()).await;
+    }
+  }
+
+  /// Tells the receiver task the sender task has given up on the client (for not answering pings, or for being idle).
+  pub fn hang_up(&self) {
+    self.hung_up.notify_one();
+  }
+
+  /// Resolves once the sender task has given up on the client.
+  pub async fn hung_up(&self) {
+    self.hung_up.notified().await
+  }
+
+  /// Notes why the server's closing the connection (unanswered pings, an idle timeout, the shutdown). The first reason given sticks.
+  pub fn closing(&self, reason: &str) {
+    self.server_close.lock().unwrap_or_else(PoisonError::into_inner).get_or_insert_with(|| reason.to_string());
+  }
+
+  /// Why the server closed the connection; None if it didn't (the client closed it, or it was lost).
+  pub fn server_close(&self) -> Option<String> {
+    self.server_close.lock().unwrap_or_else(PoisonError::into_inner).clone()
+  }
+
+  pub fn idle_timeout(&self) -> Option<Duration> {
+    self.idle_timeout
+  }
+
+  pub fn keepalive(&self) -> Option<Keepalive> {
+    self.keepalive
+  }
+}
+
+/// A client's ping schedule (see Liveness::pings()).
+pub(crate) struct PingTimer(Option<Interval>);
+
+impl PingTimer {
+  pub async fn tick(&mut self) {
+    match &mut self.0 {
+      Some(interval) => { interval.tick().await; }
+      None => std::future::pending().await,
+    }
+  }
+}
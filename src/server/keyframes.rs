@@ -0,0 +1,157 @@
+// keyframes.rs
+//
+// Keyframe and delta streams (Server::send_keyframe() and Server::send_delta()), for state that's sent in full now and then and as changes in between, like a video codec's frames, or a document and its edits. A delta only makes sense to a client that's been sent the keyframe before it, and every delta since; one that joins mid-stream, or falls behind and misses broadcasts, can't use the deltas that follow.
+//
+// So the server keeps each topic's latest keyframe, and the deltas sent since it (its chain), and each client's sender task keeps track of how far along each topic's chain it's written the client. A keyframe or delta the client can use is written as usual. A delta the client's missed the start of (it's new, it missed a keyframe or delta by falling behind the broadcasts, or the stream's moved on to a newer keyframe) is written after the keyframe and deltas it needs first, from the chain, so that it picks up where the stream is; one from a chain the stream's already replaced is skipped, as the newer keyframe's on its way. A newly connected client is written every topic's keyframe and chain straight away, so it needn't wait for the next delta. The catch-ups are counted in the stats (keyframes_resent).
+//
+// Keyframes and deltas are broadcasts (of a message each), so they're queued, lost by lagging clients and recorded like any other; a client's catch-ups aren't recorded. They go to this server's clients only, not a cluster's other nodes. A topic's keyframe and chain count against the memory budget until its next keyframe replaces them, so a stream should send keyframes often enough to keep its chain short.
+
+use std::{collections::HashMap, sync::{Arc, Mutex, PoisonError, atomic::{AtomicU64, Ordering}}};
+
+use super::{buffer_pool::OUTBOUND, clients::Broadcast};
+
+/// Where a broadcast is in a keyframe stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamPart {
+  pub topic: Arc<str>,
+  /// Which of the topic's keyframes it is, or follows.
+  pub generation: u64,
+  /// None for the keyframe itself, or the delta's place in the keyframe's chain.
+  pub delta: Option<usize>,
+}
+
+/// A topic's latest keyframe and the deltas sent since.
+struct Chain {
+  generation: u64,
+  keyframe: Arc<Broadcast>,
+  deltas: Vec<Arc<Broadcast>>,
+}
+
+/// Every topic's latest keyframe and chain, for catching clients up. Kept by the BroadcastQueue, which adds to it as it queues keyframes and deltas, so that a chain never holds a delta queued after one it doesn't.
+#[derive(Default)]
+pub struct KeyframeStore {
+  chains: Mutex<HashMap<Arc<str>, Chain>>,
+  next_generation: AtomicU64,
+}
+
+impl KeyframeStore {
+  /// Where a new keyframe on `topic` goes: at the start of a new chain.
+  pub(crate) fn next_keyframe(&self, topic: &str) -> StreamPart {
+    let generation = self.next_generation.fetch_add(1, Ordering::Relaxed) + 1;
+    StreamPart { topic: topic.into(), generation, delta: None }
+  }
+
+  /// Where a new delta on `topic` goes, or None if no keyframe's been sent on it.
+  pub(crate) fn next_delta(&self, topic: &str) -> Option<StreamPart> {
+    let chains = self.chains.lock().unwrap_or_else(PoisonError::into_inner);
+    chains.get_key_value(topic).map(|(topic, chain)| StreamPart { topic: topic.clone(), generation: chain.generation, delta: Some(chain.deltas.len()) })
+  }
+
+  /// Adds a keyframe or delta made by next_keyframe() or next_delta() to its topic's chain (a keyframe replacing it).
+  pub(crate) fn add(&self, broadcast: &Arc<Broadcast>) {
+    let Some(part) = &broadcast.stream else { return; };
+    let mut chains = self.chains.lock().unwrap_or_else(PoisonError::into_inner);
+    match part.delta {
+      None => {
+        let chain = Chain { generation: part.generation, keyframe: broadcast.clone(), deltas: vec![] };
+        if let Some(replaced) = chains.insert(part.topic.clone(), chain) { replaced.recycle(); }
+      }
+      Some(_) => {
+        if let Some(chain) = chains.get_mut(&part.topic) { chain.deltas.push(broadcast.clone()); }
+      }
+    }
+  }
+
+  /// The keyframe of `topic`'s chain `generation` and its first `deltas` deltas, if that's still the topic's chain.
+  fn catch_up(&self, topic: &str, generation: u64, deltas: usize) -> Option<Vec<Arc<Broadcast>>> {
+    let chains = self.chains.lock().unwrap_or_else(PoisonError::into_inner);
+    let chain = chains.get(topic).filter(|chain| chain.generation == generation && chain.deltas.len() >= deltas)?;
+    Some(std::iter::once(&chain.keyframe).chain(&chain.deltas[..deltas]).cloned().collect())
+  }
+
+  /// Every topic's keyframe and chain, and where each leaves off.
+  fn everything(&self) -> Vec<(Arc<str>, u64, Vec<Arc<Broadcast>>)> {
+    let chains = self.chains.lock().unwrap_or_else(PoisonError::into_inner);
+    chains.iter().map(|(topic, chain)| (topic.clone(), chain.generation, std::iter::once(&chain.keyframe).chain(&chain.deltas).cloned().collect())).collect()
+  }
+
+  /// Forgets every chain, returning their messages to the pool (if no client's still to write them).
+  pub fn clear(&self) {
+    for (_, chain) in self.chains.lock().unwrap_or_else(PoisonError::into_inner).drain() { chain.recycle(); }
+  }
+}
+
+impl Chain {
+  fn recycle(self) {
+    OUTBOUND.recycle_shared(self.keyframe);
+    for delta in self.deltas { OUTBOUND.recycle_shared(delta); }
+  }
+}
+
+/// How far along each topic's chain a client's been written, kept by its sender task.
+#[derive(Default)]
+pub struct KeyframeSync {
+  /// Each topic's generation, and how many of its deltas have been written.
+  written: HashMap<Arc<str>, (u64, usize)>,
+  /// Catch-ups, not yet counted in the stats.
+  resent: u64,
+}
+
+impl KeyframeSync {
+  pub fn new() -> KeyframeSync {
+    KeyframeSync::default()
+  }
+
+  /// Every topic's keyframe and chain, for a client that's just connected. (Bar those it's been written already, having been sent a keyframe or delta on them first.)
+  pub fn start(&mut self, store: &KeyframeStore) -> Vec<Arc<Broadcast>> {
+    let mut broadcasts = vec![];
+    for (topic, generation, chain) in store.everything() {
+      if self.written.contains_key(&topic) { continue; }
+      self.written.insert(topic, (generation, chain.len() - 1));
+      broadcasts.extend(chain);
+    }
+    broadcasts
+  }
+
+  /// What to write the client in place of a broadcast: the broadcast itself, if it's not part of a keyframe stream or the client can use it; what it needs first as well, if it's a delta the client's missed the start of; or nothing, if the client's written it already, or the stream's moved on from it.
+  pub fn admit(&mut self, broadcast: Arc<Broadcast>, store: &KeyframeStore) -> Vec<Arc<Broadcast>> {
+    let Some(part) = broadcast.stream.clone() else { return vec![broadcast]; };
+    let written = self.written.get(&part.topic).copied();
+    match (part.delta, written) {
+      // (One it's been written already, catching up; or older than one it has.)
+      (None, Some((generation, _))) if generation >= part.generation => skip(broadcast),
+      (None, _) => {
+        self.written.insert(part.topic, (part.generation, 0));
+        vec![broadcast]
+      }
+      (Some(delta), Some((generation, next))) if generation == part.generation && delta <= next => {
+        // (Written along with a catch-up, if it's before the next.)
+        if delta < next { return skip(broadcast); }
+        self.written.insert(part.topic, (generation, next + 1));
+        vec![broadcast]
+      }
+      (Some(_), Some((generation, _))) if generation > part.generation => skip(broadcast),
+      (Some(delta), _) => match store.catch_up(&part.topic, part.generation, delta) {
+        Some(mut catch_up) => {
+          self.written.insert(part.topic, (part.generation, delta + 1));
+          self.resent += 1;
+          catch_up.push(broadcast);
+          catch_up
+        }
+        // (Its chain's been replaced: the newer keyframe will follow.)
+        None => skip(broadcast),
+      }
+    }
+  }
+
+  /// Takes the count of catch-ups since it was last taken.
+  pub fn take_resent(&mut self) -> u64 {
+    std::mem::take(&mut self.resent)
+  }
+}
+
+/// Leaves a broadcast out, returning its messages to the pool if nothing else is to write them.
+fn skip(broadcast: Arc<Broadcast>) -> Vec<Arc<Broadcast>> {
+  OUTBOUND.recycle_shared(broadcast);
+  vec![]
+}
@@ -0,0 +1,110 @@
+// latency.rs
+//
+// Latency histograms of the outbound pipeline, kept only when ServerConfig::latency_histograms is set: how long each message took to be queued by the send call (enqueue), then waited in the channel until a client's sender task picked it up (fan-out), then took to be written to the client's socket (socket write). The cumulative timings in stats.rs say where sending time goes on average; these say where the spikes come from.
+//
+// Each stage counts every message it times, so a batch of 10 messages counts 10 times, and a broadcast to N clients counts N times for fan-out and socket writes. Buckets are powers of two of microseconds, counted with atomics, so recording costs a few relaxed adds.
+
+use std::{sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+/// Bucket i counts durations under 2^i µs (and at least 2^(i-1) µs); the last counts everything from 2^(BUCKETS-2) µs (about 16.8 s) up.
+pub const BUCKETS: usize = 26;
+
+/// One stage's histogram.
+pub struct Histogram {
+  buckets: [AtomicU64; BUCKETS],
+  count: AtomicU64,
+  total_ns: AtomicU64,
+  max_ns: AtomicU64,
+}
+
+impl Default for Histogram {
+  fn default() -> Histogram {
+    Histogram {
+      buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+      count: AtomicU64::new(0),
+      total_ns: AtomicU64::new(0),
+      max_ns: AtomicU64::new(0),
+    }
+  }
+}
+
+impl Histogram {
+  /// Counts `messages` messages that each took `elapsed`.
+  pub fn record(&self, elapsed: Duration, messages: usize) {
+    if messages == 0 { return; }
+    let ns = elapsed.as_nanos() as u64;
+    let micros = ns / 1000;
+    let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+    self.buckets[bucket].fetch_add(messages as u64, Ordering::Relaxed);
+    self.count.fetch_add(messages as u64, Ordering::Relaxed);
+    self.total_ns.fetch_add(ns * messages as u64, Ordering::Relaxed);
+    self.max_ns.fetch_max(ns, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> HistogramSnapshot {
+    HistogramSnapshot {
+      buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(),
+      count: self.count.load(Ordering::Relaxed),
+      total: Duration::from_nanos(self.total_ns.load(Ordering::Relaxed)),
+      max: Duration::from_nanos(self.max_ns.load(Ordering::Relaxed)),
+    }
+  }
+}
+
+/// The three stages' histograms.
+#[derive(Default)]
+pub struct LatencyHistograms {
+  pub enqueue: Histogram,
+  pub fan_out: Histogram,
+  pub socket_write: Histogram,
+}
+
+impl LatencyHistograms {
+  pub fn snapshot(&self) -> LatencySnapshot {
+    LatencySnapshot { enqueue: self.enqueue.snapshot(), fan_out: self.fan_out.snapshot(), socket_write: self.socket_write.snapshot() }
+  }
+}
+
+/// A point-in-time copy of one histogram. Like the other stats, it only ever grows: compare two snapshots for the latencies in between.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+  /// Messages per bucket (see upper_bound()).
+  pub buckets: Vec<u64>,
+  pub count: u64,
+  /// The messages' latencies, summed.
+  pub total: Duration,
+  /// The longest latency recorded.
+  pub max: Duration,
+}
+
+impl HistogramSnapshot {
+  /// The durations bucket `bucket` counts are under this; None for the last bucket, which has no bound.
+  pub fn upper_bound(bucket: usize) -> Option<Duration> {
+    if bucket + 1 >= BUCKETS { None } else { Some(Duration::from_micros(1 << bucket)) }
+  }
+
+  /// A duration that `fraction` (0 to 1) of the messages took no longer than: the upper bound of the bucket that message falls in (or max, for the last bucket, or if it's lower). None with no messages.
+  pub fn quantile(&self, fraction: f64) -> Option<Duration> {
+    if self.count == 0 { return None; }
+    let rank = ((fraction.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (bucket, count) in self.buckets.iter().enumerate() {
+      seen += count;
+      if seen >= rank {
+        return Some(HistogramSnapshot::upper_bound(bucket).map_or(self.max, |bound| bound.min(self.max)));
+      }
+    }
+    Some(self.max)
+  }
+}
+
+/// A point-in-time copy of the three histograms.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencySnapshot {
+  /// From a send call (Server::send() and the like) to its messages being queued for the clients' sender tasks; for broadcasts from a cluster node, that includes queuing them for its peers.
+  pub enqueue: HistogramSnapshot,
+  /// From being queued to being picked up by a client's sender task, including waiting behind the client's earlier messages (or for room in its queue).
+  pub fan_out: HistogramSnapshot,
+  /// From being picked up to being flushed to the client's socket: encoding the frames, then writing them (including waiting for room in the socket's buffer).
+  pub socket_write: HistogramSnapshot,
+}
@@ -0,0 +1,95 @@
+// link.rs
+//
+// Link emulation (ServerConfig::link): each client's messages are held back as a slow, distant link would hold them, so a test setup on a LAN behaves like the links field users have (200 ms away, at 2 Mbit/s, say). Each client's sender task puts what it's sent through a DelayLine of its own, and writes each batch when it would have come out of the far end: once the batches before it have gone through at the link's bandwidth, and the latency (give or take the jitter) after that. Nothing is reordered, as TCP wouldn't, and the task goes on taking batches meanwhile, so a link with high latency still has its full bandwidth.
+//
+// Only the client's messages go through the link; pings, replies to its pings and close frames are written as usual.
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+
+use super::chaos::SplitMix64;
+
+/// Most batches a client's DelayLine holds; beyond it, the client's queue fills up behind it, as it would behind a slow client.
+const MAX_DELAYED_BATCHES: usize = 1024;
+
+/// The link each client is written to over (ServerConfig::link). For testing only.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkEmulation {
+  /// How long each message takes to reach the client, once it's been sent at the link's bandwidth.
+  pub latency: Duration,
+  /// Most the latency varies by, either way, from one batch of messages to the next (uniformly, and never below zero).
+  pub jitter: Duration,
+  /// The link's bandwidth, in bits per second of payload; unlimited if None.
+  pub bits_per_sec: Option<u64>,
+}
+
+impl LinkEmulation {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.bits_per_sec == Some(0) {
+      return Err("the emulated link's bandwidth must be more than 0".to_string());
+    }
+    Ok(())
+  }
+}
+
+/// A client's emulated link: what's been sent to it, each with when it reaches the far end, in order.
+pub(crate) struct DelayLine<T> {
+  config: LinkEmulation,
+  rng: SplitMix64,
+  pending: VecDeque<(Instant, T)>,
+  /// When the link has finished sending what's been put through it.
+  free_at: Instant,
+  /// When the last thing put through it arrives; nothing arrives before it.
+  last_arrival: Instant,
+}
+
+impl<T> DelayLine<T> {
+  /// The link for the server's `connection`th connection (which seeds its jitter).
+  pub fn new(config: LinkEmulation, connection: u64) -> DelayLine<T> {
+    let now = Instant::now();
+    DelayLine { config, rng: SplitMix64(connection), pending: VecDeque::new(), free_at: now, last_arrival: now }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pending.is_empty()
+  }
+
+  /// Whether it can take more: if not, the client's queue is left to fill up.
+  pub fn has_room(&self) -> bool {
+    self.pending.len() < MAX_DELAYED_BATCHES
+  }
+
+  /// Sends `item`, of `bytes` bytes, sent by the server at `sent_at`, down the link.
+  pub fn push(&mut self, item: T, bytes: usize, sent_at: Instant) {
+    let sent = match self.config.bits_per_sec {
+      Some(bits_per_sec) => {
+        let transmission = Duration::from_secs_f64(bytes as f64 * 8.0 / bits_per_sec as f64);
+        self.free_at = self.free_at.max(sent_at) + transmission;
+        self.free_at
+      }
+      None => sent_at,
+    };
+    // The latency, give or take up to the jitter (but never less than nothing).
+    let delay = (self.config.latency + self.config.jitter.mul_f64(self.rng.next_f64() * 2.0)).saturating_sub(self.config.jitter);
+    let arrival = (sent + delay).max(self.last_arrival);
+    self.last_arrival = arrival;
+    self.pending.push_back((arrival, item));
+  }
+
+  /// Waits for the next item to reach the far end, and returns it along with everything else that has by then. (Nothing, straight away, if the link is empty.) Cancel-safe: nothing is taken until it's returned.
+  pub async fn arrivals(&mut self) -> Vec<T> {
+    if let Some((arrival, _)) = self.pending.front() {
+      tokio::time::sleep_until((*arrival).into()).await;
+    }
+    let now = Instant::now();
+    let mut arrived = vec![];
+    while self.pending.front().is_some_and(|(arrival, _)| *arrival <= now) {
+      arrived.extend(self.pending.pop_front().map(|(_, item)| item));
+    }
+    arrived
+  }
+
+  /// Takes everything still on its way, for a client that's gone.
+  pub fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+    self.pending.drain(..).map(|(_, item)| item)
+  }
+}
@@ -0,0 +1,106 @@
+// logging.rs
+//
+// Server-side logging. Log lines are tracing events (from the module that logs them, within the spans of the connection and task they concern), so a Rust program with a tracing subscriber gets them, with their context, like any other; otherwise they go to stdout (as they always have). A sink can be installed instead to route them elsewhere, e.g. to Python's logging module (see log_bridge.rs). Use the log_debug!/log_info!/log_warn!/log_error! macros rather than println!.
+//
+// The spans: "connection" (peer), for each accepted connection, within which are its "handshake" and, for a client, its "sender" and "receiver" tasks (client); the sender task's writes are in "fan_out" spans for broadcasts and "send_to_client" ones for sends to it alone (messages). Each cluster link is in a "cluster_link" span (peer).
+//
+// With the "console" feature, the first server started installs console-subscriber (unless the program has a tracing subscriber of its own already; then add console_subscriber::spawn() to it), so that tokio-console can attach to the process and show each of the server's tasks, named as in tasks.rs, and where it's stuck. tokio only instruments its tasks when built with RUSTFLAGS="--cfg tokio_unstable". With the "otel" feature, it installs the layer that exports the spans to OpenTelemetry the same way (see otel.rs).
+
+use std::sync::RwLock;
+use tokio::sync::mpsc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+  Debug,
+  Info,
+  Warning,
+  Error,
+}
+
+impl Level {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Level::Debug   => "debug",
+      Level::Info    => "info",
+      Level::Warning => "warning",
+      Level::Error   => "error",
+    }
+  }
+}
+
+pub struct LogRecord {
+  pub level: Level,
+  pub message: String,
+}
+
+/// Where log records go instead of stdout, and the minimum level worth sending there.
+struct LogSink {
+  tx: mpsc::Sender<LogRecord>,
+  min_level: Level,
+}
+
+lazy_static! {
+  static ref SINK: RwLock<Option<LogSink>> = RwLock::new(None);
+}
+
+/// Routes log records at or above `min_level` to `tx` from now on (records below it are discarded), or back to stdout if None.
+///
+/// Records are sent with try_send, so logging never blocks the server; they're dropped if the sink falls behind.
+pub fn set_sink(sink: Option<(mpsc::Sender<LogRecord>, Level)>) {
+  let guard = SINK.write();
+  if guard.is_err() { return; }
+  *guard.unwrap() = sink.map(|(tx, min_level)| LogSink { tx, min_level });
+}
+
+/// Where the log macros send a line once it's been offered to tracing: to the sink, if there is one, or else to stdout, unless a tracing subscriber took it (`traced`).
+pub fn log(level: Level, message: String, traced: bool) {
+  if let Ok(sink) = SINK.read() {
+    if let Some(sink) = sink.as_ref() {
+      if level >= sink.min_level { let _ = sink.tx.try_send(LogRecord { level, message }); }
+      return;
+    }
+  }
+  if !traced { println!("{}", message); }
+}
+
+// Offers a log line to tracing, as an event of the module logging it, then hands it to log().
+macro_rules! log_at {
+  ($level:ident, $tracing_level:ident, $($arg:tt)*) => {{
+    let message = format!($($arg)*);
+    let traced = ::tracing::enabled!(::tracing::Level::$tracing_level);
+    if traced { ::tracing::event!(::tracing::Level::$tracing_level, "{}", message); }
+    $crate::server::logging::log($crate::server::logging::Level::$level, message, traced)
+  }}
+}
+
+macro_rules! log_debug { ($($arg:tt)*) => { log_at!(Debug,   DEBUG, $($arg)*) } }
+macro_rules! log_info  { ($($arg:tt)*) => { log_at!(Info,    INFO,  $($arg)*) } }
+macro_rules! log_warn  { ($($arg:tt)*) => { log_at!(Warning, WARN,  $($arg)*) } }
+macro_rules! log_error { ($($arg:tt)*) => { log_at!(Error,   ERROR, $($arg)*) } }
+
+/// Installs the process's tracing subscriber, once, with the layers of the features built in: console-subscriber's, so tokio-console can attach, and OpenTelemetry export's (see otel.rs). Call before the server's runtime is started, so it sees every task.
+#[cfg(any(feature = "console", feature = "otel"))]
+pub fn init_subscriber() {
+  static INIT: std::sync::Once = std::sync::Once::new();
+  INIT.call_once(|| {
+    use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+    // (console-subscriber refuses to run without tokio's instrumentation.)
+    #[cfg(all(feature = "console", not(tokio_unstable)))]
+    log_warn!("[logging] tokio-console can't attach: quicksocket was built without RUSTFLAGS=\"--cfg tokio_unstable\", so tokio doesn't instrument its tasks.");
+    let console = cfg!(all(feature = "console", tokio_unstable));
+    #[cfg(feature = "otel")]
+    let otel = super::otel::layer();
+    #[cfg(not(feature = "otel"))]
+    let otel: Option<tracing_subscriber::layer::Identity> = None;
+    // (A subscriber without layers would take every log line, which then wouldn't be printed.)
+    if !console && otel.is_none() { return; }
+
+    let registry = tracing_subscriber::registry();
+    #[cfg(all(feature = "console", tokio_unstable))]
+    let registry = registry.with(console_subscriber::spawn());
+    match registry.with(otel).try_init() {
+      Ok(()) => if console { log_info!("[logging] tokio-console can attach (at 127.0.0.1:6669, unless TOKIO_CONSOLE_BIND says otherwise)."); },
+      Err(_) => log_warn!("[logging] The program has a tracing subscriber already, so quicksocket's layers aren't installed; add them to it (console_subscriber::spawn() for tokio-console, quicksocket::server::otel::layer() for OpenTelemetry spans)."),
+    }
+  });
+}
@@ -0,0 +1,255 @@
+// metrics.rs
+//
+// Metrics streaming (Server::log_scalar() and Server::log_histogram()), TensorBoard-style, for training loops and simulations that log a value or a distribution per step and want it charted live in the browser, rather than written to event files for another process to pick up. Each scalar (a loss, an accuracy, a learning rate) and histogram (of weights, gradients, rewards) is logged under a tag, with its step.
+//
+// Logging is cheap, as it's done in a tight loop: what's logged is held, and the server's own task broadcasts what's been logged over the flush interval (MetricsConfig::flush) as one frame, binary and compact. The server also keeps each tag's last MetricsConfig::history points, and a client that connects is written them, in a frame of their own, as soon as it does, so a dashboard opened mid-run charts the run so far. A client's history is taken as it subscribes to the broadcasts, under the same lock a frame's broadcast under, and a frame's points go in the history as it's broadcast, so each point comes to the client once: in its history if its frame went out before, live if not. quicksocket/metrics.js decodes the frames. Every number is little-endian, and each frame starts with a 12-byte header:
+//
+//   offset  size  field
+//   0       4     magic: "QSMT"
+//   4       1     version: 1
+//   5       1     kind: 0 for what's been logged since the last frame, 1 for the history a client's written as it connects
+//   6       2     reserved (0)
+//   8       4     series count: u32
+//
+// followed by each series: its tag's length in bytes (u16) and its tag (UTF-8); its type (u8): 0 for scalars, 1 for histograms; and its point count (u32); then its points. A scalar is its step and its value (f64s). A histogram is its step, the least and greatest of its values (f64s), its bin count (u32), then its bins' counts (u32s): the bins split the range from the least value to the greatest evenly.
+//
+// Steps are integers, sent as f64s, so they're exact up to 2^53. Frames are broadcast like heartbeats (see heartbeat.rs): queued behind the broadcasts before them, but never waiting for room, whatever the lag policy, and only to this server's clients, not a cluster's other nodes. A frame that would take the server over its memory budget is dropped (its points still go in the history). At most MAX_PENDING_POINTS of a tag's points wait for a frame; past that, the oldest are left out of it.
+
+use std::{collections::{BTreeMap, VecDeque}, sync::{Arc, Mutex, PoisonError}, time::Duration};
+use tokio::sync::{Notify, watch};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::{BroadcastQueue, BroadcastReceiver}, outbound::Outbound, stats::{DropReason, ServerStats}};
+
+pub const MAGIC: &[u8; 4] = b"QSMT";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 12;
+/// The most bins a histogram can have.
+pub const MAX_BINS: usize = 1024;
+/// The most points of a tag that wait for a frame; past that, the oldest are left out of it.
+pub const MAX_PENDING_POINTS: usize = 4096;
+
+const LIVE: u8 = 0;
+const HISTORY: u8 = 1;
+const SCALARS: u8 = 0;
+const HISTOGRAMS: u8 = 1;
+
+/// How metrics are streamed (ServerConfig::metrics).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetricsConfig {
+  /// How long what's logged is held for more to go out with it, in one frame; zero sends it as soon as the server's task gets to it.
+  pub flush: Duration,
+  /// How many of each tag's last points are kept, for clients that connect; zero keeps none.
+  pub history: usize,
+}
+
+impl Default for MetricsConfig {
+  fn default() -> MetricsConfig {
+    MetricsConfig { flush: Duration::from_millis(100), history: 1000 }
+  }
+}
+
+/// A histogram of values logged at a step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+  pub step: i64,
+  /// The least and greatest of the values (both 0 if there were none).
+  pub min: f64,
+  pub max: f64,
+  /// How many values are in each bin, the bins splitting the range from min to max evenly.
+  pub counts: Vec<u32>,
+}
+
+impl Histogram {
+  /// A histogram of `values` in `bins` bins. NaNs and infinities are left out.
+  pub fn new(step: i64, values: &[f64], bins: usize) -> Result<Histogram, String> {
+    if bins == 0 || bins > MAX_BINS {
+      return Err(format!("a histogram must have from 1 to {} bins, not {}", MAX_BINS, bins));
+    }
+    let finite = || values.iter().copied().filter(|value| value.is_finite());
+    let min = finite().fold(f64::INFINITY, f64::min);
+    let max = finite().fold(f64::NEG_INFINITY, f64::max);
+    let mut counts = vec![0u32; bins];
+    if min > max {
+      return Ok(Histogram { step, min: 0.0, max: 0.0, counts });
+    }
+    let width = (max - min) / bins as f64;
+    for value in finite() {
+      // (The greatest value goes in the last bin, and with a single value, in the first.)
+      let bin = if width > 0.0 { (((value - min) / width) as usize).min(bins - 1) } else { 0 };
+      counts[bin] = counts[bin].saturating_add(1);
+    }
+    Ok(Histogram { step, min, max, counts })
+  }
+}
+
+/// Checks that a tag fits in a frame.
+pub fn validate_tag(tag: &str) -> Result<(), String> {
+  if tag.is_empty() || tag.len() > u16::MAX as usize {
+    return Err(format!("a metric's tag must be from 1 to {} bytes long", u16::MAX));
+  }
+  Ok(())
+}
+
+/// Tags' points, scalars and histograms.
+#[derive(Default)]
+struct Series {
+  scalars: BTreeMap<String, VecDeque<(i64, f64)>>,
+  histograms: BTreeMap<String, VecDeque<Histogram>>,
+}
+
+impl Series {
+  fn is_empty(&self) -> bool {
+    self.scalars.is_empty() && self.histograms.is_empty()
+  }
+
+  /// Adds a point to a tag's, keeping its last `keep`.
+  fn push<T>(series: &mut BTreeMap<String, VecDeque<T>>, tag: &str, point: T, keep: usize) {
+    if keep == 0 { return; }
+    let points = match series.get_mut(tag) {
+      Some(points) => points,
+      None => series.entry(tag.to_string()).or_default(),
+    };
+    if points.len() >= keep { points.pop_front(); }
+    points.push_back(point);
+  }
+
+  /// The frame of these series.
+  fn frame(&self, kind: u8, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&[VERSION, kind, 0, 0]);
+    buf.extend_from_slice(&((self.scalars.len() + self.histograms.len()) as u32).to_le_bytes());
+    for (tag, points) in &self.scalars {
+      series_header(buf, tag, SCALARS, points.len());
+      for (step, value) in points {
+        buf.extend_from_slice(&(*step as f64).to_le_bytes());
+        buf.extend_from_slice(&value.to_le_bytes());
+      }
+    }
+    for (tag, histograms) in &self.histograms {
+      series_header(buf, tag, HISTOGRAMS, histograms.len());
+      for histogram in histograms {
+        buf.extend_from_slice(&(histogram.step as f64).to_le_bytes());
+        buf.extend_from_slice(&histogram.min.to_le_bytes());
+        buf.extend_from_slice(&histogram.max.to_le_bytes());
+        buf.extend_from_slice(&(histogram.counts.len() as u32).to_le_bytes());
+        for count in &histogram.counts { buf.extend_from_slice(&count.to_le_bytes()); }
+      }
+    }
+  }
+
+  fn frame_len(&self) -> usize {
+    let scalars: usize = self.scalars.iter().map(|(tag, points)| 7 + tag.len() + points.len() * 16).sum();
+    let histograms: usize = self.histograms.iter().map(|(tag, histograms)| 7 + tag.len() + histograms.iter().map(|histogram| 28 + histogram.counts.len() * 4).sum::<usize>()).sum();
+    HEADER_LEN + scalars + histograms
+  }
+}
+
+fn series_header(buf: &mut Vec<u8>, tag: &str, kind: u8, points: usize) {
+  buf.extend_from_slice(&(tag.len() as u16).to_le_bytes());
+  buf.extend_from_slice(tag.as_bytes());
+  buf.push(kind);
+  buf.extend_from_slice(&(points as u32).to_le_bytes());
+}
+
+struct Logged {
+  /// What's been logged since the last frame.
+  pending: Series,
+  /// What's gone out in frames, each tag's last points.
+  history: Series,
+}
+
+/// A server's logged metrics, shared by the consumer, which logs them, the server's task that broadcasts them, and the connections, which subscribe clients with their history.
+pub struct MetricsLog {
+  config: MetricsConfig,
+  logged: Mutex<Logged>,
+  /// Wakes the task when something's logged with nothing pending.
+  pending: Notify,
+  stats: Arc<ServerStats>,
+  ser_msg_tx: Arc<BroadcastQueue>,
+}
+
+impl MetricsLog {
+  pub fn new(config: MetricsConfig, stats: Arc<ServerStats>, ser_msg_tx: Arc<BroadcastQueue>) -> MetricsLog {
+    MetricsLog { config, logged: Mutex::new(Logged { pending: Series::default(), history: Series::default() }), pending: Notify::new(), stats, ser_msg_tx }
+  }
+
+  pub fn log_scalar(&self, tag: &str, step: i64, value: f64) -> Result<(), String> {
+    validate_tag(tag)?;
+    let mut logged = self.logged.lock().unwrap_or_else(PoisonError::into_inner);
+    let was_empty = logged.pending.is_empty();
+    Series::push(&mut logged.pending.scalars, tag, (step, value), MAX_PENDING_POINTS);
+    if was_empty { self.pending.notify_one(); }
+    Ok(())
+  }
+
+  pub fn log_histogram(&self, tag: &str, histogram: Histogram) -> Result<(), String> {
+    validate_tag(tag)?;
+    let mut logged = self.logged.lock().unwrap_or_else(PoisonError::into_inner);
+    let was_empty = logged.pending.is_empty();
+    Series::push(&mut logged.pending.histograms, tag, histogram, MAX_PENDING_POINTS);
+    if was_empty { self.pending.notify_one(); }
+    Ok(())
+  }
+
+  /// Subscribes a connecting client to the broadcasts, along with the frame of the history, if there's any, for it to be written first: everything broadcast before it subscribed, and nothing after.
+  pub fn subscribe(&self) -> (BroadcastReceiver, Option<Message>) {
+    let logged = self.logged.lock().unwrap_or_else(PoisonError::into_inner);
+    let server_msg_rx = self.ser_msg_tx.subscribe();
+    if logged.history.is_empty() { return (server_msg_rx, None); }
+    let mut buf = Vec::with_capacity(logged.history.frame_len());
+    logged.history.frame(HISTORY, &mut buf);
+    (server_msg_rx, Some(Message::Binary(buf)))
+  }
+
+  /// Broadcasts what's been logged since the last frame, if anything, adding it to the history.
+  fn flush(&self) {
+    // (Held until the frame's queued, so a client subscribes either before it is, or with its points in its history.)
+    let mut logged = self.logged.lock().unwrap_or_else(PoisonError::into_inner);
+    if logged.pending.is_empty() { return; }
+    let pending = std::mem::take(&mut logged.pending);
+    let mut buf = OUTBOUND.take(pending.frame_len());
+    pending.frame(LIVE, &mut buf);
+    for (tag, points) in &pending.scalars {
+      for point in points { Series::push(&mut logged.history.scalars, tag, *point, self.config.history); }
+    }
+    for (tag, histograms) in pending.histograms {
+      for histogram in histograms { Series::push(&mut logged.history.histograms, &tag, histogram, self.config.history); }
+    }
+    let messages = vec![Outbound::from(Message::Binary(buf))];
+    // (Sending fails when no clients are connected, which is fine: nobody missed it.)
+    match self.stats.memory().charge(messages[0].len(), 1) {
+      Ok(charge) => { if let Err(unsent) = self.ser_msg_tx.send(messages, charge) { OUTBOUND.recycle_shared(unsent); } }
+      Err(err) => {
+        log_debug!("[metrics] Dropped a frame: {}", err);
+        self.stats.messages_dropped(DropReason::OverBudget, 1);
+        OUTBOUND.recycle_messages(messages);
+      }
+    }
+  }
+}
+
+/// Broadcasts what's logged, a flush interval after the first of it, until the server shuts down.
+pub async fn send_metrics(metrics: Arc<MetricsLog>, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  loop {
+    tokio::select! {
+      _ = metrics.pending.notified() => {}
+      _ = ser_req_shutdown_rx.changed() => {
+        if *ser_req_shutdown_rx.borrow() { break; }
+        continue;
+      }
+    }
+    // (What's logged meanwhile goes out in the same frame.)
+    let flush = tokio::time::sleep(metrics.config.flush);
+    tokio::pin!(flush);
+    loop {
+      tokio::select! {
+        _ = &mut flush => break,
+        _ = ser_req_shutdown_rx.changed() => {
+          if *ser_req_shutdown_rx.borrow() { return; }
+        }
+      }
+    }
+    metrics.flush();
+  }
+}
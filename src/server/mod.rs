@@ -1,30 +1,147 @@
-use std::thread;
-use tokio::sync::{broadcast, mpsc, watch};
+use std::{sync::Arc, thread};
+use tokio::sync::watch;
 
+#[macro_use]
+pub mod logging;
+
+pub mod audit_log;
+pub mod batching;
+pub mod capabilities;
+pub mod budget;
+pub mod chaos;
+pub mod client;
+pub mod clock;
+pub mod clients;
+pub mod cluster;
+pub mod compression;
+pub mod config;
 pub mod consumer_state;
+pub mod decimation;
+pub mod diagnostics;
+pub mod error_events;
+pub mod event_log;
+pub mod event_stream;
+pub mod events;
+pub mod figures;
+pub mod frames;
+pub mod geometry;
+pub mod handle;
+pub mod handler;
+pub mod heartbeat;
+pub mod inputs;
+pub mod keepalive;
+pub mod keyframes;
+pub mod latency;
+pub mod link;
+pub mod metrics;
+pub mod notify;
+pub mod outbound;
+pub mod playback;
+pub mod queue;
+pub mod rate_limit;
+pub mod recording;
+#[cfg(feature = "redis")]
+pub mod redis_bridge;
+#[cfg(feature = "kafka")]
+pub mod kafka_sink;
+#[cfg(feature = "zmq")]
+pub mod zmq_bridge;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "tower")]
+pub mod service;
+pub mod relay;
+pub mod replay;
+pub mod rosbridge;
+pub mod stats;
+pub mod sync_groups;
+pub mod taps;
+pub mod tasks;
+pub mod threading;
+pub mod transport;
+pub mod watchdog;
+#[cfg(feature = "uring")]
+pub mod uring;
+pub(crate) mod buffer_pool;
+mod http;
+mod inspector;
+mod proxy;
 mod tokio_server;
+mod writer;
 
-pub fn start(port: u32) -> Result<(), ()> {
-  // Server thread-alive channel.
-  let (ser_thread_alive_tokio_tx, ser_alive_consumer_rx) = {
-    watch::channel::<bool>(false)
+pub use audit_log::AuditLog;
+pub use batching::Batching;
+pub use capabilities::{CapabilityOffer, ClientCapabilities};
+pub use chaos::Chaos;
+pub use client::Client;
+pub use clients::{ClientStats, LagPolicy};
+pub use cluster::{ClusterConfig, PeerState, PeerStatus};
+pub use compression::Compression;
+pub use config::ServerConfig;
+pub use decimation::{DecimationMode, TopicRate};
+pub use diagnostics::{Diagnostics, QueueOccupancy};
+pub use figures::Figure;
+pub use proxy::ProxyRoute;
+pub use rate_limit::{ClientRateLimits, RateLimit};
+pub use recording::{RecordingFormat, RecordingStats};
+pub use handle::{Delivery, Error, Server, ShutdownOptions};
+pub use outbound::{Outbound, PreparedMessage, SharedBytes};
+pub use event_stream::{EventStream, ServerEvent};
+pub use handler::ServerHandler;
+pub use heartbeat::HeartbeatTopic;
+pub use inputs::InputSnapshot;
+pub use keepalive::{Keepalive, RoundTrip};
+pub use link::LinkEmulation;
+pub use metrics::MetricsConfig;
+pub use playback::{PlaybackControl, PlaybackEvent, PlaybackState};
+pub use relay::{Relay, RelayConfig};
+pub use replay::{Replay, ReplayConfig, ReplayStats};
+pub use threading::Threading;
+#[cfg(feature = "redis")]
+pub use redis_bridge::{RedisBridge, RedisBridgeConfig};
+#[cfg(feature = "kafka")]
+pub use kafka_sink::{KafkaSink, KafkaSinkConfig};
+#[cfg(feature = "zmq")]
+pub use zmq_bridge::{ZmqBridge, ZmqBridgeConfig, ZmqSocketType};
+#[cfg(feature = "tower")]
+pub use service::WebSocketService;
+pub use transport::{LoopbackClient, Transport};
+pub use watchdog::Watchdog;
+pub use tokio_tungstenite::tungstenite::Message;
+
+/// The address a server started on `port` listens on.
+pub fn bind_address(port: u32) -> String {
+  format!("127.0.0.1:{}", port)
+}
+
+/// Starts a server on its own thread, returning the consumer's state for it. Any number of servers can run at once (on different ports). With a `handler`, the server's events go to it (on its own dispatch thread) rather than to the consumer's drains.
+pub(crate) fn start(port: u32, config: ServerConfig, handler: Option<Box<dyn ServerHandler>>) -> Result<Arc<consumer_state::ServerState>, ()> {
+  #[cfg(any(feature = "console", feature = "otel"))]
+  logging::init_subscriber();
+
+  // Server lifecycle state channel.
+  let (ser_state_tokio_tx, ser_state_consumer_rx) = {
+    watch::channel::<consumer_state::RunState>(consumer_state::RunState::Starting)
   };
 
   // Client connection event channel.
   let (cli_conn_tokio_tx, cli_conn_consumer_rx) = {
-    mpsc::channel::<String>(16)
+    queue::channel::<events::ConnectionEvent>(16)
   };
 
-  // Server message broadcast channel (consumer -> server -> client(s)).
-  let (ser_msg_tokio_tx, _) = {
-    broadcast::channel::<Vec<tokio_tungstenite::tungstenite::Message>>(16)
+  // Client ping event channel. (Pings aren't worth holding up a client's reads for, so the receiver tasks drop them once it's full.)
+  let (cli_ping_tokio_tx, cli_ping_consumer_rx) = {
+    queue::channel::<events::PingEvent>(events::PING_EVENT_QUEUE_LEN)
   };
-  // Both the consumer thread(s) and the tokio thread(s) will have their own copies of the transmitter. The consumer thread uses its copy to send() messages. The tokio thread uses its copy to create per-connection receivers.
+
+  // Server message broadcast queue (consumer -> server -> client(s)).
+  let ser_msg_tokio_tx = Arc::new(clients::BroadcastQueue::new(config.lag_policy, config.compression.map(|compression| Arc::new(compression::Deflater::new(compression)))));
+  // Both the consumer thread(s) and the tokio thread(s) will have their own references to the queue. The consumer thread uses its reference to send() messages. The tokio thread uses its reference to create per-connection receivers.
   let ser_msg_consumer_tx = ser_msg_tokio_tx.clone();
 
   // Client message channel.
   let (cli_msg_store_tokio_tx, cli_msg_store_consumer_rx) = {
-    mpsc::channel::<tokio_tungstenite::tungstenite::Message>(16)
+    queue::channel::<events::ClientMessage>(16)
   };
 
   // Shutdown channel.
@@ -32,29 +149,113 @@ pub fn start(port: u32) -> Result<(), ()> {
     watch::channel::<bool>(false)
   };
 
-  // Set the consumer thread statics with all its relevant comms channels.
+  // Drain channel.
+  let (ser_req_drain_consumer_tx, ser_req_drain_tokio_rx) = {
+    watch::channel::<bool>(false)
+  };
+
+  // Statistics, counted by the tokio tasks and read by the consumer.
+  let stats = Arc::new(stats::ServerStats::new(config.latency_histograms, config.memory_budget, config.rate_limit));
+
+  // Session recorder, started and stopped by the consumer and recorded to by the tokio tasks. (The tokio thread spawns each recording's broadcast subscriber.)
+  let (recorder, recording_starts) = recording::Recorder::new();
+
+  // Loopback and service servers accept connections from the consumer's connector instead of binding the port.
+  let mut loopback = None;
+  #[cfg(feature = "tower")]
+  let mut service_connector = None;
+  let unbound_listener = match config.transport {
+    transport::Transport::Tcp => None,
+    #[cfg(feature = "uring")]
+    transport::Transport::Uring => None,
+    transport::Transport::Loopback => {
+      let (connector, loopback_rx) = transport::LoopbackConnector::new();
+      loopback = Some(connector);
+      Some(transport::Listener::Loopback(loopback_rx))
+    }
+    #[cfg(feature = "tower")]
+    transport::Transport::Service => {
+      let (connector, service_rx) = service::ServiceConnector::new();
+      service_connector = Some(connector);
+      Some(transport::Listener::Service(service_rx))
+    }
+  };
+
+  // Set up the consumer state with all its relevant comms channels.
   use consumer_state as cs;
-  cs::set_value(&cs::CS_SER_ALIVE_RX, ser_alive_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_CLI_CONN_RX, cli_conn_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_SER_MSG_TX, ser_msg_consumer_tx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_CLI_MSG_RX, cli_msg_store_consumer_rx)
-    .expect("Failed to set consumer state channel!");
-  cs::set_value(&cs::CS_SER_REQ_SHUTDOWN_TX, ser_req_shutdown_consumer_tx)
-    .expect("Failed to set consumer state channel!");
+  let state = cs::ServerState::new(port, config.clone(), stats.clone(), cs::ConsumerEnds {
+    ser_state_rx: ser_state_consumer_rx,
+    cli_conn_rx: cli_conn_consumer_rx,
+    cli_ping_rx: cli_ping_consumer_rx,
+    ser_msg_tx: ser_msg_consumer_tx,
+    cli_msg_rx: cli_msg_store_consumer_rx,
+    ser_req_shutdown_tx: ser_req_shutdown_consumer_tx,
+    ser_req_drain_tx: ser_req_drain_consumer_tx,
+    recorder: recorder.clone(),
+    loopback,
+    #[cfg(feature = "tower")]
+    service: service_connector,
+  });
+
+  let bound_port = state.bound_port.clone();
+  let clients = state.clients.clone();
+  let notifier = state.notifier.clone();
+  let cluster = state.cluster.clone();
+  let playback = state.playback.clone();
+  let inputs = state.inputs.clone();
+  let decimator = state.decimator.clone();
+  let sequencer = state.sequencer.clone();
+  let metrics = state.metrics.clone();
+  let taps = state.taps.clone();
+  let shutdown_options = state.shutdown_options.clone();
+  let tasks = state.tasks.clone();
+  // Subscribed before the server thread is launched, so the handler sees every error (bind errors included).
+  let handler_error_rx = handler.as_ref().map(|_| stats.subscribe_errors());
 
   // Launch the tokio thread, passing ownership of all the tokio-side channels.
   // Launch the tokio thread.
-  let _thread_handle = thread::spawn(move || tokio_server::main(
+  let thread_handle = thread::spawn(move || tokio_server::main(tokio_server::TokioEnds {
     port,
-    ser_thread_alive_tokio_tx,
-    cli_conn_tokio_tx,
-    ser_msg_tokio_tx,
-    cli_msg_store_tokio_tx,
-    ser_req_shutdown_tokio_rx
-  ));
-
-  Ok(())
+    bound_port,
+    config,
+    stats,
+    clients,
+    notifier,
+    cluster,
+    playback,
+    inputs,
+    decimator,
+    sequencer,
+    metrics,
+    unbound_listener,
+    ser_state_tx: ser_state_tokio_tx,
+    cli_conn_tx: cli_conn_tokio_tx,
+    cli_ping_tx: cli_ping_tokio_tx,
+    ser_msg_tx: ser_msg_tokio_tx,
+    cli_msg_tx: cli_msg_store_tokio_tx,
+    ser_req_shutdown_rx: ser_req_shutdown_tokio_rx,
+    ser_req_drain_rx: ser_req_drain_tokio_rx,
+    shutdown_options,
+    recorder,
+    recording_starts,
+    taps,
+    tasks,
+  }));
+  // Keep the thread handle so the consumer can join the server thread after requesting shutdown.
+  state.ser_thread.put(thread_handle);
+
+  let state = Arc::new(state);
+  if let Some(handler) = handler {
+    if let Err(err) = handler::spawn(&state, handler, handler_error_rx.unwrap()) {
+      // The server's running, but nothing's handling its events; shut it down rather than leave it unattended.
+      cs::weakly_record_error(err);
+      state.ser_req_shutdown_tx.send_replace(true);
+      return Err(());
+    }
+  }
+  cs::mutate(&cs::CS_SERVERS, |servers| {
+    servers.retain(|server| server.is_alive());
+    servers.push(state.clone());
+  });
+  Ok(state)
 }
@@ -0,0 +1,78 @@
+// notify.rs
+//
+// A readiness file descriptor for event loops. The consumer can register it with any event loop (asyncio's add_reader(), trio's wait_readable(), ...) to wait for client messages without polling or a helper thread: the tokio tasks make it readable whenever a client message is queued (and once more when the server stops), and draining messages makes it unreadable again.
+//
+// The descriptor's a Unix socket pair, created the first time it's asked for, so servers nobody awaits don't pay for it.
+
+#[cfg(unix)]
+use std::{io::{ErrorKind, Read, Write}, os::unix::{io::{AsRawFd, RawFd}, net::UnixStream}, sync::{PoisonError, RwLock}};
+
+#[derive(Default)]
+pub struct MessageNotifier {
+  /// (read end, write end), both non-blocking.
+  #[cfg(unix)]
+  pair: RwLock<Option<(UnixStream, UnixStream)>>,
+}
+
+#[cfg(unix)]
+impl MessageNotifier {
+  pub fn new() -> MessageNotifier {
+    MessageNotifier::default()
+  }
+
+  /// The readable end of the notifier, creating it if needed. The descriptor stays owned by the server; it's closed when the server state is dropped.
+  pub fn fd(&self) -> Result<RawFd, String> {
+    let mut pair = self.pair.write().unwrap_or_else(PoisonError::into_inner);
+    if pair.is_none() {
+      let created = UnixStream::pair().and_then(|(read_end, write_end)| {
+        read_end.set_nonblocking(true)?;
+        write_end.set_nonblocking(true)?;
+        Ok((read_end, write_end))
+      });
+      if let Err(err) = created {
+        return Err(format!("Failed to create the message notifier: {}", err));
+      }
+      *pair = Some(created.unwrap());
+    }
+    Ok(pair.as_ref().unwrap().0.as_raw_fd())
+  }
+
+  /// Makes the descriptor readable, if anyone asked for it. A full socket buffer just means it's readable already.
+  pub fn notify(&self) {
+    let pair = self.pair.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, write_end)) = pair.as_ref() {
+      let _ = (&*write_end).write(&[1]);
+    }
+  }
+
+  /// Makes the descriptor unreadable again. Called before draining, so a message queued after the drain looked is notified afresh.
+  pub fn clear(&self) {
+    let pair = self.pair.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some((read_end, _)) = pair.as_ref() {
+      let mut buf = [0u8; 64];
+      loop {
+        match (&*read_end).read(&mut buf) {
+          Ok(0) => { break; }
+          Ok(_) => {}
+          Err(err) if err.kind() == ErrorKind::Interrupted => {}
+          Err(_) => { break; } // WouldBlock: nothing left to read.
+        }
+      }
+    }
+  }
+}
+
+#[cfg(not(unix))]
+impl MessageNotifier {
+  pub fn new() -> MessageNotifier {
+    MessageNotifier::default()
+  }
+
+  pub fn fd(&self) -> Result<i32, String> {
+    Err("Message notification descriptors are only supported on Unix.".to_string())
+  }
+
+  pub fn notify(&self) {}
+
+  pub fn clear(&self) {}
+}
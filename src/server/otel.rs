@@ -0,0 +1,442 @@
+// otel.rs
+//
+// OpenTelemetry export (the "otel" feature): spans for the servers' connection lifecycles, and metrics for their throughput and queues, sent to an OTLP collector, so quicksocket shows up in an existing observability stack. Configured by the standard OTEL_* environment variables (read once, when the first server starts). Speaks OTLP over HTTP with JSON bodies itself, through hyper's client, so it needs none of the OpenTelemetry SDK crates; there's no TLS (https://) yet, and no gRPC.
+//
+// The spans are the tracing spans of logging.rs, taken by a tracing layer (see layer()) as they close: each connection's is the root of a trace, with its handshake and its client's sender and receiver tasks as its children, and each cluster link has one of its own. Per-message spans ("fan_out", "send_to_client") aren't exported, as they'd swamp the collector. The metrics are every running server's stats (see stats.rs), each data point with its server's port as "server.port".
+//
+// Both are exported by a task on the client-mode runtime (see client.rs): spans in batches, every OTEL_BSP_SCHEDULE_DELAY, and metrics every OTEL_METRIC_EXPORT_INTERVAL. Failed exports are retried with the next batch's, and recorded as "otel" error events.
+
+use std::{collections::hash_map::RandomState, env, fmt, hash::{BuildHasher, Hasher}, sync::atomic::{AtomicU64, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use hyper::{Body, Client, Request, client::HttpConnector, header};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tracing::{Metadata, Subscriber, field::{Field, Visit}, span};
+use tracing_subscriber::{Layer, filter::filter_fn, layer::Context, registry::LookupSpan};
+
+use super::{client::CLIENT_RT, consumer_state as cs, error_events::{self, Category, Severity}, inspector::json_string, stats::StatsSnapshot};
+
+/// The spans exported.
+const EXPORTED_SPANS: &[&str] = &["connection", "handshake", "sender", "receiver", "cluster_link"];
+/// How many finished spans can wait to be exported before more are dropped (the default OTEL_BSP_MAX_QUEUE_SIZE).
+const SPAN_QUEUE_LEN: usize = 2048;
+/// The most spans in one export request (the default OTEL_BSP_MAX_EXPORT_BATCH_SIZE). Spans that didn't make it into one wait for the next.
+const MAX_EXPORT_BATCH: usize = 512;
+/// The collector quicksocket exports to unless OTEL_EXPORTER_OTLP_ENDPOINT says otherwise: a local one, on OTLP/HTTP's port.
+const DEFAULT_ENDPOINT: &str = "http://localhost:4318";
+/// The defaults for OTEL_BSP_SCHEDULE_DELAY, OTEL_METRIC_EXPORT_INTERVAL and OTEL_EXPORTER_OTLP_TIMEOUT.
+const DEFAULT_SPAN_DELAY: Duration = Duration::from_millis(5000);
+const DEFAULT_METRIC_INTERVAL: Duration = Duration::from_millis(60000);
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(10000);
+
+lazy_static! {
+  /// Where the layer sends finished spans, if they're exported. Reading the configuration and starting the export task, the first time it's needed.
+  static ref SPAN_TX: Option<mpsc::Sender<FinishedSpan>> = start();
+}
+
+/// Spans dropped because the export queue was full.
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+/// The layer that exports the spans, for the process's tracing subscriber: installed by the first server started (see logging.rs), unless the program has a subscriber of its own already, to which it can add this. Also starts exporting metrics, if they're to be. None if spans aren't to be exported (OTEL_TRACES_EXPORTER=none, OTEL_SDK_DISABLED=true, or a configuration that's not usable, which is logged).
+pub fn layer<S: Subscriber + for<'a> LookupSpan<'a>>() -> Option<impl Layer<S>> {
+  let span_tx = SPAN_TX.clone()?;
+  Some(SpanLayer { span_tx }.with_filter(filter_fn(is_exported)))
+}
+
+fn is_exported(metadata: &Metadata<'_>) -> bool {
+  metadata.is_span() && metadata.target().starts_with("quicksocket") && EXPORTED_SPANS.contains(&metadata.name())
+}
+
+fn start() -> Option<mpsc::Sender<FinishedSpan>> {
+  let config = match ExportConfig::from_env() {
+    Ok(Some(config)) => config,
+    Ok(None) => return None,
+    Err(err) => {
+      log_error!("[otel] Not exporting to OpenTelemetry: {}", err);
+      error_events::record(Severity::Error, Category::Otel, format!("Not exporting to OpenTelemetry: {}", err), None);
+      return None;
+    }
+  };
+  log_info!("[otel] Exporting {} to {}.", match (&config.traces_url, &config.metrics_url) {
+    (Some(_), Some(_)) => "spans and metrics",
+    (Some(_), None) => "spans",
+    _ => "metrics",
+  }, config.traces_url.as_ref().or(config.metrics_url.as_ref()).unwrap());
+
+  let (span_tx, span_rx) = mpsc::channel(SPAN_QUEUE_LEN);
+  let span_tx = config.traces_url.is_some().then_some(span_tx);
+  CLIENT_RT.spawn(run_exporter(config, span_rx));
+  span_tx
+}
+
+// Configuration
+// -------------
+
+/// Where and how often to export, from the OTEL_* environment variables.
+struct ExportConfig {
+  /// None for what isn't exported.
+  traces_url: Option<String>,
+  metrics_url: Option<String>,
+  /// OTEL_EXPORTER_OTLP_HEADERS, sent with every request (e.g. an API key).
+  headers: Vec<(String, String)>,
+  /// The resource the spans and metrics come from, encoded: service.name and OTEL_RESOURCE_ATTRIBUTES.
+  resource: String,
+  span_delay: Duration,
+  metric_interval: Duration,
+  timeout: Duration,
+}
+
+impl ExportConfig {
+  /// Reads the configuration. None if nothing's to be exported; an error for settings quicksocket can't follow.
+  fn from_env() -> Result<Option<ExportConfig>, String> {
+    if var("OTEL_SDK_DISABLED").is_some_and(|disabled| disabled.eq_ignore_ascii_case("true")) {
+      return Ok(None);
+    }
+    for protocol in ["OTEL_EXPORTER_OTLP_PROTOCOL", "OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", "OTEL_EXPORTER_OTLP_METRICS_PROTOCOL"] {
+      if let Some(value) = var(protocol).filter(|value| value != "http/json") {
+        return Err(format!("{} is {:?}, but quicksocket only exports http/json", protocol, value));
+      }
+    }
+
+    let endpoint = var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_else(|| DEFAULT_ENDPOINT.to_string());
+    let url = |exporter: &str, signal: &str, path: &str| -> Result<Option<String>, String> {
+      match var(exporter).as_deref() {
+        None | Some("otlp") => {}
+        Some("none") => return Ok(None),
+        Some(other) => return Err(format!("{} is {:?}, but quicksocket only exports otlp", exporter, other)),
+      }
+      // (The general endpoint is a base for each signal's path; a signal's own is used as it is.)
+      let url = var(signal).unwrap_or_else(|| format!("{}/{}", endpoint.trim_end_matches('/'), path));
+      match url.starts_with("http://") {
+        true => Ok(Some(url)),
+        false => Err(format!("{:?} isn't an http:// URL (quicksocket doesn't export over TLS)", url)),
+      }
+    };
+    let traces_url = url("OTEL_TRACES_EXPORTER", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", "v1/traces")?;
+    let metrics_url = url("OTEL_METRICS_EXPORTER", "OTEL_EXPORTER_OTLP_METRICS_ENDPOINT", "v1/metrics")?;
+    if traces_url.is_none() && metrics_url.is_none() {
+      return Ok(None);
+    }
+
+    let headers = key_values("OTEL_EXPORTER_OTLP_HEADERS")?;
+    let mut attributes = key_values("OTEL_RESOURCE_ATTRIBUTES")?;
+    let service_name = var("OTEL_SERVICE_NAME")
+      .or_else(|| attributes.iter().find(|(key, _)| key == "service.name").map(|(_, value)| value.clone()))
+      .unwrap_or_else(|| "quicksocket".to_string());
+    attributes.retain(|(key, _)| key != "service.name");
+    attributes.insert(0, ("service.name".to_string(), service_name));
+    let resource = attributes.iter().map(|(key, value)| string_attribute(key, value)).collect::<Vec<_>>().join(",");
+
+    Ok(Some(ExportConfig {
+      traces_url,
+      metrics_url,
+      headers,
+      resource,
+      span_delay: millis("OTEL_BSP_SCHEDULE_DELAY")?.unwrap_or(DEFAULT_SPAN_DELAY),
+      metric_interval: millis("OTEL_METRIC_EXPORT_INTERVAL")?.unwrap_or(DEFAULT_METRIC_INTERVAL),
+      timeout: millis("OTEL_EXPORTER_OTLP_TIMEOUT")?.unwrap_or(DEFAULT_TIMEOUT),
+    }))
+  }
+}
+
+/// An environment variable's value, if it's set to something.
+fn var(name: &str) -> Option<String> {
+  env::var(name).ok().map(|value| value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+/// A list of key=value pairs, separated by commas, with values percent-encoded (as in OTEL_EXPORTER_OTLP_HEADERS).
+fn key_values(name: &str) -> Result<Vec<(String, String)>, String> {
+  let value = match var(name) {
+    Some(value) => value,
+    None => return Ok(vec![]),
+  };
+  value.split(',').filter(|pair| !pair.trim().is_empty()).map(|pair| match pair.split_once('=') {
+    Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), percent_decode(value.trim()))),
+    _ => Err(format!("{} has {:?}, which isn't a key=value pair", name, pair)),
+  }).collect()
+}
+
+fn percent_decode(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let escaped = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    match (bytes[i], escaped) {
+      (b'%', Some(byte)) => { decoded.push(byte); i += 3; }
+      (byte, _) => { decoded.push(byte); i += 1; }
+    }
+  }
+  String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A duration in milliseconds, if it's set.
+fn millis(name: &str) -> Result<Option<Duration>, String> {
+  var(name).map(|value| match value.parse::<u64>() {
+    Ok(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+    _ => Err(format!("{} is {:?}, which isn't a number of milliseconds", name, value)),
+  }).transpose()
+}
+
+// Spans
+// -----
+
+/// Kept in an exported span's extensions while it's open.
+struct OpenSpan {
+  trace_id: u128,
+  span_id: u64,
+  parent_span_id: Option<u64>,
+  start: SystemTime,
+  attributes: Vec<(&'static str, String)>,
+}
+
+struct FinishedSpan {
+  name: &'static str,
+  span: OpenSpan,
+  end: SystemTime,
+}
+
+struct SpanLayer {
+  span_tx: mpsc::Sender<FinishedSpan>,
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanLayer {
+  fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+    let span = match ctx.span(id) {
+      Some(span) => span,
+      None => return,
+    };
+    // A span within an exported one (the nearest, as the layer only sees those) joins its trace; others start a trace of their own.
+    let parent = span.scope().skip(1).find_map(|ancestor| ancestor.extensions().get::<OpenSpan>().map(|parent| (parent.trace_id, parent.span_id)));
+    let (trace_id, parent_span_id) = match parent {
+      Some((trace_id, parent_span_id)) => (trace_id, Some(parent_span_id)),
+      None => ((random_id() as u128) << 64 | random_id() as u128, None),
+    };
+    let mut attributes = vec![];
+    attrs.record(&mut Attributes(&mut attributes));
+    span.extensions_mut().insert(OpenSpan { trace_id, span_id: random_id(), parent_span_id, start: SystemTime::now(), attributes });
+  }
+
+  fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+    if let Some(span) = ctx.span(id) {
+      if let Some(open) = span.extensions_mut().get_mut::<OpenSpan>() { values.record(&mut Attributes(&mut open.attributes)); }
+    }
+  }
+
+  fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+    let span = match ctx.span(&id) {
+      Some(span) => span,
+      None => return,
+    };
+    let open = span.extensions_mut().remove::<OpenSpan>();
+    if let Some(open) = open {
+      // (Never waits: a collector that can't keep up mustn't hold up the server's tasks.)
+      if self.span_tx.try_send(FinishedSpan { name: span.name(), span: open, end: SystemTime::now() }).is_err() {
+        DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+  }
+}
+
+/// Collects a span's fields as its attributes.
+struct Attributes<'a>(&'a mut Vec<(&'static str, String)>);
+
+impl Visit for Attributes<'_> {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    self.0.push((field.name(), value.to_string()));
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+    self.0.push((field.name(), format!("{:?}", value)));
+  }
+}
+
+/// A random, nonzero id. (Unique enough for spans; they needn't be unguessable.)
+fn random_id() -> u64 {
+  static COUNTER: AtomicU64 = AtomicU64::new(0);
+  let mut hasher = RandomState::new().build_hasher();
+  hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+  hasher.finish().max(1)
+}
+
+// Export
+// ------
+
+/// Exports the finished spans in batches, and the servers' metrics, for as long as the process runs.
+async fn run_exporter(config: ExportConfig, mut span_rx: mpsc::Receiver<FinishedSpan>) {
+  let client = Client::new();
+  let mut traces = Export::new("spans");
+  let mut metrics = Export::new("metrics");
+  let mut spans = vec![];
+  let mut span_timer = tokio::time::interval(config.span_delay);
+  let mut metric_timer = tokio::time::interval(config.metric_interval);
+  metric_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+  loop {
+    tokio::select! {
+      Some(span) = span_rx.recv(), if spans.len() < SPAN_QUEUE_LEN => {
+        spans.push(span);
+        if spans.len() < MAX_EXPORT_BATCH { continue; }
+      }
+      _ = span_timer.tick() => {}
+      _ = metric_timer.tick(), if config.metrics_url.is_some() => {
+        let servers = server_stats();
+        if !servers.is_empty() { metrics.send(&client, &config, config.metrics_url.as_ref().unwrap(), metrics_json(&config.resource, &servers)).await; }
+        continue;
+      }
+    }
+    if let (Some(url), false) = (&config.traces_url, spans.is_empty()) {
+      let batch_len = spans.len().min(MAX_EXPORT_BATCH);
+      let body = traces_json(&config.resource, &spans[..batch_len]);
+      // (Kept for the next attempt if they weren't taken, up to the queue's length.)
+      if traces.send(&client, &config, url, body).await { spans.drain(..batch_len); }
+    }
+    let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+      log_warn!("[otel] Dropped {} span(s): the export queue was full.", dropped);
+    }
+  }
+}
+
+/// Posts one kind of export, reporting it failing (once, until it succeeds again) and recovering.
+struct Export {
+  what: &'static str,
+  failing: bool,
+}
+
+impl Export {
+  fn new(what: &'static str) -> Export {
+    Export { what, failing: false }
+  }
+
+  /// Whether the collector took the export.
+  async fn send(&mut self, client: &Client<HttpConnector>, config: &ExportConfig, url: &str, body: String) -> bool {
+    match post(client, config, url, body).await {
+      Ok(()) => {
+        if self.failing { log_info!("[otel] Exporting {} to {} again.", self.what, url); }
+        self.failing = false;
+        true
+      }
+      Err(err) if self.failing => {
+        log_debug!("[otel] Still failing to export {} to {}: {}", self.what, url, err);
+        false
+      }
+      Err(err) => {
+        log_warn!("[otel] Failed to export {} to {}: {}", self.what, url, err);
+        error_events::record(Severity::Warning, Category::Otel, format!("Failed to export {} to {}: {}", self.what, url, err), None);
+        self.failing = true;
+        false
+      }
+    }
+  }
+}
+
+async fn post(client: &Client<HttpConnector>, config: &ExportConfig, url: &str, body: String) -> Result<(), String> {
+  let mut request = Request::post(url).header(header::CONTENT_TYPE, "application/json");
+  for (name, value) in &config.headers {
+    request = request.header(name.as_str(), value.as_str());
+  }
+  let request = request.body(Body::from(body)).map_err(|err| err.to_string())?;
+  let response = tokio::time::timeout(config.timeout, client.request(request)).await
+    .map_err(|_| format!("no response within {} ms", config.timeout.as_millis()))?
+    .map_err(|err| err.to_string())?;
+  match response.status().is_success() {
+    true => Ok(()),
+    false => Err(format!("the collector answered {}", response.status())),
+  }
+}
+
+/// Every running server's port and stats.
+fn server_stats() -> Vec<(u32, StatsSnapshot)> {
+  let servers = cs::read(&cs::CS_SERVERS, |servers| servers.clone()).unwrap_or_default();
+  servers.iter().filter(|server| server.is_alive()).map(|server| {
+    let port = match server.bound_port.load(Ordering::Relaxed) {
+      0 => server.port,
+      bound => bound,
+    };
+    (port, server.stats.snapshot())
+  }).collect()
+}
+
+// OTLP/JSON encoding
+// ------------------
+
+fn unix_nanos(time: SystemTime) -> u128 {
+  time.duration_since(UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or(0)
+}
+
+fn string_attribute(key: &str, value: &str) -> String {
+  format!(r#"{{"key":{},"value":{{"stringValue":{}}}}}"#, json_string(key), json_string(value))
+}
+
+fn int_attribute(key: &str, value: u64) -> String {
+  format!(r#"{{"key":{},"value":{{"intValue":"{}"}}}}"#, json_string(key), value)
+}
+
+/// Wraps a list of encoded spans or metrics in the resource and scope they come from.
+fn envelope(resource: &str, resource_key: &str, scope_key: &str, items_key: &str, items: &str) -> String {
+  format!(
+    r#"{{"{}":[{{"resource":{{"attributes":[{}]}},"{}":[{{"scope":{{"name":"quicksocket","version":{}}},"{}":[{}]}}]}}]}}"#,
+    resource_key, resource, scope_key, json_string(env!("CARGO_PKG_VERSION")), items_key, items
+  )
+}
+
+fn traces_json(resource: &str, spans: &[FinishedSpan]) -> String {
+  let spans = spans.iter().map(|finished| {
+    let span = &finished.span;
+    // A connection is the server's side of one; a cluster link is a client of another node's.
+    let kind = match finished.name {
+      "connection" => 2,
+      "cluster_link" => 3,
+      _ => 1,
+    };
+    let parent = span.parent_span_id.map(|parent| format!(r#""parentSpanId":"{:016x}","#, parent)).unwrap_or_default();
+    let attributes = span.attributes.iter().map(|(key, value)| string_attribute(key, value)).collect::<Vec<_>>().join(",");
+    format!(
+      r#"{{"traceId":"{:032x}","spanId":"{:016x}",{}"name":{},"kind":{},"startTimeUnixNano":"{}","endTimeUnixNano":"{}","attributes":[{}]}}"#,
+      span.trace_id, span.span_id, parent, json_string(finished.name), kind, unix_nanos(span.start), unix_nanos(finished.end), attributes
+    )
+  }).collect::<Vec<_>>().join(",");
+  envelope(resource, "resourceSpans", "scopeSpans", "spans", &spans)
+}
+
+/// A metric's kind: a cumulative count, or a reading.
+enum Instrument {
+  Sum,
+  Gauge,
+}
+
+/// A metric exported for each server: its name, unit and description, and how it's read off the server's stats.
+struct MetricSpec {
+  name: &'static str,
+  unit: &'static str,
+  description: &'static str,
+  instrument: Instrument,
+  value: fn(&StatsSnapshot) -> u64,
+}
+
+fn metrics_json(resource: &str, servers: &[(u32, StatsSnapshot)]) -> String {
+  let now = unix_nanos(SystemTime::now());
+  let metrics = [
+    MetricSpec { name: "quicksocket.connections", unit: "{connection}", description: "Websocket clients accepted.", instrument: Instrument::Sum, value: |stats| stats.total_connections },
+    MetricSpec { name: "quicksocket.clients", unit: "{client}", description: "Websocket clients connected.", instrument: Instrument::Gauge, value: |stats| stats.current_clients },
+    MetricSpec { name: "quicksocket.messages.sent", unit: "{message}", description: "Messages written to clients.", instrument: Instrument::Sum, value: |stats| stats.messages_sent },
+    MetricSpec { name: "quicksocket.messages.received", unit: "{message}", description: "Messages received from clients.", instrument: Instrument::Sum, value: |stats| stats.messages_received },
+    MetricSpec { name: "quicksocket.messages.dropped", unit: "{message}", description: "Messages lost on the way, to clients or from them.", instrument: Instrument::Sum, value: |stats| stats.messages_dropped },
+    MetricSpec { name: "quicksocket.bytes.sent", unit: "By", description: "Payload bytes written to clients.", instrument: Instrument::Sum, value: |stats| stats.bytes_sent },
+    MetricSpec { name: "quicksocket.bytes.received", unit: "By", description: "Payload bytes received from clients.", instrument: Instrument::Sum, value: |stats| stats.bytes_received },
+    MetricSpec { name: "quicksocket.queue.bytes", unit: "By", description: "Payload bytes in the server's queues.", instrument: Instrument::Gauge, value: |stats| stats.queued_bytes },
+    MetricSpec { name: "quicksocket.queue.peak_bytes", unit: "By", description: "The most payload bytes there have been in the server's queues.", instrument: Instrument::Gauge, value: |stats| stats.peak_queued_bytes },
+    MetricSpec { name: "quicksocket.errors", unit: "{error}", description: "Error events recorded by the server, warnings included.", instrument: Instrument::Sum, value: |stats| stats.warnings + stats.errors },
+  ];
+  let metrics = metrics.iter().map(|MetricSpec { name, unit, description, instrument, value }| {
+    let points = servers.iter().map(|(port, stats)| {
+      let start = unix_nanos(SystemTime::now() - stats.uptime);
+      format!(r#"{{"attributes":[{}],"startTimeUnixNano":"{}","timeUnixNano":"{}","asInt":"{}"}}"#, int_attribute("server.port", *port as u64), start, now, value(stats))
+    }).collect::<Vec<_>>().join(",");
+    let data = match instrument {
+      // (Cumulative, i.e. since the server started.)
+      Instrument::Sum => format!(r#""sum":{{"aggregationTemporality":2,"isMonotonic":true,"dataPoints":[{}]}}"#, points),
+      Instrument::Gauge => format!(r#""gauge":{{"dataPoints":[{}]}}"#, points),
+    };
+    format!(r#"{{"name":{},"unit":{},"description":{},{}}}"#, json_string(name), json_string(unit), json_string(description), data)
+  }).collect::<Vec<_>>().join(",");
+  envelope(resource, "resourceMetrics", "scopeMetrics", "metrics", &metrics)
+}
@@ -0,0 +1,162 @@
+// outbound.rs
+//
+// The messages on their way out to clients. Most are tungstenite Messages, their payloads copied into pooled buffers (see buffer_pool.rs); but a binary payload can also be shared with whatever it came from for as long as it's being sent, rather than copied: a Python bytes object, say, which is immutable, and kept alive by the reference the message holds. Clients' writers write a shared payload straight from where it is (see writer.rs), so a big binary broadcast isn't copied at all on its way to the sockets.
+//
+// A message sent over and over (a static asset, say, or a snapshot every new client is sent) can also be prepared once: a PreparedMessage is the whole frame, its header encoded along with the payload, shared by every send of it, so each costs neither a copy nor an encoding.
+
+use std::{fmt, sync::Arc};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{compression::DeflatedMessage, writer::{MAX_HEADER_LEN, push_header}};
+
+/// A payload shared with its owner rather than copied. It mustn't change while it's shared.
+pub type SharedBytes = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+/// A message for clients.
+#[derive(Clone)]
+pub enum Outbound {
+  Message(Message),
+  /// A binary message whose payload is shared.
+  SharedBinary(SharedBytes),
+  /// A message whose frame was encoded beforehand.
+  Prepared(PreparedMessage),
+  /// A broadcast message that's deflated for the clients that agreed to compression, alongside the original for the rest (see compression.rs).
+  Deflated(DeflatedMessage),
+}
+
+/// A text or binary message encoded as a frame once, to be sent any number of times. Cloning one shares the frame.
+#[derive(Clone)]
+pub struct PreparedMessage(Arc<PreparedFrame>);
+
+struct PreparedFrame {
+  /// The frame: its header, then the payload.
+  frame: Vec<u8>,
+  header_len: usize,
+  text: bool,
+}
+
+impl PreparedMessage {
+  pub fn text(text: &str) -> PreparedMessage {
+    PreparedMessage::new(0x1, text.as_bytes(), true)
+  }
+
+  pub fn binary(data: &[u8]) -> PreparedMessage {
+    PreparedMessage::new(0x2, data, false)
+  }
+
+  fn new(opcode: u8, payload: &[u8], text: bool) -> PreparedMessage {
+    let mut frame = Vec::with_capacity(MAX_HEADER_LEN + payload.len());
+    push_header(opcode, payload.len(), &mut frame);
+    let header_len = frame.len();
+    frame.extend_from_slice(payload);
+    PreparedMessage(Arc::new(PreparedFrame { frame, header_len, text }))
+  }
+
+  pub fn is_text(&self) -> bool {
+    self.0.text
+  }
+
+  pub fn payload(&self) -> &[u8] {
+    &self.0.frame[self.0.header_len..]
+  }
+
+  /// The whole frame, header and all, as written to a socket.
+  pub fn frame(&self) -> &[u8] {
+    &self.0.frame
+  }
+}
+
+impl From<PreparedMessage> for Outbound {
+  fn from(prepared: PreparedMessage) -> Outbound {
+    Outbound::Prepared(prepared)
+  }
+}
+
+impl From<Message> for Outbound {
+  fn from(msg: Message) -> Outbound {
+    Outbound::Message(msg)
+  }
+}
+
+impl fmt::Debug for Outbound {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Outbound::Message(msg) => msg.fmt(f),
+      Outbound::SharedBinary(data) => write!(f, "SharedBinary({} bytes)", (**data).as_ref().len()),
+      Outbound::Prepared(prepared) => write!(f, "Prepared({}, {} bytes)", if prepared.is_text() { "text" } else { "binary" }, prepared.payload().len()),
+      Outbound::Deflated(deflated) => write!(f, "Deflated({:?})", deflated.original()),
+    }
+  }
+}
+
+impl Outbound {
+  /// Payload length, in bytes.
+  pub fn len(&self) -> usize {
+    match self {
+      Outbound::Message(msg) => msg.len(),
+      Outbound::SharedBinary(data) => (**data).as_ref().len(),
+      Outbound::Prepared(prepared) => prepared.payload().len(),
+      Outbound::Deflated(deflated) => deflated.original().len(),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  pub fn is_text(&self) -> bool {
+    match self {
+      Outbound::Message(msg) => msg.is_text(),
+      Outbound::SharedBinary(_) => false,
+      Outbound::Prepared(prepared) => prepared.is_text(),
+      Outbound::Deflated(deflated) => deflated.original().is_text(),
+    }
+  }
+
+  pub fn is_binary(&self) -> bool {
+    match self {
+      Outbound::Message(msg) => msg.is_binary(),
+      Outbound::SharedBinary(_) => true,
+      Outbound::Prepared(prepared) => !prepared.is_text(),
+      Outbound::Deflated(deflated) => deflated.original().is_binary(),
+    }
+  }
+
+  /// The payload of a text message, wherever it is.
+  pub fn text_data(&self) -> Option<&str> {
+    match self {
+      Outbound::Message(Message::Text(text)) => Some(text),
+      // (Prepared from a str.)
+      Outbound::Prepared(prepared) if prepared.is_text() => Some(unsafe { std::str::from_utf8_unchecked(prepared.payload()) }),
+      Outbound::Deflated(deflated) => deflated.original().text_data(),
+      _ => None,
+    }
+  }
+
+  /// The payload of a binary message, wherever it is.
+  pub fn binary_data(&self) -> Option<&[u8]> {
+    match self {
+      Outbound::Message(Message::Binary(data)) => Some(data),
+      Outbound::SharedBinary(data) => Some((**data).as_ref()),
+      Outbound::Prepared(prepared) if !prepared.is_text() => Some(prepared.payload()),
+      Outbound::Deflated(deflated) => deflated.original().binary_data(),
+      _ => None,
+    }
+  }
+
+  /// The message as a tungstenite Message, copying a shared or prepared payload: for connections that tungstenite frames (client mode's).
+  pub fn into_message(self) -> Message {
+    match self {
+      Outbound::Message(msg) => msg,
+      Outbound::SharedBinary(data) => Message::Binary((*data).as_ref().to_vec()),
+      Outbound::Prepared(prepared) if prepared.is_text() => Message::Text(String::from_utf8_lossy(prepared.payload()).into_owned()),
+      Outbound::Prepared(prepared) => Message::Binary(prepared.payload().to_vec()),
+      Outbound::Deflated(deflated) => deflated.original().clone().into_message(),
+    }
+  }
+}
+
+/// Converts messages of any kind the send functions take.
+pub(crate) fn collect<M: Into<Outbound>>(messages: Vec<M>) -> Vec<Outbound> {
+  messages.into_iter().map(Into::into).collect()
+}
@@ -0,0 +1,168 @@
+// playback.rs
+//
+// The playback control protocol (ServerConfig::playback_control): transport controls for visualizers that play something back (a recording, a simulation) to their clients, so that each doesn't invent its own. Clients send text messages
+//
+//   playback:pause
+//   playback:resume
+//   playback:seek:<position>   (in seconds, from 0)
+//   playback:speed:<rate>      (1 for real time; more than 0)
+//
+// which their receiver tasks apply to the server's playback state instead of passing them on to the consumer. There's one state for all the clients: whoever sent the last control set it. It's a clock: while it's playing, its position moves on at its speed, so the consumer can read where playback should be whenever it sends the next frame (Server::playback_state()), as well as drain the controls as PlaybackEvents, each with the state it left, to react to them (Server::drain_playback_events()). Playback starts out playing, at position 0 and speed 1, as the server starts.
+//
+// A malformed control (an unknown one, or a position or speed that isn't a number in range) changes nothing; it's recorded as a "receive" warning event. At most PLAYBACK_EVENT_QUEUE_LEN events are kept until they're drained; later ones are dropped (though the state still changes).
+
+use std::{collections::VecDeque, sync::{Mutex, PoisonError}, time::{Instant, SystemTime}};
+
+/// What a playback control starts with.
+pub const PLAYBACK_PREFIX: &str = "playback:";
+/// How many playback events are kept until they're drained; more are dropped.
+pub const PLAYBACK_EVENT_QUEUE_LEN: usize = 1024;
+
+/// A control a client sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackControl {
+  Pause,
+  Resume,
+  /// To a position, in seconds.
+  Seek(f64),
+  /// To a rate: 1 for real time, 2 for twice as fast, and so on.
+  Speed(f64),
+}
+
+impl PlaybackControl {
+  /// The control a message is, if it's one: Some(Err) for a malformed one.
+  pub fn parse(text: &str) -> Option<Result<PlaybackControl, String>> {
+    let control = text.strip_prefix(PLAYBACK_PREFIX)?;
+    let (name, arg) = match control.split_once(':') {
+      Some((name, arg)) => (name, Some(arg)),
+      None => (control, None),
+    };
+    let number = |min_exclusive: bool| {
+      let value = arg.and_then(|arg| arg.trim().parse::<f64>().ok()).filter(|value| value.is_finite());
+      value.filter(|value| if min_exclusive { *value > 0.0 } else { *value >= 0.0 })
+    };
+    Some(match (name, arg) {
+      ("pause", None) => Ok(PlaybackControl::Pause),
+      ("resume", None) => Ok(PlaybackControl::Resume),
+      ("seek", Some(_)) => number(false).map(PlaybackControl::Seek).ok_or_else(|| format!("the seek position must be a number, 0 or more: {:?}", text)),
+      ("speed", Some(_)) => number(true).map(PlaybackControl::Speed).ok_or_else(|| format!("the speed must be a number more than 0: {:?}", text)),
+      _ => Err(format!("unknown playback control {:?}", text)),
+    })
+  }
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      PlaybackControl::Pause => "pause",
+      PlaybackControl::Resume => "resume",
+      PlaybackControl::Seek(_) => "seek",
+      PlaybackControl::Speed(_) => "speed",
+    }
+  }
+
+  /// A seek's position, or a speed's rate.
+  pub fn value(&self) -> Option<f64> {
+    match self {
+      PlaybackControl::Seek(value) | PlaybackControl::Speed(value) => Some(*value),
+      _ => None,
+    }
+  }
+}
+
+/// Where playback is, as returned by Server::playback_state(). A snapshot: the position's as of when it was taken.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaybackState {
+  pub paused: bool,
+  /// In seconds.
+  pub position: f64,
+  pub speed: f64,
+  /// How many controls have been applied.
+  pub changes: u64,
+  /// The client that sent the last control, if any has.
+  pub client_id: Option<String>,
+}
+
+/// A control a client sent, and the state it left playback in, as drained by Server::drain_playback_events().
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlaybackEvent {
+  pub client_id: String,
+  pub timestamp: SystemTime,
+  pub control: PlaybackControl,
+  pub state: PlaybackState,
+}
+
+/// The state, with its position as of `at`.
+struct Clock {
+  paused: bool,
+  position: f64,
+  at: Instant,
+  speed: f64,
+  changes: u64,
+  client_id: Option<String>,
+}
+
+impl Clock {
+  fn position(&self, now: Instant) -> f64 {
+    match self.paused {
+      true => self.position,
+      false => self.position + now.saturating_duration_since(self.at).as_secs_f64() * self.speed,
+    }
+  }
+
+  fn state(&self, now: Instant) -> PlaybackState {
+    PlaybackState { paused: self.paused, position: self.position(now), speed: self.speed, changes: self.changes, client_id: self.client_id.clone() }
+  }
+}
+
+/// A server's playback state, shared by its clients' receiver tasks, which apply their controls, and the consumer.
+pub struct Playback {
+  clock: Mutex<Clock>,
+  events: Mutex<VecDeque<PlaybackEvent>>,
+}
+
+impl Playback {
+  pub fn new() -> Playback {
+    let clock = Clock { paused: false, position: 0.0, at: Instant::now(), speed: 1.0, changes: 0, client_id: None };
+    Playback { clock: Mutex::new(clock), events: Mutex::default() }
+  }
+
+  /// Applies a client's control, and queues an event for it (unless the queue's full, which it says).
+  pub fn apply(&self, client_id: &str, control: PlaybackControl) -> Result<(), String> {
+    let now = Instant::now();
+    let state = {
+      let mut clock = self.clock.lock().unwrap_or_else(PoisonError::into_inner);
+      // (Moved on to now first, so changes take effect from here.)
+      clock.position = clock.position(now);
+      clock.at = now;
+      match control {
+        PlaybackControl::Pause => { clock.paused = true; }
+        PlaybackControl::Resume => { clock.paused = false; }
+        PlaybackControl::Seek(position) => { clock.position = position; }
+        PlaybackControl::Speed(speed) => { clock.speed = speed; }
+      }
+      clock.changes += 1;
+      clock.client_id = Some(client_id.to_string());
+      clock.state(now)
+    };
+    let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+    if events.len() >= PLAYBACK_EVENT_QUEUE_LEN {
+      return Err("the playback event queue is full".to_string());
+    }
+    events.push_back(PlaybackEvent { client_id: client_id.to_string(), timestamp: SystemTime::now(), control, state });
+    Ok(())
+  }
+
+  pub fn state(&self) -> PlaybackState {
+    self.clock.lock().unwrap_or_else(PoisonError::into_inner).state(Instant::now())
+  }
+
+  /// Takes the pending events, oldest first.
+  pub fn drain(&self) -> Vec<PlaybackEvent> {
+    self.events.lock().unwrap_or_else(PoisonError::into_inner).drain(..).collect()
+  }
+}
+
+impl Default for Playback {
+  fn default() -> Playback {
+    Playback::new()
+  }
+}
@@ -0,0 +1,164 @@
+// proxy.rs
+//
+// Reverse-proxy pass-through: websocket upgrades for configured paths (see ServerConfig::proxy_routes) are relayed to another websocket backend instead of becoming the server's clients, so one port can front both the server's own clients and a legacy service. A proxied connection is invisible to the consumer (no connection events, messages, or client counts); only its failures are recorded, as "proxy" errors.
+
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::{Message, protocol::{CloseFrame, frame::coding::CloseCode}};
+
+use super::{error_events::{Category, Severity}, http::{self, RequestHead}, stats::ServerStats, transport::Connection};
+
+/// How long connecting to (and handshaking with) a backend may take before the client gets a 502.
+const BACKEND_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long closing waits for either side to answer its close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Proxies websocket connections whose path is `path`, or starts with `path` followed by a '/', to `backend`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyRoute {
+  /// The path prefix to proxy, e.g. "/legacy". "/" proxies every path the inspector doesn't serve.
+  pub path: String,
+  /// The ws:// URL the connections go to. The rest of the request's path (after `path`) and its query string are appended to it, so with the route "/legacy" -> "ws://127.0.0.1:8000", "/legacy/feed?x=1" goes to "ws://127.0.0.1:8000/feed?x=1".
+  pub backend: String,
+}
+
+impl ProxyRoute {
+  pub fn new(path: &str, backend: &str) -> ProxyRoute {
+    ProxyRoute { path: path.to_string(), backend: backend.to_string() }
+  }
+
+  /// Checks the route can work, returning what's wrong with it otherwise.
+  pub fn validate(&self) -> Result<(), String> {
+    if !self.path.starts_with('/') {
+      return Err(format!("proxy path {:?} must start with '/'", self.path));
+    }
+    if !self.backend.starts_with("ws://") {
+      return Err(format!("proxy backend {:?} for {} must be a ws:// URL (wss:// needs TLS, which isn't supported yet)", self.backend, self.path));
+    }
+    Ok(())
+  }
+
+  /// The backend URL for a request for `path` (including any query), if the route covers it.
+  fn backend_url(&self, path: &str) -> Option<String> {
+    let prefix = self.path.trim_end_matches('/');
+    let rest = path.strip_prefix(prefix)?;
+    if !(rest.is_empty() || rest.starts_with('/') || rest.starts_with('?')) {
+      return None;
+    }
+    let rest = rest.strip_prefix('/').unwrap_or(rest);
+    Some(match rest.starts_with('?') {
+      true => format!("{}{}", self.backend, rest),
+      false => format!("{}/{}", self.backend.trim_end_matches('/'), rest),
+    })
+  }
+}
+
+/// Which backend URL, if any, a request for `path` is proxied to: the route with the longest matching path wins.
+pub fn backend_for(routes: &[ProxyRoute], path: &str) -> Option<String> {
+  routes.iter()
+    .filter_map(|route| route.backend_url(path).map(|url| (route.path.trim_end_matches('/').len(), url)))
+    .max_by_key(|(prefix_len, _)| *prefix_len)
+    .map(|(_, url)| url)
+}
+
+/// Serves a proxied connection for its lifetime: connects to the backend (answering 502 if that fails), completes the client's handshake, then relays text, binary and close frames both ways until either side closes or the server shuts down. Pings are answered on each side rather than relayed.
+pub async fn serve(addr: String, mut stream: Connection, head: &RequestHead, backend_url: String, stats: &ServerStats, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  let connecting = async { tokio_tungstenite::connect_async(&backend_url).await.map_err(|err| err.to_string()) };
+  let backend = tokio::time::timeout(BACKEND_CONNECT_TIMEOUT, connecting).await
+    .unwrap_or_else(|_| Err(format!("timed out after {} ms", BACKEND_CONNECT_TIMEOUT.as_millis())));
+  if let Err(err) = backend {
+    log_warn!("[proxy] Failed to connect to {} for {}: {}", backend_url, addr, err);
+    stats.record_error(Severity::Warning, Category::Proxy, format!("Failed to connect to the backend {}: {}", backend_url, err), Some(addr.clone()));
+    let res = http::respond(&mut stream, head, &http::Response::text(502, "Bad Gateway", "The backend for this path is unavailable.\n")).await;
+    if let Err(err) = res { log_warn!("[proxy] Failed to send 502 response to {}: {:?}", addr, err); }
+    return;
+  }
+  let (backend, _) = backend.unwrap();
+
+  let client = tokio_tungstenite::accept_async(stream).await;
+  if let Err(err) = client {
+    log_warn!("[proxy] Error during the websocket handshake with {}: {:?}", addr, err);
+    stats.record_error(Severity::Warning, Category::Handshake, format!("Websocket handshake failed: {}", err), Some(addr));
+    return;
+  }
+  let client = client.unwrap();
+  log_info!("[proxy] Proxying {} to {}.", addr, backend_url);
+
+  let (mut client_write, mut client_read) = client.split();
+  let (mut backend_write, mut backend_read) = backend.split();
+  loop { tokio::select! {
+    msg = client_read.next() => {
+      match relayable(msg) {
+        Ok(Some(msg)) => {
+          let closing = msg.is_close();
+          if let Err(err) = backend_write.send(msg).await {
+            record_relay_error(stats, &addr, format!("Failed to write to the backend {}: {}", backend_url, err));
+            break;
+          }
+          if closing { break; }
+        }
+        Ok(None) => {}
+        Err(reason) => {
+          if let Some(reason) = reason { record_relay_error(stats, &addr, format!("Failed to read from the client: {}", reason)); }
+          // A close frame, since the backend can't be told any better why the client went away.
+          let _ = backend_write.send(Message::Close(None)).await;
+          break;
+        }
+      }
+    }
+
+    msg = backend_read.next() => {
+      match relayable(msg) {
+        Ok(Some(msg)) => {
+          let closing = msg.is_close();
+          if let Err(err) = client_write.send(msg).await {
+            record_relay_error(stats, &addr, format!("Failed to write to the client: {}", err));
+            break;
+          }
+          if closing { break; }
+        }
+        Ok(None) => {}
+        Err(reason) => {
+          if let Some(reason) = reason { record_relay_error(stats, &addr, format!("Failed to read from the backend {}: {}", backend_url, reason)); }
+          let close_frame = CloseFrame { code: CloseCode::Error, reason: "Backend connection lost".into() };
+          let _ = client_write.send(Message::Close(Some(close_frame))).await;
+          break;
+        }
+      }
+    }
+
+    _ = ser_req_shutdown_rx.changed() => {
+      if *ser_req_shutdown_rx.borrow() {
+        let close_frame = CloseFrame { code: CloseCode::Away, reason: "Server shutting down".into() };
+        if client_write.send(Message::Close(Some(close_frame))).await.is_ok() { stats.close_frame_sent(); }
+        let _ = backend_write.send(Message::Close(None)).await;
+        break;
+      }
+    }
+  }}
+
+  // Give both sides a moment to answer the close frames (answering theirs is up to the streams themselves).
+  let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+    tokio::join!(
+      async { while let Some(Ok(_)) = client_read.next().await {} },
+      async { while let Some(Ok(_)) = backend_read.next().await {} },
+    )
+  }).await;
+  log_info!("[proxy] Proxied connection from {} closed.", addr);
+}
+
+/// What to do with a message read from either side: Ok(Some) to relay it, Ok(None) to skip it (pings and pongs), or Err once that side's gone (with the reason, if it didn't close cleanly).
+fn relayable(msg: Option<Result<Message, tokio_tungstenite::tungstenite::Error>>) -> Result<Option<Message>, Option<String>> {
+  match msg {
+    Some(Ok(msg)) if msg.is_ping() || msg.is_pong() => Ok(None),
+    Some(Ok(msg)) => Ok(Some(msg)),
+    Some(Err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)) | None => Err(None),
+    Some(Err(err)) => Err(Some(err.to_string())),
+  }
+}
+
+fn record_relay_error(stats: &ServerStats, addr: &str, err: String) {
+  log_warn!("[proxy] {} ({})", err, addr);
+  stats.record_error(Severity::Warning, Category::Proxy, err, Some(addr.to_string()));
+}
@@ -0,0 +1,177 @@
+// queue.rs
+//
+// The bounded queues that carry client messages and connection events from the tokio tasks to the consumer, in place of tokio's mpsc channels. With a channel, a receiver task that had read a message but found the channel full held on to it while it waited for room: a drain only ever saw the (at most 16) messages in the channel, not the ones already read and waiting to go in, and had to come back for them, a few at a time. Here, a message that's been read always goes straight in (push()), and the receiver tasks wait for room before reading the next one instead (room()), so a drain takes everything received so far in one go, and a consumer that falls behind still holds up the sockets rather than the queue growing without bound. (It can go over its capacity by one item per sender, at most.)
+
+use std::{collections::VecDeque, sync::{Arc, Mutex, MutexGuard, PoisonError}};
+use tokio::sync::Notify;
+
+pub use tokio::sync::mpsc::error::TryRecvError;
+
+struct Shared<T> {
+  state: Mutex<State<T>>,
+  capacity: usize,
+  /// Notified when items are queued, or the last sender is gone; for the receiver.
+  readable: Notify,
+  /// Notified when the queue drops below its capacity, or the receiver is gone; for senders waiting for room.
+  room: Notify,
+}
+
+struct State<T> {
+  items: VecDeque<T>,
+  senders: usize,
+  receiver_open: bool,
+}
+
+impl<T> Shared<T> {
+  fn state(&self) -> MutexGuard<'_, State<T>> {
+    self.state.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+/// A queue holding `capacity` items before senders wait (with send()) or fail (with try_send()).
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+  let shared = Arc::new(Shared {
+    state: Mutex::new(State { items: VecDeque::with_capacity(capacity), senders: 1, receiver_open: true }),
+    capacity,
+    readable: Notify::new(),
+    room: Notify::new(),
+  });
+  (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for Sender<T> {
+  fn clone(&self) -> Sender<T> {
+    self.shared.state().senders += 1;
+    Sender { shared: self.shared.clone() }
+  }
+}
+
+impl<T> Drop for Sender<T> {
+  fn drop(&mut self) {
+    let mut state = self.shared.state();
+    state.senders -= 1;
+    if state.senders == 0 {
+      drop(state);
+      self.shared.readable.notify_one();
+    }
+  }
+}
+
+impl<T> Sender<T> {
+  /// Queues an item however full the queue is, for items that have already been received (wait for room() before receiving the next). Fails, handing it back, if the receiver is gone.
+  pub fn push(&self, item: T) -> Result<(), T> {
+    let mut state = self.shared.state();
+    if !state.receiver_open { return Err(item); }
+    state.items.push_back(item);
+    drop(state);
+    self.shared.readable.notify_one();
+    Ok(())
+  }
+
+  /// Resolves once the queue is below its capacity (or the receiver is gone, so pushing would fail).
+  pub async fn room(&self) {
+    loop {
+      // (Registered before checking, so room made in between isn't missed.)
+      let notified = self.shared.room.notified();
+      tokio::pin!(notified);
+      notified.as_mut().enable();
+      {
+        let state = self.shared.state();
+        if state.items.len() < self.shared.capacity || !state.receiver_open { return; }
+      }
+      notified.await;
+    }
+  }
+
+  /// Waits for room, then queues an item. Fails, handing it back, if the receiver is gone.
+  pub async fn send(&self, item: T) -> Result<(), T> {
+    self.room().await;
+    self.push(item)
+  }
+
+  /// Queues an item if there's room. Fails, handing it back, if there isn't (or the receiver is gone).
+  pub fn try_send(&self, item: T) -> Result<(), T> {
+    let mut state = self.shared.state();
+    if !state.receiver_open || state.items.len() >= self.shared.capacity { return Err(item); }
+    state.items.push_back(item);
+    drop(state);
+    self.shared.readable.notify_one();
+    Ok(())
+  }
+}
+
+pub struct Receiver<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T> Drop for Receiver<T> {
+  fn drop(&mut self) {
+    let items = {
+      let mut state = self.shared.state();
+      state.receiver_open = false;
+      std::mem::take(&mut state.items)
+    };
+    drop(items);
+    self.shared.room.notify_waiters();
+  }
+}
+
+impl<T> Receiver<T> {
+  /// The next item, waiting for one if none are queued; None once the queue is empty and every sender is gone. Cancel-safe: an item is only taken when this resolves.
+  pub async fn recv(&mut self) -> Option<T> {
+    loop {
+      match self.try_recv() {
+        Ok(item) => { return Some(item); }
+        Err(TryRecvError::Disconnected) => { return None; }
+        // (A push since the last notification left a permit, so this doesn't miss it.)
+        Err(TryRecvError::Empty) => { self.shared.readable.notified().await; }
+      }
+    }
+  }
+
+  /// The next item, if one is queued.
+  pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+    let mut state = self.shared.state();
+    let had_room = state.items.len() < self.shared.capacity;
+    match state.items.pop_front() {
+      Some(item) => {
+        let has_room = state.items.len() < self.shared.capacity;
+        drop(state);
+        if has_room && !had_room { self.shared.room.notify_waiters(); }
+        Ok(item)
+      }
+      None if state.senders == 0 => Err(TryRecvError::Disconnected),
+      None => Err(TryRecvError::Empty),
+    }
+  }
+
+  /// How many items are queued.
+  pub fn len(&self) -> usize {
+    self.shared.state().items.len()
+  }
+
+  /// How many items are queued, for diagnostics, from wherever the receiver is (see Occupancy).
+  pub fn occupancy(&self) -> Occupancy<T> {
+    Occupancy { shared: self.shared.clone() }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+}
+
+/// A queue's fill level, readable without its receiver (which may be locked by a drain that's waiting for items).
+pub struct Occupancy<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T> Occupancy<T> {
+  /// How many items are queued, and how many the queue holds before senders wait.
+  pub fn get(&self) -> (usize, usize) {
+    (self.shared.state().items.len(), self.shared.capacity)
+  }
+}
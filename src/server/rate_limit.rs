@@ -0,0 +1,120 @@
+// rate_limit.rs
+//
+// A cap on the bytes the server writes to its clients' sockets each second, all clients together (ServerConfig::rate_limit), so that a server sharing its uplink with something more important can't take all of it. The clients' sender tasks share one token bucket: a write takes its bytes out of the bucket, which refills at the rate, up to the burst; if that leaves the bucket in debt, the write waits until it's paid off. A write bigger than the burst still goes out (after a longer wait), and later writes wait for whatever debt it left, so the rate holds on average even for huge messages.
+//
+// Each client can also have a limit of its own (ServerConfig::client_rate_limits), for clients on slow links: a remote viewer, say, that should get a throttled stream while the ones on the LAN get everything. Its writes then take their bytes from its own bucket too, and wait for whichever bucket says to wait longer. Which limit a client has depends on its tag, which the consumer gives it with Server::set_client_tag() (once it knows where the client is, from its address or its first message); clients start out untagged, with the default limit (if any).
+//
+// Messages held up this way wait in the clients' queues, which fill up like they do for clients that can't keep up: the lag policy then decides between missing broadcasts and waiting for room (see clients.rs). Writes of nothing but control frames (pongs, close frames) take their bytes without waiting, so clients still get answers and the server can still close connections promptly.
+
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+/// The burst a RateLimit allows by default: this fraction of a second's bytes (so 100 ms of them), ...
+const DEFAULT_BURST_SECS: f64 = 0.1;
+/// ... but at least this many, so that writes of a few small messages aren't held up at low rates.
+const MIN_DEFAULT_BURST: u64 = 64 * 1024;
+
+/// A cap on outbound bytes per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+  pub bytes_per_sec: u64,
+  /// Most bytes written at once after a quiet spell, before writes start waiting.
+  pub burst: u64,
+}
+
+impl RateLimit {
+  /// A cap of `bytes_per_sec`, with the default burst.
+  pub fn new(bytes_per_sec: u64) -> RateLimit {
+    RateLimit { bytes_per_sec, burst: ((bytes_per_sec as f64 * DEFAULT_BURST_SECS) as u64).max(MIN_DEFAULT_BURST) }
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.bytes_per_sec == 0 {
+      return Err("an outbound rate limit must allow at least 1 byte per second".to_string());
+    }
+    if self.burst == 0 {
+      return Err("an outbound rate limit's burst must be at least 1 byte".to_string());
+    }
+    Ok(())
+  }
+}
+
+/// Tokens (bytes) to spend on writes, refilled at a RateLimit's rate.
+pub struct TokenBucket {
+  limit: RateLimit,
+  /// The tokens in the bucket (negative when it's in debt), as of when they were last counted.
+  state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+  /// A full bucket.
+  pub fn new(limit: RateLimit) -> TokenBucket {
+    TokenBucket { limit, state: Mutex::new((limit.burst as f64, Instant::now())) }
+  }
+
+  pub fn limit(&self) -> RateLimit {
+    self.limit
+  }
+
+  /// Takes `bytes` out of the bucket, returning how long to wait before writing them (zero if the bucket had them).
+  pub fn take(&self, bytes: usize) -> Duration {
+    let mut state = match self.state.lock() {
+      Ok(state) => state,
+      // (Only a panic while counting could poison it; don't hold writes up over that.)
+      Err(_) => { return Duration::ZERO; }
+    };
+    let (tokens, counted_at) = &mut *state;
+    let now = Instant::now();
+    let rate = self.limit.bytes_per_sec as f64;
+    *tokens = (*tokens + now.saturating_duration_since(*counted_at).as_secs_f64() * rate).min(self.limit.burst as f64) - bytes as f64;
+    *counted_at = now;
+    if *tokens >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-*tokens / rate) }
+  }
+}
+
+/// Limits on each client's outbound bytes per second, on top of any for all clients together.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientRateLimits {
+  /// The limit for clients without a tag, or with one that isn't in `by_tag`. If None, they have none.
+  pub default: Option<RateLimit>,
+  /// The limits for clients with these tags.
+  pub by_tag: HashMap<String, RateLimit>,
+}
+
+impl ClientRateLimits {
+  /// The limit for a client with `tag`.
+  pub fn for_tag(&self, tag: Option<&str>) -> Option<RateLimit> {
+    tag.and_then(|tag| self.by_tag.get(tag)).copied().or(self.default)
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    self.default.iter().chain(self.by_tag.values()).try_for_each(RateLimit::validate)
+  }
+}
+
+/// One client's limit, which changes when it's tagged.
+pub struct ClientThrottle {
+  bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl ClientThrottle {
+  pub fn new(limit: Option<RateLimit>) -> Arc<ClientThrottle> {
+    Arc::new(ClientThrottle { bucket: Mutex::new(limit.map(TokenBucket::new)) })
+  }
+
+  /// Changes the client's limit, starting it with a full bucket (unless it's the limit the client already had, which then keeps its bucket as it is).
+  pub fn set_limit(&self, limit: Option<RateLimit>) {
+    if let Ok(mut bucket) = self.bucket.lock() {
+      if bucket.as_ref().map(TokenBucket::limit) != limit {
+        *bucket = limit.map(TokenBucket::new);
+      }
+    }
+  }
+
+  /// Takes `bytes` out of the client's bucket, if it has a limit, as TokenBucket::take() does.
+  pub fn take(&self, bytes: usize) -> Duration {
+    match self.bucket.lock() {
+      Ok(bucket) => bucket.as_ref().map_or(Duration::ZERO, |bucket| bucket.take(bytes)),
+      Err(_) => Duration::ZERO,
+    }
+  }
+}
@@ -0,0 +1,627 @@
+// recording.rs
+//
+// Session recording (see Server::start_recording()): every message a server's clients send it, and every message it sends them, written to a file as it passes through the server's tasks, with when, which way, and which client, for auditing and for analysing a session offline. Each broadcast is recorded once, with no client, rather than once per client it reaches; sends to one client are recorded with its id. Control frames (pings, pongs, close frames) aren't recorded.
+//
+// The tasks never wait on the file: they hand their records to a writer thread through a bounded queue, and when it's full (the disk can't keep up), the records are dropped and counted (see RecordingStats). Broadcasts are recorded by a subscriber to the broadcast queue of its own, like the inspector's, subscribed as the recording starts. RecordingReader reads recordings back, in either format, for replaying them (see replay.rs).
+//
+// Formats:
+// - JSONL: one JSON object per line, {"t": 1700000000.123456, "dir": "in", "client": "127.0.0.1:50000", "type": "text", "data": "hello"}: t is seconds since the Unix epoch (to the microsecond), dir is "in" (from a client) or "out" (to clients), client is null for broadcasts, type is "text" or "binary", and data is the text, or the binary payload in base64.
+// - Binary: the 8 bytes MAGIC, then each record as a u32 of the length of the rest of the record, a u64 of microseconds since the Unix epoch, a u8 direction (0 in, 1 out), a u8 type (0 text, 1 binary), a u16 of the client id's length (0 for broadcasts; client ids are never empty), the client id, and the payload. Integers are big-endian.
+
+use std::{convert::TryInto, fs::File, io::{self, BufRead, BufReader, BufWriter, Read, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex, PoisonError, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{self, SyncSender, TryRecvError}}, thread::{self, JoinHandle}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use tokio::sync::{mpsc as tokio_mpsc, watch};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::{Broadcast, BroadcastQueue, BroadcastReceiver}, error_events::{self, Category, Severity}, outbound::Outbound};
+
+/// The first bytes of a binary recording: "QSREC", two zero bytes, and the format's version.
+pub const MAGIC: &[u8; 8] = b"QSREC\0\0\x01";
+
+/// How many records can wait for the writer thread before they're dropped.
+const QUEUE_LEN: usize = 4096;
+/// Longest stopping a recording waits for its broadcast subscriber to record what was broadcast before it stopped.
+const BROADCASTS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How a recording is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RecordingFormat {
+  /// One JSON object per line.
+  #[default]
+  Jsonl,
+  /// Length-prefixed binary records, for big or binary-heavy sessions (no base64, no escaping).
+  Binary,
+}
+
+impl RecordingFormat {
+  /// "jsonl" or "binary", as for parse().
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      RecordingFormat::Jsonl  => "jsonl",
+      RecordingFormat::Binary => "binary",
+    }
+  }
+
+  pub fn parse(name: &str) -> Option<RecordingFormat> {
+    match name {
+      "jsonl"  => Some(RecordingFormat::Jsonl),
+      "binary" => Some(RecordingFormat::Binary),
+      _ => None,
+    }
+  }
+}
+
+/// Which way a recorded message went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  /// From a client to the server.
+  In,
+  /// From the server to a client, or to every client.
+  Out,
+}
+
+impl Direction {
+  /// "in" or "out", as in JSONL recordings.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Direction::In  => "in",
+      Direction::Out => "out",
+    }
+  }
+}
+
+/// How a recording went (or is going).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordingStats {
+  pub path: PathBuf,
+  pub format: RecordingFormat,
+  /// Messages written to the file.
+  pub recorded: u64,
+  /// Messages that weren't: the writer thread couldn't keep up, or writing failed.
+  pub dropped: u64,
+  /// Bytes written to the file.
+  pub bytes_written: u64,
+  /// Why writing the file failed, if it did; nothing more was written after that.
+  pub error: Option<String>,
+}
+
+/// A message on its way to the writer thread.
+struct Record {
+  timestamp: SystemTime,
+  direction: Direction,
+  client_id: Option<String>,
+  text: bool,
+  data: Vec<u8>,
+}
+
+impl Record {
+  fn new(direction: Direction, client_id: Option<&str>, text: bool, data: &[u8]) -> Record {
+    Record { timestamp: SystemTime::now(), direction, client_id: client_id.map(str::to_string), text, data: data.to_vec() }
+  }
+
+  /// The record of an outbound message; None for control frames.
+  fn of_outbound(direction: Direction, client_id: Option<&str>, msg: &Outbound) -> Option<Record> {
+    match (msg.text_data(), msg.binary_data()) {
+      (Some(text), _) => Some(Record::new(direction, client_id, true, text.as_bytes())),
+      (_, Some(data)) => Some(Record::new(direction, client_id, false, data)),
+      _ => None,
+    }
+  }
+
+  fn write_to(&self, format: RecordingFormat, out: &mut impl Write) -> io::Result<usize> {
+    let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    match format {
+      RecordingFormat::Jsonl => {
+        let client = self.client_id.as_deref().map(super::inspector::json_string).unwrap_or_else(|| "null".to_string());
+        let data = match self.text {
+          // (Text messages are valid UTF-8: tungstenite checks inbound ones, and outbound ones are Strings.)
+          true  => super::inspector::json_string(&String::from_utf8_lossy(&self.data)),
+          false => format!("\"{}\"", base64(&self.data)),
+        };
+        let line = format!(
+          "{{\"t\":{}.{:06},\"dir\":\"{}\",\"client\":{},\"type\":\"{}\",\"data\":{}}}\n",
+          since_epoch.as_secs(), since_epoch.subsec_micros(), self.direction.as_str(), client, if self.text { "text" } else { "binary" }, data
+        );
+        out.write_all(line.as_bytes())?;
+        Ok(line.len())
+      }
+      RecordingFormat::Binary => {
+        let client_id = self.client_id.as_deref().unwrap_or("").as_bytes();
+        let client_id = &client_id[..client_id.len().min(u16::MAX as usize)];
+        let len = 8 + 1 + 1 + 2 + client_id.len() + self.data.len();
+        out.write_all(&(len as u32).to_be_bytes())?;
+        out.write_all(&(since_epoch.as_micros() as u64).to_be_bytes())?;
+        out.write_all(&[(self.direction == Direction::Out) as u8, (!self.text) as u8])?;
+        out.write_all(&(client_id.len() as u16).to_be_bytes())?;
+        out.write_all(client_id)?;
+        out.write_all(&self.data)?;
+        Ok(4 + len)
+      }
+    }
+  }
+}
+
+/// A recording in progress: the writer thread, and the queue to it.
+struct Recording {
+  path: PathBuf,
+  format: RecordingFormat,
+  /// None tells the writer thread to finish.
+  tx: SyncSender<Option<Record>>,
+  writer: Mutex<Option<JoinHandle<()>>>,
+  recorded: Arc<AtomicU64>,
+  dropped: Arc<AtomicU64>,
+  bytes_written: Arc<AtomicU64>,
+  error: Arc<Mutex<Option<String>>>,
+  /// Set when the recording stops, for its broadcast subscriber.
+  stopped: watch::Sender<bool>,
+  /// Disconnected once the broadcast subscriber is done (or gone).
+  broadcasts_done: Mutex<Option<mpsc::Receiver<()>>>,
+}
+
+impl Recording {
+  fn record(&self, record: Record) {
+    // (Fails when the queue's full, i.e. the writer thread's behind.)
+    if self.tx.try_send(Some(record)).is_err() {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  fn stats(&self) -> RecordingStats {
+    RecordingStats {
+      path: self.path.clone(),
+      format: self.format,
+      recorded: self.recorded.load(Ordering::Relaxed),
+      dropped: self.dropped.load(Ordering::Relaxed),
+      bytes_written: self.bytes_written.load(Ordering::Relaxed),
+      error: self.error.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+    }
+  }
+
+  /// Tells the writer thread to finish (once it's written what's queued), and waits for it to.
+  fn finish(&self) {
+    self.stopped.send_replace(true);
+    if let Some(broadcasts_done) = self.broadcasts_done.lock().unwrap_or_else(PoisonError::into_inner).take() {
+      let _ = broadcasts_done.recv_timeout(BROADCASTS_TIMEOUT);
+    }
+    // (Blocks while the queue's full; the writer thread empties it, or has exited, which fails the send.)
+    let _ = self.tx.send(None);
+    if let Some(writer) = self.writer.lock().unwrap_or_else(PoisonError::into_inner).take() {
+      let _ = writer.join();
+    }
+  }
+}
+
+/// A new recording's broadcast subscriber, for the server's main task to spawn.
+pub struct BroadcastRecording {
+  recording: Arc<Recording>,
+  ser_msg_rx: BroadcastReceiver,
+  /// Dropped when the subscriber's done (or is never spawned).
+  _done: mpsc::Sender<()>,
+}
+
+/// The server's main task's end of its recorder: a new BroadcastRecording each time a recording starts.
+pub type RecordingStarts = tokio_mpsc::UnboundedReceiver<BroadcastRecording>;
+
+/// A server's recorder: the recording in progress, if any. Shared by the consumer, which starts and stops recordings, and the server's tasks, which record to them.
+pub struct Recorder {
+  /// Whether a recording's in progress, so the tasks only take the lock when one is.
+  active: AtomicBool,
+  recording: RwLock<Option<Arc<Recording>>>,
+  /// Set once the server has stopped, after which nothing can be recorded.
+  closed: AtomicBool,
+  starts: tokio_mpsc::UnboundedSender<BroadcastRecording>,
+}
+
+impl Recorder {
+  pub fn new() -> (Arc<Recorder>, RecordingStarts) {
+    let (starts, starts_rx) = tokio_mpsc::unbounded_channel();
+    (Arc::new(Recorder { active: AtomicBool::new(false), recording: RwLock::new(None), closed: AtomicBool::new(false), starts }), starts_rx)
+  }
+
+  /// Starts recording to `path` (created, or truncated), subscribing to `ser_msg_tx` for the broadcasts. Fails if a recording's in progress already, the server has stopped, or the file can't be created.
+  pub fn start(&self, path: &Path, format: RecordingFormat, ser_msg_tx: &Arc<BroadcastQueue>) -> Result<(), String> {
+    let mut current = self.recording.write().unwrap_or_else(PoisonError::into_inner);
+    if self.closed.load(Ordering::Acquire) {
+      return Err("the server has stopped".to_string());
+    }
+    if let Some(recording) = current.as_ref() {
+      return Err(format!("already recording to {}", recording.path.display()));
+    }
+    let file = File::create(path).map_err(|err| format!("failed to create {}: {}", path.display(), err))?;
+
+    let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+    let (done_tx, done_rx) = mpsc::channel();
+    let recorded = Arc::new(AtomicU64::new(0));
+    let dropped = Arc::new(AtomicU64::new(0));
+    let bytes_written = Arc::new(AtomicU64::new(0));
+    let error = Arc::new(Mutex::new(None));
+    let writer = {
+      let (path, recorded, dropped, bytes_written, error) = (path.to_path_buf(), recorded.clone(), dropped.clone(), bytes_written.clone(), error.clone());
+      thread::Builder::new().name("quicksocket-recorder".to_string()).spawn(move || {
+        let res = write_records(BufWriter::new(file), format, &rx, &recorded, &bytes_written);
+        if let Err(err) = res {
+          log_error!("[recording] Failed to write to {}: {}", path.display(), err);
+          error_events::record(Severity::Error, Category::Recording, format!("Stopped recording: failed to write to {}: {}", path.display(), err), None);
+          *error.lock().unwrap_or_else(PoisonError::into_inner) = Some(err.to_string());
+          // Whatever's still queued (and recorded after this) won't be written.
+          while let Ok(Some(_)) = rx.recv() { dropped.fetch_add(1, Ordering::Relaxed); }
+        }
+      }).map_err(|err| format!("failed to spawn the recorder thread: {}", err))?
+    };
+
+    let recording = Arc::new(Recording {
+      path: path.to_path_buf(),
+      format,
+      tx,
+      writer: Mutex::new(Some(writer)),
+      recorded,
+      dropped,
+      bytes_written,
+      error,
+      stopped: watch::channel(false).0,
+      broadcasts_done: Mutex::new(Some(done_rx)),
+    });
+    // (Subscribed now, rather than when the main task gets to it, so no broadcast sent after this returns is missed. If the server's stopping, the main task never does, and broadcasts aren't recorded, as there are none.)
+    let _ = self.starts.send(BroadcastRecording { recording: recording.clone(), ser_msg_rx: ser_msg_tx.subscribe(), _done: done_tx });
+    *current = Some(recording);
+    self.active.store(true, Ordering::Release);
+    log_info!("[recording] Recording to {} ({}).", path.display(), format.as_str());
+    Ok(())
+  }
+
+  /// Stops the recording in progress, if any, returning how it went once everything recorded has been written and the file closed.
+  pub fn stop(&self) -> Option<RecordingStats> {
+    let recording = {
+      let mut current = self.recording.write().unwrap_or_else(PoisonError::into_inner);
+      self.active.store(false, Ordering::Release);
+      current.take()?
+    };
+    recording.finish();
+    let stats = recording.stats();
+    log_info!("[recording] Stopped recording to {}: {} message(s) recorded, {} dropped.", stats.path.display(), stats.recorded, stats.dropped);
+    Some(stats)
+  }
+
+  /// Stops the recording in progress (as stop()) for the server stopping, and refuses any more.
+  pub fn close(&self) -> Option<RecordingStats> {
+    {
+      // (Under the lock, so a recording can't start between this and the stop.)
+      let _current = self.recording.write().unwrap_or_else(PoisonError::into_inner);
+      self.closed.store(true, Ordering::Release);
+    }
+    self.stop()
+  }
+
+  /// How the recording in progress is going, if there is one.
+  pub fn stats(&self) -> Option<RecordingStats> {
+    self.recording.read().unwrap_or_else(PoisonError::into_inner).as_ref().map(|recording| recording.stats())
+  }
+
+  fn with_recording(&self, f: impl FnOnce(&Recording)) {
+    if !self.active.load(Ordering::Acquire) { return; }
+    if let Some(recording) = self.recording.read().unwrap_or_else(PoisonError::into_inner).as_ref() { f(recording); }
+  }
+
+  /// Records a message from a client, if it's text or binary.
+  pub fn inbound(&self, client_id: &str, msg: &Message) {
+    self.with_recording(|recording| {
+      let record = match msg {
+        Message::Text(text)    => Record::new(Direction::In, Some(client_id), true, text.as_bytes()),
+        Message::Binary(data)  => Record::new(Direction::In, Some(client_id), false, data),
+        _ => { return; }
+      };
+      recording.record(record);
+    });
+  }
+
+  /// Records messages sent to one client.
+  pub fn outbound(&self, client_id: &str, msgs: &[Outbound]) {
+    self.with_recording(|recording| {
+      for msg in msgs {
+        if let Some(record) = Record::of_outbound(Direction::Out, Some(client_id), msg) { recording.record(record); }
+      }
+    });
+  }
+}
+
+fn write_records(mut out: BufWriter<File>, format: RecordingFormat, rx: &mpsc::Receiver<Option<Record>>, recorded: &AtomicU64, bytes_written: &AtomicU64) -> io::Result<()> {
+  if format == RecordingFormat::Binary {
+    out.write_all(MAGIC)?;
+    bytes_written.fetch_add(MAGIC.len() as u64, Ordering::Relaxed);
+  }
+  loop {
+    // Flushed whenever the queue's empty, so the file's up to date whenever the server's quiet (and writes are batched while it's busy).
+    let record = match rx.try_recv() {
+      Ok(record) => record,
+      Err(TryRecvError::Empty) => { out.flush()?; rx.recv().unwrap_or(None) }
+      Err(TryRecvError::Disconnected) => None,
+    };
+    // (Until told to finish, or every sender's gone.)
+    let record = match record {
+      Some(record) => record,
+      None => { break; }
+    };
+    let len = record.write_to(format, &mut out)?;
+    recorded.fetch_add(1, Ordering::Relaxed);
+    bytes_written.fetch_add(len as u64, Ordering::Relaxed);
+  }
+  out.flush()
+}
+
+/// Records each broadcast once, for as long as its recording's in progress (or the server runs).
+pub async fn record_broadcasts(start: BroadcastRecording, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  let BroadcastRecording { recording, mut ser_msg_rx, _done } = start;
+  let mut stopped_rx = recording.stopped.subscribe();
+  // (The recording may have stopped before this task got going.)
+  while !*stopped_rx.borrow() && !*ser_req_shutdown_rx.borrow() {
+    tokio::select! {
+      recv_res = ser_msg_rx.recv() => { match recv_res {
+        Some(broadcast) => { record_broadcast(&recording, broadcast); }
+        None => { return; }
+      }}
+      _ = stopped_rx.changed() => {}
+      _ = ser_req_shutdown_rx.changed() => {}
+    }
+  }
+  // Whatever was broadcast before the recording (or the server) stopped is in it, even if this task hadn't got to it yet.
+  while let Some(broadcast) = ser_msg_rx.try_recv() { record_broadcast(&recording, broadcast); }
+}
+
+fn record_broadcast(recording: &Recording, (msgs, missed): (Arc<Broadcast>, u64)) {
+  recording.dropped.fetch_add(missed, Ordering::Relaxed);
+  for msg in msgs.messages.iter() {
+    if let Some(record) = Record::of_outbound(Direction::Out, None, msg) { recording.record(record); }
+  }
+  OUTBOUND.recycle_shared(msgs);
+}
+
+/// A message read back from a recording.
+#[derive(Clone, Debug)]
+pub struct RecordedMessage {
+  pub timestamp: SystemTime,
+  pub direction: Direction,
+  /// The client it came from or went to; None for broadcasts.
+  pub client_id: Option<String>,
+  /// A text or binary message.
+  pub message: Message,
+}
+
+/// Reads a recording's messages in order, whichever format it's in (binary recordings start with MAGIC; anything else is taken for JSONL).
+pub struct RecordingReader {
+  input: BufReader<File>,
+  format: RecordingFormat,
+  /// Records read so far, for saying where a malformed one is.
+  read: u64,
+}
+
+impl RecordingReader {
+  pub fn open(path: &Path) -> io::Result<RecordingReader> {
+    let mut input = BufReader::new(File::open(path)?);
+    let format = if input.fill_buf()?.starts_with(MAGIC) { RecordingFormat::Binary } else { RecordingFormat::Jsonl };
+    if format == RecordingFormat::Binary { input.consume(MAGIC.len()); }
+    Ok(RecordingReader { input, format, read: 0 })
+  }
+
+  pub fn format(&self) -> RecordingFormat {
+    self.format
+  }
+
+  /// The next message, None at the end of the recording, or why it can't be read.
+  pub fn next_message(&mut self) -> Result<Option<RecordedMessage>, String> {
+    let res = match self.format {
+      RecordingFormat::Jsonl  => self.next_jsonl(),
+      RecordingFormat::Binary => self.next_binary(),
+    };
+    self.read += 1;
+    res.map_err(|err| format!("record {} is unreadable: {}", self.read, err))
+  }
+
+  fn next_jsonl(&mut self) -> Result<Option<RecordedMessage>, String> {
+    let mut line = String::new();
+    loop {
+      line.clear();
+      if self.input.read_line(&mut line).map_err(|err| err.to_string())? == 0 { return Ok(None); }
+      // (Blank lines, e.g. at the end of a file written by hand, are skipped.)
+      if !line.trim().is_empty() { break; }
+    }
+    let mut fields = JsonFields::parse(line.trim())?;
+    let seconds = fields.take("t").ok_or("its \"t\" is missing")?;
+    let direction = match fields.take("dir").as_deref() {
+      Some("in")  => Direction::In,
+      Some("out") => Direction::Out,
+      _ => { return Err("its \"dir\" isn't \"in\" or \"out\"".to_string()); }
+    };
+    let client_id = fields.take("client");
+    let data = fields.take("data").ok_or("its \"data\" is missing")?;
+    let message = match fields.take("type").as_deref() {
+      Some("text")   => Message::Text(data),
+      Some("binary") => Message::Binary(base64_decode(&data).ok_or("its \"data\" isn't base64")?),
+      _ => { return Err("its \"type\" isn't \"text\" or \"binary\"".to_string()); }
+    };
+    Ok(Some(RecordedMessage { timestamp: parse_timestamp(&seconds)?, direction, client_id, message }))
+  }
+
+  fn next_binary(&mut self) -> Result<Option<RecordedMessage>, String> {
+    let mut len = [0u8; 4];
+    match self.input.read_exact(&mut len) {
+      Ok(()) => {}
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => { return Ok(None); }
+      Err(err) => { return Err(err.to_string()); }
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len < 12 { return Err(format!("its length ({}) is too short", len)); }
+    let mut record = vec![0u8; len];
+    self.input.read_exact(&mut record).map_err(|err| format!("it's cut short ({})", err))?;
+
+    let micros = u64::from_be_bytes(record[0..8].try_into().unwrap());
+    let direction = if record[8] == 0 { Direction::In } else { Direction::Out };
+    let text = record[9] == 0;
+    let client_len = u16::from_be_bytes(record[10..12].try_into().unwrap()) as usize;
+    if 12 + client_len > len { return Err("its client id runs past its end".to_string()); }
+    let client_id = match client_len {
+      0 => None,
+      _ => Some(String::from_utf8_lossy(&record[12..12 + client_len]).into_owned()),
+    };
+    let payload = record.split_off(12 + client_len);
+    let message = match text {
+      true  => Message::Text(String::from_utf8(payload).map_err(|_| "its text isn't UTF-8")?),
+      false => Message::Binary(payload),
+    };
+    Ok(Some(RecordedMessage { timestamp: UNIX_EPOCH + Duration::from_micros(micros), direction, client_id, message }))
+  }
+}
+
+/// Parses a JSONL record's "t", seconds since the Unix epoch with up to microsecond decimals, without rounding through an f64.
+fn parse_timestamp(seconds: &str) -> Result<SystemTime, String> {
+  let invalid = || format!("its \"t\" ({}) isn't a time", seconds);
+  let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, ""));
+  let whole: u64 = whole.parse().map_err(|_| invalid())?;
+  if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) { return Err(invalid()); }
+  let nanos: u32 = format!("{:0<9}", fraction).parse().map_err(|_| invalid())?;
+  Ok(UNIX_EPOCH + Duration::new(whole, nanos))
+}
+
+/// The fields of a JSONL record: a flat JSON object, whose values are strings, numbers (kept as written) or null. That's all recordings are made of, so it's all this parses.
+struct JsonFields(Vec<(String, Option<String>)>);
+
+impl JsonFields {
+  fn parse(json: &str) -> Result<JsonFields, String> {
+    let mut cursor = JsonCursor { json: json.as_bytes(), pos: 0 };
+    let mut fields = vec![];
+    cursor.expect(b'{')?;
+    if cursor.peek() == Some(b'}') {
+      cursor.pos += 1;
+    } else {
+      loop {
+        let key = cursor.string()?;
+        cursor.expect(b':')?;
+        fields.push((key, cursor.value()?));
+        match cursor.bump() {
+          Some(b',') => {}
+          Some(b'}') => { break; }
+          _ => { return Err(format!("expected ',' or '}}' at column {}", cursor.pos)); }
+        }
+      }
+    }
+    if cursor.peek().is_some() { return Err(format!("unexpected text after the object at column {}", cursor.pos)); }
+    Ok(JsonFields(fields))
+  }
+
+  /// The value of the field `key`: None if it's null or missing.
+  fn take(&mut self, key: &str) -> Option<String> {
+    self.0.iter_mut().find(|(field, _)| field == key).and_then(|(_, value)| value.take())
+  }
+}
+
+struct JsonCursor<'a> {
+  json: &'a [u8],
+  pos: usize,
+}
+
+impl JsonCursor<'_> {
+  /// The next byte that isn't whitespace, without taking it.
+  fn peek(&mut self) -> Option<u8> {
+    while self.pos < self.json.len() && self.json[self.pos].is_ascii_whitespace() { self.pos += 1; }
+    self.json.get(self.pos).copied()
+  }
+
+  fn bump(&mut self) -> Option<u8> {
+    let next = self.peek();
+    self.pos += 1;
+    next
+  }
+
+  fn expect(&mut self, expected: u8) -> Result<(), String> {
+    match self.bump() {
+      Some(b) if b == expected => Ok(()),
+      _ => Err(format!("expected '{}' at column {}", expected as char, self.pos)),
+    }
+  }
+
+  fn value(&mut self) -> Result<Option<String>, String> {
+    match self.peek() {
+      Some(b'"') => self.string().map(Some),
+      Some(b'n') if self.json[self.pos..].starts_with(b"null") => { self.pos += 4; Ok(None) }
+      Some(b'-' | b'0'..=b'9') => {
+        let start = self.pos;
+        while self.pos < self.json.len() && matches!(self.json[self.pos], b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') { self.pos += 1; }
+        Ok(Some(String::from_utf8_lossy(&self.json[start..self.pos]).into_owned()))
+      }
+      _ => Err(format!("expected a string, a number or null at column {}", self.pos)),
+    }
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    self.expect(b'"')?;
+    let mut out = Vec::new();
+    loop {
+      let b = *self.json.get(self.pos).ok_or("unterminated string")?;
+      self.pos += 1;
+      match b {
+        b'"' => { break; }
+        b'\\' => {
+          let escaped = *self.json.get(self.pos).ok_or("unterminated string")?;
+          self.pos += 1;
+          match escaped {
+            b'"' | b'\\' | b'/' => out.push(escaped),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'u' => {
+              let mut c = self.hex4()?;
+              // (A UTF-16 surrogate pair: the second half follows as another escape.)
+              if (0xd800..0xdc00).contains(&c) && self.json[self.pos..].starts_with(b"\\u") {
+                self.pos += 2;
+                let low = self.hex4()?;
+                c = 0x10000 + ((c - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+              }
+              let c = char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER);
+              out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            _ => { return Err(format!("invalid escape at column {}", self.pos)); }
+          }
+        }
+        _ => out.push(b),
+      }
+    }
+    // (Only whole characters are copied, or escaped ones encoded, so it's UTF-8 as the line was.)
+    String::from_utf8(out).map_err(|_| "a string isn't UTF-8".to_string())
+  }
+
+  fn hex4(&mut self) -> Result<u32, String> {
+    let digits = self.json.get(self.pos..self.pos + 4).ok_or("unterminated \\u escape")?;
+    self.pos += 4;
+    u32::from_str_radix(std::str::from_utf8(digits).unwrap_or("-"), 16).map_err(|_| format!("invalid \\u escape at column {}", self.pos))
+  }
+}
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64, with padding.
+pub fn base64(data: &[u8]) -> String {
+  let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+  for chunk in data.chunks(3) {
+    let n = chunk.iter().enumerate().fold(0u32, |n, (i, byte)| n | ((*byte as u32) << (16 - 8 * i)));
+    for i in 0..4 {
+      if i <= chunk.len() { out.push(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize] as char); } else { out.push('='); }
+    }
+  }
+  out
+}
+
+/// Decodes standard base64 (padded or not); None if it isn't.
+pub fn base64_decode(text: &str) -> Option<Vec<u8>> {
+  let text = text.trim_end_matches('=').as_bytes();
+  let mut out = Vec::with_capacity(text.len() * 3 / 4);
+  for chunk in text.chunks(4) {
+    if chunk.len() == 1 { return None; }
+    let mut n = 0u32;
+    for (i, c) in chunk.iter().enumerate() {
+      let sextet = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+      n |= sextet << (18 - 6 * i);
+    }
+    out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+  }
+  Some(out)
+}
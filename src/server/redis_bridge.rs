@@ -0,0 +1,498 @@
+// redis_bridge.rs
+//
+// Redis pub/sub bridge (the "redis" feature): messages published on Redis channels are broadcast to a server's clients, and optionally the clients' messages are published back to a channel, so several quicksocket processes (or any other Redis publishers) can share one stream of messages. Speaks just enough of the Redis protocol (RESP) for pub/sub itself, over plain TCP, so it needs no Redis client library. Both directions run as tasks on the client-mode runtime (see client.rs), and reconnect by themselves if the Redis connection drops.
+
+use std::{future::Future, io, pin::Pin, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpStream, sync::watch, task::JoinHandle};
+
+use super::{Error, Message, Server, client::CLIENT_RT, consumer_state::{self as cs, RunState, SharedReceiver}, error_events::{self, Category, Severity}, events::ClientMessage};
+
+/// How long connecting (and authenticating, and subscribing) may take, at startup and on reconnects.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait between attempts to reconnect to Redis.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The largest bulk string accepted from Redis, the same as the largest websocket message tungstenite accepts by default.
+const MAX_BULK_LEN: usize = 64 << 20;
+/// The longest line accepted from Redis (a simple string, an error, or a length), CRLF included; Redis's own are far shorter.
+const MAX_LINE: usize = 64 << 10;
+
+/// What a Redis bridge subscribes to and publishes.
+#[derive(Clone, Debug, Default)]
+pub struct RedisBridgeConfig {
+  /// The Redis server, as redis://[[username]:password@]host[:port][/db]. (Pub/sub isn't scoped by database, so a /db is accepted and ignored.)
+  pub url: String,
+  /// Channels whose messages are broadcast to the clients.
+  pub channels: Vec<String>,
+  /// Channel patterns (as for PSUBSCRIBE, e.g. "prices.*") whose messages are broadcast to the clients.
+  pub patterns: Vec<String>,
+  /// If given, every client message is published to this channel. The bridge then takes the server's client messages for itself, as a relay forwarding them upstream does; otherwise they're left to the server's consumer.
+  pub publish_channel: Option<String>,
+}
+
+/// How many messages a Redis bridge has passed on, from RedisBridge::stats().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RedisBridgeStats {
+  /// Messages received from Redis and broadcast to the clients.
+  pub broadcast: u64,
+  /// Client messages published to Redis.
+  pub published: u64,
+  /// Times a dropped Redis connection was reconnected.
+  pub reconnects: u64,
+}
+
+#[derive(Default)]
+struct BridgeCounters {
+  broadcast: AtomicU64,
+  published: AtomicU64,
+  reconnects: AtomicU64,
+}
+
+/// A bridge started with RedisBridge::start(). It runs until stop() is called or the server stops; dropping the RedisBridge doesn't stop it. A lost Redis connection is retried every second (and recorded as an error) rather than ending the bridge; messages published while it's down are missed, as with any Redis subscriber.
+pub struct RedisBridge {
+  url: String,
+  stop_tx: watch::Sender<bool>,
+  tasks: Mutex<Vec<JoinHandle<()>>>,
+  counters: Arc<BridgeCounters>,
+}
+
+impl RedisBridge {
+  /// Connects to Redis, subscribes, and starts bridging to `server`'s clients. Blocks until subscribed (or failing that, for at most a few seconds). Fails with Error::InvalidConfig for a bad URL or a bridge with nothing to do, Error::Connect if Redis can't be reached or refuses the connection, Error::NotRunning if the server isn't running, and Error::ReceiverUnavailable (with publish_channel) if something else has the server's client messages.
+  pub fn start(server: &Server, config: RedisBridgeConfig) -> Result<RedisBridge, Error> {
+    let address = RedisAddress::parse(&config.url).map_err(Error::InvalidConfig)?;
+    if config.channels.is_empty() && config.patterns.is_empty() && config.publish_channel.is_none() {
+      return Err(Error::InvalidConfig("a Redis bridge needs channels or patterns to subscribe to, or a channel to publish to".to_string()));
+    }
+    if !server.is_running() {
+      return Err(Error::NotRunning);
+    }
+    let url = address.display_url();
+    let connect_error = |reason: String| Error::Connect { url: url.clone(), reason };
+
+    // Connect both directions before taking anything from the server, so a failure leaves it as it was.
+    let subscribing = !config.channels.is_empty() || !config.patterns.is_empty();
+    let connected = CLIENT_RT.block_on(async {
+      let subscriber = match subscribing {
+        true => Some(subscribe(&address, &config).await?),
+        false => None,
+      };
+      let publisher = match &config.publish_channel {
+        Some(_) => Some(RedisConnection::open(&address).await?),
+        None => None,
+      };
+      Ok::<_, String>((subscriber, publisher))
+    });
+    let (subscriber, publisher) = connected.map_err(connect_error)?;
+    let client_msg_rx = match publisher {
+      Some(_) => Some(server.cli_msg_rx.claim().ok_or(Error::ReceiverUnavailable)?),
+      None => None,
+    };
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let counters = Arc::new(BridgeCounters::default());
+    let state_rx = server.state_rx();
+    let mut tasks = vec![];
+    if let Some(subscriber) = subscriber {
+      tasks.push(CLIENT_RT.spawn(run_subscriber(address.clone(), config.clone(), subscriber, server.clone(), counters.clone(), stop_rx.clone(), state_rx.clone())));
+    }
+    if let (Some(publisher), Some(client_msg_rx)) = (publisher, client_msg_rx) {
+      let channel = config.publish_channel.clone().unwrap();
+      tasks.push(CLIENT_RT.spawn(run_publisher(address, channel, publisher, server.clone(), client_msg_rx, counters.clone(), stop_rx, state_rx)));
+    }
+
+    log_info!("[redis_bridge] Bridging {} to the clients of port {}.", url, server.port);
+    Ok(RedisBridge { url, stop_tx, tasks: Mutex::new(tasks), counters })
+  }
+
+  /// The Redis server's URL, without any credentials, as it's logged.
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// Whether the bridge is still running, i.e. any of its tasks is.
+  pub fn is_running(&self) -> bool {
+    self.tasks.lock().is_ok_and(|tasks| tasks.iter().any(|task| !task.is_finished()))
+  }
+
+  pub fn stats(&self) -> RedisBridgeStats {
+    RedisBridgeStats {
+      broadcast: self.counters.broadcast.load(Ordering::Relaxed),
+      published: self.counters.published.load(Ordering::Relaxed),
+      reconnects: self.counters.reconnects.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Stops the bridge, waiting for its tasks to finish (which hands the server's client messages back for draining). Stopping a stopped bridge does nothing. Mustn't be called from an async task.
+  pub fn stop(&self) {
+    self.stop_tx.send_replace(true);
+    let tasks = self.tasks.lock().map(|mut tasks| std::mem::take(&mut *tasks)).unwrap_or_default();
+    for task in tasks {
+      let _ = cs::block_on(task);
+    }
+  }
+}
+
+// Bridge tasks
+// ------------
+
+/// Broadcasts messages from the subscription until stopped or the server stops, resubscribing whenever the connection drops.
+#[allow(clippy::too_many_arguments)]
+async fn run_subscriber(
+  address: RedisAddress,
+  config: RedisBridgeConfig,
+  mut subscriber: RedisConnection,
+  server: Server,
+  counters: Arc<BridgeCounters>,
+  mut stop_rx: watch::Receiver<bool>,
+  mut state_rx: watch::Receiver<RunState>,
+) {
+  let url = address.display_url();
+  loop {
+    let frame = tokio::select! {
+      // (Reading isn't cancel-safe, but the connection is dropped with the task anyway.)
+      frame = subscriber.read_frame() => frame,
+      _ = stop_rx.wait_for(|stop| *stop) => { break; }
+      _ = state_rx.wait_for(|state| !state.is_alive()) => { break; }
+    };
+    let failure = match frame {
+      Ok(frame) => match frame.into_published() {
+        Some(payload) => {
+          if server.send(vec![payload_message(payload)]).is_err() { break; }
+          counters.broadcast.fetch_add(1, Ordering::Relaxed);
+          continue;
+        }
+        // Subscription confirmations and the like.
+        None => { continue; }
+      },
+      Err(err) => err,
+    };
+
+    record_error(&url, format!("Lost the Redis subscription: {}", failure));
+    let resubscribed = reconnect(&mut stop_rx, &mut state_rx, &url, || subscribe(&address, &config)).await;
+    match resubscribed {
+      Some(resubscribed) => {
+        subscriber = resubscribed;
+        counters.reconnects.fetch_add(1, Ordering::Relaxed);
+      }
+      None => { break; }
+    }
+  }
+  log_debug!("[redis_bridge] Subscriber task exiting.");
+}
+
+/// Publishes the clients' messages to `channel` until stopped or the server stops, reconnecting whenever the connection drops. Messages that couldn't be published are retried once reconnected. Hands the client messages back when done.
+#[allow(clippy::too_many_arguments)]
+async fn run_publisher(
+  address: RedisAddress,
+  channel: String,
+  mut publisher: RedisConnection,
+  server: Server,
+  shared_rx: SharedReceiver<ClientMessage>,
+  counters: Arc<BridgeCounters>,
+  mut stop_rx: watch::Receiver<bool>,
+  mut state_rx: watch::Receiver<RunState>,
+) {
+  let url = address.display_url();
+  {
+    let mut client_msg_rx = shared_rx.lock().await;
+    let mut pending: Vec<Vec<u8>> = vec![];
+    loop {
+      if pending.is_empty() {
+        let msg = tokio::select! {
+          msg = client_msg_rx.recv() => msg,
+          _ = stop_rx.wait_for(|stop| *stop) => { break; }
+        };
+        let msg = match msg {
+          Some(msg) => msg,
+          // The server has stopped.
+          None => { break; }
+        };
+        pending.extend(published_payload(msg));
+        while let Ok(msg) = client_msg_rx.try_recv() { pending.extend(published_payload(msg)); }
+        if pending.is_empty() { continue; }
+      }
+
+      let published = tokio::select! {
+        published = publisher.publish(&channel, &pending) => published,
+        _ = stop_rx.wait_for(|stop| *stop) => { break; }
+      };
+      match published {
+        Ok(()) => {
+          counters.published.fetch_add(pending.len() as u64, Ordering::Relaxed);
+          pending.clear();
+        }
+        Err(err) => {
+          record_error(&url, format!("Failed to publish {} message(s) to Redis: {}", pending.len(), err));
+          match reconnect(&mut stop_rx, &mut state_rx, &url, || RedisConnection::open(&address)).await {
+            Some(reconnected) => {
+              publisher = reconnected;
+              counters.reconnects.fetch_add(1, Ordering::Relaxed);
+            }
+            None => { break; }
+          }
+        }
+      }
+    }
+  }
+
+  server.cli_msg_rx.release();
+  log_debug!("[redis_bridge] Publisher task exiting.");
+}
+
+/// Retries `connect` every RECONNECT_DELAY until it succeeds, returning the connection, or until stopped or the server stops, returning None.
+async fn reconnect<F, Fut>(stop_rx: &mut watch::Receiver<bool>, state_rx: &mut watch::Receiver<RunState>, url: &str, connect: F) -> Option<RedisConnection>
+where F: Fn() -> Fut, Fut: Future<Output = Result<RedisConnection, String>> {
+  loop {
+    tokio::select! {
+      _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+      _ = stop_rx.wait_for(|stop| *stop) => { return None; }
+      _ = state_rx.wait_for(|state| !state.is_alive()) => { return None; }
+    }
+    match connect().await {
+      Ok(connection) => {
+        log_info!("[redis_bridge] Reconnected to {}.", url);
+        return Some(connection);
+      }
+      Err(err) => { log_debug!("[redis_bridge] Reconnecting to {} failed: {}", url, err); }
+    }
+  }
+}
+
+/// Opens a connection and subscribes it to the configured channels and patterns, waiting for Redis to confirm each one.
+async fn subscribe(address: &RedisAddress, config: &RedisBridgeConfig) -> Result<RedisConnection, String> {
+  let mut connection = RedisConnection::open(address).await?;
+  let subscribing = async {
+    let mut confirmations = 0;
+    if !config.channels.is_empty() {
+      connection.write_command("SUBSCRIBE", &config.channels).await?;
+      confirmations += config.channels.len();
+    }
+    if !config.patterns.is_empty() {
+      connection.write_command("PSUBSCRIBE", &config.patterns).await?;
+      confirmations += config.patterns.len();
+    }
+    for _ in 0..confirmations {
+      if let Frame::Error(err) = connection.read_frame().await? {
+        return Err(io::Error::other(err));
+      }
+    }
+    Ok::<_, io::Error>(())
+  };
+  tokio::time::timeout(CONNECT_TIMEOUT, subscribing).await
+    .map_err(|_| "timed out subscribing".to_string())?
+    .map_err(|err| format!("subscribing failed: {}", err))?;
+  Ok(connection)
+}
+
+/// A message from Redis, as a websocket message: text if it's valid UTF-8, binary otherwise.
+fn payload_message(payload: Vec<u8>) -> Message {
+  match String::from_utf8(payload) {
+    Ok(text) => Message::Text(text),
+    Err(err) => Message::Binary(err.into_bytes()),
+  }
+}
+
+/// The bytes to publish for a client message: text as UTF-8, binary as is, and nothing for control frames.
+fn published_payload(msg: ClientMessage) -> Option<Vec<u8>> {
+  match msg.message {
+    Message::Text(text) => Some(text.into_bytes()),
+    Message::Binary(bytes) => Some(bytes),
+    _ => None,
+  }
+}
+
+fn record_error(url: &str, err: String) {
+  log_warn!("[redis_bridge] {} ({})", err, url);
+  error_events::record(Severity::Warning, Category::Redis, err, None);
+}
+
+// Redis protocol
+// --------------
+
+/// Where a Redis server is, and how to log in to it, from a redis:// URL.
+#[derive(Clone, Debug)]
+struct RedisAddress {
+  host_port: String,
+  username: Option<String>,
+  password: Option<String>,
+}
+
+impl RedisAddress {
+  fn parse(url: &str) -> Result<RedisAddress, String> {
+    if url.starts_with("rediss://") {
+      return Err("rediss:// URLs need TLS, which isn't supported yet".to_string());
+    }
+    let rest = url.strip_prefix("redis://").ok_or_else(|| format!("{:?} isn't a redis:// URL", url))?;
+    let authority = rest.split('/').next().unwrap_or("");
+    let (credentials, host_port) = match authority.rsplit_once('@') {
+      Some((credentials, host_port)) => (Some(credentials), host_port),
+      None => (None, authority),
+    };
+    if host_port.is_empty() {
+      return Err(format!("{:?} has no host", url));
+    }
+    let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+      None => (None, None),
+      // (A bare "password@", as older clients write it.)
+      Some(None) => (None, Some(credentials.unwrap().to_string())),
+      Some(Some((username, password))) => ((!username.is_empty()).then(|| username.to_string()), Some(password.to_string())),
+    };
+    let host_port = match host_port.rsplit_once(':') {
+      Some((_, port)) if !port.contains(']') => {
+        port.parse::<u16>().map_err(|_| format!("{:?} has an invalid port", url))?;
+        host_port.to_string()
+      }
+      _ => format!("{}:6379", host_port),
+    };
+    Ok(RedisAddress { host_port, username, password })
+  }
+
+  /// The server's URL for logs and errors, leaving out the credentials.
+  fn display_url(&self) -> String {
+    format!("redis://{}", self.host_port)
+  }
+}
+
+/// A RESP reply or push.
+#[derive(Debug)]
+enum Frame {
+  Simple(String),
+  Error(String),
+  /// (Integer replies, e.g. PUBLISH's receiver count, aren't needed.)
+  Integer,
+  Bulk(Option<Vec<u8>>),
+  Array(Option<Vec<Frame>>),
+}
+
+impl Frame {
+  /// The payload, if this is a published message: ["message", channel, payload] or ["pmessage", pattern, channel, payload].
+  fn into_published(self) -> Option<Vec<u8>> {
+    let mut items = match self {
+      Frame::Array(Some(items)) => items,
+      _ => { return None; }
+    };
+    let kind = match items.first() {
+      Some(Frame::Bulk(Some(kind))) => kind.as_slice(),
+      _ => { return None; }
+    };
+    let expected_len = match kind {
+      b"message" => 3,
+      b"pmessage" => 4,
+      _ => { return None; }
+    };
+    if items.len() != expected_len { return None; }
+    match items.pop() {
+      Some(Frame::Bulk(Some(payload))) => Some(payload),
+      _ => None,
+    }
+  }
+}
+
+struct RedisConnection {
+  stream: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+  /// Connects, and logs in if the address has a password.
+  async fn open(address: &RedisAddress) -> Result<RedisConnection, String> {
+    let opening = async {
+      let stream = TcpStream::connect(&address.host_port).await.map_err(|err| err.to_string())?;
+      let mut connection = RedisConnection { stream: BufReader::new(stream) };
+      if let Some(password) = &address.password {
+        let mut args = vec![];
+        if let Some(username) = &address.username { args.push(username.clone()); }
+        args.push(password.clone());
+        connection.write_command("AUTH", &args).await.map_err(|err| err.to_string())?;
+        match connection.read_frame().await.map_err(|err| err.to_string())? {
+          Frame::Simple(ok) if ok == "OK" => {}
+          Frame::Error(err) => { return Err(format!("authentication failed: {}", err)); }
+          other => { return Err(format!("unexpected reply to AUTH: {:?}", other)); }
+        }
+      }
+      Ok(connection)
+    };
+    tokio::time::timeout(CONNECT_TIMEOUT, opening).await.unwrap_or_else(|_| Err("timed out".to_string()))
+  }
+
+  /// Publishes each payload to `channel`, pipelined, then checks every reply.
+  async fn publish(&mut self, channel: &str, payloads: &[Vec<u8>]) -> io::Result<()> {
+    let mut buf = vec![];
+    for payload in payloads {
+      encode_command(&mut buf, &[b"PUBLISH", channel.as_bytes(), payload]);
+    }
+    self.stream.get_mut().write_all(&buf).await?;
+    for _ in payloads {
+      if let Frame::Error(err) = self.read_frame().await? {
+        return Err(io::Error::other(err));
+      }
+    }
+    Ok(())
+  }
+
+  async fn write_command(&mut self, command: &str, args: &[String]) -> io::Result<()> {
+    let mut parts: Vec<&[u8]> = vec![command.as_bytes()];
+    parts.extend(args.iter().map(|arg| arg.as_bytes()));
+    let mut buf = vec![];
+    encode_command(&mut buf, &parts);
+    self.stream.get_mut().write_all(&buf).await
+  }
+
+  /// Reads the next frame. Not cancel-safe: a frame that's partly read when the future is dropped is lost, along with the connection's framing.
+  fn read_frame(&mut self) -> Pin<Box<dyn Future<Output = io::Result<Frame>> + Send + '_>> {
+    Box::pin(async move {
+      let line = self.read_line().await?;
+      let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} from Redis: {:?}", what, line));
+      // (The reply types are all ASCII, so the rest of a line that has one starts after its first byte.)
+      let rest = || &line[1..];
+      Ok(match line.as_bytes()[0] {
+        b'+' => Frame::Simple(rest().to_string()),
+        b'-' => Frame::Error(rest().to_string()),
+        b':' => { rest().parse::<i64>().map_err(|_| invalid("integer"))?; Frame::Integer }
+        b'$' => {
+          let len: i64 = rest().parse().map_err(|_| invalid("bulk length"))?;
+          if len < 0 { return Ok(Frame::Bulk(None)); }
+          if len as usize > MAX_BULK_LEN { return Err(invalid("bulk length (too large)")); }
+          let mut data = vec![0u8; len as usize + 2];
+          self.stream.read_exact(&mut data).await?;
+          data.truncate(len as usize);
+          Frame::Bulk(Some(data))
+        }
+        b'*' => {
+          let len: i64 = rest().parse().map_err(|_| invalid("array length"))?;
+          if len < 0 { return Ok(Frame::Array(None)); }
+          let mut items = Vec::with_capacity((len as usize).min(16));
+          for _ in 0..len { items.push(self.read_frame().await?); }
+          Frame::Array(Some(items))
+        }
+        _ => { return Err(invalid("reply type")); }
+      })
+    })
+  }
+
+  /// Reads a CRLF-terminated line, without the CRLF. Fails once the connection closes, and for a line that isn't UTF-8 or is longer than MAX_LINE.
+  async fn read_line(&mut self) -> io::Result<String> {
+    let mut line = vec![];
+    (&mut self.stream).take(MAX_LINE as u64).read_until(b'\n', &mut line).await?;
+    if line.last() != Some(&b'\n') {
+      return Err(match line.len() < MAX_LINE {
+        true => io::Error::new(io::ErrorKind::UnexpectedEof, "Redis closed the connection"),
+        false => io::Error::new(io::ErrorKind::InvalidData, format!("line from Redis longer than {} bytes", MAX_LINE)),
+      });
+    }
+    let line = String::from_utf8(line).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "line from Redis isn't UTF-8"))?;
+    let line = line.trim_end_matches(['\r', '\n']).to_string();
+    if line.is_empty() {
+      return Err(io::Error::new(io::ErrorKind::InvalidData, "empty line from Redis"));
+    }
+    Ok(line)
+  }
+}
+
+/// Appends a command, as a RESP array of bulk strings, to `buf`.
+fn encode_command(buf: &mut Vec<u8>, parts: &[&[u8]]) {
+  buf.extend_from_slice(format!("*{}\r\n", parts.len()).as_bytes());
+  for part in parts {
+    buf.extend_from_slice(format!("${}\r\n", part.len()).as_bytes());
+    buf.extend_from_slice(part);
+    buf.extend_from_slice(b"\r\n");
+  }
+}
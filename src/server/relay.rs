@@ -0,0 +1,214 @@
+// relay.rs
+//
+// Relaying between an upstream connection (client mode, see client.rs) and a server's clients, so quicksocket can fan a single-connection data source out to any number of clients: everything the upstream sends is broadcast to the server's clients, and optionally everything they send is forwarded upstream. Messages are relayed a batch at a time on a dedicated relay thread, through optional filters.
+
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}, thread::{self, JoinHandle}};
+use tokio::sync::watch;
+
+use super::{Client, Error, Message, Server, consumer_state::{self as cs, SharedReceiver}, error_events::Category, events::ClientMessage};
+
+/// Decides whether a message is relayed (true) or dropped (false). Called on the relay thread, one message at a time.
+pub type RelayFilter = Box<dyn FnMut(&ClientMessage) -> bool + Send>;
+
+/// What a relay forwards. The default broadcasts every upstream message to the clients, and forwards nothing back.
+#[derive(Default)]
+pub struct RelayConfig {
+  /// Also forward the clients' messages to the upstream. The relay then takes the server's client messages for itself (as a message callback does, so they can't be drained meanwhile); otherwise they're left to the server's consumer.
+  pub to_upstream: bool,
+  /// Filters the upstream's messages before they're broadcast. The messages' client_id is the upstream URL.
+  pub filter: Option<RelayFilter>,
+  /// Filters the clients' messages before they're forwarded upstream (with to_upstream).
+  pub upstream_filter: Option<RelayFilter>,
+}
+
+/// How many messages a relay has forwarded, from Relay::stats().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RelayStats {
+  /// Upstream messages broadcast to the clients.
+  pub to_clients: u64,
+  /// Client messages forwarded to the upstream.
+  pub to_upstream: u64,
+  /// Messages (in either direction) dropped by a filter.
+  pub filtered: u64,
+}
+
+#[derive(Default)]
+struct RelayCounters {
+  to_clients: AtomicU64,
+  to_upstream: AtomicU64,
+  filtered: AtomicU64,
+  running: AtomicBool,
+}
+
+/// A relay started with Relay::start(). It runs until stop() is called, the upstream connection closes, or the server stops; dropping the Relay doesn't stop it. Stopping leaves the connection and the server as they are, and hands the server's client messages back for draining.
+pub struct Relay {
+  stop_tx: watch::Sender<bool>,
+  thread: Mutex<Option<JoinHandle<()>>>,
+  counters: Arc<RelayCounters>,
+}
+
+impl Relay {
+  /// Starts relaying between `upstream` and `server`'s clients. While it runs, the relay has the upstream's messages to itself: draining `upstream` returns nothing. Fails with Error::NotRunning if either end has already closed, and with Error::ReceiverUnavailable (with to_upstream) if something else, e.g. a message callback, has the server's client messages.
+  pub fn start(upstream: &Client, server: &Server, config: RelayConfig) -> Result<Relay, Error> {
+    if !upstream.is_connected() || !server.is_running() {
+      return Err(Error::NotRunning);
+    }
+    let state_rx = server.state_rx();
+    let client_msg_rx = if config.to_upstream {
+      Some(server.cli_msg_rx.claim().ok_or(Error::ReceiverUnavailable)?)
+    } else { None };
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let counters = Arc::new(RelayCounters::default());
+    counters.running.store(true, Ordering::Relaxed);
+    let relaying = Relaying {
+      upstream: upstream.clone(),
+      server: server.clone(),
+      config,
+      counters: counters.clone(),
+    };
+    let thread_client_msg_rx = client_msg_rx.clone();
+    let thread = thread::Builder::new()
+      .name("quicksocket-relay".to_string())
+      .spawn(move || relaying.run(thread_client_msg_rx, state_rx, stop_rx));
+    if let Err(err) = thread {
+      if client_msg_rx.is_some() { server.cli_msg_rx.release(); }
+      return Err(Error::Internal(format!("Failed to spawn the relay thread: {:?}", err)));
+    }
+    log_info!("[relay] Relaying {} to the clients of port {}.", upstream.url(), server.port);
+    Ok(Relay { stop_tx, thread: Mutex::new(Some(thread.unwrap())), counters })
+  }
+
+  /// Whether the relay is still forwarding messages.
+  pub fn is_running(&self) -> bool {
+    self.counters.running.load(Ordering::Relaxed)
+  }
+
+  pub fn stats(&self) -> RelayStats {
+    RelayStats {
+      to_clients: self.counters.to_clients.load(Ordering::Relaxed),
+      to_upstream: self.counters.to_upstream.load(Ordering::Relaxed),
+      filtered: self.counters.filtered.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Stops relaying and waits for the relay thread to finish the batch it's on. Stopping a stopped relay does nothing. Mustn't be called from a filter.
+  pub fn stop(&self) {
+    self.stop_tx.send_replace(true);
+    let thread = self.thread.lock().ok().and_then(|mut thread| thread.take());
+    if let Some(thread) = thread {
+      if thread.join().is_err() {
+        cs::weakly_record_error_in(Category::Internal, "Relay thread panicked.".to_string());
+      }
+    }
+  }
+}
+
+/// Everything the relay thread works with.
+struct Relaying {
+  upstream: Client,
+  server: Server,
+  config: RelayConfig,
+  counters: Arc<RelayCounters>,
+}
+
+impl Relaying {
+  /// Relay thread body. Waits for messages from either end, and forwards each batch of immediately-available ones, until stopped or either end goes away.
+  fn run(mut self, client_msg_rx: Option<SharedReceiver<ClientMessage>>, mut state_rx: watch::Receiver<cs::RunState>, mut stop_rx: watch::Receiver<bool>) {
+    // The channels are runtime-agnostic, so a bare current-thread runtime (no IO/time drivers) is enough to wait on them.
+    let waiter = tokio::runtime::Builder::new_current_thread().build();
+    if let Err(err) = waiter {
+      cs::weakly_record_error_in(Category::Internal, format!("Relay thread failed to create its runtime: {:?}", err));
+      self.finish(client_msg_rx);
+      return;
+    }
+    let waiter = waiter.unwrap();
+
+    let upstream_rx = self.upstream.message_receiver().clone();
+    waiter.block_on(async {
+      // A drain that got hold of a receiver just before the relay started finishes within its own timeout.
+      let mut upstream_rx = tokio::select! {
+        rx = upstream_rx.lock() => rx,
+        _ = stop_rx.changed() => { return; }
+      };
+      let mut clients_rx = match &client_msg_rx {
+        Some(client_msg_rx) => Some(tokio::select! {
+          rx = client_msg_rx.lock() => rx,
+          _ = stop_rx.changed() => { return; }
+        }),
+        None => None,
+      };
+
+      loop { tokio::select! {
+        _ = stop_rx.changed() => { break; }
+
+        // (Also resolves if the server thread is gone.)
+        _ = state_rx.wait_for(|state| !state.is_alive()) => {
+          log_info!("[relay] The server stopped; relay from {} finished.", self.upstream.url());
+          break;
+        }
+
+        msg = upstream_rx.recv() => {
+          let first_msg = match msg {
+            Some(first_msg) => first_msg,
+            None => {
+              log_info!("[relay] The connection to {} closed; relay finished.", self.upstream.url());
+              break;
+            }
+          };
+          let mut batch = vec![first_msg];
+          while let Ok(msg) = upstream_rx.try_recv() { batch.push(msg); }
+          let messages = filter_batch(batch, self.config.filter.as_mut(), &self.counters);
+          if messages.is_empty() { continue; }
+          let message_count = messages.len() as u64;
+          // Broadcasting only fails once the server has stopped.
+          if self.server.send(messages).is_err() { break; }
+          self.counters.to_clients.fetch_add(message_count, Ordering::Relaxed);
+        }
+
+        msg = async { clients_rx.as_mut().unwrap().recv().await }, if clients_rx.is_some() => {
+          let first_msg = match msg {
+            Some(first_msg) => first_msg,
+            None => { break; }
+          };
+          let clients_rx = clients_rx.as_mut().unwrap();
+          let mut batch = vec![first_msg];
+          while let Ok(msg) = clients_rx.try_recv() { batch.push(msg); }
+          let messages = filter_batch(batch, self.config.upstream_filter.as_mut(), &self.counters);
+          if messages.is_empty() { continue; }
+          let message_count = messages.len() as u64;
+          // Waits for room in the upstream's send queue, so a slow upstream slows the clients' messages down rather than dropping them.
+          if self.upstream.send_queued(messages).await.is_err() {
+            log_info!("[relay] The connection to {} closed; relay finished.", self.upstream.url());
+            break;
+          }
+          self.counters.to_upstream.fetch_add(message_count, Ordering::Relaxed);
+        }
+      }}
+    });
+    self.finish(client_msg_rx);
+  }
+
+  /// Hands the server's client messages back for draining, and reports the relay stopped.
+  fn finish(&self, client_msg_rx: Option<SharedReceiver<ClientMessage>>) {
+    if client_msg_rx.is_some() { self.server.cli_msg_rx.release(); }
+    self.counters.running.store(false, Ordering::Relaxed);
+    log_debug!("[relay] Relay thread exiting.");
+  }
+}
+
+/// The batch's text and binary messages that pass `filter` (all of them, without one).
+fn filter_batch(batch: Vec<ClientMessage>, mut filter: Option<&mut RelayFilter>, counters: &RelayCounters) -> Vec<Message> {
+  let mut messages = Vec::with_capacity(batch.len());
+  for msg in batch {
+    if !msg.is_data() { continue; }
+    if let Some(filter) = filter.as_mut() {
+      if !filter(&msg) {
+        counters.filtered.fetch_add(1, Ordering::Relaxed);
+        continue;
+      }
+    }
+    messages.push(msg.message);
+  }
+  messages
+}
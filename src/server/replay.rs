@@ -0,0 +1,256 @@
+// replay.rs
+//
+// Replaying a session recording (see recording.rs) to a server's clients, so a frontend can be developed against a realistic stream of messages without running whatever produced it. What was broadcast in the recording is broadcast again, with the original gaps between messages (or scaled by a speed); messages sent to single clients, and messages from clients, are skipped, as those clients aren't there any more. Messages are replayed on a dedicated replay thread, like a relay's.
+
+use std::{path::{Path, PathBuf}, sync::{Arc, Mutex, PoisonError, atomic::{AtomicBool, AtomicU64, Ordering}}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use tokio::sync::watch;
+
+use super::{Error, Message, Server, consumer_state::{self as cs, RunState}, error_events::Category, recording::{Direction, RecordedMessage, RecordingReader}};
+
+/// How a recording is replayed. The default replays it once, in real time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayConfig {
+  /// How much faster than recorded the messages are sent: 2.0 halves the gaps between them, 0.5 doubles them. Infinity sends them all as fast as the server takes them.
+  pub speed: f64,
+  /// Start over from the beginning of the recording each time it ends, until stopped.
+  pub repeat: bool,
+}
+
+impl Default for ReplayConfig {
+  fn default() -> ReplayConfig {
+    ReplayConfig { speed: 1.0, repeat: false }
+  }
+}
+
+impl ReplayConfig {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.speed.is_nan() || self.speed <= 0.0 {
+      return Err(format!("the replay speed must be positive (it's {})", self.speed));
+    }
+    Ok(())
+  }
+}
+
+/// How far a replay has got, from Replay::stats().
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+  /// Recorded broadcasts sent to the clients.
+  pub sent: u64,
+  /// Recorded messages that weren't broadcasts (client messages, and sends to single clients), and so weren't replayed.
+  pub skipped: u64,
+  /// Times the recording was replayed to its end.
+  pub passes: u64,
+  /// Why the replay stopped early, if the recording couldn't be read.
+  pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct ReplayCounters {
+  sent: AtomicU64,
+  skipped: AtomicU64,
+  passes: AtomicU64,
+  running: AtomicBool,
+  error: Mutex<Option<String>>,
+}
+
+/// A replay started with Replay::start(). It runs until the recording ends (unless it's repeated), stop() is called, or the server stops; dropping the Replay doesn't stop it.
+pub struct Replay {
+  path: PathBuf,
+  stop_tx: watch::Sender<bool>,
+  thread: Mutex<Option<JoinHandle<()>>>,
+  counters: Arc<ReplayCounters>,
+}
+
+impl Replay {
+  /// Starts replaying the recording at `path` (in either format) to `server`'s clients. Fails with Error::InvalidConfig for a speed that isn't positive, Error::Replay if the file can't be opened, and Error::NotRunning if the server isn't running. A recording that turns out to be malformed stops the replay where it goes wrong, with a "recording" error event.
+  pub fn start(server: &Server, path: impl AsRef<Path>, config: ReplayConfig) -> Result<Replay, Error> {
+    config.validate().map_err(Error::InvalidConfig)?;
+    if !server.is_running() {
+      return Err(Error::NotRunning);
+    }
+    let path = path.as_ref().to_path_buf();
+    let reader = RecordingReader::open(&path).map_err(|err| Error::Replay(format!("failed to open {}: {}", path.display(), err)))?;
+    let state_rx = server.state_rx();
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let counters = Arc::new(ReplayCounters::default());
+    counters.running.store(true, Ordering::Relaxed);
+    let replaying = Replaying {
+      path: path.clone(),
+      reader,
+      server: server.clone(),
+      config,
+      counters: counters.clone(),
+    };
+    let thread = thread::Builder::new()
+      .name("quicksocket-replay".to_string())
+      .spawn(move || replaying.run(state_rx, stop_rx))
+      .map_err(|err| Error::Internal(format!("Failed to spawn the replay thread: {:?}", err)))?;
+    log_info!("[replay] Replaying {} to the clients of port {} at {}x.", path.display(), server.port, config.speed);
+    Ok(Replay { path, stop_tx, thread: Mutex::new(Some(thread)), counters })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Whether the replay is still sending messages.
+  pub fn is_running(&self) -> bool {
+    self.counters.running.load(Ordering::Relaxed)
+  }
+
+  pub fn stats(&self) -> ReplayStats {
+    ReplayStats {
+      sent: self.counters.sent.load(Ordering::Relaxed),
+      skipped: self.counters.skipped.load(Ordering::Relaxed),
+      passes: self.counters.passes.load(Ordering::Relaxed),
+      error: self.counters.error.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+    }
+  }
+
+  /// Stops replaying and waits for the replay thread to finish the batch it's on. Stopping a stopped replay does nothing.
+  pub fn stop(&self) {
+    self.stop_tx.send_replace(true);
+    let thread = self.thread.lock().ok().and_then(|mut thread| thread.take());
+    if let Some(thread) = thread {
+      if thread.join().is_err() {
+        cs::weakly_record_error_in(Category::Internal, "Replay thread panicked.".to_string());
+      }
+    }
+  }
+}
+
+/// How a pass through the recording ended.
+enum Ended {
+  /// The recording's end was reached.
+  Finished,
+  /// The recording has no broadcasts to replay (so repeating it would only spin).
+  Empty,
+  /// The replay was stopped, or the server stopped.
+  Stopped,
+}
+
+/// Everything the replay thread works with.
+struct Replaying {
+  path: PathBuf,
+  reader: RecordingReader,
+  server: Server,
+  config: ReplayConfig,
+  counters: Arc<ReplayCounters>,
+}
+
+impl Replaying {
+  /// Replay thread body. Replays the recording (over and over, with repeat) until it ends, it's stopped, or the server goes away.
+  fn run(mut self, mut state_rx: watch::Receiver<RunState>, mut stop_rx: watch::Receiver<bool>) {
+    // Only timers are needed: the channels are runtime-agnostic.
+    let waiter = tokio::runtime::Builder::new_current_thread().enable_time().build();
+    if let Err(err) = waiter {
+      cs::weakly_record_error_in(Category::Internal, format!("Replay thread failed to create its runtime: {:?}", err));
+      self.counters.running.store(false, Ordering::Relaxed);
+      return;
+    }
+    let waiter = waiter.unwrap();
+
+    waiter.block_on(async {
+      loop {
+        match self.replay_pass(&mut state_rx, &mut stop_rx).await {
+          Ok(Ended::Finished) => {
+            self.counters.passes.fetch_add(1, Ordering::Relaxed);
+            if !self.config.repeat {
+              log_info!("[replay] Finished replaying {}.", self.path.display());
+              break;
+            }
+            match RecordingReader::open(&self.path) {
+              Ok(reader) => { self.reader = reader; }
+              Err(err) => {
+                self.fail(format!("failed to reopen {}: {}", self.path.display(), err));
+                break;
+              }
+            }
+          }
+          Ok(Ended::Empty) => {
+            log_info!("[replay] {} has no broadcasts to replay.", self.path.display());
+            break;
+          }
+          Ok(Ended::Stopped) => { break; }
+          Err(err) => {
+            self.fail(format!("{}: {}", self.path.display(), err));
+            break;
+          }
+        }
+      }
+    });
+    self.counters.running.store(false, Ordering::Relaxed);
+    log_debug!("[replay] Replay thread exiting.");
+  }
+
+  /// Replays the recording from where the reader is to its end. Each run of broadcasts recorded at the same moment is sent as a batch, when as much time has passed since the pass started as had passed since the first broadcast in the recording (divided by the speed).
+  async fn replay_pass(&mut self, state_rx: &mut watch::Receiver<RunState>, stop_rx: &mut watch::Receiver<bool>) -> Result<Ended, String> {
+    let started = Instant::now();
+    let mut next = self.next_broadcast()?;
+    let origin = match &next {
+      Some(first) => first.timestamp,
+      None => { return Ok(Ended::Empty); }
+    };
+
+    while let Some(first) = next.take() {
+      let timestamp = first.timestamp;
+      let mut batch: Vec<Message> = vec![first.message];
+      // (A malformed record read ahead stops the replay once the batch before it has gone out.)
+      let mut read_error = None;
+      loop {
+        match self.next_broadcast() {
+          Ok(Some(msg)) if msg.timestamp == timestamp => batch.push(msg.message),
+          Ok(other) => { next = other; break; }
+          Err(err) => { read_error = Some(err); break; }
+        }
+      }
+
+      if *stop_rx.borrow() { return Ok(Ended::Stopped); }
+      if self.config.speed.is_finite() {
+        // (A timestamp before the first one, e.g. from the clock being set back while recording, is due at once.)
+        let offset = timestamp.duration_since(origin).unwrap_or_default();
+        let due = Duration::try_from_secs_f64(offset.as_secs_f64() / self.config.speed).ok().and_then(|delay| started.checked_add(delay));
+        tokio::select! {
+          _ = async { match due {
+            Some(due) => tokio::time::sleep_until(due.into()).await,
+            None => std::future::pending::<()>().await,
+          }} => {}
+          _ = stop_rx.changed() => { return Ok(Ended::Stopped); }
+          // (Also resolves if the server thread is gone.)
+          _ = state_rx.wait_for(|state| !state.is_alive()) => {
+            log_info!("[replay] The server stopped; replay of {} finished.", self.path.display());
+            return Ok(Ended::Stopped);
+          }
+        }
+      }
+
+      let message_count = batch.len() as u64;
+      // Broadcasting only fails once the server has stopped.
+      if self.server.send(batch).is_err() {
+        log_info!("[replay] The server stopped; replay of {} finished.", self.path.display());
+        return Ok(Ended::Stopped);
+      }
+      self.counters.sent.fetch_add(message_count, Ordering::Relaxed);
+      if let Some(err) = read_error { return Err(err); }
+    }
+    Ok(Ended::Finished)
+  }
+
+  /// The next broadcast in the recording, counting the other messages on the way as skipped.
+  fn next_broadcast(&mut self) -> Result<Option<RecordedMessage>, String> {
+    while let Some(msg) = self.reader.next_message()? {
+      if msg.direction == Direction::Out && msg.client_id.is_none() {
+        return Ok(Some(msg));
+      }
+      self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(None)
+  }
+
+  fn fail(&self, reason: String) {
+    log_warn!("[replay] Stopped replaying: {}", reason);
+    cs::weakly_record_error_in(Category::Recording, format!("Stopped replaying: {}", reason));
+    *self.counters.error.lock().unwrap_or_else(PoisonError::into_inner) = Some(reason);
+  }
+}
@@ -0,0 +1,246 @@
+// rosbridge.rs
+//
+// rosbridge compatibility (ServerConfig::rosbridge), so ROS web tooling (roslibjs, and what's built on it) can connect straight to a quicksocket server, as it would to a rosbridge server, and follow and publish its topics. It speaks the ops of the rosbridge v2 JSON protocol that takes:
+//
+//   {"op":"subscribe","topic":"/pose","type":"geometry_msgs/Pose","id":"subscribe:/pose:1"}
+//   {"op":"unsubscribe","topic":"/pose","id":"subscribe:/pose:1"}
+//   {"op":"advertise","topic":"/cmd_vel","type":"geometry_msgs/Twist"}
+//   {"op":"unadvertise","topic":"/cmd_vel"}
+//   {"op":"publish","topic":"/cmd_vel","msg":{"linear":{"x":1.0}}}
+//
+// A client's receiver task takes its subscribes and unsubscribes, keeping its subscriptions with the client: a client can subscribe to a topic more than once, under different ids (as roslibjs does for each Topic it subscribes with), and is subscribed until it's unsubscribed them all, or unsubscribed without an id. Advertises and unadvertises only say what the client's going to publish, so they're taken and that's all. Publishes are passed on to the consumer as they are, for it to route by their topic. The consumer publishes a message on a topic (Server::publish_ros()) as a publish op sent to each client subscribed to it, like a message sent to it alone. Subscriptions' throttle_rate, queue_length and compression are ignored: messages go out as they're published, as JSON.
+//
+// Anything else a rosbridge server does (calling services, getting parameters) isn't there: the client's answered with an error "status" op saying so, and a service call with a failed "service_response" too, so its callback isn't left waiting. A malformed op (a subscribe without a topic, say) is answered with a "status" op, and recorded as a "receive" warning event. Text that isn't a JSON object with an "op" is passed on as usual.
+
+use std::collections::{HashMap, HashSet};
+
+use super::inspector::json_string;
+
+/// A rosbridge op a client sent, as far as the server takes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+  Subscribe { topic: String, id: Option<String> },
+  Unsubscribe { topic: String, id: Option<String> },
+  Advertise { topic: String },
+  Unadvertise { topic: String },
+  /// Passed on to the consumer.
+  Publish { topic: String },
+  CallService { service: String, id: Option<String> },
+  /// Any other op, which the server doesn't do.
+  Other { op: String, id: Option<String> },
+}
+
+impl Op {
+  /// The op a message is, if it's a JSON object with an "op": Some(Err) for a malformed one.
+  pub fn parse(text: &str) -> Option<Result<Op, String>> {
+    if !text.trim_start().starts_with('{') { return None; }
+    let fields = Fields::parse(text)?;
+    let op = fields.string("op")?.ok()?;
+    let id = match fields.string("id") {
+      None => None,
+      Some(Ok(id)) => Some(id),
+      Some(Err(_)) => fields.raw("id").map(str::to_string),
+    };
+    let required = |name: &str| match fields.string(name) {
+      Some(Ok(value)) if !value.is_empty() => Ok(value),
+      _ => Err(format!("a {:?} op needs a {:?}, as a string", op, name)),
+    };
+    Some(match op.as_str() {
+      "subscribe" => required("topic").map(|topic| Op::Subscribe { topic, id }),
+      "unsubscribe" => required("topic").map(|topic| Op::Unsubscribe { topic, id }),
+      "advertise" => required("topic").map(|topic| Op::Advertise { topic }),
+      "unadvertise" => required("topic").map(|topic| Op::Unadvertise { topic }),
+      "publish" => match fields.raw("msg") {
+        Some(msg) if msg.starts_with('{') => required("topic").map(|topic| Op::Publish { topic }),
+        _ => Err("a \"publish\" op needs a \"msg\", as an object".to_string()),
+      },
+      "call_service" => required("service").map(|service| Op::CallService { service, id }),
+      _ => Ok(Op::Other { op: op.clone(), id }),
+    })
+  }
+
+  /// What the client's answered with, if anything: for ops the server doesn't do.
+  pub fn reply(&self) -> Option<String> {
+    match self {
+      Op::CallService { service, id } => Some(format!(
+        "{{\"op\":\"service_response\",\"service\":{}{},\"result\":false,\"values\":{}}}",
+        json_string(service), id_field(id), json_string(&format!("quicksocket doesn't call services ({})", service))
+      )),
+      Op::Other { op, id } => Some(status(&format!("quicksocket doesn't do the {:?} op", op), id)),
+      _ => None,
+    }
+  }
+}
+
+/// An error "status" op, for the client's log.
+pub fn status(msg: &str, id: &Option<String>) -> String {
+  format!("{{\"op\":\"status\",\"level\":\"error\",\"msg\":{}{}}}", json_string(msg), id_field(id))
+}
+
+/// A publish op, for clients subscribed to `topic`; `msg` is the message, as a JSON object.
+pub fn publish(topic: &str, msg: &str) -> String {
+  format!("{{\"op\":\"publish\",\"topic\":{},\"msg\":{}}}", json_string(topic), msg.trim())
+}
+
+fn id_field(id: &Option<String>) -> String {
+  id.as_deref().map_or_else(String::new, |id| format!(",\"id\":{}", json_string(id)))
+}
+
+/// A client's subscriptions: the ids it's subscribed to each topic under.
+#[derive(Debug, Default)]
+pub struct Subscriptions(HashMap<String, HashSet<String>>);
+
+impl Subscriptions {
+  pub fn subscribe(&mut self, topic: &str, id: Option<&str>) {
+    self.0.entry(topic.to_string()).or_default().insert(id.unwrap_or_default().to_string());
+  }
+
+  /// Takes the subscription under `id`, or without one, all of the topic's.
+  pub fn unsubscribe(&mut self, topic: &str, id: Option<&str>) {
+    let Some(ids) = self.0.get_mut(topic) else { return; };
+    match id {
+      Some(id) => { ids.remove(id); }
+      None => { ids.clear(); }
+    }
+    if ids.is_empty() { self.0.remove(topic); }
+  }
+
+  pub fn contains(&self, topic: &str) -> bool {
+    self.0.contains_key(topic)
+  }
+
+  pub fn topics(&self) -> impl Iterator<Item = &str> {
+    self.0.keys().map(String::as_str)
+  }
+}
+
+/// A JSON object's fields, with their values as they're written. Just enough of a JSON reader for ops.
+struct Fields<'a>(Vec<(String, &'a str)>);
+
+impl<'a> Fields<'a> {
+  /// The fields of an object; None if the text isn't one.
+  fn parse(text: &'a str) -> Option<Fields<'a>> {
+    let mut json = Json { text, at: 0 };
+    let mut fields = vec![];
+    json.expect(b'{')?;
+    if !json.eat(b'}') {
+      loop {
+        let key = json.string()?;
+        json.expect(b':')?;
+        fields.push((key, json.value()?));
+        if json.eat(b'}') { break; }
+        json.expect(b',')?;
+      }
+    }
+    json.peek().is_none().then_some(Fields(fields))
+  }
+
+  fn raw(&self, name: &str) -> Option<&'a str> {
+    self.0.iter().find(|(key, _)| key == name).map(|(_, value)| *value)
+  }
+
+  /// A field's value, if it's there: Err if it isn't a string.
+  fn string(&self, name: &str) -> Option<Result<String, ()>> {
+    let value = self.raw(name)?;
+    Some(Json { text: value, at: 0 }.string().ok_or(()))
+  }
+}
+
+struct Json<'a> {
+  text: &'a str,
+  at: usize,
+}
+
+impl<'a> Json<'a> {
+  /// The next byte that isn't whitespace, skipping to it.
+  fn peek(&mut self) -> Option<u8> {
+    let rest = &self.text.as_bytes()[self.at..];
+    self.at += rest.iter().take_while(|byte| byte.is_ascii_whitespace()).count();
+    self.text.as_bytes().get(self.at).copied()
+  }
+
+  fn eat(&mut self, byte: u8) -> bool {
+    let next = self.peek() == Some(byte);
+    if next { self.at += 1; }
+    next
+  }
+
+  fn expect(&mut self, byte: u8) -> Option<()> {
+    self.eat(byte).then_some(())
+  }
+
+  /// A value of any kind, skipped, as it's written.
+  fn value(&mut self) -> Option<&'a str> {
+    let next = self.peek()?;
+    let start = self.at;
+    match next {
+      b'"' => { self.string()?; }
+      b'{' | b'[' => {
+        let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+        for (offset, byte) in self.text.as_bytes()[start..].iter().enumerate() {
+          match (in_string, escaped, byte) {
+            (true, true, _) => { escaped = false; }
+            (true, false, b'\\') => { escaped = true; }
+            (true, false, b'"') | (false, _, b'"') => { in_string = !in_string; }
+            (false, _, b'{' | b'[') => { depth += 1; }
+            (false, _, b'}' | b']') => {
+              depth -= 1;
+              if depth == 0 {
+                self.at = start + offset + 1;
+                return Some(&self.text[start..self.at]);
+              }
+            }
+            _ => {}
+          }
+        }
+        return None;
+      }
+      _ => {
+        let len = self.text.as_bytes()[start..].iter().take_while(|byte| !matches!(byte, b',' | b'}' | b']') && !byte.is_ascii_whitespace()).count();
+        if len == 0 { return None; }
+        self.at = start + len;
+      }
+    }
+    Some(&self.text[start..self.at])
+  }
+
+  /// A string, unescaped.
+  fn string(&mut self) -> Option<String> {
+    if self.peek()? != b'"' { return None; }
+    let mut chars = self.text[self.at + 1..].char_indices();
+    let mut out = String::new();
+    while let Some((offset, c)) = chars.next() {
+      match c {
+        '"' => {
+          self.at += offset + 2;
+          return Some(out);
+        }
+        '\\' => match chars.next()?.1 {
+          'b' => out.push('\u{8}'),
+          'f' => out.push('\u{c}'),
+          'n' => out.push('\n'),
+          'r' => out.push('\r'),
+          't' => out.push('\t'),
+          'u' => {
+            let mut c = hex4(&mut chars)?;
+            // (A UTF-16 surrogate pair: the second half follows as another escape.)
+            if (0xd800..0xdc00).contains(&c) && chars.as_str().starts_with("\\u") {
+              chars.nth(1);
+              c = 0x10000 + ((c - 0xd800) << 10) + (hex4(&mut chars)?.wrapping_sub(0xdc00) & 0x3ff);
+            }
+            out.push(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+          }
+          escaped => out.push(escaped),
+        },
+        _ => out.push(c),
+      }
+    }
+    None
+  }
+}
+
+/// The four hex digits of a \u escape.
+fn hex4(chars: &mut std::str::CharIndices) -> Option<u32> {
+  let hex = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<String>>()?;
+  u32::from_str_radix(&hex, 16).ok()
+}
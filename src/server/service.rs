@@ -0,0 +1,127 @@
+// service.rs
+//
+// The tower Service (behind the "tower" feature) that lets another HTTP server, e.g. an axum application, hand its websocket upgrade requests to a quicksocket server started with Transport::Service. The service answers the handshake itself, in the application's runtime, subscribing the client to the broadcasts before it does; once hyper has upgraded the connection, it's queued for the server's tokio thread, which runs it through the same client tasks (and events, stats and targeted sends) as a connection to its own port. Routing (the inspector, proxy routes, the landing page) is the application's job, so none of it applies.
+
+use std::{convert::Infallible, future::{self, Ready}, sync::{Arc, atomic::{AtomicU64, Ordering}}, task::{Context, Poll}};
+use hyper::{Body, Request, Response, header::{self, HeaderValue}, upgrade::Upgraded};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::Instrument;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+use super::{clients::BroadcastReceiver, error_events::{Category, Severity}, http, metrics::MetricsLog, stats::ServerStats};
+
+/// A connection upgraded by the service, waiting for the server's tokio thread to accept it.
+pub struct PendingUpgrade {
+  pub upgraded: Upgraded,
+  pub client_id: String,
+  /// Its subscription to the broadcasts, with the metrics' history (see MetricsLog::subscribe()), from before it was sent the handshake response.
+  pub subscription: (BroadcastReceiver, Option<Message>),
+}
+
+/// The consumer's side of a service listener: mints WebSocketServices that queue their upgraded connections for it.
+pub struct ServiceConnector {
+  pending_tx: mpsc::UnboundedSender<PendingUpgrade>,
+  next_client: Arc<AtomicU64>,
+}
+
+impl ServiceConnector {
+  /// A connector, and the receiver its listener accepts from.
+  pub fn new() -> (ServiceConnector, mpsc::UnboundedReceiver<PendingUpgrade>) {
+    let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+    (ServiceConnector { pending_tx, next_client: Arc::new(AtomicU64::new(1)) }, pending_rx)
+  }
+
+  pub(crate) fn service(&self, stats: Arc<ServerStats>, metrics: Arc<MetricsLog>) -> WebSocketService {
+    WebSocketService { pending_tx: self.pending_tx.clone(), next_client: self.next_client.clone(), stats, metrics }
+  }
+}
+
+/// A tower Service that accepts websocket upgrade requests as clients of a quicksocket server (see Server::service()). Cheap to clone; every clone feeds the same server.
+///
+/// With axum, mount it on a route alongside the application's own, e.g. `Router::new().route_service("/ws", server.service()?)`. Clients are known by the peer address if the request carries one as a `std::net::SocketAddr` extension, and as "service-1", "service-2", ... otherwise.
+#[derive(Clone)]
+pub struct WebSocketService {
+  pending_tx: mpsc::UnboundedSender<PendingUpgrade>,
+  next_client: Arc<AtomicU64>,
+  stats: Arc<ServerStats>,
+  metrics: Arc<MetricsLog>,
+}
+
+impl<B> tower_service::Service<Request<B>> for WebSocketService {
+  type Response = Response<Body>;
+  type Error = Infallible;
+  type Future = Ready<Result<Response<Body>, Infallible>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, req: Request<B>) -> Self::Future {
+    future::ready(Ok(self.upgrade(req)))
+  }
+}
+
+impl WebSocketService {
+  /// Answers a request: with the handshake response if it's a valid websocket upgrade, or with the same error responses a server's own port would give.
+  fn upgrade<B>(&self, mut req: Request<B>) -> Response<Body> {
+    let client_id = match req.extensions().get::<std::net::SocketAddr>() {
+      Some(addr) => addr.to_string(),
+      None => format!("service-{}", self.next_client.fetch_add(1, Ordering::Relaxed)),
+    };
+    let head = http::RequestHead::from_request(&req);
+    if !head.is_upgrade {
+      return to_hyper(http::Response::landing(None));
+    }
+    if let Err(response) = head.validate_upgrade() {
+      log_warn!("[service] Rejecting malformed websocket upgrade from {} ({}).", client_id, response.status);
+      self.stats.record_error(Severity::Warning, Category::Handshake, format!("Rejected malformed websocket upgrade ({} {}).", response.status, response.reason), Some(client_id));
+      return to_hyper(response);
+    }
+    if self.pending_tx.is_closed() {
+      return to_hyper(http::Response::text(503, "Service Unavailable", "The quicksocket server isn't accepting connections.\n"));
+    }
+    // (validate_upgrade() checked for the key.)
+    let accept_key = derive_accept_key(head.ws_key.as_deref().unwrap_or("").as_bytes());
+
+    // Subscribed before the response, so the client gets every message sent once it's connected, though the server only takes it from the service later.
+    let subscription = self.metrics.subscribe();
+    // The upgrade completes once hyper has sent the response, so it's waited for on a task of the application's runtime, which also drives the connection's socket.
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    let pending_tx = self.pending_tx.clone();
+    let stats = self.stats.clone();
+    let span = tracing::debug_span!("handshake", client = %client_id);
+    tokio::spawn(async move {
+      match on_upgrade.await {
+        Ok(upgraded) => {
+          if pending_tx.send(PendingUpgrade { upgraded, client_id, subscription }).is_err() {
+            log_debug!("[service] Dropped an upgraded connection: the server has stopped.");
+          }
+        }
+        Err(err) => {
+          log_warn!("[service] Failed to upgrade the connection from {}: {}", client_id, err);
+          stats.record_error(Severity::Warning, Category::Handshake, format!("Websocket upgrade failed: {}", err), Some(client_id));
+        }
+      }
+    }.instrument(span));
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = hyper::StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::SEC_WEBSOCKET_ACCEPT, HeaderValue::from_str(&accept_key).expect("accept keys are base64"));
+    response
+  }
+}
+
+fn to_hyper(response: http::Response) -> Response<Body> {
+  let mut builder = Response::builder()
+    .status(response.status)
+    .header(header::CONTENT_TYPE, response.content_type)
+    .header(header::CACHE_CONTROL, "no-store");
+  for (name, value) in response.headers {
+    builder = builder.header(name, value);
+  }
+  builder.body(Body::from(response.body)).expect("quicksocket's responses are valid")
+}
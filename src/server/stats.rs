@@ -0,0 +1,418 @@
+// stats.rs
+//
+// Aggregate server statistics, counted by the tokio tasks and read by the consumer (see get_server_stats()). One ServerStats is created per server start and shared via an Arc.
+//
+// Messages lost on the way are counted by why they were lost (see DropReason), as well as in all, and each loss is recorded as a warning event too (by whatever lost them), so it isn't invisible to the producer.
+//
+// Besides counts, there are cumulative timings of the outbound path (frame encoding, channel wait, socket writes), for finding out where sending time goes: compare two snapshots taken a while apart. With ServerConfig::latency_histograms, the outbound stages' latencies are kept as histograms too (see latency.rs).
+
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}};
+
+use tokio::sync::{broadcast, watch};
+
+use super::{budget::MemoryBudget, error_events::{self, Category, ErrorEvent, Severity}, latency::{LatencyHistograms, LatencySnapshot}, rate_limit::{RateLimit, TokenBucket}};
+
+/// How many of this server's error events can be waiting for a slow subscriber (see subscribe_errors()) before it starts missing them.
+const ERROR_SUBSCRIBER_QUEUE_LEN: usize = 64;
+
+pub struct ServerStats {
+  started_at: Instant,
+  /// Set once the server thread has finished shutting down, so uptime stops counting.
+  stopped_at: Mutex<Option<Instant>>,
+  total_connections: AtomicU64,
+  /// Current client count. Kept in a watch channel, rather than an atomic like the rest, so the consumer can wait for it to change (see wait_for_client()).
+  current_clients: watch::Sender<u64>,
+  /// Accepted connections not yet closed (see OpenSocket).
+  open_sockets: AtomicU64,
+  messages_sent: AtomicU64,
+  bytes_sent: AtomicU64,
+  messages_received: AtomicU64,
+  bytes_received: AtomicU64,
+  messages_dropped: AtomicU64,
+  /// messages_dropped, by DropReason.
+  dropped_by_reason: [AtomicU64; DropReason::COUNT],
+  /// Close frames written and flushed to clients while shutting down.
+  close_frames_sent: AtomicU64,
+  /// Close frames received from clients while shutting down (normally their answers to the server's).
+  close_acks_received: AtomicU64,
+  keepalive_timeouts: AtomicU64,
+  idle_timeouts: AtomicU64,
+  frames_skipped: AtomicU64,
+  keyframes_resent: AtomicU64,
+  samples_decimated: AtomicU64,
+  synced_late: AtomicU64,
+  serialization_ns: AtomicU64,
+  channel_wait_ns: AtomicU64,
+  socket_write_ns: AtomicU64,
+  socket_writes: AtomicU64,
+  broadcast_wait_ns: AtomicU64,
+  /// Only if the server was configured to keep them.
+  latency: Option<LatencyHistograms>,
+  /// The bytes in the server's queues (see budget.rs).
+  memory: Arc<MemoryBudget>,
+  /// The outbound rate limit's bucket, if there is one (see rate_limit.rs).
+  rate_limit: Option<TokenBucket>,
+  rate_limit_wait_ns: AtomicU64,
+  warnings: AtomicU64,
+  errors: AtomicU64,
+  /// This server's error events, for Rust handlers (see handler.rs). Separate from the process-wide queue in error_events.rs, which mixes every server's errors together.
+  error_tx: broadcast::Sender<ErrorEvent>,
+}
+
+/// An accepted connection's socket, counted as open until this is dropped, by whatever holds the socket last (for a client, the later of its sender and receiver tasks to finish).
+pub struct OpenSocket(Arc<ServerStats>);
+
+impl Drop for OpenSocket {
+  fn drop(&mut self) {
+    self.0.open_sockets.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Why messages were lost on the way (see ServerStats::messages_dropped()).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+  /// Broadcasts missed by clients that fell too far behind (see clients.rs).
+  Lagged,
+  /// Messages received from clients, or relayed from other cluster nodes, that would have taken the queues over the memory budget (see budget.rs).
+  OverBudget,
+  /// Messages sent to a single client that disconnected before they were written.
+  Disconnected,
+  /// Messages sent as the server shut down, or after it had, that it never wrote; and client messages received after the consumer's queue had closed.
+  Shutdown,
+}
+
+impl DropReason {
+  const COUNT: usize = 4;
+
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DropReason::Lagged       => "lagged",
+      DropReason::OverBudget   => "over_budget",
+      DropReason::Disconnected => "disconnected",
+      DropReason::Shutdown     => "shutdown",
+    }
+  }
+}
+
+/// The messages dropped for each DropReason, as in a StatsSnapshot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DroppedMessages {
+  pub lagged: u64,
+  pub over_budget: u64,
+  pub disconnected: u64,
+  pub shutdown: u64,
+}
+
+impl DroppedMessages {
+  /// Each reason's name (DropReason::as_str()) and count.
+  pub fn by_reason(&self) -> [(&'static str, u64); DropReason::COUNT] {
+    [
+      (DropReason::Lagged.as_str(), self.lagged),
+      (DropReason::OverBudget.as_str(), self.over_budget),
+      (DropReason::Disconnected.as_str(), self.disconnected),
+      (DropReason::Shutdown.as_str(), self.shutdown),
+    ]
+  }
+}
+
+/// A point-in-time copy of the counters.
+#[derive(Clone, Debug)]
+pub struct StatsSnapshot {
+  pub uptime: Duration,
+  /// Websocket clients accepted since the server started.
+  pub total_connections: u64,
+  pub current_clients: u64,
+  /// Messages (and their payload bytes) written to clients. A broadcast to N clients counts N times.
+  pub messages_sent: u64,
+  pub bytes_sent: u64,
+  /// Text and binary messages (and their payload bytes) received from clients.
+  pub messages_received: u64,
+  pub bytes_received: u64,
+  /// Messages lost on the way: server messages skipped by clients that fell behind or never written to them, messages sent after the server stopped, and client messages that couldn't be buffered; and the same, by why they were lost.
+  pub messages_dropped: u64,
+  pub dropped: DroppedMessages,
+  /// Error events recorded by the server's tasks since it started, by severity (critical ones counting as errors).
+  pub warnings: u64,
+  pub errors: u64,
+  /// Time the clients' sender tasks have spent encoding frames (their headers, and the small payloads copied in after them).
+  pub serialization: Duration,
+  /// Time batches have waited, from being sent to being picked up by a client's sender task. Summed over clients, like messages_sent, so broadcasts to N clients count N times.
+  pub channel_wait: Duration,
+  /// Time the clients' sender tasks have spent writing to and flushing their sockets (including waiting for room in the clients' socket buffers).
+  pub socket_write: Duration,
+  /// Writes (each with its flush) of messages to clients' sockets. messages_sent / socket_writes is how many messages a write took on average, which grows as writes are batched under load (see batching.rs).
+  pub socket_writes: u64,
+  /// Time broadcasts have waited for the slowest client to make room, with LagPolicy::Block (see clients.rs).
+  pub broadcast_wait: Duration,
+  /// Payload bytes in the server's queues right now (broadcasts and targeted sends not yet written, received messages not yet drained), and the most there have been at once.
+  pub queued_bytes: u64,
+  pub peak_queued_bytes: u64,
+  /// Messages turned away because they'd have taken the queues over ServerConfig::memory_budget: sends refused, and received (or relayed) messages dropped, which messages_dropped counts too.
+  pub messages_over_budget: u64,
+  /// Time clients' writes have waited for the outbound rate limit (ServerConfig::rate_limit). Summed over clients, so writes held up at once count once each.
+  pub rate_limit_wait: Duration,
+  /// Clients disconnected for leaving too many pings unanswered (ServerConfig::keepalive).
+  pub keepalive_timeouts: u64,
+  /// Clients disconnected for being idle (ServerConfig::idle_timeout).
+  pub idle_timeouts: u64,
+  /// Frames clients skipped, as a newer frame of the same topic was sent before they'd been written them (see frames.rs). Not counted as dropped.
+  pub frames_skipped: u64,
+  /// Times a client was written a keyframe again, and the deltas since, to catch up on a keyframe stream it had missed part of (see keyframes.rs).
+  pub keyframes_resent: u64,
+  /// Samples on decimated topics that didn't go out on their own: dropped for a later one, or averaged with it (see decimation.rs). Not counted as dropped.
+  pub samples_decimated: u64,
+  /// Messages in sync groups dropped for being sent after one with a later pts had gone out (see sync_groups.rs). Not counted as dropped.
+  pub synced_late: u64,
+}
+
+impl Default for ServerStats {
+  fn default() -> ServerStats {
+    ServerStats::new(false, None, None)
+  }
+}
+
+impl ServerStats {
+  /// With `latency_histograms`, the outbound stages' latencies are kept as histograms too (see latency()). The queues may hold up to `memory_budget` bytes (see memory()), and clients be written at most `rate_limit` (see rate_limit()), if given.
+  pub fn new(latency_histograms: bool, memory_budget: Option<usize>, rate_limit: Option<RateLimit>) -> ServerStats {
+    ServerStats {
+      started_at: Instant::now(),
+      stopped_at: Mutex::new(None),
+      total_connections: AtomicU64::new(0),
+      current_clients: watch::channel(0).0,
+      open_sockets: AtomicU64::new(0),
+      messages_sent: AtomicU64::new(0),
+      bytes_sent: AtomicU64::new(0),
+      messages_received: AtomicU64::new(0),
+      bytes_received: AtomicU64::new(0),
+      messages_dropped: AtomicU64::new(0),
+      dropped_by_reason: Default::default(),
+      close_frames_sent: AtomicU64::new(0),
+      close_acks_received: AtomicU64::new(0),
+      keepalive_timeouts: AtomicU64::new(0),
+      idle_timeouts: AtomicU64::new(0),
+      frames_skipped: AtomicU64::new(0),
+      keyframes_resent: AtomicU64::new(0),
+      samples_decimated: AtomicU64::new(0),
+      synced_late: AtomicU64::new(0),
+      serialization_ns: AtomicU64::new(0),
+      channel_wait_ns: AtomicU64::new(0),
+      socket_write_ns: AtomicU64::new(0),
+      socket_writes: AtomicU64::new(0),
+      broadcast_wait_ns: AtomicU64::new(0),
+      latency: latency_histograms.then(LatencyHistograms::default),
+      memory: MemoryBudget::new(memory_budget),
+      rate_limit: rate_limit.map(TokenBucket::new),
+      rate_limit_wait_ns: AtomicU64::new(0),
+      warnings: AtomicU64::new(0),
+      errors: AtomicU64::new(0),
+      error_tx: broadcast::channel(ERROR_SUBSCRIBER_QUEUE_LEN).0,
+    }
+  }
+
+  pub fn server_stopped(&self) {
+    if let Ok(mut stopped_at) = self.stopped_at.lock() {
+      stopped_at.get_or_insert_with(Instant::now);
+    }
+  }
+
+  /// Counts a new client in, returning its number: how many connected before it.
+  pub fn client_connected(&self) -> u64 {
+    let number = self.total_connections.fetch_add(1, Ordering::Relaxed);
+    self.current_clients.send_modify(|clients| *clients += 1);
+    number
+  }
+
+  pub fn client_disconnected(&self) {
+    self.current_clients.send_modify(|clients| *clients = clients.saturating_sub(1));
+  }
+
+  /// Counts an accepted connection's socket as open until the returned OpenSocket is dropped.
+  pub fn socket_opened(self: &Arc<Self>) -> OpenSocket {
+    self.open_sockets.fetch_add(1, Ordering::Relaxed);
+    OpenSocket(self.clone())
+  }
+
+  /// Accepted connections not yet closed (see Server::diagnostics()).
+  pub fn open_sockets(&self) -> u64 {
+    self.open_sockets.load(Ordering::Relaxed)
+  }
+
+  /// A receiver for the current client count, which sees every change to it from now on.
+  pub fn subscribe_current_clients(&self) -> watch::Receiver<u64> {
+    self.current_clients.subscribe()
+  }
+
+  pub fn message_sent(&self, len: usize) {
+    self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+  }
+
+  pub fn message_received(&self, len: usize) {
+    self.messages_received.fetch_add(1, Ordering::Relaxed);
+    self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+  }
+
+  /// Counts messages lost on the way. (Whatever lost them records the warning event saying so.)
+  pub fn messages_dropped(&self, reason: DropReason, count: u64) {
+    self.messages_dropped.fetch_add(count, Ordering::Relaxed);
+    self.dropped_by_reason[reason as usize].fetch_add(count, Ordering::Relaxed);
+  }
+
+  pub fn serialized(&self, elapsed: Duration) {
+    self.serialization_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  /// A send call took `elapsed` to queue `messages` messages. Only kept in the latency histograms.
+  pub fn enqueued(&self, elapsed: Duration, messages: usize) {
+    if let Some(latency) = &self.latency { latency.enqueue.record(elapsed, messages); }
+  }
+
+  /// A batch of `messages` messages waited `elapsed` to be picked up by a client's sender task.
+  pub fn waited_in_channel(&self, elapsed: Duration, messages: usize) {
+    self.channel_wait_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    if let Some(latency) = &self.latency { latency.fan_out.record(elapsed, messages); }
+  }
+
+  pub fn wrote_to_socket(&self, elapsed: Duration) {
+    self.socket_write_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  /// A broadcast waited `elapsed` for room in the slowest client's queue.
+  pub fn waited_for_room(&self, elapsed: Duration) {
+    self.broadcast_wait_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  /// A client's sender task took `elapsed` to encode `messages` messages and write them to its socket, in one write. (The time is only kept in the latency histograms.)
+  pub fn messages_written(&self, elapsed: Duration, messages: usize) {
+    if messages > 0 { self.socket_writes.fetch_add(1, Ordering::Relaxed); }
+    if let Some(latency) = &self.latency { latency.socket_write.record(elapsed, messages); }
+  }
+
+  /// The latency histograms, if they're being kept.
+  pub fn latency(&self) -> Option<LatencySnapshot> {
+    self.latency.as_ref().map(LatencyHistograms::snapshot)
+  }
+
+  /// The server's memory budget, which whatever's queued is charged against.
+  pub fn memory(&self) -> &Arc<MemoryBudget> {
+    &self.memory
+  }
+
+  /// The bucket clients' writes take their bytes from, if there's an outbound rate limit.
+  pub fn rate_limit(&self) -> Option<&TokenBucket> {
+    self.rate_limit.as_ref()
+  }
+
+  /// A client's write waited `elapsed` for the outbound rate limit.
+  pub fn waited_for_rate_limit(&self, elapsed: Duration) {
+    self.rate_limit_wait_ns.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+  }
+
+  pub fn close_frame_sent(&self) {
+    self.close_frames_sent.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Close frames flushed to clients so far; only ever nonzero once the server is shutting down.
+  pub fn close_frames_sent(&self) -> u64 {
+    self.close_frames_sent.load(Ordering::Relaxed)
+  }
+
+  pub fn close_ack_received(&self) {
+    self.close_acks_received.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Clients that have answered the shutdown's close frame so far.
+  pub fn close_acks_received(&self) -> u64 {
+    self.close_acks_received.load(Ordering::Relaxed)
+  }
+
+  /// A client was taken for dead for not answering pings.
+  pub fn keepalive_timed_out(&self) {
+    self.keepalive_timeouts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// A client was disconnected for being idle.
+  pub fn idle_timed_out(&self) {
+    self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Clients skipped frames, replaced by newer ones before they were written.
+  pub fn frames_skipped(&self, frames: u64) {
+    self.frames_skipped.fetch_add(frames, Ordering::Relaxed);
+  }
+
+  /// Clients were caught up on keyframe streams.
+  pub fn keyframes_resent(&self, catch_ups: u64) {
+    self.keyframes_resent.fetch_add(catch_ups, Ordering::Relaxed);
+  }
+
+  /// Samples on a decimated topic went out as one.
+  pub fn samples_decimated(&self, samples: u64) {
+    self.samples_decimated.fetch_add(samples, Ordering::Relaxed);
+  }
+
+  /// Messages in a sync group came too late to go out in order.
+  pub fn synced_late(&self, messages: u64) {
+    self.synced_late.fetch_add(messages, Ordering::Relaxed);
+  }
+
+  pub fn current_clients(&self) -> u64 {
+    *self.current_clients.borrow()
+  }
+
+  /// Records an error event (see error_events::record()) and counts it against this server.
+  pub fn record_error(&self, severity: Severity, category: Category, message: String, client_id: Option<String>) {
+    self.record_error_event(ErrorEvent::new(severity, category, message, client_id))
+  }
+
+  pub fn record_error_event(&self, event: ErrorEvent) {
+    match event.severity {
+      Severity::Warning => self.warnings.fetch_add(1, Ordering::Relaxed),
+      Severity::Error | Severity::Critical => self.errors.fetch_add(1, Ordering::Relaxed),
+    };
+    if self.error_tx.receiver_count() > 0 { let _ = self.error_tx.send(event.clone()); }
+    error_events::record_event(event);
+  }
+
+  /// Receives this server's error events from now on (as they're recorded with record_error()).
+  pub fn subscribe_errors(&self) -> broadcast::Receiver<ErrorEvent> {
+    self.error_tx.subscribe()
+  }
+
+  pub fn snapshot(&self) -> StatsSnapshot {
+    let stopped_at = self.stopped_at.lock().ok().and_then(|stopped_at| *stopped_at);
+    StatsSnapshot {
+      uptime: stopped_at.unwrap_or_else(Instant::now).duration_since(self.started_at),
+      total_connections: self.total_connections.load(Ordering::Relaxed),
+      current_clients: self.current_clients(),
+      messages_sent: self.messages_sent.load(Ordering::Relaxed),
+      bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+      messages_received: self.messages_received.load(Ordering::Relaxed),
+      bytes_received: self.bytes_received.load(Ordering::Relaxed),
+      messages_dropped: self.messages_dropped.load(Ordering::Relaxed),
+      dropped: DroppedMessages {
+        lagged: self.dropped_by_reason[DropReason::Lagged as usize].load(Ordering::Relaxed),
+        over_budget: self.dropped_by_reason[DropReason::OverBudget as usize].load(Ordering::Relaxed),
+        disconnected: self.dropped_by_reason[DropReason::Disconnected as usize].load(Ordering::Relaxed),
+        shutdown: self.dropped_by_reason[DropReason::Shutdown as usize].load(Ordering::Relaxed),
+      },
+      warnings: self.warnings.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      serialization: Duration::from_nanos(self.serialization_ns.load(Ordering::Relaxed)),
+      channel_wait: Duration::from_nanos(self.channel_wait_ns.load(Ordering::Relaxed)),
+      socket_write: Duration::from_nanos(self.socket_write_ns.load(Ordering::Relaxed)),
+      socket_writes: self.socket_writes.load(Ordering::Relaxed),
+      broadcast_wait: Duration::from_nanos(self.broadcast_wait_ns.load(Ordering::Relaxed)),
+      queued_bytes: self.memory.resident() as u64,
+      peak_queued_bytes: self.memory.peak() as u64,
+      messages_over_budget: self.memory.refused(),
+      rate_limit_wait: Duration::from_nanos(self.rate_limit_wait_ns.load(Ordering::Relaxed)),
+      keepalive_timeouts: self.keepalive_timeouts.load(Ordering::Relaxed),
+      idle_timeouts: self.idle_timeouts.load(Ordering::Relaxed),
+      frames_skipped: self.frames_skipped.load(Ordering::Relaxed),
+      keyframes_resent: self.keyframes_resent.load(Ordering::Relaxed),
+      samples_decimated: self.samples_decimated.load(Ordering::Relaxed),
+      synced_late: self.synced_late.load(Ordering::Relaxed),
+    }
+  }
+}
@@ -0,0 +1,208 @@
+// sync_groups.rs
+//
+// Synchronization groups (ServerConfig::sync_groups), for streams a client plays back together: a robot's camera frames, its pose and its IMU samples, say, which a browser should show as of the same moment. Messages sent under a group's topics (Server::send_synced()) are tagged with a presentation timestamp (pts), the time they're to be shown at, which the consumer gives, or the server stamps with its monotonic clock (see clock.rs), and with their group and topic; and each group's are broadcast in pts order, so that every client gets them in that order (bar those it loses by falling behind).
+//
+// A text message that's a JSON object has the tags spliced in as its first fields, e.g. {"sync_group":"robot","sync_topic":"pose","pts_us":1234567,"x":1}; any other text is wrapped, as a JSON string under "data". A binary message is prefixed with the pts, as a big-endian u64, then its topic's length in bytes, as a big-endian u16, then the topic.
+//
+// The streams of a group are seldom sent in pts order, each being sent as it's ready (a camera frame takes longer to encode than a pose), so each message is held for the group's window (ServerConfig::sync_window) first, to let those with earlier pts that are sent after it catch up, and the group's held messages go out in pts order as their windows are up. A message that's sent after one with a later pts has gone out, having missed the window, is dropped, rather than going out of order, and counted in the stats (synced_late).
+//
+// What goes out is broadcast like a heartbeat (see heartbeat.rs): queued behind the broadcasts before it, but never waiting for room, whatever the lag policy, and only to this server's clients, not a cluster's other nodes. A message that would take the server over its memory budget is dropped.
+
+use std::{cmp::{Ordering, Reverse}, collections::{BinaryHeap, HashMap}, sync::{Arc, Mutex, PoisonError}, time::{Duration, Instant}};
+use tokio::sync::{Notify, watch};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::{buffer_pool::OUTBOUND, clients::BroadcastQueue, inspector::json_string, outbound::Outbound, stats::{DropReason, ServerStats}};
+
+/// Checks that no topic's in more than one group.
+pub fn validate(groups: &HashMap<String, Vec<String>>) -> Result<(), String> {
+  let mut seen: HashMap<&str, &str> = HashMap::new();
+  for (group, topics) in groups {
+    for topic in topics {
+      if let Some(other) = seen.insert(topic, group) {
+        if other != group { return Err(format!("topic {:?} is in two sync groups, {:?} and {:?}", topic, other, group)); }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// A message held until its window's up.
+struct Held {
+  pts_us: u64,
+  /// The order it was sent in, to keep those with the same pts in it.
+  seq: u64,
+  until: Instant,
+  message: Outbound,
+}
+
+impl PartialEq for Held {
+  fn eq(&self, other: &Held) -> bool {
+    (self.pts_us, self.seq) == (other.pts_us, other.seq)
+  }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+  fn partial_cmp(&self, other: &Held) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Held {
+  fn cmp(&self, other: &Held) -> Ordering {
+    (self.pts_us, self.seq).cmp(&(other.pts_us, other.seq))
+  }
+}
+
+#[derive(Default)]
+struct Group {
+  /// Earliest pts first.
+  held: BinaryHeap<Reverse<Held>>,
+  /// The pts of the last message that went out.
+  released_us: Option<u64>,
+  next_seq: u64,
+}
+
+impl Group {
+  fn due(&self) -> Option<Instant> {
+    self.held.peek().map(|Reverse(held)| held.until)
+  }
+}
+
+/// The sync groups' held messages, shared by the consumer, which offers them, and the server's task that sends them as their windows are up.
+pub struct Sequencer {
+  /// Each topic's group.
+  topics: HashMap<String, Arc<str>>,
+  groups: Mutex<HashMap<Arc<str>, Group>>,
+  window: Duration,
+  /// Wakes the task when a message is held, which may be due before the one it's waiting for.
+  held: Notify,
+  stats: Arc<ServerStats>,
+  ser_msg_tx: Arc<BroadcastQueue>,
+}
+
+impl Sequencer {
+  pub fn new(groups: &HashMap<String, Vec<String>>, window: Duration, stats: Arc<ServerStats>, ser_msg_tx: Arc<BroadcastQueue>) -> Sequencer {
+    let names: Vec<Arc<str>> = groups.keys().map(|group| Arc::from(group.as_str())).collect();
+    let topics = names.iter().flat_map(|group| groups[&**group].iter().map(move |topic| (topic.clone(), group.clone()))).collect();
+    let groups = names.into_iter().map(|group| (group, Group::default())).collect();
+    Sequencer { topics, groups: Mutex::new(groups), window, held: Notify::new(), stats, ser_msg_tx }
+  }
+
+  /// The group `topic` is in, if any.
+  pub fn group(&self, topic: &str) -> Option<&str> {
+    self.topics.get(topic).map(|group| &**group)
+  }
+
+  /// Tags a message on a grouped topic, and holds it for the window (sending it straight away, with no window), or drops it if it's late.
+  pub fn offer(&self, topic: &str, message: Outbound, pts_us: u64) {
+    let Some(name) = self.topics.get(topic) else { return; };
+    let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(group) = groups.get_mut(name) else { return; };
+    if group.released_us.is_some_and(|released_us| pts_us < released_us) {
+      log_debug!("[sync] Dropped a message on {:?} for {:?}: its pts ({} us) is before the last sent's", topic, name, pts_us);
+      self.stats.synced_late(1);
+      OUTBOUND.recycle_messages(vec![message]);
+      return;
+    }
+    let message = tag(message, name, topic, pts_us);
+    let seq = group.next_seq;
+    group.next_seq += 1;
+    let was_due = group.due();
+    group.held.push(Reverse(Held { pts_us, seq, until: Instant::now() + self.window, message }));
+    if self.window.is_zero() {
+      self.send_due_in(group, Instant::now());
+    } else if was_due.is_none() {
+      self.held.notify_one();
+    }
+  }
+
+  /// When the next held message is due, if any is held.
+  fn next_due(&self) -> Option<Instant> {
+    self.groups.lock().unwrap_or_else(PoisonError::into_inner).values().filter_map(Group::due).min()
+  }
+
+  /// Sends the held messages that are due, in pts order.
+  fn send_due(&self) {
+    let now = Instant::now();
+    let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+    for group in groups.values_mut() { self.send_due_in(group, now); }
+  }
+
+  fn send_due_in(&self, group: &mut Group, now: Instant) {
+    // (The earliest pts goes first, so one that's due waits for an earlier one that isn't yet.)
+    while group.due().is_some_and(|due| due <= now) {
+      let Some(Reverse(held)) = group.held.pop() else { break; };
+      group.released_us = Some(held.pts_us);
+      self.broadcast(held.message);
+    }
+  }
+
+  /// Forgets the held messages, returning them to the pool.
+  fn clear(&self) {
+    let mut groups = self.groups.lock().unwrap_or_else(PoisonError::into_inner);
+    let held = groups.values_mut().flat_map(|group| group.held.drain()).map(|Reverse(held)| held.message).collect();
+    OUTBOUND.recycle_messages(held);
+  }
+
+  fn broadcast(&self, message: Outbound) {
+    let messages = vec![message];
+    // (Sending fails when no clients are connected, which is fine: nobody missed it.)
+    match self.stats.memory().charge(messages[0].len(), 1) {
+      Ok(charge) => { if let Err(unsent) = self.ser_msg_tx.send(messages, charge) { OUTBOUND.recycle_shared(unsent); } }
+      Err(err) => {
+        log_debug!("[sync] Dropped a message: {}", err);
+        self.stats.messages_dropped(DropReason::OverBudget, 1);
+        OUTBOUND.recycle_messages(messages);
+      }
+    }
+  }
+}
+
+/// Sends the sync groups' held messages as they're due, until the server shuts down.
+pub async fn send_held_messages(sequencer: Arc<Sequencer>, mut ser_req_shutdown_rx: watch::Receiver<bool>) {
+  loop {
+    let due = sequencer.next_due();
+    let wait = async {
+      match due {
+        Some(due) => tokio::time::sleep_until(tokio::time::Instant::from_std(due)).await,
+        None => std::future::pending().await,
+      }
+    };
+    tokio::select! {
+      _ = wait => sequencer.send_due(),
+      _ = sequencer.held.notified() => {}
+      _ = ser_req_shutdown_rx.changed() => {
+        if *ser_req_shutdown_rx.borrow() { break; }
+      }
+    }
+  }
+  sequencer.clear();
+}
+
+/// Tags a text or binary message with its group, topic and pts (see above), returning the original's buffer to the pool. Control messages are left as they are.
+fn tag(message: Outbound, group: &str, topic: &str, pts_us: u64) -> Outbound {
+  let tagged = if let Some(text) = message.text_data() {
+    let fields = format!("\"sync_group\":{},\"sync_topic\":{},\"pts_us\":{}", json_string(group), json_string(topic), pts_us);
+    let trimmed = text.trim();
+    Message::Text(match trimmed.strip_prefix('{').filter(|_| trimmed.ends_with('}')) {
+      Some(rest) if rest[..rest.len() - 1].trim().is_empty() => format!("{{{}}}", fields),
+      Some(rest) => format!("{{{},{}", fields, rest),
+      None => format!("{{{},\"data\":{}}}", fields, json_string(text)),
+    })
+  } else if let Some(data) = message.binary_data() {
+    let topic = &topic.as_bytes()[..topic.len().min(u16::MAX as usize)];
+    let mut buf = OUTBOUND.take(10 + topic.len() + data.len());
+    buf.extend_from_slice(&pts_us.to_be_bytes());
+    buf.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    buf.extend_from_slice(topic);
+    buf.extend_from_slice(data);
+    Message::Binary(buf)
+  } else {
+    return message;
+  };
+  OUTBOUND.recycle_messages(vec![message]);
+  Outbound::from(tagged)
+}
@@ -0,0 +1,83 @@
+// taps.rs
+//
+// Copies of a server's client messages, for sinks that see every one of them without taking them from the server's consumer (the Kafka sink; see kafka_sink.rs). The receiver tasks copy each message they queue for the consumer to every tap, as they queue it. A tap's copies wait in a bounded channel of its own: a tap that falls that far behind misses copies (and counts them), rather than holding up the clients or the consumer.
+
+use std::sync::{Arc, PoisonError, RwLock, atomic::{AtomicU64, AtomicUsize, Ordering}};
+use tokio::sync::mpsc;
+
+use super::events::ClientMessage;
+
+/// How many copies can wait for a tap before it misses the next.
+const TAP_QUEUE_LEN: usize = 8192;
+
+/// A server's taps, shared between its consumer, which adds them, and its receiver tasks, which copy to them.
+#[derive(Default)]
+pub struct MessageTaps {
+  /// How many taps there are, so the receiver tasks only take the lock when there are any.
+  count: AtomicUsize,
+  taps: RwLock<Vec<Arc<TapEnd>>>,
+}
+
+struct TapEnd {
+  tx: mpsc::Sender<ClientMessage>,
+  missed: AtomicU64,
+}
+
+impl MessageTaps {
+  /// Adds a tap, which is copied every client message from now on, until it's dropped or the server stops.
+  pub fn tap(self: &Arc<Self>) -> Tap {
+    let (tx, rx) = mpsc::channel(TAP_QUEUE_LEN);
+    let end = Arc::new(TapEnd { tx, missed: AtomicU64::new(0) });
+    let mut taps = self.taps.write().unwrap_or_else(PoisonError::into_inner);
+    taps.push(end.clone());
+    self.count.store(taps.len(), Ordering::Release);
+    Tap { taps: self.clone(), end, rx }
+  }
+
+  /// Copies a client message to every tap. (Control frames aren't copied.)
+  pub fn copy(&self, msg: &ClientMessage) {
+    if self.count.load(Ordering::Acquire) == 0 || !msg.is_data() { return; }
+    for end in self.taps.read().unwrap_or_else(PoisonError::into_inner).iter() {
+      if end.tx.try_send(msg.copy()).is_err() {
+        end.missed.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+  }
+
+  /// Drops every tap's sending end, once the server has stopped: each tap's recv() returns None once it's had what was copied to it.
+  pub fn close(&self) {
+    self.taps.write().unwrap_or_else(PoisonError::into_inner).clear();
+    self.count.store(0, Ordering::Release);
+  }
+
+  fn remove(&self, end: &Arc<TapEnd>) {
+    let mut taps = self.taps.write().unwrap_or_else(PoisonError::into_inner);
+    taps.retain(|tap| !Arc::ptr_eq(tap, end));
+    self.count.store(taps.len(), Ordering::Release);
+  }
+}
+
+/// A tap on a server's client messages, from MessageTaps::tap(). Dropping it removes it.
+pub struct Tap {
+  taps: Arc<MessageTaps>,
+  end: Arc<TapEnd>,
+  rx: mpsc::Receiver<ClientMessage>,
+}
+
+impl Tap {
+  /// The next copy, or None once the server has stopped and every copy has been received.
+  pub async fn recv(&mut self) -> Option<ClientMessage> {
+    self.rx.recv().await
+  }
+
+  /// How many copies the tap has missed since the last call, being too far behind.
+  pub fn take_missed(&self) -> u64 {
+    self.end.missed.swap(0, Ordering::Relaxed)
+  }
+}
+
+impl Drop for Tap {
+  fn drop(&mut self) {
+    self.taps.remove(&self.end);
+  }
+}
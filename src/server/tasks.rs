@@ -0,0 +1,151 @@
+// tasks.rs
+//
+// The tokio tasks a shutdown waits for: each connection's (routing it, and then a client's sender and receiver tasks, or a proxied connection or cluster peer's), and each link to a cluster peer. Every one holds a Task, registered under a name saying what it is, for as long as it runs, and the shutdown waits for them all to be dropped. Those that don't finish by the shutdown's deadline (see ShutdownOptions::timeout) are aborted along with the runtime: a client whose socket won't take its messages, say, with its sender task stuck mid-write, where it never sees the shutdown. They're logged, recorded as an error event and kept for ShutdownHandle.aborted_tasks, so it's clear what was cut off.
+//
+// The names are given to the tokio tasks too, for tokio-console (with the "console" feature, in builds with tokio_unstable; see logging.rs).
+//
+// A task that panics is caught where it's spawned, and recorded as a critical "internal" error event naming it, with the panic's message and location, and its backtrace if RUST_BACKTRACE asks for one (noted by a panic hook, installed along with the first server, which then hands the panic on to the hook that was there before). Otherwise all there'd be to show for it is a message on stderr and clients that stop hearing from the server. Whatever the task was doing ends with it: a panicked sender task disconnects its client, say (see tokio_server.rs).
+
+use std::{any::Any, backtrace::{Backtrace, BacktraceStatus}, cell::RefCell, collections::BTreeMap, future::Future, panic::{self, AssertUnwindSafe}, sync::{Arc, Mutex, Once, PoisonError, atomic::{AtomicU64, Ordering}}, time::Duration};
+use futures_util::FutureExt;
+use tokio::sync::watch;
+
+use super::{error_events::{Category, ErrorEvent, Severity}, stats::ServerStats};
+
+/// The server's running tasks, and those its shutdown aborted.
+pub struct TaskTracker {
+  next_id: AtomicU64,
+  /// The running tasks' names, by when they were registered.
+  running: watch::Sender<BTreeMap<u64, String>>,
+  aborted: Mutex<Vec<String>>,
+  /// Where the tasks' panics are recorded.
+  stats: Arc<ServerStats>,
+}
+
+impl TaskTracker {
+  pub fn new(stats: Arc<ServerStats>) -> TaskTracker {
+    install_panic_hook();
+    TaskTracker { next_id: AtomicU64::new(0), running: watch::Sender::new(BTreeMap::new()), aborted: Mutex::default(), stats }
+  }
+
+  /// Registers a task, until the returned Task is dropped. (Register tasks before they're spawned, so none is missed by a shutdown that comes before they start.)
+  pub fn track(self: &Arc<Self>, name: String) -> Task {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    self.running.send_modify(|running| { running.insert(id, name); });
+    Task { tracker: self.clone(), id }
+  }
+
+  /// Registers a task (as track() does) and spawns it, with `task` making its future from the Task it's to hold. If it panics, the panic's recorded (see record_panic()).
+  pub fn spawn<F: Future<Output = ()> + Send + 'static>(self: &Arc<Self>, name: String, task: impl FnOnce(Task) -> F) {
+    let future = task(self.track(name.clone()));
+    spawn_named(&name, caught(self.stats.clone(), name.clone(), future));
+  }
+
+  /// Spawns a task the shutdown doesn't wait for (one that's done for once the runtime goes, such as the inspector's feed), recording its panic if it panics, as spawn() does.
+  pub fn spawn_untracked<F: Future<Output = ()> + Send + 'static>(&self, name: String, future: F) {
+    spawn_named(&name, caught(self.stats.clone(), name.clone(), future));
+  }
+
+  /// Waits for every task to finish, for at most `timeout`. Returns the names of those that didn't, which are to be aborted (and are kept as such).
+  pub async fn wait_for_all(&self, timeout: Duration) -> Vec<String> {
+    let mut running_rx = self.running.subscribe();
+    if tokio::time::timeout(timeout, running_rx.wait_for(BTreeMap::is_empty)).await.is_ok() {
+      return vec![];
+    }
+    let aborted: Vec<String> = self.running.borrow().values().cloned().collect();
+    *self.aborted.lock().unwrap_or_else(PoisonError::into_inner) = aborted.clone();
+    aborted
+  }
+
+  /// How many tasks are running (untracked ones aside).
+  pub fn running(&self) -> usize {
+    self.running.borrow().len()
+  }
+
+  /// The tasks the shutdown aborted, oldest first; empty unless the shutdown's deadline passed.
+  pub fn aborted(&self) -> Vec<String> {
+    self.aborted.lock().unwrap_or_else(PoisonError::into_inner).clone()
+  }
+}
+
+/// A running task's registration with the TaskTracker, dropped with the task.
+pub struct Task {
+  tracker: Arc<TaskTracker>,
+  id: u64,
+}
+
+impl Task {
+  /// Spawns another task (one this one launches), registered with the same tracker.
+  pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, name: String, task: impl FnOnce(Task) -> F) {
+    self.tracker.spawn(name, task)
+  }
+}
+
+impl Drop for Task {
+  fn drop(&mut self) {
+    self.tracker.running.send_modify(|running| { running.remove(&self.id); });
+  }
+}
+
+thread_local! {
+  /// Where the last panic on this thread happened, and its backtrace (if RUST_BACKTRACE asks for one), as the panic hook saw it, for whatever catches the panic.
+  static LAST_PANIC: RefCell<Option<(Option<String>, Option<String>)>> = const { RefCell::new(None) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Installs (once per process) a panic hook noting where each panic happened for record_panic(), and handing it on to the hook that was there before (by default, the one printing it).
+fn install_panic_hook() {
+  PANIC_HOOK.call_once(|| {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      let location = info.location().map(|location| location.to_string());
+      let backtrace = Backtrace::capture();
+      let backtrace = (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string());
+      let _ = LAST_PANIC.try_with(|last| *last.borrow_mut() = Some((location, backtrace)));
+      previous(info);
+    }));
+  });
+}
+
+/// Runs `future`, recording its panic if it panics.
+async fn caught<F: Future<Output = ()>>(stats: Arc<ServerStats>, name: String, future: F) {
+  if let Err(panic) = AssertUnwindSafe(future).catch_unwind().await {
+    record_panic(&stats, &name, &*panic, false);
+  }
+}
+
+/// Records a panic caught on this thread, in the task (or loop) named `name`, as a critical error event: its message and location, and its backtrace if there is one. `restarting` says the task's being restarted (see watchdog.rs).
+pub(crate) fn record_panic(stats: &ServerStats, name: &str, panic: &(dyn Any + Send), restarting: bool) {
+  let message = panic.downcast_ref::<&str>().map(|msg| msg.to_string())
+    .or_else(|| panic.downcast_ref::<String>().cloned())
+    .unwrap_or_else(|| "(no message)".to_string());
+  let (location, backtrace) = LAST_PANIC.try_with(|last| last.borrow_mut().take()).ok().flatten().unwrap_or_default();
+  let at = location.map(|location| format!(" at {}", location)).unwrap_or_default();
+  let restarting = if restarting { "; restarting it" } else { "" };
+  log_error!("[tasks] {} panicked{}: {}{}", name, at, message, restarting);
+  let event = ErrorEvent::new(Severity::Critical, Category::Internal, format!("{} panicked{}: {}{}.", capitalized(name), at, message, restarting), None);
+  stats.record_error_event(event.with_backtrace(backtrace));
+}
+
+/// "the accept loop" -> "The accept loop", to start an error event with.
+pub(crate) fn capitalized(name: &str) -> String {
+  let mut chars = name.chars();
+  match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  }
+}
+
+/// Spawns `future` on the current runtime, named for tokio-console if it's in use.
+#[cfg(all(feature = "console", tokio_unstable))]
+fn spawn_named<F: Future<Output = ()> + Send + 'static>(name: &str, future: F) {
+  if let Err(err) = tokio::task::Builder::new().name(name).spawn(future) {
+    log_error!("[tasks] Failed to spawn {}: {}", name, err);
+  }
+}
+
+#[cfg(not(all(feature = "console", tokio_unstable)))]
+fn spawn_named<F: Future<Output = ()> + Send + 'static>(_name: &str, future: F) {
+  tokio::spawn(future);
+}
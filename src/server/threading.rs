@@ -0,0 +1,123 @@
+// threading.rs
+//
+// The server's threads: how many workers its tokio runtime has, and which cores they run on. By default there's a worker per core, scheduled anywhere alongside the rest of the process; so when the process's other threads keep every core busy (a training job's, say), the workers wait their turn with them, and websocket latency swings with the load. Pinned to cores of their own (Threading::cores), the server's threads only ever compete for those. With Threading::isolate, the thread that starts the server is taken off them too, and so are the threads it starts afterwards (which inherit its cores): Python's, and numpy's or torch's worker pools, so long as they're started after the server is.
+//
+// Pinning is Linux's sched_setaffinity(); elsewhere, servers given cores fail to start. (The io_uring transport's ring thread is shared by every server in the process, so it isn't pinned.)
+
+use std::io;
+
+/// Name of the server runtime's threads (as in top -H; Linux shows the first 15 bytes).
+const THREAD_NAME: &str = "quicksocket-server";
+
+/// The server's threads (ServerConfig::threading).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Threading {
+  /// Worker threads in the server's runtime. If None, one per core it may run on (of `cores`, if given).
+  pub worker_threads: Option<usize>,
+  /// If not empty, the cores (numbered from 0, as the OS numbers them) the server's threads are pinned to: its main thread, its workers, and any blocking threads they start.
+  pub cores: Vec<usize>,
+  /// Whether to take `cores` away from the thread starting the server, and so from the threads it starts from then on. Threads already running keep the cores they have.
+  pub isolate: bool,
+}
+
+impl Threading {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.worker_threads == Some(0) {
+      return Err("the server needs at least one worker thread".to_string());
+    }
+    if self.isolate && self.cores.is_empty() {
+      return Err("only servers pinned to cores can be isolated from the starting thread".to_string());
+    }
+    if self.cores.is_empty() { return Ok(()); }
+    sys::validate_cores(&self.cores)
+  }
+
+  /// Takes `cores` away from the calling thread, if the server is to be isolated (see `isolate`).
+  pub(crate) fn isolate_caller(&self) -> Result<(), String> {
+    if !self.isolate { return Ok(()); }
+    let remaining: Vec<usize> = sys::current_cores().map_err(|err| format!("couldn't read the starting thread's cores: {}", err))?
+      .into_iter().filter(|core| !self.cores.contains(core)).collect();
+    if remaining.is_empty() {
+      return Err(format!("isolating cores {:?} would leave the starting thread none to run on", self.cores));
+    }
+    sys::pin_current_thread(&remaining).map_err(|err| format!("couldn't take the starting thread off cores {:?}: {}", self.cores, err))
+  }
+
+  /// Builds the server's runtime, on the server's main thread: which is pinned first (if it's to be), so a failure to pin shows up as the server failing to start.
+  pub(crate) fn runtime(&self) -> io::Result<tokio::runtime::Runtime> {
+    if !self.cores.is_empty() {
+      sys::pin_current_thread(&self.cores)?;
+    }
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all().thread_name(THREAD_NAME);
+    match self.worker_threads {
+      Some(worker_threads) => { builder.worker_threads(worker_threads); }
+      None if !self.cores.is_empty() => { builder.worker_threads(self.cores.len()); }
+      None => {}
+    }
+    if !self.cores.is_empty() {
+      // Threads inherit their creator's cores, so the runtime's would get the main thread's anyway; unless some are started from elsewhere (by spawn_blocking() outside the runtime).
+      let cores = self.cores.clone();
+      builder.on_thread_start(move || {
+        if let Err(err) = sys::pin_current_thread(&cores) {
+          log_warn!("[threading.rs] Couldn't pin a server thread to cores {:?}: {}", cores, err);
+        }
+      });
+    }
+    builder.build()
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+  use std::{io, mem};
+
+  pub fn validate_cores(cores: &[usize]) -> Result<(), String> {
+    let configured = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    let count = if configured > 0 { configured as usize } else { libc::CPU_SETSIZE as usize };
+    match cores.iter().find(|&&core| core >= count.min(libc::CPU_SETSIZE as usize)) {
+      Some(core) => Err(format!("there's no core {} to pin the server to (cores are numbered from 0, and there are {})", core, count)),
+      None => Ok(()),
+    }
+  }
+
+  pub fn current_cores() -> io::Result<Vec<usize>> {
+    unsafe {
+      let mut set: libc::cpu_set_t = mem::zeroed();
+      if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+        return Err(io::Error::last_os_error());
+      }
+      Ok((0..libc::CPU_SETSIZE as usize).filter(|&core| libc::CPU_ISSET(core, &set)).collect())
+    }
+  }
+
+  pub fn pin_current_thread(cores: &[usize]) -> io::Result<()> {
+    unsafe {
+      let mut set: libc::cpu_set_t = mem::zeroed();
+      for &core in cores {
+        libc::CPU_SET(core, &mut set);
+      }
+      if libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+        return Err(io::Error::last_os_error());
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+  use std::io;
+
+  pub fn validate_cores(_cores: &[usize]) -> Result<(), String> {
+    Err("pinning the server to cores is only supported on Linux".to_string())
+  }
+
+  pub fn current_cores() -> io::Result<Vec<usize>> {
+    Err(io::ErrorKind::Unsupported.into())
+  }
+
+  pub fn pin_current_thread(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+  }
+}
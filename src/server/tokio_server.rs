@@ -1,161 +1,634 @@
-use std::{net::SocketAddr};
-use futures_util::{SinkExt, StreamExt, stream::{SplitSink, SplitStream}};
-use tokio::{net::{TcpListener, TcpStream}, sync::{broadcast, mpsc, watch}};
-use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+use std::{panic::AssertUnwindSafe, sync::{Arc, Mutex, PoisonError, atomic::{AtomicU32, Ordering}}, time::{Duration, Instant}};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tokio::{net::TcpListener, sync::{mpsc, watch}};
+use tracing::Instrument;
+use tokio_tungstenite::tungstenite::{Message, protocol::{CloseFrame, frame::coding::CloseCode}};
+
+use super::{audit_log::Auditor, batching::{Batcher, Batching}, buffer_pool::OUTBOUND, capabilities::{CapabilityOffer, ClientCapabilities}, chaos::ClientChaos, clients::{Broadcast, BroadcastQueue, BroadcastReceiver, ClientRegistry, TargetedSend}, clock::{self, Timestamp}, cluster::{self, Cluster}, compression::{self, DeflatingClient}, config::ServerConfig, consumer_state::RunState, decimation::{self, Decimator}, error_events::{Category, Severity}, event_log::{self, Kind}, events::{ClientClose, ClientMessage, ConnectionChange, ConnectionEvent, PingEvent, PingKind}, frames::{self, Frame, FrameSlots}, handle::ShutdownOptions, heartbeat, http, inputs::{self, InputAggregator}, inspector::{self, Inspector}, keepalive::Liveness, keyframes::KeyframeSync, link::DelayLine, logging::Level, metrics::{self, MetricsLog}, notify::MessageNotifier, outbound::Outbound, playback::{Playback, PlaybackControl}, proxy, queue, rate_limit::ClientThrottle, recording::{self, Recorder, RecordingStarts}, rosbridge, stats::{DropReason, OpenSocket, ServerStats}, sync_groups::{self, Sequencer}, taps::MessageTaps, tasks::{self, Task, TaskTracker}, transport::{Connection, Listener}, watchdog::{Heartbeat, Heartbeats}, writer::{self, ClientReader, FrameWriter}};
+
+/// How much longer than the shutdown's close timeout (see Server::shutdown_with()) the server waits for connection tasks to wind down before the runtime is torn down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+/// Longest the runtime waits for its blocking work to finish when it's torn down; what's left finishes in the background.
+const BLOCKING_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+/// Longest a client that's idled out is given to take its close frame.
+const IDLE_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The tokio thread's ends of a server's channels, and the server's state it shares with the consumer, created along with the consumer's ends by server::start().
+pub struct TokioEnds {
+  pub port: u32,
+  pub bound_port: Arc<AtomicU32>,
+  pub config: ServerConfig,
+  pub stats: Arc<ServerStats>,
+  pub clients: Arc<ClientRegistry>,
+  pub notifier: Arc<MessageNotifier>,
+  pub cluster: Option<Arc<Cluster>>,
+  pub playback: Option<Arc<Playback>>,
+  pub inputs: Option<Arc<InputAggregator>>,
+  pub decimator: Option<Arc<Decimator>>,
+  pub sequencer: Option<Arc<Sequencer>>,
+  pub metrics: Arc<MetricsLog>,
+  pub unbound_listener: Option<Listener>,
+  pub ser_state_tx: watch::Sender::<RunState>,
+  pub cli_conn_tx: queue::Sender<ConnectionEvent>,
+  pub cli_ping_tx: queue::Sender<PingEvent>,
+  pub ser_msg_tx: Arc<BroadcastQueue>,
+  pub cli_msg_tx: queue::Sender<ClientMessage>,
+  pub ser_req_shutdown_rx: watch::Receiver::<bool>,
+  pub ser_req_drain_rx: watch::Receiver::<bool>,
+  pub shutdown_options: Arc<Mutex<ShutdownOptions>>,
+  pub recorder: Arc<Recorder>,
+  pub recording_starts: RecordingStarts,
+  pub taps: Arc<MessageTaps>,
+  pub tasks: Arc<TaskTracker>,
+}
+
+/// What every connection's tasks share: the server's configuration and state, and the tokio thread's channel ends. Built by main() once it's listening, and passed to each connection's handle_connection() (and serve_client(), for clients).
+struct ServerContext {
+  config: ServerConfig,
+  inspector: Option<Arc<Inspector>>,
+  cluster: Option<Arc<Cluster>>,
+  stats: Arc<ServerStats>,
+  clients: Arc<ClientRegistry>,
+  notifier: Arc<MessageNotifier>,
+  cli_conn_tx: queue::Sender<ConnectionEvent>,
+  /// (Only if the server reports pings and pongs.)
+  cli_ping_tx: Option<queue::Sender<PingEvent>>,
+  ser_msg_tx: Arc<BroadcastQueue>,
+  client_msg_tx: queue::Sender<ClientMessage>,
+  ser_req_shutdown_rx: watch::Receiver::<bool>,
+  shutdown_options: Arc<Mutex<ShutdownOptions>>,
+  recorder: Arc<Recorder>,
+  taps: Arc<MessageTaps>,
+  heartbeats: Arc<Heartbeats>,
+  audit: Option<Arc<Auditor>>,
+  playback: Option<Arc<Playback>>,
+  inputs: Option<Arc<InputAggregator>>,
+  metrics: Arc<MetricsLog>,
+}
 
 /// Main thread loop for running the websocket server.
 ///
 /// This function launches a tokio runtime to handle most server functions. The function will return after the tokio runtime exits.
-pub fn main(
-  port: u32,
-  ser_thread_alive_tx: watch::Sender::<bool>,
-  cli_conn_tokio_tx: mpsc::Sender<String>,
-  ser_msg_tx: broadcast::Sender::<Vec<tokio_tungstenite::tungstenite::Message>>,
-  cli_msg_tx: mpsc::Sender::<tokio_tungstenite::tungstenite::Message>,
-  mut ser_req_shutdown_rx: watch::Receiver::<bool>
-) -> Result<String, String> {
+pub fn main(ends: TokioEnds) -> Result<String, String> {
+  let TokioEnds {
+    port, bound_port, config, stats, clients, notifier, cluster, playback, inputs, decimator, sequencer, metrics, unbound_listener, ser_state_tx,
+    cli_conn_tx: cli_conn_tokio_tx, cli_ping_tx: cli_ping_tokio_tx, ser_msg_tx, cli_msg_tx, mut ser_req_shutdown_rx, mut ser_req_drain_rx, shutdown_options, recorder, mut recording_starts, taps, tasks,
+  } = ends;
   // Start the tokio runtime for the server and launch the top-level server task.
-  println!("Server launching runtime.");
-  let tokio_runtime = tokio::runtime::Runtime::new().unwrap();
+  log_info!("Server launching runtime.");
+  let tokio_runtime = match config.threading.runtime() {
+    Ok(runtime) => runtime,
+    Err(err) => {
+      log_error!("[tokio_server.rs] Failed to start the server's runtime: {}", err);
+      stats.record_error(Severity::Error, Category::Internal, format!("Failed to start the server's runtime (with {:?}): {}", config.threading, err), None);
+      stats.server_stopped();
+      ser_state_tx.send_replace(RunState::Failed(err.to_string()));
+      return Err(err.to_string());
+    }
+  };
+  // The broadcasts are deflated (if the server compresses its messages) on threads of their own, started from this one so they share its cores.
+  if let Some(deflater) = ser_msg_tx.deflater() { deflater.start(); }
+  // The watchdog (if there is one) watches from a thread of its own, so it notices even if the runtime's wedged.
+  let heartbeats = Arc::new(Heartbeats::new(config.watchdog, stats.clone()));
+  let watchdog = heartbeats.launch();
+  // The audit log (if there is one) is written from a thread of its own too, so the tasks never wait on the file.
+  let audit = match config.audit_log.as_ref().map(Auditor::start).transpose() {
+    Ok(audit) => audit.map(Arc::new),
+    Err(err) => {
+      log_error!("[tokio_server.rs] Failed to start the audit log: {}", err);
+      stats.record_error(Severity::Error, Category::Audit, format!("Failed to start the audit log: {}", err), None);
+      stats.server_stopped();
+      ser_state_tx.send_replace(RunState::Failed(err.clone()));
+      return Err(err);
+    }
+  };
   tokio_runtime.block_on(async {
 
     // Top-level tokio task
     // --------------------
     //
-    let res = ser_thread_alive_tx.send(true);
-    if res.is_err() { println!("Failed to set server alive."); return; }
-
-    // Bind to websocket on localhost port 59994.
-    let addr = format!("127.0.0.1:{}", port);
-    println!("[quicksocket] Attempting to bind TcpListener at: {}", addr);
-    let listener = TcpListener::bind(&addr).await;
-    if listener.is_err() { println!("Failed to bind TcpListener. It's possible that port {} is already in use.", port); return; }
+    // (The state starts out as Starting. State changes use send_replace(), which works whether or not anyone is watching.)
+
+    // Bind to websocket on localhost port 59994 (unless connections come over the loopback transport instead).
+    let mut addr = super::bind_address(port);
+    let listener = match unbound_listener {
+      Some(listener) => {
+        let kind = if matches!(listener, Listener::Loopback(_)) { "loopback" } else { "service" };
+        log_info!("[quicksocket] Accepting {} connections only (port {} isn't bound).", kind, port);
+        event_log::record(Level::Info, Kind::Bind, format!("Server for port {} accepting {} connections only.", port, kind), None);
+        Ok(listener)
+      }
+      #[cfg(feature = "uring")]
+      None if config.transport == super::Transport::Uring => {
+        log_info!("[quicksocket] Attempting to bind UringListener at: {}", addr);
+        super::uring::UringListener::bind(&addr).map(Listener::Uring)
+      }
+      None => {
+        log_info!("[quicksocket] Attempting to bind TcpListener at: {}", addr);
+        TcpListener::bind(&addr).await.map(Listener::Tcp)
+      }
+    };
+    if let Err(err) = &listener {
+      log_error!("Failed to bind TcpListener. It's possible that port {} is already in use.", port);
+      stats.record_error(Severity::Error, Category::Bind, format!("Failed to bind {}: {}", addr, err), None);
+
+      // The server never served, so it's no longer alive; this also lets the consumer start a new server in its place.
+      stats.server_stopped();
+      ser_state_tx.send_replace(RunState::Failed(err.to_string()));
+      return;
+    }
     let listener = listener.unwrap();
     //.expect("Failed to bind to address")
-    println!("Listening on: {}", addr);
+    if let Some(local_addr) = listener.local_addr() {
+      // (Port 0 asks the OS to pick one; this is how the consumer finds out which.)
+      if let Ok(local_addr) = local_addr {
+        bound_port.store(local_addr.port() as u32, Ordering::Relaxed);
+        addr = local_addr.to_string();
+      }
+      log_info!("Listening on: {}", addr);
+      event_log::record(Level::Info, Kind::Bind, format!("Server for port {} listening on {}.", logged_port(port, &bound_port), addr), None);
+    }
+    ser_state_tx.send_replace(RunState::Running);
+
+    // The inspector, if enabled, is shared by all connection tasks and records every broadcast via its own subscription.
+    let inspector = if config.inspector {
+      let inspector = Arc::new(Inspector::new());
+      tasks.spawn_untracked("the inspector's broadcast feed".to_string(), inspector::record_broadcasts(inspector.clone(), ser_msg_tx.subscribe(), ser_req_shutdown_rx.clone()));
+      log_info!("[tokio_server.rs] Inspector available at: http://{}{}", addr, inspector::PAGE_PATH);
+      Some(inspector)
+    } else { None };
+
+    // The heartbeat topic, if any, is broadcast from here for as long as the server runs, whatever the consumer's doing.
+    if let Some(heartbeat) = &config.heartbeat {
+      tasks.spawn_untracked("the heartbeat topic".to_string(), heartbeat::send_heartbeats(heartbeat.clone(), stats.clone(), ser_msg_tx.clone(), ser_req_shutdown_rx.clone()));
+    }
+    // So are the decimated topics' held samples, as they come due.
+    if let Some(decimator) = &decimator {
+      tasks.spawn_untracked("the decimated topics".to_string(), decimation::send_held_samples(decimator.clone(), ser_req_shutdown_rx.clone()));
+    }
+    // And the sync groups' held messages.
+    if let Some(sequencer) = &sequencer {
+      tasks.spawn_untracked("the sync groups".to_string(), sync_groups::send_held_messages(sequencer.clone(), ser_req_shutdown_rx.clone()));
+    }
+    // And the clients' aggregated input's snapshots.
+    if let Some(inputs) = &inputs {
+      tasks.spawn_untracked("the input aggregation".to_string(), inputs::take_snapshots(inputs.clone(), ser_req_shutdown_rx.clone()));
+    }
+    // And the logged metrics' frames.
+    tasks.spawn_untracked("the metrics".to_string(), metrics::send_metrics(metrics.clone(), ser_req_shutdown_rx.clone()));
+
+    // Cluster nodes keep a link to each of their peers for as long as they run.
+    if let Some(cluster) = &cluster {
+      log_info!("[tokio_server.rs] Cluster node {} linking to {} peer(s).", cluster.node_id(), cluster.peer_count());
+      for index in 0..cluster.peer_count() {
+        let span = tracing::info_span!("cluster_link", peer = %cluster.peer_url(index));
+        tasks.spawn(format!("the link to cluster peer {}", cluster.peer_url(index)), |task| {
+          cluster::run_link(cluster.clone(), index, stats.clone(), ser_req_shutdown_rx.clone(), task).instrument(span)
+        });
+      }
+    }
+
+    // Everything the connections' tasks share. (The receiver tasks only report pings and pongs if asked to.)
+    let context = Arc::new(ServerContext {
+      cli_ping_tx: config.ping_events.then_some(cli_ping_tokio_tx),
+      config,
+      inspector,
+      cluster,
+      stats: stats.clone(),
+      clients,
+      notifier: notifier.clone(),
+      cli_conn_tx: cli_conn_tokio_tx.clone(),
+      ser_msg_tx: ser_msg_tx.clone(),
+      client_msg_tx: cli_msg_tx,
+      ser_req_shutdown_rx: ser_req_shutdown_rx.clone(),
+      shutdown_options: shutdown_options.clone(),
+      recorder: recorder.clone(),
+      taps: taps.clone(),
+      heartbeats: heartbeats.clone(),
+      audit: audit.clone(),
+      playback,
+      inputs,
+      metrics,
+    });
 
     // Listen for connections until shutdown.
     // -----------------------------------
     //
-    // Loop, responding to whichever future finishes first. (We break on a shutdown signal.)
+    // Loop, responding to whichever future finishes first. (We break on a shutdown signal.) Draining closes the listener (None from then on), but clients go on being served until the shutdown.
+    //
+    // With a watchdog, the loop beats a heartbeat on every turn (see watchdog.rs); restarted, it starts over where it was, with the same listener.
+    let mut listener = Some(listener);
+    let mut clients_rx = stats.subscribe_current_clients();
+    let mut drained = false;
+    let mut beats = heartbeats.ticks();
     loop {
-      let accept_conn = listener.accept();
-      tokio::pin!(accept_conn);
+      let heartbeat = heartbeats.watch("the accept loop".to_string(), None);
+      let accept_loop = async { loop {
+        heartbeat.beat();
+        tokio::select! {
+          // Valid connection. Launch task to handle the connection for its lifetime.
+          Ok((stream, peer)) = accept(&mut listener) => {
+            log_debug!("[tokio_server.rs] Peer address: {}", peer);
 
-      tokio::select! {
-        // Valid connection. Launch task to handle the connection for its lifetime.
-        Ok((stream, _)) = &mut accept_conn => {
-          let peer = stream.peer_addr().expect("Connected streams should have a peer address");
-          println!("[tokio_server.rs] Peer address: {}", peer);
-          let new_client_evt = peer.to_string();
-          cli_conn_tokio_tx.send(new_client_evt).await.unwrap_or_else(|_| println!("[tokio_server.rs] Failed to report new client event to consumer."));
-
-          // Each connection receives a reciever for messages to forward from the server, and a transmitter to forward client messages back to the server.
-          let (ser_msg_broadcast_rx, cli_msg_store_tx) = (
-            ser_msg_tx.subscribe(), cli_msg_tx.clone()
-          );
-
-          // Spawn a connection handler task, which will live for the duration of the connection.
-          tokio::spawn(handle_connection(peer, stream, ser_msg_broadcast_rx, cli_msg_store_tx, ser_req_shutdown_rx.clone()));
-        }
+            // Spawn a connection handler task, which will live for the duration of the connection. The handler routes the connection first (it may be a plain HTTP request or an inspector feed), so it's responsible for reporting new clients and subscribing to server messages.
+            let span = tracing::info_span!("connection", peer = %peer);
+            let socket = stats.socket_opened();
+            tasks.spawn(format!("the connection from {}", peer), |task| handle_connection(peer, stream, socket, context.clone(), task).instrument(span));
+          }
 
-        // Receive an exit signal and shutdown.
-        _ = ser_req_shutdown_rx.changed() => {
-          if *ser_req_shutdown_rx.borrow() {
-            println!("[tokio_server.rs] Received shutdown signal.");
-            break;
+          // A session recording started: record its broadcasts, from the subscription it was started with.
+          Some(start) = recording_starts.recv() => {
+            tasks.spawn_untracked("the session recording's broadcast feed".to_string(), recording::record_broadcasts(start, ser_req_shutdown_rx.clone()));
+          }
+
+          // Receive a drain signal, and stop accepting connections. (Dropping the listener frees the port.)
+          _ = ser_req_drain_rx.changed(), if listener.is_some() => {
+            if *ser_req_drain_rx.borrow() {
+              log_info!("[tokio_server.rs] Received drain signal; no longer accepting connections ({} client(s) still connected).", stats.current_clients());
+              event_log::record(Level::Info, Kind::Drain, format!("Server for port {} draining, with {} client(s) connected.", logged_port(port, &bound_port), stats.current_clients()), None);
+              listener = None;
+              ser_state_tx.send_replace(RunState::Draining);
+            }
           }
-        }
-      } // tokio::select!
-    } // loop
+
+          // Once draining, report the last client disconnecting. (Its disconnection was reported before it stopped being counted, so the consumer sees that first.)
+          _ = clients_rx.wait_for(|clients| *clients == 0), if listener.is_none() && !drained => {
+            log_info!("[tokio_server.rs] Drained: no clients left.");
+            event_log::record(Level::Info, Kind::Drain, format!("Server for port {} drained.", logged_port(port, &bound_port)), None);
+            drained = true;
+            if cli_conn_tokio_tx.try_send(ConnectionEvent::new(String::new(), ConnectionChange::Drained)).is_err() {
+              log_debug!("[tokio_server.rs] Couldn't report the server drained to the consumer; its connection event queue is full.");
+            }
+          }
+
+          // Receive an exit signal and shutdown.
+          _ = ser_req_shutdown_rx.changed() => {
+            if *ser_req_shutdown_rx.borrow() {
+              log_info!("[tokio_server.rs] Received shutdown signal.");
+              event_log::record(Level::Info, Kind::Shutdown, format!("Server for port {} shutting down, with {} client(s) connected.", logged_port(port, &bound_port), stats.current_clients()), None);
+              ser_state_tx.send_replace(RunState::Draining);
+              break;
+            }
+          }
+
+          // Beat with nothing else to do, so an idle loop isn't taken for a stalled one (if there's a watchdog).
+          _ = beats.tick() => {}
+        } // tokio::select!
+      }}; // loop
+
+      tokio::select! {
+        ended = AssertUnwindSafe(accept_loop).catch_unwind() => match ended {
+          Ok(()) => { break; }
+          // Recorded (see tasks.rs); and unless the watchdog restarts it, it takes the server thread down as ever.
+          Err(panic) => {
+            let restart = heartbeats.restarts();
+            tasks::record_panic(&stats, "the accept loop", &*panic, restart);
+            if !restart {
+              drop(heartbeat);
+              std::panic::resume_unwind(panic);
+            }
+          }
+        },
+        // Stalled at an await, and restarted: the stalled loop was dropped with whatever it was stuck on.
+        _ = heartbeat.restarted() => {}
+      }
+    } // restarts
 
     // Shut down.
-    println!("[tokio_server.rs] Server writing alive = false.");
-    ser_thread_alive_tx.send(false).unwrap_or_else(|_| println!("[tokio_server.rs] Failed to set server thread alive to false!"));
+    //
+    // Connection tasks see the same shutdown signal and send their clients close frames, and clients' tasks wait for the answers (for at most the close timeout); give them until the shutdown's deadline to finish before the runtime is dropped, which aborts those that haven't (mid-write, perhaps).
+    let options = shutdown_options.lock().unwrap_or_else(PoisonError::into_inner).clone();
+    let deadline = options.timeout.unwrap_or(options.close_timeout + SHUTDOWN_GRACE);
+    let aborted = tasks.wait_for_all(deadline).await;
+    if !aborted.is_empty() {
+      log_warn!("[tokio_server.rs] {} task(s) still running {} ms into the shutdown; aborting them: {}", aborted.len(), deadline.as_millis(), aborted.join(", "));
+      stats.record_error(Severity::Error, Category::Internal, format!("Aborted {} task(s) still running {} ms into the shutdown: {}.", aborted.len(), deadline.as_millis(), aborted.join(", ")), None);
+    }
+
+    ser_state_tx.send_replace(RunState::Stopping);
   });
-  
-  println!("[tokio_server.rs] Server tokio thread exiting.");
+  drop(watchdog);
+  // (Dropping the runtime would wait for its blocking work, e.g. a cluster link's DNS lookup, however long that takes.)
+  tokio_runtime.shutdown_timeout(BLOCKING_SHUTDOWN_TIMEOUT);
+
+  // Whatever's been recorded (or audited) is in the file before the server's reported stopped.
+  recorder.close();
+  if let Some(audit) = &audit { audit.close(); }
+  // The taps are done with, once they've had what was copied to them.
+  taps.close();
+  // (The keyframe streams end with the server, releasing their memory, as do the deflating threads.)
+  ser_msg_tx.keyframes().clear();
+  if let Some(deflater) = ser_msg_tx.deflater() { deflater.stop(); }
+
+  // A failed start has already been reported (and counted as the server stopping).
+  if !matches!(*ser_state_tx.borrow(), RunState::Failed(_)) {
+    stats.server_stopped();
+    log_debug!("[tokio_server.rs] Server writing state = stopped.");
+    event_log::record(Level::Info, Kind::Shutdown, format!("Server for port {} stopped.", logged_port(port, &bound_port)), None);
+    ser_state_tx.send_replace(RunState::Stopped);
+  }
+  // Wake anyone awaiting messages, so they notice the server stopped.
+  notifier.notify();
+
+  log_info!("[tokio_server.rs] Server tokio thread exiting.");
   Ok("Server shut-down successfully.".to_string())
 }
 
-async fn handle_connection(
-  _peer: SocketAddr,
-  stream: TcpStream,
-  server_msg_rx: broadcast::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
-  client_msg_tx: mpsc::Sender<tokio_tungstenite::tungstenite::Message>,
-  ser_req_shutdown_rx: watch::Receiver::<bool>
-) {
-  let addr = stream.peer_addr();
-  if addr.is_err() {
-    println!("[handle_connection] Error: Connected streams should have a peer address.");
+/// The port to name a server by in the event log: the one it's bound to, which differs from the one it was started on for port 0.
+fn logged_port(port: u32, bound_port: &AtomicU32) -> u32 {
+  match bound_port.load(Ordering::Relaxed) {
+    0 => port,
+    bound => bound,
+  }
+}
+
+/// The listener's next connection; never, once it's been closed for draining.
+async fn accept(listener: &mut Option<Listener>) -> std::io::Result<(Connection, String)> {
+  match listener {
+    Some(listener) => listener.accept().await,
+    None => std::future::pending().await,
+  }
+}
+
+/// Routes a connection by its request head, as a plain HTTP request, an inspector feed, a cluster peer's link, a proxied connection, or a client.
+async fn handle_connection(addr: String, mut stream: Connection, socket: OpenSocket, context: Arc<ServerContext>, task: Task) {
+  let ServerContext { config, inspector, cluster, stats, ser_msg_tx, ser_req_shutdown_rx, audit, metrics, .. } = &*context;
+  #[cfg(feature = "tower")]
+  if let Connection::Service { subscription, .. } = &mut stream {
+    // Routed, handshaken and subscribed by the service (see service.rs) already. (Its request isn't seen here, so it's audited without a path or principal.)
+    let subscription = subscription.take().unwrap_or_else(|| metrics.subscribe());
+    if let Some(audit) = audit { audit.accepted(&addr, None, None); }
+    serve_client(addr, stream, socket, &context, subscription, None, task).await;
+    return;
+  }
+
+  // Route the connection based on its request head. Plain HTTP requests and malformed upgrades get a real HTTP response, inspector traffic is handled separately, and everything else is treated as a regular websocket client.
+  let head = http::peek_request_head(&mut stream).await;
+  if let Err(err) = head {
+    log_warn!("[handle_connection] Failed to read request head from {}: {}", addr, err);
+    stats.record_error(Severity::Warning, Category::Http, format!("Failed to read request head: {}", err), Some(addr.clone()));
+    if let Some(audit) = audit { audit.rejected(&addr, None, None, format!("no valid request head ({})", err)); }
+    if let http::PeekError::Malformed { buffered, .. } = err {
+      let res = http::write_response(&mut stream, buffered, &http::Response::text(400, "Bad Request", "Malformed HTTP request.\n")).await;
+      if let Err(err) = res { log_warn!("[handle_connection] Failed to send 400 response to {}: {:?}", addr, err); }
+    }
+    return;
+  }
+  let head = head.unwrap();
+
+  if let Some(inspector) = inspector {
+    match (head.route(), head.is_upgrade) {
+      (inspector::PAGE_PATH, false) => {
+        let res = http::respond(&mut stream, &head, &http::Response::html(inspector::PAGE_HTML)).await;
+        if let Err(err) = res { log_warn!("[handle_connection] Failed to serve inspector page to {}: {:?}", addr, err); }
+        return;
+      }
+      (inspector::FEED_PATH, true) => {
+        inspector::serve_feed(inspector.clone(), stream, ser_req_shutdown_rx.clone()).await;
+        return;
+      }
+      _ => {}
+    }
+  }
+
+  if !head.is_upgrade {
+    log_debug!("[handle_connection] Plain HTTP request from {} for {}; sending landing response.", addr, head.path);
+    let res = http::respond(&mut stream, &head, &http::Response::landing(config.landing_page.as_deref())).await;
+    if let Err(err) = res { log_warn!("[handle_connection] Failed to send landing response to {}: {:?}", addr, err); }
+    return;
+  }
+  if let Err(response) = head.validate_upgrade() {
+    log_warn!("[handle_connection] Rejecting malformed websocket upgrade from {} ({}).", addr, response.status);
+    stats.record_error(Severity::Warning, Category::Handshake, format!("Rejected malformed websocket upgrade ({} {}).", response.status, response.reason), Some(addr.clone()));
+    if let Some(audit) = audit { audit.rejected(&addr, Some(&head.path), audit.principal(&head), format!("malformed websocket upgrade ({} {})", response.status, response.reason)); }
+    let res = http::respond(&mut stream, &head, &response).await;
+    if let Err(err) = res { log_warn!("[handle_connection] Failed to send {} response to {}: {:?}", response.status, addr, err); }
+    return;
+  }
+
+  if let (Some(cluster), cluster::PATH) = (cluster, head.route()) {
+    // Another node's link: not a client, and never proxied.
+    let _task = task;
+    cluster::serve_peer(addr, stream, cluster.clone(), stats, ser_msg_tx.clone(), ser_req_shutdown_rx.clone()).await;
+    return;
+  }
+
+  if let Some(backend_url) = proxy::backend_for(&config.proxy_routes, &head.path) {
+    // (Holds its task like a client's tasks do, so shutdown waits for it to send its close frames.)
+    let _task = task;
+    proxy::serve(addr, stream, &head, backend_url, stats, ser_req_shutdown_rx.clone()).await;
+    return;
+  }
+
+  // Each connection receives a reciever for messages to forward from the server, and a transmitter to forward client messages back to the server. (Subscribed before the handshake, so the client gets every message sent once it's connected.)
+  let subscription = metrics.subscribe();
+  // (And counted as a client broadcasts are deflated for, if it agrees to compression, for as long as it's connected.)
+  let deflating = ser_msg_tx.deflater().and_then(|deflater| deflater.accept(head.ws_extensions.as_deref()));
+
+  if let Err(err) = http::accept_upgrade(&mut stream, &head, deflating.as_ref().map(|_| compression::RESPONSE)).instrument(tracing::debug_span!("handshake", path = %head.path)).await {
+    log_warn!("[handle_connection] Error during the websocket handshake with {}: {:?}", addr, err);
+    stats.record_error(Severity::Warning, Category::Handshake, format!("Websocket handshake failed: {}", err), Some(addr.clone()));
+    if let Some(audit) = audit { audit.rejected(&addr, Some(&head.path), audit.principal(&head), format!("websocket handshake failed ({})", err)); }
     return;
   }
-  let addr = addr.unwrap();
+  if let Some(audit) = audit { audit.accepted(&addr, Some(&head.path), audit.principal(&head)); }
+  serve_client(addr, stream, socket, &context, subscription, deflating, task).await;
+}
+
+/// Registers and reports a client whose websocket handshake is done, and launches its sender and receiver tasks. `subscription` is its subscription to the broadcasts, with the metrics' history (see MetricsLog::subscribe()).
+async fn serve_client(addr: String, mut stream: Connection, socket: OpenSocket, context: &ServerContext, subscription: (BroadcastReceiver, Option<Message>), deflating: Option<DeflatingClient>, task: Task) {
+  let (server_msg_rx, metrics_history) = subscription;
+  let config = &context.config;
+  let (stats, clients, recorder) = (context.stats.clone(), context.clients.clone(), context.recorder.clone());
+  let (inspector, cli_conn_tx) = (context.inspector.clone(), context.cli_conn_tx.clone());
+  let client_id = addr.clone();
 
-  let ws_stream = tokio_tungstenite::accept_async(stream)
-    .await
-    .expect("Error during the websocket handshake occurred");
+  log_info!("[handle_connection] New websocket connection: {}", addr);
+  event_log::record(Level::Info, Kind::Accept, "Client connected.".to_string(), Some(client_id.clone()));
+  // Targeted sends for this client alone arrive on their own channel, alongside the broadcast subscription. Registered before the client is counted or reported, so it can be sent to as soon as anyone knows it's there.
+  let throttle = ClientThrottle::new(config.client_rate_limits.default);
+  // The sender task pings the client (if it's kept alive) and the receiver task reads its pongs, timing its link; both note its messages, which keep it from idling out.
+  let liveness = Arc::new(Liveness::new(config.keepalive, config.idle_timeout));
+  // Frames sent to the client are put in its slots, which its sender task takes them from (see frames.rs).
+  let frames = Arc::new(FrameSlots::new(&config.client_topic_rates));
+  let client_send_rx = clients.register(&client_id, throttle.clone(), liveness.clone(), frames.clone());
+  stream.client_registered();
 
-  println!("[handle_connection] New websocket connection: {}", addr);
+  if let Some(inspector) = &inspector { inspector.client_connected(&client_id); }
+  let connection = stats.client_connected();
+  let chaos = config.chaos.map(|chaos| ClientChaos::new(chaos, connection));
+  let link = config.link.map(|link| DelayLine::new(link, connection));
+  cli_conn_tx.send(ConnectionEvent::new(client_id.clone(), ConnectionChange::Connected)).await.unwrap_or_else(|_| log_warn!("[handle_connection] Failed to report new client event to consumer."));
 
-  
   // Split up the stream to a client reader and a client writer.
-  let (ws_client_write, ws_client_read) = ws_stream.split();
+  let (ws_client_read, ws_client_write) = writer::split(stream, stats.clone(), throttle, deflating).await;
+
+  // Each task holds the socket's half it uses, so it's open until both are done with it.
+  let socket = Arc::new(socket);
 
   // Create a channel between the tasks to handle a client-initiated shutdown handshake.
   let (ws_client_req_shutdown_tx, ws_client_req_shutdown_rx) = watch::channel::<()>(());
 
-  // Launch a task to handle sending messages from the server-side library consumer to the websocket client over ws_write.
-  tokio::spawn(send_ws_client_messages(
-    server_msg_rx, ws_client_write, ser_req_shutdown_rx.clone(), ws_client_req_shutdown_rx
-  ));
+  // (The shutdown waits for both tasks, rather than for this one, which returns once they're launched; their spans are within the connection's.)
+  let sender_span = tracing::info_span!("sender", client = %client_id);
+  let receiver_span = tracing::info_span!("receiver", client = %client_id);
+
+  // Launch a task to handle sending messages from the server-side library consumer to the websocket client over ws_write. If the watchdog restarts it, it's cancelled, which disconnects the client (as the sender task stopping always does).
+  let heartbeat = Arc::new(context.heartbeats.watch(format!("client {}'s sender task", client_id), Some(client_id.clone())));
+  let sender_socket = socket.clone();
+  // (The receiver task throttles the client's topics as it asks, if it can.)
+  let receiver_frames = frames.clone();
+  // (And takes its capabilities, if it's offered any.)
+  let capabilities = config.capabilities.clone().map(|offer| (offer, clients.clone()));
+  // (And its rosbridge subscriptions, if it can make them.)
+  let rosbridge = config.rosbridge.then(|| clients.clone());
+  task.spawn(format!("client {}'s sender task", client_id), |sender_task| {
+    let sending = send_ws_client_messages(
+      client_id.clone(), stats.clone(), clients, recorder.clone(), config.batching, chaos, link, liveness.clone(), heartbeat.clone(), cli_conn_tx.clone(), server_msg_rx, client_send_rx, frames, ws_client_write, context.ser_req_shutdown_rx.clone(), context.shutdown_options.clone(), ws_client_req_shutdown_rx, sender_task
+    );
+    async move {
+      let _socket = sender_socket;
+      tokio::select! {
+        _ = sending => {}
+        _ = heartbeat.restarted() => {}
+      }
+    }.instrument(sender_span)
+  });
 
   // Launch a task to handle receiving messages from the websocket client over ws_read and buffering them for the server-side library consumer to drain and handle later.
-  tokio::spawn(recv_ws_client_messages(
-    client_msg_tx, ws_client_read, ser_req_shutdown_rx, ws_client_req_shutdown_tx
-  ));
+  task.spawn(format!("client {}'s receiver task", client_id), |receiver_task| {
+    let receiving = recv_ws_client_messages(
+      client_id, inspector, recorder, stats, context.notifier.clone(), cli_conn_tx, context.cli_ping_tx.clone(), context.client_msg_tx.clone(), context.taps.clone(), liveness, context.audit.clone(), config.time_sync, context.playback.clone(), context.inputs.clone(), metrics_history, config.topic_throttle_requests.then_some(receiver_frames), capabilities, rosbridge, ws_client_read, context.ser_req_shutdown_rx.clone(), ws_client_req_shutdown_tx, receiver_task
+    );
+    async move {
+      let _socket = socket;
+      receiving.await
+    }.instrument(receiver_span)
+  });
 
   // Archived: For debugging purposes, we can create a simple message forwarder for the lifetime of the connection (bouncing messages from the websocket client back to them).
   // let (write, read) = ws_stream.split();
   // read.forward(write).await.expect("Failed to forward message");
 
-  println!("[handle_connection] Websocket connection handled.");
+  log_debug!("[handle_connection] Websocket connection handled.");
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn send_ws_client_messages(
-  mut server_msg_rx: broadcast::Receiver<Vec<tokio_tungstenite::tungstenite::Message>>,
-  mut ws_client_write: SplitSink<WebSocketStream<TcpStream>, Message>,
+  client_id: String,
+  stats: Arc<ServerStats>,
+  clients: Arc<ClientRegistry>,
+  recorder: Arc<Recorder>,
+  batching: Batching,
+  mut chaos: Option<ClientChaos>,
+  mut link: Option<DelayLine<Batch>>,
+  liveness: Arc<Liveness>,
+  heartbeat: Arc<Heartbeat>,
+  cli_conn_tx: queue::Sender<ConnectionEvent>,
+  mut server_msg_rx: BroadcastReceiver,
+  mut client_send_rx: mpsc::Receiver<TargetedSend>,
+  frames: Arc<FrameSlots>,
+  mut ws_client_write: FrameWriter,
   mut ser_req_shutdown_rx: watch::Receiver::<bool>,
-  mut ws_client_req_shutdown_rx: watch::Receiver::<()>
+  shutdown_options: Arc<Mutex<ShutdownOptions>>,
+  mut ws_client_req_shutdown_rx: watch::Receiver::<()>,
+  _task: Task
 ) {
+  // However the sender task stops (even if the shutdown aborts it), the connection's done: the receiver task stops reading it too (if it hasn't already), and the client can't be sent to. Targeted sends still queued are dropped along with client_send_rx, which tells anyone waiting on their confirmation that they weren't sent.
+  let _done = OnDrop(|| {
+    liveness.hang_up();
+    clients.unregister(&client_id);
+  });
+  let replies = ws_client_write.replies();
+  let mut batcher = Batcher::new(batching);
+  let mut pings = liveness.pings();
+  // How far along the keyframe streams the client's been written (see keyframes.rs). It's written what there is of them as soon as it connects.
+  let mut keyframes = KeyframeSync::new();
+  let mut started = false;
   loop { tokio::select! {
-    // Receive server messages and forward them to connected clients.
-    recv_res = server_msg_rx.recv() => { match recv_res {
-      Ok(msgs) => {
-        for msg in msgs {
-          let res = ws_client_write.feed(msg).await;
-          if res.is_err() {
-            println!("[send_ws_client_messages] Failed to feed ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.");
-            break;
-          }
-        }
-        let res = ws_client_write.flush().await;
-        if res.is_err() {
-          println!("[send_ws_client_messages] Failed to flush ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.");
-          break;
-        }
+    _ = std::future::ready(()), if !started => {
+      started = true;
+      let batches: Vec<Batch> = keyframes.start(server_msg_rx.keyframes()).into_iter().map(Batch::Broadcast).collect();
+      if batches.is_empty() { continue; }
+      let _busy = heartbeat.busy();
+      let span = tracing::debug_span!("send_keyframes", messages = batches.len());
+      if forward(&client_id, &stats, &clients, &recorder, &mut ws_client_write, &mut batcher, chaos.as_mut(), link.as_mut(), batches, &mut server_msg_rx, &mut client_send_rx, &frames, &mut keyframes).instrument(span).await.is_err() { break; }
+      liveness.active();
+    }
+
+    // Receive server messages and forward them to connected clients. (Unless the client's emulated link is full, when they're left in its queue.)
+    Some((msgs, missed)) = server_msg_rx.recv(), if link.as_ref().is_none_or(DelayLine::has_room) => {
+      // (At work until it's written them, as far as the watchdog's concerned, if there is one.)
+      let _busy = heartbeat.busy();
+      record_missed_broadcasts(&client_id, &stats, &clients, missed);
+      let span = tracing::debug_span!("fan_out", messages = msgs.messages.len());
+      let batches = broadcast_batches(&stats, &mut keyframes, &server_msg_rx, msgs);
+      if forward(&client_id, &stats, &clients, &recorder, &mut ws_client_write, &mut batcher, chaos.as_mut(), link.as_mut(), batches, &mut server_msg_rx, &mut client_send_rx, &frames, &mut keyframes).instrument(span).await.is_err() { break; }
+      liveness.active();
+    }
+
+    // Receive messages sent to this client alone (confirming them if asked to), and forward them.
+    Some(targeted) = client_send_rx.recv(), if link.as_ref().is_none_or(DelayLine::has_room) => {
+      let _busy = heartbeat.busy();
+      let span = tracing::debug_span!("send_to_client", messages = targeted.messages.len());
+      if forward(&client_id, &stats, &clients, &recorder, &mut ws_client_write, &mut batcher, chaos.as_mut(), link.as_mut(), vec![Batch::Targeted(targeted)], &mut server_msg_rx, &mut client_send_rx, &frames, &mut keyframes).instrument(span).await.is_err() { break; }
+      liveness.active();
+    }
+
+    // Forward the newest frame of each topic pending for the client (see frames.rs), which forward() takes as it writes. (Frames written along with other batches since they were put in leave nothing to forward, as do those of throttled topics that aren't due yet.)
+    _ = frames.ready(), if link.as_ref().is_none_or(DelayLine::has_room) => {
+      let due = frames.due();
+      if due == 0 { continue; }
+      let _busy = heartbeat.busy();
+      let span = tracing::debug_span!("send_frames", frames = due);
+      if forward(&client_id, &stats, &clients, &recorder, &mut ws_client_write, &mut batcher, chaos.as_mut(), link.as_mut(), vec![], &mut server_msg_rx, &mut client_send_rx, &frames, &mut keyframes).instrument(span).await.is_err() { break; }
+      liveness.active();
+    }
+
+    // Write what's come out of the client's emulated link (if it has one).
+    batches = async { link.as_mut().unwrap().arrivals().await }, if link.as_ref().is_some_and(|link| !link.is_empty()) => {
+      let _busy = heartbeat.busy();
+      if write_batches(&client_id, &stats, &recorder, &mut ws_client_write, chaos.as_mut(), batches).await.is_err() { break; }
+    }
+
+    // Write the receiver task's replies to the client's pings and close frames.
+    _ = replies.queued() => {
+      let _busy = heartbeat.busy();
+      if let Err(err) = ws_client_write.write_replies().await {
+        log_warn!("[send_ws_client_messages] Failed to write replies to the client: {:?}", err);
+        break;
       }
-      Err(err) => {
-        println!("[send_ws_client_messages] Error sending msg to WS client: {:?}", err);
+    }
+
+    // Ping the client, if it's kept alive; unless it's missed too many pongs already, in which case it's taken for dead. (Missing fewer may make it unhealthy first.)
+    _ = pings.tick() => {
+      let _busy = heartbeat.busy();
+      if liveness.turned_unhealthy() {
+        report_health(&client_id, &cli_conn_tx, false);
       }
-    }}
+      if !liveness.ping_due() {
+        time_out(&client_id, &stats, &liveness, &mut ws_client_write).await;
+        break;
+      }
+      if let Err(err) = ws_client_write.write_messages(&[&Outbound::Message(Message::Ping(liveness.ping_payload()))]).await {
+        log_warn!("[send_ws_client_messages] Failed to ping the client: {:?}", err);
+        break;
+      }
+    }
+
+    // Disconnect the client if it's been idle too long (if it can idle out at all).
+    _ = liveness.idled_out() => {
+      idle_out(&client_id, &stats, &liveness, &mut ws_client_write).await;
+      break;
+    }
 
     // Receive a shutdown signal from the client receiver task, indicating the client sent a shutdown handshake.
     _ = ws_client_req_shutdown_rx.changed() => {
-      println!("[send_ws_client_messages] Received shutdown signal from the client receiver task; the client wants to disconnect. Resolving the shutdown handshake.");
-      let res = ws_client_write.close().await;
+      log_debug!("[send_ws_client_messages] Received shutdown signal from the client receiver task; the client wants to disconnect. Resolving the shutdown handshake.");
+      // (The receiver task queued the reply to the client's close frame before signalling.)
+      let res = ws_client_write.write_replies().await;
       if let Err(err) = res {
-        println!("[send_ws_client_messages] Error closing ws_client_write: {:?}", err);
+        log_warn!("[send_ws_client_messages] Error closing ws_client_write: {:?}", err);
       }
       break;
     }
@@ -163,49 +636,519 @@ async fn send_ws_client_messages(
     // Receive an exit signal and shutdown.
     _ = ser_req_shutdown_rx.changed() => {
       if *ser_req_shutdown_rx.borrow() {
-        println!("[send_ws_client_messages] Received shutdown signal.");
+        log_debug!("[send_ws_client_messages] Received shutdown signal. Sending close frame.");
+        let options = shutdown_options.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        liveness.closing(&format!("the server shut down ({} {:?})", options.close_code, options.close_reason));
+        close_for_shutdown(&client_id, &stats, options, &mut ws_client_write, &mut ws_client_req_shutdown_rx).await;
         break;
       }
     }
   }}
-  println!("[send_ws_client_messages] Client sender loop shutdown.")
+  // Whatever's still queued for the client won't be written now.
+  record_unsent(&client_id, &stats, *ser_req_shutdown_rx.borrow(), &mut server_msg_rx, &mut client_send_rx, &frames, link.as_mut());
+  log_debug!("[send_ws_client_messages] Client sender loop shutdown.")
+}
+
+/// Runs a closure when dropped: when the function holding it returns, or when its task is aborted.
+struct OnDrop<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for OnDrop<F> {
+  fn drop(&mut self) {
+    (self.0)()
+  }
+}
+
+/// Messages for a client: a broadcast, a send to it alone, or a frame.
+enum Batch {
+  Broadcast(Arc<Broadcast>),
+  Targeted(TargetedSend),
+  Frame(Arc<Frame>),
+}
+
+impl Batch {
+  fn messages(&self) -> &[Outbound] {
+    match self {
+      Batch::Broadcast(broadcast) => &broadcast.messages,
+      Batch::Targeted(targeted) => &targeted.messages,
+      Batch::Frame(frame) => std::slice::from_ref(&frame.message),
+    }
+  }
+
+  fn bytes(&self) -> usize {
+    self.messages().iter().map(Outbound::len).sum()
+  }
+
+  fn queued_at(&self) -> Instant {
+    match self {
+      Batch::Broadcast(broadcast) => broadcast.queued_at,
+      Batch::Targeted(targeted) => targeted.queued_at,
+      Batch::Frame(frame) => frame.queued_at,
+    }
+  }
+}
+
+/// Writes batches to a client, along with every other batch already queued for it (as many as the batcher lets a write take) and its pending frames, in a single vectored write and flush; then records (if a session recording's in progress) and confirms the targeted sends among them, and hands the messages back to the pool (a broadcast's once its last client has written it). Fails if the write did.
+///
+/// Under load, or if the server corks its writes (see batching.rs), the write is held back a moment first, for the batches arriving meanwhile to join it. A chaos testing server (see chaos.rs) also holds writes back at random, reorders their messages, and now and then drops the connection instead of writing. With an emulated link (see link.rs), the batches are put through it instead, and written as they come out of it.
+#[allow(clippy::too_many_arguments)]
+async fn forward(
+  client_id: &str,
+  stats: &ServerStats,
+  clients: &ClientRegistry,
+  recorder: &Recorder,
+  ws_client_write: &mut FrameWriter,
+  batcher: &mut Batcher,
+  mut chaos: Option<&mut ClientChaos>,
+  link: Option<&mut DelayLine<Batch>>,
+  mut batches: Vec<Batch>,
+  server_msg_rx: &mut BroadcastReceiver,
+  client_send_rx: &mut mpsc::Receiver<TargetedSend>,
+  frames: &FrameSlots,
+  keyframes: &mut KeyframeSync
+) -> Result<(), String> {
+  while batches.len() < batcher.limit() {
+    match server_msg_rx.try_recv() {
+      Some((msgs, missed)) => {
+        record_missed_broadcasts(client_id, stats, clients, missed);
+        let more = broadcast_batches(stats, keyframes, server_msg_rx, msgs);
+        batches.extend(more);
+      }
+      None => { break; }
+    }
+  }
+  while batches.len() < batcher.limit() {
+    match client_send_rx.try_recv() {
+      Ok(targeted) => { batches.push(Batch::Targeted(targeted)); }
+      Err(_) => { break; }
+    }
+  }
+  if let Some(link) = link {
+    batches.extend(frames.take().into_iter().map(Batch::Frame));
+    for batch in batches {
+      let (bytes, queued_at) = (batch.bytes(), batch.queued_at());
+      link.push(batch, bytes, queued_at);
+    }
+    return Ok(());
+  }
+  let delay = batcher.delay(batches.len()).max(chaos.as_mut().map_or(Duration::ZERO, |chaos| chaos.delay()));
+  if !delay.is_zero() {
+    // Taken as they arrive, so the client's queue doesn't fill up (and drop broadcasts) while the write waits.
+    let deadline = tokio::time::sleep(delay);
+    tokio::pin!(deadline);
+    while batches.len() < batcher.limit() {
+      tokio::select! {
+        _ = &mut deadline => { break; }
+        Some((msgs, missed)) = server_msg_rx.recv() => {
+          record_missed_broadcasts(client_id, stats, clients, missed);
+          let more = broadcast_batches(stats, keyframes, server_msg_rx, msgs);
+          batches.extend(more);
+        }
+        Some(targeted) = client_send_rx.recv() => { batches.push(Batch::Targeted(targeted)); }
+      }
+    }
+  }
+  batcher.wrote(batches.len());
+  // (Taken last, so they're the newest there are. They don't count towards the batcher's limit: there's at most one per topic.)
+  batches.extend(frames.take().into_iter().map(Batch::Frame));
+  // (Nothing, if all it was to write were keyframes or deltas the client didn't need.)
+  if batches.is_empty() { return Ok(()); }
+  write_batches(client_id, stats, recorder, ws_client_write, chaos, batches).await
+}
+
+/// What to write a client for a broadcast: the broadcast, or, for a keyframe or delta, what the client's keyframe streams need (see keyframes.rs), counting its catch-ups.
+fn broadcast_batches(stats: &ServerStats, keyframes: &mut KeyframeSync, server_msg_rx: &BroadcastReceiver, msgs: Arc<Broadcast>) -> Vec<Batch> {
+  let batches = keyframes.admit(msgs, server_msg_rx.keyframes()).into_iter().map(Batch::Broadcast).collect();
+  let resent = keyframes.take_resent();
+  if resent > 0 { stats.keyframes_resent(resent); }
+  batches
 }
 
+/// Writes batches to a client in a single vectored write and flush (see forward()).
+async fn write_batches(client_id: &str, stats: &ServerStats, recorder: &Recorder, ws_client_write: &mut FrameWriter, mut chaos: Option<&mut ClientChaos>, batches: Vec<Batch>) -> Result<(), String> {
+  let picked_up = Instant::now();
+  for batch in batches.iter() { stats.waited_in_channel(picked_up.saturating_duration_since(batch.queued_at()), batch.messages().len()); }
+  let mut msgs: Vec<&Outbound> = batches.iter().flat_map(Batch::messages).collect();
+  let dropped = chaos.as_mut().is_some_and(|chaos| chaos.drops_connection());
+  let res = if dropped {
+    log_info!("[send_ws_client_messages] Chaos testing: dropping client {}'s connection.", client_id);
+    Err("Connection dropped by chaos testing".to_string())
+  } else {
+    if let Some(chaos) = chaos { chaos.reorder(&mut msgs); }
+    write_messages(client_id, stats, ws_client_write, &msgs).await
+  };
+  for batch in batches {
+    match batch {
+      Batch::Broadcast(msgs) => { OUTBOUND.recycle_shared(msgs); }
+      Batch::Targeted(TargetedSend { messages, confirm, .. }) => {
+        // (Broadcasts are recorded once, as they're queued, rather than for each client.)
+        if res.is_ok() { recorder.outbound(client_id, &messages); }
+        // The consumer may have stopped waiting for confirmation; that's fine.
+        if let Some(confirm) = confirm { let _ = confirm.send(res.clone()); }
+        OUTBOUND.recycle_messages(messages);
+      }
+      Batch::Frame(frame) => {
+        // (Recorded for each client, as each is written frames of its own.)
+        if res.is_ok() { recorder.outbound(client_id, std::slice::from_ref(&frame.message)); }
+        frames::recycle(frame);
+      }
+    }
+  }
+  res
+}
+
+/// Counts the server messages a client missed by falling too far behind the broadcasts (if any), against it and in the stats, and records an error event for them.
+fn record_missed_broadcasts(client_id: &str, stats: &ServerStats, clients: &ClientRegistry, missed: u64) {
+  if missed == 0 { return; }
+  let total = clients.count_missed(client_id, missed);
+  log_warn!("[send_ws_client_messages] Client {} fell behind and missed {} server messages.", client_id, missed);
+  stats.messages_dropped(DropReason::Lagged, missed);
+  stats.record_error(Severity::Warning, Category::Send, format!("Fell behind and missed {} server messages ({} in all).", missed, total), Some(client_id.to_string()));
+}
+
+/// Counts, and records an event for, the messages still queued for a client whose sender task is done with it, which it will never be sent: those sent to it alone, and, if the server's shutting down, its broadcasts and frames too. (Broadcasts and frames pending for a client that's left were for whoever's connected; nobody missed them.) Targeted sends made from now on fail, as the client's gone.
+fn record_unsent(client_id: &str, stats: &ServerStats, shutting_down: bool, server_msg_rx: &mut BroadcastReceiver, client_send_rx: &mut mpsc::Receiver<TargetedSend>, frames: &FrameSlots, link: Option<&mut DelayLine<Batch>>) {
+  client_send_rx.close();
+  let mut unsent = link.map(|link| link.drain().collect::<Vec<_>>()).unwrap_or_default();
+  while let Some((msgs, _)) = server_msg_rx.try_recv() { unsent.push(Batch::Broadcast(msgs)); }
+  while let Ok(targeted) = client_send_rx.try_recv() { unsent.push(Batch::Targeted(targeted)); }
+  unsent.extend(frames.take_all().into_iter().map(Batch::Frame));
+  let (mut broadcast, mut targeted) = (0, 0);
+  for batch in unsent {
+    match batch {
+      Batch::Broadcast(msgs) => { broadcast += msgs.messages.len(); OUTBOUND.recycle_shared(msgs); }
+      Batch::Targeted(send) => { targeted += send.messages.len(); OUTBOUND.recycle_messages(send.messages); }
+      Batch::Frame(frame) => { broadcast += 1; frames::recycle(frame); }
+    }
+  }
+  let (reason, lost, why) = match shutting_down {
+    true => (DropReason::Shutdown, broadcast + targeted, "the server shut down first"),
+    false => (DropReason::Disconnected, targeted, "the client disconnected first"),
+  };
+  if lost == 0 { return; }
+  log_warn!("[send_ws_client_messages] {} message(s) queued for client {} were never written: {}.", lost, client_id, why);
+  stats.messages_dropped(reason, lost as u64);
+  stats.record_error(Severity::Warning, Category::Send, format!("{} message(s) queued for the client were never written: {}.", lost, why), Some(client_id.to_string()));
+}
+
+/// Writes and flushes messages to a client, counting them. On failure, records the error and returns a description of it; the connection should be assumed closed.
+async fn write_messages(client_id: &str, stats: &ServerStats, ws_client_write: &mut FrameWriter, msgs: &[&Outbound]) -> Result<(), String> {
+  let res = ws_client_write.write_messages(msgs).await;
+  if let Err(err) = res {
+    log_warn!("[send_ws_client_messages] Failed to write to ws_client_write. Assuming the connection has closed; terminating server forwarding task for this client.");
+    let err = format!("Failed to write to client: {}", err);
+    stats.record_error(Severity::Warning, Category::Send, err.clone(), Some(client_id.to_string()));
+    return Err(err);
+  }
+  for msg in msgs { stats.message_sent(msg.len()); }
+  Ok(())
+}
+
+/// Closes a client's connection for the server shutting down: sends it the shutdown's close frame (see Server::shutdown_with()), and waits for it to answer, which ends the receiver task; giving up on both after the shutdown's close timeout.
+async fn close_for_shutdown(client_id: &str, stats: &ServerStats, options: ShutdownOptions, ws_client_write: &mut FrameWriter, ws_client_req_shutdown_rx: &mut watch::Receiver::<()>) {
+  let close_frame = CloseFrame { code: options.close_code.into(), reason: options.close_reason.into() };
+  let closed = async {
+    // (Writes flush, so a success means the frame made it to the socket.)
+    let res = ws_client_write.write_messages(&[&Outbound::Message(Message::Close(Some(close_frame)))]).await;
+    if let Err(err) = res {
+      log_warn!("[send_ws_client_messages] Error sending close frame: {:?}", err);
+      return;
+    }
+    stats.close_frame_sent();
+    // (Resolves with an error once the receiver task has exited, which is how it signals an answer.)
+    let _ = ws_client_req_shutdown_rx.changed().await;
+  };
+  if tokio::time::timeout(options.close_timeout, closed).await.is_err() {
+    log_debug!("[send_ws_client_messages] Client {} didn't answer its close frame within {} ms; disconnecting it anyway.", client_id, options.close_timeout.as_millis());
+  }
+}
+
+/// Gives up on a client that hasn't answered its pings: counts and reports it, tells the receiver task, and sends the client a close frame, in case it's still there to read it (though not waiting longer than a ping interval for its socket to take it).
+async fn time_out(client_id: &str, stats: &ServerStats, liveness: &Liveness, ws_client_write: &mut FrameWriter) {
+  let keepalive = match liveness.keepalive() {
+    Some(keepalive) => keepalive,
+    None => { return; }
+  };
+  log_warn!("[send_ws_client_messages] Client {} didn't answer {} pings in a row; disconnecting it.", client_id, keepalive.max_missed_pongs);
+  stats.keepalive_timed_out();
+  stats.record_error(Severity::Warning, Category::Receive, format!("Disconnected after {} pings in a row went unanswered.", keepalive.max_missed_pongs), Some(client_id.to_string()));
+  liveness.closing(&format!("the server disconnected it after {} pings in a row went unanswered", keepalive.max_missed_pongs));
+  liveness.hang_up();
+  let close_frame = CloseFrame { code: CloseCode::Away, reason: "No answer to pings".into() };
+  let _ = tokio::time::timeout(keepalive.interval, ws_client_write.write_messages(&[&Outbound::Message(Message::Close(Some(close_frame)))])).await;
+}
+
+/// Reports a client marked unhealthy for leaving its pings unanswered, or healthy again for answering one (see keepalive.rs). Doesn't wait for room in the connection event queue: the client's tasks have the client to see to.
+fn report_health(client_id: &str, cli_conn_tx: &queue::Sender<ConnectionEvent>, healthy: bool) {
+  let change = if healthy { ConnectionChange::Healthy } else { ConnectionChange::Unhealthy };
+  if healthy {
+    log_info!("[tokio_server.rs] Client {} answered a ping again; it's healthy.", client_id);
+  } else {
+    log_warn!("[tokio_server.rs] Client {} hasn't answered its pings; marking it unhealthy.", client_id);
+  }
+  if cli_conn_tx.try_send(ConnectionEvent::new(client_id.to_string(), change)).is_err() {
+    log_debug!("[tokio_server.rs] Couldn't report client {} {}; the connection event queue is full.", client_id, change.as_str());
+  }
+}
+
+/// Disconnects a client that's been idle for its idle timeout: counts it, tells the receiver task, and sends the client a close frame (waiting for its socket to take it no longer than IDLE_CLOSE_TIMEOUT).
+async fn idle_out(client_id: &str, stats: &ServerStats, liveness: &Liveness, ws_client_write: &mut FrameWriter) {
+  let idle_timeout = liveness.idle_timeout().unwrap_or_default();
+  log_info!("[send_ws_client_messages] Client {} has been idle for {} ms; disconnecting it.", client_id, idle_timeout.as_millis());
+  stats.idle_timed_out();
+  liveness.closing(&format!("the server disconnected it after {} ms idle", idle_timeout.as_millis()));
+  liveness.hang_up();
+  let close_frame = CloseFrame { code: CloseCode::Away, reason: "Idle for too long".into() };
+  let _ = tokio::time::timeout(IDLE_CLOSE_TIMEOUT, ws_client_write.write_messages(&[&Outbound::Message(Message::Close(Some(close_frame)))])).await;
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn recv_ws_client_messages(
-  client_msg_tx: mpsc::Sender<tokio_tungstenite::tungstenite::Message>,
-  mut ws_client_read: SplitStream<WebSocketStream<TcpStream>>,
-  mut ser_req_shutdown_rx: watch::Receiver::<bool>,
-  ws_client_req_shutdown_tx: watch::Sender::<()>
+  client_id: String,
+  inspector: Option<Arc<Inspector>>,
+  recorder: Arc<Recorder>,
+  stats: Arc<ServerStats>,
+  notifier: Arc<MessageNotifier>,
+  cli_conn_tx: queue::Sender<ConnectionEvent>,
+  cli_ping_tx: Option<queue::Sender<PingEvent>>,
+  client_msg_tx: queue::Sender<ClientMessage>,
+  taps: Arc<MessageTaps>,
+  liveness: Arc<Liveness>,
+  audit: Option<Arc<Auditor>>,
+  time_sync: bool,
+  playback: Option<Arc<Playback>>,
+  inputs: Option<Arc<InputAggregator>>,
+  metrics_history: Option<Message>,
+  throttled_frames: Option<Arc<FrameSlots>>,
+  capabilities: Option<(CapabilityOffer, Arc<ClientRegistry>)>,
+  rosbridge: Option<Arc<ClientRegistry>>,
+  mut ws_client_read: ClientReader,
+  ser_req_shutdown_rx: watch::Receiver::<bool>,
+  ws_client_req_shutdown_tx: watch::Sender::<()>,
+  _task: Task
 ) {
+  let mut disconnection = Disconnection { client_id: client_id.clone(), inspector: inspector.clone(), stats: stats.clone(), cli_conn_tx, audit: audit.map(|audit| (audit, liveness.clone())), close: None };
+  // Clients offered capabilities are written the offer first thing (see capabilities.rs).
+  if let Some((offer, _)) = &capabilities {
+    if let Err(err) = ws_client_read.send(Message::Text(offer.message())).await {
+      log_warn!("[recv_ws_client_messages] Failed to write the capability offer: {:?}", err);
+    }
+  }
+  // And the metrics' history, if any's gone out before it subscribed (see metrics.rs).
+  if let Some(history) = metrics_history {
+    if let Err(err) = ws_client_read.send(history).await {
+      log_warn!("[recv_ws_client_messages] Failed to write the metrics' history: {:?}", err);
+    }
+  }
   loop { tokio::select! {
-    // Receive messages from connected clients and forward them to client message buffer.
-    read_res = ws_client_read.next() => { match read_res {
+    // Receive messages from connected clients and forward them to client message buffer. (Once it has room: a message that's been read goes straight in, so drains get everything received so far; see queue.rs.)
+    read_res = async { client_msg_tx.room().await; ws_client_read.next().await } => { match read_res {
       Some(Ok(msg)) => {
-        let res = client_msg_tx.send(msg).await;
-        if res.is_err() { println!("[recv_ws_client_messages] Failed to send client message to client msg buffer"); }
+        if let Some(inspector) = &inspector { inspector.record_inbound(&client_id, &msg); }
+        recorder.inbound(&client_id, &msg);
+        let ping = match &msg {
+          Message::Ping(payload) => Some((PingKind::Ping, payload)),
+          Message::Pong(payload) => {
+            // (Pongs answering the keepalive pings are the server's business.)
+            let answered_keepalive = liveness.ponged(payload);
+            if liveness.recovered() {
+              report_health(&client_id, &disconnection.cli_conn_tx, true);
+            }
+            (!answered_keepalive).then_some((PingKind::Pong, payload))
+          }
+          _ => None,
+        };
+        if let (Some(cli_ping_tx), Some((kind, payload))) = (&cli_ping_tx, ping) {
+          if cli_ping_tx.try_send(PingEvent::new(client_id.clone(), kind, payload.clone())).is_err() {
+            log_debug!("[recv_ws_client_messages] Dropped a {} from {}: the ping event queue is full.", kind.as_str(), client_id);
+          }
+        }
+        if let Message::Close(frame) = &msg { disconnection.close = Some(ClientClose::from_frame(frame.as_ref())); }
+        // Time sync requests are answered here and now, rather than passed on to the consumer (see clock.rs).
+        if let (true, Message::Text(text)) = (time_sync, &msg) {
+          if let Some(echo) = clock::time_sync_request(text) {
+            liveness.active();
+            let reply = clock::time_sync_reply(echo, Timestamp::now());
+            // (Queued with the pongs, for the sender task to write between its own frames.)
+            if let Err(err) = ws_client_read.send(Message::Text(reply)).await {
+              log_warn!("[recv_ws_client_messages] Failed to answer a time sync request: {:?}", err);
+            }
+            continue;
+          }
+        }
+        // So are throttle requests, which set the rate the client's written a topic's frames at (see frames.rs).
+        if let (Some(frames), Message::Text(text)) = (&throttled_frames, &msg) {
+          if let Some(request) = frames::throttle_request(text) {
+            liveness.active();
+            match request {
+              Ok((topic, rate)) => frames.throttle(topic, rate),
+              Err(err) => {
+                log_warn!("[recv_ws_client_messages] Ignored a malformed throttle request from {}: {}", client_id, err);
+                stats.record_error(Severity::Warning, Category::Receive, format!("Ignored a malformed throttle request: {}.", err), Some(client_id.clone()));
+              }
+            }
+            continue;
+          }
+        }
+        // So are playback controls, which are applied to the server's playback state (see playback.rs).
+        if let (Some(playback), Message::Text(text)) = (&playback, &msg) {
+          if let Some(control) = PlaybackControl::parse(text) {
+            liveness.active();
+            match control.map(|control| playback.apply(&client_id, control)) {
+              Ok(Ok(())) => {}
+              Ok(Err(err)) => log_debug!("[recv_ws_client_messages] Dropped a playback event from {}: {}.", client_id, err),
+              Err(err) => {
+                log_warn!("[recv_ws_client_messages] Ignored a malformed playback control from {}: {}", client_id, err);
+                stats.record_error(Severity::Warning, Category::Receive, format!("Ignored a malformed playback control: {}.", err), Some(client_id.clone()));
+              }
+            }
+            continue;
+          }
+        }
+        // So is input, which is merged into the clients' aggregated input (see inputs.rs).
+        if let (Some(inputs), Message::Text(text)) = (&inputs, &msg) {
+          if let Some(input) = inputs::parse(text) {
+            liveness.active();
+            if let Err(err) = input.and_then(|(name, value)| inputs.merge(&client_id, name, value)) {
+              log_warn!("[recv_ws_client_messages] Ignored a malformed input from {}: {}", client_id, err);
+              stats.record_error(Severity::Warning, Category::Receive, format!("Ignored a malformed input: {}.", err), Some(client_id.clone()));
+            }
+            continue;
+          }
+        }
+        // So are capability declarations, which are answered with the format negotiated, and stored with the client (see capabilities.rs).
+        if let (Some((offer, clients)), Message::Text(text)) = (&capabilities, &msg) {
+          if let Some(declared) = ClientCapabilities::parse(text, offer) {
+            liveness.active();
+            match declared {
+              Ok(declared) => {
+                let reply = declared.reply();
+                if clients.set_capabilities(&client_id, declared) && disconnection.cli_conn_tx.try_send(ConnectionEvent::new(client_id.clone(), ConnectionChange::Capabilities)).is_err() {
+                  log_debug!("[recv_ws_client_messages] Couldn't report client {}'s capabilities; the connection event queue is full.", client_id);
+                }
+                if let Err(err) = ws_client_read.send(Message::Text(reply)).await {
+                  log_warn!("[recv_ws_client_messages] Failed to answer a capability declaration: {:?}", err);
+                }
+              }
+              Err(err) => {
+                log_warn!("[recv_ws_client_messages] Ignored a malformed capability declaration from {}: {}", client_id, err);
+                stats.record_error(Severity::Warning, Category::Receive, format!("Ignored a malformed capability declaration: {}.", err), Some(client_id.clone()));
+              }
+            }
+            continue;
+          }
+        }
+        // So are rosbridge ops, which subscribe the client to topics, or are answered as ops the server doesn't do; bar publishes, which are passed on (see rosbridge.rs).
+        if let (Some(clients), Message::Text(text)) = (&rosbridge, &msg) {
+          if let Some(op) = rosbridge::Op::parse(text).filter(|op| !matches!(op, Ok(rosbridge::Op::Publish { .. }))) {
+            liveness.active();
+            let reply = match op {
+              Ok(rosbridge::Op::Subscribe { topic, id }) => { clients.ros_subscription(&client_id, &topic, id.as_deref(), true); None }
+              Ok(rosbridge::Op::Unsubscribe { topic, id }) => { clients.ros_subscription(&client_id, &topic, id.as_deref(), false); None }
+              Ok(op) => op.reply(),
+              Err(err) => {
+                log_warn!("[recv_ws_client_messages] Ignored a malformed rosbridge op from {}: {}", client_id, err);
+                stats.record_error(Severity::Warning, Category::Receive, format!("Ignored a malformed rosbridge op: {}.", err), Some(client_id.clone()));
+                Some(rosbridge::status(&err, &None))
+              }
+            };
+            if let Some(reply) = reply {
+              if let Err(err) = ws_client_read.send(Message::Text(reply)).await {
+                log_warn!("[recv_ws_client_messages] Failed to answer a rosbridge op: {:?}", err);
+              }
+            }
+            continue;
+          }
+        }
+        let mut client_msg = ClientMessage::new(client_id.clone(), msg);
+        if client_msg.is_data() {
+          liveness.active();
+          stats.message_received(client_msg.message.len());
+          // (Only data is charged: control frames, close frames included, always go through.)
+          match stats.memory().charge(client_msg.message.len(), 1) {
+            Ok(charge) => { client_msg = client_msg.with_charge(charge); }
+            Err(err) => {
+              log_warn!("[recv_ws_client_messages] Dropped a client message: {}", err);
+              stats.messages_dropped(DropReason::OverBudget, 1);
+              stats.record_error(Severity::Warning, Category::Receive, format!("Dropped a client message: {}.", err), Some(client_id.clone()));
+              continue;
+            }
+          }
+        }
+        taps.copy(&client_msg);
+        let res = client_msg_tx.push(client_msg);
+        notifier.notify();
+        if res.is_err() {
+          log_warn!("[recv_ws_client_messages] Failed to send client message to client msg buffer");
+          stats.messages_dropped(DropReason::Shutdown, 1);
+          stats.record_error(Severity::Warning, Category::Receive, "Dropped a client message: the client message buffer is closed.".to_string(), Some(client_id.clone()));
+        }
+        // While the server shuts down, the sender task waits for the client to answer its close frame: the close handshake is done. (Exiting is the signal.)
+        if disconnection.close.is_some() && *ser_req_shutdown_rx.borrow() {
+          log_debug!("[recv_ws_client_messages] The client answered the shutdown's close frame.");
+          stats.close_ack_received();
+          break;
+        }
       }
       Some(Err(err)) => {
-        println!("[recv_ws_client_messages] Error receiving msg from WS client: {:?}", err);
+        log_warn!("[recv_ws_client_messages] Error receiving msg from WS client: {:?}", err);
+        stats.record_error(Severity::Warning, Category::Receive, format!("Error receiving message from client: {}", err), Some(client_id.clone()));
       }
       None => {
-        println!("[recv_ws_client_messages] None received from ws_client_read.next(), connection stream must be closed. Sending notification to the sender task.");
+        log_debug!("[recv_ws_client_messages] None received from ws_client_read.next(), connection stream must be closed. Sending notification to the sender task.");
 
         // Send the shutdown signal to the sender-side task for this connection.
         let conn_shutdown_res = ws_client_req_shutdown_tx.send(());
         if let Err(err) = conn_shutdown_res {
-          println!("[recv_ws_client_messages] Error sending a shutdown signal to the sender-side task for the closed connection: {:?}", err)
+          log_warn!("[recv_ws_client_messages] Error sending a shutdown signal to the sender-side task for the closed connection: {:?}", err)
         }
         break;
       }
     }}
 
-    // Receive an exit signal and shutdown.
-    _ = ser_req_shutdown_rx.changed() => {
-      if *ser_req_shutdown_rx.borrow() {
-        println!("[send_ws_client_messages] Received shutdown signal.");
-        break;
-      }
+    // The sender task is done with the client: it gave up on it (for not answering its pings, or for being idle), it closed its connection for the server shutting down (and the client didn't answer in time), or it exited.
+    _ = liveness.hung_up() => {
+      log_debug!("[recv_ws_client_messages] The sender task hung up on the client.");
+      break;
     }
   }}
-  println!("[recv_ws_client_messages] Client receiver loop shutdown.")
+  if let Some(inputs) = &inputs { inputs.forget(&client_id); }
+  log_debug!("[recv_ws_client_messages] Client receiver loop shutdown.")
+}
+
+/// Counts and reports a client's disconnection once its receiver task is done, however it stops: when it returns, or when the shutdown aborts it.
+struct Disconnection {
+  client_id: String,
+  inspector: Option<Arc<Inspector>>,
+  stats: Arc<ServerStats>,
+  cli_conn_tx: queue::Sender<ConnectionEvent>,
+  /// The audit log, if there is one, with the client's Liveness, which says whether the server closed the connection.
+  audit: Option<(Arc<Auditor>, Arc<Liveness>)>,
+  /// The client's close frame, if it sent one, for its disconnection event. (tungstenite answers it, and ends the stream.)
+  close: Option<ClientClose>,
+}
+
+impl Drop for Disconnection {
+  fn drop(&mut self) {
+    if let Some(inspector) = &self.inspector { inspector.client_disconnected(&self.client_id); }
+    let message = match &self.close {
+      Some(close) => format!("Client disconnected, with close code {} ({:?}).", close.code, close.reason),
+      None => "Client disconnected without sending a close frame.".to_string(),
+    };
+    event_log::record(Level::Info, Kind::Disconnect, message, Some(self.client_id.clone()));
+    if let Some((audit, liveness)) = &self.audit {
+      // (A client answering the server's close frame sends one too, but it's the server's reason that counts.)
+      let reason = match (liveness.server_close(), &self.close) {
+        (Some(reason), _) => reason,
+        (None, Some(close)) => format!("the client closed the connection ({} {:?})", close.code, close.reason),
+        (None, None) => "the connection was lost".to_string(),
+      };
+      audit.closed(&self.client_id, reason);
+    }
+    // Unlike connection events, this doesn't wait for room in the queue: a consumer that never drains it mustn't hold up the shutdown of its connections.
+    if self.cli_conn_tx.try_send(ConnectionEvent::new(self.client_id.clone(), ConnectionChange::Disconnected).with_close(self.close.take())).is_err() {
+      log_debug!("[recv_ws_client_messages] Couldn't report the client disconnecting to the consumer; its connection event queue is full.");
+    }
+    // (After the event, so a draining server's Drained event comes after it.)
+    self.stats.client_disconnected();
+  }
 }
@@ -0,0 +1,321 @@
+// transport.rs
+//
+// Where connections come from: a TCP listener on the server's port (or, with the "uring" feature, one whose sockets go through io_uring; see uring.rs); for tests, an in-memory loopback that hands the server one end of an in-process pipe for each LoopbackClient; or, with the "tower" feature, another HTTP server's upgraded connections (see service.rs). Either way the connection goes through the same routing, handshake, and client tasks, so a loopback test exercises the whole pipeline without opening a socket (and without racing other tests for ports).
+
+use std::{io::{self, IoSlice}, pin::Pin, sync::atomic::{AtomicU64, Ordering}, task::{Context, Poll}, time::Duration};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf}, net::{TcpListener, TcpStream}, sync::{mpsc, oneshot}};
+use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
+
+use super::{Error, consumer_state as cs};
+#[cfg(feature = "tower")]
+use super::{clients::BroadcastReceiver, service::PendingUpgrade};
+#[cfg(feature = "uring")]
+use super::uring::{UringListener, UringStream};
+
+/// Capacity of each direction of a loopback pipe. Writers wait once it's full, like a socket with a full send buffer.
+const LOOPBACK_PIPE_LEN: usize = 64 * 1024;
+/// How long a LoopbackClient waits for the server to accept its websocket handshake.
+const LOOPBACK_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which transport a server accepts connections on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+  /// Listen on the server's port (on 127.0.0.1).
+  #[default]
+  Tcp,
+  /// Don't listen anywhere; connections come from Server::connect_loopback(). The port is only a label.
+  Loopback,
+  /// Don't listen anywhere; connections come from the WebSocketService of Server::service(), mounted in another HTTP server. The port is only a label.
+  #[cfg(feature = "tower")]
+  Service,
+  /// Listen on the server's port, like Tcp, but accept, read and write through io_uring (Linux only). The server fails to start, as if the port couldn't be bound, if io_uring isn't available.
+  #[cfg(feature = "uring")]
+  Uring,
+}
+
+impl Transport {
+  /// Whether the server listens on its port, so it can be connected to from outside the process.
+  pub fn binds_port(&self) -> bool {
+    match self {
+      Transport::Tcp => true,
+      Transport::Loopback => false,
+      #[cfg(feature = "tower")]
+      Transport::Service => false,
+      #[cfg(feature = "uring")]
+      Transport::Uring => true,
+    }
+  }
+}
+
+/// A connection's byte stream.
+pub enum Connection {
+  Tcp(TcpStream),
+  /// One end of a loopback pipe, with the bytes peek() has read ahead (and read returns first), and the LoopbackClient to tell once it's registered.
+  Loopback { pipe: DuplexStream, peeked: Vec<u8>, registered: Option<oneshot::Sender<()>> },
+  /// A connection another HTTP server has already upgraded, past its handshake, with the subscription to the broadcasts the service took for it, until the server takes it.
+  #[cfg(feature = "tower")]
+  Service { upgraded: hyper::upgrade::Upgraded, subscription: Option<(BroadcastReceiver, Option<Message>)> },
+  #[cfg(feature = "uring")]
+  Uring(UringStream),
+}
+
+impl Connection {
+  /// Called once the connection's client has been reported and registered for targeted sends, so a LoopbackClient's handshake only completes once the server can route to it.
+  pub fn client_registered(&mut self) {
+    if let Connection::Loopback { registered, .. } = self {
+      if let Some(registered) = registered.take() { let _ = registered.send(()); }
+    }
+  }
+
+  /// Reads data into `buf` without consuming it, waiting for some if none is available, like TcpStream::peek(). Returns 0 once the peer has closed the stream and nothing is buffered.
+  pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      Connection::Tcp(stream) => stream.peek(buf).await,
+      Connection::Loopback { pipe, peeked, .. } => {
+        if peeked.len() < buf.len() {
+          let mut more = vec![0u8; buf.len() - peeked.len()];
+          // Wait for data only if there's none, as TcpStream::peek() does; otherwise just take what's there. (Reads are cancel-safe, so dropping one unfinished loses nothing.)
+          let read = if peeked.is_empty() { Some(pipe.read(&mut more).await) } else { pipe.read(&mut more).now_or_never() };
+          if let Some(read) = read {
+            let len = read?;
+            peeked.extend_from_slice(&more[..len]);
+          }
+        }
+        let len = peeked.len().min(buf.len());
+        buf[..len].copy_from_slice(&peeked[..len]);
+        Ok(len)
+      }
+      // (Its request head was read by the server that upgraded it.)
+      #[cfg(feature = "tower")]
+      Connection::Service { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "upgraded connections can't be peeked")),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => stream.peek(buf).await,
+    }
+  }
+}
+
+impl AsyncRead for Connection {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+      Connection::Loopback { pipe, peeked, .. } => {
+        if !peeked.is_empty() {
+          let len = peeked.len().min(buf.remaining());
+          buf.put_slice(&peeked[..len]);
+          peeked.drain(..len);
+          return Poll::Ready(Ok(()));
+        }
+        Pin::new(pipe).poll_read(cx, buf)
+      }
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => Pin::new(upgraded).poll_read(cx, buf),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => Pin::new(stream).poll_read(cx, buf),
+    }
+  }
+}
+
+impl AsyncWrite for Connection {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+      Connection::Loopback { pipe, .. } => Pin::new(pipe).poll_write(cx, buf),
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => Pin::new(upgraded).poll_write(cx, buf),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => Pin::new(stream).poll_write(cx, buf),
+    }
+  }
+
+  // (Forwarded, so client writers' vectored writes stay vectored; see writer.rs.)
+  fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+      Connection::Loopback { pipe, .. } => Pin::new(pipe).poll_write_vectored(cx, bufs),
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => Pin::new(upgraded).poll_write_vectored(cx, bufs),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+    }
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    match self {
+      Connection::Tcp(stream) => stream.is_write_vectored(),
+      Connection::Loopback { pipe, .. } => pipe.is_write_vectored(),
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => upgraded.is_write_vectored(),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => stream.is_write_vectored(),
+    }
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+      Connection::Loopback { pipe, .. } => Pin::new(pipe).poll_flush(cx),
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => Pin::new(upgraded).poll_flush(cx),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => Pin::new(stream).poll_flush(cx),
+    }
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    match self.get_mut() {
+      Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+      Connection::Loopback { pipe, .. } => Pin::new(pipe).poll_shutdown(cx),
+      #[cfg(feature = "tower")]
+      Connection::Service { upgraded, .. } => Pin::new(upgraded).poll_shutdown(cx),
+      #[cfg(feature = "uring")]
+      Connection::Uring(stream) => Pin::new(stream).poll_shutdown(cx),
+    }
+  }
+}
+
+/// A loopback connection waiting to be accepted: the server's end of the pipe, the client id it was given, and the client's registration signal.
+pub struct PendingLoopback {
+  pipe: DuplexStream,
+  client_id: String,
+  registered: oneshot::Sender<()>,
+}
+
+/// Accepts connections for the server's tokio thread.
+pub enum Listener {
+  Tcp(TcpListener),
+  Loopback(mpsc::UnboundedReceiver<PendingLoopback>),
+  #[cfg(feature = "tower")]
+  Service(mpsc::UnboundedReceiver<PendingUpgrade>),
+  #[cfg(feature = "uring")]
+  Uring(UringListener),
+}
+
+impl Listener {
+  /// The next connection, and the id of its client (the peer address, for TCP).
+  pub async fn accept(&mut self) -> io::Result<(Connection, String)> {
+    match self {
+      Listener::Tcp(listener) => {
+        let (stream, peer) = listener.accept().await?;
+        Ok((Connection::Tcp(stream), peer.to_string()))
+      }
+      Listener::Loopback(pending_rx) => match pending_rx.recv().await {
+        Some(pending) => Ok((Connection::Loopback { pipe: pending.pipe, peeked: vec![], registered: Some(pending.registered) }, pending.client_id)),
+        // The connector lives as long as the server state, so this doesn't happen while anyone could connect.
+        None => std::future::pending().await,
+      },
+      #[cfg(feature = "tower")]
+      Listener::Service(pending_rx) => match pending_rx.recv().await {
+        Some(pending) => Ok((Connection::Service { upgraded: pending.upgraded, subscription: Some(pending.subscription) }, pending.client_id)),
+        // (Likewise for the service connector.)
+        None => std::future::pending().await,
+      },
+      #[cfg(feature = "uring")]
+      Listener::Uring(listener) => {
+        let (stream, peer) = listener.accept().await?;
+        Ok((Connection::Uring(stream), peer.to_string()))
+      }
+    }
+  }
+
+  /// The address the listener is bound to: None if it doesn't listen on a port.
+  pub fn local_addr(&self) -> Option<io::Result<std::net::SocketAddr>> {
+    match self {
+      Listener::Tcp(listener) => Some(listener.local_addr()),
+      #[cfg(feature = "uring")]
+      Listener::Uring(listener) => Some(listener.local_addr()),
+      _ => None,
+    }
+  }
+}
+
+/// The consumer's side of a loopback listener: makes pipes and queues the server's ends for it to accept.
+pub struct LoopbackConnector {
+  pending_tx: mpsc::UnboundedSender<PendingLoopback>,
+  next_client: AtomicU64,
+}
+
+impl LoopbackConnector {
+  /// A connector, and the receiver its listener accepts from.
+  pub fn new() -> (LoopbackConnector, mpsc::UnboundedReceiver<PendingLoopback>) {
+    let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+    (LoopbackConnector { pending_tx, next_client: AtomicU64::new(1) }, pending_rx)
+  }
+
+  /// Queues a new connection for the server, returning the client's end of its pipe, its client id (e.g. "loopback-1"), and a receiver that resolves once the server has registered the client.
+  pub(crate) fn connect(&self) -> Result<(DuplexStream, String, oneshot::Receiver<()>), Error> {
+    let client_id = format!("loopback-{}", self.next_client.fetch_add(1, Ordering::Relaxed));
+    let (client_end, server_end) = tokio::io::duplex(LOOPBACK_PIPE_LEN);
+    let (registered_tx, registered_rx) = oneshot::channel();
+    self.pending_tx.send(PendingLoopback { pipe: server_end, client_id: client_id.clone(), registered: registered_tx }).map_err(|_| Error::NotRunning)?;
+    Ok((client_end, client_id, registered_rx))
+  }
+}
+
+/// A websocket client connected to a loopback server (see Server::connect_loopback()), for tests. Its methods block the calling thread, like the rest of the Rust API.
+pub struct LoopbackClient {
+  client_id: String,
+  ws: WebSocketStream<DuplexStream>,
+}
+
+impl LoopbackClient {
+  /// Completes the websocket handshake over a pipe from LoopbackConnector::connect() (which needs the server to be running), then waits for the server to register the client, so sends to it are routed by the time this returns.
+  pub(crate) fn handshake(pipe: DuplexStream, client_id: String, registered: oneshot::Receiver<()>) -> Result<LoopbackClient, Error> {
+    let handshake = cs::block_on(async {
+      tokio::time::timeout(LOOPBACK_HANDSHAKE_TIMEOUT, async {
+        let (ws, _) = tokio_tungstenite::client_async("ws://loopback/", pipe).await?;
+        // Dropped unsent if the server gave up on the connection, e.g. because it's shutting down.
+        Ok::<_, tokio_tungstenite::tungstenite::Error>(registered.await.ok().map(|_| ws))
+      }).await
+    });
+    match handshake {
+      Ok(Ok(Some(ws))) => Ok(LoopbackClient { client_id, ws }),
+      Ok(Ok(None)) => Err(Error::NotRunning),
+      Ok(Err(err)) => Err(Error::Internal(format!("The loopback websocket handshake failed: {}", err))),
+      Err(_) => Err(Error::Internal("Timed out waiting for the server to accept the loopback connection; is it running?".to_string())),
+    }
+  }
+
+  /// The id the server knows this client by, as in its connection events and messages.
+  pub fn client_id(&self) -> &str {
+    &self.client_id
+  }
+
+  /// Sends messages to the server, in order, flushing after the last.
+  pub fn send(&mut self, messages: Vec<Message>) -> Result<(), Error> {
+    let message_count = messages.len();
+    let ws = &mut self.ws;
+    cs::block_on(async move {
+      for msg in messages { ws.feed(msg).await?; }
+      ws.flush().await
+    }).map_err(|err| Error::Send { reason: err.to_string(), message_count })
+  }
+
+  /// Waits for the next text or binary message from the server, for at most `timeout` if given, returning None on timeout. Fails with Error::NotRunning once the connection is closed (e.g. because the server shut down).
+  pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Option<Message>, Error> {
+    let ws = &mut self.ws;
+    // Pings are answered by the websocket stream itself while it reads.
+    let next = async move {
+      loop {
+        match ws.next().await {
+          Some(Ok(msg)) if msg.is_text() || msg.is_binary() => break Ok(msg),
+          Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break Err(Error::NotRunning),
+          Some(Ok(_)) => {}
+        }
+      }
+    };
+    match timeout {
+      Some(timeout) => cs::block_on(async { tokio::time::timeout(timeout, next).await.ok().transpose() }),
+      None => cs::block_on(next).map(Some),
+    }
+  }
+
+  /// Closes the connection, as a well-behaved client would: sends a close frame and waits (briefly) for the server's. Closing a closed connection does nothing.
+  pub fn close(&mut self) {
+    let ws = &mut self.ws;
+    cs::block_on(async move {
+      if ws.close(None).await.is_err() { return; }
+      let _ = tokio::time::timeout(LOOPBACK_HANDSHAKE_TIMEOUT, async { while let Some(Ok(_)) = ws.next().await {} }).await;
+    });
+  }
+}
@@ -0,0 +1,547 @@
+// uring.rs
+//
+// The io_uring transport (behind the "uring" feature; Linux 5.6 or later): a listener and connections whose accepts, reads and writes go through an io_uring rather than tokio's readiness polling. One ring serves every uring server in the process, driven by a thread of its own: the servers' tasks queue operations for it and wait for their completions, and it submits whatever has been queued and reaps whatever has completed in one io_uring_enter call, so thousands of busy connections cost a handful of syscalls per round rather than a poll and a read or write each.
+//
+// Like the other bridges, it speaks the interface itself (the ring's layout and opcodes, through libc's raw syscalls), so it needs neither liburing nor tokio-uring's runtime. Everything above the byte stream, from routing and handshakes to the client tasks, is the same as for TCP.
+//
+// The kernel reads and writes an operation's buffer until it completes, however long after the task that queued it has gone away, so the buffers belong to Slots the driver keeps a reference to while their operation is in flight. Writes are copied into their slot's buffer (and count as written from then on; flushing waits for them), reads land in their slot's and are copied out from there.
+
+use std::{collections::{HashMap, VecDeque}, io::{self, IoSlice}, mem, net::{SocketAddr, TcpListener, TcpStream}, os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd}, pin::Pin, ptr, sync::{Arc, Mutex, MutexGuard, Weak, atomic::{AtomicBool, AtomicU32, Ordering}}, task::{Context, Poll, Waker}, thread};
+use futures_util::future::poll_fn;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Submission queue entries; the completion queue gets twice as many. Only bounds how many operations are submitted per round, not how many can be in flight.
+const RING_ENTRIES: u32 = 1024;
+/// Size of a connection's read buffer (it grows if a peek wants more).
+const READ_BUF_LEN: usize = 16 * 1024;
+/// Most bytes a single write copies into a connection's write buffer.
+const MAX_WRITE_LEN: usize = 256 * 1024;
+
+// The parts of the io_uring ABI (linux/io_uring.h) used here.
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_ACCEPT: u8 = 13;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_SEND: u8 = 26;
+const IORING_OP_RECV: u8 = 27;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets { head: u32, tail: u32, ring_mask: u32, ring_entries: u32, flags: u32, dropped: u32, array: u32, resv1: u32, user_addr: u64 }
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets { head: u32, tail: u32, ring_mask: u32, ring_entries: u32, overflow: u32, cqes: u32, flags: u32, resv1: u32, user_addr: u64 }
+
+#[repr(C)]
+#[derive(Default)]
+struct Params { sq_entries: u32, cq_entries: u32, flags: u32, sq_thread_cpu: u32, sq_thread_idle: u32, features: u32, wq_fd: u32, resv: [u32; 3], sq_off: SqRingOffsets, cq_off: CqRingOffsets }
+
+/// A submission queue entry. (`off` doubles as addr2, e.g. accept's address length; `op_flags` as the op's flags, e.g. send's msg_flags.)
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Sqe { opcode: u8, flags: u8, ioprio: u16, fd: i32, off: u64, addr: u64, len: u32, op_flags: u32, user_data: u64, buf_index: u16, personality: u16, splice_fd_in: i32, addr3: u64, pad: u64 }
+
+/// A completion queue entry.
+#[repr(C)]
+struct Cqe { user_data: u64, res: i32, flags: u32 }
+
+/// The ring's shared memory: the queues' heads and tails, and their entries. Only the driver thread touches it.
+struct Ring {
+  fd: RawFd,
+  sq_head: *const AtomicU32,
+  sq_tail: *const AtomicU32,
+  sq_mask: u32,
+  sq_entries: u32,
+  sq_array: *mut u32,
+  sqes: *mut Sqe,
+  cq_head: *const AtomicU32,
+  cq_tail: *const AtomicU32,
+  cq_mask: u32,
+  cqes: *const Cqe,
+  /// Entries written since the last io_uring_enter.
+  unsubmitted: u32,
+}
+
+// (The pointers are into the ring's mapping, which lives as long as the process.)
+unsafe impl Send for Ring {}
+
+impl Ring {
+  fn new(entries: u32) -> io::Result<Ring> {
+    let mut params = Params::default();
+    let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params as *mut Params) };
+    if fd < 0 { return Err(io::Error::last_os_error()); }
+    let fd = fd as RawFd;
+    let map = |len: usize, offset: libc::off_t| {
+      let addr = unsafe { libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE, fd, offset) };
+      if addr == libc::MAP_FAILED { Err(io::Error::last_os_error()) } else { Ok(addr as *mut u8) }
+    };
+    let mapped = map(params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>(), IORING_OFF_SQ_RING).and_then(|sq| {
+      let cq = map(params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>(), IORING_OFF_CQ_RING)?;
+      let sqes = map(params.sq_entries as usize * mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+      Ok((sq, cq, sqes))
+    });
+    let (sq, cq, sqes) = match mapped {
+      Ok(mapped) => mapped,
+      Err(err) => {
+        unsafe { libc::close(fd); }
+        return Err(err);
+      }
+    };
+    unsafe {
+      Ok(Ring {
+        fd,
+        sq_head: sq.add(params.sq_off.head as usize) as *const AtomicU32,
+        sq_tail: sq.add(params.sq_off.tail as usize) as *const AtomicU32,
+        sq_mask: *(sq.add(params.sq_off.ring_mask as usize) as *const u32),
+        sq_entries: params.sq_entries,
+        sq_array: sq.add(params.sq_off.array as usize) as *mut u32,
+        sqes: sqes as *mut Sqe,
+        cq_head: cq.add(params.cq_off.head as usize) as *const AtomicU32,
+        cq_tail: cq.add(params.cq_off.tail as usize) as *const AtomicU32,
+        cq_mask: *(cq.add(params.cq_off.ring_mask as usize) as *const u32),
+        cqes: cq.add(params.cq_off.cqes as usize) as *const Cqe,
+        unsubmitted: 0,
+      })
+    }
+  }
+
+  fn has_room(&self) -> bool {
+    let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+    let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+    tail.wrapping_sub(head) < self.sq_entries
+  }
+
+  /// Writes an entry to the submission queue, which must have room for it.
+  fn push(&mut self, sqe: Sqe) {
+    unsafe {
+      let tail = (*self.sq_tail).load(Ordering::Relaxed);
+      let index = tail & self.sq_mask;
+      *self.sqes.add(index as usize) = sqe;
+      *self.sq_array.add(index as usize) = index;
+      (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+    }
+    self.unsubmitted += 1;
+  }
+
+  /// Submits the entries written since last time, and waits for at least one completion (which may already be there).
+  fn submit_and_wait(&mut self) -> io::Result<()> {
+    let submitted = unsafe { libc::syscall(libc::SYS_io_uring_enter, self.fd, self.unsubmitted, 1u32, IORING_ENTER_GETEVENTS, ptr::null::<libc::sigset_t>(), 0usize) };
+    if submitted < 0 {
+      let err = io::Error::last_os_error();
+      // (Interrupted, or out of room for completions until some are reaped: either way, reap and try again.)
+      return match err.raw_os_error() {
+        Some(libc::EINTR) | Some(libc::EBUSY) | Some(libc::EAGAIN) => Ok(()),
+        _ => Err(err),
+      };
+    }
+    self.unsubmitted -= submitted as u32;
+    Ok(())
+  }
+
+  /// The next completion, as its operation's key and result.
+  fn pop(&mut self) -> Option<(u64, i32)> {
+    unsafe {
+      let head = (*self.cq_head).load(Ordering::Relaxed);
+      if head == (*self.cq_tail).load(Ordering::Acquire) { return None; }
+      let cqe = &*self.cqes.add((head & self.cq_mask) as usize);
+      let completion = (cqe.user_data, cqe.res);
+      (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+      Some(completion)
+    }
+  }
+}
+
+/// One direction of a connection (or a listener's accepts): its buffer, and the state of the operation on it. At most one operation is in flight per slot.
+#[derive(Default)]
+struct Slot {
+  state: Mutex<SlotState>,
+}
+
+#[derive(Default)]
+struct SlotState {
+  buf: Vec<u8>,
+  /// The part of `buf` that's data: read and not yet consumed, or to be written and not yet sent.
+  start: usize,
+  end: usize,
+  in_flight: bool,
+  /// A read returned 0: the peer has closed its side.
+  eof: bool,
+  /// The failure of the last operation, for the next poll to return.
+  error: Option<io::Error>,
+  /// A listener's accepted connection, not yet taken.
+  accepted: Option<RawFd>,
+  waker: Option<Waker>,
+}
+
+impl Slot {
+  fn lock(&self) -> MutexGuard<'_, SlotState> {
+    // (Nothing panics while holding the lock, so it doesn't get poisoned.)
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Applies an operation's result and wakes whoever's waiting for it.
+  fn complete(&self, apply: impl FnOnce(&mut SlotState)) {
+    let waker = {
+      let mut state = self.lock();
+      apply(&mut state);
+      state.in_flight = false;
+      state.waker.take()
+    };
+    if let Some(waker) = waker { waker.wake(); }
+  }
+}
+
+/// An operation for the driver to submit.
+enum Op {
+  Accept { fd: RawFd, slot: Arc<Slot> },
+  /// Reads into the free end of the slot's buffer.
+  Recv { fd: RawFd, slot: Arc<Slot> },
+  /// Sends the slot's data, resubmitting itself until all of it has been sent.
+  Send { fd: RawFd, slot: Arc<Slot> },
+  Close { fd: RawFd },
+  /// Cancels the operations in flight on the slot. (Weak, so that it doesn't count as a user of the slot, and the slot can't be freed and another allocated in its place meanwhile.)
+  Cancel { slot: Weak<Slot> },
+}
+
+/// An operation the kernel has, and what to do with its result.
+enum InFlight {
+  Accept(Arc<Slot>),
+  Recv(Arc<Slot>),
+  Send(Arc<Slot>),
+  /// Closes and cancellations: nothing to do.
+  Other,
+}
+
+impl InFlight {
+  /// Whether it's an operation on the slot.
+  fn is_on(&self, slot: &Weak<Slot>) -> bool {
+    match self {
+      InFlight::Accept(op_slot) | InFlight::Recv(op_slot) | InFlight::Send(op_slot) => ptr::eq(Arc::as_ptr(op_slot), slot.as_ptr()),
+      InFlight::Other => false,
+    }
+  }
+}
+
+/// The key of the driver's own read of its eventfd.
+const WAKEUP_KEY: u64 = 0;
+
+/// The tasks' side of the driver: its queue of operations, and the eventfd that wakes it up for them.
+pub struct Driver {
+  queue: Mutex<VecDeque<Op>>,
+  /// Whether the eventfd has been written since the driver last took the queue, so submitters write it at most once per round.
+  notified: AtomicBool,
+  eventfd: RawFd,
+}
+
+lazy_static! {
+  /// The process's ring, started along with the first uring listener. An Err (the reason) if io_uring isn't available: too old a kernel, or a sandbox that forbids it.
+  static ref DRIVER: Result<Arc<Driver>, String> = Driver::start();
+}
+
+impl Driver {
+  fn start() -> Result<Arc<Driver>, String> {
+    let ring = Ring::new(RING_ENTRIES).map_err(|err| format!("io_uring isn't available: {}", err))?;
+    let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if eventfd < 0 { return Err(format!("Failed to create the io_uring driver's eventfd: {}", io::Error::last_os_error())); }
+    let driver = Arc::new(Driver { queue: Mutex::new(VecDeque::new()), notified: AtomicBool::new(false), eventfd });
+    let run = driver.clone();
+    thread::Builder::new().name("quicksocket-uring".to_string()).spawn(move || run.run(ring))
+      .map_err(|err| format!("Failed to start the io_uring driver thread: {}", err))?;
+    Ok(driver)
+  }
+
+  fn submit(&self, op: Op) {
+    self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push_back(op);
+    if !self.notified.swap(true, Ordering::AcqRel) {
+      let one = 1u64;
+      unsafe { libc::write(self.eventfd, &one as *const u64 as *const libc::c_void, mem::size_of::<u64>()); }
+    }
+  }
+
+  /// The driver thread: submits queued operations and completes finished ones, for as long as the process runs.
+  fn run(&self, mut ring: Ring) {
+    let mut in_flight: HashMap<u64, (RawFd, InFlight)> = HashMap::new();
+    let mut next_key = WAKEUP_KEY + 1;
+    // Operations taken from the queue that don't fit in the submission queue yet, and cancellations to submit.
+    let mut backlog: VecDeque<Op> = VecDeque::new();
+    let mut cancels: Vec<u64> = vec![];
+    // (Read into by the wakeup read, which is always in flight or about to be.)
+    let mut wakeup_count = Box::new(0u64);
+    let mut wakeup_armed = false;
+
+    loop {
+      if !wakeup_armed && ring.has_room() {
+        ring.push(Sqe { opcode: IORING_OP_READ, fd: self.eventfd, addr: &mut *wakeup_count as *mut u64 as u64, len: mem::size_of::<u64>() as u32, user_data: WAKEUP_KEY, ..Default::default() });
+        wakeup_armed = true;
+      }
+      // (Cleared before taking the queue, so an operation queued after this wakes the ring again.)
+      self.notified.store(false, Ordering::Release);
+      backlog.extend(self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).drain(..));
+
+      while ring.has_room() {
+        if let Some(target) = cancels.pop() {
+          ring.push(Sqe { opcode: IORING_OP_ASYNC_CANCEL, fd: -1, addr: target, user_data: next_key, ..Default::default() });
+          in_flight.insert(next_key, (-1, InFlight::Other));
+          next_key += 1;
+          continue;
+        }
+        let op = match backlog.pop_front() { Some(op) => op, None => break };
+        let key = next_key;
+        next_key += 1;
+        let (fd, sqe, op) = match op {
+          Op::Accept { fd, slot } => (fd, Sqe { opcode: IORING_OP_ACCEPT, fd, op_flags: libc::SOCK_CLOEXEC as u32, ..Default::default() }, InFlight::Accept(slot)),
+          Op::Recv { fd, slot } => {
+            let sqe = {
+              let mut state = slot.lock();
+              let end = state.end;
+              let free = &mut state.buf[end..];
+              Sqe { opcode: IORING_OP_RECV, fd, addr: free.as_mut_ptr() as u64, len: free.len() as u32, ..Default::default() }
+            };
+            (fd, sqe, InFlight::Recv(slot))
+          }
+          Op::Send { fd, slot } => {
+            let sqe = {
+              let state = slot.lock();
+              let data = &state.buf[state.start..state.end];
+              Sqe { opcode: IORING_OP_SEND, fd, addr: data.as_ptr() as u64, len: data.len() as u32, op_flags: libc::MSG_NOSIGNAL as u32, ..Default::default() }
+            };
+            (fd, sqe, InFlight::Send(slot))
+          }
+          Op::Close { fd } => (fd, Sqe { opcode: IORING_OP_CLOSE, fd, ..Default::default() }, InFlight::Other),
+          Op::Cancel { slot } => {
+            cancels.extend(in_flight.iter().filter(|(_, (_, op))| op.is_on(&slot)).map(|(key, _)| *key));
+            continue;
+          }
+        };
+        ring.push(Sqe { user_data: key, ..sqe });
+        in_flight.insert(key, (fd, op));
+      }
+
+      if let Err(err) = ring.submit_and_wait() {
+        log_error!("[uring] The io_uring driver failed, so uring connections will stall: {}", err);
+        return;
+      }
+
+      while let Some((key, res)) = ring.pop() {
+        if key == WAKEUP_KEY {
+          wakeup_armed = false;
+          continue;
+        }
+        let (fd, op) = match in_flight.remove(&key) { Some(op) => op, None => continue };
+        match op {
+          InFlight::Accept(slot) => {
+            // Nobody's listening any more (the listener was dropped while this was in flight).
+            if res >= 0 && Arc::strong_count(&slot) == 1 {
+              unsafe { libc::close(res); }
+              continue;
+            }
+            slot.complete(|state| if res >= 0 { state.accepted = Some(res) } else { state.error = Some(io::Error::from_raw_os_error(-res)) });
+          }
+          InFlight::Recv(slot) => slot.complete(|state| match res {
+            0 => { state.eof = true; }
+            len if len > 0 => { state.end += len as usize; }
+            _ => { state.error = Some(io::Error::from_raw_os_error(-res)); }
+          }),
+          InFlight::Send(slot) => {
+            let unsent = {
+              let mut state = slot.lock();
+              if res > 0 { state.start += res as usize; }
+              res > 0 && state.start < state.end
+            };
+            if unsent {
+              backlog.push_back(Op::Send { fd, slot });
+              continue;
+            }
+            slot.complete(|state| match res {
+              len if len > 0 => { state.start = 0; state.end = 0; }
+              0 => { state.error = Some(io::ErrorKind::WriteZero.into()); }
+              _ => { state.error = Some(io::Error::from_raw_os_error(-res)); }
+            });
+          }
+          InFlight::Other => {}
+        }
+      }
+    }
+  }
+}
+
+fn driver() -> io::Result<Arc<Driver>> {
+  DRIVER.as_ref().map(Arc::clone).map_err(|reason| io::Error::new(io::ErrorKind::Unsupported, reason.clone()))
+}
+
+/// A TCP listener whose accepts go through the ring.
+pub struct UringListener {
+  listener: TcpListener,
+  accepts: Arc<Slot>,
+  driver: Arc<Driver>,
+}
+
+impl UringListener {
+  /// Binds `addr`. Fails if it can't be bound, or if io_uring isn't available.
+  pub fn bind(addr: &str) -> io::Result<UringListener> {
+    let driver = driver()?;
+    Ok(UringListener { listener: TcpListener::bind(addr)?, accepts: Arc::new(Slot::default()), driver })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.listener.local_addr()
+  }
+
+  /// The next connection, and its peer's address. Cancel-safe: a connection accepted for a dropped call is returned by the next.
+  pub async fn accept(&self) -> io::Result<(UringStream, SocketAddr)> {
+    let fd = poll_fn(|cx: &mut Context<'_>| {
+      let mut state = self.accepts.lock();
+      if let Some(fd) = state.accepted.take() { return Poll::Ready(Ok(fd)); }
+      if let Some(err) = state.error.take() { return Poll::Ready(Err(err)); }
+      state.waker = Some(cx.waker().clone());
+      if !state.in_flight {
+        state.in_flight = true;
+        drop(state);
+        self.driver.submit(Op::Accept { fd: self.listener.as_raw_fd(), slot: self.accepts.clone() });
+      }
+      Poll::Pending
+    }).await?;
+    // (Borrowed as a TcpStream for its address; the UringStream owns the descriptor.)
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let peer = stream.peer_addr();
+    let fd = stream.into_raw_fd();
+    let stream = UringStream { fd, reads: Arc::new(Slot::default()), writes: Arc::new(Slot::default()), driver: self.driver.clone() };
+    Ok((stream, peer?))
+  }
+}
+
+impl Drop for UringListener {
+  fn drop(&mut self) {
+    // The accept in flight holds on to the socket, which would stay bound until a connection came along. (It's cancelled by its slot rather than by the descriptor, whose number the next socket opened may well reuse before the driver gets to it.)
+    self.driver.submit(Op::Cancel { slot: Arc::downgrade(&self.accepts) });
+  }
+}
+
+/// A connection accepted by a UringListener.
+pub struct UringStream {
+  fd: RawFd,
+  reads: Arc<Slot>,
+  writes: Arc<Slot>,
+  driver: Arc<Driver>,
+}
+
+impl UringStream {
+  /// Queues a read into the free end of the read buffer (making room first). The caller holds the read slot's lock.
+  fn start_recv(&self, state: &mut SlotState) {
+    if state.start == state.end {
+      state.start = 0;
+      state.end = 0;
+    }
+    if state.buf.len() == state.end {
+      let len = (state.buf.len() * 2).max(READ_BUF_LEN);
+      state.buf.resize(len, 0);
+    }
+    state.in_flight = true;
+    self.driver.submit(Op::Recv { fd: self.fd, slot: self.reads.clone() });
+  }
+
+  /// Reads data into `buf` without consuming it, waiting for some if none is available, like TcpStream::peek(). Returns 0 once the peer has closed the stream and nothing is buffered.
+  pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    poll_fn(|cx: &mut Context<'_>| {
+      let mut state = self.reads.lock();
+      let buffered = state.end - state.start;
+      if buffered == 0 {
+        if let Some(err) = state.error.take() { return Poll::Ready(Err(err)); }
+        if state.eof { return Poll::Ready(Ok(0)); }
+      }
+      // Reads ahead for more, if what's there won't fill `buf`.
+      if buffered < buf.len() && !state.in_flight && !state.eof && state.error.is_none() { self.start_recv(&mut state); }
+      if buffered == 0 {
+        state.waker = Some(cx.waker().clone());
+        return Poll::Pending;
+      }
+      let len = buffered.min(buf.len());
+      buf[..len].copy_from_slice(&state.buf[state.start..state.start + len]);
+      Poll::Ready(Ok(len))
+    }).await
+  }
+}
+
+impl AsyncRead for UringStream {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let mut state = self.reads.lock();
+    if state.start < state.end {
+      let len = (state.end - state.start).min(buf.remaining());
+      buf.put_slice(&state.buf[state.start..state.start + len]);
+      state.start += len;
+      return Poll::Ready(Ok(()));
+    }
+    if let Some(err) = state.error.take() { return Poll::Ready(Err(err)); }
+    if state.eof { return Poll::Ready(Ok(())); }
+    state.waker = Some(cx.waker().clone());
+    if !state.in_flight { self.start_recv(&mut state); }
+    Poll::Pending
+  }
+}
+
+impl AsyncWrite for UringStream {
+  fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+  }
+
+  /// Copies as much of `bufs` as a write takes into the write buffer, and queues it. Waits while the previous write is still in flight.
+  fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<io::Result<usize>> {
+    let mut state = self.writes.lock();
+    if state.in_flight {
+      state.waker = Some(cx.waker().clone());
+      return Poll::Pending;
+    }
+    if let Some(err) = state.error.take() { return Poll::Ready(Err(err)); }
+    state.buf.clear();
+    for buf in bufs {
+      let room = MAX_WRITE_LEN - state.buf.len();
+      if room == 0 { break; }
+      state.buf.extend_from_slice(&buf[..buf.len().min(room)]);
+    }
+    let len = state.buf.len();
+    if len == 0 { return Poll::Ready(Ok(0)); }
+    state.start = 0;
+    state.end = len;
+    state.in_flight = true;
+    drop(state);
+    self.driver.submit(Op::Send { fd: self.fd, slot: self.writes.clone() });
+    Poll::Ready(Ok(len))
+  }
+
+  fn is_write_vectored(&self) -> bool {
+    true
+  }
+
+  /// Waits for the write in flight, if any, returning its error if it failed.
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    let mut state = self.writes.lock();
+    if state.in_flight {
+      state.waker = Some(cx.waker().clone());
+      return Poll::Pending;
+    }
+    match state.error.take() {
+      Some(err) => Poll::Ready(Err(err)),
+      None => Poll::Ready(Ok(())),
+    }
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    futures_util::ready!(self.as_mut().poll_flush(cx))?;
+    unsafe { libc::shutdown(self.fd, libc::SHUT_WR); }
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl Drop for UringStream {
+  fn drop(&mut self) {
+    // Ends the read in flight (if any), then closes the descriptor behind whatever's still queued for it.
+    unsafe { libc::shutdown(self.fd, libc::SHUT_RDWR); }
+    self.driver.submit(Op::Close { fd: self.fd });
+  }
+}
@@ -0,0 +1,216 @@
+// watchdog.rs
+//
+// The watchdog (ServerConfig::watchdog): a thread of its own that notices when the server's tasks stop making progress, e.g. after a deadlock. The tasks it watches beat a Heartbeat as they go: the accept loop on every turn (and at least every quarter of the stall timeout, with nothing to accept), and each client's sender task, which fans the broadcasts out to its client, whenever it's in the middle of something (forwarding a batch, say, but not waiting for one). A task that goes the stall timeout without progress is recorded as a critical "internal" error event, once per stall. (Panics are recorded whether or not there's a watchdog: see tasks.rs.) Being on a thread of its own, the watchdog still notices a runtime that's wedged altogether.
+//
+// With restart on, a stalled task is restarted as well. The accept loop starts over, keeping its listener (and a panicked accept loop starts over too, rather than taking the server thread down); a client's sender task is cancelled, which disconnects its client, free to reconnect. Only a task stuck at an await can be restarted: one blocking its thread outright can't be stopped from outside, and is only reported.
+
+use std::{collections::BTreeMap, sync::{Arc, Mutex, PoisonError, atomic::{AtomicU64, Ordering}, mpsc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use tokio::{sync::Notify, time::{self, MissedTickBehavior}};
+
+use super::{error_events::{Category, Severity}, stats::ServerStats, tasks::capitalized};
+
+/// Most often the watchdog checks on the tasks, however short the stall timeout.
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How the server's tasks are watched (ServerConfig::watchdog).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchdog {
+  /// How long a task can go without making progress before it's taken to have stalled. Set it well above the longest a slow client can hold up a write, or slow clients are reported (and with restart, disconnected) too.
+  pub stall_timeout: Duration,
+  /// Restart tasks that stall (or, for the accept loop, panic), rather than only reporting them.
+  pub restart: bool,
+}
+
+impl Default for Watchdog {
+  fn default() -> Watchdog {
+    Watchdog { stall_timeout: Duration::from_secs(10), restart: false }
+  }
+}
+
+impl Watchdog {
+  pub fn validate(&self) -> Result<(), String> {
+    if self.stall_timeout.is_zero() {
+      return Err("the watchdog's stall timeout must be more than 0".to_string());
+    }
+    Ok(())
+  }
+
+  /// How often the watchdog checks on the tasks, and the accept loop beats with nothing to do.
+  fn check_interval(&self) -> Duration {
+    (self.stall_timeout / 4).max(MIN_CHECK_INTERVAL)
+  }
+}
+
+/// A watched task's progress, shared between its Heartbeat and the watchdog thread.
+struct Beat {
+  name: String,
+  client_id: Option<String>,
+  /// Since when it's been at work without progress (in nanoseconds since the Heartbeats were made, plus one); 0 while it's idle.
+  busy_since: AtomicU64,
+  /// The busy_since of the stall last reported, so each stall's reported once.
+  reported: AtomicU64,
+  /// Notified to restart the task.
+  restart: Notify,
+}
+
+/// A server's watched tasks (all none, without a watchdog).
+pub(crate) struct Heartbeats {
+  config: Option<Watchdog>,
+  stats: Arc<ServerStats>,
+  origin: Instant,
+  next_id: AtomicU64,
+  watched: Mutex<BTreeMap<u64, Arc<Beat>>>,
+}
+
+impl Heartbeats {
+  pub fn new(config: Option<Watchdog>, stats: Arc<ServerStats>) -> Heartbeats {
+    Heartbeats { config, stats, origin: Instant::now(), next_id: AtomicU64::new(0), watched: Mutex::default() }
+  }
+
+  /// Watches a task, named for the error events (e.g. "the accept loop"), until the returned Heartbeat is dropped. Watches nothing without a watchdog.
+  pub fn watch(self: &Arc<Self>, name: String, client_id: Option<String>) -> Heartbeat {
+    if self.config.is_none() {
+      return Heartbeat { heartbeats: None, id: 0, beat: None };
+    }
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let beat = Arc::new(Beat { name, client_id, busy_since: AtomicU64::new(0), reported: AtomicU64::new(0), restart: Notify::new() });
+    self.watched.lock().unwrap_or_else(PoisonError::into_inner).insert(id, beat.clone());
+    Heartbeat { heartbeats: Some(self.clone()), id, beat: Some(beat) }
+  }
+
+  /// Launches the watchdog thread, which watches until the returned WatchdogThread is dropped. None without a watchdog.
+  pub fn launch(self: &Arc<Self>) -> Option<WatchdogThread> {
+    let config = self.config?;
+    let (stop_tx, stop_rx) = mpsc::channel::<()>();
+    let heartbeats = self.clone();
+    let thread = thread::Builder::new()
+      .name("quicksocket-watchdog".to_string())
+      .spawn(move || {
+        // (Stopped by the sender being dropped.)
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(config.check_interval()) {
+          heartbeats.check(config);
+        }
+      });
+    match thread {
+      Ok(thread) => Some(WatchdogThread { stop_tx: Some(stop_tx), thread: Some(thread) }),
+      Err(err) => {
+        self.stats.record_error(Severity::Error, Category::Internal, format!("Failed to spawn the watchdog thread: {:?}", err), None);
+        None
+      }
+    }
+  }
+
+  /// Reports (and with restart, restarts) the tasks that have stalled since the last check.
+  fn check(&self, config: Watchdog) {
+    let now = self.now();
+    let stall = config.stall_timeout.as_nanos() as u64;
+    let watched: Vec<Arc<Beat>> = self.watched.lock().unwrap_or_else(PoisonError::into_inner).values().cloned().collect();
+    for beat in watched {
+      let busy_since = beat.busy_since.load(Ordering::Relaxed);
+      if busy_since == 0 || now.saturating_sub(busy_since) < stall || beat.reported.swap(busy_since, Ordering::Relaxed) == busy_since {
+        continue;
+      }
+      let restarting = if config.restart { "; restarting it" } else { "" };
+      log_error!("[watchdog] {} hasn't made progress for {} ms{}.", beat.name, config.stall_timeout.as_millis(), restarting);
+      self.stats.record_error(Severity::Critical, Category::Internal, format!("{} hasn't made progress for {} ms{}.", capitalized(&beat.name), config.stall_timeout.as_millis(), restarting), beat.client_id.clone());
+      if config.restart {
+        beat.restart.notify_one();
+      }
+    }
+  }
+
+  /// Nanoseconds since the Heartbeats were made, plus one (so never 0).
+  fn now(&self) -> u64 {
+    self.origin.elapsed().as_nanos() as u64 + 1
+  }
+
+  pub fn restarts(&self) -> bool {
+    self.config.is_some_and(|config| config.restart)
+  }
+
+  /// A timer for the accept loop to beat by, with nothing else to do; never ticks without a watchdog.
+  pub fn ticks(&self) -> BeatTimer {
+    BeatTimer(self.config.map(|config| {
+      let mut interval = time::interval(config.check_interval());
+      interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      interval
+    }))
+  }
+}
+
+/// A watched task's side of the watchdog: it beats to show it's making progress, and is told when it's to restart. Dropping it stops the watching.
+pub(crate) struct Heartbeat {
+  heartbeats: Option<Arc<Heartbeats>>,
+  id: u64,
+  beat: Option<Arc<Beat>>,
+}
+
+impl Heartbeat {
+  /// Marks progress, for a task that's always at work (the accept loop): it's stalled if it goes the stall timeout without another beat.
+  pub fn beat(&self) {
+    if let (Some(heartbeats), Some(beat)) = (&self.heartbeats, &self.beat) {
+      beat.busy_since.store(heartbeats.now(), Ordering::Relaxed);
+    }
+  }
+
+  /// Marks the task at work until the returned guard is dropped: it's stalled if that takes longer than the stall timeout.
+  pub fn busy(&self) -> Busy<'_> {
+    self.beat();
+    Busy(self)
+  }
+
+  /// Resolves when the watchdog restarts the task; never, without restart.
+  pub async fn restarted(&self) {
+    match &self.beat {
+      Some(beat) if self.heartbeats.as_ref().is_some_and(|heartbeats| heartbeats.restarts()) => beat.restart.notified().await,
+      _ => std::future::pending().await,
+    }
+  }
+}
+
+impl Drop for Heartbeat {
+  fn drop(&mut self) {
+    if let Some(heartbeats) = &self.heartbeats {
+      heartbeats.watched.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.id);
+    }
+  }
+}
+
+/// A task at work (see Heartbeat::busy()).
+pub(crate) struct Busy<'a>(&'a Heartbeat);
+
+impl Drop for Busy<'_> {
+  fn drop(&mut self) {
+    if let Some(beat) = &self.0.beat {
+      beat.busy_since.store(0, Ordering::Relaxed);
+    }
+  }
+}
+
+/// Ticks for the accept loop to beat on (see Heartbeats::ticks()).
+pub(crate) struct BeatTimer(Option<time::Interval>);
+
+impl BeatTimer {
+  pub async fn tick(&mut self) {
+    match &mut self.0 {
+      Some(interval) => { interval.tick().await; }
+      None => std::future::pending().await,
+    }
+  }
+}
+
+/// The running watchdog thread, stopped (and joined) when dropped.
+pub(crate) struct WatchdogThread {
+  stop_tx: Option<mpsc::Sender<()>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WatchdogThread {
+  fn drop(&mut self) {
+    // (Dropping the sender is what stops the thread.)
+    self.stop_tx.take();
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
@@ -0,0 +1,258 @@
+// writer.rs
+//
+// The two halves of a client connection once its handshake is done. tungstenite's sink takes ownership of every message it sends (so each client needed its own copy of each broadcast) and frames it into a buffer of its own; instead, a client's sender task encodes its frames itself, straight from the shared batches (see buffer_pool.rs). Server frames aren't masked, so a frame is just a header and the payload: the headers go in a pooled buffer, and each write is a vectored one of those and the payloads, so a burst of queued batches costs one write and one flush.
+//
+// tungstenite still reads the connection: it parses the client's frames, and answers pings and close frames. Its answers go to a ReplyQueue rather than the socket, and the sender task writes them between frames of its own, so the two never interleave mid-frame.
+//
+// For a client that agreed to compression, the writer writes broadcasts' deflated frames in place of their messages (waiting for them to be deflated, if they haven't been yet), and the reader inflates the client's deflated messages before tungstenite reads them (see compression.rs).
+
+use std::{io::{self, IoSlice}, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll, ready}, time::{Duration, Instant}};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf}, sync::Notify};
+use tokio_tungstenite::{WebSocketStream, tungstenite::{Message, protocol::Role}};
+
+use super::{buffer_pool::OUTBOUND, compression::{DeflatingClient, Inflater}, outbound::Outbound, rate_limit::ClientThrottle, stats::ServerStats, transport::Connection};
+
+/// Longest frame header the server writes: two bytes plus a 64-bit extended length (and no mask).
+pub(crate) const MAX_HEADER_LEN: usize = 10;
+/// Payloads up to this size are copied in after their headers; bigger ones are written from the message, as a slice of their own.
+const MAX_INLINE_PAYLOAD: usize = 1024;
+/// Most slices handed to one vectored write (Linux's IOV_MAX).
+const MAX_IO_SLICES: usize = 1024;
+/// How much a reader that inflates reads from the socket at a time.
+const INFLATING_READ_LEN: usize = 16 * 1024;
+
+/// The bytes tungstenite has written on the reader's side (complete frames), waiting for the sender task.
+#[derive(Default)]
+pub struct ReplyQueue {
+  bytes: Mutex<Vec<u8>>,
+  queued: Notify,
+}
+
+impl ReplyQueue {
+  /// Resolves once replies have been queued since the last time this resolved. (Possibly replies that have already been written along with the sender's own frames; writing an empty queue does nothing.)
+  pub async fn queued(&self) {
+    self.queued.notified().await
+  }
+
+  fn take_into(&self, buf: &mut Vec<u8>) {
+    if let Ok(mut bytes) = self.bytes.lock() {
+      buf.append(&mut bytes);
+    }
+  }
+}
+
+/// The reader's end of the connection: reads from the socket (inflating, for a client that agreed to compression), and writes to the ReplyQueue.
+pub struct ReaderIo {
+  read: ReadHalf<Connection>,
+  replies: Arc<ReplyQueue>,
+  inflater: Option<Box<Inflater>>,
+}
+
+impl AsyncRead for ReaderIo {
+  fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    let this = self.get_mut();
+    let inflater = match &mut this.inflater {
+      Some(inflater) => inflater,
+      None => { return Pin::new(&mut this.read).poll_read(cx, buf); }
+    };
+    // Reads until there's a frame for tungstenite, or the socket's done.
+    while !inflater.has_plain() {
+      let mut bytes = [0u8; INFLATING_READ_LEN];
+      let mut read = ReadBuf::new(&mut bytes);
+      ready!(Pin::new(&mut this.read).poll_read(cx, &mut read))?;
+      if read.filled().is_empty() { return Poll::Ready(Ok(())); }
+      inflater.push(read.filled())?;
+    }
+    inflater.read_plain(buf);
+    Poll::Ready(Ok(()))
+  }
+}
+
+impl AsyncWrite for ReaderIo {
+  fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+    // Always takes everything, so each of tungstenite's writes (of whole frames) lands in the queue in one piece.
+    let replies = &self.get_mut().replies;
+    if let Ok(mut bytes) = replies.bytes.lock() {
+      bytes.extend_from_slice(buf);
+    }
+    replies.queued.notify_one();
+    Poll::Ready(Ok(buf.len()))
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// The client's incoming messages.
+pub type ClientReader = WebSocketStream<ReaderIo>;
+
+/// The sender's end of the connection.
+pub struct FrameWriter {
+  write: WriteHalf<Connection>,
+  replies: Arc<ReplyQueue>,
+  /// For the time spent encoding and writing (and the outbound rate limit, if there is one).
+  stats: Arc<ServerStats>,
+  /// The client's own rate limit.
+  throttle: Arc<ClientThrottle>,
+  /// If the client agreed to compression, its count in the server's Deflater.
+  deflating: Option<DeflatingClient>,
+}
+
+/// Splits a connection whose handshake is done into its reader and writer, which keeps to `throttle`'s rate limit as well as the server's, and deflates and inflates messages if the client agreed to (`deflating`).
+pub async fn split(conn: Connection, stats: Arc<ServerStats>, throttle: Arc<ClientThrottle>, deflating: Option<DeflatingClient>) -> (ClientReader, FrameWriter) {
+  let (read, write) = tokio::io::split(conn);
+  let replies = Arc::new(ReplyQueue::default());
+  let inflater = deflating.as_ref().map(|_| Box::default());
+  let reader = WebSocketStream::from_raw_socket(ReaderIo { read, replies: replies.clone(), inflater }, Role::Server, None).await;
+  (reader, FrameWriter { write, replies, stats, throttle, deflating })
+}
+
+impl FrameWriter {
+  /// The queue of the reader's replies, to wait on.
+  pub fn replies(&self) -> Arc<ReplyQueue> {
+    self.replies.clone()
+  }
+
+  /// Writes any queued replies, then `messages`, and flushes.
+  ///
+  /// All of it goes out in as few (vectored) writes as the socket takes: the headers, small payloads and replies are copied into one pooled buffer, and bigger payloads (shared ones included) are written from the messages themselves, in between.
+  pub async fn write_messages(&mut self, messages: &[&Outbound]) -> io::Result<()> {
+    let deflate = self.deflating.is_some();
+    if deflate {
+      for msg in messages {
+        if let Outbound::Deflated(deflated) = msg { deflated.deflated().await; }
+      }
+    }
+    let started = Instant::now();
+    let inline_len = messages.iter().map(|msg| MAX_HEADER_LEN + if msg.len() <= MAX_INLINE_PAYLOAD { msg.len() } else { 0 }).sum();
+    let mut inline = OUTBOUND.take(inline_len);
+    self.replies.take_into(&mut inline);
+    // Each bigger payload, and how much of `inline` comes before it.
+    let mut payloads: Vec<(usize, &[u8])> = vec![];
+    for msg in messages {
+      let payload = encode_header(msg, deflate, &mut inline);
+      if payload.len() <= MAX_INLINE_PAYLOAD {
+        inline.extend_from_slice(payload);
+      } else {
+        payloads.push((inline.len(), payload));
+      }
+    }
+
+    let mut bufs: Vec<&[u8]> = Vec::with_capacity(2 * payloads.len() + 1);
+    let mut start = 0;
+    for (end, payload) in payloads {
+      if end > start { bufs.push(&inline[start..end]); }
+      bufs.push(payload);
+      start = end;
+    }
+    if inline.len() > start { bufs.push(&inline[start..]); }
+    let encoded = Instant::now();
+    self.stats.serialized(encoded - started);
+    let throttled = throttle(&self.stats, &self.throttle, &bufs, messages).await;
+    let writing = Instant::now();
+    let res = self.write_all(bufs).await;
+    self.stats.wrote_to_socket(writing.elapsed());
+    self.stats.messages_written(started.elapsed().saturating_sub(throttled), messages.len());
+    OUTBOUND.recycle(inline);
+    res
+  }
+
+  /// Writes any queued replies, and flushes.
+  pub async fn write_replies(&mut self) -> io::Result<()> {
+    self.write_messages(&[]).await
+  }
+
+  async fn write_all(&mut self, mut bufs: Vec<&[u8]>) -> io::Result<()> {
+    if bufs.is_empty() { return Ok(()); }
+    let mut first = 0;
+    while first < bufs.len() {
+      let slices: Vec<IoSlice> = bufs[first..].iter().take(MAX_IO_SLICES).map(|buf| IoSlice::new(buf)).collect();
+      let mut written = self.write.write_vectored(&slices).await?;
+      if written == 0 { return Err(io::ErrorKind::WriteZero.into()); }
+      // Skip what was written: whole buffers, then part of the next.
+      while written > 0 {
+        if written >= bufs[first].len() {
+          written -= bufs[first].len();
+          first += 1;
+        } else {
+          bufs[first] = &bufs[first][written..];
+          written = 0;
+        }
+      }
+    }
+    self.write.flush().await
+  }
+}
+
+/// Takes the bytes about to be written from the rate limits' buckets (the server's and the client's, if they have them), and waits as long as the longer says to, returning how long that was. Writes of control frames alone never wait (see rate_limit.rs).
+async fn throttle(stats: &ServerStats, client: &ClientThrottle, bufs: &[&[u8]], messages: &[&Outbound]) -> Duration {
+  let bytes = bufs.iter().map(|buf| buf.len()).sum();
+  let wait = client.take(bytes).max(stats.rate_limit().map_or(Duration::ZERO, |bucket| bucket.take(bytes)));
+  if wait.is_zero() || !messages.iter().any(|msg| msg.is_text() || msg.is_binary()) { return Duration::ZERO; }
+  tokio::time::sleep(wait).await;
+  stats.waited_for_rate_limit(wait);
+  wait
+}
+
+/// Appends the header of `msg` as a single unmasked frame, returning the payload to follow it. (Close frames' payloads are built, so they're appended too, and nothing is returned; a prepared message already has its header, so the whole frame is returned, as is a deflated one's, if `deflate`, and it's been deflated.)
+fn encode_header<'a>(msg: &'a Outbound, deflate: bool, buf: &mut Vec<u8>) -> &'a [u8] {
+  let msg = match msg {
+    Outbound::Message(msg) => msg,
+    Outbound::SharedBinary(data) => {
+      let payload = (**data).as_ref();
+      push_header(0x2, payload.len(), buf);
+      return payload;
+    }
+    Outbound::Prepared(prepared) => { return prepared.frame(); }
+    Outbound::Deflated(deflated) => {
+      return match deflated.frame().filter(|_| deflate) {
+        Some(frame) => frame,
+        None => encode_header(deflated.original(), deflate, buf),
+      };
+    }
+  };
+  let (opcode, payload): (u8, &[u8]) = match msg {
+    Message::Text(text) => (0x1, text.as_bytes()),
+    Message::Binary(data) => (0x2, data),
+    Message::Ping(data) => (0x9, data),
+    Message::Pong(data) => (0xA, data),
+    Message::Close(frame) => {
+      buf.push(0x80 | 0x8);
+      match frame {
+        Some(frame) => {
+          push_len(2 + frame.reason.len(), buf);
+          buf.extend_from_slice(&u16::from(frame.code).to_be_bytes());
+          buf.extend_from_slice(frame.reason.as_bytes());
+        }
+        None => push_len(0, buf),
+      }
+      return &[];
+    }
+  };
+  push_header(opcode, payload.len(), buf);
+  payload
+}
+
+/// Appends the header of a single unmasked frame of `len` bytes.
+pub(crate) fn push_header(opcode: u8, len: usize, buf: &mut Vec<u8>) {
+  buf.push(0x80 | opcode);
+  push_len(len, buf);
+}
+
+/// The second byte of a header (with the mask bit clear), and the extended length if it needs one.
+fn push_len(len: usize, buf: &mut Vec<u8>) {
+  if len < 126 {
+    buf.push(len as u8);
+  } else if len <= u16::MAX as usize {
+    buf.push(126);
+    buf.extend_from_slice(&(len as u16).to_be_bytes());
+  } else {
+    buf.push(127);
+    buf.extend_from_slice(&(len as u64).to_be_bytes());
+  }
+}
@@ -0,0 +1,458 @@
+// zmq_bridge.rs
+//
+// ZeroMQ ingestion bridge (the "zmq" feature): messages from ZeroMQ PUB or PUSH sockets (a simulator's, say) are received by a SUB or PULL endpoint of the bridge's own and broadcast to a server's clients, with no Python in between. Speaks ZMTP 3.0 with the NULL security mechanism itself, over tcp://, so it needs neither libzmq nor any ZeroMQ binding. The bridge runs as tasks on the client-mode runtime (see client.rs). Like a ZeroMQ socket, it either connects to its endpoint (retrying every second for as long as the peer isn't there) or binds it and takes any number of peers.
+
+use std::{io, sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt, BufReader}, net::{TcpListener, TcpStream}, sync::watch, task::{JoinHandle, JoinSet}};
+
+use super::{Error, Message, Server, client::CLIENT_RT, consumer_state::{self as cs, RunState}, error_events::{self, Category, Severity}};
+
+/// How long connecting, and the ZMTP handshake, may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connecting bridge waits between attempts to (re)connect.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// The largest frame accepted from a peer, the same as the largest websocket message tungstenite accepts by default.
+const MAX_FRAME_LEN: u64 = 64 << 20;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+/// The bridge's end of the ZeroMQ pattern.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZmqSocketType {
+  /// Subscribes to PUB (or XPUB) sockets.
+  #[default]
+  Sub,
+  /// Pulls from PUSH sockets.
+  Pull,
+}
+
+impl ZmqSocketType {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      ZmqSocketType::Sub  => "SUB",
+      ZmqSocketType::Pull => "PULL",
+    }
+  }
+
+  /// Whether a peer of `peer_type` (from its READY command) can talk to this socket type, as ZeroMQ allows.
+  fn accepts_peer(&self, peer_type: &str) -> bool {
+    match self {
+      ZmqSocketType::Sub  => peer_type == "PUB" || peer_type == "XPUB",
+      ZmqSocketType::Pull => peer_type == "PUSH",
+    }
+  }
+}
+
+/// Where a ZeroMQ bridge receives messages from, and how.
+#[derive(Clone, Debug, Default)]
+pub struct ZmqBridgeConfig {
+  /// A tcp:// endpoint, e.g. "tcp://127.0.0.1:5556". With `bind`, "tcp://*:5556" listens on every interface.
+  pub endpoint: String,
+  pub socket_type: ZmqSocketType,
+  /// For SUB: the topics (message prefixes, as zmq_setsockopt's ZMQ_SUBSCRIBE) to subscribe to. If empty, everything is subscribed to. PULL sockets don't take topics.
+  pub topics: Vec<String>,
+  /// Listen on the endpoint for peers to connect to, instead of connecting to it.
+  pub bind: bool,
+}
+
+/// What a ZeroMQ bridge has passed on, from ZmqBridge::stats().
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ZmqBridgeStats {
+  /// Messages received and broadcast to the clients.
+  pub broadcast: u64,
+  /// Peer connections that completed the ZMTP handshake, including reconnections.
+  pub connections: u64,
+}
+
+#[derive(Default)]
+struct BridgeCounters {
+  broadcast: AtomicU64,
+  connections: AtomicU64,
+}
+
+/// A bridge started with ZmqBridge::start(). It runs until stop() is called or the server stops; dropping the ZmqBridge doesn't stop it.
+///
+/// Each ZeroMQ message is broadcast as one websocket message: its last frame (earlier frames of a multipart message being topics or envelopes), as text if it's valid UTF-8 and binary otherwise. A SUB bridge also drops messages whose first frame doesn't start with one of its topics, as a SUB socket does.
+pub struct ZmqBridge {
+  endpoint: String,
+  stop_tx: watch::Sender<bool>,
+  task: Mutex<Option<JoinHandle<()>>>,
+  counters: Arc<BridgeCounters>,
+}
+
+impl ZmqBridge {
+  /// Starts bridging to `server`'s clients. A binding bridge is listening once this returns; a connecting one connects in the background, since (as with ZeroMQ) its peer needn't be up yet. Fails with Error::InvalidConfig for a bad endpoint or topics for a PULL bridge, Error::Bind if the endpoint can't be bound, and Error::NotRunning if the server isn't running.
+  pub fn start(server: &Server, config: ZmqBridgeConfig) -> Result<ZmqBridge, Error> {
+    let address = parse_endpoint(&config.endpoint, config.bind).map_err(Error::InvalidConfig)?;
+    if config.socket_type == ZmqSocketType::Pull && !config.topics.is_empty() {
+      return Err(Error::InvalidConfig("PULL sockets don't take topics".to_string()));
+    }
+    if !server.is_running() {
+      return Err(Error::NotRunning);
+    }
+    let state_rx = server.state_rx();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let counters = Arc::new(BridgeCounters::default());
+    let bridge = Arc::new(Bridge { config: config.clone(), server: server.clone(), counters: counters.clone() });
+
+    let task = match config.bind {
+      true => {
+        let listener = CLIENT_RT.block_on(TcpListener::bind(&address)).map_err(|err| Error::Bind {
+          port: address.rsplit_once(':').and_then(|(_, port)| port.parse().ok()).unwrap_or(0),
+          address: address.clone(),
+          reason: err.to_string(),
+        })?;
+        log_info!("[zmq_bridge] Listening on {} ({}) for the clients of port {}.", config.endpoint, config.socket_type.as_str(), server.port);
+        CLIENT_RT.spawn(run_listener(listener, bridge, stop_rx, state_rx))
+      }
+      false => {
+        log_info!("[zmq_bridge] Connecting to {} ({}) for the clients of port {}.", config.endpoint, config.socket_type.as_str(), server.port);
+        CLIENT_RT.spawn(run_connector(address, bridge, stop_rx, state_rx))
+      }
+    };
+    Ok(ZmqBridge { endpoint: config.endpoint, stop_tx, task: Mutex::new(Some(task)), counters })
+  }
+
+  pub fn endpoint(&self) -> &str {
+    &self.endpoint
+  }
+
+  pub fn is_running(&self) -> bool {
+    self.task.lock().is_ok_and(|task| task.as_ref().is_some_and(|task| !task.is_finished()))
+  }
+
+  pub fn stats(&self) -> ZmqBridgeStats {
+    ZmqBridgeStats {
+      broadcast: self.counters.broadcast.load(Ordering::Relaxed),
+      connections: self.counters.connections.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Stops the bridge, waiting for its connections to close. Stopping a stopped bridge does nothing. Mustn't be called from an async task.
+  pub fn stop(&self) {
+    self.stop_tx.send_replace(true);
+    let task = self.task.lock().ok().and_then(|mut task| task.take());
+    if let Some(task) = task {
+      let _ = cs::block_on(task);
+    }
+  }
+}
+
+/// The host:port to connect to (or bind) for a tcp:// endpoint.
+fn parse_endpoint(endpoint: &str, bind: bool) -> Result<String, String> {
+  let address = match endpoint.split_once("://") {
+    Some(("tcp", address)) => address,
+    Some((transport, _)) => { return Err(format!("{:?} isn't a tcp:// endpoint ({}:// isn't supported)", endpoint, transport)); }
+    None => { return Err(format!("{:?} isn't a ZeroMQ endpoint (tcp://host:port)", endpoint)); }
+  };
+  let (host, port) = address.rsplit_once(':').ok_or_else(|| format!("{:?} has no port", endpoint))?;
+  port.parse::<u16>().map_err(|_| format!("{:?} has an invalid port", endpoint))?;
+  let host = match host {
+    "" => { return Err(format!("{:?} has no host", endpoint)); }
+    "*" if bind => "0.0.0.0",
+    "*" => { return Err(format!("{:?} can only be bound, not connected to", endpoint)); }
+    host => host,
+  };
+  Ok(format!("{}:{}", host, port))
+}
+
+// Bridge tasks
+// ------------
+
+/// What every connection of a bridge shares.
+struct Bridge {
+  config: ZmqBridgeConfig,
+  server: Server,
+  counters: Arc<BridgeCounters>,
+}
+
+/// How a connection to a peer ended.
+enum SessionEnd {
+  /// The bridge was stopped, or the server stopped.
+  Stopped,
+  /// The connection failed, or the peer closed it, for this reason.
+  Lost(String),
+}
+
+/// Connects to the endpoint, and reconnects RECONNECT_DELAY after the connection is lost (or can't be made), until stopped or the server stops.
+async fn run_connector(address: String, bridge: Arc<Bridge>, mut stop_rx: watch::Receiver<bool>, mut state_rx: watch::Receiver<RunState>) {
+  let endpoint = &bridge.config.endpoint;
+  // Whether the current outage has been recorded, so a peer that's down for a while is one error, not one a second.
+  let mut reported = false;
+  loop {
+    let connecting = async {
+      tokio::time::timeout(HANDSHAKE_TIMEOUT, TcpStream::connect(&address)).await
+        .unwrap_or_else(|_| Err(io::Error::other("timed out")))
+    };
+    let connected = tokio::select! {
+      connected = connecting => connected,
+      _ = stop_rx.wait_for(|stop| *stop) => { break; }
+      _ = state_rx.wait_for(|state| !state.is_alive()) => { break; }
+    };
+    let end = match connected {
+      Ok(stream) => {
+        let mut handshaken = false;
+        let end = run_session(stream, &bridge, &mut handshaken, stop_rx.clone(), state_rx.clone()).await;
+        if handshaken { reported = false; }
+        end
+      }
+      Err(err) => SessionEnd::Lost(format!("couldn't connect: {}", err)),
+    };
+    match end {
+      SessionEnd::Stopped => { break; }
+      SessionEnd::Lost(reason) => {
+        if !reported {
+          record_error(format!("Can't receive from {}: {}; retrying every {} ms", endpoint, reason, RECONNECT_DELAY.as_millis()));
+          reported = true;
+        }
+        log_debug!("[zmq_bridge] Connection to {} lost: {}", endpoint, reason);
+      }
+    }
+    tokio::select! {
+      _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+      _ = stop_rx.wait_for(|stop| *stop) => { break; }
+      _ = state_rx.wait_for(|state| !state.is_alive()) => { break; }
+    }
+  }
+  log_debug!("[zmq_bridge] Connector for {} exiting.", endpoint);
+}
+
+/// Accepts peers on the bound endpoint, each served by a task of its own, until stopped or the server stops; then waits for those tasks to finish.
+async fn run_listener(listener: TcpListener, bridge: Arc<Bridge>, mut stop_rx: watch::Receiver<bool>, mut state_rx: watch::Receiver<RunState>) {
+  let mut peers = JoinSet::new();
+  // (Cloned for each peer; the originals are borrowed by the select below.)
+  let (peer_stop_rx, peer_state_rx) = (stop_rx.clone(), state_rx.clone());
+  loop {
+    tokio::select! {
+      accepted = listener.accept() => {
+        let (stream, addr) = match accepted {
+          Ok(accepted) => accepted,
+          Err(err) => {
+            log_warn!("[zmq_bridge] Failed to accept a connection on {}: {}", bridge.config.endpoint, err);
+            continue;
+          }
+        };
+        let (bridge, stop_rx, state_rx) = (bridge.clone(), peer_stop_rx.clone(), peer_state_rx.clone());
+        peers.spawn(async move {
+          let mut handshaken = false;
+          if let SessionEnd::Lost(reason) = run_session(stream, &bridge, &mut handshaken, stop_rx, state_rx).await {
+            // Peers leaving is routine; only failures before the handshake completes are errors.
+            match handshaken {
+              true => log_info!("[zmq_bridge] Peer {} disconnected: {}", addr, reason),
+              false => record_error(format!("Peer {} of {} failed: {}", addr, bridge.config.endpoint, reason)),
+            }
+          }
+        });
+      }
+      Some(_) = peers.join_next(), if !peers.is_empty() => {}
+      _ = stop_rx.wait_for(|stop| *stop) => { break; }
+      _ = state_rx.wait_for(|state| !state.is_alive()) => { break; }
+    }
+  }
+  while peers.join_next().await.is_some() {}
+  log_debug!("[zmq_bridge] Listener on {} exiting.", bridge.config.endpoint);
+}
+
+/// Handshakes with a peer and broadcasts its messages until the connection ends, or the bridge or server stops (either of which drops the connection wherever it's got to). Sets `handshaken` once the handshake completes.
+async fn run_session(stream: TcpStream, bridge: &Bridge, handshaken: &mut bool, mut stop_rx: watch::Receiver<bool>, mut state_rx: watch::Receiver<RunState>) -> SessionEnd {
+  let session = async {
+    let _ = stream.set_nodelay(true);
+    let mut connection = ZmtpConnection { stream: BufReader::new(stream) };
+    let handshake = connection.handshake(&bridge.config);
+    let peer_type = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await
+      .map_err(|_| "timed out during the ZMTP handshake".to_string())??;
+    bridge.counters.connections.fetch_add(1, Ordering::Relaxed);
+    *handshaken = true;
+    log_info!("[zmq_bridge] Receiving from a {} peer on {}.", peer_type, bridge.config.endpoint);
+
+    loop {
+      let parts = connection.read_message().await?;
+      if !subscribed(&bridge.config, &parts) { continue; }
+      let payload = parts.into_iter().last().unwrap_or_default();
+      if bridge.server.send(vec![payload_message(payload)]).is_err() {
+        // The server has stopped.
+        return Ok(());
+      }
+      bridge.counters.broadcast.fetch_add(1, Ordering::Relaxed);
+    }
+  };
+  tokio::select! {
+    ended = session => match ended {
+      Ok(()) => SessionEnd::Stopped,
+      Err(reason) => SessionEnd::Lost(reason),
+    },
+    _ = stop_rx.wait_for(|stop| *stop) => SessionEnd::Stopped,
+    _ = state_rx.wait_for(|state| !state.is_alive()) => SessionEnd::Stopped,
+  }
+}
+
+/// Whether a message matches the bridge's subscriptions: always, for PULL.
+fn subscribed(config: &ZmqBridgeConfig, parts: &[Vec<u8>]) -> bool {
+  if config.socket_type != ZmqSocketType::Sub || config.topics.is_empty() { return true; }
+  let first = parts.first().map(|part| part.as_slice()).unwrap_or_default();
+  config.topics.iter().any(|topic| first.starts_with(topic.as_bytes()))
+}
+
+/// A message's payload, as a websocket message: text if it's valid UTF-8, binary otherwise.
+fn payload_message(payload: Vec<u8>) -> Message {
+  match String::from_utf8(payload) {
+    Ok(text) => Message::Text(text),
+    Err(err) => Message::Binary(err.into_bytes()),
+  }
+}
+
+fn record_error(err: String) {
+  log_warn!("[zmq_bridge] {}", err);
+  error_events::record(Severity::Warning, Category::Zmq, err, None);
+}
+
+// ZMTP
+// ----
+
+/// One frame: a part of a message, or a command.
+struct Frame {
+  flags: u8,
+  body: Vec<u8>,
+}
+
+struct ZmtpConnection {
+  stream: BufReader<TcpStream>,
+}
+
+impl ZmtpConnection {
+  /// Exchanges greetings (ZMTP 3.0, NULL mechanism) and READY commands, then subscribes if the bridge is a SUB. Returns the peer's socket type.
+  async fn handshake(&mut self, config: &ZmqBridgeConfig) -> Result<String, String> {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    self.stream.get_mut().write_all(&greeting).await.map_err(|err| err.to_string())?;
+
+    let mut peer = [0u8; 64];
+    self.stream.read_exact(&mut peer).await.map_err(|err| format!("no ZMTP greeting: {}", err))?;
+    if peer[0] != 0xff || peer[9] & 0x01 == 0 {
+      return Err("the peer isn't speaking ZMTP".to_string());
+    }
+    if peer[10] < 3 {
+      return Err(format!("the peer speaks ZMTP {}.{}; 3.0 or later is needed", peer[10], peer[11]));
+    }
+    let mechanism = String::from_utf8_lossy(&peer[12..32]).trim_end_matches('\0').to_string();
+    if mechanism != "NULL" {
+      return Err(format!("the peer wants the {} security mechanism; only NULL is supported", mechanism));
+    }
+
+    let mut ready = command_name("READY");
+    ready.push(b"Socket-Type".len() as u8);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(config.socket_type.as_str().len() as u32).to_be_bytes());
+    ready.extend_from_slice(config.socket_type.as_str().as_bytes());
+    self.write_frame(FLAG_COMMAND, &ready).await.map_err(|err| err.to_string())?;
+
+    let frame = self.read_frame().await?;
+    if frame.flags & FLAG_COMMAND == 0 {
+      return Err("the peer sent a message before its READY command".to_string());
+    }
+    let (name, data) = split_command(&frame.body)?;
+    let peer_type = match name.as_str() {
+      "READY" => ready_property(data, "Socket-Type")?.ok_or("the peer's READY command has no Socket-Type")?,
+      "ERROR" => {
+        let reason = data.get(1..).map(String::from_utf8_lossy).unwrap_or_default();
+        return Err(format!("the peer refused the handshake: {}", reason));
+      }
+      other => { return Err(format!("expected the peer's READY command, got {}", other)); }
+    };
+    if !config.socket_type.accepts_peer(&peer_type) {
+      return Err(format!("a {} socket can't receive from a {} socket", config.socket_type.as_str(), peer_type));
+    }
+
+    if config.socket_type == ZmqSocketType::Sub {
+      // ZMTP 3.0 subscriptions: a message of 0x01 and the topic.
+      let topics = match config.topics.is_empty() {
+        true => vec![String::new()],
+        false => config.topics.clone(),
+      };
+      for topic in topics {
+        let mut subscription = vec![0x01];
+        subscription.extend_from_slice(topic.as_bytes());
+        self.write_frame(0, &subscription).await.map_err(|err| err.to_string())?;
+      }
+    }
+    Ok(peer_type)
+  }
+
+  /// Reads the next message's frames, skipping any commands in between.
+  async fn read_message(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let mut parts = vec![];
+    loop {
+      let frame = self.read_frame().await?;
+      if frame.flags & FLAG_COMMAND != 0 { continue; }
+      parts.push(frame.body);
+      if frame.flags & FLAG_MORE == 0 { return Ok(parts); }
+    }
+  }
+
+  /// Reads a frame. Not cancel-safe: a frame that's partly read when the future is dropped is lost, along with the connection's framing.
+  async fn read_frame(&mut self) -> Result<Frame, String> {
+    let reading = async {
+      let flags = self.stream.read_u8().await?;
+      let len = match flags & FLAG_LONG {
+        0 => self.stream.read_u8().await? as u64,
+        _ => self.stream.read_u64().await?,
+      };
+      if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("a {} byte frame is too large", len)));
+      }
+      let mut body = vec![0u8; len as usize];
+      self.stream.read_exact(&mut body).await?;
+      Ok(Frame { flags, body })
+    };
+    reading.await.map_err(|err: io::Error| match err.kind() {
+      io::ErrorKind::UnexpectedEof => "the peer closed the connection".to_string(),
+      _ => err.to_string(),
+    })
+  }
+
+  async fn write_frame(&mut self, flags: u8, body: &[u8]) -> io::Result<()> {
+    let mut frame = vec![];
+    match body.len() {
+      len if len <= 255 => { frame.push(flags); frame.push(len as u8); }
+      len => { frame.push(flags | FLAG_LONG); frame.extend_from_slice(&(len as u64).to_be_bytes()); }
+    }
+    frame.extend_from_slice(body);
+    self.stream.get_mut().write_all(&frame).await
+  }
+}
+
+/// A command body's name, length-prefixed, to append the command's data to.
+fn command_name(name: &str) -> Vec<u8> {
+  let mut body = vec![name.len() as u8];
+  body.extend_from_slice(name.as_bytes());
+  body
+}
+
+/// A command body's name and data.
+fn split_command(body: &[u8]) -> Result<(String, &[u8]), String> {
+  let len = *body.first().ok_or("empty command from the peer")? as usize;
+  let name = body.get(1..1 + len).ok_or("truncated command from the peer")?;
+  Ok((String::from_utf8_lossy(name).into_owned(), &body[1 + len..]))
+}
+
+/// A property's value from a READY command's metadata, if it's there. Property names are case-insensitive.
+fn ready_property(mut metadata: &[u8], wanted: &str) -> Result<Option<String>, String> {
+  let truncated = || "truncated READY command from the peer".to_string();
+  while !metadata.is_empty() {
+    let name_len = metadata[0] as usize;
+    let name = metadata.get(1..1 + name_len).ok_or_else(truncated)?;
+    let value_len_bytes = metadata.get(1 + name_len..5 + name_len).ok_or_else(truncated)?;
+    let value_len = u32::from_be_bytes([value_len_bytes[0], value_len_bytes[1], value_len_bytes[2], value_len_bytes[3]]) as usize;
+    let value = metadata.get(5 + name_len..5 + name_len + value_len).ok_or_else(truncated)?;
+    if name.eq_ignore_ascii_case(wanted.as_bytes()) {
+      return Ok(Some(String::from_utf8_lossy(value).into_owned()));
+    }
+    metadata = &metadata[5 + name_len + value_len..];
+  }
+  Ok(None)
+}
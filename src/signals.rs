@@ -0,0 +1,108 @@
+// signals.rs
+// ==========
+//
+// Opt-in handling of SIGINT and SIGTERM (Ctrl-C only, on Windows) for headless deployments, e.g. under systemd or `docker stop`. Once enabled, a dedicated signal thread waits for either signal and requests a graceful shutdown of every running server, and records which signal arrived so the consumer can notice and exit.
+
+use std::{sync::RwLock, thread};
+
+use crate::server::consumer_state as cs;
+
+lazy_static! {
+    /// The name of the most recent shutdown signal received, if any.
+    static ref RECEIVED_SIGNAL: RwLock<Option<&'static str>> = RwLock::new(None);
+
+    /// Whether the signal thread has been started; it's only ever started once.
+    static ref SIGNAL_THREAD_STARTED: RwLock<bool> = RwLock::new(false);
+}
+
+/// Installs the signal handlers and starts the signal thread, if that hasn't been done already.
+///
+/// The handlers are added alongside any existing ones, so Python's own SIGINT handling (KeyboardInterrupt) still happens too. SIGTERM, however, no longer terminates the process by itself; the consumer should check received_signal() (or notice that its servers stopped) and exit.
+pub fn enable() -> Result<(), String> {
+    let started = SIGNAL_THREAD_STARTED.write();
+    if started.is_err() { return Err("Failed to get access to the signal thread state.".to_string()); }
+    let mut started = started.unwrap();
+    if *started { return Ok(()); }
+
+    // Signal streams need an IO driver, so the thread gets its own small runtime. The streams are created here, rather than on the thread, so that failing to install a handler becomes an error for the caller.
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build();
+    if let Err(err) = runtime {
+        return Err(format!("Failed to create the signal thread's runtime: {:?}", err));
+    }
+    let runtime = runtime.unwrap();
+    let signals = runtime.block_on(async { Signals::new() });
+    if let Err(err) = signals {
+        return Err(format!("Failed to install signal handlers: {:?}", err));
+    }
+    let mut signals = signals.unwrap();
+
+    let thread = thread::Builder::new()
+        .name("quicksocket-signals".to_string())
+        .spawn(move || runtime.block_on(async move {
+            loop {
+                let signal = signals.recv().await;
+                on_signal(signal);
+            }
+        }));
+    if let Err(err) = thread {
+        return Err(format!("Failed to spawn the signal thread: {:?}", err));
+    }
+
+    *started = true;
+    Ok(())
+}
+
+/// The name of the last shutdown signal received ("SIGINT" or "SIGTERM") since handling was enabled, or None.
+pub fn received_signal() -> Option<&'static str> {
+    RECEIVED_SIGNAL.read().ok().and_then(|signal| *signal)
+}
+
+/// Records the signal and requests that every server shut down. Servers started after a signal are left alone until the next one.
+fn on_signal(signal: &'static str) {
+    log_info!("[signals.rs] Received {}; shutting down all servers.", signal);
+    if let Ok(mut received) = RECEIVED_SIGNAL.write() { *received = Some(signal); }
+
+    let servers = cs::read(&cs::CS_SERVERS, |servers| servers.clone()).unwrap_or_default();
+    for server in servers {
+        server.ser_req_shutdown_tx.send_replace(true);
+    }
+}
+
+/// The signal streams the signal thread listens to.
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Signals { interrupt: signal(SignalKind::interrupt())?, terminate: signal(SignalKind::terminate())? })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+        }
+    }
+}
+
+#[cfg(not(unix))]
+struct Signals {
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+#[cfg(not(unix))]
+impl Signals {
+    fn new() -> std::io::Result<Signals> {
+        Ok(Signals { ctrl_c: tokio::signal::windows::ctrl_c()? })
+    }
+
+    async fn recv(&mut self) -> &'static str {
+        self.ctrl_c.recv().await;
+        "SIGINT"
+    }
+}
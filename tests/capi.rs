@@ -0,0 +1,100 @@
+// Tests of the C ABI (the "capi" feature), called as a C host would, with a websocket client of the test's own.
+#![cfg(feature = "capi")]
+
+use std::{ffi::{CStr, CString}, net::TcpListener, ptr};
+use quicksocket::ffi::*;
+use tungstenite::Message;
+
+const TIMEOUT_MS: i64 = 5000;
+
+/// A port nothing's listening on, for qs_server_start(), which has no way to report the port it bound.
+fn free_port() -> u32 {
+  TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port() as u32
+}
+
+/// Drains until there are `count` messages, as (client id, data, is_text), going through the batch functions.
+unsafe fn drain(server: *const QsServer, count: usize) -> Vec<(String, Vec<u8>, bool)> {
+  let mut drained = vec![];
+  while drained.len() < count {
+    let mut batch = ptr::null_mut();
+    assert_eq!(qs_server_drain_messages(server, TIMEOUT_MS, usize::MAX, &mut batch), QsStatus::QsOk);
+    assert!(qs_batch_len(batch) > 0, "no messages from the client");
+    for index in 0..qs_batch_len(batch) {
+      let mut msg = QsMessage { client_id: ptr::null(), data: ptr::null(), len: 0, is_text: false };
+      assert_eq!(qs_batch_get(batch, index, &mut msg), QsStatus::QsOk);
+      let client_id = CStr::from_ptr(msg.client_id).to_str().unwrap().to_string();
+      drained.push((client_id, std::slice::from_raw_parts(msg.data, msg.len).to_vec(), msg.is_text));
+    }
+    qs_batch_free(batch);
+  }
+  drained
+}
+
+#[test]
+fn a_host_serves_a_client_through_the_c_abi() {
+  unsafe {
+    let port = free_port();
+    let server = qs_server_start(port);
+    assert!(!server.is_null());
+    assert_eq!(qs_server_wait_until_started(server, TIMEOUT_MS), QsStatus::QsOk);
+    assert!(qs_server_is_running(server));
+
+    let (mut ws, _) = tungstenite::connect(format!("ws://127.0.0.1:{}", port)).unwrap();
+    ws.write_message(Message::text("hello")).unwrap();
+    ws.write_message(Message::binary(vec![0, 1, 2])).unwrap();
+    let drained = drain(server, 2);
+    let client_id = drained[0].0.clone();
+    assert_eq!(drained, vec![(client_id.clone(), b"hello".to_vec(), true), (client_id.clone(), vec![0, 1, 2], false)]);
+
+    let broadcast = b"to everyone";
+    assert_eq!(qs_server_send(server, broadcast.as_ptr(), broadcast.len(), true), QsStatus::QsOk);
+    assert_eq!(ws.read_message().unwrap(), Message::text("to everyone"));
+    // Confirmed, and then queued without waiting.
+    let client_id = CString::new(client_id).unwrap();
+    let targeted = [7u8, 8, 9];
+    assert_eq!(qs_server_send_to_client(server, client_id.as_ptr(), targeted.as_ptr(), targeted.len(), false, TIMEOUT_MS), QsStatus::QsOk);
+    assert_eq!(qs_server_send_to_client(server, client_id.as_ptr(), targeted.as_ptr(), targeted.len(), false, -1), QsStatus::QsOk);
+    assert_eq!(ws.read_message().unwrap(), Message::binary(targeted.to_vec()));
+    assert_eq!(ws.read_message().unwrap(), Message::binary(targeted.to_vec()));
+
+    assert_eq!(qs_server_shutdown(server, true), QsStatus::QsOk);
+    assert!(!qs_server_is_running(server));
+    assert_eq!(qs_server_send(server, broadcast.as_ptr(), broadcast.len(), true), QsStatus::QsNotRunning);
+    assert!(!qs_last_error().is_null());
+    qs_server_free(server);
+  }
+}
+
+#[test]
+fn bad_arguments_are_reported_rather_than_crashing() {
+  unsafe {
+    let port = free_port();
+    let server = qs_server_start(port);
+    assert_eq!(qs_server_wait_until_started(server, TIMEOUT_MS), QsStatus::QsOk);
+
+    let invalid_utf8 = [0xffu8, 0xfe];
+    assert_eq!(qs_server_send(server, invalid_utf8.as_ptr(), invalid_utf8.len(), true), QsStatus::QsInvalidArgument);
+    assert!(CStr::from_ptr(qs_last_error()).to_str().unwrap().contains("UTF-8"));
+    assert_eq!(qs_server_send(server, ptr::null(), 3, false), QsStatus::QsInvalidArgument);
+    assert_eq!(qs_server_send_to_client(server, ptr::null(), ptr::null(), 0, false, -1), QsStatus::QsInvalidArgument);
+    assert_eq!(qs_server_send(ptr::null(), ptr::null(), 0, false), QsStatus::QsInvalidArgument);
+    assert!(!qs_server_is_running(ptr::null()));
+
+    // An empty batch, on a timeout; out of range at any index.
+    let mut batch = ptr::null_mut();
+    assert_eq!(qs_server_drain_messages(server, 10, usize::MAX, &mut batch), QsStatus::QsOk);
+    assert_eq!(qs_batch_len(batch), 0);
+    let mut msg = QsMessage { client_id: ptr::null(), data: ptr::null(), len: 0, is_text: false };
+    assert_eq!(qs_batch_get(batch, 0, &mut msg), QsStatus::QsInvalidArgument);
+    qs_batch_free(batch);
+    qs_batch_free(ptr::null_mut());
+
+    // A second server on the same port fails to bind.
+    let clash = qs_server_start(port);
+    assert_eq!(qs_server_wait_until_started(clash, TIMEOUT_MS), QsStatus::QsBindError);
+    qs_server_free(clash);
+
+    assert_eq!(qs_server_shutdown(server, true), QsStatus::QsOk);
+    qs_server_free(server);
+  }
+}
@@ -0,0 +1,53 @@
+// Tests of Server::events(), over the loopback transport.
+
+use std::time::Duration;
+use futures_util::StreamExt;
+use quicksocket::server::{Message, Server, ServerConfig, ServerEvent, Transport};
+use quicksocket::server::events::ConnectionChange;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn events_arrive_in_the_order_they_happened() {
+  let server = Server::start(0, ServerConfig { transport: Transport::Loopback, ..ServerConfig::default() }).unwrap();
+  assert!(server.wait_until_started(Some(TIMEOUT)).unwrap());
+  let mut events = server.events().unwrap();
+
+  // (The client blocks, so it's driven from a thread of its own rather than the stream's runtime.)
+  let client_server = server.clone();
+  let client = std::thread::spawn(move || {
+    let mut client = client_server.connect_loopback().unwrap();
+    client.send(vec![Message::text("one"), Message::binary(vec![2]), Message::text("three")]).unwrap();
+    client.close();
+    client.client_id().to_string()
+  });
+
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  let mut seen = vec![];
+  runtime.block_on(async {
+    while seen.len() < 5 {
+      let event = tokio::time::timeout(TIMEOUT, events.next()).await.expect("no event from the server").unwrap();
+      seen.push(event);
+    }
+  });
+  let id = client.join().unwrap();
+
+  match &seen[0] {
+    ServerEvent::Connection(event) => assert_eq!((event.client_id.as_str(), event.change), (id.as_str(), ConnectionChange::Connected)),
+    other => panic!("expected the connection first, not {:?}", other),
+  }
+  let messages: Vec<Message> = seen[1..4].iter().map(|event| match event {
+    ServerEvent::Message(msg) => { assert_eq!(msg.client_id, id); msg.message.clone() }
+    other => panic!("expected a message, not {:?}", other),
+  }).collect();
+  assert_eq!(messages, vec![Message::text("one"), Message::binary(vec![2]), Message::text("three")]);
+  match &seen[4] {
+    ServerEvent::Connection(event) => assert_eq!((event.client_id.as_str(), event.change), (id.as_str(), ConnectionChange::Disconnected)),
+    other => panic!("expected the disconnection last, not {:?}", other),
+  }
+
+  // The stream ends once the server has stopped.
+  server.shutdown(true).unwrap();
+  let last = runtime.block_on(async { tokio::time::timeout(TIMEOUT, events.next()).await }).unwrap();
+  assert!(last.is_none(), "{:?}", last);
+}
@@ -0,0 +1,59 @@
+// Tests of ServerHandler, over the loopback transport.
+
+use std::sync::mpsc;
+use std::time::Duration;
+use quicksocket::server::{Message, Server, ServerConfig, ServerHandler, Transport};
+use quicksocket::server::events::{ClientClose, ClientMessage};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports each event it handles, and echoes text messages back to the client that sent them.
+struct Echo {
+  events: mpsc::Sender<String>,
+}
+
+impl ServerHandler for Echo {
+  fn on_connect(&mut self, _server: &Server, client_id: &str) {
+    self.events.send(format!("connect {}", client_id)).unwrap();
+  }
+
+  fn on_message(&mut self, server: &Server, message: ClientMessage) {
+    let text = message.message.to_text().unwrap().to_string();
+    self.events.send(format!("message {} {}", message.client_id, text)).unwrap();
+    server.send_to_client(&message.client_id, vec![Message::text(format!("echo: {}", text))]).unwrap();
+  }
+
+  fn on_close(&mut self, _server: &Server, client_id: &str, close: &ClientClose) {
+    self.events.send(format!("close {} {}", client_id, close.code)).unwrap();
+  }
+
+  fn on_disconnect(&mut self, _server: &Server, client_id: &str) {
+    self.events.send(format!("disconnect {}", client_id)).unwrap();
+  }
+}
+
+#[test]
+fn handler_sees_a_client_through_and_replies_to_it() {
+  let (events_tx, events) = mpsc::channel();
+  let config = ServerConfig { transport: Transport::Loopback, ..ServerConfig::default() };
+  let server = Server::start_with_handler(0, config, Echo { events: events_tx }).unwrap();
+  assert!(server.wait_until_started(Some(TIMEOUT)).unwrap());
+
+  let mut client = server.connect_loopback().unwrap();
+  let id = client.client_id().to_string();
+  client.send(vec![Message::text("one"), Message::text("two")]).unwrap();
+  assert_eq!(client.recv(Some(TIMEOUT)).unwrap(), Some(Message::text("echo: one")));
+  assert_eq!(client.recv(Some(TIMEOUT)).unwrap(), Some(Message::text("echo: two")));
+  client.close();
+  // (The dispatch thread has handled every event once shutdown(true) returns.)
+  server.shutdown(true).unwrap();
+
+  let seen: Vec<String> = events.try_iter().collect();
+  assert_eq!(seen, vec![
+    format!("connect {}", id),
+    format!("message {} one", id),
+    format!("message {} two", id),
+    format!("close {} 1005", id),
+    format!("disconnect {}", id),
+  ]);
+}
@@ -0,0 +1,73 @@
+// Tests of the Rust API over the loopback transport, which serves in-process clients without binding a port.
+
+use std::time::Duration;
+use quicksocket::server::{LoopbackClient, Message, Server, ServerConfig, Transport};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn loopback_server() -> Server {
+  let server = Server::start(0, ServerConfig { transport: Transport::Loopback, ..ServerConfig::default() }).unwrap();
+  assert!(server.wait_until_started(Some(TIMEOUT)).unwrap());
+  server
+}
+
+fn recv(client: &mut LoopbackClient) -> Message {
+  client.recv(Some(TIMEOUT)).unwrap().expect("no message from the server")
+}
+
+#[test]
+fn broadcasts_reach_every_client() {
+  let server = loopback_server();
+  let mut first = server.connect_loopback().unwrap();
+  let mut second = server.connect_loopback().unwrap();
+  server.send(vec![Message::text("hello"), Message::binary(vec![1, 2, 3])]).unwrap();
+  for client in [&mut first, &mut second] {
+    assert_eq!(recv(client), Message::text("hello"));
+    assert_eq!(recv(client), Message::binary(vec![1, 2, 3]));
+    client.close();
+  }
+  server.shutdown(true).unwrap();
+}
+
+#[test]
+fn client_messages_are_drained() {
+  let server = loopback_server();
+  let mut client = server.connect_loopback().unwrap();
+  client.send(vec![Message::text("one"), Message::binary(vec![2])]).unwrap();
+  let mut messages = vec![];
+  while messages.len() < 2 {
+    let drained = server.drain_messages(Some(TIMEOUT), usize::MAX).unwrap();
+    assert!(!drained.is_empty());
+    messages.extend(drained);
+  }
+  assert!(messages.iter().all(|msg| msg.client_id == client.client_id()));
+  assert_eq!(messages.into_iter().map(|msg| msg.message).collect::<Vec<_>>(), vec![Message::text("one"), Message::binary(vec![2])]);
+  client.close();
+  server.shutdown(true).unwrap();
+}
+
+#[test]
+fn sends_to_a_client_reach_only_it() {
+  let server = loopback_server();
+  let mut addressee = server.connect_loopback().unwrap();
+  let mut bystander = server.connect_loopback().unwrap();
+  assert_ne!(addressee.client_id(), bystander.client_id());
+  server.send_to_client(addressee.client_id(), vec![Message::text("just for you")]).unwrap();
+  server.send(vec![Message::text("for everyone")]).unwrap();
+  assert_eq!(recv(&mut addressee), Message::text("just for you"));
+  assert_eq!(recv(&mut addressee), Message::text("for everyone"));
+  assert_eq!(recv(&mut bystander), Message::text("for everyone"));
+  assert_eq!(bystander.recv(Some(Duration::from_millis(100))).unwrap(), None);
+  addressee.close();
+  bystander.close();
+  server.shutdown(true).unwrap();
+}
+
+#[test]
+fn clients_see_the_server_stop() {
+  let server = loopback_server();
+  let mut client = server.connect_loopback().unwrap();
+  server.shutdown(true).unwrap();
+  assert!(client.recv(Some(TIMEOUT)).is_err());
+  assert!(server.connect_loopback().is_err());
+}
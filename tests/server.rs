@@ -0,0 +1,54 @@
+// Tests of the Rust API (server::Server) over TCP, as a Rust program embedding the server would use it, with a websocket client of the test's own. (Nothing here needs the "python" feature.)
+
+use std::time::Duration;
+use quicksocket::server::{Error, Message, Server, ServerConfig};
+use quicksocket::server::events::ConnectionChange;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn tcp_server() -> (Server, u32) {
+  let server = Server::start(0, ServerConfig::default()).unwrap();
+  assert!(server.wait_until_started(Some(TIMEOUT)).unwrap());
+  let port = server.bound_port().expect("the server didn't report the port it bound");
+  (server, port)
+}
+
+#[test]
+fn a_client_round_trips_over_tcp() {
+  let (server, port) = tcp_server();
+  let (mut ws, _) = tokio_tungstenite::tungstenite::connect(format!("ws://127.0.0.1:{}", port)).unwrap();
+  assert!(server.wait_for_client(Some(TIMEOUT)).unwrap());
+
+  ws.write_message(Message::text("ping")).unwrap();
+  let drained = server.drain_messages(Some(TIMEOUT), usize::MAX).unwrap();
+  assert_eq!(drained.len(), 1);
+  assert_eq!(drained[0].message, Message::text("ping"));
+  let client_id = drained[0].client_id.clone();
+  assert!(server.is_client_connected(&client_id));
+
+  server.send(vec![Message::text("to everyone")]).unwrap();
+  server.send_to_client(&client_id, vec![Message::binary(vec![1, 2])]).unwrap();
+  assert_eq!(ws.read_message().unwrap(), Message::text("to everyone"));
+  assert_eq!(ws.read_message().unwrap(), Message::binary(vec![1, 2]));
+
+  ws.close(None).unwrap();
+  // (Read until the server's answer to the close, which ends the connection.)
+  while ws.read_message().is_ok() {}
+  server.shutdown(true).unwrap();
+  let changes: Vec<_> = server.drain_connection_events().into_iter().map(|event| (event.client_id, event.change)).collect();
+  assert_eq!(changes, vec![(client_id.clone(), ConnectionChange::Connected), (client_id, ConnectionChange::Disconnected)]);
+}
+
+#[test]
+fn errors_say_what_went_wrong() {
+  let (server, port) = tcp_server();
+  let clash = Server::start(port, ServerConfig::default()).unwrap();
+  match clash.wait_until_started(Some(TIMEOUT)) {
+    Err(Error::Bind { port: clash_port, .. }) => assert_eq!(clash_port, port),
+    other => panic!("expected a bind error, not {:?}", other),
+  }
+
+  server.shutdown(true).unwrap();
+  assert!(!server.is_running());
+  assert_eq!(server.send(vec![Message::text("too late")]), Err(Error::NotRunning));
+}
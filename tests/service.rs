@@ -0,0 +1,41 @@
+// Tests of the tower Service (the "tower" feature), mounted under a hyper server of the test's own.
+#![cfg(feature = "tower")]
+
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use futures_util::StreamExt;
+use hyper::service::make_service_fn;
+use quicksocket::server::{Message, Server, ServerConfig, Transport};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[test]
+fn broadcasts_right_after_connecting_are_received() {
+  let server = Server::start(0, ServerConfig { transport: Transport::Service, ..ServerConfig::default() }).unwrap();
+  assert!(server.wait_until_started(Some(TIMEOUT)).unwrap());
+  let service = server.service().unwrap();
+
+  let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+  runtime.block_on(async {
+    let make_service = make_service_fn(move |_| {
+      let service = service.clone();
+      async move { Ok::<_, Infallible>(service) }
+    });
+    let http = hyper::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+    let url = format!("ws://{}/ws", http.local_addr());
+    tokio::spawn(http);
+
+    // (Each client is sent a broadcast as soon as its handshake is done, so a client that's only subscribed once the server has taken it from the service misses it.)
+    for round in 0..20 {
+      let (mut ws, response) = tokio_tungstenite::connect_async(url.as_str()).await.unwrap();
+      assert_eq!(response.status(), 101);
+      let text = format!("round {}", round);
+      server.send(vec![Message::text(text.as_str())]).unwrap();
+      let received = tokio::time::timeout(TIMEOUT, ws.next()).await.unwrap_or_else(|_| panic!("round {}: the broadcast wasn't received", round)).unwrap().unwrap();
+      assert_eq!(received, Message::text(text));
+      ws.close(None).await.unwrap();
+      // (As a consumer would, so the server doesn't wait for room to report the next client.)
+      server.drain_connection_events();
+    }
+  });
+  server.shutdown(true).unwrap();
+}
@@ -0,0 +1,26 @@
+import asyncio
+import threading
+import time
+
+import quicksocket.aio
+import quicksocket.server
+
+def test_async_messages_end_when_server_stops():
+  port = 59989
+
+  server = quicksocket.server.Server(port)
+  server.start()
+  assert(server.wait_until_started(timeout_ms = 2000))
+  threading.Timer(0.200, server.stop).start()
+
+  async def collect():
+    return [msg async for msg in quicksocket.aio.messages(server)]
+
+  # Waiting on the message descriptor wakes up for the stop, rather than hanging.
+  started = time.time()
+  assert(asyncio.run(asyncio.wait_for(collect(), timeout = 5)) == [])
+  assert(time.time() - started < 2)
+  server.stop(wait = True)
+
+if __name__ == "__main__":
+  test_async_messages_end_when_server_stops()
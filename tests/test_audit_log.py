@@ -0,0 +1,99 @@
+'''Tests for the connection audit log (audit_log_path and friends): a line of JSON per connection accepted or rejected, and per accepted connection closed, in a file the server rotates.'''
+
+import json
+import os
+import socket
+import tempfile
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def raw_request(port: int, request: str) -> bytes:
+  '''Sends a request head and returns the response head, leaving the connection to be dropped.'''
+  sock = socket.create_connection(('127.0.0.1', port), timeout = 5)
+  sock.sendall(request.encode())
+  response = b''
+  while b'\r\n\r\n' not in response:
+    chunk = sock.recv(4096)
+    if not chunk:
+      break
+    response += chunk
+  sock.close()
+  return response
+
+def read_entries(path: str):
+  with open(path) as f:
+    return [json.loads(line) for line in f]
+
+def test_audit_log():
+  with tempfile.TemporaryDirectory() as tmp:
+    path = os.path.join(tmp, 'audit.jsonl')
+    with quicksocket.testing.running_server(audit_log_path = path, audit_principal_header = 'X-Forwarded-User') as server:
+      port = server.get_bound_port()
+      with quicksocket.testing.connect(server, path = '/feed') as client:
+        client_id = client.client_id
+      # An upgrade without a key is turned away.
+      rejected = raw_request(port, 'GET /feed HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n')
+      assert(rejected.startswith(b'HTTP/1.1 400'))
+      # A proxied client, named by its proxy, that drops its connection.
+      accepted = raw_request(port, 'GET /admin HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nX-Forwarded-User: alice\r\n\r\n')
+      assert(accepted.startswith(b'HTTP/1.1 101'))
+      # (Given time to notice, before the shutdown closes it instead.)
+      time.sleep(0.2)
+    # (Everything's in the file once the server has stopped.)
+    entries = read_entries(path)
+    by_event = lambda event: [entry for entry in entries if entry['event'] == event]
+    assert(len(by_event('accepted')) == 2 and len(by_event('rejected')) == 1 and len(by_event('closed')) == 2)
+    assert(all(entry['ip'] == '127.0.0.1' and entry['t'] > 0 for entry in entries))
+
+    feed = [entry for entry in entries if entry['peer'] == client_id]
+    assert([entry['event'] for entry in feed] == ['accepted', 'closed'])
+    assert(all(entry['path'] == '/feed' and entry['principal'] is None for entry in feed))
+    assert(feed[1]['reason'].startswith('the client closed the connection (1000'))
+
+    assert(by_event('rejected')[0]['path'] == '/feed')
+    assert('400' in by_event('rejected')[0]['reason'])
+
+    admin = [entry for entry in entries if entry['path'] == '/admin']
+    assert([entry['event'] for entry in admin] == ['accepted', 'closed'])
+    assert(all(entry['principal'] == 'alice' for entry in admin))
+    assert(admin[1]['reason'] == 'the connection was lost')
+
+def test_audit_log_records_shutdown():
+  with tempfile.TemporaryDirectory() as tmp:
+    path = os.path.join(tmp, 'audit.jsonl')
+    with quicksocket.testing.running_server(audit_log_path = path) as server:
+      client = quicksocket.testing.connect(server)
+    closed = [entry for entry in read_entries(path) if entry['event'] == 'closed']
+    assert(len(closed) == 1 and closed[0]['reason'].startswith('the server shut down'))
+    client.close()
+
+def test_audit_log_rotation():
+  with tempfile.TemporaryDirectory() as tmp:
+    path = os.path.join(tmp, 'audit.jsonl')
+    with quicksocket.testing.running_server(audit_log_path = path, audit_log_max_bytes = 400, audit_log_max_files = 2) as server:
+      for _ in range(8):
+        with quicksocket.testing.connect(server):
+          pass
+    assert(sorted(os.listdir(tmp)) == ['audit.jsonl', 'audit.jsonl.1', 'audit.jsonl.2'])
+    for name in os.listdir(tmp):
+      assert(os.path.getsize(os.path.join(tmp, name)) <= 400)
+      read_entries(os.path.join(tmp, name))
+
+def test_audit_log_arguments():
+  with tempfile.TemporaryDirectory() as tmp:
+    path = os.path.join(tmp, 'audit.jsonl')
+    # (A directory can't be appended to.)
+    for kwargs in [{"audit_log_max_bytes": 1000}, {"audit_principal_header": "X-Forwarded-User"}, {"audit_log_path": path, "audit_log_max_bytes": 0}, {"audit_log_path": tmp}]:
+      try:
+        quicksocket.Server(port = 0, **kwargs).start()
+        assert(False)
+      except ValueError:
+        pass
+
+if __name__ == "__main__":
+  test_audit_log()
+  test_audit_log_records_shutdown()
+  test_audit_log_rotation()
+  test_audit_log_arguments()
@@ -0,0 +1,69 @@
+'''Tests for capability negotiation (capability_formats): the offer clients are written, their declarations, and the format the server negotiates for each.'''
+
+import json
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain_connection_events(server, kind, timeout_s = 2.0):
+  events = []
+  deadline = time.time() + timeout_s
+  while not events and time.time() < deadline:
+    events += [event for event in server.drain_connection_events() if event.kind == kind]
+    time.sleep(0.01)
+  return events
+
+def test_negotiation():
+  with quicksocket.testing.running_server(capability_formats = ['webp', 'jpeg']) as server, quicksocket.testing.connect(server) as client:
+    assert(json.loads(client.expect(timeout_ms = 2000)) == {'topic': 'capabilities', 'formats': ['webp', 'jpeg']})
+    assert(server.get_client_capabilities(client.client_id) is None)
+
+    client.send(['capabilities:formats=png,jpeg;max_resolution=1280x720;topics=camera;quality=high'])
+    assert(json.loads(client.expect(timeout_ms = 2000)) == {'topic': 'capabilities', 'format': 'jpeg'})
+    events = drain_connection_events(server, 'capabilities')
+    assert(len(events) == 1 and events[0].client_id == client.client_id)
+
+    capabilities = server.get_client_capabilities(client.client_id)
+    assert(capabilities.formats == ['png', 'jpeg'] and capabilities.format == 'jpeg')
+    assert((capabilities.max_width, capabilities.max_height) == (1280, 720))
+    assert(capabilities.topics == ['camera'] and capabilities.extra == {'quality': 'high'})
+    assert(capabilities.wants('camera') and not capabilities.wants('pose'))
+    assert(list(server.get_all_client_capabilities()) == [client.client_id])
+
+    # A later declaration replaces it; with no format in common, there's none.
+    client.send(['capabilities:formats=avif'])
+    assert(json.loads(client.expect(timeout_ms = 2000)) == {'topic': 'capabilities', 'format': None})
+    capabilities = server.get_client_capabilities(client.client_id)
+    assert(capabilities.format is None and capabilities.topics is None and capabilities.wants('pose'))
+
+    # Declarations aren't passed on to the consumer, but other messages are.
+    client.send(['hello'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['hello'])
+
+def test_malformed_declarations():
+  with quicksocket.testing.running_server(capability_formats = ['jpeg']) as server, quicksocket.testing.connect(server) as client:
+    client.expect(timeout_ms = 2000)
+    server.drain_error_events()
+    client.send(['capabilities:formats', 'capabilities:max_resolution=big', 'capabilities:formats=jpeg'])
+    assert(json.loads(client.expect(timeout_ms = 2000)) == {'topic': 'capabilities', 'format': 'jpeg'})
+    errors = [error for error in server.drain_error_events() if 'capability' in error.message]
+    assert(len(errors) == 2 and all(error.severity == 'warning' and error.category == 'receive' for error in errors))
+
+def test_capabilities_off():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client.send(['capabilities:formats=jpeg'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['capabilities:formats=jpeg'])
+    assert(server.get_client_capabilities(client.client_id) is None)
+
+  for formats in [['a,b'], ['']]:
+    try:
+      quicksocket.Server(port = 0, capability_formats = formats).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == '__main__':
+  test_negotiation()
+  test_malformed_declarations()
+  test_capabilities_off()
@@ -0,0 +1,48 @@
+'''Tests for chaos testing (chaos_seed): connections dropped, writes held back and messages reordered within bounds, reproducibly for a seed.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def test_drops_connections():
+  with quicksocket.testing.running_server(chaos_seed = 1, chaos_drop_rate = 1.0) as server, quicksocket.testing.connect(server) as client:
+    server.send_messages(["lost"])
+    # Dropped without a close frame, rather than written to.
+    try:
+      client.recv(timeout_ms = 2000)
+      assert(False)
+    except ConnectionError:
+      pass
+    deadline = time.monotonic() + 2
+    while server.is_client_connected(client.client_id) and time.monotonic() < deadline:
+      time.sleep(0.01)
+    assert(not server.is_client_connected(client.client_id))
+
+def reordered(seed):
+  '''The order the first client of a server with the given seed gets 30 messages sent together in.'''
+  with quicksocket.testing.running_server(chaos_seed = seed, chaos_reorder_window = 3, chaos_max_delay_ms = 5) as server, quicksocket.testing.connect(server) as client:
+    server.send_messages([str(i) for i in range(30)])
+    return [int(client.expect()) for _ in range(30)]
+
+def test_reorders_within_bounds_reproducibly():
+  order = reordered(7)
+  assert(sorted(order) == list(range(30)))
+  assert(order != list(range(30)))
+  assert(all(abs(position - i) <= 3 for position, i in enumerate(order)))
+  # The same seed, the same order; and another seed, (almost certainly) another.
+  assert(reordered(7) == order)
+  assert(reordered(8) != order)
+
+def test_chaos_arguments():
+  for kwargs in [{"chaos_seed": 1, "chaos_drop_rate": 1.5}, {"chaos_seed": 1, "chaos_max_delay_ms": -1}, {"chaos_reorder_window": 2}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_drops_connections()
+  test_reorders_within_bounds_reproducibly()
+  test_chaos_arguments()
@@ -0,0 +1,123 @@
+'''Tests for the standalone quicksocket binary, run as a subprocess. Needs it built (cargo build --no-default-features --features cli), and found: at $QUICKSOCKET_BIN, on the PATH, or in cargo's target directory ($CARGO_TARGET_DIR, or target/); skipped otherwise.'''
+
+import os
+import shutil
+import signal
+import subprocess
+import tempfile
+import time
+import urllib.request
+
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+
+def find_binary():
+  '''The path of the quicksocket binary, or None if it can't be found.'''
+  if os.environ.get("QUICKSOCKET_BIN"):
+    return os.environ["QUICKSOCKET_BIN"] if os.path.isfile(os.environ["QUICKSOCKET_BIN"]) else None
+  on_path = shutil.which("quicksocket")
+  if on_path is not None:
+    return on_path
+  target = os.environ.get("CARGO_TARGET_DIR") or os.path.join(os.path.dirname(os.path.abspath(__file__)), "..", "target")
+  built = os.path.join(target, "debug", "quicksocket")
+  return built if os.path.isfile(built) else None
+
+BINARY = find_binary()
+if pytest is not None:
+  pytestmark = pytest.mark.skipif(BINARY is None, reason = "the quicksocket binary isn't built (cargo build --no-default-features --features cli), or QUICKSOCKET_BIN doesn't point to it.")
+
+def start(*args, **kwargs) -> (subprocess.Popen, int):
+  '''Runs the binary on a port it picks, returning the process and the port (read from its stderr).'''
+  process = subprocess.Popen([BINARY, "--port", "0"] + list(args), stdin = subprocess.PIPE, stdout = subprocess.PIPE, stderr = subprocess.PIPE, **kwargs)
+  while True:
+    line = process.stderr.readline().decode()
+    assert(line != "")
+    if line.startswith("quicksocket: listening on "):
+      return process, int(line.rsplit(":", 1)[1])
+
+def test_lines_in_and_out():
+  process, port = start("--client-ids")
+  with quicksocket.testing.connect(port) as first, quicksocket.testing.connect(port) as second:
+    # Lines written together are broadcast together; blank lines are skipped.
+    process.stdin.write(b"hello\r\n\n{\"t\": 1}\n")
+    process.stdin.flush()
+    for client in (first, second):
+      assert(client.expect() == "hello")
+      assert(client.expect() == '{"t": 1}')
+
+    first.send(["from the first"])
+    assert(process.stdout.readline() == "{}\tfrom the first\n".format(first.client_id).encode())
+    # Line breaks become spaces; binary messages are skipped.
+    second.send([b"\x00binary", "two\nlines"])
+    assert(process.stdout.readline() == "{}\ttwo lines\n".format(second.client_id).encode())
+
+    # The end of stdin stops the server, closing the connections.
+    process.stdin.close()
+    assert(process.wait(timeout = 5) == 0)
+    try:
+      first.recv(timeout_ms = 1000)
+      assert(False)
+    except ConnectionError as err:
+      assert("1001" in str(err))
+
+def test_config_file_and_signals():
+  with tempfile.TemporaryDirectory() as temp_dir:
+    with open(os.path.join(temp_dir, "landing.html"), "w") as landing:
+      landing.write("<p>Hi.</p>")
+    config_path = os.path.join(temp_dir, "quicksocket.conf")
+    with open(config_path, "w") as config:
+      config.write("# Paths are relative to this file.\nport = 1\nlanding_page = landing.html\ninput = none   # Nothing to broadcast.\noutput = messages.txt\n")
+    # Flags override the file.
+    process, port = start("--config", config_path)
+    assert(urllib.request.urlopen("http://127.0.0.1:{}/".format(port)).read() == b"<p>Hi.</p>")
+    with quicksocket.testing.connect(port) as client:
+      client.send(["logged"])
+      output_path = os.path.join(temp_dir, "messages.txt")
+      assert(wait_until(lambda: os.path.exists(output_path) and open(output_path).read() == "logged\n"))
+    # Input's off, so only a signal stops it.
+    time.sleep(0.2)
+    assert(process.poll() is None)
+    process.send_signal(signal.SIGTERM)
+    assert(process.wait(timeout = 5) == 0)
+
+def test_named_pipes_are_reopened():
+  with tempfile.TemporaryDirectory() as temp_dir:
+    input_path = os.path.join(temp_dir, "in")
+    os.mkfifo(input_path)
+    process, port = start("--input", input_path, "--output", "none")
+    with quicksocket.testing.connect(port) as client:
+      # One writer after another.
+      for text in ("first writer", "second writer"):
+        with open(input_path, "w") as pipe:
+          pipe.write(text + "\n")
+        assert(client.expect() == text)
+      # Client messages are discarded.
+      client.send(["nowhere"])
+    process.send_signal(signal.SIGINT)
+    assert(process.wait(timeout = 5) == 0)
+    assert(process.stdout.read() == b"")
+
+def test_bad_arguments():
+  for args in (["--port"], ["--port", "nine"], ["--unknown", "setting"], ["--log-level", "loud"], ["--config", "/nonexistent"], ["stray"]):
+    result = subprocess.run([BINARY] + args, capture_output = True)
+    assert(result.returncode == 2)
+    assert(b"quicksocket --help" in result.stderr)
+  # A port is required.
+  result = subprocess.run([BINARY], capture_output = True)
+  assert(result.returncode == 1)
+  result = subprocess.run([BINARY, "--help"], capture_output = True)
+  assert(result.returncode == 0 and result.stdout.startswith(b"Usage: quicksocket"))
+
+if __name__ == "__main__":
+  if BINARY is None:
+    print("Skipped: the quicksocket binary isn't built (cargo build --no-default-features --features cli), or QUICKSOCKET_BIN doesn't point to it.")
+  else:
+    test_lines_in_and_out()
+    test_config_file_and_signals()
+    test_named_pipes_are_reopened()
+    test_bad_arguments()
@@ -0,0 +1,64 @@
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain(source, count: int, structured: bool = False):
+  '''Drains messages from a Server or Client until count have arrived (or a drain times out).'''
+  msgs = []
+  while len(msgs) < count:
+    drained = source.drain_client_messages(timeout_ms = 1000, structured = structured)
+    if not drained:
+      break
+    msgs += drained
+  return msgs
+
+def test_client_round_trip():
+  with quicksocket.testing.running_server() as upstream:
+    url = "ws://127.0.0.1:{}/".format(upstream.get_bound_port())
+    with quicksocket.connect_to(url, timeout_ms = 5000) as client:
+      assert(client.is_connected() and client.url == url)
+      client.send_messages(["hello", b"\x01"])
+      assert(drain(upstream, 2) == ["hello", b"\x01"])
+
+      client.send_and_confirm(["confirmed"], timeout_ms = 1000)
+      assert(drain(upstream, 1) == ["confirmed"])
+
+      upstream.send_messages(["feed 1", b"feed 2"])
+      msgs = drain(client, 2, structured = True)
+      assert([msg.data for msg in msgs] == ["feed 1", b"feed 2"])
+      assert(all(msg.client_id == url for msg in msgs))
+    assert(not client.is_connected())
+
+    # The upstream server sees a clean disconnection.
+    kinds = [event.kind for event in upstream.drain_connection_events()]
+    assert(kinds == ["connected", "disconnected"])
+
+def test_client_sees_server_close():
+  with quicksocket.testing.running_server() as upstream:
+    client = quicksocket.connect_to("ws://127.0.0.1:{}/".format(upstream.get_bound_port()))
+    upstream.send_messages(["last words"])
+    assert(drain(client, 1) == ["last words"])
+    upstream.stop(wait = True)
+    deadline = time.monotonic() + 5
+    while client.is_connected() and time.monotonic() < deadline:
+      time.sleep(0.010)
+    assert(not client.is_connected())
+    try:
+      client.send_messages(["anyone?"])
+      assert(False)
+    except quicksocket.QuicksocketError as e:
+      assert("closed" in str(e))
+
+def test_connect_errors():
+  for url in ["ws://127.0.0.1:1/", "wss://example.invalid/"]:
+    try:
+      quicksocket.connect_to(url, timeout_ms = 2000)
+      assert(False)
+    except quicksocket.ConnectError as e:
+      assert(e.url == url and e.reason)
+
+if __name__ == "__main__":
+  test_client_round_trip()
+  test_client_sees_server_close()
+  test_connect_errors()
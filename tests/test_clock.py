@@ -0,0 +1,57 @@
+'''Tests for server-side timestamps (send_stamped()) and clock sync (time_sync): messages stamped with the server's clocks, and time sync requests answered by the server itself.'''
+
+import json
+import struct
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def test_stamped_messages():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    before_us = time.time() * 1e6
+    server.send_stamped(['{"x": 1}', '{}', 'plain text', b'\x01\x02'])
+    after_us = time.time() * 1e6
+
+    spliced = json.loads(client.expect())
+    assert(list(spliced.keys()) == ['server_time_us', 'server_mono_us', 'x'] and spliced['x'] == 1)
+    assert(before_us - 1e6 <= spliced['server_time_us'] <= after_us + 1e6)
+
+    empty = json.loads(client.expect())
+    assert(empty == {'server_time_us': spliced['server_time_us'], 'server_mono_us': spliced['server_mono_us']})
+
+    wrapped = json.loads(client.expect())
+    assert(wrapped['data'] == 'plain text' and wrapped['server_time_us'] == spliced['server_time_us'])
+
+    binary = client.expect()
+    assert(struct.unpack('>QQ', binary[:16]) == (spliced['server_time_us'], spliced['server_mono_us']))
+    assert(binary[16:] == b'\x01\x02')
+
+    # The monotonic clock goes on from where it was.
+    time.sleep(0.01)
+    server.send_stamped(['{}'])
+    assert(json.loads(client.expect())['server_mono_us'] >= spliced['server_mono_us'] + 10000)
+
+def test_time_sync():
+  with quicksocket.testing.running_server(time_sync = True) as server, quicksocket.testing.connect(server) as client:
+    t0 = time.time() * 1e6
+    client.send(['time_sync:%f' % t0])
+    reply = json.loads(client.expect())
+    t3 = time.time() * 1e6
+    assert(reply['topic'] == 'time_sync' and reply['echo'] == '%f' % t0)
+    # (The same clock, so within the round trip.)
+    assert(t0 - 1000 <= reply['server_time_us'] <= t3 + 1000)
+    # Answered by the server: the consumer doesn't see them, but sees other messages.
+    client.send(['hello'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['hello'])
+
+def test_time_sync_off():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client.send(['time_sync:1'])
+    assert(client.recv(timeout_ms = 200) is None)
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['time_sync:1'])
+
+if __name__ == "__main__":
+  test_stamped_messages()
+  test_time_sync()
+  test_time_sync_off()
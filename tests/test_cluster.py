@@ -0,0 +1,168 @@
+'''Tests for cluster mode: several servers in one process, on ports picked up front, linked up as cluster nodes.'''
+
+import socket
+import time
+
+import quicksocket
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+def free_port() -> int:
+  with socket.socket() as probe:
+    probe.bind(("127.0.0.1", 0))
+    return probe.getsockname()[1]
+
+def url(port: int) -> str:
+  return "ws://127.0.0.1:{}".format(port)
+
+def start_nodes(count: int, **kwargs):
+  '''Starts count servers, each listing all the others as peers, and waits for every link to be up.'''
+  ports = [free_port() for _ in range(count)]
+  nodes = []
+  for i, port in enumerate(ports):
+    node = quicksocket.Server(port = port, cluster_peers = [url(other) for other in ports if other != port], node_id = "node-{}".format(i), **kwargs)
+    node.start()
+    assert(node.wait_until_started(timeout_ms = 5000))
+    nodes.append(node)
+  assert(wait_until(lambda: all(peer.state == "up" for node in nodes for peer in node.get_cluster_peers())))
+  return nodes
+
+def stop_nodes(nodes):
+  for node in nodes:
+    if node.is_running():
+      node.stop(wait = True)
+
+def test_broadcasts_fan_out():
+  nodes = start_nodes(3)
+  try:
+    assert([node.get_cluster_node_id() for node in nodes] == ["node-0", "node-1", "node-2"])
+    peers = nodes[0].get_cluster_peers()
+    assert([peer.node_id for peer in peers] == ["node-1", "node-2"])
+    assert(all(peer.last_error is None for peer in peers))
+    # (Measured by the links' pings, the first of which goes out on linking up.)
+    assert(wait_until(lambda: all(peer.rtt_ms is not None for peer in nodes[0].get_cluster_peers())))
+
+    clients = [quicksocket.testing.connect(node) for node in nodes]
+    # Peers aren't clients.
+    assert([node.get_stats().current_clients for node in nodes] == [1, 1, 1])
+
+    nodes[0].send_messages(["from node 0"])
+    nodes[2].send_messages([b"\x00from node 2", "and more"])
+    for client in clients:
+      received = [client.expect() for _ in range(3)]
+      assert(sorted(received, key = repr) == sorted(["from node 0", b"\x00from node 2", "and more"], key = repr))
+      # Exactly once each: relayed broadcasts aren't relayed again.
+      assert(client.recv(timeout_ms = 200) is None)
+
+    # Messages for a single client stay on its node.
+    nodes[1].send_to_client(clients[1].client_id, ["just for you"])
+    assert(clients[1].expect() == "just for you")
+    assert(all(client.recv(timeout_ms = 200) is None for client in clients))
+
+    peers = nodes[0].get_cluster_peers()
+    assert([(peer.sent, peer.dropped) for peer in peers] == [(1, 0), (1, 0)])
+    assert([peer.received for peer in peers] == [0, 1])
+
+    # Client messages are still each node's own.
+    clients[1].send(["to node 1"])
+    assert(nodes[1].drain_client_messages(timeout_ms = 1000) == ["to node 1"])
+    assert(nodes[0].drain_client_messages(timeout_ms = 100) == [])
+    for client in clients:
+      client.close()
+  finally:
+    stop_nodes(nodes)
+
+def test_text_is_relayed():
+  nodes = start_nodes(2)
+  try:
+    client = quicksocket.testing.connect(nodes[1])
+    texts = ["ascii", "caf\u00e9 \u5024\u6bb5 \U0001f600", "y" * 100000]
+    nodes[0].send_messages(texts + [b"\xff not text"])
+    assert([client.expect() for _ in range(4)] == texts + [b"\xff not text"])
+    client.close()
+  finally:
+    stop_nodes(nodes)
+
+def test_peer_health_follows_the_peers():
+  nodes = start_nodes(2)
+  try:
+    first, second = nodes
+    up_since = first.get_cluster_peers()[0].since
+    first.drain_error_events()
+
+    second.stop(wait = True)
+    assert(wait_until(lambda: first.get_cluster_peers()[0].state == "down"))
+    peer = first.get_cluster_peers()[0]
+    assert(peer.since > up_since)
+    assert(peer.last_error is not None)
+    # Broadcasts while a peer's down are counted as dropped for it.
+    first.send_messages(["nobody else hears this"])
+    assert(first.get_cluster_peers()[0].dropped == 1)
+
+    # Retried in the background, with one error for the whole outage.
+    time.sleep(2.5)
+    errors = [error for error in first.drain_error_events() if error.category == "cluster"]
+    assert(len(errors) == 1)
+
+    second.start()
+    assert(wait_until(lambda: first.get_cluster_peers()[0].state == "up"))
+    assert(wait_until(lambda: second.get_cluster_peers()[0].state == "up"))
+    with quicksocket.testing.connect(second) as client:
+      first.send_messages(["back again"])
+      assert(client.expect() == "back again")
+  finally:
+    stop_nodes(nodes)
+
+def test_links_are_refused():
+  ports = [free_port(), free_port()]
+  # A peer with the wrong secret, and a node listed as its own peer.
+  first = quicksocket.Server(port = ports[0], cluster_peers = [url(ports[1]), url(ports[0])], node_id = "first", cluster_secret = "one secret")
+  second = quicksocket.Server(port = ports[1], cluster_peers = [url(ports[0])], node_id = "second", cluster_secret = "another secret")
+  try:
+    quicksocket.quicksocket.drain_error_events()
+    first.start()
+    second.start()
+    # (The first attempts may have been made before the other node was listening.)
+    assert(wait_until(lambda: all("secret" in (node.get_cluster_peers()[0].last_error or "") for node in (first, second))))
+    to_second, to_self = first.get_cluster_peers()
+    assert((to_second.state, to_self.state) == ("down", "down"))
+    assert("this node's id" in to_self.last_error)
+    assert(to_second.node_id is None)
+
+    with quicksocket.testing.connect(second) as client:
+      first.send_messages(["kept out"])
+      assert(client.recv(timeout_ms = 200) is None)
+
+    # Retries aren't recorded again: each node records its failed links, and each refusal of the other's, once.
+    time.sleep(2.5)
+    errors = [error for error in quicksocket.quicksocket.drain_error_events() if error.category == "cluster"]
+    assert(len([error for error in errors if "Refused" in error.message]) == 3)
+    assert(len([error for error in errors if "Cluster peer" in error.message]) == 3)
+  finally:
+    stop_nodes([first, second])
+
+def test_bad_configurations_are_rejected():
+  for kwargs in (
+    {"cluster_peers": ["wss://127.0.0.1:9001"]},
+    {"cluster_peers": ["127.0.0.1:9001"]},
+    {"cluster_peers": [], "node_id": "x" * 256},
+    {"cluster_peers": [], "loopback": True},
+    {"node_id": "without peers"},
+  ):
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+  # Servers that aren't cluster nodes have no peers.
+  with quicksocket.testing.running_server() as server:
+    assert(server.get_cluster_node_id() is None)
+    assert(server.get_cluster_peers() == [])
+
+if __name__ == "__main__":
+  test_broadcasts_fan_out()
+  test_trusted_text_is_relayed()
+  test_peer_health_follows_the_peers()
+  test_links_are_refused()
+  test_bad_configurations_are_rejected()
@@ -0,0 +1,62 @@
+'''Tests for compression (compression, compression_min_bytes, compression_threads): the permessage-deflate extension, broadcasts deflated once for every client that agreed to it, and clients' deflated messages inflated.'''
+
+import json
+
+import quicksocket
+import quicksocket.testing
+
+BIG = json.dumps([{'x': i, 'y': i * 2, 'label': 'point'} for i in range(200)])
+
+def test_broadcasts_deflated():
+  with quicksocket.testing.running_server(compression = True) as server, \
+      quicksocket.testing.connect(server, compression = True) as deflating, quicksocket.testing.connect(server, compression = True) as other, \
+      quicksocket.testing.connect(server) as plain:
+    assert(deflating.compression and other.compression and not plain.compression)
+    server.send_messages([BIG, 'small', BIG.encode() * 4])
+    for client in [deflating, other, plain]:
+      assert(client.expect() == BIG and client.expect() == 'small' and client.expect() == BIG.encode() * 4)
+    # The small message went out as it was, and only to the clients that agreed to compression were the others deflated.
+    assert((deflating.deflated_received, other.deflated_received, plain.deflated_received) == (2, 2, 0))
+
+    # Sent to a client alone, a message goes out as it is.
+    server.send_to_client(deflating.client_id, [BIG])
+    assert(deflating.expect() == BIG and deflating.deflated_received == 2)
+
+def test_min_bytes():
+  with quicksocket.testing.running_server(compression = True, compression_min_bytes = 100000, compression_threads = 1) as server, quicksocket.testing.connect(server, compression = True) as client:
+    server.send_messages([BIG])
+    assert(client.expect() == BIG and client.deflated_received == 0)
+
+def test_clients_deflated():
+  with quicksocket.testing.running_server(compression = True) as server, quicksocket.testing.connect(server, compression = True) as client:
+    # (The client keeps its context from message to message, so the second refers back to the first.)
+    client.send([BIG, BIG, b'\x00\x01' * 1000], deflate = True)
+    client.send(['plain'])
+    msgs = []
+    while len(msgs) < 4:
+      drained = server.drain_client_messages(timeout_ms = 1000)
+      assert(drained)
+      msgs += drained
+    assert(msgs == [BIG, BIG, b'\x00\x01' * 1000, 'plain'])
+
+def test_not_offered():
+  # A server without compression doesn't agree to it.
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server, compression = True) as client:
+    assert(not client.compression)
+    server.send_messages([BIG])
+    assert(client.expect() == BIG and client.deflated_received == 0)
+
+def test_compression_arguments():
+  for kwargs in [{'compression': True, 'compression_threads': 0}, {'compression_min_bytes': 10}, {'compression_threads': 2}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == '__main__':
+  test_broadcasts_deflated()
+  test_min_bytes()
+  test_clients_deflated()
+  test_not_offered()
+  test_compression_arguments()
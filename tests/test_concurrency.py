@@ -0,0 +1,111 @@
+import threading
+import time
+
+import quicksocket.server
+
+def test_concurrent_sends_and_drains():
+  port = 59991
+
+  with quicksocket.server.Server(port) as server:
+    errors = []
+    def send_loop():
+      try:
+        for i in range(200):
+          server.send_messages(["message " + str(i)])
+      except Exception as e:
+        errors.append(e)
+    def drain_loop():
+      try:
+        for i in range(20):
+          server.drain_client_messages(timeout_ms = 10)
+      except Exception as e:
+        errors.append(e)
+
+    threads = [threading.Thread(target = send_loop) for i in range(4)] + [threading.Thread(target = drain_loop) for i in range(4)]
+    for thread in threads: thread.start()
+    for thread in threads: thread.join(timeout = 10)
+    assert(not any(thread.is_alive() for thread in threads))
+    assert(errors == [])
+
+def test_drain_does_not_wait_behind_another_drain():
+  port = 59990
+
+  with quicksocket.server.Server(port) as server:
+    waiting = threading.Thread(target = lambda: server.drain_client_messages(timeout_ms = 1000))
+    waiting.start()
+    time.sleep(0.100)
+
+    # Another thread is waiting on the receiver; a non-blocking drain returns right away, and a blocking one gives up after its own timeout.
+    started = time.time()
+    assert(server.drain_client_messages() == [])
+    assert(server.drain_client_messages(timeout_ms = 100) == [])
+    assert(time.time() - started < 0.500)
+
+    # Sends aren't held up by the waiting drain either.
+    server.send_messages(["hello"])
+    waiting.join()
+
+def test_calls_never_fail_while_the_receivers_change_hands():
+  import quicksocket.testing
+
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    errors = []
+    stop = threading.Event()
+    def run(operation):
+      try:
+        while not stop.is_set():
+          operation()
+      except Exception as e:
+        errors.append(e)
+    def claim_and_release():
+      # A message callback claims the client message receiver for as long as it's set.
+      server.set_on_message(lambda message: None)
+      server.set_on_message(None)
+
+    sent = [0]
+    def send():
+      server.send_messages(["message " + str(sent[0])])
+      sent[0] += 1
+    threads = [threading.Thread(target = run, args = (operation,)) for operation in (
+      send,
+      claim_and_release,
+      lambda: server.drain_client_messages(timeout_ms = 1),
+      server.drain_connection_events,
+      server.get_state,
+    )]
+    for thread in threads: thread.start()
+    time.sleep(0.5)
+    stop.set()
+    for thread in threads: thread.join(timeout = 10)
+    assert(not any(thread.is_alive() for thread in threads))
+    assert(errors == [])
+
+    # Every send was broadcast: the client got it, or fell behind and skipped it.
+    received = 0
+    while client.recv(timeout_ms = 200) is not None:
+      received += 1
+    assert(received > 0)
+    assert(received + server.get_stats().messages_dropped == sent[0])
+
+def test_big_batches_drain_whole_and_in_order():
+  import quicksocket.testing
+
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    expected = ["m" + str(i) for i in range(20000)]
+    client.send(expected)
+    received = []
+    deadline = time.time() + 10
+    # In pieces, and in both forms, so each drain reuses the buffer the one before it filled.
+    while len(received) < len(expected) and time.time() < deadline:
+      if len(received) % 2 == 0:
+        received += server.drain_client_messages(timeout_ms = 100, max_messages = 7000)
+      else:
+        received += [msg.data for msg in server.drain_client_messages(timeout_ms = 100, max_messages = 5000, structured = True)]
+    assert(received == expected)
+    assert(server.drain_client_messages() == [])
+
+if __name__ == "__main__":
+  test_concurrent_sends_and_drains()
+  test_drain_does_not_wait_behind_another_drain()
+  test_calls_never_fail_while_the_receivers_change_hands()
+  test_big_batches_drain_whole_and_in_order()
@@ -0,0 +1,75 @@
+'''Tests for server-side decimation (max_topic_rates_hz and send_sample()): samples on a rate-limited topic going out at most at its rate, the rest dropped or averaged.'''
+
+import json
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def recv_all(client, timeout_ms = 300):
+  messages = []
+  while True:
+    message = client.recv(timeout_ms = timeout_ms)
+    if message is None:
+      return messages
+    messages.append(message)
+
+def test_drop():
+  with quicksocket.testing.running_server(max_topic_rates_hz = {'imu': 10}) as server, quicksocket.testing.connect(server) as client:
+    for i in range(50):
+      server.send_sample('imu', json.dumps({'i': i}))
+    # The first straight away, and the latest as the interval's up.
+    assert([json.loads(message)['i'] for message in recv_all(client)] == [0, 49])
+    assert(server.get_stats().samples_decimated == 48)
+
+def test_average():
+  with quicksocket.testing.running_server(max_topic_rates_hz = {'imu': 10}, decimation = 'average') as server, quicksocket.testing.connect(server) as client:
+    server.send_sample('imu', '{"x": 0, "label": "a"}')
+    for x in [1, 2, 3, 6]:
+      server.send_sample('imu', json.dumps({'x': x, 'label': 'b'}))
+    assert([json.loads(message) for message in recv_all(client)] == [{'x': 0, 'label': 'a'}, {'x': 3, 'label': 'b'}])
+
+    time.sleep(0.2)
+    server.send_sample('imu', '[1, 2]')
+    server.send_sample('imu', '[3, 4]')
+    server.send_sample('imu', '[5, 6]')
+    assert([json.loads(message) for message in recv_all(client)] == [[1, 2], [4, 5]])
+
+    # Samples that can't be averaged go out as with 'drop'.
+    time.sleep(0.2)
+    server.send_sample('imu', b'\x01')
+    server.send_sample('imu', b'\x02')
+    server.send_sample('imu', b'\x03')
+    assert(recv_all(client) == [b'\x01', b'\x03'])
+
+def test_rate():
+  with quicksocket.testing.running_server(max_topic_rates_hz = {'imu': 20}) as server, quicksocket.testing.connect(server) as client:
+    started = time.time()
+    while time.time() - started < 0.5:
+      server.send_sample('imu', 'sample')
+      time.sleep(0.001)
+    received = recv_all(client)
+    # At most 20 a second (and one to start with).
+    assert(5 <= len(received) <= 12)
+
+def test_undecimated_topics():
+  with quicksocket.testing.running_server(max_topic_rates_hz = {'imu': 1}) as server, quicksocket.testing.connect(server) as client:
+    for i in range(3):
+      server.send_sample('other', str(i))
+    assert(recv_all(client) == ['0', '1', '2'])
+    assert(server.get_stats().samples_decimated == 0)
+
+def test_invalid_config():
+  for kwargs in [{'max_topic_rates_hz': {'imu': 0}}, {'max_topic_rates_hz': {'imu': 1e-20}}, {'decimation': 'median'}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_drop()
+  test_average()
+  test_rate()
+  test_undecimated_topics()
+  test_invalid_config()
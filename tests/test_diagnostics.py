@@ -0,0 +1,44 @@
+'''Tests for get_diagnostics(): what a server holds right now, back where it started once its clients have gone.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def wait_for(condition, timeout_s = 5):
+  deadline = time.monotonic() + timeout_s
+  while not condition() and time.monotonic() < deadline:
+    time.sleep(0.01)
+  return condition()
+
+def test_clients_are_cleaned_up():
+  with quicksocket.testing.running_server() as server:
+    idle = server.get_diagnostics()
+    assert((idle.open_sockets, idle.tasks, idle.registered_clients) == (0, 0, 0))
+    clients = [quicksocket.testing.connect(server) for _ in range(3)]
+    assert(wait_for(lambda: server.get_diagnostics().connected_clients == 3))
+    diagnostics = server.get_diagnostics()
+    assert(diagnostics.open_sockets == 3 and diagnostics.registered_clients == 3)
+    assert(diagnostics.tasks >= 6 and diagnostics.broadcast_subscribers >= 3)
+    assert(diagnostics.queues["targeted_sends"][1] > 0)
+    for client in clients:
+      client.close()
+    def cleaned_up():
+      diagnostics = server.get_diagnostics()
+      return (diagnostics.open_sockets, diagnostics.tasks, diagnostics.registered_clients) == (0, 0, 0)
+    assert(wait_for(cleaned_up))
+    assert(server.get_diagnostics().broadcast_subscribers == 0)
+
+def test_queues_and_heap():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client.send(["hello"])
+    assert(wait_for(lambda: server.get_diagnostics().queues["client_messages"][0] == 1))
+    diagnostics = server.get_diagnostics()
+    assert(diagnostics.queued_bytes == len("hello"))
+    assert(diagnostics.heap_bytes == diagnostics.queued_bytes + diagnostics.pooled_buffer_bytes)
+    server.drain_client_messages()
+    assert(server.get_diagnostics().queues["client_messages"] == (0, diagnostics.queues["client_messages"][1]))
+
+if __name__ == "__main__":
+  test_clients_are_cleaned_up()
+  test_queues_and_heap()
@@ -0,0 +1,94 @@
+'''Tests for draining servers (begin_draining()): they stop accepting connections, and free their port, while their clients go on being served, and report being drained once the last of them has disconnected.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+def test_draining_serves_connected_clients():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    port = server.get_bound_port()
+    server.drain_connection_events()
+    server.begin_draining()
+    assert(wait_until(lambda: server.get_state() == quicksocket.ServerState.DRAINING))
+    assert(server.is_running())
+    # New connections are refused...
+    try:
+      quicksocket.testing.connect(port, timeout_ms = 1000)
+      assert(False)
+    except OSError:
+      pass
+    # ... but the client still gets its messages, broadcasts included, and its own go through.
+    server.send_messages(["broadcast"])
+    assert(client.expect() == "broadcast")
+    server.send_to_client(client.client_id, ["targeted"])
+    assert(client.expect() == "targeted")
+    client.send(["still here"])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ["still here"])
+    assert(not server.wait_until_drained(timeout_ms = 100))
+    assert(server.drain_connection_events() == [])
+
+    # Once it's gone, the server's drained.
+    client.close()
+    assert(server.wait_until_drained(timeout_ms = 5000))
+    events = []
+    assert(wait_until(lambda: events.extend(server.drain_connection_events()) or len(events) >= 2))
+    assert([(event.client_id, event.kind) for event in events] == [(client.client_id, "disconnected"), ("", "drained")])
+    assert(repr(events[1]) == "<quicksocket.ConnectionEvent: drained>")
+    # It keeps running until it's stopped.
+    assert(server.get_state() == quicksocket.ServerState.DRAINING)
+    server.stop(wait = True)
+    assert(server.get_state() == quicksocket.ServerState.STOPPED)
+
+def test_draining_without_clients():
+  with quicksocket.testing.running_server() as server:
+    server.begin_draining()
+    assert(server.wait_until_drained(timeout_ms = 5000))
+    events = []
+    assert(wait_until(lambda: events.extend(server.drain_connection_events()) or len(events) >= 1))
+    assert([event.kind for event in events] == ["drained"])
+    # Draining again does nothing.
+    server.begin_draining()
+    time.sleep(0.1)
+    assert(server.drain_connection_events() == [])
+
+def test_draining_frees_the_port():
+  with quicksocket.testing.running_server() as old, quicksocket.testing.connect(old) as old_client:
+    port = old.get_bound_port()
+    old.begin_draining()
+    assert(wait_until(lambda: old.get_state() == quicksocket.ServerState.DRAINING))
+    # Its replacement takes the port over, while the old one still serves its client.
+    with quicksocket.Server(port = port) as new:
+      assert(new.wait_until_started(timeout_ms = 5000))
+      with quicksocket.testing.connect(new) as new_client:
+        assert(new.wait_for_client(timeout_ms = 1000))
+        old.send_messages(["old"])
+        new.send_messages(["new"])
+        assert(old_client.expect() == "old")
+        assert(new_client.expect() == "new")
+
+def test_stopping_drains_too():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.stop()
+    assert(server.wait_until_drained(timeout_ms = 5000))
+    try:
+      client.recv(timeout_ms = 1000)
+      assert(False)
+    except ConnectionError:
+      pass
+
+def test_draining_needs_a_server():
+  server = quicksocket.Server()
+  try:
+    server.begin_draining()
+    assert(False)
+  except quicksocket.ServerNotRunning:
+    pass
+
+if __name__ == "__main__":
+  test_draining_serves_connected_clients()
+  test_draining_without_clients()
+  test_draining_frees_the_port()
+  test_stopping_drains_too()
+  test_draining_needs_a_server()
@@ -0,0 +1,59 @@
+'''Tests for the event log (get_event_log()): servers' binds, clients' connections and disconnections, shutdowns and error events, in order, and readable from any point without consuming them.'''
+
+import socket
+import time
+
+import quicksocket
+import quicksocket.server
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+def last_seq():
+  entries = quicksocket.get_event_log()
+  return entries[-1].seq if entries else 0
+
+def test_event_log_follows_a_server():
+  since = last_seq()
+  with quicksocket.testing.running_server() as server:
+    port = server.get_bound_port()
+    client = quicksocket.testing.connect(server)
+    client_id = client.client_id
+    client.close()
+    assert(wait_until(lambda: not server.is_client_connected(client_id)))
+  # (Only this server's entries, as there may be others' from other tests' servers winding down.)
+  entries = [entry for entry in quicksocket.get_event_log(since_seq = since) if "port {} ".format(port) in entry.message or entry.client_id == client_id]
+  assert([(entry.kind, entry.level) for entry in entries] == [("bind", "info"), ("accept", "info"), ("disconnect", "info"), ("shutdown", "info"), ("shutdown", "info")])
+  assert(entries[1].client_id == client_id)
+  assert("1000" in entries[2].message)
+  assert([entry.seq for entry in entries] == sorted(entry.seq for entry in entries))
+  assert(all(entry.timestamp <= time.time() for entry in entries))
+
+  # Reading it consumes nothing, and following it from the last entry gives only what's new.
+  assert(len(quicksocket.get_event_log(since_seq = since)) >= len(entries))
+  assert(quicksocket.get_event_log(since_seq = last_seq()) == [])
+
+def test_event_log_includes_error_events():
+  port = 59976
+
+  blocker = socket.socket()
+  blocker.bind(("127.0.0.1", port))
+  blocker.listen()
+  since = last_seq()
+  server = quicksocket.server.Server()
+  server.start(port)
+  try:
+    server.wait_until_started(timeout_ms = 2000)
+    assert(False)
+  except quicksocket.BindError:
+    pass
+  finally:
+    blocker.close()
+
+  # The failed bind is logged as an error, under its category.
+  entries = [entry for entry in quicksocket.get_event_log(since_seq = since) if entry.level == "error"]
+  assert(entries[-1].kind == "bind")
+  assert(repr(entries[-1]).startswith("<quicksocket.EventLogEntry {}: error (bind): ".format(entries[-1].seq)))
+
+if __name__ == "__main__":
+  test_event_log_follows_a_server()
+  test_event_log_includes_error_events()
@@ -0,0 +1,100 @@
+import socket
+import time
+
+import quicksocket
+import quicksocket.server
+
+def test_send_without_server_raises_server_not_running():
+  server = quicksocket.server.Server()
+  try:
+    server.send_messages(["hello"])
+    assert(False)
+  except quicksocket.ServerNotRunning as e:
+    print("[test_exceptions] ServerNotRunning: {} (operation: {})".format(e, e.operation))
+    assert(isinstance(e, quicksocket.QuicksocketError))
+    assert(e.operation == "send messages")
+
+def test_invalid_port_raises_bind_error():
+  server = quicksocket.server.Server()
+  try:
+    server.start(70000)
+    assert(False)
+  except quicksocket.BindError as e:
+    print("[test_exceptions] BindError: {}".format(e))
+    assert(e.port == 70000)
+
+def test_start_twice_raises():
+  port = 59996
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  try:
+    server.start(port)
+    assert(False)
+  except quicksocket.QuicksocketError as e:
+    print("[test_exceptions] Second start: {}".format(e))
+
+  server.stop(wait = True)
+  assert(not server.is_running())
+
+def test_port_in_use_raises_bind_error_on_wait():
+  port = 59995
+
+  blocker = socket.socket()
+  blocker.bind(("127.0.0.1", port))
+  blocker.listen()
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  try:
+    server.wait_until_started(timeout_ms = 2000)
+    assert(False)
+  except quicksocket.BindError as e:
+    print("[test_exceptions] BindError on wait: {} (reason: {})".format(e, e.reason))
+    assert(e.port == port)
+  finally:
+    blocker.close()
+
+  # A failed bind leaves the server stopped, so it can be started again.
+  time.sleep(0.050)
+  assert(not server.is_running())
+  server.start(port)
+  assert(server.wait_until_started(timeout_ms = 2000))
+  server.stop(wait = True)
+
+def test_recent_errors_are_kept():
+  port = 59977
+
+  blocker = socket.socket()
+  blocker.bind(("127.0.0.1", port))
+  blocker.listen()
+  server = quicksocket.server.Server()
+  server.start(port)
+  try:
+    server.wait_until_started(timeout_ms = 2000)
+  except quicksocket.BindError:
+    pass
+  finally:
+    blocker.close()
+
+  # Draining the error events doesn't take them away from get_recent_errors().
+  server.drain_error_events()
+  recent = quicksocket.get_recent_errors()
+  assert(recent[-1].category == "bind")
+  # (Only panics come with backtraces.)
+  assert(recent[-1].severity == "error" and recent[-1].backtrace is None)
+  assert(len(quicksocket.get_recent_errors(count = 1)) == 1)
+
+  quicksocket.set_recent_error_capacity(0)
+  assert(quicksocket.get_recent_errors() == [])
+  quicksocket.set_recent_error_capacity(100)
+
+if __name__ == "__main__":
+  test_send_without_server_raises_server_not_running()
+  test_invalid_port_raises_bind_error()
+  test_start_twice_raises()
+  test_port_in_use_raises_bind_error_on_wait()
+  test_recent_errors_are_kept()
@@ -0,0 +1,53 @@
+'''Tests for the atexit hook (shutdown_all_servers()), from a script run in a subprocess that exits with its server still running.'''
+
+import os
+import subprocess
+import sys
+
+import quicksocket.testing
+
+REPO_ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+
+SERVER_SCRIPT = """
+import sys
+import quicksocket.server
+server = quicksocket.server.Server(port = 0)
+server.start()
+assert(server.wait_until_started(timeout_ms = 2000))
+# (A message callback thread as well, which has to be stopped too.)
+server.set_on_message(lambda message: None)
+print("port", server.get_bound_port(), flush = True)
+sys.stdin.readline()
+# Exits without stop().
+"""
+
+def test_exit_without_stop_shuts_the_server_down():
+  # (With the repo on the path, as the test itself has it.)
+  env = dict(os.environ, PYTHONPATH = os.pathsep.join([REPO_ROOT] + [path for path in [os.environ.get("PYTHONPATH")] if path]))
+  process = subprocess.Popen([sys.executable, "-c", SERVER_SCRIPT], env = env, stdin = subprocess.PIPE, stdout = subprocess.PIPE, stderr = subprocess.PIPE)
+  try:
+    # (Past the server's own log lines.)
+    line = process.stdout.readline()
+    while line and not line.startswith(b"port "):
+      line = process.stdout.readline()
+    port = int(line.split()[1])
+    with quicksocket.testing.connect(port) as client:
+      client.send(["hello"])
+      process.stdin.write(b"\n")
+      process.stdin.flush()
+      # The server says it's going away, rather than the connection just dropping with the process.
+      try:
+        client.recv(timeout_ms = 5000)
+        assert(False)
+      except ConnectionError as e:
+        assert("code 1001" in str(e))
+    assert(process.wait(timeout = 10) == 0)
+  finally:
+    if process.poll() is None:
+      process.kill()
+      process.wait()
+  stderr = process.stderr.read()
+  assert(b"Traceback" not in stderr)
+
+if __name__ == "__main__":
+  test_exit_without_stop_shuts_the_server_down()
@@ -0,0 +1,129 @@
+'''Tests for send_figure(): Plotly figures sent as JSON, and Matplotlib figures as PNGs, on the "figure" topic quicksocket/figures.js shows.'''
+
+import io
+import json
+import struct
+import zlib
+
+import quicksocket
+import quicksocket.testing
+
+# Stand-ins for Plotly and Matplotlib figures, which are told apart by the packages their classes are from.
+class PlotlyFigure:
+  __module__ = 'plotly.graph_objs._figure'
+
+  def to_json(self):
+    return json.dumps({'data': [{'type': 'scatter', 'x': [1, 2], 'y': [3, 4]}], 'layout': {'title': {'text': 'loss'}}})
+
+class AggCanvas:
+  def __init__(self, width, height, pixel):
+    self.pixels = bytearray(pixel * (width * height))
+    self.shape = (height, width, 4)
+    self.drawn = False
+
+  def draw(self):
+    self.drawn = True
+
+  def buffer_rgba(self):
+    return memoryview(self.pixels).cast('B', self.shape)
+
+class MatplotlibFigure:
+  __module__ = 'matplotlib.figure'
+
+  def __init__(self, canvas):
+    self.canvas = canvas
+
+class SvgCanvas:
+  pass
+
+class SavedFigure(MatplotlibFigure):
+  def savefig(self, file, format):
+    assert(format == 'png')
+    file.write(b'\x89PNG\r\n\x1a\n' + b'saved')
+
+def image(message):
+  '''The id and PNG of an image figure.'''
+  magic, version, format, id_len = struct.unpack('<4sBBH', message[:8])
+  assert((magic, version, format) == (b'QSFG', 1, 1))
+  return message[8:8 + id_len].decode(), message[8 + id_len:]
+
+def png_pixels(png):
+  '''The size and RGBA pixels of a PNG, as the encoder writes them (unfiltered).'''
+  assert(png[:8] == b'\x89PNG\r\n\x1a\n')
+  at, data = 8, b''
+  while at < len(png):
+    length, kind = struct.unpack('>I4s', png[at:at + 8])
+    chunk = png[at + 8:at + 8 + length]
+    assert(struct.unpack('>I', png[at + 8 + length:at + 12 + length])[0] == zlib.crc32(kind + chunk))
+    if kind == b'IHDR':
+      width, height, depth, color = struct.unpack('>IIBB', chunk[:10])
+      assert((depth, color) == (8, 6))
+    elif kind == b'IDAT':
+      data += chunk
+    at += 12 + length
+  rows = zlib.decompress(data)
+  stride = width * 4 + 1
+  assert(all(rows[row * stride] == 0 for row in range(height)))
+  return width, height, b''.join(rows[row * stride + 1:(row + 1) * stride] for row in range(height))
+
+def test_plotly():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.send_figure(PlotlyFigure(), figure_id = 'loss')
+    message = client.expect(timeout_ms = 2000)
+    assert(message.startswith('{"topic":"figure",'))
+    assert(json.loads(message) == {'topic': 'figure', 'id': 'loss', 'format': 'plotly', 'figure': json.loads(PlotlyFigure().to_json())})
+
+def test_matplotlib():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    canvas = AggCanvas(300, 200, b'\xff\xff\xff\xff')
+    # A line across the middle.
+    canvas.pixels[100 * 300 * 4:101 * 300 * 4] = b'\x1f\x77\xb4\xff' * 300
+    server.send_figure(MatplotlibFigure(canvas))
+    figure_id, png = image(client.expect(timeout_ms = 2000))
+    assert(canvas.drawn and figure_id == 'figure')
+    assert(png_pixels(png) == (300, 200, bytes(canvas.pixels)))
+    # (Flat plots compress well.)
+    assert(len(png) < len(canvas.pixels) // 20)
+
+    # A canvas that doesn't draw to pixels is saved as a PNG instead.
+    server.send_figure(SavedFigure(SvgCanvas()), figure_id = 'saved')
+    assert(image(client.expect(timeout_ms = 2000)) == ('saved', b'\x89PNG\r\n\x1a\nsaved'))
+
+  try:
+    import matplotlib
+    matplotlib.use('Agg')
+    import matplotlib.pyplot as plt
+  except ImportError:
+    return
+  figure, axes = plt.subplots(figsize = (4, 3), dpi = 50)
+  axes.plot([0, 1, 2], [0, 1, 0])
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.send_figure(figure)
+    _, png = image(client.expect(timeout_ms = 2000))
+    width, height, pixels = png_pixels(png)
+    assert((width, height) == (200, 150) and pixels == bytes(figure.canvas.buffer_rgba()))
+  plt.close(figure)
+
+def test_invalid():
+  with quicksocket.testing.running_server() as server:
+    try:
+      server.send_figure({'data': []})
+      assert(False)
+    except TypeError:
+      pass
+    try:
+      server.send_figure(PlotlyFigure(), figure_id = 'x' * 70000)
+      assert(False)
+    except quicksocket.SendError:
+      pass
+
+  try:
+    quicksocket.Server(port = 0).send_figure(PlotlyFigure())
+    assert(False)
+  except quicksocket.ServerNotRunning:
+    pass
+
+if __name__ == '__main__':
+  test_plotly()
+  test_matplotlib()
+  test_invalid()
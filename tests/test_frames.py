@@ -0,0 +1,109 @@
+'''Tests for latest-frame-only streaming (send_frame()): a slow client skips to the newest frame of each topic rather than queueing them all.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+FRAME_BYTES = 256 * 1024
+
+def frame(topic: str, i: int) -> bytes:
+  return ('%s:%05d:' % (topic, i)).encode().ljust(FRAME_BYTES, b'.')
+
+def receive_until(client, last: bytes):
+  received = []
+  while not received or received[-1] != last:
+    received.append(client.expect(timeout_ms = 5000))
+  return received
+
+def test_slow_client_skips_to_the_newest_frame():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    # The client reads nothing while far more is sent than its socket can take.
+    for i in range(200):
+      server.send_frame('camera', frame('camera', i))
+    received = receive_until(client, frame('camera', 199))
+    assert(len(received) < 200)
+    # Whatever it skipped, it's written the frames it's written in order.
+    numbers = [int(message.split(b':')[1]) for message in received]
+    assert(numbers == sorted(numbers))
+    assert(server.get_stats().frames_skipped == 200 - len(received))
+
+def test_topics_have_a_frame_each():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    for i in range(100):
+      server.send_frame('left', frame('left', i))
+      server.send_frame('right', frame('right', i))
+    received = set()
+    while frame('left', 99) not in received or frame('right', 99) not in received:
+      received.add(client.expect(timeout_ms = 5000))
+    assert(server.get_stats().frames_skipped > 0)
+
+def test_frames_are_released():
+  with quicksocket.testing.running_server() as server:
+    with quicksocket.testing.connect(server):
+      for i in range(50):
+        server.send_frame('camera', frame('camera', i))
+    # The client left without reading them.
+    deadline = time.monotonic() + 2
+    released = lambda diagnostics: diagnostics.pending_frames == 0 and diagnostics.queued_bytes == 0
+    while not released(server.get_diagnostics()) and time.monotonic() < deadline:
+      time.sleep(0.01)
+    assert(released(server.get_diagnostics()))
+
+def send_for(server, topic: str, seconds: float):
+  started = time.monotonic()
+  i = 0
+  while time.monotonic() - started < seconds:
+    server.send_frame(topic, '%s:%d' % (topic, i))
+    i += 1
+    time.sleep(0.002)
+  return i
+
+def receive_for(client, seconds: float):
+  received = []
+  deadline = time.monotonic() + seconds
+  while time.monotonic() < deadline:
+    message = client.recv(timeout_ms = 50)
+    if message is not None:
+      received.append(message)
+  return received
+
+def test_default_client_topic_rates():
+  with quicksocket.testing.running_server(default_client_topic_rates_hz = {'slow': 10}) as server, quicksocket.testing.connect(server) as client:
+    sent = send_for(server, 'slow', 0.5)
+    received = receive_for(client, 0.3)
+    # At most 10 a second (and one to start with), ending with the newest.
+    assert(3 <= len(received) <= 7 < sent)
+    assert(received[-1] == 'slow:%d' % (sent - 1))
+
+def test_throttle_requests():
+  with quicksocket.testing.running_server(topic_throttle_requests = True) as server, quicksocket.testing.connect(server) as phone, quicksocket.testing.connect(server) as desktop:
+    # (The phone's messages are taken in order, so once 'throttled' is drained, its throttle request has been.)
+    phone.send(['throttle:camera:5', 'throttled'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['throttled'])
+    send_for(server, 'camera', 0.6)
+    # At most 5 a second over the 0.9 seconds: the first frame, then one every 0.2 seconds.
+    assert(len(receive_for(phone, 0.3)) <= 5)
+    assert(len(receive_for(desktop, 0.1)) > 20)
+    # The requests aren't passed on; malformed ones are errors.
+    phone.send(['throttle:camera:0', 'throttle:camera:off', 'hello'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['hello'])
+    assert(any('throttle' in error.message and error.severity == 'warning' for error in server.drain_error_events()))
+    # Off, every frame's written again.
+    send_for(server, 'camera', 0.2)
+    assert(len(receive_for(phone, 0.3)) > 20)
+
+def test_send_frame_without_a_server():
+  try:
+    quicksocket.Server(port = 0).send_frame('camera', b'frame')
+    assert(False)
+  except quicksocket.ServerNotRunning:
+    pass
+
+if __name__ == "__main__":
+  test_slow_client_skips_to_the_newest_frame()
+  test_topics_have_a_frame_each()
+  test_frames_are_released()
+  test_default_client_topic_rates()
+  test_throttle_requests()
+  test_send_frame_without_a_server()
@@ -0,0 +1,70 @@
+'''Tests for send_pointcloud() and send_mesh(): geometry packed into the binary layout quicksocket/geometry.js decodes.'''
+
+import array
+import struct
+
+import quicksocket
+import quicksocket.testing
+
+def header(message):
+  magic, version, channels, _, count, faces = struct.unpack('<4sBBHII', message[:16])
+  return (magic, version, channels, count, faces)
+
+def test_pointcloud():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.send_pointcloud(array.array('f', [0, 1, 2, 3.5, 4.5, 5.5]), array.array('B', [255, 0, 0, 0, 255, 0]))
+    message = client.expect(timeout_ms = 2000)
+    assert(header(message) == (b'QSPC', 1, 3, 2, 0))
+    assert(struct.unpack('<6f', message[16:40]) == (0, 1, 2, 3.5, 4.5, 5.5))
+    assert(message[40:] == bytes([255, 0, 0, 0, 255, 0]))
+
+    # Float64s are converted, and RGBA colors take 4 channels.
+    server.send_pointcloud(array.array('d', [1, 2, 3]), bytes([1, 2, 3, 4]))
+    message = client.expect(timeout_ms = 2000)
+    assert(header(message) == (b'QSPC', 1, 4, 1, 0))
+    assert(struct.unpack('<3f', message[16:28]) == (1, 2, 3))
+    assert(message[28:] == bytes([1, 2, 3, 4]))
+
+    # No colors.
+    server.send_pointcloud(array.array('f', [1, 2, 3]))
+    message = client.expect(timeout_ms = 2000)
+    assert(header(message) == (b'QSPC', 1, 0, 1, 0))
+    assert(len(message) == 28)
+
+def test_mesh():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    vertices = array.array('f', [0, 0, 0, 1, 0, 0, 0, 1, 0, 1, 1, 0])
+    server.send_mesh(vertices, array.array('q', [0, 1, 2, 1, 3, 2]))
+    message = client.expect(timeout_ms = 2000)
+    assert(header(message) == (b'QSMS', 1, 0, 4, 2))
+    assert(struct.unpack('<12f', message[16:64]) == tuple(vertices))
+    assert(struct.unpack('<6I', message[64:]) == (0, 1, 2, 1, 3, 2))
+
+def test_invalid():
+  with quicksocket.testing.running_server() as server:
+    invalid = [
+      lambda: server.send_pointcloud(array.array('f', [1, 2])),  # A missing coordinate.
+      lambda: server.send_pointcloud(array.array('f', [1, 2, 3]), bytes([1, 2])),  # Too few colors.
+      lambda: server.send_pointcloud(array.array('f', [1, 2, 3]), array.array('f', [1, 2, 3])),  # Float colors.
+      lambda: server.send_mesh(array.array('f', [1, 2, 3]), array.array('i', [0, 0, 1])),  # No vertex 1.
+      lambda: server.send_mesh(array.array('f', [1, 2, 3]), array.array('i', [0, 0])),  # A missing index.
+      lambda: server.send_mesh(array.array('f', [1, 2, 3]), array.array('i', [0, 0, -1])),  # A negative index.
+      lambda: server.send_mesh(array.array('f', [1, 2, 3]), array.array('f', [0, 0, 0])),  # Float indices.
+    ]
+    for send in invalid:
+      try:
+        send()
+        assert(False)
+      except ValueError:
+        pass
+
+    try:
+      server.send_pointcloud([1, 2, 3])
+      assert(False)
+    except TypeError:
+      pass
+
+if __name__ == '__main__':
+  test_pointcloud()
+  test_mesh()
+  test_invalid()
@@ -0,0 +1,37 @@
+'''Tests for the heartbeat topic (heartbeat_interval_ms, heartbeat_topic): heartbeats broadcast by the server itself, whatever Python's doing.'''
+
+import json
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def test_heartbeats():
+  with quicksocket.testing.running_server(heartbeat_interval_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    before_ms = time.time() * 1000
+    beats = [json.loads(client.expect()) for _ in range(3)]
+    assert([beat["topic"] for beat in beats] == ["heartbeat"] * 3)
+    # Numbered in order (the first ones may have gone out before the client connected).
+    assert([beat["seq"] for beat in beats] == list(range(beats[0]["seq"], beats[0]["seq"] + 3)))
+    assert(all(beat["clients"] == 1 for beat in beats))
+    assert(before_ms - 1000 <= beats[-1]["server_time_ms"] <= time.time() * 1000 + 1000)
+
+def test_heartbeats_while_python_is_quiet():
+  with quicksocket.testing.running_server(heartbeat_interval_ms = 20, heartbeat_topic = "server.alive") as server, quicksocket.testing.connect(server) as client:
+    # The consumer sends nothing at all; the client hears from the server regardless.
+    time.sleep(0.2)
+    beats = [json.loads(client.expect()) for _ in range(5)]
+    assert(all(beat["topic"] == "server.alive" for beat in beats))
+
+def test_heartbeat_arguments():
+  for kwargs in [{"heartbeat_interval_ms": 0}, {"heartbeat_topic": "alive"}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_heartbeats()
+  test_heartbeats_while_python_is_quiet()
+  test_heartbeat_arguments()
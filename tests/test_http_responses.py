@@ -0,0 +1,32 @@
+import time
+import urllib.error
+import urllib.request
+
+import quicksocket.server
+
+def fetch(port: int):
+  '''GET the server root as a plain HTTP client would, returning (status, body).'''
+  try:
+    with urllib.request.urlopen("http://localhost:" + str(port) + "/") as response:
+      return response.status, response.read()
+  except urllib.error.HTTPError as e:
+    return e.code, e.read()
+
+def test_plain_http_request_gets_426():
+  port = 59995
+
+  server = quicksocket.server.Server()
+  server.start(port)
+  time.sleep(0.200)
+  assert(server.is_running())
+
+  status, _ = fetch(port)
+  print("[test_http_responses] Plain GET status: {}".format(status))
+  assert(status == 426)
+
+  server.stop()
+  time.sleep(0.200)
+  assert(not server.is_running())
+
+if __name__ == "__main__":
+  test_plain_http_request_gets_426()
@@ -0,0 +1,65 @@
+'''Tests for input aggregation (input_aggregation_ms): clients' input merged, each client's latest value of each input, into periodic snapshots.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain_input_snapshots(server, timeout_s = 2.0):
+  snapshots = []
+  deadline = time.time() + timeout_s
+  while not snapshots and time.time() < deadline:
+    snapshots += server.drain_input_snapshots()
+    time.sleep(0.01)
+  return snapshots
+
+def test_aggregation():
+  with quicksocket.testing.running_server(input_aggregation_ms = 200) as server, quicksocket.testing.connect(server) as first, quicksocket.testing.connect(server) as second:
+    first.send(['input:cursor:%d,%d' % (x, x) for x in range(60)] + ['input:zoom:2'])
+    second.send(['input:cursor:5,6', 'input:url:http://example.com/'])
+    # (Both clients' input may have been split over two snapshots.)
+    snapshots = drain_input_snapshots(server)
+    time.sleep(0.3)
+    snapshots += server.drain_input_snapshots()
+    assert(1 <= len(snapshots) <= 2)
+    assert(sum(snapshot.updates for snapshot in snapshots) == 63)
+
+    inputs = {}
+    for snapshot in snapshots:
+      for client_id, latest in snapshot.inputs.items():
+        inputs.setdefault(client_id, {}).update(latest)
+    assert(inputs[first.client_id] == {'cursor': '59,59', 'zoom': '2'})
+    assert(inputs[second.client_id] == {'cursor': '5,6', 'url': 'http://example.com/'})
+
+    # No input, no snapshot; and input isn't passed on to the consumer, but other messages are.
+    time.sleep(0.3)
+    assert(server.drain_input_snapshots() == [])
+    first.send(['hello'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['hello'])
+
+def test_malformed_input():
+  with quicksocket.testing.running_server(input_aggregation_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    client.send(['input:cursor', 'input::1', 'input:zoom:3'])
+    snapshots = drain_input_snapshots(server)
+    assert(len(snapshots) == 1 and snapshots[0].inputs == {client.client_id: {'zoom': '3'}} and snapshots[0].updates == 1)
+    errors = [error for error in server.drain_error_events() if 'input' in error.message]
+    assert(len(errors) == 2 and all(error.severity == 'warning' and error.category == 'receive' for error in errors))
+
+def test_aggregation_off():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client.send(['input:zoom:3'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['input:zoom:3'])
+    assert(server.drain_input_snapshots() == [])
+
+  for input_aggregation_ms in [0, -1]:
+    try:
+      quicksocket.Server(port = 0, input_aggregation_ms = input_aggregation_ms).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == '__main__':
+  test_aggregation()
+  test_malformed_input()
+  test_aggregation_off()
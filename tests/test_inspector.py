@@ -0,0 +1,61 @@
+import json
+import urllib.error
+import urllib.request
+
+import quicksocket.server
+import quicksocket.testing
+
+def fetch(port: int, path: str):
+  '''GET a path from the server as a plain HTTP client would, returning (status, content type, body).'''
+  try:
+    with urllib.request.urlopen("http://localhost:" + str(port) + path) as response:
+      return response.status, response.headers["Content-Type"], response.read()
+  except urllib.error.HTTPError as e:
+    return e.code, e.headers["Content-Type"], e.read()
+
+def next_snapshot(feed: quicksocket.testing.TestClient, condition):
+  '''Reads the inspector feed's snapshots until one satisfies condition, returning it (or None, if none did within a few of them).'''
+  for _ in range(10):
+    snapshot = json.loads(feed.expect(timeout_ms = 2000))
+    if condition(snapshot):
+      return snapshot
+  return None
+
+def test_inspector_page_is_served():
+  with quicksocket.testing.running_server(inspector = True) as server:
+    status, content_type, body = fetch(server.get_bound_port(), "/inspector")
+    assert(status == 200)
+    assert(content_type.startswith("text/html"))
+    # (The page connects back to its feed.)
+    assert(b"/inspector/ws" in body)
+
+def test_inspector_feed_reports_clients_and_messages():
+  with quicksocket.testing.running_server(inspector = True) as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.testing.connect(server.get_bound_port(), path = "/inspector/ws") as feed:
+      client.send(["hello inspector"])
+      assert(server.drain_client_messages(timeout_ms = 1000) == ["hello inspector"])
+      server.send_messages(["hello clients"])
+      assert(client.expect() == "hello clients")
+
+      snapshot = next_snapshot(feed, lambda snapshot: snapshot["totals"]["msgs_in"] >= 1 and snapshot["totals"]["msgs_out"] >= 1)
+      assert(snapshot is not None)
+      assert(snapshot["uptime_ms"] >= 0)
+      assert(snapshot["totals"]["bytes_in"] == len("hello inspector"))
+      assert(snapshot["totals"]["bytes_out"] == len("hello clients"))
+      # The feed isn't a client of its own.
+      assert([entry["id"] for entry in snapshot["clients"]] == [client.client_id])
+      assert(snapshot["clients"][0]["msgs_in"] == 1)
+      recent = [(entry["dir"], entry["client"], entry["kind"], entry["preview"]) for entry in snapshot["recent"]]
+      assert(("in", client.client_id, "text", "hello inspector") in recent)
+      assert(("out", "*", "text", "hello clients") in recent)
+      assert(server.get_stats().current_clients == 1)
+
+def test_inspector_is_off_by_default():
+  with quicksocket.testing.running_server() as server:
+    status, _, _ = fetch(server.get_bound_port(), "/inspector")
+    assert(status == 426)
+
+if __name__ == "__main__":
+  test_inspector_page_is_served()
+  test_inspector_feed_reports_clients_and_messages()
+  test_inspector_is_off_by_default()
@@ -0,0 +1,48 @@
+'''Tests for quicksocket.jupyter: serve_widget()'s servers, the HTML it shows, and stopping its servers when their cells are re-run.'''
+
+import urllib.request
+
+import quicksocket.jupyter
+import quicksocket.testing
+
+def test_serve_widget():
+  server = quicksocket.jupyter.serve_widget(display = False)
+  try:
+    port = server.get_bound_port()
+    assert(server.is_running() and port)
+    assert(quicksocket.jupyter.get_widget_server() is server)
+
+    page = quicksocket.jupyter.widget_html(server)
+    assert('new WebSocket("ws://" + window.location.hostname + ":{}/")'.format(port) in page)
+    with quicksocket.testing.connect(server) as client:
+      server.send_messages(['hello'])
+      client.expect('hello')
+
+    # Re-running the cell replaces its server.
+    replacement = quicksocket.jupyter.serve_widget(display = False)
+    assert(not server.is_running() and replacement.is_running())
+    assert(quicksocket.jupyter.get_widget_server() is replacement)
+  finally:
+    quicksocket.jupyter.stop_widgets()
+  assert(quicksocket.jupyter.get_widget_server() is None)
+
+def test_landing_page_widget():
+  server = quicksocket.jupyter.serve_widget('<p>plot</p>', key = 'plot', display = False)
+  other = quicksocket.jupyter.serve_widget(key = 'other', display = False)
+  try:
+    port = server.get_bound_port()
+    with urllib.request.urlopen('http://localhost:{}/'.format(port)) as response:
+      assert(response.read() == b'<p>plot</p>')
+    page = quicksocket.jupyter.widget_html(server, iframe = True, width = 640, height = 480, host = 'example.com')
+    assert('<iframe' in page and 'width: 640px; height: 480px' in page and '"example.com" + ":{}/"'.format(port) in page)
+
+    # Only the key's server is stopped.
+    quicksocket.jupyter.stop_widgets('plot')
+    assert(not server.is_running() and other.is_running())
+  finally:
+    quicksocket.jupyter.stop_widgets()
+  assert(not other.is_running())
+
+if __name__ == '__main__':
+  test_serve_widget()
+  test_landing_page_widget()
@@ -0,0 +1,301 @@
+'''Tests for the Kafka sink, against a minimal in-process stand-in for a Kafka broker (just Metadata v1 and Produce v3, decoding and checksumming the record batches independently). Needs quicksocket built with the "kafka" feature; skipped otherwise.'''
+
+import socket
+import struct
+import threading
+import time
+
+import quicksocket
+import quicksocket.server
+import quicksocket.testing
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+if pytest is not None:
+  pytestmark = pytest.mark.skipif(quicksocket.server.BACKEND_start_kafka_sink is None, reason = "quicksocket was built without the \"kafka\" feature.")
+
+def crc32c(data: bytes) -> int:
+  crc = 0xffffffff
+  for byte in data:
+    crc ^= byte
+    for _ in range(8):
+      crc = (crc >> 1) ^ 0x82f63b78 if crc & 1 else crc >> 1
+  return crc ^ 0xffffffff
+
+assert(crc32c(b"123456789") == 0xe3069283)
+
+class Reader:
+  def __init__(self, data: bytes):
+    self.data = data
+    self.pos = 0
+
+  def take(self, n: int) -> bytes:
+    taken = self.data[self.pos:self.pos + n]
+    assert(len(taken) == n)
+    self.pos += n
+    return taken
+
+  def int(self, fmt: str) -> int:
+    return struct.unpack('>' + fmt, self.take(struct.calcsize(fmt)))[0]
+
+  def string(self):
+    length = self.int('h')
+    return None if length < 0 else self.take(length).decode()
+
+  def varint(self) -> int:
+    shift, value = 0, 0
+    while True:
+      byte = self.take(1)[0]
+      value |= (byte & 0x7f) << shift
+      shift += 7
+      if not byte & 0x80:
+        return (value >> 1) ^ -(value & 1)
+
+class FakeKafka:
+  '''A single broker (node 0) with the given topics and partition counts. Produced records are kept in .records, as dicts of partition, key, value and timestamp; error codes put in .fail_next are answered (and their records dropped), one per partition, before anything succeeds again.'''
+  def __init__(self, topics):
+    self.topics = topics
+    self.records = []
+    self.fail_next = []
+    self.produce_requests = 0
+    self._lock = threading.Lock()
+    self._listener = socket.socket()
+    self._listener.bind(("127.0.0.1", 0))
+    self._listener.listen()
+    self.port = self._listener.getsockname()[1]
+    self.address = "127.0.0.1:{}".format(self.port)
+    threading.Thread(target = self._accept, daemon = True).start()
+
+  def wait_for_records(self, count: int, timeout_s: float = 5) -> bool:
+    deadline = time.monotonic() + timeout_s
+    while len(self.records) < count:
+      if time.monotonic() > deadline:
+        return False
+      time.sleep(0.01)
+    return True
+
+  def close(self):
+    self._listener.close()
+
+  def _accept(self):
+    while True:
+      try:
+        sock, _ = self._listener.accept()
+      except OSError:
+        return
+      threading.Thread(target = self._serve, args = (sock,), daemon = True).start()
+
+  def _serve(self, sock):
+    reader = sock.makefile("rb")
+    with sock:
+      while True:
+        size = reader.read(4)
+        if len(size) < 4:
+          return
+        request = Reader(reader.read(struct.unpack('>i', size)[0]))
+        api_key, api_version, correlation_id = request.int('h'), request.int('h'), request.int('i')
+        request.string() # client_id
+        if (api_key, api_version) == (3, 1):
+          body = self._metadata(request)
+        elif (api_key, api_version) == (0, 3):
+          body = self._produce(request)
+        else:
+          return
+        response = struct.pack('>i', correlation_id) + body
+        sock.sendall(struct.pack('>i', len(response)) + response)
+
+  def _metadata(self, request: Reader) -> bytes:
+    names = [request.string() for _ in range(request.int('i'))]
+    def string(value: str) -> bytes:
+      return struct.pack('>h', len(value)) + value.encode()
+    body = struct.pack('>i', 1) + struct.pack('>i', 0) + string("127.0.0.1") + struct.pack('>ih', self.port, -1)
+    body += struct.pack('>ii', 0, len(names))
+    for name in names:
+      partitions = self.topics.get(name, 0)
+      body += struct.pack('>h', 0 if name in self.topics else 3) + string(name) + b'\x00' + struct.pack('>i', partitions)
+      for index in range(partitions):
+        body += struct.pack('>hii', 0, index, 0) + struct.pack('>ii', 1, 0) + struct.pack('>ii', 1, 0)
+    return body
+
+  def _produce(self, request: Reader) -> bytes:
+    self.produce_requests += 1
+    assert(request.string() is None) # transactional_id
+    assert(request.int('h') == 1) # acks
+    request.int('i') # timeout_ms
+    body = struct.pack('>i', request.int('i'))
+    for _ in range(len(body) and struct.unpack('>i', body)[0]):
+      name = request.string()
+      partitions = request.int('i')
+      body += struct.pack('>h', len(name)) + name.encode() + struct.pack('>i', partitions)
+      for _ in range(partitions):
+        index = request.int('i')
+        records = self._decode_batch(Reader(request.take(request.int('i'))), index)
+        with self._lock:
+          error_code = self.fail_next.pop(0) if self.fail_next else 0
+          if error_code == 0:
+            self.records += records
+        body += struct.pack('>ihqq', index, error_code, 0, -1)
+    return body + struct.pack('>i', 0)
+
+  def _decode_batch(self, batch: Reader, partition: int):
+    assert(batch.int('q') == 0) # base_offset
+    assert(batch.int('i') == len(batch.data) - 12) # batch_length
+    assert(batch.int('i') == -1) # partition_leader_epoch
+    assert(batch.int('b') == 2) # magic
+    crc = batch.int('I')
+    assert(crc == crc32c(batch.data[batch.pos:]))
+    assert(batch.int('h') == 0) # attributes
+    last_offset_delta = batch.int('i')
+    base_timestamp, max_timestamp = batch.int('q'), batch.int('q')
+    assert((batch.int('q'), batch.int('h'), batch.int('i')) == (-1, -1, -1))
+    count = batch.int('i')
+    assert(last_offset_delta == count - 1)
+    records = []
+    for offset_delta in range(count):
+      length = batch.varint()
+      end = batch.pos + length
+      assert(batch.int('b') == 0)
+      timestamp = base_timestamp + batch.varint()
+      assert(batch.varint() == offset_delta)
+      key = batch.take(batch.varint())
+      value = batch.take(batch.varint())
+      assert(batch.varint() == 0) # headers
+      assert(batch.pos == end)
+      assert(base_timestamp <= timestamp <= max_timestamp)
+      records.append({"partition": partition, "key": key.decode(), "value": value, "timestamp": timestamp})
+    assert(batch.pos == len(batch.data))
+    return records
+
+def test_client_messages_are_produced():
+  kafka = FakeKafka({"client-messages": 3})
+  with quicksocket.testing.running_server() as server:
+    with quicksocket.testing.connect(server) as first, quicksocket.testing.connect(server) as second:
+      with quicksocket.kafka_sink(server, kafka.address, "client-messages") as sink:
+        assert(sink.is_running())
+        started_ms = time.time() * 1000
+        for i in range(20):
+          first.send(["first {}".format(i)])
+          second.send([b"second %d" % i])
+        # (Messages are still received only as fast as they're drained.)
+        drained = []
+        while len(drained) < 40:
+          more = server.drain_client_messages(timeout_ms = 1000)
+          assert(more)
+          drained += more
+        assert(set(drained) == set(["first {}".format(i) for i in range(20)] + [b"second %d" % i for i in range(20)]))
+        assert(kafka.wait_for_records(40))
+        assert(sink.get_stats().delivered == 40)
+        assert(sink.get_stats().failed == 0)
+
+      # In order, one partition per client.
+      for client, expected in ((first, [b"first %d" % i for i in range(20)]), (second, [b"second %d" % i for i in range(20)])):
+        records = [record for record in kafka.records if record["key"] == client.client_id]
+        assert([record["value"] for record in records] == expected)
+        assert(len(set(record["partition"] for record in records)) == 1)
+        assert(all(started_ms - 1000 <= record["timestamp"] <= time.time() * 1000 for record in records))
+
+      # Nothing more is produced once the sink stops.
+      first.send(["direct"])
+      assert(server.drain_client_messages(timeout_ms = 1000) == ["direct"])
+      assert(len(kafka.records) == 40)
+  kafka.close()
+
+def test_messages_are_drained_while_the_sink_runs():
+  kafka = FakeKafka({"drained": 1})
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.kafka_sink(server, kafka.address, "drained") as sink:
+      drained = []
+      for i in range(50):
+        client.send(["message {}".format(i)])
+        if i % 10 == 9:
+          drained += server.drain_client_messages(timeout_ms = 1000)
+      while len(drained) < 50:
+        more = server.drain_client_messages(timeout_ms = 1000)
+        assert(more)
+        drained += more
+      # Both have every message, in order.
+      assert(drained == ["message {}".format(i) for i in range(50)])
+      assert(kafka.wait_for_records(50))
+      assert([record["value"] for record in kafka.records] == [b"message %d" % i for i in range(50)])
+      assert(sink.get_stats().delivered == 50)
+  kafka.close()
+
+def test_messages_are_batched():
+  kafka = FakeKafka({"batched": 1})
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.kafka_sink(server, [kafka.address], "batched", max_batch = 4, linger_ms = 200) as sink:
+      client.send([str(i) for i in range(10)])
+      assert(kafka.wait_for_records(10))
+      # Two full batches, then the rest once they've lingered.
+      assert(kafka.produce_requests == 3)
+      assert(sink.get_stats().requests == 3)
+      assert([record["value"] for record in kafka.records] == [str(i).encode() for i in range(10)])
+  kafka.close()
+
+def test_delivery_failures():
+  kafka = FakeKafka({"flaky": 1})
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.kafka_sink(server, kafka.address, "flaky") as sink:
+      # NOT_LEADER_OR_FOLLOWER is retried.
+      kafka.fail_next = [6]
+      client.send(["retried"])
+      assert(kafka.wait_for_records(1))
+      assert(sink.get_stats().delivered == 1)
+      assert(sink.get_stats().requests == 2)
+
+      # MESSAGE_TOO_LARGE isn't.
+      kafka.fail_next = [10]
+      client.send(["dropped"])
+      deadline = time.monotonic() + 5
+      while sink.get_stats().failed == 0 and time.monotonic() < deadline:
+        time.sleep(0.01)
+      assert(sink.get_stats().failed == 1)
+      errors = [error for error in server.drain_error_events() if error.category == "kafka"]
+      assert(len(errors) == 1 and "MESSAGE_TOO_LARGE" in errors[0].message)
+
+      client.send(["after"])
+      assert(kafka.wait_for_records(2))
+      assert([record["value"] for record in kafka.records] == [b"retried", b"after"])
+  kafka.close()
+
+def test_bad_configurations_are_rejected():
+  kafka = FakeKafka({"exists": 1})
+  with quicksocket.testing.running_server() as server:
+    for brokers, topic in (([], "exists"), ([kafka.address], ""), ([kafka.address], "not a topic"), (["127.0.0.1:notaport"], "exists")):
+      try:
+        quicksocket.kafka_sink(server, brokers, topic)
+        assert(False)
+      except ValueError:
+        pass
+
+    try:
+      quicksocket.kafka_sink(server, kafka.address, "missing")
+      assert(False)
+    except quicksocket.ConnectError as e:
+      assert("UNKNOWN_TOPIC_OR_PARTITION" in e.reason)
+
+    with socket.socket() as probe:
+      probe.bind(("127.0.0.1", 0))
+      dead_port = probe.getsockname()[1]
+    try:
+      quicksocket.kafka_sink(server, "127.0.0.1:{}".format(dead_port), "exists")
+      assert(False)
+    except quicksocket.ConnectError:
+      pass
+
+    # Nothing was taken from the server.
+    with quicksocket.testing.connect(server) as client:
+      client.send(["still mine"])
+      assert(server.drain_client_messages(timeout_ms = 1000) == ["still mine"])
+  kafka.close()
+
+if __name__ == "__main__":
+  if quicksocket.server.BACKEND_start_kafka_sink is None:
+    print("Skipped: quicksocket was built without the \"kafka\" feature.")
+  else:
+    test_client_messages_are_produced()
+    test_messages_are_batched()
+    test_delivery_failures()
+    test_bad_configurations_are_rejected()
@@ -0,0 +1,201 @@
+'''Tests for keepalive pings (clients are pinged at ping_interval_ms, and those that leave max_missed_pongs of them unanswered are disconnected, after being marked unhealthy with unhealthy_after_missed_pongs; the pongs time the clients' round trips), and reporting clients' own pings and pongs (ping_events) and idle timeouts (clients that go idle_timeout_ms without a message either way are disconnected).'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+def test_clients_are_pinged():
+  with quicksocket.testing.running_server(ping_interval_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    # (Read raw, so it isn't answered.)
+    frame = client._recv_frame(time.monotonic() + 0.5)
+    assert(frame is not None and frame[1] == quicksocket.testing.OPCODE_PING)
+
+def test_answering_clients_stay_connected():
+  with quicksocket.testing.running_server(ping_interval_ms = 50, max_missed_pongs = 1) as server, quicksocket.testing.connect(server) as client:
+    # recv() answers the pings as they come.
+    deadline = time.monotonic() + 0.5
+    while time.monotonic() < deadline:
+      assert(client.recv(timeout_ms = 20) is None)
+    assert(server.is_client_connected(client.client_id))
+    server.send_messages(["still here"])
+    assert(client.expect() == "still here")
+    assert(server.get_stats().keepalive_timeouts == 0)
+
+def test_silent_clients_are_disconnected():
+  with quicksocket.testing.running_server(ping_interval_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    server.drain_connection_events()
+    server.drain_error_events()
+    # Never reading, the client never answers.
+    assert(wait_until(lambda: not server.is_client_connected(client.client_id)))
+    assert(server.get_stats().keepalive_timeouts == 1)
+    assert([event.kind for event in server.drain_connection_events()] == ["disconnected"])
+    events = [event for event in server.drain_error_events() if event.category == "receive"]
+    assert(len(events) == 1 and events[0].client_id == client.client_id and "2 pings" in events[0].message)
+    # It was told why, after the pings it didn't answer.
+    frames = []
+    while not frames or frames[-1][1] == quicksocket.testing.OPCODE_PING:
+      frames.append(client._recv_frame(time.monotonic() + 1))
+    assert([opcode for _, opcode, _ in frames[:-1]] == [quicksocket.testing.OPCODE_PING] * 2)
+    assert(frames[-1][1] == quicksocket.testing.OPCODE_CLOSE and frames[-1][2][:2] == (1001).to_bytes(2, 'big'))
+
+def test_unhealthy_clients_are_reported():
+  with quicksocket.testing.running_server(ping_interval_ms = 100, max_missed_pongs = 4, unhealthy_after_missed_pongs = 1) as server, quicksocket.testing.connect(server) as client:
+    server.drain_connection_events()
+    events = []
+    def kinds():
+      events.extend(server.drain_connection_events())
+      return [event.kind for event in events]
+    # Not reading, the client doesn't answer; it's unhealthy, but still connected.
+    assert(wait_until(lambda: kinds() == ["unhealthy"]))
+    assert(events[0].client_id == client.client_id)
+    assert(server.is_client_connected(client.client_id) and not server.get_client_stats(client.client_id).healthy)
+    # recv() answers the pings waiting for it.
+    assert(wait_until(lambda: client.recv(timeout_ms = 20) is None and kinds() == ["unhealthy", "healthy"]))
+    assert(server.get_client_stats(client.client_id).healthy)
+    # Silent again, it's unhealthy again, and then disconnected.
+    assert(wait_until(lambda: kinds() == ["unhealthy", "healthy", "unhealthy", "disconnected"]))
+    assert(server.get_stats().keepalive_timeouts == 1)
+
+def test_round_trips_are_timed():
+  with quicksocket.testing.running_server(ping_interval_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    stats = server.get_client_stats(client.client_id)
+    assert(stats.client_id == client.client_id and (stats.rtt_ms, stats.smoothed_rtt_ms) == (None, None))
+    # Pongs that don't echo a ping are ignored.
+    client._send_frame(quicksocket.testing.OPCODE_PONG, b"unsolicited")
+    client._send_frame(quicksocket.testing.OPCODE_PONG, (2 ** 63).to_bytes(8, 'big'))
+    client.recv(timeout_ms = 30)
+    assert(server.get_client_stats(client.client_id).rtt_ms is None)
+    # recv() answers the pings as they come.
+    def answered():
+      client.recv(timeout_ms = 20)
+      return server.get_client_stats(client.client_id).rtt_ms is not None
+    assert(wait_until(answered))
+    stats = server.get_client_stats(client.client_id)
+    assert(0 <= stats.rtt_ms < 1000 and 0 <= stats.smoothed_rtt_ms < 1000)
+    assert(stats.tag is None and stats.messages_missed == 0)
+    assert(server.get_client_stats("nobody") is None)
+
+def test_round_trips_need_pings():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client._send_frame(quicksocket.testing.OPCODE_PONG, (0).to_bytes(8, 'big'))
+    assert(client.recv(timeout_ms = 100) is None)
+    server.set_client_tag(client.client_id, "remote")
+    stats = server.get_client_stats(client.client_id)
+    assert((stats.tag, stats.rtt_ms, stats.smoothed_rtt_ms) == ("remote", None, None))
+
+def test_idle_clients_are_disconnected():
+  with quicksocket.testing.running_server(idle_timeout_ms = 300) as server, quicksocket.testing.connect(server) as busy, quicksocket.testing.connect(server) as idle:
+    server.drain_connection_events()
+    # Messages either way keep a client from idling out.
+    deadline = time.monotonic() + 0.6
+    while time.monotonic() < deadline:
+      busy.send(["still here"])
+      server.send_to_client(busy.client_id, ["good"])
+      assert(busy.expect() == "good")
+      time.sleep(0.05)
+    assert(not server.is_client_connected(idle.client_id))
+    assert(server.is_client_connected(busy.client_id))
+    events = server.drain_connection_events()
+    assert([(event.client_id, event.kind) for event in events] == [(idle.client_id, "disconnected")])
+    assert(server.get_stats().idle_timeouts == 1)
+    try:
+      idle.recv(timeout_ms = 1000)
+      assert(False)
+    except ConnectionError as err:
+      assert("1001" in str(err))
+
+def test_pings_dont_keep_clients_from_idling():
+  with quicksocket.testing.running_server(ping_interval_ms = 50, idle_timeout_ms = 300) as server, quicksocket.testing.connect(server) as client:
+    closed = False
+    deadline = time.monotonic() + 2
+    while not closed and time.monotonic() < deadline:
+      try:
+        # (Answering the pings as they come.)
+        client.recv(timeout_ms = 20)
+      except ConnectionError:
+        closed = True
+    assert(closed)
+    stats = server.get_stats()
+    assert((stats.idle_timeouts, stats.keepalive_timeouts) == (1, 0))
+
+def test_ping_events():
+  with quicksocket.testing.running_server(ping_events = True) as server, quicksocket.testing.connect(server) as client:
+    # The client's ping is answered, and reported.
+    client._send_frame(quicksocket.testing.OPCODE_PING, b"hello")
+    frame = client._recv_frame(time.monotonic() + 1)
+    assert(frame is not None and frame[1:] == (quicksocket.testing.OPCODE_PONG, b"hello"))
+    # And so is its answer to the server's.
+    server.send_ping(client.client_id, b"are you there")
+    frame = client._recv_frame(time.monotonic() + 1)
+    assert(frame is not None and frame[1:] == (quicksocket.testing.OPCODE_PING, b"are you there"))
+    client._send_frame(quicksocket.testing.OPCODE_PONG, frame[2])
+    events = []
+    assert(wait_until(lambda: events.extend(server.drain_ping_events()) or len(events) >= 2))
+    assert([(event.client_id, event.kind, event.payload) for event in events] == [(client.client_id, "ping", b"hello"), (client.client_id, "pong", b"are you there")])
+    assert(abs(events[0].timestamp - time.time()) < 5)
+
+def test_keepalive_pongs_are_not_reported():
+  with quicksocket.testing.running_server(ping_interval_ms = 50, ping_events = True) as server, quicksocket.testing.connect(server) as client:
+    # recv() answers the pings as they come.
+    def answered():
+      client.recv(timeout_ms = 20)
+      return server.get_client_stats(client.client_id).rtt_ms is not None
+    assert(wait_until(answered))
+    assert(server.drain_ping_events() == [])
+    # The consumer's own pings' pongs don't time the round trips, even if they look like the keepalive pings'.
+    server.send_ping(client.client_id, (0).to_bytes(8, 'big'))
+    assert(wait_until(lambda: client.recv(timeout_ms = 20) is None and len(server.drain_ping_events()) == 1))
+
+def test_ping_events_are_opt_in():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client._send_frame(quicksocket.testing.OPCODE_PING, b"hello")
+    frame = client._recv_frame(time.monotonic() + 1)
+    assert(frame is not None and frame[1:] == (quicksocket.testing.OPCODE_PONG, b"hello"))
+    assert(server.drain_ping_events() == [])
+
+def test_bad_pings_are_refused():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.send_ping(client.client_id, b"x" * 125)
+    for client_id, payload, error in [
+      (client.client_id, b"x" * 126, ValueError),
+      (client.client_id, "text", TypeError),
+      ("nobody", b"", quicksocket.SendError),
+    ]:
+      try:
+        server.send_ping(client_id, payload)
+        assert(False)
+      except error:
+        pass
+
+def test_bad_keepalive_is_refused():
+  for kwargs, complaint in [
+    (dict(ping_interval_ms = 0), "more than 0"),
+    (dict(ping_interval_ms = 100, max_missed_pongs = 0), "at least 1 pong"),
+    (dict(max_missed_pongs = 3), "pass ping_interval_ms too"),
+    (dict(ping_interval_ms = 100, unhealthy_after_missed_pongs = 2), "fewer than the 2"),
+    (dict(ping_interval_ms = 100, unhealthy_after_missed_pongs = 0), "at least 1 missed pong"),
+    (dict(unhealthy_after_missed_pongs = 1), "pass ping_interval_ms too"),
+    (dict(idle_timeout_ms = 0), "idle timeout must be more than 0"),
+  ]:
+    try:
+      with quicksocket.testing.running_server(**kwargs):
+        assert(False)
+    except ValueError as err:
+      assert(complaint in str(err)), err
+
+if __name__ == "__main__":
+  test_clients_are_pinged()
+  test_answering_clients_stay_connected()
+  test_silent_clients_are_disconnected()
+  test_unhealthy_clients_are_reported()
+  test_round_trips_are_timed()
+  test_round_trips_need_pings()
+  test_idle_clients_are_disconnected()
+  test_pings_dont_keep_clients_from_idling()
+  test_ping_events()
+  test_keepalive_pongs_are_not_reported()
+  test_ping_events_are_opt_in()
+  test_bad_pings_are_refused()
+  test_bad_keepalive_is_refused()
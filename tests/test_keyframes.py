@@ -0,0 +1,56 @@
+'''Tests for keyframe and delta streams (send_keyframe(), send_delta()): clients that connect mid-stream, or fall behind, are sent the keyframe and deltas they need before any more deltas.'''
+
+import quicksocket
+import quicksocket.testing
+
+def test_new_client_is_sent_the_stream_so_far():
+  with quicksocket.testing.running_server() as server:
+    server.send_keyframe('doc', 'k1')
+    server.send_delta('doc', 'k1+1')
+    server.send_delta('doc', 'k1+2')
+    with quicksocket.testing.connect(server) as client:
+      assert([client.expect() for _ in range(3)] == ['k1', 'k1+1', 'k1+2'])
+      server.send_delta('doc', 'k1+3')
+      assert(client.expect() == 'k1+3')
+      # A new keyframe starts the stream over.
+      server.send_keyframe('doc', 'k2')
+      server.send_delta('doc', 'k2+1')
+      assert([client.expect() for _ in range(2)] == ['k2', 'k2+1'])
+    with quicksocket.testing.connect(server) as client:
+      assert([client.expect() for _ in range(2)] == ['k2', 'k2+1'])
+      assert(client.recv(timeout_ms = 200) is None)
+    assert(server.get_stats().keyframes_resent == 0)
+
+def test_lagging_client_catches_up():
+  payload = b'.' * (256 * 1024)
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    # The client reads nothing while far more is sent than its socket can take, and misses broadcasts.
+    server.send_keyframe('video', b'k:0:' + payload)
+    for i in range(200):
+      server.send_delta('video', b'd:%d:' % i + payload)
+    # Whatever it missed, it's never written a delta without the keyframe and every delta before it.
+    next_delta = None
+    while next_delta != 200:
+      kind, number = client.expect(timeout_ms = 5000).split(b':')[:2]
+      if kind == b'k':
+        next_delta = 0
+      else:
+        assert(int(number) == next_delta)
+        next_delta += 1
+    assert(server.get_stats().messages_dropped_by_reason.get('lagged', 0) > 0)
+    assert(server.get_stats().keyframes_resent > 0)
+
+def test_delta_without_a_keyframe():
+  with quicksocket.testing.running_server() as server:
+    try:
+      server.send_delta('doc', 'edit')
+      assert(False)
+    except quicksocket.SendError:
+      pass
+    server.send_keyframe('doc', 'document')
+    server.send_delta('doc', 'edit')
+
+if __name__ == "__main__":
+  test_new_client_is_sent_the_stream_so_far()
+  test_lagging_client_catches_up()
+  test_delta_without_a_keyframe()
@@ -0,0 +1,39 @@
+'''Tests for link emulation (link_latency_ms, link_jitter_ms, link_bits_per_sec): clients written to as if over a slow, distant link.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def test_latency_and_jitter():
+  with quicksocket.testing.running_server(link_latency_ms = 200, link_jitter_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    sent = time.monotonic()
+    server.send_messages([str(i) for i in range(20)])
+    # Held back by the latency, give or take the jitter, but never reordered.
+    assert(client.expect() == "0")
+    assert(time.monotonic() - sent >= 0.14)
+    assert([client.expect() for _ in range(19)] == [str(i) for i in range(1, 20)])
+    assert(time.monotonic() - sent < 2)
+
+def test_bandwidth():
+  # 100 KB at 2 Mbit/s takes 0.4s to send.
+  with quicksocket.testing.running_server(link_bits_per_sec = 2_000_000) as server, quicksocket.testing.connect(server) as client:
+    sent = time.monotonic()
+    for _ in range(10):
+      server.send_messages([b"x" * 10_000])
+    for _ in range(10):
+      assert(client.expect() == b"x" * 10_000)
+    assert(0.35 <= time.monotonic() - sent < 2)
+
+def test_link_arguments():
+  for kwargs in [{"link_latency_ms": -1}, {"link_jitter_ms": -1}, {"link_bits_per_sec": 0}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_latency_and_jitter()
+  test_bandwidth()
+  test_link_arguments()
@@ -0,0 +1,64 @@
+import socket
+
+import quicksocket.server
+
+def drain(server: quicksocket.server.Server, count: int):
+  '''Drains client messages until count have arrived (or a drain times out).'''
+  msgs = []
+  while len(msgs) < count:
+    drained = server.drain_client_messages(timeout_ms = 1000)
+    if not drained:
+      break
+    msgs += drained
+  return msgs
+
+def test_loopback_round_trip():
+  # The port is only a label for a loopback server: nothing binds it, so it can't clash with anything.
+  with quicksocket.server.Server(1, loopback = True) as server, server.connect_loopback() as client:
+    client.send(["hello", b"\x00\x01"])
+    assert(drain(server, 2) == ["hello", b"\x00\x01"])
+    assert([(event.client_id, event.kind) for event in server.drain_connection_events()] == [(client.client_id, "connected")])
+
+    # Broadcasts and targeted sends both reach it.
+    server.send_messages(["to everyone"])
+    server.send_to_client(client.client_id, [b"just you"])
+    assert(client.recv(timeout_ms = 1000) == "to everyone")
+    assert(client.recv(timeout_ms = 1000) == b"just you")
+    assert(client.recv(timeout_ms = 50) is None)
+
+def test_loopback_clients_are_routed_separately():
+  with quicksocket.server.Server(1, loopback = True) as server:
+    first = server.connect_loopback()
+    second = server.connect_loopback()
+    assert(first.client_id != second.client_id)
+
+    server.send_and_confirm(second.client_id, ["only second"], timeout_ms = 1000)
+    assert(second.recv(timeout_ms = 1000) == "only second")
+    assert(first.recv(timeout_ms = 50) is None)
+
+    first.close()
+    assert(server.wait_for_client(timeout_ms = 1000))
+    assert(server.get_stats().current_clients == 1)
+
+def test_loopback_server_binds_nothing():
+  with socket.socket() as probe:
+    probe.bind(("127.0.0.1", 0))
+    port = probe.getsockname()[1]
+    # Would fail with BindError if the server tried to bind the (taken) port.
+    with quicksocket.server.Server(port, loopback = True) as server, server.connect_loopback() as client:
+      client.send(["ok"])
+      assert(drain(server, 1) == ["ok"])
+
+def test_connect_loopback_requires_loopback_server():
+  with quicksocket.server.Server(59989) as server:
+    try:
+      server.connect_loopback()
+      assert(False)
+    except quicksocket.QuicksocketError:
+      pass
+
+if __name__ == "__main__":
+  test_loopback_round_trip()
+  test_loopback_clients_are_routed_separately()
+  test_loopback_server_binds_nothing()
+  test_connect_loopback_requires_loopback_server()
@@ -0,0 +1,63 @@
+'''Tests for the memory budget: the payload bytes in the server's queues are counted, and with memory_budget_bytes, sends that would take them over it fail and client messages that would are dropped.'''
+
+
+import quicksocket
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+# Big enough that a few fill the sockets' buffers, so the rest wait in the queues.
+BIG = b"x" * 1000000
+
+def test_queued_bytes_are_counted():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    assert(server.get_stats().queued_bytes == 0)
+    client.send([b"a" * 1000, "b" * 500])
+    # Received, but not drained yet.
+    assert(wait_until(lambda: server.get_stats().queued_bytes == 1500))
+    assert(server.drain_client_messages() == [b"a" * 1000, "b" * 500])
+    stats = server.get_stats()
+    assert((stats.queued_bytes, stats.peak_queued_bytes, stats.messages_over_budget) == (0, 1500, 0))
+
+def test_sends_over_the_budget_fail():
+  with quicksocket.testing.running_server(memory_budget_bytes = 4000000) as server, quicksocket.testing.connect(server) as client:
+    # Too big to ever fit.
+    try:
+      server.send_messages([b"y" * 4000001])
+      assert(False)
+    except quicksocket.SendError as err:
+      assert("memory budget" in str(err))
+    # The client isn't reading, so once its socket is full, the broadcasts wait in the queue until there's no more room.
+    sent = 0
+    try:
+      for _ in range(40):
+        server.send_messages([BIG])
+        sent += 1
+      assert(False)
+    except quicksocket.SendError:
+      pass
+    assert(server.get_stats().queued_bytes <= 4000000)
+    assert([client.expect(timeout_ms = 5000) for _ in range(sent)] == [BIG] * sent)
+    assert(wait_until(lambda: server.get_stats().queued_bytes == 0))
+    stats = server.get_stats()
+    assert(stats.messages_over_budget == 2 and stats.messages_dropped == 0)
+    server.send_to_client(client.client_id, ["room again"])
+    assert(client.expect() == "room again")
+
+def test_client_messages_over_the_budget_are_dropped():
+  with quicksocket.testing.running_server(memory_budget_bytes = 10000) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    client.send([b"m" * 4000 for _ in range(5)])
+    assert(wait_until(lambda: server.get_stats().messages_over_budget == 3))
+    assert(server.drain_client_messages() == [b"m" * 4000] * 2)
+    stats = server.get_stats()
+    assert(stats.messages_dropped == 3 and stats.queued_bytes == 0)
+    events = [event for event in server.drain_error_events() if event.category == "receive"]
+    assert(len(events) == 3 and "memory budget" in events[0].message)
+    # Drained, so there's room for more.
+    client.send(["after"])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ["after"])
+
+if __name__ == "__main__":
+  test_queued_bytes_are_counted()
+  test_sends_over_the_budget_fail()
+  test_client_messages_over_the_budget_are_dropped()
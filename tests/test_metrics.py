@@ -0,0 +1,107 @@
+'''Tests for log_scalar() and log_histogram(): metrics batched into "QSMT" frames, and their history written to clients as they connect.'''
+
+import array
+import struct
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def decode(frame):
+  '''A metrics frame's kind, and its series: {tag: [(step, value)]} for scalars, {tag: [(step, min, max, counts)]} for histograms.'''
+  magic, version, kind, series_count = struct.unpack('<4sBBxxI', frame[:12])
+  assert((magic, version) == (b'QSMT', 1))
+  at, scalars, histograms = 12, {}, {}
+  for _ in range(series_count):
+    tag_len, = struct.unpack('<H', frame[at:at + 2])
+    tag = frame[at + 2:at + 2 + tag_len].decode()
+    kind_of, count = struct.unpack('<BI', frame[at + 2 + tag_len:at + 7 + tag_len])
+    at += 7 + tag_len
+    points = []
+    for _ in range(count):
+      if kind_of == 0:
+        step, value = struct.unpack('<dd', frame[at:at + 16])
+        points.append((int(step), value))
+        at += 16
+      else:
+        step, least, greatest, bins = struct.unpack('<dddI', frame[at:at + 28])
+        points.append((int(step), least, greatest, list(struct.unpack('<{}I'.format(bins), frame[at + 28:at + 28 + bins * 4]))))
+        at += 28 + bins * 4
+    (scalars if kind_of == 0 else histograms)[tag] = points
+  assert(at == len(frame))
+  return kind, scalars, histograms
+
+def test_batching():
+  with quicksocket.testing.running_server(metrics_flush_ms = 200) as server, quicksocket.testing.connect(server) as client:
+    for step in range(5):
+      server.log_scalar('loss', step, 1.0 / (step + 1))
+      server.log_scalar('accuracy', step, step / 10)
+    # All ten go out together, in one frame.
+    kind, scalars, histograms = decode(client.expect(timeout_ms = 2000))
+    assert(kind == 0 and histograms == {})
+    assert(scalars == {'loss': [(step, 1.0 / (step + 1)) for step in range(5)], 'accuracy': [(step, step / 10) for step in range(5)]})
+
+    server.log_scalar('loss', 5, 0.1)
+    assert(decode(client.expect(timeout_ms = 2000)) == (0, {'loss': [(5, 0.1)]}, {}))
+
+def test_histogram():
+  with quicksocket.testing.running_server(metrics_flush_ms = 0) as server, quicksocket.testing.connect(server) as client:
+    server.log_histogram('weights', 3, array.array('f', [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, float('nan')]), bins = 5)
+    assert(decode(client.expect(timeout_ms = 2000)) == (0, {}, {'weights': [(3, 0.0, 9.0, [2, 2, 2, 2, 2])]}))
+
+    # A list works too, as do integers, and a single value goes in the first bin.
+    server.log_histogram('rewards', 4, [7, 7, 7], bins = 2)
+    assert(decode(client.expect(timeout_ms = 2000)) == (0, {}, {'rewards': [(4, 7.0, 7.0, [3, 0])]}))
+
+def test_history():
+  with quicksocket.testing.running_server(metrics_flush_ms = 0, metrics_history = 3) as server:
+    with quicksocket.testing.connect(server) as client:
+      # Nothing's been logged, so there's no history.
+      server.send_messages(['hello'])
+      assert(client.expect(timeout_ms = 2000) == 'hello')
+    for step in range(5):
+      server.log_scalar('loss', step, step * 2.0)
+    server.log_histogram('weights', 4, [1.0, 2.0], bins = 1)
+    # (Broadcast to no one, before the next client connects.)
+    time.sleep(0.2)
+
+    # A client that connects later is written each tag's last 3 points.
+    with quicksocket.testing.connect(server) as late:
+      assert(decode(late.expect(timeout_ms = 2000)) == (1, {'loss': [(2, 4.0), (3, 6.0), (4, 8.0)]}, {'weights': [(4, 1.0, 2.0, [2])]}))
+
+def test_invalid():
+  with quicksocket.testing.running_server() as server:
+    for tag in ['', 'x' * 70000]:
+      try:
+        server.log_scalar(tag, 0, 1.0)
+        assert(False)
+      except quicksocket.SendError:
+        pass
+    for bins in [0, 1025]:
+      try:
+        server.log_histogram('weights', 0, [1.0], bins = bins)
+        assert(False)
+      except quicksocket.SendError:
+        pass
+    try:
+      server.log_histogram('weights', 0, ['a'])
+      assert(False)
+    except TypeError:
+      pass
+
+  try:
+    quicksocket.Server(port = 0, metrics_flush_ms = -1).start()
+    assert(False)
+  except ValueError:
+    pass
+  try:
+    quicksocket.Server(port = 0).log_scalar('loss', 0, 1.0)
+    assert(False)
+  except quicksocket.ServerNotRunning:
+    pass
+
+if __name__ == '__main__':
+  test_batching()
+  test_histogram()
+  test_history()
+  test_invalid()
@@ -0,0 +1,33 @@
+import time
+import urllib.error
+import urllib.request
+
+import quicksocket.server
+
+def fetch(port: int):
+  '''GET the server root as a plain HTTP client would, returning (status, body).'''
+  try:
+    with urllib.request.urlopen("http://localhost:" + str(port) + "/") as response:
+      return response.status, response.read()
+  except urllib.error.HTTPError as e:
+    return e.code, e.read()
+
+def test_two_servers_on_different_ports():
+  viewer_port = 59997
+  control_port = 59998
+
+  with quicksocket.server.Server(viewer_port, landing_page = "viewer") as viewer, quicksocket.server.Server(control_port, landing_page = "control") as control:
+    time.sleep(0.200)
+    assert(viewer.is_running() and control.is_running())
+
+    assert(fetch(viewer_port) == (200, b"viewer"))
+    assert(fetch(control_port) == (200, b"control"))
+
+    # Stopping one server leaves the other running.
+    control.stop(wait = True)
+    assert(not control.is_running())
+    assert(viewer.is_running())
+    assert(fetch(viewer_port) == (200, b"viewer"))
+
+if __name__ == "__main__":
+  test_two_servers_on_different_ports()
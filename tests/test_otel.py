@@ -0,0 +1,75 @@
+'''Tests for OpenTelemetry export, from a server run in a subprocess (as the OTEL_* environment variables are read once per process) to a minimal in-process OTLP/HTTP collector. Needs quicksocket built with the "otel" feature; skipped otherwise.'''
+
+import http.server
+import json
+import os
+import subprocess
+import sys
+import threading
+
+SERVER_SCRIPT = """
+import time
+import quicksocket.testing
+with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+  server.send_messages(["hello"])
+  client.expect("hello")
+  time.sleep(0.5)
+# (Long enough for the last spans to be exported.)
+time.sleep(0.5)
+"""
+
+class Collector(http.server.ThreadingHTTPServer):
+  '''Takes OTLP/JSON exports, keeping each request's path and body.'''
+  def __init__(self):
+    self.exports = []
+    super().__init__(("127.0.0.1", 0), CollectorHandler)
+
+class CollectorHandler(http.server.BaseHTTPRequestHandler):
+  def do_POST(self):
+    body = self.rfile.read(int(self.headers["Content-Length"]))
+    self.server.exports.append((self.path, self.headers["Content-Type"], self.headers.get("X-Api-Key"), json.loads(body)))
+    self.send_response(200)
+    self.send_header("Content-Length", "2")
+    self.end_headers()
+    self.wfile.write(b"{}")
+
+  def log_message(self, *args):
+    pass
+
+def test_spans_and_metrics_are_exported():
+  collector = Collector()
+  threading.Thread(target = collector.serve_forever, daemon = True).start()
+  env = dict(os.environ,
+    OTEL_EXPORTER_OTLP_ENDPOINT = "http://127.0.0.1:{}".format(collector.server_address[1]),
+    OTEL_EXPORTER_OTLP_HEADERS = "x-api-key=secret%20key",
+    OTEL_SERVICE_NAME = "quicksocket-test",
+    OTEL_BSP_SCHEDULE_DELAY = "100",
+    OTEL_METRIC_EXPORT_INTERVAL = "100")
+  result = subprocess.run([sys.executable, "-c", SERVER_SCRIPT], env = env, capture_output = True, timeout = 30)
+  collector.shutdown()
+  assert(result.returncode == 0)
+  if b"[otel] Exporting" not in result.stdout:
+    print("[test_otel] quicksocket wasn't built with the \"otel\" feature; skipping.")
+    return
+
+  assert(all(content_type == "application/json" and api_key == "secret key" for (_, content_type, api_key, _) in collector.exports))
+  spans = [span for (path, _, _, export) in collector.exports if path == "/v1/traces"
+    for resource in export["resourceSpans"] for scope in resource["scopeSpans"] for span in scope["spans"]]
+  resource = [export for (path, _, _, export) in collector.exports if path == "/v1/traces"][0]["resourceSpans"][0]["resource"]
+  assert({"key": "service.name", "value": {"stringValue": "quicksocket-test"}} in resource["attributes"])
+
+  # The connection is the root of a trace, with its handshake and client's tasks within it.
+  connection = [span for span in spans if span["name"] == "connection"][0]
+  assert("parentSpanId" not in connection)
+  children = [span for span in spans if span.get("parentSpanId") == connection["spanId"]]
+  assert(sorted(span["name"] for span in children) == ["handshake", "receiver", "sender"])
+  assert(all(span["traceId"] == connection["traceId"] for span in children))
+  assert(int(connection["startTimeUnixNano"]) <= int(connection["endTimeUnixNano"]))
+
+  metrics = {metric["name"]: metric for (path, _, _, export) in collector.exports if path == "/v1/metrics"
+    for resource in export["resourceMetrics"] for scope in resource["scopeMetrics"] for metric in scope["metrics"]}
+  assert(int(metrics["quicksocket.messages.sent"]["sum"]["dataPoints"][0]["asInt"]) >= 0)
+  assert("quicksocket.clients" in metrics and "quicksocket.queue.bytes" in metrics)
+
+if __name__ == "__main__":
+  test_spans_and_metrics_are_exported()
@@ -0,0 +1,265 @@
+'''Tests for the server's outbound path: the frames it encodes itself, the replies its reader queues for the writer, payload buffers reused from one message to the next, big bytes payloads sent without being copied, registered messages, bursts written together (and writes batched under load), clients that fall behind, and the time it all takes (in total, and in latency histograms).'''
+
+import sys
+import threading
+import time
+
+import quicksocket.testing
+from quicksocket.testing import OPCODE_PING, OPCODE_PONG, OPCODE_TEXT, wait_until
+
+def test_every_frame_length_encoding():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    # Around each boundary of the 7-bit, 16-bit and 64-bit length encodings.
+    for length in (0, 1, 125, 126, 127, 65535, 65536, 200000):
+      server.send_messages([b"\x07" * length, "é" * (length // 2)])
+      assert(client.expect() == b"\x07" * length)
+      assert(client.expect() == "é" * (length // 2))
+
+def test_pings_are_answered_between_messages():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client._send_frame(OPCODE_PING, b"are you there")
+    server.send_messages(["before or after the pong"])
+    frames = [client._recv_frame(deadline = None) for _ in range(2)]
+    assert(sorted((opcode, payload) for _, opcode, payload in frames) == sorted([(OPCODE_PONG, b"are you there"), (OPCODE_TEXT, b"before or after the pong")]))
+
+def test_reused_buffers_hold_only_their_own_message():
+  with quicksocket.testing.running_server() as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(3)]
+    # Big then small, so a small message lands in a buffer that held a big one; and to one client alone, whose messages are recycled as soon as they're written. (Fewer broadcasts than the server queues, so no client can fall behind and skip any.)
+    for i in range(12):
+      server.send_messages([str(i) * (1000 if i % 2 == 0 else 1)])
+      server.send_to_client(clients[i % 3].client_id, [bytes([i]) * (i % 7)])
+    for index, client in enumerate(clients):
+      expected = [str(i) * (1000 if i % 2 == 0 else 1) for i in range(12)] + [bytes([i]) * (i % 7) for i in range(index, 12, 3)]
+      # (Targeted sends can overtake broadcasts, so only the broadcasts' order is certain.)
+      received = [client.expect() for _ in expected]
+      assert(sorted(received, key = repr) == sorted(expected, key = repr))
+      assert([msg for msg in received if isinstance(msg, str)] == expected[:12])
+      client.close()
+    assert(server.get_stats().messages_sent == 3 * 12 + 12)
+
+def test_big_bytes_are_shared_until_sent():
+  with quicksocket.testing.running_server() as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(3)]
+    payload = bytes(range(256)) * 400
+    refs = sys.getrefcount(payload)
+    server.send_messages([payload, b"small"])
+    for client in clients:
+      assert(client.expect() == payload)
+      assert(client.expect() == b"small")
+    server.send_to_client(clients[0].client_id, [payload])
+    assert(clients[0].expect() == payload)
+    # The server's references are released once every client has been sent the payload (the last of them the next time Python calls in).
+    def released():
+      server.get_stats()
+      return sys.getrefcount(payload) == refs
+    assert(wait_until(released))
+    for client in clients:
+      client.close()
+
+def test_registered_messages_are_sent_as_registered():
+  asset = bytearray(b"\x01" * 100000)
+  registered = [quicksocket.register_message(payload) for payload in ("hello", "é" * 70000, b"", asset, memoryview(b"view"))]
+  assert([(msg.is_text, msg.len) for msg in registered] == [(True, 5), (True, 140000), (False, 0), (False, 100000), (False, 4)])
+  assert(repr(registered[0]) == "<quicksocket.RegisteredMessage text, 5 bytes>")
+  # Registering copies the payload.
+  asset[0] = 0
+  with quicksocket.testing.running_server() as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(2)]
+    expected = ["hello", "é" * 70000, b"", b"\x01" * 100000, b"view"]
+    for _ in range(3):
+      server.send_messages(registered + ["unregistered"])
+      for client in clients:
+        assert([client.expect() for _ in range(6)] == expected + ["unregistered"])
+    server.send_to_client(clients[1].client_id, [registered[0], b"targeted"])
+    assert([clients[1].expect() for _ in range(2)] == ["hello", b"targeted"])
+    server.send_and_confirm(clients[0].client_id, [registered[3]])
+    assert(clients[0].expect() == b"\x01" * 100000)
+    assert(server.get_stats().messages_sent == 2 * 3 * 6 + 2 + 1)
+    assert(wait_until(lambda: server.get_stats().queued_bytes == 0))
+    try:
+      server.send_messages([registered[0], 12])
+      assert(False)
+    except TypeError as err:
+      assert("RegisteredMessage" in str(err))
+    for client in clients:
+      client.close()
+
+def test_bursts_arrive_whole_and_in_order():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    # Sent faster than they're written, so they queue up and go out together; payloads big enough to be written from where they are, between small ones copied in after their headers.
+    expected = []
+    for i in range(15):
+      batch = [str(i), bytes([i]) * (3000 + i), "x" * (1024 + (i % 2))]
+      server.send_messages(batch)
+      expected += batch
+    assert([client.expect() for _ in expected] == expected)
+    assert(client.recv(timeout_ms = 50) is None)
+
+def test_writes_are_batched_under_load_only():
+  with quicksocket.testing.running_server(lag_policy = "block") as server, quicksocket.testing.connect(server) as client:
+    # One message at a time, faster than one write each: the writes are held back for more to join them.
+    for i in range(2000):
+      server.send_messages([str(i)])
+    assert([client.expect() for _ in range(2000)] == [str(i) for i in range(2000)])
+    stats = server.get_stats()
+    assert(stats.messages_sent == 2000 and stats.socket_writes < 2000 / 10)
+    # Sent one at a time once the client has caught up, each goes straight out on its own.
+    for i in range(5):
+      started = time.monotonic()
+      server.send_messages(["idle " + str(i)])
+      assert(client.expect() == "idle " + str(i))
+      assert(time.monotonic() - started < 0.5)
+    assert(server.get_stats().socket_writes == stats.socket_writes + 5)
+  try:
+    quicksocket.Server(port = 1, loopback = True, max_flush_delay_ms = -1).start()
+    assert(False)
+  except ValueError:
+    pass
+
+def test_corked_writes_take_what_arrives_meanwhile():
+  with quicksocket.testing.running_server(cork_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    # Too far apart to count as load, but inside the cork.
+    for i in range(10):
+      server.send_messages([str(i)])
+      time.sleep(0.002)
+    assert([client.expect() for _ in range(10)] == [str(i) for i in range(10)])
+    stats = server.get_stats()
+    assert(stats.messages_sent == 10 and stats.socket_writes <= 3)
+  try:
+    quicksocket.Server(port = 1, loopback = True, cork_ms = -1).start()
+    assert(False)
+  except ValueError as err:
+    assert("cork_ms" in str(err))
+
+def test_outbound_time_is_counted():
+  with quicksocket.testing.running_server() as server:
+    stats = server.get_stats()
+    assert((stats.serialization_ns, stats.channel_wait_ns, stats.socket_write_ns) == (0, 0, 0))
+    with quicksocket.testing.connect(server) as client:
+      server.send_messages(["x" * 100000] * 5)
+      for _ in range(5): client.expect()
+    # (The stats are counted once a write is done, which the client can see a moment before.)
+    deadline = time.monotonic() + 1
+    while time.monotonic() < deadline and server.get_stats().socket_write_ns == 0: time.sleep(0.01)
+    stats = server.get_stats()
+    assert(stats.serialization_ns > 0 and stats.channel_wait_ns > 0 and stats.socket_write_ns > 0)
+    assert("socket_write_ns=" in repr(stats))
+
+def test_latency_histograms():
+  with quicksocket.testing.running_server() as server:
+    # Only kept when asked for.
+    assert(server.get_latency_histograms() is None)
+  with quicksocket.testing.running_server(latency_histograms = True) as server:
+    histograms = server.get_latency_histograms()
+    assert([h.count for h in (histograms.enqueue, histograms.fan_out, histograms.socket_write)] == [0, 0, 0])
+    assert(histograms.enqueue.quantile_ns(0.99) is None)
+    with quicksocket.testing.connect(server) as client:
+      for _ in range(10):
+        server.send_messages(["x" * 1000, b"y", "z"])
+      server.send_to_client(client.client_id, ["just you"])
+      for _ in range(31): client.expect()
+    # (Socket writes are timed once they're done, which the client can see a moment before.)
+    deadline = time.monotonic() + 1
+    while time.monotonic() < deadline and server.get_latency_histograms().socket_write.count < 31: time.sleep(0.01)
+    histograms = server.get_latency_histograms()
+    for histogram in (histograms.enqueue, histograms.fan_out, histograms.socket_write):
+      # Every message counts, not every batch.
+      assert(histogram.count == 31)
+      assert(sum(count for _, count in histogram.buckets) == 31)
+      assert(histogram.buckets[-1][0] is None and [bound for bound, _ in histogram.buckets[:3]] == [1000, 2000, 4000])
+      assert(0 < histogram.max_ns and histogram.max_ns * 31 >= histogram.total_ns)
+      p50, p99 = histogram.quantile_ns(0.5), histogram.quantile_ns(0.99)
+      assert(0 < p50 <= p99 <= histogram.max_ns)
+    assert("p99_ns=" in repr(histograms))
+
+# Big enough that a few fill the sockets' buffers, so a client that doesn't read falls behind the rest.
+BIG = "x" * 1000000
+
+def read_until_quiet(client):
+  received = []
+  while True:
+    msg = client.recv(timeout_ms = 500)
+    if msg is None: return received
+    received.append(msg)
+
+def test_lagging_clients_miss_messages_and_are_told():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    for i in range(40):
+      server.send_messages([str(i) + BIG])
+    received = read_until_quiet(client)
+    missed = server.get_stats().messages_missed_by_client.get(client.client_id, 0)
+    assert(missed > 0)
+    assert(len(received) + missed == 40)
+    assert(server.get_stats().messages_dropped == missed)
+    assert(server.get_stats().messages_dropped_by_reason == {"lagged": missed, "over_budget": 0, "disconnected": 0, "shutdown": 0})
+    # It missed the oldest it hadn't been written yet; the rest arrived in order.
+    assert([int(msg[:-len(BIG)]) for msg in received] == sorted(int(msg[:-len(BIG)]) for msg in received))
+    assert(received[-1] == "39" + BIG)
+    events = [event for event in server.drain_error_events() if event.category == "send" and event.client_id == client.client_id]
+    assert(events and "missed" in events[-1].message and "(" + str(missed) + " in all)" in events[-1].message)
+
+def test_the_block_policy_waits_for_lagging_clients():
+  with quicksocket.testing.running_server(lag_policy = "block") as server, quicksocket.testing.connect(server) as client:
+    done = threading.Event()
+    def send():
+      for i in range(40):
+        server.send_messages([str(i) + BIG])
+      done.set()
+    sender = threading.Thread(target = send)
+    sender.start()
+    # The sends wait for the client, which has yet to read anything.
+    assert(not done.wait(timeout = 0.5))
+    received = read_until_quiet(client)
+    sender.join(timeout = 10)
+    assert(done.is_set())
+    assert(received == [str(i) + BIG for i in range(40)])
+    stats = server.get_stats()
+    assert((stats.messages_dropped, stats.messages_missed_by_client) == (0, {}))
+    assert(stats.broadcast_wait_ns > 0)
+
+def test_blocked_sends_give_up_after_the_timeout():
+  with quicksocket.testing.running_server(lag_policy = "block", block_timeout_ms = 20) as server, quicksocket.testing.connect(server) as client:
+    started = time.monotonic()
+    for i in range(40):
+      server.send_messages([str(i) + BIG])
+    assert(time.monotonic() - started >= 0.02)
+    received = read_until_quiet(client)
+    missed = server.get_stats().messages_missed_by_client.get(client.client_id, 0)
+    assert(missed > 0 and len(received) + missed == 40)
+  for bad in ({"lag_policy": "sometimes"}, {"block_timeout_ms": 10}):
+    try:
+      quicksocket.Server(port = 0, **bad).start()
+      assert(False)
+    except ValueError:
+      pass
+
+def test_sends_after_shutdown_are_counted():
+  with quicksocket.testing.running_server() as server:
+    server.stop(wait = True)
+    server.drain_error_events()
+    try:
+      server.send_messages(["too", "late"])
+      assert(False)
+    except quicksocket.ServerNotRunning:
+      pass
+    assert(server.get_stats().messages_dropped_by_reason["shutdown"] == 2)
+    events = server.drain_error_events()
+    assert(len(events) == 1 and (events[0].severity, events[0].category) == ("warning", "send") and "after the server shut down" in events[0].message)
+
+if __name__ == "__main__":
+  test_every_frame_length_encoding()
+  test_pings_are_answered_between_messages()
+  test_reused_buffers_hold_only_their_own_message()
+  test_big_bytes_are_shared_until_sent()
+  test_registered_messages_are_sent_as_registered()
+  test_bursts_arrive_whole_and_in_order()
+  test_writes_are_batched_under_load_only()
+  test_corked_writes_take_what_arrives_meanwhile()
+  test_outbound_time_is_counted()
+  test_latency_histograms()
+  test_lagging_clients_miss_messages_and_are_told()
+  test_the_block_policy_waits_for_lagging_clients()
+  test_blocked_sends_give_up_after_the_timeout()
+  test_sends_after_shutdown_are_counted()
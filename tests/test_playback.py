@@ -0,0 +1,63 @@
+'''Tests for the playback control protocol (playback_control): pause, resume, seek and speed controls from clients, applied by the server to the playback state the consumer reads.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain_playback_events(server, count, timeout_s = 2.0):
+  events = []
+  deadline = time.time() + timeout_s
+  while len(events) < count and time.time() < deadline:
+    events += server.drain_playback_events()
+    time.sleep(0.01)
+  return events
+
+def test_controls():
+  with quicksocket.testing.running_server(playback_control = True) as server, quicksocket.testing.connect(server) as client:
+    state = server.get_playback_state()
+    assert(not state.paused and state.speed == 1.0 and state.changes == 0 and state.client_id is None)
+
+    client.send(['playback:pause', 'playback:seek:12.5', 'playback:speed:2'])
+    events = drain_playback_events(server, 3)
+    assert([(event.control, event.value) for event in events] == [('pause', None), ('seek', 12.5), ('speed', 2.0)])
+    assert(events[0].state.paused and events[1].state.position == 12.5)
+
+    # Paused, it stays where it was seeked to.
+    time.sleep(0.05)
+    state = server.get_playback_state()
+    assert(state.paused and state.position == 12.5 and state.speed == 2.0 and state.changes == 3)
+    assert(state.client_id == events[0].client_id)
+
+    # Playing, it moves on at its speed.
+    client.send(['playback:resume'])
+    assert(drain_playback_events(server, 1)[0].control == 'resume')
+    time.sleep(0.1)
+    state = server.get_playback_state()
+    assert(not state.paused and state.position >= 12.5 + 0.2)
+
+    # The controls aren't passed on to the consumer, but other messages are.
+    client.send(['hello'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['hello'])
+
+def test_malformed_controls():
+  with quicksocket.testing.running_server(playback_control = True) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    client.send(['playback:seek:-1', 'playback:speed:0', 'playback:rewind', 'playback:seek:3'])
+    events = drain_playback_events(server, 1)
+    assert([(event.control, event.value) for event in events] == [('seek', 3.0)])
+    assert(server.get_playback_state().changes == 1)
+    errors = [error for error in server.drain_error_events() if 'playback' in error.message]
+    assert(len(errors) == 3 and all(error.severity == 'warning' and error.category == 'receive' for error in errors))
+
+def test_playback_control_off():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    assert(server.get_playback_state() is None)
+    client.send(['playback:pause'])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ['playback:pause'])
+    assert(server.drain_playback_events() == [])
+
+if __name__ == "__main__":
+  test_controls()
+  test_malformed_controls()
+  test_playback_control_off()
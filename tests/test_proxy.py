@@ -0,0 +1,92 @@
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain(server: quicksocket.Server, count: int):
+  '''Drains client messages until count have arrived (or a drain times out).'''
+  msgs = []
+  while len(msgs) < count:
+    drained = server.drain_client_messages(timeout_ms = 1000)
+    if not drained:
+      break
+    msgs += drained
+  return msgs
+
+def test_proxied_path_reaches_backend():
+  with quicksocket.testing.running_server() as backend:
+    routes = {"/legacy": "ws://127.0.0.1:{}".format(backend.get_bound_port())}
+    with quicksocket.testing.running_server(proxy = routes) as front:
+      port = front.get_bound_port()
+      with quicksocket.testing.TestClient(port, path = "/legacy/feed?x=1") as proxied:
+        assert(backend.wait_for_client(timeout_ms = 1000))
+        proxied.send(["to the backend", b"\x01"])
+        assert(drain(backend, 2) == ["to the backend", b"\x01"])
+
+        backend.send_messages(["from the backend"])
+        proxied.expect("from the backend")
+
+        # Other paths are the front server's own clients.
+        with quicksocket.testing.connect(front) as own:
+          own.send(["to the front"])
+          assert(drain(front, 1) == ["to the front"])
+          front.send_messages(["front broadcast"])
+          own.expect("front broadcast")
+          assert(proxied.recv(timeout_ms = 100) is None)
+
+      # The proxied connection never was one of the front server's clients, and closing it closes the backend connection.
+      assert([event.kind for event in front.drain_connection_events()] == ["connected", "disconnected"])
+      deadline = time.monotonic() + 2
+      while backend.get_stats().current_clients > 0 and time.monotonic() < deadline:
+        time.sleep(0.01)
+      assert(backend.get_stats().current_clients == 0)
+
+def test_prefix_is_matched_on_segments():
+  with quicksocket.testing.running_server() as backend:
+    routes = {"/legacy": "ws://127.0.0.1:{}".format(backend.get_bound_port())}
+    with quicksocket.testing.running_server(proxy = routes) as front:
+      # "/legacyx" isn't below "/legacy".
+      with quicksocket.testing.TestClient(front.get_bound_port(), path = "/legacyx") as client:
+        client.send(["mine"])
+        assert(drain(front, 1) == ["mine"])
+      assert(backend.drain_client_messages(timeout_ms = 100) == [])
+
+def test_unreachable_backend_gets_502():
+  with quicksocket.testing.running_server() as unused:
+    dead_port = unused.get_bound_port()
+  with quicksocket.testing.running_server(proxy = {"/": "ws://127.0.0.1:{}".format(dead_port)}) as front:
+    try:
+      quicksocket.testing.TestClient(front.get_bound_port(), path = "/anything")
+      assert(False)
+    except ConnectionError as e:
+      assert("502" in str(e))
+    assert(any(error.category == "proxy" for error in front.drain_error_events()))
+
+def test_shutdown_closes_proxied_connections():
+  with quicksocket.testing.running_server() as backend:
+    front = quicksocket.Server(port = 0, proxy = {"/": "ws://127.0.0.1:{}".format(backend.get_bound_port())})
+    front.start()
+    front.wait_until_started()
+    proxied = quicksocket.testing.TestClient(front.get_bound_port())
+    assert(backend.wait_for_client(timeout_ms = 1000))
+    front.stop(wait = True)
+    try:
+      proxied.recv(timeout_ms = 1000)
+      assert(False)
+    except ConnectionError as e:
+      assert("1001" in str(e))
+
+def test_invalid_routes_are_rejected():
+  for routes in ({"legacy": "ws://127.0.0.1:1"}, {"/legacy": "http://127.0.0.1:1"}):
+    try:
+      quicksocket.Server(port = 0, proxy = routes).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_proxied_path_reaches_backend()
+  test_prefix_is_matched_on_segments()
+  test_unreachable_backend_gets_502()
+  test_shutdown_closes_proxied_connections()
+  test_invalid_routes_are_rejected()
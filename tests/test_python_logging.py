@@ -0,0 +1,76 @@
+import logging
+import socket
+
+import quicksocket
+import quicksocket.testing
+
+class RecordingHandler(logging.Handler):
+  '''Keeps every record it's handed.'''
+  def __init__(self):
+    super().__init__(level = logging.DEBUG)
+    self.records = []
+
+  def emit(self, record):
+    self.records.append(record)
+
+def recording_logger(name: str):
+  '''A logger that passes everything on to a RecordingHandler, so only quicksocket's own level filters what it sees.'''
+  logger = logging.getLogger(name)
+  logger.setLevel(logging.DEBUG)
+  logger.propagate = False
+  handler = RecordingHandler()
+  logger.addHandler(handler)
+  return handler
+
+def send_malformed_upgrade(port: int):
+  '''Asks for a websocket upgrade without a key, which the server rejects with a warning.'''
+  with socket.create_connection(("127.0.0.1", port), timeout = 2) as sock:
+    sock.sendall(b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+    assert(sock.recv(1024).startswith(b"HTTP/1.1 400"))
+
+def test_records_reach_the_logger_at_its_level():
+  handler = recording_logger("quicksocket.test_info")
+  quicksocket.enable_python_logging(logger_name = "quicksocket.test_info", level = logging.INFO)
+  try:
+    with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+      pass
+  finally:
+    # (Delivers the records still queued before it returns.)
+    quicksocket.disable_python_logging()
+
+  messages = [(record.levelno, record.getMessage()) for record in handler.records]
+  assert((logging.INFO, "[handle_connection] New websocket connection: " + client.client_id) in messages)
+  assert(all(record.name == "quicksocket.test_info" for record in handler.records))
+  # Debug lines (e.g. each peer's address) are below the level.
+  assert(all(levelno >= logging.INFO for levelno, _ in messages))
+  assert(not any("Peer address" in message for _, message in messages))
+
+def test_records_below_the_level_are_filtered():
+  handler = recording_logger("quicksocket.test_warning")
+  quicksocket.enable_python_logging(logger_name = "quicksocket.test_warning", level = logging.WARNING)
+  try:
+    with quicksocket.testing.running_server() as server:
+      with quicksocket.testing.connect(server):
+        pass
+      send_malformed_upgrade(server.get_bound_port())
+      assert(quicksocket.testing.wait_until(lambda: server.get_stats().warning_count >= 1))
+  finally:
+    quicksocket.disable_python_logging()
+
+  messages = [(record.levelno, record.getMessage()) for record in handler.records]
+  assert(any(levelno == logging.WARNING and "Rejecting malformed websocket upgrade" in message for levelno, message in messages))
+  assert(all(levelno >= logging.WARNING for levelno, _ in messages))
+  assert(not any("New websocket connection" in message for _, message in messages))
+
+def test_nothing_reaches_the_logger_once_disabled():
+  handler = recording_logger("quicksocket.test_disabled")
+  quicksocket.enable_python_logging(logger_name = "quicksocket.test_disabled", level = logging.DEBUG)
+  quicksocket.disable_python_logging()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server):
+    pass
+  assert(handler.records == [])
+
+if __name__ == "__main__":
+  test_records_reach_the_logger_at_its_level()
+  test_records_below_the_level_are_filtered()
+  test_nothing_reaches_the_logger_once_disabled()
@@ -0,0 +1,30 @@
+import json
+
+import quicksocket
+import quicksocket.server
+
+def test_send_python_objects_limits():
+  port = 59985
+
+  with quicksocket.server.Server(port) as server:
+    # Nothing's connected, so these just go nowhere.
+    server.send_python_objects([{"a": [1, 2, 3]}, (4, 5)])
+    server.send_python_objects([{"a": 1}], serializer = json.dumps)
+
+    try:
+      server.send_python_objects(["x" * 1000], max_bytes = 100)
+      assert(False)
+    except quicksocket.SendError as e:
+      assert(e.message_count == 1)
+      assert("over the limit" in e.reason)
+
+    try:
+      server.send_python_objects([lambda: None])
+      assert(False)
+    except quicksocket.SendError as e:
+      assert("couldn't be serialized" in e.reason)
+
+    assert(server.drain_python_objects() == [])
+
+if __name__ == "__main__":
+  test_send_python_objects_limits()
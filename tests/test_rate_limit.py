@@ -0,0 +1,92 @@
+'''Tests for the outbound rate limits: with max_outbound_bytes_per_sec, the server's writes to all its clients together keep to the rate (after a burst), and the time they wait for it is counted; with client_bytes_per_sec(_by_tag), each client's writes keep to its own, by its tag.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+PAYLOAD = b"r" * 50000
+
+def receive_all(clients, count):
+  for client in clients:
+    assert([client.expect(timeout_ms = 10000) for _ in range(count)] == [PAYLOAD] * count)
+
+def test_writes_keep_to_the_rate():
+  with quicksocket.testing.running_server(max_outbound_bytes_per_sec = 1000000, outbound_burst_bytes = 100000) as server, quicksocket.testing.connect(server) as client:
+    started = time.monotonic()
+    server.send_messages([PAYLOAD] * 10)
+    server.send_messages([PAYLOAD] * 10)
+    receive_all([client], 20)
+    # A million bytes at a million a second, less the burst.
+    elapsed = time.monotonic() - started
+    assert(0.7 < elapsed < 5), elapsed
+    assert(server.get_stats().rate_limit_wait_ns > 500000000)
+
+def test_clients_share_the_rate():
+  with quicksocket.testing.running_server(max_outbound_bytes_per_sec = 1000000, outbound_burst_bytes = 100000) as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(2)]
+    started = time.monotonic()
+    server.send_messages([PAYLOAD] * 10)
+    receive_all(clients, 10)
+    elapsed = time.monotonic() - started
+    assert(0.7 < elapsed < 5), elapsed
+    for client in clients:
+      client.close()
+
+def test_no_limit_by_default():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    started = time.monotonic()
+    server.send_messages([PAYLOAD] * 20)
+    receive_all([client], 20)
+    assert(time.monotonic() - started < 0.7)
+    assert(server.get_stats().rate_limit_wait_ns == 0)
+
+def timed_receive(client, count):
+  started = time.monotonic()
+  receive_all([client], count)
+  return time.monotonic() - started
+
+def test_clients_keep_to_their_tags_limits():
+  with quicksocket.testing.running_server(client_bytes_per_sec_by_tag = {"remote": 500000}) as server:
+    lan, remote = [quicksocket.testing.connect(server) for _ in range(2)]
+    assert(server.set_client_tag(remote.client_id, "remote"))
+    assert(not server.set_client_tag("nobody", "remote"))
+    assert((server.get_client_tag(lan.client_id), server.get_client_tag(remote.client_id)) == (None, "remote"))
+    server.send_messages([PAYLOAD] * 10)
+    # Half a million bytes at half a million a second, less the burst (64 KiB); the LAN client isn't held up.
+    assert(timed_receive(lan, 10) < 0.5)
+    assert(0.6 < timed_receive(remote, 10) < 5)
+    # Untagged again, it has no limit.
+    assert(server.set_client_tag(remote.client_id, None))
+    server.send_messages([PAYLOAD] * 10)
+    assert(timed_receive(remote, 10) < 0.5)
+    lan.close()
+    remote.close()
+
+def test_the_default_client_limit():
+  with quicksocket.testing.running_server(client_bytes_per_sec = 500000, client_bytes_per_sec_by_tag = {"lan": 100000000}) as server:
+    lan, other = [quicksocket.testing.connect(server) for _ in range(2)]
+    server.set_client_tag(lan.client_id, "lan")
+    # A tag without a limit of its own has the default one.
+    server.set_client_tag(other.client_id, "elsewhere")
+    server.send_messages([PAYLOAD] * 10)
+    assert(timed_receive(lan, 10) < 0.5)
+    assert(0.6 < timed_receive(other, 10) < 5)
+    lan.close()
+    other.close()
+
+def test_bad_limits_are_refused():
+  for kwargs in [dict(max_outbound_bytes_per_sec = 0), dict(max_outbound_bytes_per_sec = 1000, outbound_burst_bytes = 0), dict(outbound_burst_bytes = 1000), dict(client_bytes_per_sec = 0), dict(client_bytes_per_sec_by_tag = {"remote": 0})]:
+    try:
+      with quicksocket.testing.running_server(**kwargs):
+        assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_writes_keep_to_the_rate()
+  test_clients_share_the_rate()
+  test_no_limit_by_default()
+  test_clients_keep_to_their_tags_limits()
+  test_the_default_client_limit()
+  test_bad_limits_are_refused()
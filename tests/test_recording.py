@@ -0,0 +1,105 @@
+'''Tests for session recording (start_recording()): every data message in and out, with its time, direction and client, in JSONL or length-prefixed binary.'''
+
+import base64
+import json
+import os
+import struct
+import tempfile
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def drain(server, count, timeout_s = 2.0):
+  '''Drains the server's client messages until count have arrived, or the deadline passes.'''
+  msgs, deadline = [], time.monotonic() + timeout_s
+  while len(msgs) < count and time.monotonic() < deadline:
+    msgs += server.drain_client_messages(timeout_ms = 100, max_messages = count - len(msgs))
+  return msgs
+
+def record_session(server, path, format):
+  with quicksocket.testing.connect(server) as client:
+    server.start_recording(path, format = format)
+    before = time.time()
+    client.send(["from client", b"\x00\x01"])
+    assert(len(drain(server, 2)) == 2)
+    server.send_messages(["broadcast", b"\xff" * 3])
+    assert(client.expect() == "broadcast" and client.expect() == b"\xff" * 3)
+    server.send_to_client(client.client_id, ["targeted"])
+    assert(client.expect() == "targeted")
+    stats = server.stop_recording()
+    return client.client_id, before, stats
+
+def test_records_jsonl():
+  with tempfile.TemporaryDirectory() as dir, quicksocket.testing.running_server() as server:
+    path = os.path.join(dir, "session.jsonl")
+    client_id, before, stats = record_session(server, path, "jsonl")
+    assert((stats.path, stats.format, stats.recorded, stats.dropped, stats.error) == (path, "jsonl", 5, 0, None))
+    assert(stats.bytes_written == os.path.getsize(path))
+
+    with open(path) as file:
+      records = [json.loads(line) for line in file]
+    # (Broadcasts are recorded by a task of their own, so they may come before or after the targeted send.)
+    assert(sorted((record["dir"], record["client"] or "", record["type"], record["data"]) for record in records) == sorted([
+      ("in", client_id, "text", "from client"), ("in", client_id, "binary", "AAE="),
+      ("out", "", "text", "broadcast"), ("out", "", "binary", "////"), ("out", client_id, "text", "targeted")]))
+    assert(base64.b64decode(records[1]["data"]) == b"\x00\x01")
+    assert(all(before - 1 <= record["t"] <= time.time() for record in records))
+
+    # Nothing's recorded once it's stopped.
+    assert(server.stop_recording() is None and server.get_recording_stats() is None)
+
+def test_records_binary():
+  with tempfile.TemporaryDirectory() as dir, quicksocket.testing.running_server() as server:
+    path = os.path.join(dir, "session.qsrec")
+    client_id, _, stats = record_session(server, path, "binary")
+    assert(stats.recorded == 5)
+
+    with open(path, "rb") as file:
+      data = file.read()
+    assert(data[:8] == b"QSREC\x00\x00\x01")
+    records = []
+    offset = 8
+    while offset < len(data):
+      (length,) = struct.unpack_from(">I", data, offset)
+      _, direction, kind, client_len = struct.unpack_from(">QBBH", data, offset + 4)
+      client = data[offset + 16:offset + 16 + client_len].decode()
+      payload = data[offset + 16 + client_len:offset + 4 + length]
+      records.append((direction, kind, client, payload))
+      offset += 4 + length
+    assert(offset == len(data))
+    assert(sorted(records) == sorted([
+      (0, 0, client_id, b"from client"), (0, 1, client_id, b"\x00\x01"), (1, 0, "", b"broadcast"), (1, 1, "", b"\xff" * 3), (1, 0, client_id, b"targeted")]))
+
+def test_recording_errors():
+  with tempfile.TemporaryDirectory() as dir:
+    with quicksocket.testing.running_server() as server:
+      try:
+        server.start_recording(os.path.join(dir, "session"), format = "csv")
+        assert(False)
+      except ValueError:
+        pass
+      try:
+        server.start_recording(os.path.join(dir, "missing", "session.jsonl"))
+        assert(False)
+      except quicksocket.QuicksocketError:
+        pass
+
+      # One at a time; and the server stopping stops it.
+      path = os.path.join(dir, "session.jsonl")
+      server.start_recording(path)
+      assert(server.get_recording_stats().path == path)
+      try:
+        server.start_recording(os.path.join(dir, "other.jsonl"))
+        assert(False)
+      except quicksocket.QuicksocketError:
+        pass
+      server.send_messages(["last"])
+    assert(server.stop_recording() is None)
+    with open(path) as file:
+      assert([json.loads(line)["data"] for line in file] == ["last"])
+
+if __name__ == "__main__":
+  test_records_jsonl()
+  test_records_binary()
+  test_recording_errors()
@@ -0,0 +1,204 @@
+'''Tests for the Redis bridge, against a minimal in-process stand-in for Redis (just AUTH, SUBSCRIBE, PSUBSCRIBE and PUBLISH). Needs quicksocket built with the "redis" feature; skipped otherwise.'''
+
+import fnmatch
+import socket
+import threading
+
+import quicksocket
+import quicksocket.server
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+if pytest is not None:
+  pytestmark = pytest.mark.skipif(quicksocket.server.BACKEND_start_redis_bridge is None, reason = "quicksocket was built without the \"redis\" feature.")
+
+class FakeRedis:
+  '''Speaks enough RESP for the bridge. Records every PUBLISH it's sent in .published, as (channel, payload) pairs.'''
+  def __init__(self, password = None):
+    self.password = password
+    self.published = []
+    self._subscribers = []  # (socket, channels, patterns)
+    self._lock = threading.Lock()
+    self._listener = socket.socket()
+    self._listener.bind(("127.0.0.1", 0))
+    self._listener.listen()
+    self.port = self._listener.getsockname()[1]
+    self.url = "redis://127.0.0.1:{}".format(self.port)
+    threading.Thread(target = self._accept, daemon = True).start()
+
+  def publish(self, channel: str, payload: bytes) -> int:
+    with self._lock:
+      receivers = 0
+      for sock, channels, patterns in self._subscribers:
+        if channel in channels:
+          self._send(sock, [b"message", channel.encode(), payload])
+          receivers += 1
+        for pattern in patterns:
+          if fnmatch.fnmatchcase(channel, pattern):
+            self._send(sock, [b"pmessage", pattern.encode(), channel.encode(), payload])
+            receivers += 1
+      return receivers
+
+  def subscriber_count(self) -> int:
+    with self._lock:
+      return len(self._subscribers)
+
+  def drop_subscribers(self):
+    with self._lock:
+      for sock, _, _ in self._subscribers:
+        # (Closing alone leaves the connection open while _serve's reader holds it.)
+        try:
+          sock.shutdown(socket.SHUT_RDWR)
+        except OSError:
+          pass
+        sock.close()
+      self._subscribers = []
+
+  def close(self):
+    self.drop_subscribers()
+    self._listener.close()
+
+  def _send(self, sock, items):
+    data = b"*%d\r\n" % len(items) + b"".join(b"$%d\r\n%s\r\n" % (len(item), item) for item in items)
+    try:
+      sock.sendall(data)
+    except OSError:
+      pass
+
+  def _accept(self):
+    while True:
+      try:
+        sock, _ = self._listener.accept()
+      except OSError:
+        return
+      threading.Thread(target = self._serve, args = (sock,), daemon = True).start()
+
+  def _serve(self, sock):
+    reader = sock.makefile("rb")
+    channels, patterns = set(), set()
+    try:
+      while True:
+        command = self._read_command(reader)
+        if command is None:
+          return
+        name = command[0].upper()
+        if name == b"AUTH":
+          sock.sendall(b"+OK\r\n" if command[-1].decode() == self.password else b"-WRONGPASS invalid password\r\n")
+        elif name in (b"SUBSCRIBE", b"PSUBSCRIBE"):
+          with self._lock:
+            for target in command[1:]:
+              (channels if name == b"SUBSCRIBE" else patterns).add(target.decode())
+              self._send(sock, [name.lower(), target, str(len(channels) + len(patterns)).encode()])
+            if not any(entry[0] is sock for entry in self._subscribers):
+              self._subscribers.append((sock, channels, patterns))
+        elif name == b"PUBLISH":
+          with self._lock:
+            self.published.append((command[1].decode(), command[2]))
+          sock.sendall(b":%d\r\n" % self.publish(command[1].decode(), command[2]))
+        else:
+          sock.sendall(b"-ERR unknown command\r\n")
+    except OSError:
+      return
+
+  def _read_command(self, reader):
+    line = reader.readline()
+    if not line:
+      return None
+    count = int(line[1:])
+    items = []
+    for _ in range(count):
+      length = int(reader.readline()[1:])
+      items.append(reader.read(length + 2)[:-2])
+    return items
+
+def test_channels_are_broadcast():
+  redis = FakeRedis()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.redis_bridge(server, redis.url, channels = ["updates"], patterns = ["prices.*"]) as bridge:
+      assert(bridge.is_running())
+      redis.publish("updates", b"hello")
+      redis.publish("prices.eur", b"\xff\x00")
+      redis.publish("elsewhere", b"not for us")
+      assert(client.expect() == "hello")
+      assert(client.expect() == b"\xff\x00")
+      assert(client.recv(timeout_ms = 100) is None)
+      assert(bridge.get_stats().broadcast == 2)
+
+      # Without publish_channel, client messages are still the server's.
+      client.send(["mine"])
+      assert(server.drain_client_messages(timeout_ms = 1000) == ["mine"])
+    assert(not bridge.is_running())
+  redis.close()
+
+def test_client_messages_are_published():
+  redis = FakeRedis(password = "secret")
+  url = "redis://:secret@127.0.0.1:{}".format(redis.port)
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.redis_bridge(server, url, publish_channel = "inbound") as bridge:
+      # The password stays out of logs and reprs.
+      assert("secret" not in repr(bridge))
+      client.send(["text", b"\x01\x02"])
+      assert(wait_until(lambda: len(redis.published) == 2))
+      assert(redis.published == [("inbound", b"text"), ("inbound", b"\x01\x02")])
+      assert(server.drain_client_messages(timeout_ms = 100) == [])
+      assert(bridge.get_stats().published == 2)
+    # Handed back once the bridge stops.
+    client.send(["direct"])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ["direct"])
+
+    try:
+      quicksocket.redis_bridge(server, "redis://:wrong@127.0.0.1:{}".format(redis.port), publish_channel = "inbound")
+      assert(False)
+    except quicksocket.ConnectError as e:
+      assert("authentication" in e.reason)
+  redis.close()
+
+def test_subscription_survives_reconnects():
+  redis = FakeRedis()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    bridge = quicksocket.redis_bridge(server, redis.url, channels = ["updates"])
+    redis.drop_subscribers()
+    assert(wait_until(lambda: redis.subscriber_count() == 1))
+    redis.publish("updates", b"after the reconnect")
+    assert(client.expect() == "after the reconnect")
+    assert(bridge.get_stats().reconnects == 1)
+    assert(any(error.category == "redis" for error in server.drain_error_events()))
+  # The bridge ends with the server.
+  assert(wait_until(lambda: not bridge.is_running()))
+  redis.close()
+
+def test_bad_configurations_are_rejected():
+  with quicksocket.testing.running_server() as server:
+    for url in ("http://127.0.0.1:6379", "rediss://127.0.0.1:6379", "redis://"):
+      try:
+        quicksocket.redis_bridge(server, url, channels = ["x"])
+        assert(False)
+      except ValueError:
+        pass
+    try:
+      quicksocket.redis_bridge(server, "redis://127.0.0.1:6379")
+      assert(False)
+    except ValueError:
+      pass
+
+    with socket.socket() as probe:
+      probe.bind(("127.0.0.1", 0))
+      dead_port = probe.getsockname()[1]
+    try:
+      quicksocket.redis_bridge(server, "redis://127.0.0.1:{}".format(dead_port), channels = ["x"])
+      assert(False)
+    except quicksocket.ConnectError:
+      pass
+
+if __name__ == "__main__":
+  if quicksocket.server.BACKEND_start_redis_bridge is None:
+    print("Skipped: quicksocket was built without the \"redis\" feature.")
+  else:
+    test_channels_are_broadcast()
+    test_client_messages_are_published()
+    test_subscription_survives_reconnects()
+    test_bad_configurations_are_rejected()
@@ -0,0 +1,95 @@
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def feed_url(upstream: quicksocket.Server) -> str:
+  return "ws://127.0.0.1:{}/".format(upstream.get_bound_port())
+
+def drain(source, count: int):
+  '''Drains messages from a Server or Client until count have arrived (or a drain times out).'''
+  msgs = []
+  while len(msgs) < count:
+    drained = source.drain_client_messages(timeout_ms = 1000)
+    if not drained:
+      break
+    msgs += drained
+  return msgs
+
+def test_relay_fans_out():
+  with quicksocket.testing.running_server() as upstream, quicksocket.testing.running_server() as downstream:
+    feed = quicksocket.connect_to(feed_url(upstream), timeout_ms = 5000)
+    assert(upstream.wait_for_client(timeout_ms = 1000))
+    first = quicksocket.testing.connect(downstream)
+    second = quicksocket.testing.connect(downstream)
+
+    with quicksocket.relay(feed, downstream, filter = lambda msg: msg != "heartbeat") as relay:
+      assert(relay.is_running())
+      upstream.send_messages(["tick 1", "heartbeat", b"\x01\x02"])
+      for client in (first, second):
+        assert(client.expect() == "tick 1")
+        assert(client.expect() == b"\x01\x02")
+
+      # Without to_upstream, the clients' messages are left for the server's consumer.
+      first.send(["hello"])
+      assert(drain(downstream, 1) == ["hello"])
+      assert(upstream.drain_client_messages(timeout_ms = 100) == [])
+
+      stats = relay.get_stats()
+      assert((stats.to_clients, stats.to_upstream, stats.filtered) == (2, 0, 1))
+    assert(not relay.is_running())
+
+    # Stopping the relay leaves the connection open, and its messages drainable again.
+    upstream.send_messages(["after"])
+    assert(drain(feed, 1) == ["after"])
+    assert(first.recv(timeout_ms = 100) is None)
+    feed.close(wait = True)
+
+def test_relay_to_upstream():
+  with quicksocket.testing.running_server() as upstream, quicksocket.testing.running_server() as downstream:
+    feed = quicksocket.connect_to(feed_url(upstream), timeout_ms = 5000)
+    client = quicksocket.testing.connect(downstream)
+
+    with quicksocket.relay(feed, downstream, to_upstream = True, upstream_filter = lambda msg: isinstance(msg, str)) as relay:
+      client.send(["subscribe", b"\x00"])
+      assert(drain(upstream, 1) == ["subscribe"])
+      # The relay has the clients' messages while it runs.
+      assert(downstream.drain_client_messages(timeout_ms = 100) == [])
+      deadline = time.monotonic() + 1
+      while relay.get_stats().filtered < 1 and time.monotonic() < deadline:
+        time.sleep(0.01)
+      assert(relay.get_stats().to_upstream == 1 and relay.get_stats().filtered == 1)
+
+      # A second relay can't take them too.
+      try:
+        quicksocket.relay(feed, downstream, to_upstream = True)
+        assert(False)
+      except quicksocket.QuicksocketError:
+        pass
+
+    # Handed back once the relay stops.
+    client.send(["direct"])
+    assert(drain(downstream, 1) == ["direct"])
+    feed.close(wait = True)
+
+def test_relay_ends_with_upstream():
+  with quicksocket.testing.running_server() as upstream, quicksocket.testing.running_server() as downstream:
+    feed = quicksocket.connect_to(feed_url(upstream), timeout_ms = 5000)
+    relay = quicksocket.relay(feed, downstream)
+    upstream.stop(wait = True)
+    deadline = time.monotonic() + 5
+    while relay.is_running() and time.monotonic() < deadline:
+      time.sleep(0.01)
+    assert(not relay.is_running())
+    assert(downstream.is_running())
+
+    try:
+      quicksocket.relay(feed, downstream)
+      assert(False)
+    except quicksocket.ServerNotRunning:
+      pass
+
+if __name__ == "__main__":
+  test_relay_fans_out()
+  test_relay_to_upstream()
+  test_relay_ends_with_upstream()
@@ -0,0 +1,86 @@
+'''Tests for session replay (replay()): a recording's broadcasts broadcast again with their original timing, or scaled, in either recording format.'''
+
+import json
+import os
+import tempfile
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def record_broadcasts(path, format):
+  '''Records three broadcasts, 0.2s apart, along with a client message and a targeted send, which aren't replayed.'''
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    server.start_recording(path, format = format)
+    client.send(["from client"])
+    assert(len(server.drain_client_messages(timeout_ms = 1000, max_messages = 1)) == 1)
+    server.send_to_client(client.client_id, ["targeted"])
+    assert(client.expect() == "targeted")
+    for message in ["one", b"\x00two", "three"]:
+      server.send_messages([message])
+      assert(client.expect() == message)
+      time.sleep(0.2)
+    assert(server.stop_recording().recorded == 5)
+
+def test_replays_with_timing():
+  for format in ["jsonl", "binary"]:
+    with tempfile.TemporaryDirectory() as dir:
+      path = os.path.join(dir, "session")
+      record_broadcasts(path, format)
+
+      with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+        started = time.monotonic()
+        with quicksocket.replay(server, path) as replay:
+          assert(client.expect() == "one" and client.expect() == b"\x00two" and client.expect() == "three")
+          elapsed = time.monotonic() - started
+          assert(replay.wait(timeout_ms = 1000))
+        # (The broadcasts were 0.2s apart, so the last came 0.4s after the first.)
+        assert(0.3 <= elapsed < 2)
+        stats = replay.get_stats()
+        assert((stats.sent, stats.skipped, stats.passes, stats.error) == (3, 2, 1, None))
+        assert(repr(stats) == 'ReplayStats(sent=3, skipped=2, passes=1, error=None)')
+
+def test_replays_scaled_and_repeated():
+  with tempfile.TemporaryDirectory() as dir:
+    path = os.path.join(dir, "session.jsonl")
+    record_broadcasts(path, "jsonl")
+
+    with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+      started = time.monotonic()
+      with quicksocket.replay(server, path, speed = 100, repeat = True) as replay:
+        for _ in range(3):
+          assert([client.expect(), client.expect(), client.expect()] == ["one", b"\x00two", "three"])
+        assert(time.monotonic() - started < 0.3)
+        assert(replay.is_running())
+      assert(not replay.is_running() and replay.get_stats().passes >= 2)
+
+def test_replay_errors():
+  with tempfile.TemporaryDirectory() as dir, quicksocket.testing.running_server() as server:
+    try:
+      quicksocket.replay(server, os.path.join(dir, "missing.jsonl"))
+      assert(False)
+    except quicksocket.QuicksocketError:
+      pass
+    path = os.path.join(dir, "session.jsonl")
+    with open(path, "w") as file:
+      file.write(json.dumps({"t": 1700000000.5, "dir": "out", "client": None, "type": "text", "data": "fine"}) + "\n")
+      file.write("{not json\n")
+    try:
+      quicksocket.replay(server, path, speed = 0)
+      assert(False)
+    except ValueError:
+      pass
+
+    # A malformed record stops the replay, after what came before it.
+    with quicksocket.testing.connect(server) as client:
+      replay = quicksocket.replay(server, path)
+      assert(client.expect() == "fine")
+      assert(replay.wait(timeout_ms = 1000))
+      stats = replay.get_stats()
+      assert(stats.sent == 1 and "record 2" in stats.error)
+      assert(repr(stats).endswith('error={!r})'.format(stats.error)))
+
+if __name__ == "__main__":
+  test_replays_with_timing()
+  test_replays_scaled_and_repeated()
+  test_replay_errors()
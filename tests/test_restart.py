@@ -0,0 +1,121 @@
+import struct
+import time
+
+import quicksocket
+import quicksocket.quicksocket
+import quicksocket.testing
+
+def test_server_restarts_on_same_port():
+  port = 59993
+
+  server = quicksocket.server.Server(port)
+  for i in range(5):
+    server.start()
+    assert(server.wait_until_started(timeout_ms = 2000))
+    assert(server.is_running())
+    # Not waiting here: the next start() has to finish the shutdown itself before it can bind the port again.
+    server.stop()
+
+  server.stop(wait = True)
+  assert(not server.is_running())
+
+def test_module_level_restart():
+  port = 59992
+
+  for i in range(3):
+    quicksocket.quicksocket.start_server(port)
+    assert(quicksocket.quicksocket.wait_until_started(timeout_ms = 2000))
+    quicksocket.quicksocket.set_on_message(lambda message: None)
+    quicksocket.quicksocket.shutdown_server()
+
+  quicksocket.quicksocket.shutdown_server(wait = True)
+  assert(not quicksocket.quicksocket.is_server_running())
+
+def test_shutdown_progress():
+  port = 59982
+
+  server = quicksocket.server.Server(port)
+  server.start()
+  assert(server.wait_until_started(timeout_ms = 2000))
+  progress = server.stop(progress = True)
+  assert(progress.clients_at_shutdown == 0)
+  assert(progress.wait(timeout_ms = 5000))
+  assert(progress.done)
+  assert(progress.state == quicksocket.server.ServerState.STOPPED)
+  assert(progress.clients_remaining == 0)
+  assert(not server.is_running())
+
+  # Without progress=True, stop() still returns nothing.
+  assert(server.stop() is None)
+
+def test_shutdown_close_handshake():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    progress = server.stop(progress = True, close_code = 1012, close_reason = "Restarting")
+    frame = client._recv_frame(time.monotonic() + 1)
+    assert(frame is not None and frame[1:] == (quicksocket.testing.OPCODE_CLOSE, struct.pack('>H', 1012) + b"Restarting"))
+    # The server waits for the answer.
+    time.sleep(0.2)
+    assert(progress.close_frames_sent == 1 and progress.clients_remaining == 1 and not progress.done)
+    client._close_socket(reply = frame[2][:2])
+    assert(progress.wait(timeout_ms = 5000))
+    assert((progress.close_acks_received, progress.clients_remaining) == (1, 0))
+
+def test_unanswered_close_frames_time_out():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server):
+    started = time.monotonic()
+    # The client never reads, so never answers.
+    progress = server.stop(progress = True, close_timeout_ms = 300)
+    assert(progress.wait(timeout_ms = 5000))
+    assert(time.monotonic() - started >= 0.3)
+    assert((progress.close_frames_sent, progress.close_acks_received, progress.clients_remaining) == (1, 0, 0))
+
+def test_wedged_connections_are_aborted():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    # The client never reads, so its sender task is soon stuck writing to its full socket, where it can't see the shutdown.
+    for _ in range(20):
+      server.send_messages([b"x" * 1000000])
+    time.sleep(0.3)
+    server.drain_connection_events()
+    started = time.monotonic()
+    progress = server.stop(progress = True, timeout_ms = 300)
+    assert(progress.wait(timeout_ms = 5000))
+    assert(time.monotonic() - started < 2)
+    assert(progress.aborted_tasks == ["client {}'s sender task".format(client.client_id), "client {}'s receiver task".format(client.client_id)])
+    events = [event for event in server.drain_error_events() if event.category == "internal"]
+    assert(len(events) == 1 and "Aborted 2 task(s)" in events[0].message)
+    # Its disconnection is still counted and reported.
+    assert(progress.clients_remaining == 0 and not server.is_client_connected(client.client_id))
+    assert([event.kind for event in server.drain_connection_events()] == ["disconnected"])
+
+def test_nothing_is_aborted_in_time():
+  with quicksocket.testing.running_server() as server:
+    with quicksocket.testing.connect(server) as client:
+      progress = server.stop(progress = True, timeout_ms = 2000)
+      try:
+        client.recv(timeout_ms = 1000)
+        assert(False)
+      except ConnectionError:
+        pass
+    assert(progress.wait(timeout_ms = 5000))
+    assert(progress.aborted_tasks == [] and progress.close_acks_received == 1)
+
+def test_bad_close_frames_are_refused():
+  with quicksocket.testing.running_server() as server:
+    for kwargs in [dict(close_code = 1005), dict(close_code = 999), dict(close_code = 5000), dict(close_reason = "x" * 124)]:
+      try:
+        server.stop(**kwargs)
+        assert(False)
+      except ValueError:
+        pass
+    assert(server.get_state() == quicksocket.server.ServerState.RUNNING)
+    server.stop(close_reason = "x" * 123)
+
+if __name__ == "__main__":
+  test_server_restarts_on_same_port()
+  test_module_level_restart()
+  test_shutdown_progress()
+  test_shutdown_close_handshake()
+  test_unanswered_close_frames_time_out()
+  test_wedged_connections_are_aborted()
+  test_nothing_is_aborted_in_time()
+  test_bad_close_frames_are_refused()
@@ -0,0 +1,91 @@
+'''Tests for rosbridge compatibility (rosbridge and publish_ros()): clients' subscribes and unsubscribes, publishing to their topics, and the ops the server doesn't do.'''
+
+import json
+import time
+
+import quicksocket
+import quicksocket.testing
+
+POSE = {'position': {'x': 1.0, 'y': 2.0, 'z': 0.0}}
+
+def wait_for_subscriptions(server, expected, timeout_s = 2.0):
+  deadline = time.time() + timeout_s
+  while server.get_ros_subscriptions() != expected and time.time() < deadline:
+    time.sleep(0.01)
+  return server.get_ros_subscriptions()
+
+def drain(server, count, timeout_s = 2.0):
+  '''Drains the server's client messages until count have arrived, or the deadline passes.'''
+  msgs, deadline = [], time.time() + timeout_s
+  while len(msgs) < count and time.time() < deadline:
+    msgs += server.drain_client_messages(timeout_ms = 100, max_messages = count - len(msgs))
+  return msgs
+
+def test_subscriptions():
+  with quicksocket.testing.running_server(rosbridge = True) as server, quicksocket.testing.connect(server) as client:
+    assert(server.publish_ros('/pose', POSE) == 0)
+
+    client.send([json.dumps({'op': 'subscribe', 'topic': '/pose', 'type': 'geometry_msgs/Pose', 'id': 'subscribe:/pose:1'})])
+    client.send([json.dumps({'op': 'subscribe', 'topic': '/pose', 'id': 'subscribe:/pose:2'})])
+    assert(wait_for_subscriptions(server, {'/pose': [client.client_id]}) == {'/pose': [client.client_id]})
+
+    assert(server.publish_ros('/pose', POSE) == 1)
+    assert(server.publish_ros('/imu', '{}') == 0)
+    assert(json.loads(client.expect(timeout_ms = 2000)) == {'op': 'publish', 'topic': '/pose', 'msg': POSE})
+
+    # It's subscribed until it's unsubscribed under both ids.
+    client.send([json.dumps({'op': 'unsubscribe', 'topic': '/pose', 'id': 'subscribe:/pose:1'})])
+    time.sleep(0.2)
+    assert(server.get_ros_subscriptions() == {'/pose': [client.client_id]})
+    client.send([json.dumps({'op': 'unsubscribe', 'topic': '/pose', 'id': 'subscribe:/pose:2'})])
+    assert(wait_for_subscriptions(server, {}) == {})
+    assert(server.publish_ros('/pose', POSE) == 0)
+
+    # Subscribes aren't passed on to the consumer, but publishes are.
+    publish = json.dumps({'op': 'publish', 'topic': '/cmd_vel', 'msg': {'linear': {'x': 1.0}}})
+    client.send([json.dumps({'op': 'advertise', 'topic': '/cmd_vel', 'type': 'geometry_msgs/Twist'}), publish])
+    assert(server.drain_client_messages(timeout_ms = 1000) == [publish])
+
+def test_unsupported_and_malformed_ops():
+  with quicksocket.testing.running_server(rosbridge = True) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    client.send([json.dumps({'op': 'call_service', 'service': '/reset', 'id': 'call_service:/reset:1'})])
+    response = json.loads(client.expect(timeout_ms = 2000))
+    assert(response['op'] == 'service_response' and response['service'] == '/reset' and response['id'] == 'call_service:/reset:1' and response['result'] is False)
+
+    client.send([json.dumps({'op': 'get_param', 'name': '/rate'})])
+    status = json.loads(client.expect(timeout_ms = 2000))
+    assert(status['op'] == 'status' and status['level'] == 'error')
+
+    client.send([json.dumps({'op': 'subscribe', 'id': 'subscribe:1'})])
+    status = json.loads(client.expect(timeout_ms = 2000))
+    assert(status['op'] == 'status' and 'topic' in status['msg'])
+    errors = [error for error in server.drain_error_events() if 'rosbridge' in error.message]
+    assert(len(errors) == 1 and errors[0].severity == 'warning' and errors[0].category == 'receive')
+
+    # Text that isn't an op is passed on as usual.
+    client.send(['hello', '{"topic": "chat"}'])
+    assert(drain(server, 2) == ['hello', '{"topic": "chat"}'])
+
+def test_rosbridge_off():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    subscribe = json.dumps({'op': 'subscribe', 'topic': '/pose'})
+    client.send([subscribe])
+    assert(server.drain_client_messages(timeout_ms = 1000) == [subscribe])
+    try:
+      server.publish_ros('/pose', POSE)
+      assert(False)
+    except quicksocket.SendError:
+      pass
+
+  with quicksocket.testing.running_server(rosbridge = True) as server:
+    try:
+      server.publish_ros('/pose', '[1, 2]')
+      assert(False)
+    except quicksocket.SendError:
+      pass
+
+if __name__ == '__main__':
+  test_subscriptions()
+  test_unsupported_and_malformed_ops()
+  test_rosbridge_off()
@@ -0,0 +1,63 @@
+import quicksocket
+import quicksocket.quicksocket
+import quicksocket.server
+import quicksocket.testing
+from quicksocket.server import ServerState
+
+def test_module_level_server_goes_from_starting_to_running_to_stopped():
+  port = 59974
+
+  quicksocket.quicksocket.start_server(port)
+  try:
+    # (start_server() returns as the server thread is launched, so it's usually still binding; on a busy machine, it may have bound already.)
+    states = [quicksocket.get_server_state()]
+    while states[-1] == ServerState.STARTING:
+      states.append(quicksocket.get_server_state())
+    assert(states[0] in (ServerState.STARTING, ServerState.RUNNING))
+    assert(states[-1] == ServerState.RUNNING)
+    assert(quicksocket.quicksocket.wait_until_started(timeout_ms = 2000))
+    assert(quicksocket.get_server_state() == ServerState.RUNNING)
+    assert(quicksocket.quicksocket.is_server_running())
+  finally:
+    quicksocket.quicksocket.shutdown_server(wait = True)
+  assert(quicksocket.get_server_state() == ServerState.STOPPED)
+  assert(not quicksocket.quicksocket.is_server_running())
+
+def test_module_level_server_fails_on_a_port_clash():
+  with quicksocket.testing.running_server() as holder:
+    port = holder.get_bound_port()
+    quicksocket.quicksocket.start_server(port)
+    try:
+      try:
+        quicksocket.quicksocket.wait_until_started(timeout_ms = 2000)
+        assert(False)
+      except quicksocket.BindError as e:
+        assert(e.port == port)
+      assert(quicksocket.get_server_state() == ServerState.FAILED)
+      assert(not quicksocket.quicksocket.is_server_running())
+    finally:
+      quicksocket.quicksocket.shutdown_server(wait = True)
+    # The server holding the port is unaffected.
+    assert(holder.get_state() == ServerState.RUNNING)
+
+def test_server_instance_states():
+  server = quicksocket.server.Server(port = 0)
+  assert(server.get_state() == ServerState.STOPPED)
+  server.start()
+  assert(server.get_state() in (ServerState.STARTING, ServerState.RUNNING))
+  assert(server.wait_until_started(timeout_ms = 2000))
+  assert(server.get_state() == ServerState.RUNNING)
+
+  clash = quicksocket.server.Server(port = server.get_bound_port())
+  clash.start()
+  assert(quicksocket.testing.wait_until(lambda: clash.get_state() == ServerState.FAILED))
+  assert(not clash.is_running())
+  clash.stop(wait = True)
+
+  server.stop(wait = True)
+  assert(server.get_state() == ServerState.STOPPED)
+
+if __name__ == "__main__":
+  test_module_level_server_goes_from_starting_to_running_to_stopped()
+  test_module_level_server_fails_on_a_port_clash()
+  test_server_instance_states()
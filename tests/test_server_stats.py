@@ -0,0 +1,56 @@
+import quicksocket
+import quicksocket.quicksocket
+import quicksocket.testing
+
+def test_get_server_stats_counts_a_round_trip():
+  port = 59975
+
+  quicksocket.quicksocket.start_server(port)
+  try:
+    assert(quicksocket.quicksocket.wait_until_started(timeout_ms = 2000))
+    stats = quicksocket.quicksocket.get_server_stats()
+    assert((stats.total_connections, stats.current_clients, stats.messages_received, stats.messages_sent) == (0, 0, 0, 0))
+
+    with quicksocket.testing.connect(port) as client:
+      assert(quicksocket.quicksocket.wait_for_client(timeout_ms = 2000))
+      client.send(["hello", b"\x00\x01\x02"])
+      received = []
+      while len(received) < 2:
+        drained = quicksocket.quicksocket.drain_client_messages(timeout_ms = 1000)
+        assert(drained)
+        received += drained
+      quicksocket.quicksocket.try_send_messages(["four", b"\x05" * 16])
+      assert(client.expect() == "four")
+      assert(client.expect() == b"\x05" * 16)
+
+      # (Sent messages are counted once they've been written, which may be after the client has read them.)
+      assert(quicksocket.testing.wait_until(lambda: quicksocket.quicksocket.get_server_stats().messages_sent == 2))
+      stats = quicksocket.quicksocket.get_server_stats()
+      assert((stats.total_connections, stats.current_clients) == (1, 1))
+      assert((stats.messages_received, stats.bytes_received) == (2, len("hello") + 3))
+      assert((stats.messages_sent, stats.bytes_sent) == (2, len("four") + 16))
+      assert(stats.messages_dropped == 0)
+
+    assert(quicksocket.testing.wait_until(lambda: quicksocket.quicksocket.get_server_stats().current_clients == 0))
+    stats = quicksocket.quicksocket.get_server_stats()
+    assert((stats.total_connections, stats.messages_received, stats.messages_sent) == (1, 2, 2))
+  finally:
+    quicksocket.quicksocket.shutdown_server(wait = True)
+
+  # The stopped server's stats are still there.
+  stats = quicksocket.quicksocket.get_server_stats()
+  assert((stats.total_connections, stats.current_clients, stats.bytes_received, stats.bytes_sent) == (1, 0, len("hello") + 3, len("four") + 16))
+
+def test_broadcasts_count_once_per_client():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as first, quicksocket.testing.connect(server) as second:
+    server.send_messages(["to both"])
+    assert(first.expect() == "to both" and second.expect() == "to both")
+    assert(quicksocket.testing.wait_until(lambda: server.get_stats().messages_sent == 2))
+    stats = server.get_stats()
+    assert((stats.total_connections, stats.current_clients) == (2, 2))
+    assert((stats.messages_sent, stats.bytes_sent) == (2, 2 * len("to both")))
+    assert((stats.messages_received, stats.bytes_received) == (0, 0))
+
+if __name__ == "__main__":
+  test_get_server_stats_counts_a_round_trip()
+  test_broadcasts_count_once_per_client()
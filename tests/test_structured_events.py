@@ -0,0 +1,51 @@
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def test_structured_client_messages():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    before, monotonic_before = time.time(), time.monotonic()
+    client.send(["hello", b"\x00\x01\x02"])
+    msgs = []
+    while len(msgs) < 2:
+      drained = server.drain_client_messages(timeout_ms = 1000, structured = True)
+      assert(drained)
+      msgs += drained
+    after, monotonic_after = time.time(), time.monotonic()
+
+    assert(all(isinstance(msg, quicksocket.ClientMessage) for msg in msgs))
+    assert([(msg.client_id, msg.data, msg.is_text) for msg in msgs] == [(client.client_id, "hello", True), (client.client_id, b"\x00\x01\x02", False)])
+    assert(all(before <= msg.timestamp <= after for msg in msgs))
+    assert(all(monotonic_before <= msg.monotonic_timestamp <= monotonic_after for msg in msgs))
+    assert(msgs[0].monotonic_timestamp <= msgs[1].monotonic_timestamp)
+    assert(repr(msgs[0]) == "<quicksocket.ClientMessage from {}: text message of length 5>".format(client.client_id))
+    assert(repr(msgs[1]) == "<quicksocket.ClientMessage from {}: binary message of length 3>".format(client.client_id))
+
+    # Unstructured, they're just their data.
+    client.send(["plain"])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ["plain"])
+
+def test_connection_events():
+  with quicksocket.testing.running_server() as server:
+    before = time.time()
+    client = quicksocket.testing.connect(server)
+    client.close()
+    assert(quicksocket.testing.wait_until(lambda: server.get_stats().current_clients == 0))
+    after = time.time()
+
+    events = server.drain_connection_events()
+    assert(all(isinstance(event, quicksocket.ConnectionEvent) for event in events))
+    assert([(event.client_id, event.kind) for event in events] == [(client.client_id, "connected"), (client.client_id, "disconnected")])
+    assert(all(before <= event.timestamp <= after for event in events))
+    assert(events[0].timestamp <= events[1].timestamp)
+    # The close frame the client sent (with a code, but no reason), only on its disconnection.
+    assert((events[0].close_code, events[0].close_reason) == (None, None))
+    assert((events[1].close_code, events[1].close_reason) == (1000, ""))
+    assert(repr(events[0]) == "<quicksocket.ConnectionEvent: {} connected>".format(client.client_id))
+    assert(repr(events[1]) == "<quicksocket.ConnectionEvent: {} disconnected (1000)>".format(client.client_id))
+    assert(server.drain_connection_events() == [])
+
+if __name__ == "__main__":
+  test_structured_client_messages()
+  test_connection_events()
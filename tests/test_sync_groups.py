@@ -0,0 +1,66 @@
+'''Tests for sync groups (sync_groups and send_synced()): messages on a group's topics tagged with a presentation timestamp, and delivered in its order.'''
+
+import json
+import struct
+import time
+
+import quicksocket
+import quicksocket.testing
+
+GROUPS = {'robot': ['camera', 'pose', 'imu']}
+
+def recv_all(client, timeout_ms = 300):
+  messages = []
+  while True:
+    message = client.recv(timeout_ms = timeout_ms)
+    if message is None:
+      return messages
+    messages.append(message)
+
+def test_order():
+  with quicksocket.testing.running_server(sync_groups = GROUPS, sync_window_ms = 200) as server, quicksocket.testing.connect(server) as client:
+    # Sent out of order, as streams that take different times to get ready are.
+    server.send_synced('pose', json.dumps({'x': 2}), pts_us = 2000)
+    server.send_synced('imu', '3', pts_us = 3000)
+    server.send_synced('camera', b'\xff\xd8', pts_us = 1000)
+    server.send_synced('pose', '{}', pts_us = 2000)
+    messages = recv_all(client, timeout_ms = 1000)
+    assert(len(messages) == 4)
+
+    assert(messages[0][:8] == struct.pack('>Q', 1000))
+    assert(messages[0][8:10] == struct.pack('>H', 6))
+    assert(messages[0][10:] == b'camera\xff\xd8')
+    assert(json.loads(messages[1]) == {'sync_group': 'robot', 'sync_topic': 'pose', 'pts_us': 2000, 'x': 2})
+    assert(json.loads(messages[2]) == {'sync_group': 'robot', 'sync_topic': 'pose', 'pts_us': 2000})
+    assert(json.loads(messages[3]) == {'sync_group': 'robot', 'sync_topic': 'imu', 'pts_us': 3000, 'data': '3'})
+
+def test_late():
+  with quicksocket.testing.running_server(sync_groups = GROUPS, sync_window_ms = 50) as server, quicksocket.testing.connect(server) as client:
+    server.send_synced('pose', '{}', pts_us = 2000)
+    time.sleep(0.3)
+    # Its window's long gone: it'd go out of order.
+    server.send_synced('camera', '{}', pts_us = 1000)
+    server.send_synced('imu', '{}', pts_us = 3000)
+    messages = [json.loads(message) for message in recv_all(client)]
+    assert([message['sync_topic'] for message in messages] == ['pose', 'imu'])
+    assert(server.get_stats().synced_late == 1)
+
+def test_invalid():
+  with quicksocket.testing.running_server(sync_groups = GROUPS) as server:
+    try:
+      server.send_synced('elsewhere', '{}')
+      assert(False)
+    except quicksocket.SendError:
+      pass
+
+  for kwargs in [{'sync_groups': {'a': ['x'], 'b': ['x']}}, {'sync_window_ms': -1}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == '__main__':
+  test_order()
+  test_late()
+  test_invalid()
@@ -0,0 +1,117 @@
+import time
+
+import quicksocket.testing
+
+def test_ephemeral_port_round_trip():
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    port = server.get_bound_port()
+    assert(port is not None and port != 0)
+
+    client.send(["ping", b"\x01\x02"])
+    msgs = []
+    while len(msgs) < 2:
+      drained = server.drain_client_messages(timeout_ms = 1000, structured = True)
+      assert(drained)
+      msgs += drained
+    assert([msg.data for msg in msgs] == ["ping", b"\x01\x02"])
+    assert(all(msg.client_id == client.client_id for msg in msgs))
+
+    # Registered by the time connect() returns, so a targeted send can't miss it.
+    server.send_to_client(client.client_id, ["pong"])
+    client.expect("pong")
+    server.send_messages([b"\x00" * 70000])
+    assert(client.expect() == b"\x00" * 70000)
+
+    try:
+      client.expect(timeout_ms = 50)
+      assert(False)
+    except AssertionError as e:
+      assert("nothing arrived" in str(e))
+
+    client.close()
+    assert(client.closed)
+
+def test_received_text_arrives_intact():
+  # ASCII text is copied straight into a str; the rest is decoded by Python. Either way it's an ordinary str.
+  texts = ["", "{\"price\": 101.5}", "x" * 200000, "caf\u00e9", "\u5024\u6bb5", "\U0001f600 and ascii"]
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    client.send(texts)
+    received = []
+    while len(received) < len(texts):
+      drained = server.drain_client_messages(timeout_ms = 1000)
+      assert(drained)
+      received += drained
+    assert(received == texts)
+    assert([text.isascii() for text in received] == [True, True, True, False, False, False])
+    assert({text: i for i, text in enumerate(received)} == {text: i for i, text in enumerate(texts)})
+    assert(received[1] + "!" == texts[1] + "!")
+
+def test_a_drain_gets_everything_received():
+  with quicksocket.testing.running_server() as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(3)]
+    server.drain_connection_events()
+    # More than the server queues: it stops reading once its queue is full, but whatever it has read is queued.
+    for index, client in enumerate(clients):
+      client.send([str(index) + " " + str(i) for i in range(20)])
+    received = -1
+    while server.get_stats().messages_received != received:
+      received = server.get_stats().messages_received
+      time.sleep(0.2)
+    assert(0 < received < 60)
+    drained = server.drain_client_messages()
+    assert(len(drained) == received)
+    while len(drained) < 60:
+      more = server.drain_client_messages(timeout_ms = 1000)
+      assert(more)
+      drained += more
+    for index in range(3):
+      assert([msg for msg in drained if msg.startswith(str(index) + " ")] == [str(index) + " " + str(i) for i in range(20)])
+    for client in clients: client.close()
+
+def test_server_close_is_reported():
+  with quicksocket.testing.running_server() as server:
+    client = quicksocket.testing.connect(server)
+    server.stop(wait = True)
+    try:
+      client.recv(timeout_ms = 1000)
+      assert(False)
+    except ConnectionError as e:
+      assert("1001" in str(e))
+    assert(client.closed)
+
+def test_client_close_frames_are_reported():
+  with quicksocket.testing.running_server() as server:
+    clients = [quicksocket.testing.connect(server) for _ in range(4)]
+    connected = server.drain_connection_events()
+    assert([(event.kind, event.close_code, event.close_reason) for event in connected] == [("connected", None, None)] * 4)
+    going_away, no_status, dropped, normal = clients
+    going_away._send_frame(quicksocket.testing.OPCODE_CLOSE, (1001).to_bytes(2, 'big') + "tab closed ✓".encode())
+    no_status._send_frame(quicksocket.testing.OPCODE_CLOSE, b"")
+    for client in (going_away, no_status):
+      frame = client._recv_frame(time.monotonic() + 1)
+      assert(frame is not None and frame[1] == quicksocket.testing.OPCODE_CLOSE)
+      client._close_socket()
+    dropped._close_socket()
+    normal.close()
+    events = {}
+    deadline = time.monotonic() + 5
+    while len(events) < 4 and time.monotonic() < deadline:
+      events.update((event.client_id, event) for event in server.drain_connection_events())
+      time.sleep(0.01)
+    assert(all(event.kind == "disconnected" for event in events.values()))
+    assert([(events[client.client_id].close_code, events[client.client_id].close_reason) for client in clients] == [(1001, "tab closed ✓"), (1005, ""), (None, None), (1000, "")])
+    assert("(1001)" in repr(events[going_away.client_id]))
+
+def test_wait_until():
+  started = time.monotonic()
+  assert(quicksocket.testing.wait_until(lambda: time.monotonic() - started > 0.05))
+  assert(not quicksocket.testing.wait_until(lambda: False, timeout_s = 0.05))
+  assert(time.monotonic() - started < 1)
+
+if __name__ == "__main__":
+  test_ephemeral_port_round_trip()
+  test_received_text_arrives_intact()
+  test_a_drain_gets_everything_received()
+  test_server_close_is_reported()
+  test_client_close_frames_are_reported()
+  test_wait_until()
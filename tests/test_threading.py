@@ -0,0 +1,67 @@
+'''Tests for the server's threads: how many workers it has, pinning them to cores, and isolating those cores from the thread that starts the server.'''
+
+import os
+import threading
+
+import quicksocket
+import quicksocket.testing
+
+def server_threads():
+  '''The ids of the process's server runtime threads (whose names Linux cuts to 15 bytes).'''
+  return [int(tid) for tid in os.listdir('/proc/self/task') if open('/proc/self/task/{}/comm'.format(tid)).read().strip() == 'quicksocket-server'[:15]]
+
+def test_worker_threads():
+  before = len(server_threads())
+  with quicksocket.testing.running_server(worker_threads = 3) as server, quicksocket.testing.connect(server) as client:
+    assert(len(server_threads()) >= before + 3)
+    server.send_messages(["on three workers"])
+    assert(client.expect() == "on three workers")
+
+def test_workers_are_pinned():
+  core = max(os.sched_getaffinity(0))
+  with quicksocket.testing.running_server(worker_cores = [core]) as server, quicksocket.testing.connect(server) as client:
+    assert(all(os.sched_getaffinity(tid) == {core} for tid in server_threads()))
+    client.send(["pinned"])
+    assert(server.drain_client_messages(timeout_ms = 1000) == ["pinned"])
+  # The caller wasn't isolated, so it keeps the core.
+  assert(core in os.sched_getaffinity(0))
+
+def test_isolation_takes_the_cores_from_the_caller():
+  cores = os.sched_getaffinity(0)
+  if len(cores) < 2:
+    # Nothing would be left for the caller (tested below).
+    return
+  core = max(cores)
+  seen = {}
+  def start():
+    with quicksocket.testing.running_server(worker_cores = [core], isolate_cores = True):
+      seen['caller'] = os.sched_getaffinity(0)
+      spawned = threading.Thread(target = lambda: seen.update(spawned = os.sched_getaffinity(0)))
+      spawned.start()
+      spawned.join()
+  # (On a thread of its own, so the test's own thread keeps its cores.)
+  caller = threading.Thread(target = start)
+  caller.start()
+  caller.join()
+  assert(seen['caller'] == cores - {core} and seen['spawned'] == cores - {core})
+  assert(os.sched_getaffinity(0) == cores)
+
+def test_bad_threading_is_refused():
+  for kwargs, complaint in [
+    (dict(worker_threads = 0), "at least one worker"),
+    (dict(worker_cores = [100000]), "no core 100000"),
+    (dict(isolate_cores = True), "only servers pinned to cores"),
+    (dict(worker_cores = sorted(os.sched_getaffinity(0)), isolate_cores = True), "none to run on"),
+  ]:
+    try:
+      with quicksocket.testing.running_server(**kwargs):
+        assert(False)
+    except ValueError as err:
+      assert(complaint in str(err)), err
+  assert(len(os.sched_getaffinity(0)) > 0)
+
+if __name__ == "__main__":
+  test_worker_threads()
+  test_workers_are_pinned()
+  test_isolation_takes_the_cores_from_the_caller()
+  test_bad_threading_is_refused()
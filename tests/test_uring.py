@@ -0,0 +1,102 @@
+'''Tests for the io_uring transport: the same traffic as over TCP, with the server's sockets going through io_uring. Needs quicksocket built with the "uring" feature (and a kernel that allows io_uring); skipped otherwise.'''
+
+import socket
+import urllib.error
+import urllib.request
+
+import quicksocket
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+
+def uring_unavailable():
+  '''Why the tests can't run, or None if they can.'''
+  server = quicksocket.Server(port = 0, io_uring = True)
+  try:
+    server.start()
+  except quicksocket.QuicksocketError as err:
+    return str(err)
+  try:
+    server.wait_until_started()
+  except quicksocket.QuicksocketError as err:
+    return str(err)
+  finally:
+    server.stop(wait = True)
+  return None
+
+def skip_without_io_uring():
+  '''Skips the test if io_uring can't be used.'''
+  reason = uring_unavailable()
+  if reason is not None:
+    pytest.skip(reason)
+
+def test_messages_both_ways():
+  skip_without_io_uring()
+  with quicksocket.testing.running_server(io_uring = True) as server, quicksocket.testing.connect(server) as client:
+    # Big enough to take several reads, and more than one write.
+    for payload in ["hello", b"\x01" * 70000, "é" * 300000]:
+      server.send_messages([payload])
+      assert(client.expect() == payload)
+      client.send([payload])
+      assert(server.drain_client_messages(timeout_ms = 1000) == [payload])
+    server.send_to_client(client.client_id, ["just you"])
+    assert(client.expect() == "just you")
+
+def test_plain_http_requests_are_answered():
+  skip_without_io_uring()
+  with quicksocket.testing.running_server(io_uring = True, landing_page = "<p>uring</p>") as server:
+    with urllib.request.urlopen("http://localhost:" + str(server.get_bound_port()) + "/") as response:
+      assert(response.read() == b"<p>uring</p>")
+
+def test_many_clients_come_and_go():
+  skip_without_io_uring()
+  with quicksocket.testing.running_server(io_uring = True) as server:
+    clients = []
+    for _ in range(40):
+      clients.append(quicksocket.testing.connect(server))
+      # (Connection events queue up until they're drained, and a full queue holds up new clients.)
+      server.drain_connection_events()
+    server.send_messages(["to everyone"])
+    for client in clients:
+      assert(client.expect() == "to everyone")
+    for client in clients:
+      client.close()
+    # (Their close frames are queued as client messages, a few at a time, so they're drained meanwhile.)
+    assert(wait_until(lambda: server.drain_client_messages() is not None and server.get_stats().current_clients == 0))
+    assert(server.get_stats().total_connections == 40)
+
+def test_stopping_frees_the_port():
+  skip_without_io_uring()
+  with quicksocket.testing.running_server(io_uring = True) as server:
+    port = server.get_bound_port()
+  # The listener's accept was in flight; the port is free again once it's been cancelled.
+  def port_is_free():
+    with socket.socket() as probe:
+      try:
+        probe.bind(("127.0.0.1", port))
+        return True
+      except OSError:
+        return False
+  assert(wait_until(port_is_free))
+
+def test_loopback_servers_cant_use_io_uring():
+  try:
+    quicksocket.Server(port = 1, loopback = True, io_uring = True).start()
+    assert(False)
+  except ValueError:
+    pass
+
+if __name__ == "__main__":
+  reason = uring_unavailable()
+  if reason is not None:
+    print("Skipped: " + reason)
+  else:
+    test_messages_both_ways()
+    test_plain_http_requests_are_answered()
+    test_many_clients_come_and_go()
+    test_stopping_frees_the_port()
+    test_loopback_servers_cant_use_io_uring()
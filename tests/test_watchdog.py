@@ -0,0 +1,68 @@
+'''Tests for the watchdog (watchdog_stall_timeout_ms): stalled tasks reported as critical error events, and restarted with watchdog_restart.'''
+
+import time
+
+import quicksocket
+import quicksocket.testing
+
+def stall_sender_task(server, client):
+  '''Broadcasts more than the client's socket can take without it reading any, so its sender task is stuck mid-write.'''
+  for _ in range(16):
+    server.send_messages([b"x" * 2_000_000])
+
+def critical_events(server, timeout_s):
+  deadline = time.monotonic() + timeout_s
+  while time.monotonic() < deadline:
+    events = [event for event in server.drain_error_events() if event.severity == "critical"]
+    if events:
+      return events
+    time.sleep(0.05)
+  return []
+
+def test_reports_stalled_sender_task():
+  with quicksocket.testing.running_server(watchdog_stall_timeout_ms = 300) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    stall_sender_task(server, client)
+    events = critical_events(server, 5)
+    assert(len(events) == 1 and events[0].category == "internal" and events[0].client_id == client.client_id)
+    assert("sender task" in events[0].message)
+    # Reported, not restarted: the client's still connected, and its stall's reported once.
+    assert(server.is_client_connected(client.client_id))
+    assert(critical_events(server, 0.5) == [])
+    assert(server.get_stats().error_count >= 1)
+
+def test_restarts_stalled_sender_task():
+  with quicksocket.testing.running_server(watchdog_stall_timeout_ms = 300, watchdog_restart = True) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    stall_sender_task(server, client)
+    assert("restarting it" in critical_events(server, 5)[0].message)
+    deadline = time.monotonic() + 2
+    while server.is_client_connected(client.client_id) and time.monotonic() < deadline:
+      time.sleep(0.01)
+    assert(not server.is_client_connected(client.client_id))
+    # The rest of the server carries on.
+    with quicksocket.testing.connect(server) as other:
+      server.send_messages(["still here"])
+      assert(other.expect() == "still here")
+
+def test_idle_server_isnt_stalled():
+  with quicksocket.testing.running_server(watchdog_stall_timeout_ms = 100) as server, quicksocket.testing.connect(server) as client:
+    server.drain_error_events()
+    time.sleep(0.5)
+    server.send_messages(["hello"])
+    assert(client.expect() == "hello")
+    assert(critical_events(server, 0.3) == [])
+
+def test_watchdog_arguments():
+  for kwargs in [{"watchdog_stall_timeout_ms": 0}, {"watchdog_restart": True}]:
+    try:
+      quicksocket.Server(port = 0, **kwargs).start()
+      assert(False)
+    except ValueError:
+      pass
+
+if __name__ == "__main__":
+  test_reports_stalled_sender_task()
+  test_restarts_stalled_sender_task()
+  test_idle_server_isnt_stalled()
+  test_watchdog_arguments()
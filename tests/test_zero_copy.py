@@ -0,0 +1,61 @@
+import gc
+
+import quicksocket.server
+from quicksocket.server import MessageBuffer
+
+def drain(server: quicksocket.server.Server, count: int):
+  '''Drains client messages until count have arrived (or a drain times out).'''
+  msgs = []
+  while len(msgs) < count:
+    drained = server.drain_client_messages(timeout_ms = 1000)
+    if not drained:
+      break
+    msgs += drained
+  return msgs
+
+def test_large_binary_messages_are_buffers():
+  below, at, above = b"\x01" * 1023, b"\x02" * 1024, bytes(range(256)) * 8
+  with quicksocket.server.Server(1, loopback = True, zero_copy_min_bytes = 1024) as server, server.connect_loopback() as client:
+    client.send([below, at, above, "text of more than a thousand and twenty-four bytes" * 30])
+    msgs = drain(server, 4)
+    assert([type(msg) for msg in msgs] == [bytes, MessageBuffer, MessageBuffer, str])
+    assert(msgs[0] == below)
+
+    # Its bytes, through the buffer protocol, which is read-only.
+    view = memoryview(msgs[2])
+    assert(len(msgs[2]) == len(view) == len(above))
+    assert(view.readonly and view.format == "B" and view.tobytes() == above)
+    assert(memoryview(msgs[1]).tobytes() == at)
+    assert(repr(msgs[2]) == "<quicksocket.MessageBuffer of 2048 bytes>")
+
+    # And copied out.
+    copied = msgs[2].copy()
+    assert(type(copied) == bytes and copied == above)
+    assert(bytes(msgs[1]) == at)
+
+def test_buffers_outlive_their_messages():
+  above = bytes(range(256)) * 8
+  with quicksocket.server.Server(1, loopback = True, zero_copy_min_bytes = 1024) as server, server.connect_loopback() as client:
+    client.send([above])
+    msgs = drain(server, 1)
+    view = memoryview(msgs[0])
+    # (The view holds a reference to the message, so the bytes it points to stay valid once nothing else refers to it.)
+    del msgs
+    gc.collect()
+    client.send([bytes(len(above))])
+    assert(drain(server, 1)[0].copy() == bytes(len(above)))
+    assert(view.tobytes() == above)
+  # Nor do they depend on the server.
+  assert(view[0] == 0 and view[-1] == 255 and view.tobytes() == above)
+  view.release()
+
+def test_zero_copy_is_off_by_default():
+  with quicksocket.server.Server(1, loopback = True) as server, server.connect_loopback() as client:
+    client.send([bytes(1 << 20)])
+    msgs = drain(server, 1)
+    assert(type(msgs[0]) == bytes and len(msgs[0]) == 1 << 20)
+
+if __name__ == "__main__":
+  test_large_binary_messages_are_buffers()
+  test_buffers_outlive_their_messages()
+  test_zero_copy_is_off_by_default()
@@ -0,0 +1,208 @@
+'''Tests for the ZeroMQ bridge, against minimal in-process ZMTP 3.0 peers (PUB and PUSH sockets, NULL security). Needs quicksocket built with the "zmq" feature; skipped otherwise.'''
+
+import socket
+import struct
+import threading
+import time
+
+import quicksocket
+import quicksocket.server
+import quicksocket.testing
+from quicksocket.testing import wait_until
+
+try:
+  import pytest
+except ImportError:
+  pytest = None
+if pytest is not None:
+  pytestmark = pytest.mark.skipif(quicksocket.server.BACKEND_start_zmq_bridge is None, reason = "quicksocket was built without the \"zmq\" feature.")
+
+def free_port() -> int:
+  with socket.socket() as probe:
+    probe.bind(("127.0.0.1", 0))
+    return probe.getsockname()[1]
+
+class Peer:
+  '''One end of a ZMTP connection, as the given socket type. Handshakes on construction, and records the bridge's socket type.'''
+  def __init__(self, sock: socket.socket, socket_type: str):
+    self.sock = sock
+    self.reader = sock.makefile("rb")
+    greeting = b"\xff" + b"\x00" * 8 + b"\x7f" + b"\x03\x00" + b"NULL".ljust(20, b"\x00") + b"\x00" + b"\x00" * 31
+    assert(len(greeting) == 64)
+    sock.sendall(greeting)
+    theirs = self.reader.read(64)
+    assert(theirs[0] == 0xff and theirs[9] == 0x7f and theirs[10] == 3 and theirs[12:32].rstrip(b"\x00") == b"NULL")
+    ready = b"\x05READY" + b"\x0bSocket-Type" + struct.pack(">I", len(socket_type)) + socket_type.encode()
+    self.send_frame(0x04, ready)
+    flags, body = self.read_frame()
+    assert(flags & 0x04 and body.startswith(b"\x05READY"))
+    self.their_type = body[body.index(b"Socket-Type") + 15:].decode()
+
+  def send_frame(self, flags: int, body: bytes):
+    if len(body) > 255:
+      self.sock.sendall(bytes([flags | 0x02]) + struct.pack(">Q", len(body)) + body)
+    else:
+      self.sock.sendall(bytes([flags, len(body)]) + body)
+
+  def send(self, parts):
+    for i, part in enumerate(parts):
+      self.send_frame(0x01 if i < len(parts) - 1 else 0x00, part)
+
+  def read_frame(self):
+    flags = self.reader.read(1)[0]
+    length = struct.unpack(">Q", self.reader.read(8))[0] if flags & 0x02 else self.reader.read(1)[0]
+    return flags, self.reader.read(length)
+
+  def close(self):
+    try:
+      self.sock.shutdown(socket.SHUT_RDWR)
+    except OSError:
+      pass
+    self.sock.close()
+
+class Publisher:
+  '''A PUB socket bound to a port, taking any number of subscribers. Records each subscriber's subscriptions, but (unlike a real PUB) sends every message to every subscriber, so the bridge's own filtering shows.'''
+  def __init__(self, port: int = 0):
+    self.subscribers = []
+    self.subscriptions = []
+    self._listener = socket.socket()
+    self._listener.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+    self._listener.bind(("127.0.0.1", port))
+    self._listener.listen()
+    self.port = self._listener.getsockname()[1]
+    self.endpoint = "tcp://127.0.0.1:{}".format(self.port)
+    threading.Thread(target = self._accept, daemon = True).start()
+
+  def publish(self, parts):
+    for subscriber in self.subscribers:
+      subscriber.send(parts)
+
+  def drop_subscribers(self):
+    for subscriber in self.subscribers:
+      subscriber.close()
+    self.subscribers = []
+
+  def close(self):
+    self.drop_subscribers()
+    self._listener.close()
+
+  def _accept(self):
+    while True:
+      try:
+        sock, _ = self._listener.accept()
+      except OSError:
+        return
+      peer = Peer(sock, "PUB")
+      assert(peer.their_type == "SUB")
+      threading.Thread(target = self._read_subscriptions, args = (peer,), daemon = True).start()
+
+  def _read_subscriptions(self, peer: Peer):
+    try:
+      while True:
+        flags, body = peer.read_frame()
+        self.subscriptions.append(body)
+        if body == b"\x01" or len(self.subscriptions) >= 2:
+          if peer not in self.subscribers:
+            self.subscribers.append(peer)
+    except (OSError, IndexError, struct.error):
+      return
+
+def test_sub_receives_from_a_publisher():
+  publisher = Publisher()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.zmq_bridge(server, publisher.endpoint, topics = ["frame", "state"]) as bridge:
+      assert(bridge.is_running())
+      assert(wait_until(lambda: len(publisher.subscribers) == 1))
+      assert(publisher.subscriptions == [b"\x01frame", b"\x01state"])
+
+      publisher.publish([b"frame", b'{"t": 1}'])
+      publisher.publish([b"elsewhere", b"filtered out"])
+      publisher.publish([b"state\xff\x00"])
+      assert(client.expect() == '{"t": 1}')
+      assert(client.expect() == b"state\xff\x00")
+      assert(client.recv(timeout_ms = 100) is None)
+      stats = bridge.get_stats()
+      assert((stats.broadcast, stats.connections) == (2, 1))
+
+      # Long frames too.
+      publisher.publish([b"frame", b"x" * 1000])
+      assert(client.expect() == "x" * 1000)
+    assert(not bridge.is_running())
+  publisher.close()
+
+def test_connecting_bridge_waits_and_reconnects():
+  port = free_port()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    bridge = quicksocket.zmq_bridge(server, "tcp://127.0.0.1:{}".format(port))
+    # Nothing to connect to yet: one error, however long that lasts.
+    time.sleep(1.5)
+    errors = [error for error in server.drain_error_events() if error.category == "zmq"]
+    assert(len(errors) == 1)
+    assert(bridge.is_running())
+
+    publisher = Publisher(port)
+    assert(wait_until(lambda: len(publisher.subscribers) == 1))
+    assert(publisher.subscriptions == [b"\x01"])
+    publisher.publish([b"hello"])
+    assert(client.expect() == "hello")
+
+    publisher.drop_subscribers()
+    assert(wait_until(lambda: len(publisher.subscribers) == 1))
+    publisher.publish([b"again"])
+    assert(client.expect() == "again")
+    assert(bridge.get_stats().connections == 2)
+    assert(len([error for error in server.drain_error_events() if error.category == "zmq"]) == 1)
+  # The bridge ends with the server.
+  assert(wait_until(lambda: not bridge.is_running()))
+  publisher.close()
+
+def test_pull_binds_for_pushers():
+  port = free_port()
+  with quicksocket.testing.running_server() as server, quicksocket.testing.connect(server) as client:
+    with quicksocket.zmq_bridge(server, "tcp://*:{}".format(port), socket_type = "PULL", bind = True) as bridge:
+      pushers = [Peer(socket.create_connection(("127.0.0.1", port)), "PUSH") for _ in range(2)]
+      assert(all(pusher.their_type == "PULL" for pusher in pushers))
+      pushers[0].send([b"from the first"])
+      assert(client.expect() == "from the first")
+      pushers[1].send([b"envelope", b"from the second"])
+      assert(client.expect() == "from the second")
+      assert(bridge.get_stats().connections == 2)
+
+      # A peer that can't push to a PULL socket is refused.
+      publisher = Peer(socket.create_connection(("127.0.0.1", port)), "PUB")
+      assert(wait_until(lambda: any("PUB" in error.message for error in server.drain_error_events() if error.category == "zmq")))
+      assert(publisher.reader.read(1) == b"")
+      for pusher in pushers:
+        pusher.close()
+
+      # The endpoint's taken.
+      try:
+        quicksocket.zmq_bridge(server, "tcp://127.0.0.1:{}".format(port), socket_type = "PULL", bind = True)
+        assert(False)
+      except quicksocket.BindError:
+        pass
+
+def test_bad_configurations_are_rejected():
+  with quicksocket.testing.running_server() as server:
+    for kwargs in (
+      {"endpoint": "ipc:///tmp/feed"},
+      {"endpoint": "127.0.0.1:5556"},
+      {"endpoint": "tcp://127.0.0.1"},
+      {"endpoint": "tcp://*:5556"},
+      {"endpoint": "tcp://127.0.0.1:5556", "socket_type": "REQ"},
+      {"endpoint": "tcp://127.0.0.1:5556", "socket_type": "PULL", "topics": ["x"]},
+    ):
+      try:
+        quicksocket.zmq_bridge(server, **kwargs)
+        assert(False)
+      except ValueError:
+        pass
+
+if __name__ == "__main__":
+  if quicksocket.server.BACKEND_start_zmq_bridge is None:
+    print("Skipped: quicksocket was built without the \"zmq\" feature.")
+  else:
+    test_sub_receives_from_a_publisher()
+    test_connecting_bridge_waits_and_reconnects()
+    test_pull_binds_for_pushers()
+    test_bad_configurations_are_rejected()